@@ -0,0 +1,728 @@
+//! The stable interface of the library.
+//!
+//! These types wrap the capture and decoder used by the application, so
+//! that their internals can change without breaking the programs using
+//! the library.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{bail, Context, Error};
+
+use crate::capture::{
+    self,
+    CaptureReader,
+    ItemSource,
+    PacketId,
+    TrafficItem,
+    TransactionId,
+    TransferId,
+    FRAMING_EP_NUM,
+    INVALID_EP_NUM,
+};
+use crate::decoder::load_capture;
+use crate::iti1480a;
+use crate::pcap::InputFormat;
+use crate::query::{query, Criteria};
+use crate::search::payload;
+use crate::usb::{self, validate_packet, Direction, PacketFields, PID};
+
+/// A capture, loaded and decoded.
+pub struct Capture {
+    reader: CaptureReader,
+}
+
+/// A packet in a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    index: u64,
+    timestamp: u64,
+    bytes: Vec<u8>,
+}
+
+/// The fields decoded from a packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Fields {
+    /// A start of frame packet.
+    Sof { frame_number: u16 },
+    /// A SETUP, IN, OUT or PING token.
+    Token { device_address: u8, endpoint_number: u8 },
+    /// A data packet, whose payload is given by [Packet::payload].
+    Data,
+    /// An ACK, NAK, NYET, STALL or ERR handshake.
+    Handshake,
+    /// A split transaction token, for a device behind a hub.
+    Split { hub_address: u8, port: u8, complete: bool },
+    /// A packet which could not be decoded.
+    Malformed,
+}
+
+/// An endpoint of a device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    device_address: u8,
+    number: u8,
+    direction: Direction,
+}
+
+/// What a transfer was made of.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TransferKind {
+    /// Transactions on an endpoint.
+    Endpoint(Endpoint),
+    /// A group of start of frame packets.
+    Sof,
+    /// A group of packets which could not be decoded.
+    Malformed,
+}
+
+/// A transfer, or a group of packets shown in its place.
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    id: TransferId,
+    kind: TransferKind,
+    time_range: Range<u64>,
+}
+
+/// A transaction within a transfer.
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    transfer_id: TransferId,
+    id: TransactionId,
+    packet_ids: Range<u64>,
+    time_range: Range<u64>,
+    outcome: Option<PID>,
+    error: bool,
+}
+
+/// Iterator over packets, read as they are reached.
+pub struct Packets {
+    reader: CaptureReader,
+    ids: Range<u64>,
+}
+
+/// Iterator over transfers, read as they are reached.
+pub struct Transfers {
+    reader: CaptureReader,
+    ids: Range<u64>,
+    endpoint: Option<Endpoint>,
+}
+
+/// Iterator over the transactions of transfers, read as they are reached.
+pub struct Transactions {
+    transfers: Transfers,
+    current: Option<(TransferId, Range<u64>)>,
+}
+
+/// The fields of the setup packet starting a control transfer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Setup {
+    /// The bmRequestType field.
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// The number of bytes to be transferred.
+    pub length: u16,
+}
+
+impl Capture {
+    /// Open a capture file in any of the formats the application opens.
+    pub fn open(path: impl AsRef<Path>) -> Result<Capture, Error> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let format = iti1480a::is_capture_name(&path.to_string_lossy())
+            .then_some(InputFormat::Iti1480a);
+        Capture::load(file, format)
+            .with_context(|| format!("Failed to load {}", path.display()))
+    }
+
+    /// Read a capture, detecting its format from its contents.
+    pub fn read(source: impl Read) -> Result<Capture, Error> {
+        Capture::load(source, None)
+    }
+
+    /// Read a capture in a format named as for the command line's
+    /// `--format` option, such as `pcapng`.
+    pub fn read_as(source: impl Read, format: &str)
+        -> Result<Capture, Error>
+    {
+        Capture::load(source, Some(InputFormat::from_name(format)?))
+    }
+
+    fn load(source: impl Read, format: Option<InputFormat>)
+        -> Result<Capture, Error>
+    {
+        Ok(Capture { reader: load_capture(source, format)? })
+    }
+
+    /// Number of packets in the capture.
+    pub fn packet_count(&self) -> u64 {
+        self.reader.packet_index.len()
+    }
+
+    /// A packet, by its index from zero.
+    pub fn packet(&mut self, index: u64) -> Result<Packet, Error> {
+        read_packet(&mut self.reader, index)
+    }
+
+    /// The packets of the capture, in order.
+    pub fn packets(&self) -> Packets {
+        Packets {
+            reader: self.reader.clone(),
+            ids: 0..self.packet_count(),
+        }
+    }
+
+    /// The transfers in the capture, in order of their first packets.
+    pub fn transfers(&self) -> Transfers {
+        self.transfers_on(None)
+    }
+
+    /// The transfers on an endpoint, in order of their first packets.
+    pub fn endpoint_transfers(&self, endpoint: Endpoint) -> Transfers {
+        self.transfers_on(Some(endpoint))
+    }
+
+    fn transfers_on(&self, endpoint: Option<Endpoint>) -> Transfers {
+        Transfers {
+            reader: self.reader.clone(),
+            ids: 0..self.reader.transfer_index.len(),
+            endpoint,
+        }
+    }
+
+    /// The transactions in the capture, transfer by transfer.
+    pub fn transactions(&self) -> Transactions {
+        Transactions::from(self.transfers())
+    }
+
+    /// The transactions on an endpoint, transfer by transfer.
+    pub fn endpoint_transactions(&self, endpoint: Endpoint)
+        -> Transactions
+    {
+        Transactions::from(self.endpoint_transfers(endpoint))
+    }
+
+    /// The transactions making up a transfer.
+    pub fn transfer_transactions(&self, transfer: &Transfer)
+        -> Transactions
+    {
+        let id = transfer.id.value;
+        Transactions::from(Transfers {
+            reader: self.reader.clone(),
+            ids: id..(id + 1),
+            endpoint: None,
+        })
+    }
+
+    /// The packets making up a transaction.
+    pub fn transaction_packets(&self, transaction: &Transaction) -> Packets {
+        Packets {
+            reader: self.reader.clone(),
+            ids: transaction.packet_ids.clone(),
+        }
+    }
+
+    /// The transactions which match some criteria, in the order they
+    /// happened.
+    pub fn query(&mut self, criteria: &Criteria)
+        -> Result<Vec<Transaction>, Error>
+    {
+        query(&mut self.reader, criteria)?
+            .into_iter()
+            .map(|item| read_transaction(&mut self.reader, item))
+            .collect()
+    }
+
+    /// The setup fields of a control transfer, if it is one.
+    pub fn setup(&mut self, transfer: &Transfer)
+        -> Result<Option<Setup>, Error>
+    {
+        Ok(self.reader
+            .item_control_transfer(&transfer.item())?
+            .map(|control| Setup::from(control.fields)))
+    }
+
+    /// The data sent in a transfer, not including the setup packet of a
+    /// control transfer.
+    pub fn transfer_data(&mut self, transfer: &Transfer)
+        -> Result<Vec<u8>, Error>
+    {
+        match self.reader.item_control_transfer(&transfer.item())? {
+            Some(control) => Ok(control.data),
+            None => self.reader.item_bytes(&transfer.item(), usize::MAX),
+        }
+    }
+
+    /// The data sent in the data packets of a transaction.
+    pub fn transaction_data(&mut self, transaction: &Transaction)
+        -> Result<Vec<u8>, Error>
+    {
+        let mut data = Vec::new();
+        for index in transaction.packet_ids.clone() {
+            data.extend(self.packet(index)?.payload().unwrap_or_default());
+        }
+        Ok(data)
+    }
+
+    /// A summary of a transfer, as shown in the application.
+    ///
+    /// The wording of summaries may change between versions.
+    pub fn transfer_summary(&mut self, transfer: &Transfer)
+        -> Result<String, Error>
+    {
+        self.reader.description(&transfer.item(), false)
+    }
+
+    /// A summary of a transaction, as shown in the application.
+    ///
+    /// The wording of summaries may change between versions.
+    pub fn transaction_summary(&mut self, transaction: &Transaction)
+        -> Result<String, Error>
+    {
+        self.reader.description(&transaction.item(), false)
+    }
+}
+
+impl Iterator for Packets {
+    type Item = Result<Packet, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.ids.next()?;
+        Some(read_packet(&mut self.reader, index))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.ids.size_hint()
+    }
+}
+
+impl Transfers {
+    /// Find the next transfer to be listed.
+    fn next_id(&mut self) -> Result<Option<TransferId>, Error> {
+        for id in self.ids.by_ref() {
+            let transfer_id = TransferId::from(id);
+            let entry = self.reader.transfer_index.get(transfer_id)?;
+            // Transfers interleaved with others have further entries
+            // where they continue, but their starts are enough here.
+            if !entry.is_start() {
+                continue;
+            }
+            if let Some(endpoint) = self.endpoint {
+                let kind = transfer_kind(
+                    self.reader.endpoints.get(entry.endpoint_id())?);
+                if kind != TransferKind::Endpoint(endpoint) {
+                    continue;
+                }
+            }
+            return Ok(Some(transfer_id));
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for Transfers {
+    type Item = Result<Transfer, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(match self.next_id() {
+            Ok(Some(transfer_id)) =>
+                read_transfer(&mut self.reader, transfer_id),
+            Ok(None) => return None,
+            Err(e) => Err(e),
+        })
+    }
+}
+
+impl From<Transfers> for Transactions {
+    fn from(transfers: Transfers) -> Self {
+        Transactions { transfers, current: None }
+    }
+}
+
+impl Iterator for Transactions {
+    type Item = Result<Transaction, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((transfer_id, indices)) = &mut self.current {
+                if let Some(index) = indices.next() {
+                    let parent = TrafficItem::Transfer(*transfer_id);
+                    let reader = &mut self.transfers.reader;
+                    return Some(reader
+                        .child_item(&parent, index)
+                        .and_then(|item| read_transaction(reader, item)));
+                }
+            }
+            let transfer_id = match self.transfers.next_id() {
+                Ok(Some(transfer_id)) => transfer_id,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            let parent = TrafficItem::Transfer(transfer_id);
+            match self.transfers.reader.item_children(Some(&parent)) {
+                Ok((_, count)) => self.current = Some((transfer_id, 0..count)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn read_packet(reader: &mut CaptureReader, index: u64)
+    -> Result<Packet, Error>
+{
+    let packet_id = PacketId::from(index);
+    Ok(Packet {
+        index,
+        timestamp: reader.packet_time(packet_id)?,
+        bytes: reader.packet(packet_id)?.to_vec(),
+    })
+}
+
+fn read_transfer(reader: &mut CaptureReader, transfer_id: TransferId)
+    -> Result<Transfer, Error>
+{
+    let item = TrafficItem::Transfer(transfer_id);
+    let endpoint = reader.item_endpoint(&item)?
+        .context("Transfer has no endpoint")?;
+    Ok(Transfer {
+        id: transfer_id,
+        kind: transfer_kind(endpoint),
+        time_range: reader.item_time_range(&item)?,
+    })
+}
+
+fn read_transaction(reader: &mut CaptureReader, item: TrafficItem)
+    -> Result<Transaction, Error>
+{
+    let TrafficItem::Transaction(transfer_id, id) = item else {
+        bail!("Item is not a transaction");
+    };
+    let packet_ids = reader.transaction_index
+        .target_range(id, reader.packet_index.len())?;
+    Ok(Transaction {
+        transfer_id,
+        id,
+        packet_ids: packet_ids.start.value..packet_ids.end.value,
+        time_range: reader.item_time_range(&item)?,
+        outcome: reader.item_result(&item)?,
+        error: reader.item_is_error(&item)?,
+    })
+}
+
+fn transfer_kind(endpoint: capture::Endpoint) -> TransferKind {
+    match endpoint.number() {
+        FRAMING_EP_NUM => TransferKind::Sof,
+        INVALID_EP_NUM => TransferKind::Malformed,
+        _ => TransferKind::Endpoint(Endpoint::from(endpoint)),
+    }
+}
+
+impl Packet {
+    /// Index of the packet in the capture, counting from zero.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Time of the packet in nanoseconds from the start of the capture.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// The bytes of the packet, starting with its PID.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The packet's PID, or [PID::Malformed] if it is not valid.
+    pub fn pid(&self) -> PID {
+        validate_packet(&self.bytes).unwrap_or(PID::Malformed)
+    }
+
+    /// The fields of the packet.
+    pub fn fields(&self) -> Fields {
+        use PID::*;
+        let pid = self.pid();
+        if pid == Malformed {
+            return Fields::Malformed;
+        }
+        match PacketFields::from_packet(&self.bytes) {
+            PacketFields::SOF(sof) => Fields::Sof {
+                frame_number: sof.frame_number(),
+            },
+            PacketFields::Token(token) => Fields::Token {
+                device_address: token.device_address().0,
+                endpoint_number: token.endpoint_number().0,
+            },
+            PacketFields::Split(split) => Fields::Split {
+                hub_address: split.hub_address().0,
+                port: split.port(),
+                complete: split.sc() == usb::StartComplete::Complete,
+            },
+            PacketFields::Data(_) => Fields::Data,
+            PacketFields::None => match pid {
+                DATA2 | MDATA => Fields::Data,
+                ACK | NAK | NYET | STALL | ERR => Fields::Handshake,
+                _ => Fields::Malformed,
+            },
+        }
+    }
+
+    /// The payload of a data packet, without its PID and CRC.
+    pub fn payload(&self) -> Option<&[u8]> {
+        payload(&self.bytes)
+    }
+}
+
+impl Endpoint {
+    pub fn device_address(&self) -> u8 {
+        self.device_address
+    }
+
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// The endpoint's direction. Control transfers on endpoint 0 go both
+    /// ways, and are given as [Direction::Out].
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+}
+
+impl From<capture::Endpoint> for Endpoint {
+    fn from(endpoint: capture::Endpoint) -> Self {
+        Endpoint {
+            device_address: endpoint.device_address().0,
+            number: endpoint.number().0,
+            direction: endpoint.direction(),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{} {}", self.device_address, self.number, self.direction)
+    }
+}
+
+impl Transfer {
+    fn item(&self) -> TrafficItem {
+        TrafficItem::Transfer(self.id)
+    }
+
+    pub fn kind(&self) -> TransferKind {
+        self.kind
+    }
+
+    /// The endpoint of the transfer, unless it is a group of packets not
+    /// sent to one.
+    pub fn endpoint(&self) -> Option<Endpoint> {
+        match self.kind {
+            TransferKind::Endpoint(endpoint) => Some(endpoint),
+            _ => None,
+        }
+    }
+
+    /// Time from the start of the first packet to the end of the last,
+    /// in nanoseconds from the start of the capture.
+    pub fn time_range(&self) -> Range<u64> {
+        self.time_range.clone()
+    }
+}
+
+impl Transaction {
+    /// Index of the transaction in the capture, counting from zero.
+    pub fn index(&self) -> u64 {
+        self.id.value
+    }
+
+    /// The handshake ending the transaction, if it had one.
+    pub fn outcome(&self) -> Option<PID> {
+        self.outcome
+    }
+
+    fn item(&self) -> TrafficItem {
+        TrafficItem::Transaction(self.transfer_id, self.id)
+    }
+
+    /// Whether the transaction is flagged as an error in the application:
+    /// if it had malformed packets, ended with STALL or ERR, or was not
+    /// responded to by the device.
+    pub fn is_error(&self) -> bool {
+        self.error
+    }
+
+    /// Time from the start of the first packet to the end of the last,
+    /// in nanoseconds from the start of the capture.
+    pub fn time_range(&self) -> Range<u64> {
+        self.time_range.clone()
+    }
+}
+
+impl Setup {
+    /// The direction of the data stage, from bit 7 of bmRequestType.
+    pub fn direction(&self) -> Direction {
+        if self.request_type & 0x80 != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+}
+
+impl From<usb::SetupFields> for Setup {
+    fn from(fields: usb::SetupFields) -> Self {
+        Setup {
+            request_type: fields.type_fields.0,
+            request: fields.request,
+            value: fields.value,
+            index: fields.index,
+            length: fields.length,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let mut capture =
+            Capture::open("./tests/mouse/capture.pcap").unwrap();
+        let packets: Vec<Packet> = capture.packets()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(packets.len() as u64, capture.packet_count());
+        assert!(packets.windows(2)
+            .all(|pair| pair[0].timestamp() <= pair[1].timestamp()));
+        let transfers: Vec<Transfer> = capture.transfers()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut counted = 0;
+        let mut control = 0;
+        for transfer in &transfers {
+            for transaction in capture.transfer_transactions(transfer) {
+                let transaction = transaction.unwrap();
+                for packet in capture.transaction_packets(&transaction) {
+                    let packet = packet.unwrap();
+                    assert_eq!(packet, packets[packet.index() as usize]);
+                    counted += 1;
+                }
+            }
+            if let Some(setup) = capture.setup(transfer).unwrap() {
+                let endpoint = transfer.endpoint().unwrap();
+                assert_eq!(endpoint.number(), 0);
+                let data = capture.transfer_data(transfer).unwrap();
+                assert!(data.len() <= setup.length as usize);
+                control += 1;
+            }
+        }
+        assert_eq!(counted, packets.len());
+        assert!(control > 0);
+        let interrupt = transfers.iter()
+            .find(|transfer| transfer.endpoint()
+                .is_some_and(|endpoint| endpoint.number() == 1))
+            .unwrap();
+        assert_eq!(interrupt.endpoint().unwrap().direction(), Direction::In);
+        let data = capture.transfer_data(interrupt).unwrap();
+        let mut transaction_data = Vec::new();
+        for transaction in capture.transfer_transactions(interrupt) {
+            let transaction = transaction.unwrap();
+            transaction_data.extend(
+                capture.transaction_data(&transaction).unwrap());
+        }
+        assert_eq!(data, transaction_data);
+        assert!(capture.transfer_summary(interrupt).unwrap()
+            .contains("interrupt transfer on endpoint 4.1 IN"));
+        let token = packets.iter()
+            .find(|packet| packet.pid() == PID::IN)
+            .unwrap();
+        assert!(matches!(token.fields(), Fields::Token { .. }));
+    }
+
+    #[test]
+    fn test_iterators() {
+        let mut capture =
+            Capture::open("./tests/mouse/capture.pcap").unwrap();
+        let (_, count) = capture.reader
+            .item_children(None::<&TrafficItem>)
+            .unwrap();
+        let mut starts = 0;
+        for index in 0..count {
+            let item = capture.reader.item(None, index).unwrap();
+            if let TrafficItem::Transfer(transfer_id) = item {
+                let entry = capture.reader.transfer_index
+                    .get(transfer_id)
+                    .unwrap();
+                if entry.is_start() {
+                    starts += 1;
+                }
+            }
+        }
+        assert_eq!(capture.transfers().count(), starts);
+        let mut packets = 0;
+        let mut transactions = 0;
+        for transaction in capture.transactions() {
+            let transaction = transaction.unwrap();
+            packets += capture.transaction_packets(&transaction).count();
+            transactions += 1;
+        }
+        assert_eq!(packets as u64, capture.packet_count());
+        assert_eq!(transactions as u64,
+                   capture.reader.transaction_index.len());
+        let mouse = capture.transfers()
+            .map(Result::unwrap)
+            .find_map(|transfer| transfer.endpoint()
+                .filter(|endpoint| endpoint.number() == 1))
+            .unwrap();
+        let mut expected = 0;
+        let mut transfers = 0;
+        for transfer in capture.endpoint_transfers(mouse) {
+            let transfer = transfer.unwrap();
+            assert_eq!(transfer.endpoint(), Some(mouse));
+            expected += capture.transfer_transactions(&transfer).count();
+            transfers += 1;
+        }
+        assert!(transfers > 1);
+        assert_eq!(capture.endpoint_transactions(mouse).count(), expected);
+        let criteria = Criteria {
+            device: Some(mouse.device_address()),
+            endpoint: Some(mouse.number()),
+            direction: Some(mouse.direction()),
+            .. Criteria::default()
+        };
+        let matched = capture.query(&criteria).unwrap();
+        assert_eq!(matched.len(), expected);
+        assert!(matched.windows(2)
+            .all(|pair| pair[0].index() < pair[1].index()));
+    }
+
+    #[test]
+    fn test_fields() {
+        let packet = |bytes: Vec<u8>| Packet {
+            index: 0,
+            timestamp: 0,
+            bytes,
+        };
+        let token = usb::token_packet(
+            PID::IN, usb::DeviceAddr(2), usb::EndpointNum(1));
+        assert_eq!(packet(token).fields(),
+                   Fields::Token { device_address: 2, endpoint_number: 1 });
+        assert_eq!(packet(vec![0xD2]).fields(), Fields::Handshake);
+        let data = packet(usb::data_packet(PID::DATA0, &[1, 2]));
+        assert_eq!(data.fields(), Fields::Data);
+        assert_eq!(data.payload(), Some(&[1, 2][..]));
+        assert_eq!(packet(vec![0x69]).fields(), Fields::Malformed);
+        assert_eq!(packet(vec![]).pid(), PID::Malformed);
+    }
+}
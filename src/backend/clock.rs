@@ -0,0 +1,156 @@
+//! Conversion of analyzer timestamps to a common timeline.
+//!
+//! Analyzers timestamp packets by counting cycles of their own clock, in
+//! counters that wrap or that count cycles since the previous packet. A
+//! [HardwareClock] extends these counts to a timeline in nanoseconds since
+//! the start of capture, so that packet timings reflect those on the bus.
+//!
+//! When capturing from several analyzers, their clocks run at slightly
+//! different rates. A [ClockCorrelator] maps each analyzer's timeline onto
+//! that of the host, correcting for the drift between them.
+
+use std::time::Instant;
+
+/// Cycles of an analyzer's clock, counted to give packet timestamps.
+pub struct HardwareClock {
+    frequency_hz: u64,
+    /// Mask of the bits kept by the analyzer's counter.
+    counter_mask: u64,
+    /// Value of the counter at the previous timestamp.
+    last_count: Option<u64>,
+    /// Cycles counted since the start of capture.
+    total_cycles: u64,
+}
+
+impl HardwareClock {
+    /// A clock of the given frequency, read from a counter of the given
+    /// width in bits.
+    pub fn new(frequency_hz: u64, counter_bits: u32) -> HardwareClock {
+        HardwareClock {
+            frequency_hz,
+            counter_mask: u64::MAX >> (64 - counter_bits),
+            last_count: None,
+            total_cycles: 0,
+        }
+    }
+
+    /// Timestamp in nanoseconds after a number of cycles since the
+    /// previous timestamp.
+    pub fn advance(&mut self, cycles: u64) -> u64 {
+        self.total_cycles += cycles;
+        self.timestamp_ns()
+    }
+
+    /// Timestamp in nanoseconds at a value of the wrapping counter.
+    ///
+    /// The counter must not have wrapped more than once since the
+    /// previous timestamp.
+    pub fn count(&mut self, count: u64) -> u64 {
+        let count = count & self.counter_mask;
+        let cycles = match self.last_count {
+            Some(last) => count.wrapping_sub(last) & self.counter_mask,
+            None => count,
+        };
+        self.last_count = Some(count);
+        self.advance(cycles)
+    }
+
+    /// Timestamp in nanoseconds of the latest cycle counted, rounded down.
+    pub fn timestamp_ns(&self) -> u64 {
+        let ns = self.total_cycles as u128 * 1_000_000_000 /
+            self.frequency_hz as u128;
+        ns as u64
+    }
+}
+
+/// Shortest span of timestamps over which to estimate clock drift.
+const MIN_DRIFT_SPAN_NS: u64 = 10_000_000_000;
+
+/// Largest drift accepted between an analyzer clock and the host, in
+/// parts per million. Larger apparent drifts come from delays on the host.
+const MAX_DRIFT_PPM: f64 = 500.0;
+
+/// Maps an analyzer's timestamps onto the host's clock.
+///
+/// The first packet is placed at the time the host received it, and the
+/// rate of the analyzer's clock relative to the host's is estimated from
+/// the times at which later packets arrive.
+pub struct ClockCorrelator {
+    /// The start of the common timeline.
+    start: Instant,
+    /// Analyzer and host timestamps of the first packet.
+    first: Option<(u64, u64)>,
+    /// Host nanoseconds per analyzer nanosecond.
+    rate: f64,
+    /// The last timestamp returned.
+    last_ns: u64,
+}
+
+impl ClockCorrelator {
+    pub fn new(start: Instant) -> ClockCorrelator {
+        ClockCorrelator {
+            start,
+            first: None,
+            rate: 1.0,
+            last_ns: 0,
+        }
+    }
+
+    /// Map an analyzer timestamp of a packet just received.
+    pub fn correlate(&mut self, timestamp_ns: u64) -> u64 {
+        let host_ns = self.start.elapsed().as_nanos() as u64;
+        self.correlate_at(timestamp_ns, host_ns)
+    }
+
+    /// Map an analyzer timestamp of a packet received at the given time.
+    fn correlate_at(&mut self, timestamp_ns: u64, host_ns: u64) -> u64 {
+        let (first_timestamp, first_host) =
+            *self.first.get_or_insert((timestamp_ns, host_ns));
+        let span = timestamp_ns.saturating_sub(first_timestamp);
+        if span >= MIN_DRIFT_SPAN_NS {
+            // Delays in receiving packets matter less as the span grows.
+            let host_span = host_ns.saturating_sub(first_host);
+            let rate = host_span as f64 / span as f64;
+            let max_drift = MAX_DRIFT_PPM / 1e6;
+            self.rate = rate.clamp(1.0 - max_drift, 1.0 + max_drift);
+        }
+        let mapped = first_host + (span as f64 * self.rate) as u64;
+        // Keep the timeline in order as the estimate is refined.
+        self.last_ns = self.last_ns.max(mapped);
+        self.last_ns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hardware_clock() {
+        // A 24-bit counter at 60MHz, wrapping between the packets.
+        let mut clock = HardwareClock::new(60_000_000, 24);
+        assert_eq!(clock.count(0xFFFFF0), 279_620_000);
+        assert_eq!(clock.count(0x000010), 279_620_533);
+
+        // Cycle counts since the previous packet, at 12MHz.
+        let mut clock = HardwareClock::new(12_000_000, 16);
+        assert_eq!(clock.advance(3), 250);
+        assert_eq!(clock.advance(0xFFFF), 5_461_500);
+    }
+
+    #[test]
+    fn test_clock_correlator() {
+        let mut correlator = ClockCorrelator::new(Instant::now());
+        // The first packet is placed when it was received.
+        assert_eq!(correlator.correlate_at(1_000, 5_000), 5_000);
+        // The analyzer's clock runs 100ppm slow, and is corrected for once
+        // enough time has passed.
+        assert_eq!(correlator.correlate_at(1_001_000, 1_006_000), 1_005_000);
+        assert_eq!(
+            correlator.correlate_at(20_000_001_000, 20_002_005_000),
+            20_002_005_000);
+        assert_eq!(
+            correlator.correlate_at(30_000_001_000, 30_003_005_000),
+            30_003_005_000);
+    }
+}
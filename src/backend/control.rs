@@ -0,0 +1,103 @@
+//! Control of a capture in progress.
+//!
+//! A [CaptureControl] can be used from any thread to pause and resume a
+//! capture, and to add markers to its timeline. The thread decoding the
+//! capture polls the matching [ControlReceiver] as packets arrive, so a
+//! marker is placed before the next packet received after it was added.
+
+use std::sync::mpsc;
+
+enum Request {
+    Pause,
+    Resume,
+    Mark(String),
+}
+
+/// Handle for controlling a capture in progress.
+#[derive(Clone)]
+pub struct CaptureControl {
+    tx: mpsc::Sender<Request>,
+}
+
+/// Receives the requests made through a [CaptureControl].
+pub struct ControlReceiver {
+    rx: mpsc::Receiver<Request>,
+    paused: bool,
+}
+
+/// Create a connected control handle and receiver.
+pub fn capture_control() -> (CaptureControl, ControlReceiver) {
+    let (tx, rx) = mpsc::channel();
+    (CaptureControl { tx }, ControlReceiver { rx, paused: false })
+}
+
+impl CaptureControl {
+    /// Stop adding packets to the capture until resumed.
+    pub fn pause(&self) {
+        self.send(Request::Pause);
+    }
+
+    /// Add packets to the capture again after pausing.
+    pub fn resume(&self) {
+        self.send(Request::Resume);
+    }
+
+    /// Add a marker with the given text to the timeline.
+    pub fn mark(&self, text: impl Into<String>) {
+        self.send(Request::Mark(text.into()));
+    }
+
+    fn send(&self, request: Request) {
+        // If the capture has already ended, there is nothing to control.
+        let _ = self.tx.send(request);
+    }
+}
+
+impl ControlReceiver {
+    /// Whether packets are currently being discarded.
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Take the next marker to be added, applying any pause or resume
+    /// requested before it. Pausing and resuming add markers of their own.
+    pub fn next_marker(&mut self) -> Option<String> {
+        loop {
+            match self.rx.try_recv().ok()? {
+                Request::Pause if !self.paused => {
+                    self.paused = true;
+                    return Some("Capture paused".to_string());
+                },
+                Request::Resume if self.paused => {
+                    self.paused = false;
+                    return Some("Capture resumed".to_string());
+                },
+                Request::Mark(text) => return Some(text),
+                Request::Pause | Request::Resume => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_control() {
+        let (control, mut receiver) = capture_control();
+        assert_eq!(receiver.next_marker(), None);
+        control.pause();
+        control.pause();
+        control.mark("Button pressed");
+        assert_eq!(receiver.next_marker().as_deref(), Some("Capture paused"));
+        assert!(receiver.paused());
+        assert_eq!(receiver.next_marker().as_deref(), Some("Button pressed"));
+        assert_eq!(receiver.next_marker(), None);
+        control.resume();
+        control.resume();
+        assert_eq!(receiver.next_marker().as_deref(), Some("Capture resumed"));
+        assert!(!receiver.paused());
+        assert_eq!(receiver.next_marker(), None);
+    }
+}
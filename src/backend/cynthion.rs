@@ -7,67 +7,57 @@ use std::sync::mpsc;
 use anyhow::{Context as ErrorContext, Error, bail};
 use futures_channel::oneshot;
 use futures_lite::future::block_on;
-use futures_util::future::FusedFuture;
-use futures_util::{select_biased, FutureExt};
-use num_enum::{FromPrimitive, IntoPrimitive};
 use nusb::{
     self,
     transfer::{
         Control,
         ControlType,
-        Queue,
         Recipient,
-        RequestBuffer,
-        TransferError,
     },
     DeviceInfo,
     Interface
 };
 
+use super::{
+    BackendDevice,
+    BackendHandle,
+    BackendStop,
+    BusEvent,
+    CaptureBackend,
+    CaptureHandles,
+    PacketStream,
+    ResultHandler,
+    Speed,
+    SpeedDetector,
+    TimestampedPacket,
+    TransferQueue,
+    clock::HardwareClock,
+    handle_thread_panic,
+};
+
 const VID: u16 = 0x1d50;
 const PID: u16 = 0x615b;
 
+/// Frequency of the clock timestamping packets.
+const CLOCK_HZ: u64 = 60_000_000;
+
 const CLASS: u8 = 0xff;
 const SUBCLASS: u8 = 0x10;
 const PROTOCOL: u8 = 0x01;
 
 const ENDPOINT: u8 = 0x81;
 
+// Codes of the events reported by the analyzer gateware.
+const EVENT_SPEED_DETECT_HIGH: u8 = 8;
+const EVENT_SPEED_DETECT_FULL: u8 = 9;
+const EVENT_SPEED_DETECT_LOW: u8 = 10;
+const EVENT_BUS_RESET: u8 = 13;
+const EVENT_DEVICE_CHIRP_VALID: u8 = 14;
+const EVENT_HOST_CHIRP_VALID: u8 = 15;
+
 const READ_LEN: usize = 0x4000;
 const NUM_TRANSFERS: usize = 4;
 
-#[derive(Copy, Clone, FromPrimitive, IntoPrimitive)]
-#[repr(u8)]
-pub enum Speed {
-    #[default]
-    High = 0,
-    Full = 1,
-    Low  = 2,
-    Auto = 3,
-}
-
-impl Speed {
-    pub fn description(&self) -> &'static str {
-        use Speed::*;
-        match self {
-            Auto => "Auto",
-            High => "High (480Mbps)",
-            Full => "Full (12Mbps)",
-            Low => "Low (1.5Mbps)",
-        }
-    }
-
-    pub fn mask(&self) -> u8 {
-        use Speed::*;
-        match self {
-            Auto => 0b0001,
-            Low  => 0b0010,
-            Full => 0b0100,
-            High => 0b1000,
-        }
-    }
-}
-
 bitfield! {
     #[derive(Copy, Clone)]
     struct State(u8);
@@ -134,16 +124,12 @@ pub struct CynthionHandle {
     interface: Interface,
 }
 
-pub struct CynthionQueue {
-    tx: mpsc::Sender<Vec<u8>>,
-    queue: Queue<RequestBuffer>,
-}
-
 pub struct CynthionStream {
     receiver: mpsc::Receiver<Vec<u8>>,
     buffer: VecDeque<u8>,
     padding_due: bool,
-    total_clk_cycles: u64,
+    clock: HardwareClock,
+    speed_detector: SpeedDetector,
 }
 
 pub struct CynthionStop {
@@ -151,17 +137,18 @@ pub struct CynthionStop {
     worker: JoinHandle::<()>,
 }
 
-pub struct CynthionPacket {
-    pub timestamp_ns: u64,
-    pub bytes: Vec<u8>,
-}
-
-/// Convert 60MHz clock cycles to nanoseconds, rounding down.
-fn clk_to_ns(clk_cycles: u64) -> u64 {
-    const TABLE: [u64; 3] = [0, 16, 33];
-    let quotient = clk_cycles / 3;
-    let remainder = clk_cycles % 3;
-    quotient * 50 + TABLE[remainder as usize]
+/// Interpret an event code from the analyzer gateware.
+fn bus_event(code: u8) -> Option<BusEvent> {
+    use BusEvent::*;
+    match code {
+        EVENT_SPEED_DETECT_HIGH => Some(SpeedDetected(Speed::High)),
+        EVENT_SPEED_DETECT_FULL => Some(SpeedDetected(Speed::Full)),
+        EVENT_SPEED_DETECT_LOW => Some(SpeedDetected(Speed::Low)),
+        EVENT_BUS_RESET => Some(BusReset),
+        EVENT_DEVICE_CHIRP_VALID => Some(DeviceChirp),
+        EVENT_HOST_CHIRP_VALID => Some(HostChirp),
+        _ => None,
+    }
 }
 
 /// Check whether a Cynthion device has an accessible analyzer interface.
@@ -236,26 +223,40 @@ fn check_device(device_info: &DeviceInfo)
     bail!("No supported analyzer interface found");
 }
 
+/// The backend for Cynthion devices.
+pub struct Cynthion;
+
+impl CaptureBackend for Cynthion {
+    fn matches(&self, info: &DeviceInfo) -> bool {
+        info.vendor_id() == VID && info.product_id() == PID
+    }
+
+    fn probe(&self, device_info: DeviceInfo) -> Box<dyn BackendDevice> {
+        Box::new(CynthionDevice::probe(device_info))
+    }
+}
+
 impl CynthionDevice {
     pub fn scan() -> Result<Vec<CynthionDevice>, Error> {
         Ok(nusb::list_devices()?
-            .filter(|info| info.vendor_id() == VID)
-            .filter(|info| info.product_id() == PID)
-            .map(|device_info|
-                match check_device(&device_info) {
-                    Ok((iface, speeds)) => CynthionDevice {
-                        device_info,
-                        usability: Usable(iface, speeds)
-                    },
-                    Err(err) => CynthionDevice {
-                        device_info,
-                        usability: Unusable(format!("{}", err))
-                    }
-                }
-            )
+            .filter(|info| Cynthion.matches(info))
+            .map(CynthionDevice::probe)
             .collect())
     }
 
+    fn probe(device_info: DeviceInfo) -> CynthionDevice {
+        match check_device(&device_info) {
+            Ok((iface, speeds)) => CynthionDevice {
+                device_info,
+                usability: Usable(iface, speeds)
+            },
+            Err(err) => CynthionDevice {
+                device_info,
+                usability: Unusable(format!("{}", err))
+            }
+        }
+    }
+
     pub fn open(&self) -> Result<CynthionHandle, Error> {
         match &self.usability {
             Usable(iface, _) => {
@@ -271,6 +272,37 @@ impl CynthionDevice {
     }
 }
 
+impl BackendDevice for CynthionDevice {
+    fn name(&self) -> &'static str {
+        "Cynthion"
+    }
+
+    fn device_info(&self) -> &DeviceInfo {
+        &self.device_info
+    }
+
+    fn speeds(&self) -> Result<&[Speed], &str> {
+        match &self.usability {
+            Usable(_, speeds) => Ok(speeds),
+            Unusable(reason) => Err(reason),
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn BackendHandle>, Error> {
+        Ok(Box::new(CynthionDevice::open(self)?))
+    }
+}
+
+impl BackendHandle for CynthionHandle {
+    fn start(&self, speed: Speed, result_handler: ResultHandler)
+        -> Result<CaptureHandles, Error>
+    {
+        let (stream, stop) =
+            CynthionHandle::start(self, speed, result_handler)?;
+        Ok((Box::new(stream), Box::new(stop)))
+    }
+}
+
 impl CynthionHandle {
 
     pub fn speeds(&self) -> Result<Vec<Speed>, Error> {
@@ -318,7 +350,8 @@ impl CynthionHandle {
                 receiver: rx,
                 buffer: VecDeque::new(),
                 padding_due: false,
-                total_clk_cycles: 0,
+                clock: HardwareClock::new(CLOCK_HZ, 16),
+                speed_detector: SpeedDetector::default(),
             },
             CynthionStop {
                 stop_request: stop_tx,
@@ -340,7 +373,8 @@ impl CynthionHandle {
         self.start_capture(speed)?;
 
         // Set up transfer queue.
-        let mut queue = CynthionQueue::new(&self.interface, tx);
+        let mut queue = TransferQueue::new(
+            &self.interface, ENDPOINT, READ_LEN, NUM_TRANSFERS, tx);
 
         // Spawn a worker thread to process queue until stopped.
         let worker = spawn(move || block_on(queue.process(queue_stop_rx)));
@@ -401,62 +435,10 @@ impl CynthionHandle {
     }
 }
 
-impl CynthionQueue {
-
-    fn new(interface: &Interface, tx: mpsc::Sender<Vec<u8>>)
-        -> CynthionQueue
-    {
-        let mut queue = interface.bulk_in_queue(ENDPOINT);
-        while queue.pending() < NUM_TRANSFERS {
-            queue.submit(RequestBuffer::new(READ_LEN));
-        }
-        CynthionQueue { queue, tx }
-    }
-
-    async fn process(&mut self, mut stop: oneshot::Receiver<()>)
-        -> Result<(), Error>
-    {
-        use TransferError::Cancelled;
-        loop {
-            select_biased!(
-                _ = stop => {
-                    // Stop requested. Cancel all transfers.
-                    self.queue.cancel_all();
-                }
-                completion = self.queue.next_complete().fuse() => {
-                    match completion.status {
-                        Ok(()) => {
-                            // Send data to decoder thread.
-                            self.tx.send(completion.data)
-                                .context("Failed sending capture data to channel")?;
-                            if !stop.is_terminated() {
-                                // Submit next transfer.
-                                self.queue.submit(RequestBuffer::new(READ_LEN));
-                            }
-                        },
-                        Err(Cancelled) if stop.is_terminated() => {
-                            // Transfer cancelled during shutdown. Drop it.
-                            drop(completion);
-                            if self.queue.pending() == 0 {
-                                // All cancellations now handled.
-                                return Ok(());
-                            }
-                        },
-                        Err(usb_error) => {
-                            // Transfer failed.
-                            return Err(Error::from(usb_error));
-                        }
-                    }
-                }
-            );
-        }
-    }
-}
-
 impl Iterator for CynthionStream {
-    type Item = CynthionPacket;
+    type Item = TimestampedPacket;
 
-    fn next(&mut self) -> Option<CynthionPacket> {
+    fn next(&mut self) -> Option<TimestampedPacket> {
         loop {
             // Do we have another packet already in the buffer?
             match self.next_buffered_packet() {
@@ -475,7 +457,7 @@ impl Iterator for CynthionStream {
 }
 
 impl CynthionStream {
-    fn next_buffered_packet(&mut self) -> Option<CynthionPacket> {
+    fn next_buffered_packet(&mut self) -> Option<TimestampedPacket> {
         // Are we waiting for a padding byte?
         if self.padding_due {
             if self.buffer.is_empty() {
@@ -495,7 +477,9 @@ impl CynthionStream {
 
             if self.buffer[0] == 0xFF {
                 // This is an event.
-                let _event_code = self.buffer[1];
+                if let Some(event) = bus_event(self.buffer[1]) {
+                    self.speed_detector.handle(event);
+                }
 
                 // Update our cycle count.
                 self.update_cycle_count();
@@ -527,8 +511,9 @@ impl CynthionStream {
         }
 
         // Remove the rest of the packet from the buffer and return it.
-        Some(CynthionPacket {
-            timestamp_ns: clk_to_ns(self.total_clk_cycles),
+        Some(TimestampedPacket {
+            source: 0,
+            timestamp_ns: self.clock.timestamp_ns(),
             bytes: self.buffer.drain(0..packet_len).collect()
         })
     }
@@ -539,7 +524,19 @@ impl CynthionStream {
             [self.buffer[2], self.buffer[3]]);
 
         // Update our running total.
-        self.total_clk_cycles += clk_cycles as u64;
+        self.clock.advance(clk_cycles as u64);
+    }
+}
+
+impl PacketStream for CynthionStream {
+    fn speed(&self) -> Option<Speed> {
+        self.speed_detector.speed()
+    }
+}
+
+impl BackendStop for CynthionStop {
+    fn stop(self: Box<Self>) -> Result<(), Error> {
+        CynthionStop::stop(*self)
     }
 }
 
@@ -553,19 +550,50 @@ impl CynthionStop {
     }
 }
 
-fn handle_thread_panic<T>(result: std::thread::Result<T>) -> Result<T, Error> {
-    match result {
-        Ok(x) => Ok(x),
-        Err(panic) => {
-            let msg = match (
-                panic.downcast_ref::<&str>(),
-                panic.downcast_ref::<String>())
-            {
-                (Some(&s), _) => s,
-                (_,  Some(s)) => s,
-                (None,  None) => "<No panic message>"
-            };
-            bail!("Worker thread panic: {msg}");
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SpeedCheck;
+
+    #[test]
+    fn test_speed_detection() {
+        let (tx, rx) = mpsc::channel();
+        let mut stream = CynthionStream {
+            receiver: rx,
+            buffer: VecDeque::new(),
+            padding_due: false,
+            clock: HardwareClock::new(CLOCK_HZ, 16),
+            speed_detector: SpeedDetector::default(),
+        };
+        // A bus reset and chirps, followed by a SOF packet.
+        tx.send(vec![
+            0xFF, EVENT_BUS_RESET, 0x00, 0x03,
+            0xFF, EVENT_DEVICE_CHIRP_VALID, 0x00, 0x03,
+            0xFF, EVENT_HOST_CHIRP_VALID, 0x00, 0x03,
+            0x00, 0x03, 0x00, 0x03, 0xA5, 0x00, 0x10, 0x00,
+        ]).unwrap();
+        drop(tx);
+        let packet = stream.next().unwrap();
+        assert_eq!(packet.bytes, [0xA5, 0x00, 0x10]);
+        assert_eq!(packet.timestamp_ns, 200);
+        assert_eq!(stream.speed(), Some(Speed::High));
+        assert!(stream.next().is_none());
+
+        // Chirps not following each other after a reset mean nothing.
+        let mut detector = SpeedDetector::default();
+        detector.handle(BusEvent::DeviceChirp);
+        detector.handle(BusEvent::BusReset);
+        detector.handle(BusEvent::HostChirp);
+        assert_eq!(detector.speed(), None);
+        detector.handle(BusEvent::SpeedDetected(Speed::Full));
+        assert_eq!(detector.speed(), Some(Speed::Full));
+
+        // Mostly invalid packets suggest the wrong speed was chosen.
+        let mut check = SpeedCheck::default();
+        let warnings = (0..1000)
+            .map(|i| if i % 8 == 0 { [0xD2] } else { [0x12] })
+            .filter(|packet| check.check(packet))
+            .count();
+        assert_eq!(warnings, 1);
     }
 }
@@ -0,0 +1,200 @@
+//! Filters restricting a capture to particular devices or endpoints.
+//!
+//! A filter is given as text, made up of terms separated by whitespace,
+//! each naming the device addresses or endpoint numbers to capture:
+//!
+//! ```text
+//! address=5,7 endpoint=1
+//! ```
+//!
+//! Terms of the same kind are combined, so that a packet is captured if
+//! it matches any of the addresses given, and any of the endpoints given.
+//! Analyzers which cannot filter in hardware are filtered in software as
+//! packets arrive.
+
+use anyhow::{Context, Error, bail};
+
+use crate::usb::{PID, PacketFields, validate_packet};
+
+use super::{PacketStream, Speed, TimestampedPacket};
+
+/// Which packets to capture.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaptureFilter {
+    /// Device addresses to capture, or all if empty.
+    pub addresses: Vec<u8>,
+    /// Endpoint numbers to capture, or all if empty.
+    pub endpoints: Vec<u8>,
+}
+
+impl CaptureFilter {
+    /// Parse a filter from text.
+    pub fn parse(text: &str) -> Result<CaptureFilter, Error> {
+        let mut filter = CaptureFilter::default();
+        for term in text.split_whitespace() {
+            let (key, values) = term
+                .split_once('=')
+                .with_context(|| format!("Invalid filter term '{term}'"))?;
+            let (list, limit) = match key {
+                "address" => (&mut filter.addresses, 127),
+                "endpoint" => (&mut filter.endpoints, 15),
+                _ => bail!("Unknown filter '{key}', \
+                            expected 'address' or 'endpoint'"),
+            };
+            for value in values.split(',') {
+                let number: u8 = value.parse().with_context(||
+                    format!("Invalid {key} '{value}' in filter"))?;
+                if number > limit {
+                    bail!("The {key} must be no more than {limit}");
+                }
+                list.push(number);
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether the filter captures everything.
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty() && self.endpoints.is_empty()
+    }
+
+    /// Whether a transaction to the given device and endpoint is captured.
+    fn matches(&self, address: u8, endpoint: u8) -> bool {
+        (self.addresses.is_empty() || self.addresses.contains(&address)) &&
+        (self.endpoints.is_empty() || self.endpoints.contains(&endpoint))
+    }
+}
+
+/// Filters the packets from an analyzer in software.
+///
+/// Tokens are matched against the filter, and the data and handshake
+/// packets that follow a token are kept or dropped with it. SOF packets
+/// are always kept, so that the timing of the bus remains visible.
+pub struct FilteredStream {
+    inner: Box<dyn PacketStream>,
+    filter: CaptureFilter,
+    /// Whether the current transaction is being kept.
+    keeping: bool,
+    /// A split token, kept or dropped along with the token following it.
+    split: Option<TimestampedPacket>,
+    /// A packet to be returned next.
+    queued: Option<TimestampedPacket>,
+}
+
+impl FilteredStream {
+    pub fn new(inner: Box<dyn PacketStream>, filter: CaptureFilter)
+        -> FilteredStream
+    {
+        FilteredStream {
+            inner,
+            filter,
+            keeping: true,
+            split: None,
+            queued: None,
+        }
+    }
+}
+
+impl Iterator for FilteredStream {
+    type Item = TimestampedPacket;
+
+    fn next(&mut self) -> Option<TimestampedPacket> {
+        if let Some(packet) = self.queued.take() {
+            return Some(packet);
+        }
+        loop {
+            let packet = self.inner.next()?;
+            let pid = match validate_packet(&packet.bytes) {
+                Ok(pid) => pid,
+                // Keep or drop invalid packets along with their neighbours.
+                Err(_) => PID::Malformed,
+            };
+            match (pid, PacketFields::from_packet(&packet.bytes)) {
+                (PID::SOF, _) => return Some(packet),
+                (PID::SPLIT, _) => {
+                    self.split = Some(packet);
+                    continue;
+                },
+                (_, PacketFields::Token(token)) => {
+                    self.keeping = self.filter.matches(
+                        token.device_address().0,
+                        token.endpoint_number().0);
+                    if !self.keeping {
+                        self.split = None;
+                        continue;
+                    }
+                    if let Some(split) = self.split.take() {
+                        self.queued = Some(packet);
+                        return Some(split);
+                    }
+                    return Some(packet);
+                },
+                _ if self.keeping => return Some(packet),
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl PacketStream for FilteredStream {
+    fn drops(&self) -> u64 {
+        self.inner.drops()
+    }
+
+    fn take_losses(&mut self) -> Vec<Option<u64>> {
+        self.inner.take_losses()
+    }
+
+    fn speed(&self) -> Option<Speed> {
+        self.inner.speed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestStream(std::vec::IntoIter<Vec<u8>>);
+
+    impl Iterator for TestStream {
+        type Item = TimestampedPacket;
+
+        fn next(&mut self) -> Option<TimestampedPacket> {
+            let bytes = self.0.next()?;
+            Some(TimestampedPacket { source: 0, timestamp_ns: 0, bytes })
+        }
+    }
+
+    impl PacketStream for TestStream {}
+
+    #[test]
+    fn test_capture_filter() {
+        assert_eq!(CaptureFilter::parse("address=5,7 endpoint=1").unwrap(),
+                   CaptureFilter {
+                       addresses: vec![5, 7],
+                       endpoints: vec![1],
+                   });
+        assert!(CaptureFilter::parse("").unwrap().is_empty());
+        assert!(CaptureFilter::parse("address=128").is_err());
+        assert!(CaptureFilter::parse("device=1").is_err());
+        assert!(CaptureFilter::parse("endpoint").is_err());
+
+        let sof = vec![0xA5, 0x00, 0x10];
+        // IN tokens to address 5, endpoint 1, and address 6, endpoint 1.
+        let in_5_1 = vec![0x69, 0x85, 0x58];
+        let in_6_1 = vec![0x69, 0x86, 0x18];
+        let data = vec![0xC3, 0x00, 0x00];
+        let ack = vec![0xD2];
+        let packets = vec![
+            sof.clone(),
+            in_6_1.clone(), data.clone(), ack.clone(),
+            in_5_1.clone(), data.clone(), ack.clone(),
+            in_6_1.clone(), vec![0x5A],
+        ];
+        let filter = CaptureFilter::parse("address=5").unwrap();
+        let stream = FilteredStream::new(
+            Box::new(TestStream(packets.into_iter())), filter);
+        let kept: Vec<Vec<u8>> = stream.map(|packet| packet.bytes).collect();
+        assert_eq!(kept, vec![sof, in_5_1, data, ack]);
+    }
+}
@@ -0,0 +1,391 @@
+//! Capture from a GreatFET One running packet capture firmware.
+//!
+//! Commands are sent to the GreatFET using the libgreat protocol: each is
+//! a vendor request carrying the numbers of a class and a verb, followed
+//! by any arguments, after which the response is read back with another
+//! vendor request.
+//!
+//! Once started, the firmware sends captured packets on a bulk endpoint.
+//! Each is preceded by its length as a big-endian 16-bit value, and the
+//! number of cycles of a 12MHz clock since the previous packet, also as a
+//! big-endian 16-bit value. A record with a length of 0xFFFF carries no
+//! packet, and only advances the clock.
+
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::thread::{spawn, sleep, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{Context as ErrorContext, Error, bail};
+use futures_channel::oneshot;
+use futures_lite::future::block_on;
+use nusb::{
+    self,
+    transfer::{
+        Control,
+        ControlType,
+        Recipient,
+    },
+    DeviceInfo,
+    Interface
+};
+
+use super::{
+    BackendDevice,
+    BackendHandle,
+    BackendStop,
+    CaptureBackend,
+    CaptureHandles,
+    PacketStream,
+    ResultHandler,
+    Speed,
+    TimestampedPacket,
+    TransferQueue,
+    clock::HardwareClock,
+    handle_thread_panic,
+};
+
+const VID: u16 = 0x1d50;
+const PID: u16 = 0x60e6;
+
+/// Frequency of the clock timestamping packets.
+const CLOCK_HZ: u64 = 12_000_000;
+
+const INTERFACE: u8 = 0;
+const ENDPOINT: u8 = 0x81;
+
+/// Vendor request used for all libgreat commands.
+const LIBGREAT_REQUEST: u8 = 0x65;
+const LIBGREAT_EXECUTE: u16 = 0;
+
+/// Class implemented by the packet capture firmware, and its verbs.
+const CLASS_USB_ANALYZER: u32 = 0x10A;
+const VERB_SPEEDS: u32 = 0;
+const VERB_START: u32 = 1;
+const VERB_STOP: u32 = 2;
+
+/// Length value marking a record which only advances the clock.
+const TIMESTAMP_ONLY: u16 = 0xFFFF;
+
+const READ_LEN: usize = 0x4000;
+const NUM_TRANSFERS: usize = 4;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether a GreatFET is ready for use as an analyzer.
+pub enum GreatFetUsability {
+    /// Device is usable, at the given speeds.
+    Usable(Vec<Speed>),
+    /// Device not usable, with a string explaining why.
+    Unusable(String),
+}
+
+use GreatFetUsability::*;
+
+/// A GreatFET attached to the system.
+pub struct GreatFetDevice {
+    pub device_info: DeviceInfo,
+    pub usability: GreatFetUsability,
+}
+
+/// A handle to an open GreatFET.
+#[derive(Clone)]
+pub struct GreatFetHandle {
+    interface: Interface,
+}
+
+pub struct GreatFetStream {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    buffer: VecDeque<u8>,
+    clock: HardwareClock,
+}
+
+pub struct GreatFetStop {
+    stop_request: oneshot::Sender<()>,
+    worker: JoinHandle::<()>,
+}
+
+/// Speed numbers used by the firmware.
+fn speed_number(speed: Speed) -> Result<u32, Error> {
+    use Speed::*;
+    match speed {
+        Full => Ok(1),
+        Low => Ok(2),
+        High | Auto =>
+            bail!("GreatFET cannot capture at {}", speed.description()),
+    }
+}
+
+/// The backend for GreatFET devices.
+pub struct GreatFet;
+
+impl CaptureBackend for GreatFet {
+    fn matches(&self, info: &DeviceInfo) -> bool {
+        info.vendor_id() == VID && info.product_id() == PID
+    }
+
+    fn probe(&self, device_info: DeviceInfo) -> Box<dyn BackendDevice> {
+        let usability = match check_device(&device_info) {
+            Ok(speeds) => Usable(speeds),
+            Err(err) => Unusable(format!("{}", err)),
+        };
+        Box::new(GreatFetDevice { device_info, usability })
+    }
+}
+
+impl BackendDevice for GreatFetDevice {
+    fn name(&self) -> &'static str {
+        "GreatFET"
+    }
+
+    fn device_info(&self) -> &DeviceInfo {
+        &self.device_info
+    }
+
+    fn speeds(&self) -> Result<&[Speed], &str> {
+        match &self.usability {
+            Usable(speeds) => Ok(speeds),
+            Unusable(reason) => Err(reason),
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn BackendHandle>, Error> {
+        match &self.usability {
+            Usable(_) => {
+                let device = self.device_info.open()?;
+                let interface = device.claim_interface(INTERFACE)?;
+                Ok(Box::new(GreatFetHandle { interface }))
+            },
+            Unusable(reason) => bail!("Device not usable: {}", reason),
+        }
+    }
+}
+
+/// Check whether a GreatFET is running packet capture firmware.
+fn check_device(device_info: &DeviceInfo) -> Result<Vec<Speed>, Error> {
+    let device = device_info
+        .open()
+        .context("Failed to open device")?;
+    let interface = device
+        .claim_interface(INTERFACE)
+        .context("Failed to claim interface")?;
+    GreatFetHandle { interface }.speeds()
+}
+
+impl BackendHandle for GreatFetHandle {
+    fn start(&self, speed: Speed, result_handler: ResultHandler)
+        -> Result<CaptureHandles, Error>
+    {
+        let (stream, stop) =
+            GreatFetHandle::start(self, speed, result_handler)?;
+        Ok((Box::new(stream), Box::new(stop)))
+    }
+}
+
+impl GreatFetHandle {
+
+    /// Execute a libgreat command, returning its response.
+    fn execute(&self, verb: u32, args: &[u8]) -> Result<Vec<u8>, Error> {
+        let control = || Control {
+            control_type: ControlType::Vendor,
+            recipient: Recipient::Endpoint,
+            request: LIBGREAT_REQUEST,
+            value: LIBGREAT_EXECUTE,
+            index: 0,
+        };
+        let mut command = Vec::with_capacity(8 + args.len());
+        command.extend(CLASS_USB_ANALYZER.to_le_bytes());
+        command.extend(verb.to_le_bytes());
+        command.extend_from_slice(args);
+        self.interface
+            .control_out_blocking(control(), &command, TIMEOUT)
+            .context("Failed sending command to GreatFET")?;
+        let mut response = [0; 64];
+        let size = self.interface
+            .control_in_blocking(control(), &mut response, TIMEOUT)
+            .context("Failed reading response from GreatFET")?;
+        Ok(response[..size].to_vec())
+    }
+
+    pub fn speeds(&self) -> Result<Vec<Speed>, Error> {
+        use Speed::*;
+        let response = self.execute(VERB_SPEEDS, &[])
+            .context("GreatFET firmware does not support packet capture")?;
+        let [mask] = response[..] else {
+            bail!("Expected 1-byte response to speed request, got {}",
+                  response.len());
+        };
+        Ok([Full, Low]
+            .into_iter()
+            .filter(|speed| mask & speed.mask() != 0)
+            .collect())
+    }
+
+    pub fn start<F>(&self, speed: Speed, result_handler: F)
+        -> Result<(GreatFetStream, GreatFetStop), Error>
+        where F: FnOnce(Result<(), Error>) + Send + 'static
+    {
+        // Check the speed before starting the worker.
+        speed_number(speed)?;
+        // Channel to pass captured data to the decoder thread.
+        let (tx, rx) = mpsc::channel();
+        // Channel to stop the capture thread on request.
+        let (stop_tx, stop_rx) = oneshot::channel();
+        // Clone handle to give to the worker thread.
+        let handle = self.clone();
+        // Start worker thread.
+        let worker = spawn(move ||
+            result_handler(
+                handle.run_capture(speed, tx, stop_rx)));
+        Ok((
+            GreatFetStream {
+                receiver: rx,
+                buffer: VecDeque::new(),
+                clock: HardwareClock::new(CLOCK_HZ, 16),
+            },
+            GreatFetStop {
+                stop_request: stop_tx,
+                worker,
+            }
+        ))
+    }
+
+    fn run_capture(self,
+                   speed: Speed,
+                   tx: mpsc::Sender<Vec<u8>>,
+                   stop: oneshot::Receiver<()>)
+        -> Result<(), Error>
+    {
+        // Set up a separate channel pair to stop queue processing.
+        let (queue_stop_tx, queue_stop_rx) = oneshot::channel();
+
+        // Start capture.
+        self.execute(VERB_START, &speed_number(speed)?.to_le_bytes())
+            .context("Failed to start capture")?;
+        println!("Capture enabled, speed: {}", speed.description());
+
+        // Set up transfer queue.
+        let mut queue = TransferQueue::new(
+            &self.interface, ENDPOINT, READ_LEN, NUM_TRANSFERS, tx);
+
+        // Spawn a worker thread to process queue until stopped.
+        let worker = spawn(move || block_on(queue.process(queue_stop_rx)));
+
+        // Wait until this thread is signalled to stop.
+        block_on(stop)
+            .context("Sender was dropped")?;
+
+        // Stop capture.
+        self.execute(VERB_STOP, &[])
+            .context("Failed to stop capture")?;
+        println!("Capture disabled");
+
+        // Leave queue worker running briefly to receive flushed data.
+        sleep(Duration::from_millis(100));
+
+        // Signal queue processing to stop, then join the worker thread.
+        queue_stop_tx.send(())
+            .or_else(|_| bail!("Failed sending stop signal to queue worker"))?;
+        handle_thread_panic(worker.join())?
+            .context("Error in queue worker thread")?;
+
+        Ok(())
+    }
+}
+
+impl Iterator for GreatFetStream {
+    type Item = TimestampedPacket;
+
+    fn next(&mut self) -> Option<TimestampedPacket> {
+        loop {
+            // Do we have another packet already in the buffer?
+            match self.next_buffered_packet() {
+                // Yes; return the packet.
+                Some(packet) => return Some(packet),
+                // No; wait for more data from the capture thread.
+                None => match self.receiver.recv().ok() {
+                    // Received more data; add it to the buffer and retry.
+                    Some(bytes) => self.buffer.extend(bytes.iter()),
+                    // Capture has ended, there are no more packets.
+                    None => return None
+                }
+            }
+        }
+    }
+}
+
+impl GreatFetStream {
+    fn next_buffered_packet(&mut self) -> Option<TimestampedPacket> {
+        loop {
+            // Do we have the length and timestamp for the next record?
+            if self.buffer.len() < 4 {
+                return None;
+            }
+            let length = u16::from_be_bytes([self.buffer[0], self.buffer[1]]);
+            let clk_cycles = u16::from_be_bytes(
+                [self.buffer[2], self.buffer[3]]) as u64;
+            if length == TIMESTAMP_ONLY {
+                // Update our cycle count, and move on to the next record.
+                self.clock.advance(clk_cycles);
+                self.buffer.drain(0..4);
+                continue;
+            }
+
+            // Do we have all the data for the next packet?
+            let length = length as usize;
+            if self.buffer.len() < 4 + length {
+                return None;
+            }
+
+            // Update our cycle count, and remove the packet from the buffer.
+            let timestamp_ns = self.clock.advance(clk_cycles);
+            self.buffer.drain(0..4);
+            return Some(TimestampedPacket {
+                source: 0,
+                timestamp_ns,
+                bytes: self.buffer.drain(0..length).collect(),
+            });
+        }
+    }
+}
+
+impl PacketStream for GreatFetStream {}
+
+impl BackendStop for GreatFetStop {
+    fn stop(self: Box<Self>) -> Result<(), Error> {
+        println!("Requesting capture stop");
+        self.stop_request.send(())
+            .or_else(|_| bail!("Failed sending stop request"))?;
+        handle_thread_panic(self.worker.join())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_greatfet_stream() {
+        let (tx, rx) = mpsc::channel();
+        // A SETUP token, a clock update, then a DATA0 packet split across
+        // two transfers.
+        tx.send(vec![0x00, 0x03, 0x00, 0x0C, 0x2D, 0x00, 0x10,
+                     0xFF, 0xFF, 0xFF, 0xFF,
+                     0x00, 0x03, 0x00, 0x18, 0xC3]).unwrap();
+        tx.send(vec![0x00, 0x00]).unwrap();
+        drop(tx);
+        let stream = GreatFetStream {
+            receiver: rx,
+            buffer: VecDeque::new(),
+            clock: HardwareClock::new(CLOCK_HZ, 16),
+        };
+        let packets: Vec<(u64, Vec<u8>)> = stream
+            .map(|packet| (packet.timestamp_ns, packet.bytes))
+            .collect();
+        assert_eq!(packets, vec![
+            (1000, vec![0x2D, 0x00, 0x10]),
+            (5464250, vec![0xC3, 0x00, 0x00]),
+        ]);
+        assert!(speed_number(Speed::High).is_err());
+    }
+}
@@ -1 +1,388 @@
+//! Capture from USB analyzer hardware.
+//!
+//! Each kind of supported analyzer has a [CaptureBackend], which picks out
+//! its devices among those attached to the system. A device is opened
+//! through the [BackendDevice] trait, and captures through the
+//! [BackendHandle] trait, so that the UI need not know which kinds of
+//! device it is using.
+
+use std::collections::HashSet;
+use std::sync::mpsc;
+
+use anyhow::{Context, Error, bail};
+use futures_channel::oneshot;
+use futures_lite::stream::{self, BlockOn};
+use futures_util::future::FusedFuture;
+use futures_util::{select_biased, FutureExt};
+use num_enum::{FromPrimitive, IntoPrimitive};
+use nusb::{
+    hotplug::{HotplugEvent, HotplugWatch},
+    transfer::{
+        Queue,
+        RequestBuffer,
+        TransferError,
+    },
+    DeviceId,
+    DeviceInfo,
+    Interface
+};
+
+pub mod clock;
+pub mod control;
 pub mod cynthion;
+pub mod filter;
+pub mod greatfet;
+pub mod multi;
+pub mod openvizsla;
+pub mod rolling;
+pub mod trigger;
+
+use filter::{CaptureFilter, FilteredStream};
+
+/// Backends for each kind of supported analyzer.
+pub static BACKENDS: &[&dyn CaptureBackend] = &[
+    &cynthion::Cynthion,
+    &greatfet::GreatFet,
+    &openvizsla::OpenVizsla,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum Speed {
+    #[default]
+    High = 0,
+    Full = 1,
+    Low  = 2,
+    Auto = 3,
+}
+
+impl Speed {
+    pub const ALL: [Speed; 4] =
+        [Speed::High, Speed::Full, Speed::Low, Speed::Auto];
+
+    pub fn description(&self) -> &'static str {
+        use Speed::*;
+        match self {
+            Auto => "Auto",
+            High => "High (480Mbps)",
+            Full => "Full (12Mbps)",
+            Low => "Low (1.5Mbps)",
+        }
+    }
+
+    /// Short name of the speed, as used in the preferences file.
+    pub fn name(&self) -> &'static str {
+        use Speed::*;
+        match self {
+            Auto => "auto",
+            High => "high",
+            Full => "full",
+            Low => "low",
+        }
+    }
+
+    /// The speed with a short name.
+    pub fn from_name(name: &str) -> Option<Speed> {
+        Speed::ALL.into_iter().find(|speed| speed.name() == name)
+    }
+
+    pub fn mask(&self) -> u8 {
+        use Speed::*;
+        match self {
+            Auto => 0b0001,
+            Low  => 0b0010,
+            Full => 0b0100,
+            High => 0b1000,
+        }
+    }
+}
+
+/// Events on the bus reported by an analyzer, besides packets.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BusEvent {
+    /// The analyzer determined the speed of the bus.
+    SpeedDetected(Speed),
+    /// The host reset the bus.
+    BusReset,
+    /// The device sent a valid chirp, offering to run at high speed.
+    DeviceChirp,
+    /// The host answered with a valid chirp, accepting high speed.
+    HostChirp,
+}
+
+/// Follows the speed of the bus from the events reported on it.
+#[derive(Default)]
+pub struct SpeedDetector {
+    device_chirp: bool,
+    speed: Option<Speed>,
+}
+
+impl SpeedDetector {
+    pub fn handle(&mut self, event: BusEvent) {
+        use BusEvent::*;
+        match event {
+            SpeedDetected(speed) => self.speed = Some(speed),
+            BusReset => self.device_chirp = false,
+            DeviceChirp => self.device_chirp = true,
+            // A device and host which have exchanged chirps after a reset
+            // have switched to high speed.
+            HostChirp if self.device_chirp => self.speed = Some(Speed::High),
+            HostChirp => {},
+        }
+    }
+
+    /// The speed of the bus, if known.
+    pub fn speed(&self) -> Option<Speed> {
+        self.speed
+    }
+}
+
+/// Number of packets checked to see if the capture speed is wrong.
+const SPEED_CHECK_PACKETS: usize = 256;
+
+/// Checks whether the first packets captured are mostly invalid, which
+/// is what is seen when capturing at the wrong speed.
+#[derive(Default)]
+pub struct SpeedCheck {
+    checked: usize,
+    valid: usize,
+}
+
+impl SpeedCheck {
+    /// Check the next packet, returning true once if enough packets have
+    /// been seen to suggest the speed is wrong.
+    pub fn check(&mut self, packet: &[u8]) -> bool {
+        if self.checked >= SPEED_CHECK_PACKETS {
+            return false;
+        }
+        self.checked += 1;
+        if crate::usb::validate_packet(packet).is_ok() {
+            self.valid += 1;
+        }
+        self.checked == SPEED_CHECK_PACKETS &&
+            self.valid < SPEED_CHECK_PACKETS / 4
+    }
+}
+
+/// A packet captured by an analyzer.
+pub struct TimestampedPacket {
+    /// Which analyzer captured the packet, when capturing from several.
+    pub source: u8,
+    pub timestamp_ns: u64,
+    pub bytes: Vec<u8>,
+}
+
+/// Called with the result of a capture when it ends.
+pub type ResultHandler = Box<dyn FnOnce(Result<(), Error>) + Send>;
+
+/// The packets of a capture, and the means of stopping it.
+pub type CaptureHandles = (Box<dyn PacketStream>, Box<dyn BackendStop>);
+
+/// A kind of analyzer.
+pub trait CaptureBackend: Sync {
+    /// Whether a USB device is an analyzer of this kind.
+    fn matches(&self, info: &DeviceInfo) -> bool;
+
+    /// Check whether an analyzer of this kind is ready for use.
+    fn probe(&self, info: DeviceInfo) -> Box<dyn BackendDevice>;
+}
+
+/// An analyzer attached to the system.
+pub trait BackendDevice: Send {
+    /// Name of this kind of analyzer.
+    fn name(&self) -> &'static str;
+
+    fn device_info(&self) -> &DeviceInfo;
+
+    /// Speeds at which the device can capture, or why it can't be used.
+    fn speeds(&self) -> Result<&[Speed], &str>;
+
+    fn open(&self) -> Result<Box<dyn BackendHandle>, Error>;
+}
+
+/// A handle to an open analyzer.
+pub trait BackendHandle: Send {
+    /// Start capturing at the given speed.
+    fn start(&self, speed: Speed, result_handler: ResultHandler)
+        -> Result<CaptureHandles, Error>;
+
+    /// Filter packets in hardware before capture starts, returning false
+    /// if the analyzer is unable to do so.
+    fn set_filter(&self, _filter: &CaptureFilter) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    /// Start capturing at the given speed, keeping only the packets
+    /// matching a filter.
+    fn start_filtered(&self,
+                      speed: Speed,
+                      filter: CaptureFilter,
+                      result_handler: ResultHandler)
+        -> Result<CaptureHandles, Error>
+    {
+        if filter.is_empty() || self.set_filter(&filter)? {
+            return self.start(speed, result_handler);
+        }
+        let (stream, stop) = self.start(speed, result_handler)?;
+        Ok((Box::new(FilteredStream::new(stream, filter)), stop))
+    }
+}
+
+/// The packets captured by an analyzer, ending when capture stops.
+pub trait PacketStream: Iterator<Item=TimestampedPacket> + Send {
+    /// Number of times packets have been lost so far.
+    fn drops(&self) -> u64 {
+        0
+    }
+
+    /// Take the losses reported since this was last called, which came
+    /// before the packet last returned, or after the last packet once the
+    /// stream has ended. Each gives the number of packets lost, if the
+    /// analyzer knows it.
+    fn take_losses(&mut self) -> Vec<Option<u64>> {
+        Vec::new()
+    }
+
+    /// Speed of the bus, if the analyzer has detected it.
+    fn speed(&self) -> Option<Speed> {
+        None
+    }
+}
+
+/// A means of stopping a capture.
+pub trait BackendStop: Send {
+    fn stop(self: Box<Self>) -> Result<(), Error>;
+}
+
+fn backend_for(info: &DeviceInfo) -> Option<&'static dyn CaptureBackend> {
+    BACKENDS.iter().copied().find(|backend| backend.matches(info))
+}
+
+/// Find all analyzers attached to the system.
+pub fn scan() -> Result<Vec<Box<dyn BackendDevice>>, Error> {
+    Ok(nusb::list_devices()?
+        .filter_map(|info| Some(backend_for(&info)?.probe(info)))
+        .collect())
+}
+
+/// Watches for analyzers being connected and disconnected.
+#[cfg_attr(test, allow(dead_code))]
+pub struct DeviceWatcher {
+    events: BlockOn<HotplugWatch>,
+    known: HashSet<DeviceId>,
+}
+
+// The UI does not watch for analyzers when built for tests.
+#[cfg_attr(test, allow(dead_code))]
+impl DeviceWatcher {
+    pub fn new() -> Result<DeviceWatcher, Error> {
+        let watch = nusb::watch_devices()?;
+        let known = nusb::list_devices()?
+            .filter(|info| backend_for(info).is_some())
+            .map(|info| info.id())
+            .collect();
+        Ok(DeviceWatcher { events: stream::block_on(watch), known })
+    }
+
+    /// Wait until an analyzer is connected or disconnected.
+    pub fn wait(&mut self) -> Result<(), Error> {
+        for event in self.events.by_ref() {
+            match event {
+                HotplugEvent::Connected(info)
+                    if backend_for(&info).is_some() =>
+                {
+                    self.known.insert(info.id());
+                    return Ok(());
+                },
+                HotplugEvent::Disconnected(id) if self.known.remove(&id) =>
+                    return Ok(()),
+                _ => {}
+            }
+        }
+        bail!("Device watch ended")
+    }
+}
+
+/// A queue of bulk IN transfers, passing their data to a channel.
+pub struct TransferQueue {
+    tx: mpsc::Sender<Vec<u8>>,
+    queue: Queue<RequestBuffer>,
+    read_len: usize,
+}
+
+impl TransferQueue {
+    /// Submit transfers on an endpoint, ready for processing.
+    pub fn new(interface: &Interface,
+               endpoint: u8,
+               read_len: usize,
+               num_transfers: usize,
+               tx: mpsc::Sender<Vec<u8>>)
+        -> TransferQueue
+    {
+        let mut queue = interface.bulk_in_queue(endpoint);
+        while queue.pending() < num_transfers {
+            queue.submit(RequestBuffer::new(read_len));
+        }
+        TransferQueue { queue, tx, read_len }
+    }
+
+    /// Process completed transfers until stopped.
+    pub async fn process(&mut self, mut stop: oneshot::Receiver<()>)
+        -> Result<(), Error>
+    {
+        use TransferError::Cancelled;
+        loop {
+            select_biased!(
+                _ = stop => {
+                    // Stop requested. Cancel all transfers.
+                    self.queue.cancel_all();
+                }
+                completion = self.queue.next_complete().fuse() => {
+                    match completion.status {
+                        Ok(()) => {
+                            // Send data to decoder thread.
+                            self.tx.send(completion.data)
+                                .context("Failed sending capture data to channel")?;
+                            if !stop.is_terminated() {
+                                // Submit next transfer.
+                                self.queue.submit(
+                                    RequestBuffer::new(self.read_len));
+                            }
+                        },
+                        Err(Cancelled) if stop.is_terminated() => {
+                            // Transfer cancelled during shutdown. Drop it.
+                            drop(completion);
+                            if self.queue.pending() == 0 {
+                                // All cancellations now handled.
+                                return Ok(());
+                            }
+                        },
+                        Err(usb_error) => {
+                            // Transfer failed.
+                            return Err(Error::from(usb_error));
+                        }
+                    }
+                }
+            );
+        }
+    }
+}
+
+pub fn handle_thread_panic<T>(result: std::thread::Result<T>)
+    -> Result<T, Error>
+{
+    match result {
+        Ok(x) => Ok(x),
+        Err(panic) => {
+            let msg = match (
+                panic.downcast_ref::<&str>(),
+                panic.downcast_ref::<String>())
+            {
+                (Some(&s), _) => s,
+                (_,  Some(s)) => s,
+                (None,  None) => "<No panic message>"
+            };
+            bail!("Worker thread panic: {msg}");
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Capture from several analyzers at once.
+//!
+//! Each analyzer's packets are read by their own thread, and merged into
+//! one stream in timestamp order, marked with the analyzer they came from.
+//! Each analyzer's timestamps are mapped onto the host's clock, so they
+//! are aligned to within the time taken to receive packets from them.
+
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+
+use super::{
+    BackendHandle,
+    BackendStop,
+    CaptureHandles,
+    PacketStream,
+    Speed,
+    TimestampedPacket,
+    clock::ClockCorrelator,
+    filter::CaptureFilter,
+};
+
+/// Start capturing on one or more analyzers, merging their packets if
+/// there are several.
+pub fn start_all<H>(handles: &[Box<dyn BackendHandle>],
+                    speed: Speed,
+                    filter: &CaptureFilter,
+                    result_handler: H)
+    -> Result<CaptureHandles, Error>
+    where H: Fn(Result<(), Error>) + Clone + Send + 'static
+{
+    let mut streams = Vec::with_capacity(handles.len());
+    let mut stops = Vec::with_capacity(handles.len());
+    for handle in handles {
+        let started = handle.start_filtered(
+            speed, filter.clone(), Box::new(result_handler.clone()));
+        match started {
+            Ok((stream, stop)) => {
+                streams.push(stream);
+                stops.push(stop);
+            },
+            Err(err) => {
+                // Stop any analyzers already started.
+                let _ = Box::new(MultiStop(stops)).stop();
+                return Err(err);
+            }
+        }
+    }
+    if streams.len() == 1 {
+        Ok((streams.remove(0), stops.remove(0)))
+    } else {
+        Ok((Box::new(MultiStream::new(streams)), Box::new(MultiStop(stops))))
+    }
+}
+
+/// Longest time to hold back packets while waiting for an idle analyzer.
+const MAX_DELAY: Duration = Duration::from_millis(100);
+
+/// Losses reported by an analyzer, as returned by
+/// [PacketStream::take_losses].
+type Losses = Vec<Option<u64>>;
+
+enum Message {
+    /// A packet, with any losses before it.
+    Packet(TimestampedPacket, Losses),
+    /// The stream ended, having lost packets this many times, with any
+    /// losses after the last packet.
+    End(u64, Losses),
+}
+
+/// The merged packets of several analyzers.
+pub struct MultiStream {
+    rx: mpsc::Receiver<(u8, Message)>,
+    /// Packets received from each analyzer, not yet returned.
+    pending: Vec<VecDeque<(TimestampedPacket, Losses)>>,
+    /// Whether each analyzer's stream has ended.
+    ended: Vec<bool>,
+    /// When packets started being held back waiting for an analyzer.
+    waiting_since: Option<Instant>,
+    /// Timestamp of the last packet returned.
+    last_ns: u64,
+    drops: u64,
+    /// Losses before the packets returned, not yet taken.
+    losses: Losses,
+}
+
+impl MultiStream {
+    pub fn new(streams: Vec<Box<dyn PacketStream>>) -> MultiStream {
+        let (tx, rx) = mpsc::channel();
+        let count = streams.len();
+        let start = Instant::now();
+        for (index, mut stream) in streams.into_iter().enumerate() {
+            let tx = tx.clone();
+            // Each thread ends when its analyzer is stopped.
+            spawn(move || {
+                let source = index as u8;
+                let mut correlator = ClockCorrelator::new(start);
+                while let Some(mut packet) = stream.next() {
+                    packet.source = source;
+                    packet.timestamp_ns =
+                        correlator.correlate(packet.timestamp_ns);
+                    let losses = stream.take_losses();
+                    let message = Message::Packet(packet, losses);
+                    if tx.send((source, message)).is_err() {
+                        return;
+                    }
+                }
+                let losses = stream.take_losses();
+                let message = Message::End(stream.drops(), losses);
+                let _ = tx.send((source, message));
+            });
+        }
+        MultiStream {
+            rx,
+            pending: (0..count).map(|_| VecDeque::new()).collect(),
+            ended: vec![false; count],
+            waiting_since: None,
+            last_ns: 0,
+            drops: 0,
+            losses: Vec::new(),
+        }
+    }
+
+    /// Take the earliest packet received, if any.
+    fn take_earliest(&mut self) -> Option<TimestampedPacket> {
+        let queue = self.pending
+            .iter_mut()
+            .filter(|queue| !queue.is_empty())
+            .min_by_key(|queue| queue[0].0.timestamp_ns)?;
+        let (mut packet, losses) = queue.pop_front()?;
+        self.losses.extend(losses);
+        // A packet from an analyzer that was waited on for too long may
+        // be earlier than one already returned, so keep time in order.
+        packet.timestamp_ns = packet.timestamp_ns.max(self.last_ns);
+        self.last_ns = packet.timestamp_ns;
+        Some(packet)
+    }
+}
+
+impl Iterator for MultiStream {
+    type Item = TimestampedPacket;
+
+    fn next(&mut self) -> Option<TimestampedPacket> {
+        loop {
+            // Packets can be returned in order once every analyzer still
+            // capturing has sent one.
+            let waiting = self.pending
+                .iter()
+                .zip(self.ended.iter())
+                .any(|(queue, ended)| queue.is_empty() && !ended);
+            let have_packets = self.pending
+                .iter()
+                .any(|queue| !queue.is_empty());
+            if !waiting {
+                self.waiting_since = None;
+                return self.take_earliest();
+            }
+            let timeout = if have_packets {
+                let since =
+                    *self.waiting_since.get_or_insert_with(Instant::now);
+                match MAX_DELAY.checked_sub(since.elapsed()) {
+                    Some(remaining) => remaining,
+                    None => return self.take_earliest(),
+                }
+            } else {
+                MAX_DELAY
+            };
+            match self.rx.recv_timeout(timeout) {
+                Ok((source, Message::Packet(packet, losses))) =>
+                    self.pending[source as usize].push_back((packet, losses)),
+                Ok((source, Message::End(drops, losses))) => {
+                    self.ended[source as usize] = true;
+                    self.drops += drops;
+                    self.losses.extend(losses);
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(mpsc::RecvTimeoutError::Disconnected) =>
+                    self.ended.fill(true),
+            }
+        }
+    }
+}
+
+impl PacketStream for MultiStream {
+    fn drops(&self) -> u64 {
+        self.drops
+    }
+
+    fn take_losses(&mut self) -> Vec<Option<u64>> {
+        std::mem::take(&mut self.losses)
+    }
+}
+
+/// Stops capture on several analyzers.
+pub struct MultiStop(Vec<Box<dyn BackendStop>>);
+
+impl BackendStop for MultiStop {
+    fn stop(self: Box<Self>) -> Result<(), Error> {
+        let mut result = Ok(());
+        for stop in self.0 {
+            let stopped = stop.stop();
+            if result.is_ok() {
+                result = stopped;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestStream(std::vec::IntoIter<u64>);
+
+    impl Iterator for TestStream {
+        type Item = TimestampedPacket;
+
+        fn next(&mut self) -> Option<TimestampedPacket> {
+            let timestamp_ns = self.0.next()?;
+            Some(TimestampedPacket {
+                source: 0,
+                timestamp_ns,
+                bytes: vec![0xA5, 0x00, 0x10],
+            })
+        }
+    }
+
+    impl PacketStream for TestStream {
+        fn drops(&self) -> u64 {
+            1
+        }
+
+        fn take_losses(&mut self) -> Vec<Option<u64>> {
+            // Report a loss before the second to last packet.
+            if self.0.len() == 1 { vec![Some(2)] } else { Vec::new() }
+        }
+    }
+
+    #[test]
+    fn test_multi_stream() {
+        let streams: Vec<Box<dyn PacketStream>> = vec![
+            Box::new(TestStream(vec![10, 20, 30].into_iter())),
+            Box::new(TestStream(vec![5, 25].into_iter())),
+        ];
+        let mut stream = MultiStream::new(streams);
+        let mut merged = Vec::new();
+        let mut losses = Vec::new();
+        while let Some(packet) = stream.next() {
+            merged.push((packet.source, packet.timestamp_ns));
+            losses.extend(stream.take_losses());
+        }
+        // Timestamps are mapped to when packets were received, so only
+        // their order can be checked.
+        assert_eq!(merged.len(), 5);
+        assert!(merged.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(merged.iter().filter(|(source, _)| *source == 0).count(), 3);
+        assert_eq!(stream.drops(), 2);
+        assert_eq!(losses, vec![Some(2); 2]);
+    }
+}
@@ -0,0 +1,797 @@
+//! Capture from OpenVizsla hardware.
+//!
+//! The OpenVizsla's FPGA is connected to the host through an FT2232H. The
+//! FPGA is configured through the second channel of the FT2232H, which
+//! drives its slave serial configuration pins in MPSSE mode. The first
+//! channel then runs in synchronous FIFO mode, carrying register accesses
+//! to the FPGA and, once capture is started, packet records in the same
+//! format as the packet logs saved by the OpenVizsla host tools.
+//!
+//! The FPGA bitstream is not included with Packetry. It is loaded from the
+//! firmware package built for the OpenVizsla, which also holds the map of
+//! the registers of that build of the gateware.
+
+use std::collections::VecDeque;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread::{spawn, sleep, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{Context as ErrorContext, Error, bail};
+use futures_channel::oneshot;
+use futures_lite::future::block_on;
+use nusb::{
+    self,
+    transfer::{
+        Control,
+        ControlType,
+        Recipient,
+        RequestBuffer,
+    },
+    DeviceInfo,
+    Interface
+};
+
+use crate::openvizsla::{HEADER_SIZE, MAGIC, Parser, packet_length};
+
+use super::{
+    BackendDevice,
+    BackendHandle,
+    BackendStop,
+    CaptureBackend,
+    CaptureHandles,
+    PacketStream,
+    ResultHandler,
+    Speed,
+    TimestampedPacket,
+    TransferQueue,
+    handle_thread_panic,
+};
+
+const VID: u16 = 0x1d50;
+const PID: u16 = 0x607c;
+
+/// Environment variable giving the path of the firmware package.
+pub const FIRMWARE_VARIABLE: &str = "PACKETRY_OPENVIZSLA_FIRMWARE";
+
+/// Firmware package used if the environment variable is not set.
+const FIRMWARE_DEFAULT: &str = "ov3.fwpkg";
+
+/// Names of the bitstream and register map in the firmware package.
+const BITSTREAM_NAME: &str = "ov3.bit";
+const MAP_NAME: &str = "map.txt";
+
+// Vendor requests of the FT2232H.
+const SIO_RESET: u8 = 0x00;
+const SIO_SET_LATENCY_TIMER: u8 = 0x09;
+const SIO_SET_BITMODE: u8 = 0x0B;
+
+const RESET_SIO: u16 = 0;
+const RESET_PURGE_RX: u16 = 1;
+const RESET_PURGE_TX: u16 = 2;
+
+const BITMODE_RESET: u8 = 0x00;
+const BITMODE_MPSSE: u8 = 0x02;
+const BITMODE_SYNCFF: u8 = 0x40;
+
+/// Size of the FT2232H's bulk IN packets, which each start with two
+/// bytes of modem status.
+const FTDI_PACKET_SIZE: usize = 512;
+const FTDI_STATUS_SIZE: usize = 2;
+
+// MPSSE commands.
+const MPSSE_WRITE_BYTES: u8 = 0x11;
+const MPSSE_SET_LOW: u8 = 0x80;
+const MPSSE_GET_LOW: u8 = 0x81;
+const MPSSE_DISABLE_LOOPBACK: u8 = 0x85;
+const MPSSE_SET_DIVISOR: u8 = 0x86;
+const MPSSE_SEND_IMMEDIATE: u8 = 0x87;
+const MPSSE_DISABLE_DIV5: u8 = 0x8A;
+const MPSSE_CLOCK_BYTES: u8 = 0x8F;
+
+// Pins of the second channel, connected to the FPGA's configuration pins.
+const PIN_CCLK: u8 = 0x01;
+const PIN_DIN: u8 = 0x02;
+const PIN_PROGRAM: u8 = 0x10;
+const PIN_INIT: u8 = 0x20;
+const PIN_DONE: u8 = 0x40;
+
+/// Largest block of bitstream sent in one MPSSE command.
+const BITSTREAM_CHUNK: usize = 0x1000;
+
+/// First byte of each register access, and of its response.
+const REGISTER_MAGIC: u8 = 0x55;
+const REGISTER_SIZE: usize = 5;
+const REGISTER_WRITE: u16 = 0x8000;
+
+// ULPI function control register, written through the gateware.
+const ULPI_FUNCTION_CONTROL: u8 = 0x04;
+const ULPI_WRITE: u8 = 0x80;
+
+/// Size of the ring buffer in the OpenVizsla's SDRAM.
+const RING_SIZE: u32 = 16 * 1024 * 1024;
+
+const CHANNEL_A: u8 = 0;
+const CHANNEL_B: u8 = 1;
+const ENDPOINT_A_IN: u8 = 0x81;
+const ENDPOINT_A_OUT: u8 = 0x02;
+const ENDPOINT_B_IN: u8 = 0x83;
+const ENDPOINT_B_OUT: u8 = 0x04;
+
+/// Speeds at which the OpenVizsla can capture.
+const SPEEDS: [Speed; 3] = [Speed::High, Speed::Full, Speed::Low];
+
+const READ_LEN: usize = 0x4000;
+const NUM_TRANSFERS: usize = 4;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A register of the OpenVizsla gateware.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Register {
+    address: u16,
+    size: u16,
+}
+
+/// The contents of an OpenVizsla firmware package.
+pub struct Firmware {
+    bitstream: Vec<u8>,
+    registers: Vec<(String, Register)>,
+}
+
+/// Whether an OpenVizsla device is ready for use as an analyzer.
+pub enum OpenVizslaUsability {
+    /// Device is usable, with the firmware to be loaded.
+    Usable(Arc<Firmware>),
+    /// Device not usable, with a string explaining why.
+    Unusable(String),
+}
+
+use OpenVizslaUsability::*;
+
+/// An OpenVizsla device attached to the system.
+pub struct OpenVizslaDevice {
+    pub device_info: DeviceInfo,
+    pub usability: OpenVizslaUsability,
+}
+
+/// A handle to an open OpenVizsla device, with its FPGA configured.
+#[derive(Clone)]
+pub struct OpenVizslaHandle {
+    interface: Interface,
+    firmware: Arc<Firmware>,
+}
+
+pub struct OpenVizslaStream {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    buffer: VecDeque<u8>,
+    parser: Parser,
+    /// Number of overflows already returned by `take_losses`.
+    overflows_reported: u64,
+}
+
+pub struct OpenVizslaStop {
+    stop_request: oneshot::Sender<()>,
+    worker: JoinHandle::<()>,
+}
+
+/// Path of the firmware package to load.
+pub fn firmware_path() -> PathBuf {
+    std::env::var_os(FIRMWARE_VARIABLE)
+        .map_or_else(|| PathBuf::from(FIRMWARE_DEFAULT), PathBuf::from)
+}
+
+impl Firmware {
+    /// Load a firmware package.
+    pub fn load(path: &Path) -> Result<Firmware, Error> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!(
+                "Failed to open OpenVizsla firmware package {}. \
+                 Set {FIRMWARE_VARIABLE} to its location.",
+                path.display()))?;
+        let mut package = zip::ZipArchive::new(file)
+            .context("Failed to read OpenVizsla firmware package")?;
+        let mut bitfile = Vec::new();
+        package.by_name(BITSTREAM_NAME)
+            .context("No bitstream in OpenVizsla firmware package")?
+            .read_to_end(&mut bitfile)?;
+        let mut map = String::new();
+        package.by_name(MAP_NAME)
+            .context("No register map in OpenVizsla firmware package")?
+            .read_to_string(&mut map)?;
+        Ok(Firmware {
+            bitstream: bitstream_data(&bitfile)?.to_vec(),
+            registers: parse_map(&map)?,
+        })
+    }
+
+    fn register(&self, name: &str) -> Result<Register, Error> {
+        self.registers
+            .iter()
+            .find(|(reg_name, _)| reg_name.eq_ignore_ascii_case(name))
+            .map(|(_, register)| *register)
+            .with_context(|| format!("No register {name} in gateware"))
+    }
+}
+
+/// Find the configuration data in a Xilinx bitstream file.
+///
+/// The file starts with a header of fields naming the design, part and
+/// date, followed by a field holding the data to be loaded.
+fn bitstream_data(file: &[u8]) -> Result<&[u8], Error> {
+    fn take<'a>(data: &mut &'a [u8], length: usize)
+        -> Result<&'a [u8], Error>
+    {
+        if data.len() < length {
+            bail!("Bitstream file is truncated");
+        }
+        let (taken, rest) = data.split_at(length);
+        *data = rest;
+        Ok(taken)
+    }
+    let mut data = file;
+    // Skip the initial field, and the length of the first key.
+    let length = u16::from_be_bytes(take(&mut data, 2)?.try_into()?);
+    take(&mut data, length as usize + 2)?;
+    loop {
+        match take(&mut data, 1)?[0] {
+            b'e' => {
+                let length = take(&mut data, 4)?.try_into()?;
+                return take(&mut data, u32::from_be_bytes(length) as usize);
+            },
+            b'a'..=b'd' => {
+                let length = take(&mut data, 2)?.try_into()?;
+                take(&mut data, u16::from_be_bytes(length) as usize)?;
+            },
+            key => bail!("Unexpected field {key:02X} in bitstream file"),
+        }
+    }
+}
+
+/// Parse the register map of a firmware package.
+///
+/// Each line gives the name of a register and its address, optionally
+/// followed by its size in bytes.
+fn parse_map(map: &str) -> Result<Vec<(String, Register)>, Error> {
+    let mut registers = Vec::new();
+    for line in map.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line
+            .split(|c: char| c.is_whitespace() || c == '=')
+            .filter(|field| !field.is_empty());
+        let (Some(name), Some(address)) = (fields.next(), fields.next())
+        else {
+            bail!("Invalid register map entry '{line}'");
+        };
+        let parse = |value: &str| match value.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => value.parse(),
+        };
+        let address = parse(address)
+            .with_context(|| format!("Invalid address for {name}"))?;
+        let size = match fields.next() {
+            Some(size) => parse(size)
+                .with_context(|| format!("Invalid size for {name}"))?,
+            None => 1,
+        };
+        registers.push((name.to_string(), Register { address, size }));
+    }
+    Ok(registers)
+}
+
+/// Strip the modem status from the data of a bulk IN transfer.
+fn strip_status(data: &[u8]) -> impl Iterator<Item=&u8> {
+    data.chunks(FTDI_PACKET_SIZE)
+        .flat_map(|packet| packet.get(FTDI_STATUS_SIZE..).unwrap_or(&[]))
+}
+
+fn ftdi_request(interface: &Interface, request: u8, value: u16)
+    -> Result<(), Error>
+{
+    let control = Control {
+        control_type: ControlType::Vendor,
+        recipient: Recipient::Device,
+        request,
+        value,
+        // The FT2232H numbers its channels from one.
+        index: interface.interface_number() as u16 + 1,
+    };
+    interface.control_out_blocking(control, &[], TIMEOUT)
+        .context("FTDI request failed")?;
+    Ok(())
+}
+
+fn set_bitmode(interface: &Interface, mode: u8, mask: u8)
+    -> Result<(), Error>
+{
+    ftdi_request(interface, SIO_SET_BITMODE, u16::from_be_bytes([mode, mask]))
+}
+
+fn bulk_write(interface: &Interface, endpoint: u8, data: Vec<u8>)
+    -> Result<(), Error>
+{
+    block_on(interface.bulk_out(endpoint, data))
+        .into_result()
+        .context("Failed writing to device")?;
+    Ok(())
+}
+
+fn bulk_read(interface: &Interface, endpoint: u8) -> Result<Vec<u8>, Error> {
+    let data = block_on(
+        interface.bulk_in(endpoint, RequestBuffer::new(FTDI_PACKET_SIZE)))
+        .into_result()
+        .context("Failed reading from device")?;
+    Ok(strip_status(&data).copied().collect())
+}
+
+/// Load the bitstream into the FPGA, through the second channel.
+fn configure_fpga(channel: &Interface, bitstream: &[u8])
+    -> Result<(), Error>
+{
+    let outputs = PIN_CCLK | PIN_DIN | PIN_PROGRAM;
+    let read_pins = || -> Result<u8, Error> {
+        bulk_write(channel, ENDPOINT_B_OUT,
+                   vec![MPSSE_GET_LOW, MPSSE_SEND_IMMEDIATE])?;
+        for _ in 0..10 {
+            if let Some(&pins) = bulk_read(channel, ENDPOINT_B_IN)?.last() {
+                return Ok(pins);
+            }
+        }
+        bail!("No response reading FPGA configuration pins")
+    };
+
+    ftdi_request(channel, SIO_RESET, RESET_SIO)?;
+    set_bitmode(channel, BITMODE_RESET, 0)?;
+    set_bitmode(channel, BITMODE_MPSSE, 0)?;
+    // Clock at 60MHz / ((1 + 2) * 2) = 10MHz.
+    bulk_write(channel, ENDPOINT_B_OUT, vec![
+        MPSSE_DISABLE_DIV5,
+        MPSSE_SET_DIVISOR, 2, 0,
+        MPSSE_DISABLE_LOOPBACK,
+    ])?;
+
+    // Pulse PROGRAM low to clear the FPGA, then wait for INIT to go high.
+    bulk_write(channel, ENDPOINT_B_OUT, vec![MPSSE_SET_LOW, 0, outputs])?;
+    sleep(Duration::from_millis(1));
+    bulk_write(channel, ENDPOINT_B_OUT,
+               vec![MPSSE_SET_LOW, PIN_PROGRAM, outputs])?;
+    let mut ready = false;
+    for _ in 0..100 {
+        if read_pins()? & PIN_INIT != 0 {
+            ready = true;
+            break;
+        }
+        sleep(Duration::from_millis(1));
+    }
+    if !ready {
+        bail!("FPGA did not become ready for configuration");
+    }
+
+    // Clock out the bitstream, most significant bit first.
+    for chunk in bitstream.chunks(BITSTREAM_CHUNK) {
+        let length = (chunk.len() - 1) as u16;
+        let mut command = vec![MPSSE_WRITE_BYTES];
+        command.extend(length.to_le_bytes());
+        command.extend_from_slice(chunk);
+        bulk_write(channel, ENDPOINT_B_OUT, command)?;
+    }
+
+    // Give the FPGA some extra clocks to start up.
+    bulk_write(channel, ENDPOINT_B_OUT, vec![MPSSE_CLOCK_BYTES, 15, 0])?;
+    let pins = read_pins()?;
+    set_bitmode(channel, BITMODE_RESET, 0)?;
+    if pins & PIN_DONE == 0 {
+        bail!("FPGA configuration failed");
+    }
+    Ok(())
+}
+
+/// The backend for OpenVizsla devices.
+pub struct OpenVizsla;
+
+impl CaptureBackend for OpenVizsla {
+    fn matches(&self, info: &DeviceInfo) -> bool {
+        info.vendor_id() == VID && info.product_id() == PID
+    }
+
+    fn probe(&self, device_info: DeviceInfo) -> Box<dyn BackendDevice> {
+        let usability = match Firmware::load(&firmware_path()) {
+            Ok(firmware) => Usable(Arc::new(firmware)),
+            Err(err) => Unusable(format!("{err:#}")),
+        };
+        Box::new(OpenVizslaDevice { device_info, usability })
+    }
+}
+
+impl BackendDevice for OpenVizslaDevice {
+    fn name(&self) -> &'static str {
+        "OpenVizsla"
+    }
+
+    fn device_info(&self) -> &DeviceInfo {
+        &self.device_info
+    }
+
+    fn speeds(&self) -> Result<&[Speed], &str> {
+        match &self.usability {
+            Usable(_) => Ok(&SPEEDS),
+            Unusable(reason) => Err(reason),
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn BackendHandle>, Error> {
+        Ok(Box::new(OpenVizslaDevice::open(self)?))
+    }
+}
+
+impl OpenVizslaDevice {
+    pub fn open(&self) -> Result<OpenVizslaHandle, Error> {
+        match &self.usability {
+            Usable(firmware) => {
+                let device = self.device_info.open()
+                    .context("Failed to open device")?;
+                let config = device.detach_and_claim_interface(CHANNEL_B)
+                    .context("Failed to claim configuration interface")?;
+                configure_fpga(&config, &firmware.bitstream)?;
+                let interface = device.detach_and_claim_interface(CHANNEL_A)
+                    .context("Failed to claim data interface")?;
+                ftdi_request(&interface, SIO_RESET, RESET_SIO)?;
+                set_bitmode(&interface, BITMODE_RESET, 0)?;
+                set_bitmode(&interface, BITMODE_SYNCFF, 0xFF)?;
+                ftdi_request(&interface, SIO_SET_LATENCY_TIMER, 1)?;
+                ftdi_request(&interface, SIO_RESET, RESET_PURGE_RX)?;
+                ftdi_request(&interface, SIO_RESET, RESET_PURGE_TX)?;
+                Ok(OpenVizslaHandle {
+                    interface,
+                    firmware: firmware.clone(),
+                })
+            },
+            Unusable(reason) => bail!("Device not usable: {}", reason),
+        }
+    }
+}
+
+impl BackendHandle for OpenVizslaHandle {
+    fn start(&self, speed: Speed, result_handler: ResultHandler)
+        -> Result<CaptureHandles, Error>
+    {
+        let (stream, stop) =
+            OpenVizslaHandle::start(self, speed, result_handler)?;
+        Ok((Box::new(stream), Box::new(stop)))
+    }
+}
+
+impl OpenVizslaHandle {
+
+    pub fn start<F>(&self, speed: Speed, result_handler: F)
+        -> Result<(OpenVizslaStream, OpenVizslaStop), Error>
+        where F: FnOnce(Result<(), Error>) + Send + 'static
+    {
+        // Channel to pass captured data to the decoder thread.
+        let (tx, rx) = mpsc::channel();
+        // Channel to stop the capture thread on request.
+        let (stop_tx, stop_rx) = oneshot::channel();
+        // Clone handle to give to the worker thread.
+        let handle = self.clone();
+        // Start worker thread.
+        let worker = spawn(move ||
+            result_handler(
+                handle.run_capture(speed, tx, stop_rx)));
+        Ok((
+            OpenVizslaStream::new(rx),
+            OpenVizslaStop {
+                stop_request: stop_tx,
+                worker,
+            }
+        ))
+    }
+
+    fn run_capture(self,
+                   speed: Speed,
+                   tx: mpsc::Sender<Vec<u8>>,
+                   stop: oneshot::Receiver<()>)
+        -> Result<(), Error>
+    {
+        // Set up a separate channel pair to stop queue processing.
+        let (queue_stop_tx, queue_stop_rx) = oneshot::channel();
+
+        // Start capture.
+        self.start_capture(speed)?;
+
+        // Set up transfer queue.
+        let mut queue = TransferQueue::new(
+            &self.interface, ENDPOINT_A_IN, READ_LEN, NUM_TRANSFERS, tx);
+
+        // Spawn a worker thread to process queue until stopped.
+        let worker = spawn(move || block_on(queue.process(queue_stop_rx)));
+
+        // Wait until this thread is signalled to stop.
+        block_on(stop)
+            .context("Sender was dropped")?;
+
+        // Stop capture.
+        self.stop_capture()?;
+
+        // Leave queue worker running briefly to receive flushed data.
+        sleep(Duration::from_millis(100));
+
+        // Signal queue processing to stop, then join the worker thread.
+        queue_stop_tx.send(())
+            .or_else(|_| bail!("Failed sending stop signal to queue worker"))?;
+        handle_thread_panic(worker.join())?
+            .context("Error in queue worker thread")?;
+
+        Ok(())
+    }
+
+    fn start_capture(&self, speed: Speed) -> Result<(), Error> {
+        use Speed::*;
+        let function_control = match speed {
+            High => 0x48,
+            Full => 0x49,
+            Low => 0x4A,
+            Auto => bail!("OpenVizsla cannot detect the capture speed"),
+        };
+        // Set up the ring buffer in SDRAM through which packets pass.
+        self.write_register("SDRAM_SINK_GO", 0)?;
+        self.write_register("SDRAM_HOST_READ_GO", 0)?;
+        self.write_register("SDRAM_SINK_RING_BASE", 0)?;
+        self.write_register("SDRAM_SINK_RING_END", RING_SIZE)?;
+        self.write_register("SDRAM_HOST_READ_RING_BASE", 0)?;
+        self.write_register("SDRAM_HOST_READ_RING_END", RING_SIZE)?;
+        self.write_register("SDRAM_SINK_GO", 1)?;
+        self.write_register("SDRAM_HOST_READ_GO", 1)?;
+        // Clear the overflow flag.
+        self.write_register("OVF_INSERT_CTL", 1)?;
+        self.write_register("OVF_INSERT_CTL", 0)?;
+        // Set the speed, with the PHY only listening.
+        self.write_ulpi(ULPI_FUNCTION_CONTROL, function_control)?;
+        // Start streaming packets.
+        self.write_register("CSTREAM_CFG", 1)?;
+        println!("Capture enabled, speed: {}", speed.description());
+        Ok(())
+    }
+
+    fn stop_capture(&self) -> Result<(), Error> {
+        self.write_register("CSTREAM_CFG", 0)?;
+        self.write_register("SDRAM_SINK_GO", 0)?;
+        self.write_register("SDRAM_HOST_READ_GO", 0)?;
+        println!("Capture disabled");
+        Ok(())
+    }
+
+    /// Write a register, most significant byte first.
+    fn write_register(&self, name: &str, value: u32) -> Result<(), Error> {
+        let register = self.firmware.register(name)?;
+        let mut data = Vec::new();
+        for i in 0..register.size {
+            let shift = 8 * (register.size - 1 - i) as u32;
+            let byte = value.checked_shr(shift).unwrap_or(0) as u8;
+            data.extend(
+                register_access(register.address + i, Some(byte)));
+        }
+        bulk_write(&self.interface, ENDPOINT_A_OUT, data)
+    }
+
+    /// Read a single byte register.
+    fn read_register(&self, name: &str) -> Result<u8, Error> {
+        let register = self.firmware.register(name)?;
+        bulk_write(&self.interface, ENDPOINT_A_OUT,
+                   register_access(register.address, None).to_vec())?;
+        let mut response = Vec::new();
+        for _ in 0..100 {
+            response.extend(bulk_read(&self.interface, ENDPOINT_A_IN)?);
+            if let Some(start) = response
+                .iter()
+                .position(|&byte| byte == REGISTER_MAGIC)
+            {
+                if let Some(frame) =
+                    response.get(start..start + REGISTER_SIZE)
+                {
+                    return Ok(frame[3]);
+                }
+            }
+        }
+        bail!("No response reading register {name}")
+    }
+
+    /// Write a register of the ULPI PHY, through the gateware.
+    fn write_ulpi(&self, address: u8, value: u8) -> Result<(), Error> {
+        self.write_register("UCFG_WDATA", value as u32)?;
+        self.write_register("UCFG_WCMD", (ULPI_WRITE | address) as u32)?;
+        for _ in 0..100 {
+            if self.read_register("UCFG_WCMD")? & ULPI_WRITE == 0 {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(1));
+        }
+        bail!("Timed out writing ULPI register {address:02X}")
+    }
+}
+
+/// Build a register access frame, writing a value if one is given.
+fn register_access(address: u16, value: Option<u8>) -> [u8; REGISTER_SIZE] {
+    let address = match value {
+        Some(_) => address | REGISTER_WRITE,
+        None => address,
+    };
+    let [high, low] = address.to_be_bytes();
+    let value = value.unwrap_or(0);
+    let checksum = REGISTER_MAGIC
+        .wrapping_add(high)
+        .wrapping_add(low)
+        .wrapping_add(value);
+    [REGISTER_MAGIC, high, low, value, checksum]
+}
+
+impl Iterator for OpenVizslaStream {
+    type Item = TimestampedPacket;
+
+    fn next(&mut self) -> Option<TimestampedPacket> {
+        loop {
+            // Do we have another packet already in the buffer?
+            match self.next_buffered_packet() {
+                // Yes; return the packet.
+                Some(packet) => return Some(packet),
+                // No; wait for more data from the capture thread.
+                None => match self.receiver.recv().ok() {
+                    // Received more data; add it to the buffer and retry.
+                    Some(bytes) => self.buffer.extend(strip_status(&bytes)),
+                    // Capture has ended, there are no more packets.
+                    None => return None
+                }
+            }
+        }
+    }
+}
+
+impl OpenVizslaStream {
+    fn new(receiver: mpsc::Receiver<Vec<u8>>) -> OpenVizslaStream {
+        OpenVizslaStream {
+            receiver,
+            buffer: VecDeque::new(),
+            parser: Parser::default(),
+            overflows_reported: 0,
+        }
+    }
+
+    fn next_buffered_packet(&mut self) -> Option<TimestampedPacket> {
+        loop {
+            match self.buffer.front() {
+                None => return None,
+                Some(&MAGIC) => break,
+                Some(&REGISTER_MAGIC) => {
+                    // Skip any register access responses.
+                    if self.buffer.len() < REGISTER_SIZE {
+                        return None;
+                    }
+                    self.buffer.drain(0..REGISTER_SIZE);
+                },
+                Some(_) => {
+                    // Skip any other bytes until we are back in sync.
+                    self.buffer.pop_front();
+                },
+            }
+        }
+
+        // Do we have the header and all the data for the next packet?
+        if self.buffer.len() < HEADER_SIZE {
+            return None;
+        }
+        let mut header = [0; HEADER_SIZE];
+        for (byte, value) in header.iter_mut().zip(&self.buffer) {
+            *byte = *value;
+        }
+        let packet_len = packet_length(&header).ok()?;
+        if self.buffer.len() < HEADER_SIZE + packet_len {
+            return None;
+        }
+
+        // Remove the header and packet from the buffer, and return it.
+        self.buffer.drain(0..HEADER_SIZE);
+        Some(TimestampedPacket {
+            source: 0,
+            timestamp_ns: self.parser.timestamp_ns(&header),
+            bytes: self.buffer.drain(0..packet_len).collect()
+        })
+    }
+}
+
+impl PacketStream for OpenVizslaStream {
+    fn drops(&self) -> u64 {
+        self.parser.overflows()
+    }
+
+    fn take_losses(&mut self) -> Vec<Option<u64>> {
+        // Overflows are flagged on the packet following them, but the
+        // number of packets lost is not reported.
+        let overflows = self.parser.overflows();
+        let new = overflows - self.overflows_reported;
+        self.overflows_reported = overflows;
+        vec![None; new as usize]
+    }
+}
+
+impl BackendStop for OpenVizslaStop {
+    fn stop(self: Box<Self>) -> Result<(), Error> {
+        println!("Requesting capture stop");
+        self.stop_request.send(())
+            .or_else(|_| bail!("Failed sending stop request"))?;
+        handle_thread_panic(self.worker.join())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openvizsla_backend() {
+        // A bitstream file, with its header fields.
+        let mut bitfile = vec![0x00, 0x09];
+        bitfile.extend([0x0F, 0xF0, 0x0F, 0xF0, 0x0F, 0xF0, 0x0F, 0xF0, 0]);
+        bitfile.extend([0x00, 0x01]);
+        for (key, value) in [(b'a', "ov3.ncd"), (b'b', "6slx9tqg144")] {
+            bitfile.push(key);
+            bitfile.extend((value.len() as u16).to_be_bytes());
+            bitfile.extend(value.as_bytes());
+        }
+        bitfile.push(b'e');
+        bitfile.extend(4u32.to_be_bytes());
+        bitfile.extend([0xAA, 0x99, 0x55, 0x66]);
+        assert_eq!(bitstream_data(&bitfile).unwrap(),
+                   [0xAA, 0x99, 0x55, 0x66]);
+        assert!(bitstream_data(&bitfile[..bitfile.len() - 1]).is_err());
+
+        let registers = parse_map(
+            "# Register map\nCSTREAM_CFG = 0x0800\n\
+             SDRAM_SINK_RING_END 0x0c10 4\n").unwrap();
+        let firmware = Firmware { bitstream: vec![], registers };
+        assert_eq!(firmware.register("cstream_cfg").unwrap(),
+                   Register { address: 0x800, size: 1 });
+        assert_eq!(firmware.register("SDRAM_SINK_RING_END").unwrap(),
+                   Register { address: 0xC10, size: 4 });
+        assert!(firmware.register("LEDS_OUT").is_err());
+        assert_eq!(register_access(0x800, Some(1)),
+                   [0x55, 0x88, 0x00, 0x01, 0xDE]);
+
+        // Packet records split across transfers, with a register access
+        // response between them, and modem status at the start of each
+        // bulk packet. The sniffer's buffer overflowed before the last.
+        let mut stream = vec![0x55, 0x08, 0x00, 0x01, 0x5E];
+        stream.extend([0xA0, 0, 0, 3, 0, 0x10, 0, 0, 0x2D, 0x00, 0x10]);
+        stream.extend([0xA0, 0, 0, 1, 0, 0x20, 0, 0, 0xD2]);
+        stream.extend([0xA0, 2, 0, 1, 0, 0x05, 0, 0, 0x5A]);
+        let (tx, rx) = mpsc::channel();
+        let mut data = Vec::new();
+        for chunk in stream.chunks(FTDI_PACKET_SIZE - FTDI_STATUS_SIZE) {
+            data.extend([0x31, 0x60]);
+            data.extend(chunk);
+        }
+        let (first, second) = data.split_at(12);
+        tx.send(first.to_vec()).unwrap();
+        // The status bytes appear again at the start of the next transfer.
+        tx.send([&[0x31, 0x60], second].concat()).unwrap();
+        drop(tx);
+        let mut stream = OpenVizslaStream::new(rx);
+        let mut packets = Vec::new();
+        let mut losses = Vec::new();
+        while let Some(packet) = stream.next() {
+            packets.push((packet.timestamp_ns, packet.bytes));
+            losses.push(stream.take_losses());
+        }
+        assert_eq!(losses, vec![vec![], vec![], vec![None]]);
+        assert_eq!(stream.drops(), 1);
+        assert_eq!(packets, vec![
+            (266, vec![0x2D, 0x00, 0x10]),
+            (533, vec![0xD2]),
+            // The timestamp counter wrapped before this packet.
+            (279_620_350, vec![0x5A]),
+        ]);
+    }
+}
@@ -0,0 +1,158 @@
+//! Rolling capture, keeping only the most recent packets.
+//!
+//! The capture store only grows, so a rolling capture keeps its most
+//! recent packets in a window, and periodically starts a new capture
+//! from them. The capture displayed then holds between one and two
+//! windows' worth of packets, however long capture runs for.
+
+use std::collections::VecDeque;
+
+use anyhow::{Context, Error, bail};
+
+use super::TimestampedPacket;
+
+/// How much of a rolling capture to keep.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RollingLimit {
+    /// Keep this many bytes of packet data.
+    Bytes(u64),
+    /// Keep packets from this many nanoseconds before the latest one.
+    Duration(u64),
+}
+
+impl RollingLimit {
+    /// Parse a limit such as `100M` or `30m`, or none if the text is empty.
+    ///
+    /// Sizes are given with a `K`, `M` or `G` suffix, in binary units, and
+    /// times with an `s`, `m` or `h` suffix.
+    pub fn parse(text: &str) -> Result<Option<RollingLimit>, Error> {
+        use RollingLimit::*;
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(None);
+        }
+        let split = text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let number: u64 = number.parse().with_context(||
+            format!("Invalid amount to keep '{text}'"))?;
+        let (kind, scale): (fn(u64) -> RollingLimit, u64) = match unit {
+            "s" => (Duration, 1_000_000_000),
+            "m" => (Duration, 60_000_000_000),
+            "h" => (Duration, 3_600_000_000_000),
+            "" | "B" => (Bytes, 1),
+            "K" | "KB" | "KiB" => (Bytes, 1 << 10),
+            "M" | "MB" | "MiB" => (Bytes, 1 << 20),
+            "G" | "GB" | "GiB" => (Bytes, 1 << 30),
+            _ => bail!("Unknown unit '{unit}' in amount to keep"),
+        };
+        if number == 0 {
+            bail!("The amount to keep must not be zero");
+        }
+        let amount = number.checked_mul(scale).with_context(||
+            format!("The amount to keep '{text}' is too large"))?;
+        Ok(Some(kind(amount)))
+    }
+}
+
+/// The most recent packets of a rolling capture.
+pub struct RollingWindow {
+    limit: RollingLimit,
+    packets: VecDeque<TimestampedPacket>,
+    /// Total size of the packets in the window.
+    bytes: u64,
+    /// Number of packets dropped from the window since the last roll.
+    discarded: usize,
+}
+
+impl RollingWindow {
+    pub fn new(limit: RollingLimit) -> RollingWindow {
+        RollingWindow {
+            limit,
+            packets: VecDeque::new(),
+            bytes: 0,
+            discarded: 0,
+        }
+    }
+
+    /// Add a packet, dropping the oldest packets beyond the limit.
+    pub fn push(&mut self, packet: TimestampedPacket) {
+        let latest_ns = packet.timestamp_ns;
+        self.bytes += packet.bytes.len() as u64;
+        self.packets.push_back(packet);
+        while let Some(oldest) = self.packets.front() {
+            let excess = match self.limit {
+                RollingLimit::Bytes(limit) => self.bytes > limit,
+                RollingLimit::Duration(limit) =>
+                    latest_ns.saturating_sub(oldest.timestamp_ns) > limit,
+            };
+            if !excess || self.packets.len() == 1 {
+                break;
+            }
+            self.bytes -= oldest.bytes.len() as u64;
+            self.packets.pop_front();
+            self.discarded += 1;
+        }
+    }
+
+    /// Whether the capture now holds twice as many packets as the window,
+    /// and should be started afresh from the window.
+    pub fn should_roll(&self) -> bool {
+        self.discarded >= self.packets.len()
+    }
+
+    /// Start again from the packets in the window.
+    pub fn roll(&mut self) -> impl Iterator<Item=&TimestampedPacket> {
+        self.discarded = 0;
+        self.packets.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(timestamp_ns: u64, length: usize) -> TimestampedPacket {
+        TimestampedPacket { source: 0, timestamp_ns, bytes: vec![0xA5; length] }
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        use RollingLimit::*;
+        assert_eq!(RollingLimit::parse("").unwrap(), None);
+        assert_eq!(RollingLimit::parse("100M").unwrap(),
+                   Some(Bytes(100 << 20)));
+        assert_eq!(RollingLimit::parse("30m").unwrap(),
+                   Some(Duration(1_800_000_000_000)));
+        assert!(RollingLimit::parse("0s").is_err());
+        assert!(RollingLimit::parse("10y").is_err());
+        assert!(RollingLimit::parse("M").is_err());
+
+        let mut window = RollingWindow::new(Bytes(9));
+        for i in 0..3 {
+            window.push(packet(i, 3));
+            assert!(!window.should_roll());
+        }
+        for i in 3..5 {
+            window.push(packet(i, 3));
+        }
+        assert!(!window.should_roll());
+        window.push(packet(5, 3));
+        assert!(window.should_roll());
+        let kept: Vec<u64> = window.roll()
+            .map(|packet| packet.timestamp_ns)
+            .collect();
+        assert_eq!(kept, vec![3, 4, 5]);
+        assert!(!window.should_roll());
+
+        let mut window = RollingWindow::new(Duration(10));
+        for i in 0..5 {
+            window.push(packet(i * 5, 3));
+        }
+        let kept: Vec<u64> = window.roll()
+            .map(|packet| packet.timestamp_ns)
+            .collect();
+        assert_eq!(kept, vec![10, 15, 20]);
+    }
+}
@@ -0,0 +1,346 @@
+//! Triggers starting and stopping a capture.
+
+use std::collections::VecDeque;
+
+use anyhow::{Context, Error, bail};
+
+use crate::usb::{PID, PacketFields, validate_packet};
+
+use super::{PacketStream, Speed, TimestampedPacket};
+
+/// When to start and stop recording.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CaptureTrigger {
+    /// PIDs which may start recording, or any if empty.
+    pub pids: Vec<PID>,
+    /// Device addresses which may start recording, or any if empty.
+    pub addresses: Vec<u8>,
+    /// SETUP request which must be seen to start recording.
+    pub request: Option<u8>,
+    /// Data which must be seen to start recording.
+    pub payload: Option<Vec<u8>>,
+    /// Number of packets to keep from before recording starts.
+    pub pre_trigger: usize,
+    /// Number of packets after which to stop recording.
+    pub max_packets: Option<u64>,
+    /// Number of packet bytes after which to stop recording.
+    pub max_bytes: Option<u64>,
+    /// Time in nanoseconds after which to stop recording.
+    pub max_duration_ns: Option<u64>,
+}
+
+impl CaptureTrigger {
+    /// Parse a trigger from text.
+    pub fn parse(text: &str) -> Result<CaptureTrigger, Error> {
+        let mut trigger = CaptureTrigger::default();
+        for term in text.split_whitespace() {
+            let (key, value) = term
+                .split_once('=')
+                .with_context(|| format!("Invalid trigger term '{term}'"))?;
+            let number = || -> Result<u64, Error> {
+                value.parse().with_context(||
+                    format!("Invalid {key} '{value}' in trigger"))
+            };
+            match key {
+                "pid" => for name in value.split(',') {
+                    trigger.pids.push(parse_pid(name)?);
+                },
+                "address" => for address in value.split(',') {
+                    let address: u8 = address.parse().with_context(||
+                        format!("Invalid address '{address}' in trigger"))?;
+                    if address > 127 {
+                        bail!("The address must be no more than 127");
+                    }
+                    trigger.addresses.push(address);
+                },
+                "request" => trigger.request = Some(
+                    value.parse().with_context(||
+                        format!("Invalid request '{value}' in trigger"))?),
+                "payload" => trigger.payload = Some(parse_hex(value)?),
+                "pre" => trigger.pre_trigger = number()? as usize,
+                "packets" => trigger.max_packets = Some(number()?),
+                "bytes" => trigger.max_bytes = Some(number()?),
+                "seconds" => {
+                    let seconds: f64 = value.parse().with_context(||
+                        format!("Invalid seconds '{value}' in trigger"))?;
+                    if seconds.is_nan() || seconds < 0.0 {
+                        bail!("The seconds must not be negative");
+                    }
+                    trigger.max_duration_ns = Some((seconds * 1e9) as u64);
+                },
+                _ => bail!("Unknown trigger '{key}'"),
+            }
+        }
+        Ok(trigger)
+    }
+
+    /// Whether recording waits for a start condition.
+    pub fn has_start(&self) -> bool {
+        !self.pids.is_empty() ||
+            !self.addresses.is_empty() ||
+            self.request.is_some() ||
+            self.payload.is_some()
+    }
+
+    /// Whether a packet matches the start conditions.
+    fn matches(&self, pid: PID, bytes: &[u8], transaction: &Transaction)
+        -> bool
+    {
+        use PID::*;
+        let data = matches!(pid, DATA0 | DATA1 | DATA2 | MDATA);
+        (self.pids.is_empty() || self.pids.contains(&pid)) &&
+        (self.addresses.is_empty() || transaction.address.is_some_and(
+            |address| self.addresses.contains(&address))) &&
+        self.request.map_or(true, |request|
+            data && transaction.setup && bytes.get(2) == Some(&request)) &&
+        self.payload.as_ref().map_or(true, |pattern| {
+            data && bytes[1..bytes.len() - 2]
+                .windows(pattern.len())
+                .any(|window| window == pattern)
+        })
+    }
+}
+
+fn parse_pid(name: &str) -> Result<PID, Error> {
+    PID::from_name(name)
+        .with_context(|| format!("Unknown PID '{name}' in trigger"))
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>, Error> {
+    if text.is_empty() || text.len() % 2 != 0 {
+        bail!("The payload '{text}' must be pairs of hex digits");
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).with_context(||
+            format!("Invalid payload '{text}' in trigger")))
+        .collect()
+}
+
+/// The transaction a packet is part of.
+#[derive(Default)]
+struct Transaction {
+    /// Device address from the token, if any.
+    address: Option<u8>,
+    /// Whether the token was SETUP.
+    setup: bool,
+}
+
+enum State {
+    Waiting,
+    Recording {
+        start_ns: u64,
+        packets: u64,
+        bytes: u64,
+    },
+    Finished,
+}
+
+/// Records the packets from an analyzer between a trigger's start and
+/// stop conditions, ending once the stop condition is reached.
+pub struct TriggeredStream {
+    inner: Box<dyn PacketStream>,
+    trigger: CaptureTrigger,
+    state: State,
+    transaction: Transaction,
+    /// Packets kept from before recording started.
+    buffer: VecDeque<TimestampedPacket>,
+}
+
+impl TriggeredStream {
+    pub fn new(inner: Box<dyn PacketStream>, trigger: CaptureTrigger)
+        -> TriggeredStream
+    {
+        TriggeredStream {
+            inner,
+            trigger,
+            state: State::Waiting,
+            transaction: Transaction::default(),
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Whether recording has started.
+    pub fn triggered(&self) -> bool {
+        !matches!(self.state, State::Waiting)
+    }
+
+    /// Whether recording ended due to the stop condition.
+    pub fn finished(&self) -> bool {
+        matches!(self.state, State::Finished)
+    }
+
+    /// The stream of packets from the analyzer.
+    pub fn into_inner(self) -> Box<dyn PacketStream> {
+        self.inner
+    }
+
+    /// Whether enough packets or bytes have been recorded.
+    fn count_reached(&self) -> bool {
+        let State::Recording { packets, bytes, .. } = self.state else {
+            return false;
+        };
+        let trigger = &self.trigger;
+        trigger.max_packets.is_some_and(|max| packets >= max) ||
+            trigger.max_bytes.is_some_and(|max| bytes >= max)
+    }
+
+    /// Whether a packet falls after the time limit for recording.
+    fn time_reached(&self, timestamp_ns: u64) -> bool {
+        let State::Recording { start_ns, .. } = self.state else {
+            return false;
+        };
+        self.trigger.max_duration_ns.is_some_and(
+            |max| timestamp_ns.saturating_sub(start_ns) > max)
+    }
+}
+
+impl Iterator for TriggeredStream {
+    type Item = TimestampedPacket;
+
+    fn next(&mut self) -> Option<TimestampedPacket> {
+        if let Some(packet) = self.buffer.pop_front() {
+            if self.triggered() {
+                return Some(packet);
+            }
+            self.buffer.push_front(packet);
+        }
+        loop {
+            if self.count_reached() {
+                self.state = State::Finished;
+            }
+            if self.finished() {
+                return None;
+            }
+            let packet = self.inner.next()?;
+            let pid = validate_packet(&packet.bytes)
+                .unwrap_or(PID::Malformed);
+            match (pid, PacketFields::from_packet(&packet.bytes)) {
+                (PID::SOF, _) => self.transaction = Transaction::default(),
+                (PID::SPLIT, _) => {},
+                (_, PacketFields::Token(token)) => {
+                    self.transaction = Transaction {
+                        address: Some(token.device_address().0),
+                        setup: pid == PID::SETUP,
+                    }
+                },
+                _ => {},
+            }
+            if matches!(self.state, State::Waiting) {
+                // Losses before recording starts are not shown, so don't
+                // keep packets from before a loss.
+                if !self.inner.take_losses().is_empty() {
+                    self.buffer.clear();
+                }
+                let trigger = &self.trigger;
+                if trigger.has_start() &&
+                    !trigger.matches(pid, &packet.bytes, &self.transaction)
+                {
+                    if self.trigger.pre_trigger > 0 {
+                        if self.buffer.len() == self.trigger.pre_trigger {
+                            self.buffer.pop_front();
+                        }
+                        self.buffer.push_back(packet);
+                    }
+                    continue;
+                }
+                self.state = State::Recording {
+                    start_ns: packet.timestamp_ns,
+                    packets: 0,
+                    bytes: 0,
+                };
+            }
+            if self.time_reached(packet.timestamp_ns) {
+                self.state = State::Finished;
+                return None;
+            }
+            if let State::Recording { packets, bytes, .. } = &mut self.state {
+                *packets += 1;
+                *bytes += packet.bytes.len() as u64;
+            }
+            if let Some(first) = self.buffer.pop_front() {
+                self.buffer.push_back(packet);
+                return Some(first);
+            }
+            return Some(packet);
+        }
+    }
+}
+
+impl PacketStream for TriggeredStream {
+    fn drops(&self) -> u64 {
+        self.inner.drops()
+    }
+
+    fn take_losses(&mut self) -> Vec<Option<u64>> {
+        if self.triggered() {
+            self.inner.take_losses()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn speed(&self) -> Option<Speed> {
+        self.inner.speed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestStream(std::vec::IntoIter<Vec<u8>>);
+
+    impl Iterator for TestStream {
+        type Item = TimestampedPacket;
+
+        fn next(&mut self) -> Option<TimestampedPacket> {
+            let bytes = self.0.next()?;
+            Some(TimestampedPacket { source: 0, timestamp_ns: 0, bytes })
+        }
+    }
+
+    impl PacketStream for TestStream {}
+
+    #[test]
+    fn test_capture_trigger() {
+        let trigger = CaptureTrigger::parse(
+            "pid=DATA0 address=5 request=6 payload=0001 pre=2 packets=3"
+        ).unwrap();
+        assert_eq!(trigger.pids, vec![PID::DATA0]);
+        assert_eq!(trigger.addresses, vec![5]);
+        assert_eq!(trigger.request, Some(6));
+        assert_eq!(trigger.payload, Some(vec![0, 1]));
+        assert_eq!(trigger.pre_trigger, 2);
+        assert_eq!(trigger.max_packets, Some(3));
+        assert_eq!(CaptureTrigger::parse("").unwrap(),
+                   CaptureTrigger::default());
+        assert!(CaptureTrigger::parse("pid=FOO").is_err());
+        assert!(CaptureTrigger::parse("payload=123").is_err());
+        assert!(CaptureTrigger::parse("seconds=-1").is_err());
+
+        // SOF, then GET_DESCRIPTOR (device) to address 5.
+        let sof = vec![0xA5, 0x00, 0x10];
+        let setup = vec![0x2D, 0x05, 0xD0];
+        let data = vec![
+            0xC3, 0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00, 0xDD, 0x94];
+        let ack = vec![0xD2];
+        let packets = vec![
+            sof.clone(), sof.clone(), sof.clone(),
+            setup.clone(), data.clone(), ack.clone(),
+            sof.clone(),
+        ];
+        let trigger = CaptureTrigger::parse(
+            "address=5 request=6 pre=2 packets=2").unwrap();
+        let mut stream = TriggeredStream::new(
+            Box::new(TestStream(packets.into_iter())), trigger);
+        let mut kept = Vec::new();
+        for packet in stream.by_ref() {
+            kept.push(packet.bytes);
+        }
+        assert_eq!(kept, vec![sof, setup, data, ack]);
+        assert!(stream.finished());
+        let remaining = stream.into_inner().count();
+        assert_eq!(remaining, 1);
+    }
+}
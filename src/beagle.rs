@@ -0,0 +1,158 @@
+//! Import of CSV files exported by Total Phase Data Center from captures
+//! made with Beagle USB analyzers.
+//!
+//! Data Center exports one row for each record in its capture view, with
+//! transfers and transactions followed by the packets they contain. Only
+//! the packet rows are loaded, since the transfers and transactions are
+//! found again by decoding the packets. The Data column of a packet row
+//! holds the bytes of the packet, from the PID to the CRC.
+
+use anyhow::{Context, Error, bail};
+
+/// Heading of the timestamp column.
+const TIME_COLUMN: &str = "m:s.ms.us";
+
+/// Heading of the column describing each record.
+const RECORD_COLUMN: &str = "Record";
+
+/// Heading of the column holding each record's data.
+const DATA_COLUMN: &str = "Data";
+
+/// Whether the start of a file appears to be a Data Center CSV export.
+pub fn is_csv(start: &[u8]) -> bool {
+    // The header row may follow a few lines of preamble.
+    start
+        .split(|&b| b == b'\n')
+        .take(8)
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .any(|line| Columns::from_header(line).is_some())
+}
+
+/// Positions of the columns used, found from the header row.
+pub struct Columns {
+    time: usize,
+    record: usize,
+    data: usize,
+}
+
+impl Columns {
+    /// Find the columns from a header row, if this is one.
+    pub fn from_header(line: &str) -> Option<Columns> {
+        let fields = split_fields(line);
+        let find = |heading: &str| {
+            fields.iter().position(|field| field.trim() == heading)
+        };
+        Some(Columns {
+            time: find(TIME_COLUMN)?,
+            record: find(RECORD_COLUMN)?,
+            data: find(DATA_COLUMN)?,
+        })
+    }
+
+    /// Parse a row, returning its packet and timestamp in nanoseconds if
+    /// the row is that of a packet.
+    pub fn parse(&self, line: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        let fields = split_fields(line);
+        let field = |index: usize| -> Result<&str, Error> {
+            Ok(fields.get(index).context("Row is incomplete")?.trim())
+        };
+        if !field(self.record)?.to_ascii_lowercase().ends_with("packet") {
+            return Ok(None);
+        }
+        let timestamp = parse_time(field(self.time)?)?;
+        let mut packet = Vec::new();
+        for byte in field(self.data)?.split_whitespace() {
+            packet.push(u8::from_str_radix(byte, 16)
+                .with_context(|| format!("Invalid data byte '{byte}'"))?);
+        }
+        if packet.is_empty() {
+            bail!("Packet has no data");
+        }
+        Ok(Some((packet, timestamp)))
+    }
+}
+
+/// Split a CSV row into its fields, removing any quotes.
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Parse a timestamp in minutes, seconds, milliseconds and microseconds,
+/// e.g. "1:02.345.678", returning nanoseconds. Nanoseconds may also be
+/// given, as a further group of digits.
+fn parse_time(time: &str) -> Result<u64, Error> {
+    let invalid = || format!("Invalid timestamp '{time}'");
+    let (minutes, rest) = time.split_once(':').with_context(invalid)?;
+    let mut groups = rest.split('.');
+    let seconds: u64 = groups.next().with_context(invalid)?
+        .parse().with_context(invalid)?;
+    let mut ns = (minutes.parse::<u64>().with_context(invalid)? * 60 +
+                  seconds) * 1_000_000_000;
+    for scale in [1_000_000, 1_000, 1] {
+        match groups.next() {
+            Some(group) =>
+                ns += group.parse::<u64>().with_context(invalid)? * scale,
+            None => break
+        }
+    }
+    Ok(ns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::Loader;
+
+    const EXPORT: &str = "\
+Total Phase Data Center export\r
+\r
+Level,Sp,Index,m:s.ms.us,Dur,Len,Err,Dev,Ep,Record,Data,Summary\r
+0,FS,0,0:01.000.000,4.000.000 us,8 B,,01,00,Control Transfer,\
+80 06 00 01 00 00 12 00,\"GET_DESCRIPTOR, DEVICE\"\r
+1,FS,1,0:01.000.000,12.000 us,8 B,,01,00,SETUP txn,\
+80 06 00 01 00 00 12 00,\r
+2,FS,2,0:01.000.000,,3 B,,01,00,SETUP packet,2D 01 E8,\r
+2,FS,3,0:01.000.003,,11 B,,01,00,DATA0 packet,\
+C3 80 06 00 01 00 00 12 00 E0 F4,\r
+2,FS,4,0:01.000.010,,1 B,,01,00,ACK packet,D2,\r
+1,FS,5,1:00.000.000,,0 B,,01,00,[4 IN-NAK],,\r
+";
+
+    #[test]
+    fn test_beagle_import() {
+        assert!(is_csv(EXPORT.as_bytes()));
+        assert!(!is_csv(b"Index,Time,Data\n"));
+        let mut loader = Loader::open(EXPORT.as_bytes()).unwrap();
+        let mut packets = Vec::new();
+        while let Some(result) = loader.next() {
+            let (packet, timestamp) = result.unwrap();
+            packets.push((packet.data.to_vec(), timestamp));
+        }
+        assert_eq!(packets, [
+            (vec![0x2D, 0x01, 0xE8], 0),
+            (vec![0xC3, 0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00,
+                  0xE0, 0xF4], 3_000),
+            (vec![0xD2], 10_000),
+        ]);
+        assert_eq!(loader.bytes_read, EXPORT.len() as u64);
+        assert_eq!(parse_time("1:02.345.678").unwrap(), 62_345_678_000);
+        assert_eq!(parse_time("0:00.000.001.500").unwrap(), 1_500);
+        assert!(parse_time("1.5").is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::Error;
+
+use crate::class::bt::HciPacket;
+
+/// Datalink type for HCI packets with H4 (UART) framing.
+const DATALINK_HCI_UART: u32 = 1002;
+
+/// Offset of the Unix epoch from the btsnoop epoch, in microseconds.
+const EPOCH_OFFSET_US: u64 = 0x00DC_DDB3_0F2F_8000;
+
+pub struct Writer<Dest: Write> {
+    writer: BufWriter<Dest>,
+}
+
+impl<Dest> Writer<Dest> where Dest: Write {
+    pub fn open(dest: Dest) -> Result<Writer<Dest>, Error> {
+        let mut writer = BufWriter::new(dest);
+        writer.write_all(b"btsnoop\0")?;
+        writer.write_all(&1u32.to_be_bytes())?;
+        writer.write_all(&DATALINK_HCI_UART.to_be_bytes())?;
+        Ok(Writer{writer})
+    }
+
+    pub fn add_packet(&mut self, packet: &HciPacket) -> Result<(), Error> {
+        use crate::class::bt::PacketType::*;
+        let length: u32 = (packet.data.len() + 1).try_into()?;
+        let mut flags = 0u32;
+        if packet.received {
+            flags |= 1;
+        }
+        if matches!(packet.packet_type, Command | Event) {
+            flags |= 2;
+        }
+        let timestamp = EPOCH_OFFSET_US + packet.timestamp_ns / 1000;
+        self.writer.write_all(&length.to_be_bytes())?;
+        self.writer.write_all(&length.to_be_bytes())?;
+        self.writer.write_all(&flags.to_be_bytes())?;
+        self.writer.write_all(&0u32.to_be_bytes())?;
+        self.writer.write_all(&timestamp.to_be_bytes())?;
+        self.writer.write_all(&[packet.packet_type as u8])?;
+        self.writer.write_all(&packet.data)?;
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
@@ -1,20 +1,26 @@
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::fmt::{Debug, Write};
+use std::io::{self, Read};
 use std::iter::once;
-use std::ops::Range;
+use std::ops::{Deref, Range};
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64};
 use std::sync::atomic::Ordering::{Acquire, Release};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::mem::size_of;
 
+use crate::class;
 use crate::id::{Id, HasLength};
+use crate::i18n::tr;
+use crate::detail::{
+    descriptor_fields, offset_fields, packet_fields, setup_fields, Field};
 use crate::data_stream::{
     data_stream, data_stream_with_block_size, DataWriter, DataReader};
 use crate::compact_index::{compact_index, CompactWriter, CompactReader};
 use crate::rcu::SingleWriterRcu;
+use crate::stream::read_length;
 use crate::vec_map::VecMap;
 use crate::usb::{self, prelude::*, validate_packet};
-use crate::util::{fmt_count, fmt_size};
+use crate::util::{fmt_count, fmt_duration, fmt_size};
 
 use anyhow::{Context, Error, bail};
 use arc_swap::{ArcSwap, ArcSwapOption};
@@ -24,10 +30,154 @@ use num_enum::{IntoPrimitive, FromPrimitive};
 // Use 2MB block size for packet data, which is a large page size on x86_64.
 const PACKET_DATA_BLOCK_SIZE: usize = 0x200000;
 
+/// A subset of the packets in a capture.
+#[derive(Clone)]
+pub enum PacketSubset {
+    /// The packets making up a traffic item.
+    Item(TrafficItem),
+    /// The packets captured during a time range.
+    TimeRange(Range<Timestamp>),
+    /// The packets exchanged with a device.
+    Device(DeviceId),
+}
+
+/// A filter on the top-level items shown in the traffic view.
+///
+/// A filter can be given as text, in the same form as a capture filter,
+/// with a further term naming the kinds of traffic to hide:
+///
+/// ```text
+/// address=5,7 endpoint=1 hide=SOF,NAK
+/// ```
+///
+/// Hiding SOF removes the groups of SOF packets, and hiding NAK removes
+/// the groups of NAKed transactions seen while polling an endpoint.
+///
+/// A filter may also collapse idle periods, describing each group of SOF
+/// packets by the number of frames for which the bus was idle, and
+/// collapse polling, describing each group of NAKed transactions by the
+/// number of times the endpoint was polled and for how long.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrafficFilter {
+    /// Show only the traffic exchanged with this device.
+    pub device: Option<DeviceId>,
+    /// Device addresses to show, or all if empty.
+    pub addresses: Vec<u8>,
+    /// Endpoint numbers to show, or all if empty.
+    pub endpoints: Vec<u8>,
+    /// Endpoints to hide, by device address and endpoint number.
+    pub ignored: Vec<(u8, u8)>,
+    /// Whether to hide groups of SOF packets.
+    pub hide_sof: bool,
+    /// Whether to hide groups of NAKed transactions.
+    pub hide_nak: bool,
+    /// Whether to describe groups of SOF packets as idle periods.
+    pub collapse_idle: bool,
+    /// Whether to describe groups of NAKed transactions by their count
+    /// and duration.
+    pub collapse_polling: bool,
+}
+
+impl TrafficFilter {
+    /// Parse a filter from text.
+    pub fn parse(text: &str) -> Result<TrafficFilter, Error> {
+        let mut filter = TrafficFilter::default();
+        for term in text.split_whitespace() {
+            let (key, values) = term
+                .split_once('=')
+                .with_context(|| format!("Invalid filter term '{term}'"))?;
+            for value in values.split(',') {
+                let number = |name: &str, text: &str, limit: u8| {
+                    let number: u8 = text.parse().with_context(||
+                        format!("Invalid {name} '{text}' in filter"))?;
+                    if number > limit {
+                        bail!("The {name} must be no more than {limit}");
+                    }
+                    Ok::<u8, Error>(number)
+                };
+                match key {
+                    "address" =>
+                        filter.addresses.push(number(key, value, 127)?),
+                    "endpoint" =>
+                        filter.endpoints.push(number(key, value, 15)?),
+                    "ignore" => {
+                        let (address, endpoint) = value
+                            .split_once('.')
+                            .with_context(|| format!(
+                                "Invalid endpoint '{value}' to ignore, \
+                                 expected address.endpoint"))?;
+                        filter.ignored.push((
+                            number("address", address, 127)?,
+                            number("endpoint", endpoint, 15)?));
+                    },
+                    "hide" => match value.to_ascii_uppercase().as_str() {
+                        "SOF" => filter.hide_sof = true,
+                        "NAK" => filter.hide_nak = true,
+                        _ => bail!("Cannot hide '{value}', \
+                                    expected 'SOF' or 'NAK'"),
+                    },
+                    _ => bail!("Unknown filter '{key}', expected \
+                                'address', 'endpoint', 'ignore' or 'hide'"),
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether this filter shows all traffic. Collapsing idle periods or
+    /// polling changes only how they are described.
+    pub fn is_empty(&self) -> bool {
+        self.device.is_none() &&
+            self.addresses.is_empty() &&
+            self.endpoints.is_empty() &&
+            self.ignored.is_empty() &&
+            !self.hide_sof &&
+            !self.hide_nak
+    }
+
+    /// Whether traffic on an endpoint is shown by this filter.
+    fn matches(&self, endpoint: &Endpoint) -> bool {
+        let address = endpoint.device_address().0;
+        let number = endpoint.number();
+        self.device.map_or(true, |device_id| endpoint.device_id() == device_id)
+            && (self.addresses.is_empty() || self.addresses.contains(&address))
+            && (self.endpoints.is_empty() || self.endpoints.contains(&number.0))
+            && !self.ignored.contains(&(address, number.0))
+            && !(self.hide_sof && number == FRAMING_EP_NUM)
+    }
+}
+
+/// Number of markers placed before a top-level transfer item.
+fn markers_before(markers: &[Marker], item_id: TrafficItemId) -> u64 {
+    // Each marker records its position among all the top-level items,
+    // including the markers before it.
+    let (mut low, mut high) = (0, markers.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if markers[mid].position - mid as u64 <= item_id.value {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low as u64
+}
+
 /// Capture state shared between readers and writers.
 pub struct CaptureShared {
     pub device_data: ArcSwap<VecMap<DeviceId, Arc<DeviceData>>>,
     pub endpoint_readers: ArcSwap<VecMap<EndpointId, Arc<EndpointReader>>>,
+    /// Names of the captures merged into this one, if there are several.
+    pub sources: ArcSwap<Vec<String>>,
+    /// Markers added to the capture, in order.
+    pub markers: ArcSwap<Vec<Marker>>,
+    /// Values computed by user-supplied scripts, by name.
+    pub metrics: ArcSwap<Vec<(String, String)>>,
+    /// Number of top-level items known to be polling or not.
+    pub settled_items: AtomicU64,
+    /// Time at which the capture started, in nanoseconds since the Unix
+    /// epoch, if known.
+    pub start_time: OnceLock<u64>,
     pub complete: AtomicBool,
 }
 
@@ -37,6 +187,7 @@ pub struct CaptureWriter {
     pub packet_data: DataWriter<u8, PACKET_DATA_BLOCK_SIZE>,
     pub packet_index: CompactWriter<PacketId, PacketByteId, 2>,
     pub packet_times: CompactWriter<PacketId, Timestamp, 3>,
+    pub packet_sources: DataWriter<u8>,
     pub transaction_index: CompactWriter<TransactionId, PacketId>,
     pub transfer_index: DataWriter<TransferIndexEntry>,
     pub item_index: CompactWriter<TrafficItemId, TransferId>,
@@ -56,6 +207,7 @@ pub struct CaptureReader {
     pub packet_data: DataReader<u8, PACKET_DATA_BLOCK_SIZE>,
     pub packet_index: CompactReader<PacketId, PacketByteId>,
     pub packet_times: CompactReader<PacketId, Timestamp>,
+    pub packet_sources: DataReader<u8>,
     pub transaction_index: CompactReader<TransactionId, PacketId>,
     pub transfer_index: DataReader<TransferIndexEntry>,
     pub item_index: CompactReader<TrafficItemId, TransferId>,
@@ -65,6 +217,10 @@ pub struct CaptureReader {
     pub endpoint_state_index: CompactReader<TransferId, Id<u8>>,
     #[allow(dead_code)]
     pub end_index: CompactReader<TransferId, TrafficItemId>,
+    filter: TrafficFilter,
+    /// Endpoints matched by the filter, out of those checked so far.
+    filter_endpoints: Vec<EndpointId>,
+    filter_checked: u64,
 }
 
 /// Create a capture reader-writer pair.
@@ -76,6 +232,7 @@ pub fn create_capture()
         data_stream_with_block_size::<_, PACKET_DATA_BLOCK_SIZE>()?;
     let (packets_writer, packets_reader) = compact_index()?;
     let (timestamp_writer, timestamp_reader) = compact_index()?;
+    let (sources_writer, sources_reader) = data_stream()?;
     let (transactions_writer, transactions_reader) = compact_index()?;
     let (transfers_writer, transfers_reader) = data_stream()?;
     let (items_writer, items_reader) = compact_index()?;
@@ -89,6 +246,11 @@ pub fn create_capture()
     let shared = Arc::new(CaptureShared {
         device_data: ArcSwap::new(Arc::new(VecMap::new())),
         endpoint_readers: ArcSwap::new(Arc::new(VecMap::new())),
+        sources: ArcSwap::new(Arc::new(Vec::new())),
+        markers: ArcSwap::new(Arc::new(Vec::new())),
+        metrics: ArcSwap::new(Arc::new(Vec::new())),
+        settled_items: AtomicU64::from(0),
+        start_time: OnceLock::new(),
         complete: AtomicBool::from(false),
     });
 
@@ -98,6 +260,7 @@ pub fn create_capture()
         packet_data: data_writer,
         packet_index: packets_writer,
         packet_times: timestamp_writer,
+        packet_sources: sources_writer,
         transaction_index: transactions_writer,
         transfer_index: transfers_writer,
         item_index: items_writer,
@@ -115,6 +278,7 @@ pub fn create_capture()
         packet_data: data_reader,
         packet_index: packets_reader,
         packet_times: timestamp_reader,
+        packet_sources: sources_reader,
         transaction_index: transactions_reader,
         transfer_index: transfers_reader,
         item_index: items_reader,
@@ -123,6 +287,9 @@ pub fn create_capture()
         endpoint_states: endpoint_state_reader,
         endpoint_state_index: state_index_reader,
         end_index: end_reader,
+        filter: TrafficFilter::default(),
+        filter_endpoints: Vec::new(),
+        filter_checked: 0,
     };
 
     // Return the pair.
@@ -144,6 +311,8 @@ pub struct EndpointWriter {
     pub data_transactions: CompactWriter<EndpointDataEvent, EndpointTransactionId>,
     pub data_byte_counts: CompactWriter<EndpointDataEvent, EndpointByteCount>,
     pub end_index: CompactWriter<EndpointTransferId, TrafficItemId>,
+    pub item_ids: CompactWriter<EndpointItemId, TrafficItemId>,
+    pub polling_item_ids: CompactWriter<EndpointItemId, TrafficItemId>,
 }
 
 /// Cloneable handle for read access to endpoint data.
@@ -155,6 +324,8 @@ pub struct EndpointReader {
     pub data_transactions: CompactReader<EndpointDataEvent, EndpointTransactionId>,
     pub data_byte_counts: CompactReader<EndpointDataEvent, EndpointByteCount>,
     pub end_index: CompactReader<EndpointTransferId, TrafficItemId>,
+    pub item_ids: CompactReader<EndpointItemId, TrafficItemId>,
+    pub polling_item_ids: CompactReader<EndpointItemId, TrafficItemId>,
 }
 
 /// Create a per-endpoint reader-writer pair.
@@ -167,6 +338,8 @@ pub fn create_endpoint()
     let (data_transaction_writer, data_transaction_reader) = compact_index()?;
     let (data_byte_count_writer, data_byte_count_reader) = compact_index()?;
     let (end_writer, end_reader) = compact_index()?;
+    let (items_writer, items_reader) = compact_index()?;
+    let (polling_writer, polling_reader) = compact_index()?;
 
     // Create the shared state.
     let shared = Arc::new(EndpointShared {
@@ -182,6 +355,8 @@ pub fn create_endpoint()
         data_transactions: data_transaction_writer,
         data_byte_counts: data_byte_count_writer,
         end_index: end_writer,
+        item_ids: items_writer,
+        polling_item_ids: polling_writer,
     };
 
     // Create the read handle.
@@ -192,12 +367,112 @@ pub fn create_endpoint()
         data_transactions: data_transaction_reader,
         data_byte_counts: data_byte_count_reader,
         end_index: end_reader,
+        item_ids: items_reader,
+        polling_item_ids: polling_reader,
     };
 
     // Return the pair.
     Ok((writer, reader))
 }
 
+impl EndpointReader {
+    /// Write the indices of this endpoint.
+    fn save(&mut self, dest: &mut impl io::Write) -> Result<(), Error> {
+        self.transaction_ids.save(dest)?;
+        self.transfer_index.save(dest)?;
+        self.data_transactions.save(dest)?;
+        self.data_byte_counts.save(dest)?;
+        self.end_index.save(dest)?;
+        self.item_ids.save(dest)?;
+        self.polling_item_ids.save(dest)?;
+        let total_data = self.shared.total_data.load(Acquire);
+        dest.write_all(&total_data.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+impl EndpointWriter {
+    /// Restore the indices of an endpoint saved by [EndpointReader::save].
+    fn restore(&mut self, source: &mut impl Read) -> Result<(), Error> {
+        self.transaction_ids.restore(source)?;
+        self.transfer_index.restore(source)?;
+        self.data_transactions.restore(source)?;
+        self.data_byte_counts.restore(source)?;
+        self.end_index.restore(source)?;
+        self.item_ids.restore(source)?;
+        self.polling_item_ids.restore(source)?;
+        let total_data = read_length(source)?;
+        self.shared.total_data.store(total_data, Release);
+        Ok(())
+    }
+}
+
+impl CaptureReader {
+    /// Write the packet data and all indices of a complete capture.
+    pub fn save_streams(&mut self, dest: &mut impl io::Write)
+        -> Result<(), Error>
+    {
+        if !self.shared.complete.load(Acquire) {
+            bail!("Capture is not complete")
+        }
+        self.packet_data.save(dest)?;
+        self.packet_index.save(dest)?;
+        self.packet_times.save(dest)?;
+        self.transaction_index.save(dest)?;
+        self.transfer_index.save(dest)?;
+        self.item_index.save(dest)?;
+        self.devices.save(dest)?;
+        self.endpoints.save(dest)?;
+        self.endpoint_states.save(dest)?;
+        self.endpoint_state_index.save(dest)?;
+        self.end_index.save(dest)?;
+        self.packet_sources.save(dest)?;
+        let endpoint_readers = self.shared.endpoint_readers.load_full();
+        for id in 0..self.endpoints.len() {
+            let endpoint_id = EndpointId::from(id);
+            let mut reader = endpoint_readers
+                .get(endpoint_id)
+                .with_context(|| format!("No reader for endpoint {id}"))?
+                .as_ref()
+                .clone();
+            reader.save(dest)?;
+        }
+        Ok(())
+    }
+}
+
+impl CaptureWriter {
+    /// Restore the streams of a capture saved by
+    /// [CaptureReader::save_streams].
+    ///
+    /// The capture must be empty.
+    pub fn restore_streams(&mut self, source: &mut impl Read)
+        -> Result<(), Error>
+    {
+        self.packet_data.restore(source)?;
+        self.packet_index.restore(source)?;
+        self.packet_times.restore(source)?;
+        self.transaction_index.restore(source)?;
+        self.transfer_index.restore(source)?;
+        self.item_index.restore(source)?;
+        self.devices.restore(source)?;
+        self.endpoints.restore(source)?;
+        self.endpoint_states.restore(source)?;
+        self.endpoint_state_index.restore(source)?;
+        self.end_index.restore(source)?;
+        self.packet_sources.restore(source)?;
+        let mut endpoint_readers = VecMap::new();
+        for id in 0..self.endpoints.len() {
+            let (mut writer, reader) = create_endpoint()?;
+            writer.restore(source)?;
+            endpoint_readers.set(EndpointId::from(id), Arc::new(reader));
+        }
+        self.shared.endpoint_readers.swap(Arc::new(endpoint_readers));
+        self.shared.settled_items.store(self.item_index.len(), Release);
+        Ok(())
+    }
+}
+
 pub type PacketByteId = Id<u8>;
 pub type PacketId = Id<PacketByteId>;
 pub type Timestamp = u64;
@@ -206,17 +481,132 @@ pub type TransferId = Id<TransferIndexEntry>;
 pub type EndpointTransactionId = Id<TransactionId>;
 pub type EndpointTransferId = Id<EndpointTransactionId>;
 pub type TrafficItemId = Id<TransferId>;
+pub type EndpointItemId = Id<TrafficItemId>;
 pub type DeviceId = Id<Device>;
 pub type EndpointId = Id<Endpoint>;
 pub type EndpointDataEvent = u64;
 pub type EndpointByteCount = u64;
 pub type DeviceVersion = u32;
+pub type MarkerId = Id<Marker>;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TrafficItem {
     Transfer(TransferId),
     Transaction(TransferId, TransactionId),
     Packet(TransferId, TransactionId, PacketId),
+    Marker(MarkerId),
+}
+
+/// An event added to the timeline of a capture, between its transfers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Marker {
+    pub kind: MarkerKind,
+    pub timestamp: Timestamp,
+    /// Index of the marker among the top-level traffic items.
+    pub position: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MarkerKind {
+    /// A note, such as one added by the user while capturing.
+    Note(String),
+    /// Packets lost by the analyzer, with their number if it is known.
+    Loss(Option<u64>),
+}
+
+impl std::fmt::Display for MarkerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use MarkerKind::*;
+        match self {
+            Note(text) => write!(f, "Marker: {text}"),
+            Loss(None) => write!(f, "Packets lost"),
+            Loss(Some(1)) => write!(f, "1 packet lost"),
+            Loss(Some(count)) =>
+                write!(f, "{} packets lost", fmt_count(*count)),
+        }
+    }
+}
+
+/// Overview of a capture over time, with the time from its first packet
+/// to its last divided into equal intervals.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timeline {
+    pub time_range: Range<Timestamp>,
+    /// Number of packets in each interval.
+    pub packets: Vec<u64>,
+    /// Number of invalid packets and losses in each interval.
+    pub errors: Vec<u64>,
+    /// Times of the markers noted in the capture.
+    pub events: Vec<Timestamp>,
+}
+
+/// Totals over a number of traffic items, such as those selected.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ItemStats {
+    /// Number of items.
+    pub items: u64,
+    /// Number of packets making up the items, each counted once.
+    pub packets: u64,
+    /// Total length of the items, as given by their Length column.
+    pub bytes: u64,
+    /// Time from the first packet of the items to the end of the last.
+    pub time_range: Option<Range<Timestamp>>,
+}
+
+impl ItemStats {
+    /// Average bytes per second over the time span of the items.
+    pub fn throughput(&self) -> Option<f64> {
+        let time_range = self.time_range.as_ref()?;
+        let duration = time_range.end.checked_sub(time_range.start)?;
+        (duration > 0).then(|| self.bytes as f64 * 1e9 / duration as f64)
+    }
+}
+
+/// Sort ranges of packets, and merge any which are adjacent or overlap.
+fn merge_packet_ranges(mut ranges: Vec<Range<PacketId>>)
+    -> Vec<Range<PacketId>>
+{
+    ranges.retain(|range| range.end > range.start);
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<PacketId>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => {
+                last.end = max(last.end, range.end);
+            },
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Start time of one of a number of equal intervals of a time range.
+fn interval_start(time_range: &Range<Timestamp>,
+                  intervals: usize,
+                  index: usize)
+    -> Timestamp
+{
+    let span = (time_range.end - time_range.start) as u128;
+    let count = intervals.max(1) as u128;
+    time_range.start + (span * index as u128 / count) as Timestamp
+}
+
+impl Timeline {
+    /// Start time of an interval.
+    pub fn interval_start(&self, index: usize) -> Timestamp {
+        interval_start(&self.time_range, self.packets.len(), index)
+    }
+
+    /// Index of the interval containing a time.
+    pub fn interval(&self, timestamp: Timestamp) -> Option<usize> {
+        if !self.time_range.contains(&timestamp) {
+            return None;
+        }
+        let span = self.time_range.end - self.time_range.start;
+        let offset = timestamp - self.time_range.start;
+        let count = self.packets.len() as u128;
+        Some((offset as u128 * count / span as u128) as usize)
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -302,6 +692,7 @@ pub const INVALID_EP_NUM: EndpointNum = EndpointNum(0x10);
 pub const FRAMING_EP_NUM: EndpointNum = EndpointNum(0x11);
 pub const INVALID_EP_ID: EndpointId = EndpointId::constant(0);
 pub const FRAMING_EP_ID: EndpointId = EndpointId::constant(1);
+pub const DEFAULT_DEVICE_ID: DeviceId = DeviceId::constant(0);
 
 #[derive(Copy, Clone, Debug)]
 pub enum EndpointType {
@@ -329,6 +720,8 @@ pub struct DeviceData {
     pub config_number: ArcSwapOption<ConfigNum>,
     pub endpoint_details: ArcSwap<VecMap<EndpointAddr, EndpointDetails>>,
     pub strings: ArcSwap<VecMap<StringId, UTF16ByteVec>>,
+    pub dfu_progress: ArcSwapOption<class::dfu::Progress>,
+    pub hid_report_descriptors: ArcSwap<VecMap<InterfaceNum, Vec<u8>>>,
     pub version: AtomicU32,
 }
 
@@ -401,6 +794,97 @@ impl DeviceData {
         }
     }
 
+    pub fn endpoint_interface(&self, addr: EndpointAddr)
+        -> Option<InterfaceDescriptor>
+    {
+        let number = self.config_number.load_full()?;
+        let config = self.configurations.load().get(*number)?.clone();
+        for iface in &config.interfaces {
+            for ep_desc in &iface.endpoint_descriptors {
+                if ep_desc.endpoint_address == addr {
+                    return Some(iface.descriptor);
+                }
+            }
+        }
+        None
+    }
+
+    /// Sample format of an audio streaming endpoint, if known.
+    pub fn endpoint_audio_format(&self, addr: EndpointAddr)
+        -> Option<class::uac::Format>
+    {
+        let number = self.config_number.load_full()?;
+        let config = self.configurations.load().get(*number)?.clone();
+        for iface in &config.interfaces {
+            for ep_desc in &iface.endpoint_descriptors {
+                if ep_desc.endpoint_address == addr {
+                    return class::uac::Format::from_interface(iface);
+                }
+            }
+        }
+        None
+    }
+
+    /// Usage page declared by the report descriptor of a HID interface.
+    pub fn hid_usage_page(&self, number: InterfaceNum) -> Option<u16> {
+        self.hid_report_descriptors
+            .load()
+            .get(number)
+            .and_then(|descriptor| class::hid::report_usage_page(descriptor))
+    }
+
+    /// Report descriptor of a HID interface.
+    ///
+    /// If the descriptor was not captured, the boot protocol descriptor
+    /// is assumed for boot keyboards and mice.
+    pub fn hid_report_descriptor(&self, number: InterfaceNum)
+        -> Option<class::hid::ReportDescriptor>
+    {
+        use class::hid::ReportDescriptor;
+        match self.hid_report_descriptors.load().get(number) {
+            Some(descriptor) => Some(ReportDescriptor::parse(descriptor)),
+            None => ReportDescriptor::boot(&self.interface_descriptor(number)?)
+        }
+    }
+
+    /// Decoder for the class-specific traffic of an interface, if any.
+    pub fn class_decoder(&self, number: InterfaceNum)
+        -> Option<&'static dyn class::ClassDecoder>
+    {
+        let descriptor = self.interface_descriptor(number)?;
+        let device = self.device_descriptor.load_full();
+        class::find_decoder(&class::InterfaceInfo {
+            descriptor: &descriptor,
+            device: device.as_deref(),
+            hid_usage_page: self.hid_usage_page(number),
+        })
+    }
+
+    /// Label for the function of an interface, e.g. "HID keyboard".
+    pub fn interface_label(&self, descriptor: &InterfaceDescriptor)
+        -> Option<String>
+    {
+        let device = self.device_descriptor.load_full();
+        class::interface_label(&class::InterfaceInfo {
+            descriptor,
+            device: device.as_deref(),
+            hid_usage_page: self.hid_usage_page(descriptor.interface_number),
+        })
+    }
+
+    /// Label for the function of the interface an endpoint belongs to.
+    pub fn endpoint_label(&self, addr: EndpointAddr) -> Option<String> {
+        self.interface_label(&self.endpoint_interface(addr)?)
+    }
+
+    pub fn interface_descriptor(&self, number: InterfaceNum)
+        -> Option<InterfaceDescriptor>
+    {
+        let config_number = self.config_number.load_full()?;
+        let config = self.configurations.load().get(*config_number)?.clone();
+        config.interfaces.get(number).map(|iface| iface.descriptor)
+    }
+
     pub fn set_endpoint_type(&self,
                              addr: EndpointAddr,
                              ep_type: usb::EndpointType)
@@ -425,11 +909,32 @@ impl DeviceData {
                 => self.decode_descriptor_read(fields, payload)?,
             (RequestType::Standard, StandardRequest::SetConfiguration)
                 => self.decode_configuration_set(fields)?,
+            (RequestType::Class, _)
+                => self.decode_class_request(fields, payload),
             _ => ()
         }
         Ok(())
     }
 
+    fn decode_class_request(&self, fields: &SetupFields, payload: &[u8]) {
+        if !matches!(fields.type_fields.recipient(), Recipient::Interface) {
+            return;
+        }
+        let iface_num = InterfaceNum(fields.index as u8);
+        if let Some(iface) = self.interface_descriptor(iface_num) {
+            if class::dfu::is_dfu(&iface) {
+                let mut progress = self.dfu_progress
+                    .load()
+                    .as_deref()
+                    .cloned()
+                    .unwrap_or_default();
+                progress.update(fields, payload);
+                self.dfu_progress.swap(Some(Arc::new(progress)));
+                self.increment_version();
+            }
+        }
+    }
+
     pub fn decode_descriptor_read(&self,
                                   fields: &SetupFields,
                                   payload: &[u8])
@@ -472,6 +977,16 @@ impl DeviceData {
                     self.increment_version();
                 }
             },
+            (Recipient::Interface, _) if (fields.value >> 8) as u8 ==
+                class::hid::REPORT_DESCRIPTOR =>
+            {
+                let iface_num = InterfaceNum(fields.index as u8);
+                let descriptor = payload.to_vec();
+                self.hid_report_descriptors.update(|descriptors| {
+                    descriptors.set(iface_num, descriptor.clone())
+                });
+                self.increment_version();
+            },
             _ => {}
         };
         Ok(())
@@ -536,7 +1051,7 @@ impl Transaction {
         self.payload_byte_range.as_ref().map(|range| range.len())
     }
 
-    fn successful(&self) -> bool {
+    pub fn successful(&self) -> bool {
         use PID::*;
         match (self.start_pid, self.end_pid) {
 
@@ -550,6 +1065,15 @@ impl Transaction {
         }
     }
 
+    /// Whether the device failed to respond: a token with nothing after
+    /// it, or a SETUP transaction without a handshake.
+    fn timed_out(&self) -> bool {
+        use PID::*;
+        matches!((self.start_pid, self.end_pid),
+                 (SETUP | IN | OUT, SETUP | IN | OUT) |
+                 (SETUP, DATA0 | DATA1))
+    }
+
     fn control_result(&self, direction: Direction) -> ControlResult {
         use ControlResult::*;
         use StartComplete::*;
@@ -578,7 +1102,7 @@ impl Transaction {
         }
     }
 
-    fn outcome(&self) -> Option<PID> {
+    pub fn outcome(&self) -> Option<PID> {
         use PID::*;
         match self.end_pid {
             // Any handshake response should be displayed as an outcome.
@@ -596,16 +1120,17 @@ impl Transaction {
         use PID::*;
         use StartComplete::*;
         Ok(match (self.start_pid, &self.split) {
-            (SOF, _) => format!(
-                "{} SOF packets", self.packet_count()),
-            (SPLIT, Some((split_fields, token_pid))) => format!(
-                "{} {}",
+            (SOF, _) if capture.filter.collapse_idle && !detail =>
+                capture.idle_description(&self.packet_id_range)?,
+            (SOF, _) => tr!("{} SOF packets", self.packet_count()),
+            (SPLIT, Some((split_fields, token_pid))) => {
+                let inner = self.inner_description(
+                    capture, endpoint, *token_pid, detail)?;
                 match split_fields.sc() {
-                    Start => "Starting",
-                    Complete => "Completing",
-                },
-                self.inner_description(capture, endpoint, *token_pid, detail)?
-            ),
+                    Start => tr!("Starting {}", inner),
+                    Complete => tr!("Completing {}", inner),
+                }
+            },
             (pid, _) => self.inner_description(capture, endpoint, pid, detail)?
         })
     }
@@ -617,39 +1142,40 @@ impl Transaction {
                          detail: bool)
         -> Result<String, Error>
     {
-        let mut s = String::new();
-        if detail {
-            write!(s, "{} transaction on device {}, endpoint {}",
-                pid, endpoint.device_address(), endpoint.number())
+        let address = endpoint.device_address();
+        let number = endpoint.number();
+        let mut s = if detail {
+            tr!("{} transaction on device {}, endpoint {}",
+                pid, address, number)
         } else {
-            write!(s, "{} transaction on {}.{}",
-                pid, endpoint.device_address(), endpoint.number())
-        }?;
-        match (self.payload_size(), self.outcome(), detail) {
-            (None, None, _) => Ok(()),
-            (None, Some(outcome), false) => write!(s,
+            tr!("{} transaction on {}.{}", pid, address, number)
+        };
+        s += &match (self.payload_size(), self.outcome(), detail) {
+            (None, None, _) => String::new(),
+            (None, Some(outcome), false) => format!(
                 ", {outcome}"),
-            (None, Some(outcome), true) => write!(s,
-                ", {outcome} response"),
-            (Some(0), None, _) => write!(s,
-                " with no data"),
-            (Some(0), Some(outcome), false) => write!(s,
-                " with no data, {outcome}"),
-            (Some(0), Some(outcome), true) => write!(s,
-                " with no data, {outcome} response"),
-            (Some(size), None, false) => write!(s,
-                " with {size} data bytes: {}",
+            (None, Some(outcome), true) => tr!(
+                ", {} response", outcome),
+            (Some(0), None, _) => tr!(
+                " with no data").to_string(),
+            (Some(0), Some(outcome), false) => tr!(
+                " with no data, {}", outcome),
+            (Some(0), Some(outcome), true) => tr!(
+                " with no data, {} response", outcome),
+            (Some(size), None, false) => tr!(
+                " with {} data bytes: {}", size,
                 Bytes::first(100, &capture.transaction_bytes(self)?)),
-            (Some(size), None, true) => write!(s,
-                " with {size} data bytes\nPayload: {}",
+            (Some(size), None, true) => tr!(
+                " with {} data bytes\nPayload: {}", size,
                 Bytes::first(1024, &capture.transaction_bytes(self)?)),
-            (Some(size), Some(outcome), false) => write!(s,
-                " with {size} data bytes, {outcome}: {}",
+            (Some(size), Some(outcome), false) => tr!(
+                " with {} data bytes, {}: {}", size, outcome,
                 Bytes::first(100, &capture.transaction_bytes(self)?)),
-            (Some(size), Some(outcome), true) => write!(s,
-                " with {size} data bytes, {outcome} response\nPayload: {}",
+            (Some(size), Some(outcome), true) => tr!(
+                " with {} data bytes, {} response\nPayload: {}",
+                size, outcome,
                 Bytes::first(1024, &capture.transaction_bytes(self)?)),
-        }?;
+        };
         Ok(s)
     }
 }
@@ -851,11 +1377,8 @@ impl CaptureReader {
             let data_id = data_range.next().with_context(|| format!(
                 "Ran out of data events after fetching {}/{} requested bytes",
                 transfer_bytes.len(), length))?;
-            let ep_traf = self.endpoint_traffic(endpoint_id)?;
-            let ep_transaction_id = ep_traf.data_transactions.get(data_id)?;
-            let transaction_id = ep_traf.transaction_ids.get(ep_transaction_id)?;
-            let transaction = self.transaction(transaction_id)?;
-            let transaction_bytes = self.transaction_bytes(&transaction)?;
+            let transaction_bytes =
+                self.data_event_payload(endpoint_id, data_id)?;
             let required = min(
                 length - transfer_bytes.len(),
                 transaction_bytes.len()
@@ -865,142 +1388,1788 @@ impl CaptureReader {
         Ok(transfer_bytes)
     }
 
-    fn endpoint_state(&mut self, transfer_id: TransferId)
+    /// Fetch the payload of a single data event on an endpoint.
+    pub fn data_event_payload(&mut self,
+                              endpoint_id: EndpointId,
+                              data_id: EndpointDataEvent)
         -> Result<Vec<u8>, Error>
     {
-        let range = self.endpoint_state_index.target_range(
-            transfer_id, self.endpoint_states.len())?;
-        self.endpoint_states.get_range(&range)
+        let ep_traf = self.endpoint_traffic(endpoint_id)?;
+        let ep_transaction_id = ep_traf.data_transactions.get(data_id)?;
+        let transaction_id = ep_traf.transaction_ids.get(ep_transaction_id)?;
+        let transaction = self.transaction(transaction_id)?;
+        self.transaction_bytes(&transaction)
     }
 
-    pub fn packet(&mut self, id: PacketId)
+    /// Fetch all data transferred on an endpoint, in order.
+    pub fn endpoint_data(&mut self, endpoint_id: EndpointId)
         -> Result<Vec<u8>, Error>
     {
-        let range = self.packet_index.target_range(
-            id, self.packet_data.len())?;
-        self.packet_data.get_range(&range)
+        let ep_traf = self.endpoint_traffic(endpoint_id)?;
+        let data_range = 0..ep_traf.data_transactions.len();
+        let length = ep_traf.transfer_data_length(&data_range)?.try_into()?;
+        self.transfer_bytes(endpoint_id, &data_range, length)
     }
 
-    pub fn packet_time(&mut self, id: PacketId)
-        -> Result<Timestamp, Error>
+    pub fn endpoint_id(&mut self, device_id: DeviceId, addr: EndpointAddr)
+        -> Result<Option<EndpointId>, Error>
     {
-        self.packet_times.get(id)
+        for i in 0..self.endpoints.len() {
+            let endpoint_id = EndpointId::from(i);
+            let endpoint = self.endpoints.get(endpoint_id)?;
+            if endpoint.device_id() == device_id && endpoint.address() == addr {
+                return Ok(Some(endpoint_id));
+            }
+        }
+        Ok(None)
     }
 
-    pub fn timestamped_packets(&mut self)
-        -> Result<impl Iterator<Item=Result<(u64, Vec<u8>), Error>>, Error>
+    /// Reconstruct the serial data streams of a CDC data interface.
+    ///
+    /// Returns None if the interface is not a CDC data interface.
+    pub fn serial_data(&mut self,
+                       device_id: DeviceId,
+                       config_num: ConfigNum,
+                       iface_num: InterfaceNum)
+        -> Result<Option<String>, Error>
     {
-        let packet_count = self.packet_index.len();
-        let packet_ids = PacketId::from(0)..PacketId::from(packet_count);
-        let timestamps = self.packet_times.iter(&packet_ids)?;
-        let packet_starts = self.packet_index.iter(&packet_ids)?;
-        let packet_ends = self.packet_index
-            .iter(&packet_ids)?
-            .skip(1)
-            .chain(once(Ok(PacketByteId::from(self.packet_data.len()))));
-        let data_ranges = packet_starts.zip(packet_ends);
-        let mut packet_data = self.packet_data.clone();
-        Ok(timestamps
-            .zip(data_ranges)
-            .map(move |(ts, (start, end))| -> Result<(u64, Vec<u8>), Error> {
-                let timestamp = ts?;
-                let data_range = start?..end?;
-                let packet = packet_data.get_range(&data_range)?;
-                Ok((timestamp, packet))
-            })
-        )
+        let config = match self.try_configuration(&device_id, &config_num) {
+            Some(config) => config,
+            None => return Ok(None)
+        };
+        let iface = config.interface(&iface_num)?;
+        if !class::cdc::is_data(&iface.descriptor) {
+            return Ok(None);
+        }
+        let address = self.devices.get(device_id)?.address;
+        let mut s = String::new();
+        write!(s, "Serial data on interface {address}.{iface_num}")?;
+        for ep_desc in &iface.endpoint_descriptors {
+            let ep_addr = ep_desc.endpoint_address;
+            let data = match self.endpoint_id(device_id, ep_addr)? {
+                Some(endpoint_id) => self.endpoint_data(endpoint_id)?,
+                None => Vec::new()
+            };
+            write!(s, "\n\n{} on endpoint {} ({}):\n{}",
+                match ep_addr.direction() {
+                    Direction::In => "Device to host",
+                    Direction::Out => "Host to device",
+                },
+                ep_addr.number(),
+                fmt_size(data.len() as u64),
+                class::cdc::stream_text(&data))?;
+        }
+        Ok(Some(s))
     }
 
-    fn packet_pid(&mut self, id: PacketId)
-        -> Result<PID, Error>
+    /// Timestamp of the transaction carrying a data event on an endpoint.
+    pub fn data_event_time(&mut self,
+                           endpoint_id: EndpointId,
+                           data_id: EndpointDataEvent)
+        -> Result<Timestamp, Error>
     {
-        let offset: Id<u8> = self.packet_index.get(id)?;
-        Ok(PID::from(self.packet_data.get(offset)?))
+        let ep_traf = self.endpoint_traffic(endpoint_id)?;
+        let ep_transaction_id = ep_traf.data_transactions.get(data_id)?;
+        let transaction_id = ep_traf.transaction_ids.get(ep_transaction_id)?;
+        let packet_id = self.transaction_index.get(transaction_id)?;
+        self.packet_time(packet_id)
     }
 
-    pub fn transaction(&mut self, id: TransactionId)
-        -> Result<Transaction, Error>
+    /// Collect the data sent on the endpoints of an interface, in time
+    /// order, with the direction in which each was sent. If a direction is
+    /// given, only the endpoints in that direction are included.
+    fn interface_reports(&mut self,
+                         device_id: DeviceId,
+                         iface: &Interface,
+                         direction: Option<Direction>)
+        -> Result<Vec<(Timestamp, Direction, Vec<u8>)>, Error>
     {
-        let packet_id_range = self.transaction_index.target_range(
-            id, self.packet_index.len())?;
-        let packet_count = packet_id_range.len();
-        let start_packet_id = packet_id_range.start;
-        let start_pid = self.packet_pid(start_packet_id)?;
-        let end_pid = self.packet_pid(packet_id_range.end - 1)?;
-        use PID::*;
-        use StartComplete::*;
-        let (split, data_packet_id) = match start_pid {
-            SETUP | IN | OUT if packet_count >= 2 =>
-                (None, Some(start_packet_id + 1)),
-            SPLIT => {
-                let token_packet_id = start_packet_id + 1;
-                let split_packet = self.packet(start_packet_id)?;
-                let token_pid = self.packet_pid(token_packet_id)?;
-                let split_fields = SplitFields::from_packet(&split_packet);
-                let data_packet_id = match (split_fields.sc(), token_pid) {
-                    (Start, SETUP | OUT) | (Complete, IN) => {
-                        if packet_count >= 3 {
-                            Some(start_packet_id + 2)
-                        } else {
-                            None
-                        }
-                    },
-                    (..) => None
-                };
-                (Some((split_fields, token_pid)), data_packet_id)
-            },
-            _ => (None, None)
-        };
-        let payload_byte_range = if let Some(packet_id) = data_packet_id {
-            let packet_byte_range = self.packet_index.target_range(
-                packet_id, self.packet_data.len())?;
-            let pid = self.packet_data.get(packet_byte_range.start)?;
-            match PID::from(pid) {
-                DATA0 | DATA1 => Some({
-                    packet_byte_range.start + 1 .. packet_byte_range.end - 2
-                }),
-                _ => None
+        let mut reports = Vec::new();
+        for ep_desc in &iface.endpoint_descriptors {
+            let ep_addr = ep_desc.endpoint_address;
+            let ep_direction = ep_addr.direction();
+            if direction.is_some_and(|direction| direction != ep_direction) {
+                continue;
             }
-        } else {
-            None
+            let endpoint_id = match self.endpoint_id(device_id, ep_addr)? {
+                Some(endpoint_id) => endpoint_id,
+                None => continue
+            };
+            let data_count = self
+                .endpoint_traffic(endpoint_id)?
+                .data_transactions
+                .len();
+            for data_id in 0..data_count {
+                let time = self.data_event_time(endpoint_id, data_id)?;
+                let payload = self.data_event_payload(endpoint_id, data_id)?;
+                reports.push((time, ep_direction, payload));
+            }
+        }
+        reports.sort_by_key(|(time, ..)| *time);
+        Ok(reports)
+    }
+
+    /// Reassemble the CTAPHID messages exchanged with a FIDO interface.
+    ///
+    /// Returns None if the interface is not a HID interface with the FIDO
+    /// usage page.
+    pub fn ctap_messages(&mut self,
+                         device_id: DeviceId,
+                         config_num: ConfigNum,
+                         iface_num: InterfaceNum)
+        -> Result<Option<String>, Error>
+    {
+        use class::ctap::{Assembler, REPORT_SIZE, USAGE_PAGE_FIDO};
+        let config = match self.try_configuration(&device_id, &config_num) {
+            Some(config) => config,
+            None => return Ok(None)
         };
-        Ok(Transaction {
-            start_pid,
-            end_pid,
-            split,
-            data_packet_id,
-            packet_id_range,
-            payload_byte_range,
-        })
+        let iface = config.interface(&iface_num)?;
+        let dev_data = self.device_data(&device_id)?;
+        if !class::hid::is_hid(&iface.descriptor) ||
+            dev_data.hid_usage_page(iface_num) != Some(USAGE_PAGE_FIDO)
+        {
+            return Ok(None);
+        }
+        let reports = self.interface_reports(device_id, iface, None)?;
+        let address = self.devices.get(device_id)?.address;
+        let mut s = String::new();
+        write!(s, "CTAPHID messages on interface {address}.{iface_num}")?;
+        let mut assemblers = [Assembler::default(), Assembler::default()];
+        let mut count = 0;
+        let mut describe = |message, from_device| -> Result<(), Error> {
+            let direction = if from_device { "Device" } else { "Host" };
+            write!(s, "\n{direction}: {}",
+                class::ctap::message_description(&message, from_device))?;
+            count += 1;
+            Ok(())
+        };
+        for (_, direction, payload) in reports {
+            let from_device = direction == Direction::In;
+            let assembler = &mut assemblers[from_device as usize];
+            for report in payload.chunks(REPORT_SIZE) {
+                for message in assembler.add_report(report) {
+                    describe(message, from_device)?;
+                }
+            }
+        }
+        let [host, device] = assemblers;
+        for (assembler, from_device) in [(host, false), (device, true)] {
+            if let Some(message) = assembler.finish() {
+                describe(message, from_device)?;
+            }
+        }
+        if count == 0 {
+            s.push_str("\nNo messages found");
+        }
+        Ok(Some(s))
     }
 
-    fn control_transfer(&mut self,
-                        address: DeviceAddr,
-                        endpoint_id: EndpointId,
-                        range: Range<EndpointTransactionId>)
-        -> Result<ControlTransfer, Error>
+    /// Reconstruct the input from a HID keyboard or mouse interface.
+    ///
+    /// Keyboard reports are converted to the keystrokes and text typed,
+    /// and mouse reports to a summary of movements and button presses.
+    /// Returns None if the interface is not a HID keyboard or mouse.
+    pub fn input_reconstruction(&mut self,
+                                device_id: DeviceId,
+                                config_num: ConfigNum,
+                                iface_num: InterfaceNum)
+        -> Result<Option<String>, Error>
     {
-        let ep_traf = self.endpoint_traffic(endpoint_id)?;
-        let transaction_ids = ep_traf.transaction_ids.get_range(&range)?;
-        let data_range = ep_traf.transfer_data_range(&range)?;
-        let data_length = ep_traf
-            .transfer_data_length(&data_range)?
-            .try_into()?;
-        let data = self.transfer_bytes(endpoint_id, &data_range, data_length)?;
-        let setup_transaction = self.transaction(transaction_ids[0])?;
+        use class::input::{
+            fmt_seconds, is_keyboard, is_mouse, Keyboard, Mouse
+        };
+        let config = match self.try_configuration(&device_id, &config_num) {
+            Some(config) => config,
+            None => return Ok(None)
+        };
+        let iface = config.interface(&iface_num)?;
+        if !class::hid::is_hid(&iface.descriptor) {
+            return Ok(None);
+        }
+        let descriptor = match self
+            .device_data(&device_id)?
+            .hid_report_descriptor(iface_num)
+        {
+            Some(descriptor) => descriptor,
+            None => return Ok(None)
+        };
+        let keyboard = is_keyboard(&descriptor);
+        if !keyboard && !is_mouse(&descriptor) {
+            return Ok(None);
+        }
+        let reports =
+            self.interface_reports(device_id, iface, Some(Direction::In))?;
+        let address = self.devices.get(device_id)?.address;
+        let mut s = String::new();
+        if keyboard {
+            let mut keyboard = Keyboard::default();
+            for (time, _, payload) in &reports {
+                keyboard.add_report(&descriptor, *time, payload);
+            }
+            write!(s, "Keyboard input on interface {address}.{iface_num}")?;
+            if keyboard.keystrokes().is_empty() {
+                s.push_str("\nNo keystrokes found");
+                return Ok(Some(s));
+            }
+            write!(s, "\n\nText typed:\n{}\n\nKeystrokes:",
+                keyboard.text())?;
+            for keystroke in keyboard.keystrokes() {
+                write!(s, "\n{}: {}",
+                    fmt_seconds(keystroke.timestamp_ns),
+                    keystroke.description())?;
+            }
+        } else {
+            let mut mouse = Mouse::default();
+            for (time, _, payload) in &reports {
+                mouse.add_report(&descriptor, *time, payload);
+            }
+            let (events, (x, y)) = mouse.finish();
+            write!(s, "Mouse input on interface {address}.{iface_num}")?;
+            if events.is_empty() {
+                s.push_str("\nNo movement found");
+                return Ok(Some(s));
+            }
+            write!(s, "\nTotal movement {x:+}, {y:+}\n")?;
+            for event in events {
+                write!(s, "\n{}: {}",
+                    fmt_seconds(event.timestamp_ns),
+                    event.description)?;
+            }
+        }
+        Ok(Some(s))
+    }
+
+    /// The type of an endpoint, as described by its device.
+    pub fn endpoint_type(&mut self, endpoint_id: EndpointId)
+        -> Result<EndpointType, Error>
+    {
+        let endpoint = self.endpoints.get(endpoint_id)?;
+        let dev_data = self.device_data(&endpoint.device_id())?;
+        Ok(dev_data.endpoint_details(endpoint.address()).0)
+    }
+
+    /// Pass the payload of each data packet on an endpoint to a function.
+    ///
+    /// Isochronous transactions have no handshake, so their data is not
+    /// indexed as data events, and is taken from the transactions instead.
+    pub fn endpoint_payloads<F>(&mut self,
+                                endpoint_id: EndpointId,
+                                mut payload_fn: F)
+        -> Result<(), Error>
+        where F: FnMut(Vec<u8>) -> Result<(), Error>
+    {
+        let endpoint = self.endpoints.get(endpoint_id)?;
+        let dev_data = self.device_data(&endpoint.device_id())?;
+        let (ep_type, _) = dev_data.endpoint_details(endpoint.address());
+        let ep_traf = self.endpoint_traffic(endpoint_id)?;
+        if matches!(ep_type,
+            EndpointType::Normal(usb::EndpointType::Isochronous))
+        {
+            let transaction_count = ep_traf.transaction_ids.len();
+            for i in 0..transaction_count {
+                let ep_transaction_id = EndpointTransactionId::from(i);
+                let transaction_id = self
+                    .endpoint_traffic(endpoint_id)?
+                    .transaction_ids
+                    .get(ep_transaction_id)?;
+                let transaction = self.transaction(transaction_id)?;
+                if transaction.payload_byte_range.is_some() {
+                    payload_fn(self.transaction_bytes(&transaction)?)?;
+                }
+            }
+        } else {
+            let data_count = ep_traf.data_transactions.len();
+            for data_id in 0..data_count {
+                payload_fn(self.data_event_payload(endpoint_id, data_id)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect the audio data sent on a UAC streaming endpoint.
+    ///
+    /// Returns the sample format of the stream along with its data, or
+    /// None if the endpoint has no audio format that can be exported.
+    pub fn audio_data(&mut self, endpoint_id: EndpointId)
+        -> Result<Option<(class::uac::Format, Vec<u8>)>, Error>
+    {
+        let endpoint = self.endpoints.get(endpoint_id)?;
+        let dev_data = self.device_data(&endpoint.device_id())?;
+        let format = match dev_data.endpoint_audio_format(endpoint.address()) {
+            Some(format) => format,
+            None => return Ok(None)
+        };
+        let mut data = Vec::new();
+        self.endpoint_payloads(endpoint_id, |bytes| {
+            data.extend(bytes);
+            Ok(())
+        })?;
+        Ok(Some((format, data)))
+    }
+
+    /// Collect the HCI packets exchanged with a Bluetooth controller.
+    ///
+    /// Packets are returned in order of the start of their transfers.
+    pub fn hci_packets(&mut self, device_id: DeviceId)
+        -> Result<Vec<class::bt::HciPacket>, Error>
+    {
+        use class::bt::{HciPacket, PacketType};
+        let dev_data = self.device_data(&device_id)?;
+        match dev_data.interface_descriptor(InterfaceNum(0)) {
+            Some(iface) if class::bt::is_bluetooth(&iface) => {},
+            _ => bail!("Device {device_id} is not a Bluetooth controller")
+        }
+        let mut packets = Vec::new();
+        for i in 0..self.endpoints.len() {
+            let endpoint_id = EndpointId::from(i);
+            let endpoint = self.endpoints.get(endpoint_id)?;
+            if endpoint.device_id() != device_id {
+                continue;
+            }
+            let ep_addr = endpoint.address();
+            let (ep_type, _) = dev_data.endpoint_details(ep_addr);
+            let received = matches!(ep_addr.direction(), Direction::In);
+            let packet_type = match ep_type {
+                EndpointType::Normal(usb::EndpointType::Control) =>
+                    PacketType::Command,
+                EndpointType::Normal(usb::EndpointType::Interrupt)
+                    if received => PacketType::Event,
+                EndpointType::Normal(usb::EndpointType::Bulk) =>
+                    PacketType::AclData,
+                _ => continue
+            };
+            let transfer_count =
+                self.endpoint_traffic(endpoint_id)?.transfer_index.len();
+            for index in 0..transfer_count {
+                let ep_transfer_id = EndpointTransferId::from(index);
+                let ep_traf = self.endpoint_traffic(endpoint_id)?;
+                let range = ep_traf.transfer_index.target_range(
+                    ep_transfer_id, ep_traf.transaction_ids.len())?;
+                let first_transaction_id =
+                    ep_traf.transaction_ids.get(range.start)?;
+                let data = if packet_type == PacketType::Command {
+                    let address = endpoint.device_address();
+                    match self.control_transfer(
+                        address, &dev_data, endpoint_id, range)
+                    {
+                        Ok(transfer) if matches!(
+                            transfer.fields.type_fields.request_type(),
+                            RequestType::Class) && matches!(
+                            transfer.result, ControlResult::Completed)
+                            => transfer.data,
+                        _ => continue
+                    }
+                } else {
+                    let data_range = ep_traf.transfer_data_range(&range)?;
+                    let length = ep_traf.transfer_data_length(&data_range)?;
+                    self.transfer_bytes(
+                        endpoint_id, &data_range, length.try_into()?)?
+                };
+                if data.is_empty() {
+                    continue;
+                }
+                let packet_id =
+                    self.transaction_index.get(first_transaction_id)?;
+                packets.push(HciPacket {
+                    timestamp_ns: self.packet_time(packet_id)?,
+                    packet_type,
+                    received: received && packet_type != PacketType::Command,
+                    data,
+                });
+            }
+        }
+        packets.sort_by_key(|packet| packet.timestamp_ns);
+        Ok(packets)
+    }
+
+    /// Collect the control transfers made to a device which can be
+    /// decoded, in the order they were made on each control endpoint.
+    pub fn control_transfers(&mut self, device_id: DeviceId)
+        -> Result<Vec<ControlTransfer>, Error>
+    {
+        let dev_data = self.device_data(&device_id)?;
+        let mut transfers = Vec::new();
+        for i in 0..self.endpoints.len() {
+            let endpoint_id = EndpointId::from(i);
+            let endpoint = self.endpoints.get(endpoint_id)?;
+            if endpoint.device_id() != device_id {
+                continue;
+            }
+            let (ep_type, _) = dev_data.endpoint_details(endpoint.address());
+            let EndpointType::Normal(usb::EndpointType::Control) = ep_type
+            else {
+                continue;
+            };
+            let address = endpoint.device_address();
+            let transfer_count =
+                self.endpoint_traffic(endpoint_id)?.transfer_index.len();
+            for index in 0..transfer_count {
+                let ep_transfer_id = EndpointTransferId::from(index);
+                let ep_traf = self.endpoint_traffic(endpoint_id)?;
+                let range = ep_traf.transfer_index.target_range(
+                    ep_transfer_id, ep_traf.transaction_ids.len())?;
+                if let Ok(transfer) = self.control_transfer(
+                    address, &dev_data, endpoint_id, range)
+                {
+                    transfers.push(transfer);
+                }
+            }
+        }
+        Ok(transfers)
+    }
+
+    /// Reconstruct the video frames sent on a UVC streaming endpoint.
+    ///
+    /// Each frame is passed to the supplied function in turn, and the
+    /// number of frames found is returned.
+    pub fn video_frames<F>(&mut self, endpoint_id: EndpointId, mut frame_fn: F)
+        -> Result<u64, Error>
+        where F: FnMut(Vec<u8>) -> Result<(), Error>
+    {
+        use class::uvc::FrameAssembler;
+        let endpoint = self.endpoints.get(endpoint_id)?;
+        let dev_data = self.device_data(&endpoint.device_id())?;
+        let (ep_type, ep_max) = dev_data.endpoint_details(endpoint.address());
+        // Isochronous packets each begin with a payload header. On bulk
+        // endpoints, a header begins each transfer, ended by a short packet.
+        let isochronous = matches!(ep_type,
+            EndpointType::Normal(usb::EndpointType::Isochronous));
+        let mut assembler = FrameAssembler::default();
+        let mut payload = Vec::new();
+        let mut count = 0;
+        let mut add_payload = |payload: &[u8]| -> Result<(), Error> {
+            for frame in assembler.add_payload(payload) {
+                frame_fn(frame)?;
+                count += 1;
+            }
+            Ok(())
+        };
+        self.endpoint_payloads(endpoint_id, |bytes| {
+            let short = ep_max.map_or(true, |max| bytes.len() < max);
+            payload.extend(bytes);
+            if isochronous || short {
+                add_payload(&payload)?;
+                payload.clear();
+            }
+            Ok(())
+        })?;
+        if !payload.is_empty() {
+            add_payload(&payload)?;
+        }
+        if let Some(frame) = assembler.finish() {
+            frame_fn(frame)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn endpoint_state(&mut self, transfer_id: TransferId)
+        -> Result<Vec<u8>, Error>
+    {
+        let range = self.endpoint_state_index.target_range(
+            transfer_id, self.endpoint_states.len())?;
+        self.endpoint_states.get_range(&range)
+    }
+
+    /// Access the data of a packet, without copying it where possible.
+    pub fn packet(&mut self, id: PacketId)
+        -> Result<impl Deref<Target=[u8]>, Error>
+    {
+        let range = self.packet_index.target_range(
+            id, self.packet_data.len())?;
+        self.packet_data.slice(&range)
+    }
+
+    pub fn packet_time(&mut self, id: PacketId)
+        -> Result<Timestamp, Error>
+    {
+        self.packet_times.get(id)
+    }
+
+    /// First packet at or after a time, found by bisecting the timestamps.
+    ///
+    /// Returns the packet count if there are no packets at or after it.
+    pub fn packet_at_time(&mut self, time: Timestamp)
+        -> Result<PacketId, Error>
+    {
+        self.packet_times.bisect_left(&time)
+    }
+
+    /// Packets with timestamps within a time range.
+    pub fn time_range_packets(&mut self, time_range: &Range<Timestamp>)
+        -> Result<Range<PacketId>, Error>
+    {
+        let start = self.packet_at_time(time_range.start)?;
+        let end = self.packet_at_time(time_range.end)?;
+        Ok(start..max(start, end))
+    }
+
+    /// Name of the capture a packet came from, if several were merged.
+    pub fn packet_source(&mut self, id: PacketId)
+        -> Result<Option<String>, Error>
+    {
+        if self.packet_sources.len() == 0 {
+            return Ok(None);
+        }
+        let source = self.packet_sources.get(Id::<u8>::from(id.value))?;
+        let sources = self.shared.sources.load();
+        Ok(Some(match sources.get(source as usize) {
+            Some(name) => name.clone(),
+            None => format!("source {}", source + 1),
+        }))
+    }
+
+    /// Show only the top-level traffic items matching a filter.
+    ///
+    /// Markers are always shown.
+    pub fn set_filter(&mut self, filter: TrafficFilter) {
+        self.filter = filter;
+        self.filter_endpoints.clear();
+        self.filter_checked = 0;
+    }
+
+    /// Check any endpoints added since the filter was last updated.
+    fn update_filter(&mut self) -> Result<(), Error> {
+        let endpoint_count =
+            self.shared.endpoint_readers.load().len() as u64;
+        for id in self.filter_checked..endpoint_count {
+            let endpoint_id = EndpointId::from(id);
+            let endpoint = self.endpoints.get(endpoint_id)?;
+            if self.filter.matches(&endpoint) {
+                self.filter_endpoints.push(endpoint_id);
+            }
+        }
+        self.filter_checked = endpoint_count;
+        Ok(())
+    }
+
+    /// Number of top-level transfer items matching the filter, out of
+    /// those before the given item.
+    fn filtered_items_before(&mut self, item_id: TrafficItemId)
+        -> Result<u64, Error>
+    {
+        let hide_nak = self.filter.hide_nak;
+        let mut count = 0;
+        for i in 0..self.filter_endpoints.len() {
+            let endpoint_id = self.filter_endpoints[i];
+            let ep_traf = self.endpoint_traffic(endpoint_id)?;
+            count += ep_traf.item_ids.bisect_left(&item_id)?.value;
+            if hide_nak {
+                count -= ep_traf.polling_item_ids.bisect_left(&item_id)?.value;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Number of top-level transfer items matching the filter.
+    fn filtered_item_count(&mut self) -> Result<u64, Error> {
+        if self.filter.hide_nak {
+            // Only count items already known to be polling or not, so
+            // that items are never removed once shown.
+            let settled = self.shared.settled_items.load(Acquire);
+            return self.filtered_items_before(TrafficItemId::from(settled));
+        }
+        let mut count = 0;
+        for i in 0..self.filter_endpoints.len() {
+            let endpoint_id = self.filter_endpoints[i];
+            count += self.endpoint_traffic(endpoint_id)?.item_ids.len();
+        }
+        Ok(count)
+    }
+
+    /// Position of a marker among the filtered top-level items.
+    fn filtered_marker_position(&mut self, index: usize, marker: &Marker)
+        -> Result<u64, Error>
+    {
+        let transfers_before = marker.position - index as u64;
+        let item_id = TrafficItemId::from(transfers_before);
+        Ok(index as u64 + self.filtered_items_before(item_id)?)
+    }
+
+    /// Find a top-level item among those matching the filter.
+    fn filtered_item(&mut self, index: u64) -> Result<TrafficItem, Error> {
+        self.update_filter()?;
+        // Find the first marker at or after this position.
+        let markers = self.shared.markers.load_full();
+        let (mut low, mut high) = (0, markers.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.filtered_marker_position(mid, &markers[mid])? < index {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low < markers.len() &&
+            self.filtered_marker_position(low, &markers[low])? == index
+        {
+            return Ok(TrafficItem::Marker(MarkerId::from(low as u64)));
+        }
+        // Otherwise, find the first item with enough matches up to it.
+        let matches_before = index - low as u64;
+        let (mut low, mut high) = (0, self.item_index.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let end_id = TrafficItemId::from(mid + 1);
+            if self.filtered_items_before(end_id)? > matches_before {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        let transfer_id = self.item_index.get(TrafficItemId::from(low))?;
+        Ok(TrafficItem::Transfer(transfer_id))
+    }
+
+    /// Position of a top-level transfer item among those shown, or None
+    /// if it is hidden by the filter.
+    fn root_index(&mut self, item_id: TrafficItemId)
+        -> Result<Option<u64>, Error>
+    {
+        let markers = self.shared.markers.load_full();
+        let markers_before = markers_before(&markers, item_id);
+        if self.filter.is_empty() {
+            return Ok(Some(item_id.value + markers_before));
+        }
+        self.update_filter()?;
+        let transfer_id = self.item_index.get(item_id)?;
+        let endpoint_id = self.transfer_index.get(transfer_id)?.endpoint_id();
+        if !self.filter_endpoints.contains(&endpoint_id) {
+            return Ok(None);
+        }
+        if self.filter.hide_nak {
+            let polling_ids = &mut self
+                .endpoint_traffic(endpoint_id)?
+                .polling_item_ids;
+            let position = polling_ids.bisect_left(&item_id)?;
+            if position.value < polling_ids.len() &&
+                polling_ids.get(position)? == item_id
+            {
+                return Ok(None);
+            }
+        }
+        Ok(Some(self.filtered_items_before(item_id)? + markers_before))
+    }
+
+    /// Indices leading to an item in the traffic view, starting from its
+    /// top-level item, or None if the item is hidden by the filter.
+    pub fn item_path(&mut self, item: &TrafficItem)
+        -> Result<Option<Vec<u64>>, Error>
+    {
+        use TrafficItem::*;
+        let (transfer_id, transaction_id, packet_id) = match *item {
+            Marker(marker_id) => {
+                let index = marker_id.value as usize;
+                let marker = self.marker(marker_id)?;
+                let position = if self.filter.is_empty() {
+                    marker.position
+                } else {
+                    self.update_filter()?;
+                    self.filtered_marker_position(index, &marker)?
+                };
+                return Ok(Some(vec![position]));
+            },
+            Transfer(transfer_id) => (transfer_id, None, None),
+            Transaction(transfer_id, transaction_id) =>
+                (transfer_id, Some(transaction_id), None),
+            Packet(transfer_id, transaction_id, packet_id) =>
+                (transfer_id, Some(transaction_id), Some(packet_id)),
+        };
+        let item_id = self.item_index.bisect_left(&transfer_id)?;
+        if item_id.value >= self.item_index.len() ||
+            self.item_index.get(item_id)? != transfer_id
+        {
+            bail!("Transfer {transfer_id} is not a top-level item");
+        }
+        let Some(root_index) = self.root_index(item_id)? else {
+            return Ok(None);
+        };
+        let mut path = vec![root_index];
+        if let Some(transaction_id) = transaction_id {
+            let entry = self.transfer_index.get(transfer_id)?;
+            let ep_traf = self.endpoint_traffic(entry.endpoint_id())?;
+            let first = ep_traf.transfer_index.get(entry.transfer_id())?;
+            let ep_transaction_id =
+                ep_traf.transaction_ids.bisect_left(&transaction_id)?;
+            path.push(ep_transaction_id - first);
+        }
+        if let (Some(transaction_id), Some(packet_id)) =
+            (transaction_id, packet_id)
+        {
+            let first = self.transaction_index.get(transaction_id)?;
+            path.push(packet_id - first);
+        }
+        Ok(Some(path))
+    }
+
+    /// The item for a packet, within its transaction and transfer.
+    pub fn packet_item(&mut self, packet_id: PacketId)
+        -> Result<TrafficItem, Error>
+    {
+        // Find the transaction that the packet is part of.
+        let mut transaction_id =
+            self.transaction_index.bisect_left(&packet_id)?;
+        if transaction_id.value >= self.transaction_index.len() ||
+            self.transaction_index.get(transaction_id)? != packet_id
+        {
+            transaction_id -= 1;
+        }
+        // Find the endpoint that the transaction was on.
+        let mut found = None;
+        for id in 0..self.shared.endpoint_readers.load().len() as u64 {
+            let endpoint_id = EndpointId::from(id);
+            let transaction_ids =
+                &mut self.endpoint_traffic(endpoint_id)?.transaction_ids;
+            let ep_transaction_id =
+                transaction_ids.bisect_left(&transaction_id)?;
+            if ep_transaction_id.value < transaction_ids.len() &&
+                transaction_ids.get(ep_transaction_id)? == transaction_id
+            {
+                found = Some((endpoint_id, ep_transaction_id));
+                break;
+            }
+        }
+        let (endpoint_id, ep_transaction_id) = found.with_context(||
+            format!("Transaction {transaction_id} is not on any endpoint"))?;
+        // Find the transfer that the transaction was part of.
+        let ep_traf = self.endpoint_traffic(endpoint_id)?;
+        let ep_transfer_id = ep_traf.transfer_index
+            .bisect_left(&(ep_transaction_id + 1))? - 1;
+        // Find the item that starts that transfer on this endpoint.
+        let (mut low, mut high) = (0, ep_traf.item_ids.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let item_id = self
+                .endpoint_traffic(endpoint_id)?
+                .item_ids
+                .get(EndpointItemId::from(mid))?;
+            let transfer_id = self.item_index.get(item_id)?;
+            let entry = self.transfer_index.get(transfer_id)?;
+            if (entry.transfer_id(), !entry.is_start()) <
+                (ep_transfer_id, false)
+            {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let item_id = self
+            .endpoint_traffic(endpoint_id)?
+            .item_ids
+            .get(EndpointItemId::from(low))?;
+        let transfer_id = self.item_index.get(item_id)?;
+        Ok(TrafficItem::Packet(transfer_id, transaction_id, packet_id))
+    }
+
+    pub fn marker(&mut self, id: MarkerId) -> Result<Marker, Error> {
+        self.shared.markers
+            .load()
+            .get(id.value as usize)
+            .cloned()
+            .with_context(|| format!("No marker {id}"))
+    }
+
+    /// Connectors for a marker, drawn across all endpoints.
+    fn marker_connectors(&mut self, id: MarkerId) -> Result<String, Error> {
+        let marker = self.marker(id)?;
+        let endpoint_count = self.endpoints.len() as usize;
+        // Endpoints are shown as they were after the preceding transfer.
+        let transfers_before = marker.position - id.value;
+        let endpoint_state = if transfers_before == 0 {
+            Vec::new()
+        } else {
+            let item_id = TrafficItemId::from(transfers_before - 1);
+            let transfer_id = self.item_index.get(item_id)?;
+            self.endpoint_state(transfer_id)?
+        };
+        let mut connectors = String::with_capacity(endpoint_count + 4);
+        for i in 0..endpoint_count {
+            let state = endpoint_state.get(i).copied().map(EndpointState::from);
+            connectors.push(match state {
+                Some(EndpointState::Starting | EndpointState::Ongoing) => '┼',
+                _ => '─',
+            });
+        }
+        connectors.push_str(match marker.kind {
+            MarkerKind::Note(_) => "──◆ ",
+            MarkerKind::Loss(_) => "──✕ ",
+        });
+        Ok(connectors)
+    }
+
+    pub fn timestamped_packets(&mut self)
+        -> Result<impl Iterator<Item=Result<(u64, impl Deref<Target=[u8]>),
+                                            Error>>, Error>
+    {
+        let packet_count = self.packet_index.len();
+        let packet_ids = PacketId::from(0)..PacketId::from(packet_count);
+        self.timestamped_packet_range(&packet_ids)
+    }
+
+    /// Iterate over the packets in a range, with their timestamps.
+    pub fn timestamped_packet_range(&mut self, packet_ids: &Range<PacketId>)
+        -> Result<impl Iterator<Item=Result<(u64, impl Deref<Target=[u8]>),
+                                            Error>>, Error>
+    {
+        let timestamps = self.packet_times.iter(packet_ids)?;
+        let packets = self.packet_range(packet_ids)?;
+        Ok(timestamps
+            .zip(packets)
+            .map(|(ts, packet)| Ok((ts?, packet?)))
+        )
+    }
+
+    /// Iterate over the data of a range of packets.
+    pub fn packet_range(&mut self, packet_ids: &Range<PacketId>)
+        -> Result<impl Iterator<Item=Result<impl Deref<Target=[u8]>, Error>>,
+                  Error>
+    {
+        let packet_starts = self.packet_index.iter(packet_ids)?;
+        let last_end = if packet_ids.end.value < self.packet_index.len() {
+            self.packet_index.get(packet_ids.end)?
+        } else {
+            PacketByteId::from(self.packet_data.len())
+        };
+        let packet_ends = self.packet_index
+            .iter(packet_ids)?
+            .skip(1)
+            .chain(once(Ok(last_end)));
+        let data_ranges = packet_starts.zip(packet_ends);
+        let mut packet_data = self.packet_data.clone();
+        Ok(data_ranges.map(move |(start, end)| {
+            packet_data.slice(&(start?..end?))
+        }))
+    }
+
+    /// Ranges of packets making up a subset of the capture.
+    ///
+    /// The ranges are returned in capture order, without overlaps.
+    pub fn subset_packet_ranges(&mut self, subset: &PacketSubset)
+        -> Result<Vec<Range<PacketId>>, Error>
+    {
+        use TrafficItem::*;
+        let mut ranges = Vec::new();
+        match subset {
+            PacketSubset::Item(Packet(.., packet_id)) => {
+                ranges.push(*packet_id..(*packet_id + 1));
+            },
+            PacketSubset::Item(Marker(_)) => {},
+            PacketSubset::Item(Transaction(_, transaction_id)) => {
+                ranges.push(self.transaction_packets(*transaction_id)?);
+            },
+            PacketSubset::Item(Transfer(transfer_id)) => {
+                let entry = self.transfer_index.get(*transfer_id)?;
+                if entry.is_start() {
+                    let ep_transaction_ids = self.transfer_range(&entry)?;
+                    let ep_traf = self.endpoint_traffic(entry.endpoint_id())?;
+                    let transaction_ids = if ep_transaction_ids.is_empty() {
+                        Vec::new()
+                    } else {
+                        ep_traf.transaction_ids.get_range(&ep_transaction_ids)?
+                    };
+                    for transaction_id in transaction_ids {
+                        ranges.push(self.transaction_packets(transaction_id)?);
+                    }
+                }
+            },
+            PacketSubset::TimeRange(time_range) => {
+                ranges.push(self.time_range_packets(time_range)?);
+            },
+            PacketSubset::Device(device_id) => {
+                for i in 0..self.endpoints.len() {
+                    let endpoint_id = EndpointId::from(i);
+                    let endpoint = self.endpoints.get(endpoint_id)?;
+                    if endpoint.device_id() != *device_id {
+                        continue;
+                    }
+                    let ep_traf = self.endpoint_traffic(endpoint_id)?;
+                    let count = ep_traf.transaction_ids.len();
+                    if count == 0 {
+                        continue;
+                    }
+                    let transaction_ids = ep_traf.transaction_ids.get_range(
+                        &(EndpointTransactionId::from(0)..
+                          EndpointTransactionId::from(count)))?;
+                    for transaction_id in transaction_ids {
+                        ranges.push(self.transaction_packets(transaction_id)?);
+                    }
+                }
+            },
+        }
+        Ok(merge_packet_ranges(ranges))
+    }
+
+    /// Totals over a number of traffic items.
+    ///
+    /// Packets are counted from the ranges making up the items, so that
+    /// this is quick even for large transfers, and those shared by items
+    /// such as a transfer and its transactions are counted once.
+    pub fn item_stats(&mut self, items: &[TrafficItem])
+        -> Result<ItemStats, Error>
+    {
+        let mut stats = ItemStats {
+            items: items.len() as u64,
+            ..ItemStats::default()
+        };
+        let mut ranges = Vec::new();
+        for item in items {
+            ranges.extend(
+                self.subset_packet_ranges(&PacketSubset::Item(*item))?);
+            stats.bytes += self.item_length(item)?.unwrap_or(0);
+        }
+        let ranges = merge_packet_ranges(ranges);
+        stats.packets = ranges.iter().map(|range| range.len()).sum();
+        if let (Some(first), Some(last)) = (ranges.first(), ranges.last()) {
+            let start = self.packet_time(first.start)?;
+            let end = self.packet_time(last.end - 1)? + 1;
+            stats.time_range = Some(start..end);
+        }
+        Ok(stats)
+    }
+
+    /// Iterate over the packets in a subset of the capture,
+    /// with their timestamps.
+    pub fn subset_packets(&mut self, subset: &PacketSubset)
+        -> Result<impl Iterator<Item=Result<(u64, impl Deref<Target=[u8]>),
+                                            Error>>, Error>
+    {
+        let mut iterators = Vec::new();
+        for range in self.subset_packet_ranges(subset)? {
+            iterators.push(self.timestamped_packet_range(&range)?);
+        }
+        Ok(iterators.into_iter().flatten())
+    }
+
+    /// Time span of a traffic item, from its first packet to its last.
+    pub fn item_time_range(&mut self, item: &TrafficItem)
+        -> Result<Range<Timestamp>, Error>
+    {
+        if let TrafficItem::Marker(marker_id) = item {
+            let timestamp = self.marker(*marker_id)?.timestamp;
+            return Ok(timestamp..(timestamp + 1));
+        }
+        let ranges = self.subset_packet_ranges(&PacketSubset::Item(*item))?;
+        let (Some(first), Some(last)) = (ranges.first(), ranges.last()) else {
+            bail!("Selected item has no packets")
+        };
+        let start = self.packet_time(first.start)?;
+        let end = self.packet_time(last.end - 1)?;
+        Ok(start..(end + 1))
+    }
+
+    /// Overview of the capture, divided into a number of intervals.
+    pub fn timeline(&mut self, intervals: usize) -> Result<Timeline, Error> {
+        let packet_count = self.packet_index.len();
+        if packet_count == 0 || intervals == 0 {
+            return Ok(Timeline::default());
+        }
+        let start = self.packet_time(PacketId::from(0))?;
+        let end = self.packet_time(PacketId::from(packet_count - 1))? + 1;
+        let mut timeline = Timeline {
+            time_range: start..end,
+            packets: vec![0; intervals],
+            errors: vec![0; intervals],
+            events: Vec::new(),
+        };
+        let mut first = PacketId::from(0);
+        for index in 0..intervals {
+            let last = if index + 1 == intervals {
+                PacketId::from(packet_count)
+            } else {
+                let end = timeline.interval_start(index + 1);
+                self.packet_at_time(end)?
+            };
+            timeline.packets[index] = last.value - first.value;
+            first = last;
+        }
+        for i in 0..self.endpoints.len() {
+            let endpoint_id = EndpointId::from(i);
+            if self.endpoints.get(endpoint_id)?.number() != INVALID_EP_NUM {
+                continue;
+            }
+            let ep_traf = self.endpoint_traffic(endpoint_id)?;
+            let count = ep_traf.transaction_ids.len();
+            if count == 0 {
+                continue;
+            }
+            let transaction_ids = ep_traf.transaction_ids.get_range(
+                &(EndpointTransactionId::from(0)..
+                  EndpointTransactionId::from(count)))?;
+            for transaction_id in transaction_ids {
+                let packets = self.transaction_packets(transaction_id)?;
+                let time = self.packet_time(packets.start)?;
+                if let Some(index) = timeline.interval(time) {
+                    timeline.errors[index] += packets.len();
+                }
+            }
+        }
+        for marker in self.shared.markers.load().iter() {
+            match marker.kind {
+                MarkerKind::Loss(count) => {
+                    if let Some(index) = timeline.interval(marker.timestamp) {
+                        timeline.errors[index] += count.unwrap_or(1);
+                    }
+                },
+                MarkerKind::Note(_) => timeline.events.push(marker.timestamp),
+            }
+        }
+        Ok(timeline)
+    }
+
+    /// Bytes of data transferred on an endpoint in each of a number of
+    /// equal intervals of a time range, counting each transaction's data
+    /// at the time of its first packet.
+    pub fn endpoint_throughput(&mut self,
+                               endpoint_id: EndpointId,
+                               time_range: &Range<Timestamp>,
+                               intervals: usize)
+        -> Result<Vec<u64>, Error>
+    {
+        let mut data_ids = Vec::with_capacity(intervals + 1);
+        for index in 0..=intervals {
+            let time = interval_start(time_range, intervals, index);
+            let packet_id = self.packet_at_time(time)?;
+            let transaction_id =
+                self.transaction_index.bisect_left(&packet_id)?;
+            let ep_traf = self.endpoint_traffic(endpoint_id)?;
+            let ep_transaction_id =
+                ep_traf.transaction_ids.bisect_left(&transaction_id)?;
+            data_ids.push(
+                ep_traf.data_transactions.bisect_left(&ep_transaction_id)?);
+        }
+        let ep_traf = self.endpoint_traffic(endpoint_id)?;
+        data_ids
+            .windows(2)
+            .map(|pair| ep_traf.transfer_data_length(&(pair[0]..pair[1])))
+            .collect()
+    }
+
+    fn transaction_packets(&mut self, transaction_id: TransactionId)
+        -> Result<Range<PacketId>, Error>
+    {
+        self.transaction_index.target_range(
+            transaction_id, self.packet_index.len())
+    }
+
+    /// Time from the first packet to the last of a range of transactions
+    /// on an endpoint.
+    fn transfer_duration(&mut self,
+                         endpoint_id: EndpointId,
+                         range: &Range<EndpointTransactionId>)
+        -> Result<Timestamp, Error>
+    {
+        let ep_traf = self.endpoint_traffic(endpoint_id)?;
+        let first_transaction_id = ep_traf.transaction_ids.get(range.start)?;
+        let last_transaction_id = ep_traf.transaction_ids.get(range.end - 1)?;
+        let start = self.transaction_packets(first_transaction_id)?.start;
+        let end = self.transaction_packets(last_transaction_id)?.end;
+        Ok(self.packet_time(end - 1)? - self.packet_time(start)?)
+    }
+
+    /// Describe a group of SOF packets by the number of frames for which
+    /// the bus was idle.
+    fn idle_description(&mut self, range: &Range<PacketId>)
+        -> Result<String, Error>
+    {
+        let last_id = range.end - 1;
+        let first_frame = self.sof_frame_number(range.start)?;
+        let last_frame = self.sof_frame_number(last_id)?;
+        // Frame numbers count up to 2047, then wrap around to zero.
+        let frames = (last_frame.wrapping_sub(first_frame) & 0x7ff) + 1;
+        let duration = self.packet_time(last_id)? -
+            self.packet_time(range.start)?;
+        Ok(format!("{frames} {} idle, {}",
+                   if frames == 1 {"frame"} else {"frames"},
+                   fmt_duration(duration)))
+    }
+
+    fn packet_pid(&mut self, id: PacketId)
+        -> Result<PID, Error>
+    {
+        let offset: Id<u8> = self.packet_index.get(id)?;
+        Ok(PID::from(self.packet_data.get(offset)?))
+    }
+
+    pub fn transaction(&mut self, id: TransactionId)
+        -> Result<Transaction, Error>
+    {
+        let packet_id_range = self.transaction_index.target_range(
+            id, self.packet_index.len())?;
+        let packet_count = packet_id_range.len();
+        let start_packet_id = packet_id_range.start;
+        let start_pid = self.packet_pid(start_packet_id)?;
+        let end_pid = self.packet_pid(packet_id_range.end - 1)?;
+        use PID::*;
+        use StartComplete::*;
+        let (split, data_packet_id) = match start_pid {
+            SETUP | IN | OUT if packet_count >= 2 =>
+                (None, Some(start_packet_id + 1)),
+            SPLIT => {
+                let token_packet_id = start_packet_id + 1;
+                let split_packet = self.packet(start_packet_id)?;
+                let token_pid = self.packet_pid(token_packet_id)?;
+                let split_fields = SplitFields::from_packet(&split_packet);
+                let data_packet_id = match (split_fields.sc(), token_pid) {
+                    (Start, SETUP | OUT) | (Complete, IN) => {
+                        if packet_count >= 3 {
+                            Some(start_packet_id + 2)
+                        } else {
+                            None
+                        }
+                    },
+                    (..) => None
+                };
+                (Some((split_fields, token_pid)), data_packet_id)
+            },
+            _ => (None, None)
+        };
+        let payload_byte_range = if let Some(packet_id) = data_packet_id {
+            let packet_byte_range = self.packet_index.target_range(
+                packet_id, self.packet_data.len())?;
+            let pid = self.packet_data.get(packet_byte_range.start)?;
+            match PID::from(pid) {
+                DATA0 | DATA1 => Some({
+                    packet_byte_range.start + 1 .. packet_byte_range.end - 2
+                }),
+                _ => None
+            }
+        } else {
+            None
+        };
+        Ok(Transaction {
+            start_pid,
+            end_pid,
+            split,
+            data_packet_id,
+            packet_id_range,
+            payload_byte_range,
+        })
+    }
+
+    fn control_transfer(&mut self,
+                        address: DeviceAddr,
+                        dev_data: &DeviceData,
+                        endpoint_id: EndpointId,
+                        range: Range<EndpointTransactionId>)
+        -> Result<ControlTransfer, Error>
+    {
+        let ep_traf = self.endpoint_traffic(endpoint_id)?;
+        let transaction_ids = ep_traf.transaction_ids.get_range(&range)?;
+        let data_range = ep_traf.transfer_data_range(&range)?;
+        let data_length = ep_traf
+            .transfer_data_length(&data_range)?
+            .try_into()?;
+        let data = self.transfer_bytes(endpoint_id, &data_range, data_length)?;
+        let setup_transaction = self.transaction(transaction_ids[0])?;
         let fields = self.transaction_fields(&setup_transaction)?;
         let direction = fields.type_fields.direction();
         let last = transaction_ids.len() - 1;
         let last_transaction = self.transaction(transaction_ids[last])?;
         let result = last_transaction.control_result(direction);
+        // Requests to the device as a whole are decoded according to the
+        // class of its first interface.
+        let class_iface = match (fields.type_fields.request_type(),
+                                 fields.type_fields.recipient())
+        {
+            (RequestType::Class, Recipient::Interface) =>
+                Some(InterfaceNum(fields.index as u8)),
+            (RequestType::Class, Recipient::Device) => Some(InterfaceNum(0)),
+            _ => None
+        };
+        let class_description = class_iface
+            .and_then(|number| dev_data.class_decoder(number))
+            .and_then(|decoder| decoder.request_description(&fields, &data));
         Ok(ControlTransfer {
             address,
             fields,
             data,
             result,
+            class_description,
+        })
+    }
+
+    /// The control transfer that an item starts, if it starts one which
+    /// can be decoded.
+    pub fn item_control_transfer(&mut self, item: &TrafficItem)
+        -> Result<Option<ControlTransfer>, Error>
+    {
+        let TrafficItem::Transfer(transfer_id) = item else {
+            return Ok(None);
+        };
+        let entry = self.transfer_index.get(*transfer_id)?;
+        if !entry.is_start() {
+            return Ok(None);
+        }
+        let endpoint_id = entry.endpoint_id();
+        let endpoint = self.endpoints.get(endpoint_id)?;
+        let dev_data = self.device_data(&endpoint.device_id())?;
+        let (ep_type, _) = dev_data.endpoint_details(endpoint.address());
+        let EndpointType::Normal(usb::EndpointType::Control) = ep_type else {
+            return Ok(None);
+        };
+        let range = self.transfer_range(&entry)?;
+        let address = endpoint.device_address();
+        Ok(self.control_transfer(address, &dev_data, endpoint_id, range).ok())
+    }
+
+    /// Fields decoded from a traffic item, for display as a tree.
+    pub fn detail_fields(&mut self, item: &TrafficItem)
+        -> Result<Vec<Field>, Error>
+    {
+        use TrafficItem::*;
+        match item {
+            Marker(marker_id) => {
+                let marker = self.marker(*marker_id)?;
+                Ok(vec![
+                    Field::new("Marker", marker_id.value + 1),
+                    Field::new("Timestamp", format!(
+                        "{} ns from capture start",
+                        fmt_count(marker.timestamp))),
+                    Field::new("Event", &marker.kind),
+                ])
+            },
+            Packet(.., packet_id) => self.packet_detail(*packet_id),
+            Transaction(_, transaction_id) =>
+                self.transaction_detail(*transaction_id),
+            Transfer(transfer_id) => self.transfer_detail(*transfer_id),
+        }
+    }
+
+    fn packet_detail(&mut self, id: PacketId) -> Result<Vec<Field>, Error> {
+        let packet = self.packet(id)?;
+        let mut fields = vec![
+            Field::new("Packet", id.value + 1),
+            Field::new("Timestamp", format!(
+                "{} ns from capture start",
+                fmt_count(self.packet_time(id)?))),
+        ];
+        if let Some(source) = self.packet_source(id)? {
+            fields.push(Field::new("Captured in", source));
+        }
+        fields.extend(packet_fields(&packet));
+        Ok(fields)
+    }
+
+    fn transaction_detail(&mut self, id: TransactionId)
+        -> Result<Vec<Field>, Error>
+    {
+        use PID::*;
+        let packet_id_range = self.transaction_packets(id)?;
+        let start_packet_id = packet_id_range.start;
+        let mut fields = vec![
+            Field::new("Transaction", id.value + 1),
+            Field::new("Timestamp", format!(
+                "{} ns from capture start",
+                fmt_count(self.packet_time(start_packet_id)?))),
+        ];
+        let start_packet = self.packet(start_packet_id)?;
+        let start_valid = validate_packet(&start_packet).is_ok();
+        // The transaction's bytes are those of its packets, in order.
+        let mut packets = Vec::new();
+        let mut packet_offsets = Vec::new();
+        let mut offset = 0;
+        let packet_ids = packet_id_range.start.value..packet_id_range.end.value;
+        for packet_id in packet_ids.map(PacketId::from) {
+            let packet = self.packet(packet_id)?;
+            let pid = match validate_packet(&packet) {
+                Ok(pid) => pid.to_string(),
+                Err(_) => "Malformed".to_string(),
+            };
+            let end = offset + packet.len();
+            packets.push(
+                Field::new(&format!("Packet #{}", packet_id.value + 1), pid)
+                    .at(offset..end)
+                    .with_children(
+                        offset_fields(packet_fields(&packet), offset)));
+            packet_offsets.push(offset);
+            offset = end;
+        }
+        fields.push(Field::new("Packets", packets.len())
+            .at(0..offset)
+            .with_children(packets));
+        if !start_valid {
+            return Ok(fields);
+        }
+        let transaction = self.transaction(id)?;
+        let payload_offset = transaction.data_packet_id.map(|packet_id| {
+            let index = packet_id.value - packet_id_range.start.value;
+            packet_offsets[index as usize] + 1
+        });
+        if let (Some(size), Some(start)) =
+            (transaction.payload_size(), payload_offset)
+        {
+            let end = start + size as usize;
+            fields.push(Field::new("Payload", fmt_size(size)).at(start..end));
+        }
+        if let Some(outcome) = transaction.outcome() {
+            fields.push(Field::new("Outcome", outcome));
+        }
+        if let (SETUP, _) | (SPLIT, Some((_, SETUP))) =
+            (transaction.start_pid, &transaction.split)
+        {
+            if let (Ok(setup), Some(start)) =
+                (self.transaction_fields(&transaction), payload_offset)
+            {
+                fields.push(Field::new("Setup data", "")
+                    .at(start..(start + 8))
+                    .with_children(
+                        offset_fields(setup_fields(&setup), start)));
+            }
+        }
+        Ok(fields)
+    }
+
+    fn transfer_detail(&mut self, id: TransferId)
+        -> Result<Vec<Field>, Error>
+    {
+        let entry = self.transfer_index.get(id)?;
+        let endpoint_id = entry.endpoint_id();
+        let endpoint = self.endpoints.get(endpoint_id)?;
+        let dev_data = self.device_data(&endpoint.device_id())?;
+        let ep_addr = endpoint.address();
+        let (ep_type, _) = dev_data.endpoint_details(ep_addr);
+        let range = self.transfer_range(&entry)?;
+        let mut fields = vec![
+            Field::new("Endpoint", endpoint),
+            Field::new("Endpoint type", ep_type),
+            Field::new("Transactions", range.len()),
+        ];
+        if !entry.is_start() {
+            return Ok(fields);
+        }
+        if let EndpointType::Normal(usb::EndpointType::Control) = ep_type {
+            let address = endpoint.device_address();
+            let Ok(transfer) = self.control_transfer(
+                address, &dev_data, endpoint_id, range)
+            else {
+                return Ok(fields);
+            };
+            let setup = &transfer.fields;
+            fields.push(Field::new("Request", transfer.summary()));
+            // The setup data is followed by the data stage in the bytes
+            // of a control transfer.
+            fields.push(Field::new("Setup data", "")
+                .at(0..8)
+                .with_children(setup_fields(setup)));
+            let length = transfer.data.len();
+            fields.push(Field::new("Data", fmt_size(length as u64))
+                .at(8..(8 + length)));
+            fields.push(Field::new("Result", match transfer.result {
+                ControlResult::Completed => "Completed",
+                ControlResult::Incomplete => "Incomplete",
+                ControlResult::Stalled => "Stalled",
+            }));
+            let type_fields = setup.type_fields;
+            let request = StandardRequest::from(setup.request);
+            let descriptor_type =
+                DescriptorType::from((setup.value >> 8) as u8);
+            let string_index = setup.value as u8;
+            // String descriptor zero lists language IDs, not a string.
+            if matches!(type_fields.request_type(), RequestType::Standard) &&
+                matches!(request, StandardRequest::GetDescriptor) &&
+                !(descriptor_type == DescriptorType::String &&
+                  string_index == 0)
+            {
+                let strings = dev_data.strings.load();
+                fields.extend(offset_fields(
+                    descriptor_fields(&transfer.data, &strings), 8));
+            }
+        } else if !matches!(ep_type,
+                            EndpointType::Framing | EndpointType::Invalid)
+        {
+            let ep_traf = self.endpoint_traffic(endpoint_id)?;
+            let data_range = ep_traf.transfer_data_range(&range)?;
+            let length = ep_traf.transfer_data_length(&data_range)?;
+            fields.push(Field::new("Data", fmt_size(length))
+                .at(0..(length as usize)));
+        }
+        Ok(fields)
+    }
+
+    /// Raw bytes of a traffic item, up to a limit.
+    ///
+    /// These are the bytes of a packet, or those of each packet of a
+    /// transaction in turn. For a transfer they are the data transferred,
+    /// preceded by the setup data for a control transfer. The byte ranges
+    /// of an item's detail fields are offsets into these bytes.
+    pub fn item_bytes(&mut self, item: &TrafficItem, limit: usize)
+        -> Result<Vec<u8>, Error>
+    {
+        use TrafficItem::*;
+        let mut bytes = Vec::new();
+        match item {
+            Marker(_) => {},
+            Packet(.., packet_id) => {
+                bytes = self.packet(*packet_id)?.to_vec();
+            },
+            Transaction(_, transaction_id) => {
+                let range = self.transaction_packets(*transaction_id)?;
+                for packet_id in (range.start.value..range.end.value)
+                    .map(PacketId::from)
+                {
+                    if bytes.len() >= limit {
+                        break;
+                    }
+                    bytes.extend_from_slice(&self.packet(packet_id)?);
+                }
+            },
+            Transfer(transfer_id) => {
+                let entry = self.transfer_index.get(*transfer_id)?;
+                if !entry.is_start() {
+                    return Ok(bytes);
+                }
+                let endpoint_id = entry.endpoint_id();
+                let endpoint = self.endpoints.get(endpoint_id)?;
+                let dev_data = self.device_data(&endpoint.device_id())?;
+                let ep_addr = endpoint.address();
+                let (ep_type, _) = dev_data.endpoint_details(ep_addr);
+                if let EndpointType::Framing | EndpointType::Invalid = ep_type {
+                    return Ok(bytes);
+                }
+                let range = self.transfer_range(&entry)?;
+                let ep_traf = self.endpoint_traffic(endpoint_id)?;
+                let data_range = ep_traf.transfer_data_range(&range)?;
+                let length = ep_traf.transfer_data_length(&data_range)?;
+                if let EndpointType::Normal(usb::EndpointType::Control) =
+                    ep_type
+                {
+                    let setup_id = ep_traf.transaction_ids.get(range.start)?;
+                    let setup_transaction = self.transaction(setup_id)?;
+                    if let Ok(setup) =
+                        self.transaction_bytes(&setup_transaction)
+                    {
+                        bytes = setup;
+                    }
+                }
+                let remaining = limit.saturating_sub(bytes.len());
+                let length = min(length, remaining as u64) as usize;
+                bytes.extend(
+                    self.transfer_bytes(endpoint_id, &data_range, length)?);
+            },
+        }
+        bytes.truncate(limit);
+        Ok(bytes)
+    }
+
+    /// The first packet of an item, if it is not a marker.
+    fn item_start_packet(&mut self, item: &TrafficItem)
+        -> Result<Option<PacketId>, Error>
+    {
+        use TrafficItem::*;
+        Ok(Some(match item {
+            Transfer(transfer_id) => {
+                let entry = self.transfer_index.get(*transfer_id)?;
+                let ep_traf = self.endpoint_traffic(entry.endpoint_id())?;
+                let ep_transaction_id =
+                    ep_traf.transfer_index.get(entry.transfer_id())?;
+                let transaction_id =
+                    ep_traf.transaction_ids.get(ep_transaction_id)?;
+                self.transaction_index.get(transaction_id)?
+            },
+            Transaction(.., transaction_id) =>
+                self.transaction_index.get(*transaction_id)?,
+            Packet(.., packet_id) => *packet_id,
+            Marker(_) => return Ok(None),
+        }))
+    }
+
+    /// Number of a packet or transaction, counting from 1.
+    pub fn item_number(&self, item: &TrafficItem) -> Option<u64> {
+        use TrafficItem::*;
+        match item {
+            Packet(.., packet_id) => Some(packet_id.value + 1),
+            Transaction(_, transaction_id) => Some(transaction_id.value + 1),
+            Transfer(_) | Marker(_) => None,
+        }
+    }
+
+    /// Time from the packet before an item to the item's first packet.
+    pub fn item_delta(&mut self, item: &TrafficItem)
+        -> Result<Option<Timestamp>, Error>
+    {
+        let Some(packet_id) = self.item_start_packet(item)? else {
+            return Ok(None);
+        };
+        if packet_id.value == 0 {
+            return Ok(None);
+        }
+        let time = self.packet_time(packet_id)?;
+        let previous = self.packet_time(packet_id - 1)?;
+        Ok(Some(time.saturating_sub(previous)))
+    }
+
+    /// Time at which the capture started, in nanoseconds since the Unix
+    /// epoch, if known.
+    pub fn start_time(&self) -> Option<u64> {
+        self.shared.start_time.get().copied()
+    }
+
+    /// The item shown before an item at the same level of the traffic
+    /// view, or its parent if it is the first child.
+    ///
+    /// Returns None for the first top-level item, or one hidden by the
+    /// filter.
+    pub fn item_previous(&mut self, item: &TrafficItem)
+        -> Result<Option<TrafficItem>, Error>
+    {
+        use TrafficItem::*;
+        let Some(path) = self.item_path(item)? else {
+            return Ok(None);
+        };
+        let parent = match *item {
+            Transfer(_) | Marker(_) => None,
+            Transaction(transfer_id, _) => Some(Transfer(transfer_id)),
+            Packet(transfer_id, transaction_id, _) =>
+                Some(Transaction(transfer_id, transaction_id)),
+        };
+        Ok(match (path.last(), parent) {
+            (Some(0), parent) => parent,
+            (Some(&index), parent) =>
+                Some(self.item(parent.as_ref(), index - 1)?),
+            (None, _) => None,
+        })
+    }
+
+    /// Bus time of an item's first packet, as the frame and microframe
+    /// numbers of the last SOF packet at or before it.
+    ///
+    /// At full and low speed there is one SOF per frame, so the
+    /// microframe number is always zero.
+    pub fn item_bus_time(&mut self, item: &TrafficItem)
+        -> Result<Option<(u16, u8)>, Error>
+    {
+        let Some(packet_id) = self.item_start_packet(item)? else {
+            return Ok(None);
+        };
+        if self.shared.endpoint_readers.load().get(FRAMING_EP_ID).is_none() {
+            return Ok(None);
+        }
+        // Find the transaction that the packet is part of.
+        let mut transaction_id =
+            self.transaction_index.bisect_left(&packet_id)?;
+        if transaction_id.value >= self.transaction_index.len() ||
+            self.transaction_index.get(transaction_id)? != packet_id
+        {
+            transaction_id -= 1;
+        }
+        // Find the last group of SOF packets to start at or before it.
+        let transaction_ids =
+            &mut self.endpoint_traffic(FRAMING_EP_ID)?.transaction_ids;
+        let mut group = transaction_ids.bisect_left(&transaction_id)?;
+        if group.value >= transaction_ids.len() ||
+            transaction_ids.get(group)? != transaction_id
+        {
+            if group.value == 0 {
+                return Ok(None);
+            }
+            group -= 1;
+        }
+        let mut range = self.sof_group_packets(group)?;
+        let mut sof_id = min(packet_id, range.end - 1);
+        let frame = self.sof_frame_number(sof_id)?;
+        // Count the SOFs before it with the same frame number, which may
+        // be in the previous group.
+        let mut microframe = 0;
+        while microframe < 7 {
+            let previous_id = if sof_id > range.start {
+                sof_id - 1
+            } else if group.value > 0 {
+                group -= 1;
+                range = self.sof_group_packets(group)?;
+                range.end - 1
+            } else {
+                break;
+            };
+            if self.sof_frame_number(previous_id)? != frame {
+                break;
+            }
+            microframe += 1;
+            sof_id = previous_id;
+        }
+        Ok(Some((frame, microframe)))
+    }
+
+    /// The packets of a group of SOF packets.
+    fn sof_group_packets(&mut self, group: EndpointTransactionId)
+        -> Result<Range<PacketId>, Error>
+    {
+        let transaction_id = self
+            .endpoint_traffic(FRAMING_EP_ID)?
+            .transaction_ids
+            .get(group)?;
+        self.transaction_index.target_range(
+            transaction_id, self.packet_index.len())
+    }
+
+    /// The frame number of a SOF packet.
+    fn sof_frame_number(&mut self, id: PacketId) -> Result<u16, Error> {
+        match PacketFields::from_packet(&self.packet(id)?) {
+            PacketFields::SOF(sof) => Ok(sof.frame_number()),
+            _ => bail!("Packet {id} in SOF group is not a SOF packet"),
+        }
+    }
+
+    /// The endpoint an item's traffic was on, if it is not a marker.
+    pub fn item_endpoint(&mut self, item: &TrafficItem)
+        -> Result<Option<Endpoint>, Error>
+    {
+        match self.item_endpoint_id(item)? {
+            Some(endpoint_id) => Ok(Some(self.endpoints.get(endpoint_id)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The ID of the endpoint an item's traffic was on, if not a marker.
+    pub fn item_endpoint_id(&mut self, item: &TrafficItem)
+        -> Result<Option<EndpointId>, Error>
+    {
+        use TrafficItem::*;
+        let transfer_id = match item {
+            Transfer(transfer_id) |
+            Transaction(transfer_id, _) |
+            Packet(transfer_id, ..) => transfer_id,
+            Marker(_) => return Ok(None),
+        };
+        Ok(Some(self.transfer_index.get(*transfer_id)?.endpoint_id()))
+    }
+
+    /// The PID of a packet, or the handshake ending a transaction, or the
+    /// last transaction of a transfer.
+    pub fn item_result(&mut self, item: &TrafficItem)
+        -> Result<Option<PID>, Error>
+    {
+        use TrafficItem::*;
+        let transaction_id = match item {
+            Packet(.., packet_id) =>
+                return Ok(Some(self.packet_pid(*packet_id)?)),
+            Transaction(_, transaction_id) => *transaction_id,
+            Transfer(transfer_id) => {
+                let entry = self.transfer_index.get(*transfer_id)?;
+                let range = self.transfer_range(&entry)?;
+                if range.is_empty() {
+                    return Ok(None);
+                }
+                let ep_traf = self.endpoint_traffic(entry.endpoint_id())?;
+                ep_traf.transaction_ids.get(range.end - 1)?
+            },
+            Marker(_) => return Ok(None),
+        };
+        Ok(self.transaction(transaction_id)?.outcome())
+    }
+
+    /// Whether an item is flagged as an error: a transaction of malformed
+    /// packets, one that ended with STALL or ERR, or one to which the
+    /// device did not respond, or a marker of packets lost. Transfers are
+    /// not flagged themselves, so that each error is found once, at the
+    /// transaction where it happened.
+    pub fn item_is_error(&mut self, item: &TrafficItem)
+        -> Result<bool, Error>
+    {
+        use TrafficItem::*;
+        Ok(match item {
+            Transaction(_, transaction_id) => {
+                let malformed = self
+                    .item_endpoint(item)?
+                    .is_some_and(|ep| ep.number() == INVALID_EP_NUM);
+                let transaction = self.transaction(*transaction_id)?;
+                malformed || transaction.timed_out() ||
+                    matches!(transaction.outcome(), Some(PID::STALL | PID::ERR))
+            },
+            Marker(marker_id) =>
+                matches!(self.marker(*marker_id)?.kind, MarkerKind::Loss(_)),
+            Transfer(_) | Packet(..) => false,
+        })
+    }
+
+    /// The length of a packet, or of the data in a transaction or transfer.
+    pub fn item_length(&mut self, item: &TrafficItem)
+        -> Result<Option<u64>, Error>
+    {
+        use TrafficItem::*;
+        Ok(match item {
+            Packet(.., packet_id) => {
+                let range = self.packet_index.target_range(
+                    *packet_id, self.packet_data.len())?;
+                Some(range.len())
+            },
+            Transaction(_, transaction_id) =>
+                self.transaction(*transaction_id)?.payload_size(),
+            Transfer(transfer_id) => {
+                let entry = self.transfer_index.get(*transfer_id)?;
+                let endpoint_id = entry.endpoint_id();
+                let endpoint = self.endpoints.get(endpoint_id)?;
+                let dev_data = self.device_data(&endpoint.device_id())?;
+                let (ep_type, _) =
+                    dev_data.endpoint_details(endpoint.address());
+                if !entry.is_start() ||
+                    !matches!(ep_type, EndpointType::Normal(_))
+                {
+                    return Ok(None);
+                }
+                let range = self.transfer_range(&entry)?;
+                let ep_traf = self.endpoint_traffic(endpoint_id)?;
+                let data_range = ep_traf.transfer_data_range(&range)?;
+                Some(ep_traf.transfer_data_length(&data_range)?)
+            },
+            Marker(_) => None,
+        })
+    }
+
+    /// Time from the first packet of a transaction or transfer to its
+    /// last, or None for other items.
+    pub fn item_duration(&mut self, item: &TrafficItem)
+        -> Result<Option<Timestamp>, Error>
+    {
+        use TrafficItem::*;
+        Ok(match item {
+            Transaction(_, transaction_id) => {
+                let packets = self.transaction_packets(*transaction_id)?;
+                let start = self.packet_time(packets.start)?;
+                Some(self.packet_time(packets.end - 1)? - start)
+            },
+            Transfer(transfer_id) => {
+                let entry = self.transfer_index.get(*transfer_id)?;
+                if !entry.is_start() {
+                    return Ok(None);
+                }
+                let range = self.transfer_range(&entry)?;
+                Some(self.transfer_duration(entry.endpoint_id(), &range)?)
+            },
+            Packet(..) | Marker(_) => None,
         })
     }
 
+    /// The first bytes of the data carried by an item, for a preview.
+    pub fn item_preview(&mut self, item: &TrafficItem, limit: usize)
+        -> Result<Vec<u8>, Error>
+    {
+        use TrafficItem::*;
+        let mut bytes = match item {
+            Packet(.., packet_id) => {
+                let packet = self.packet(*packet_id)?;
+                match packet.first().map(|&pid| PID::from(pid)) {
+                    Some(PID::DATA0 | PID::DATA1 | PID::DATA2 | PID::MDATA)
+                        if packet.len() >= 3 =>
+                            packet[1..packet.len() - 2].to_vec(),
+                    _ => Vec::new(),
+                }
+            },
+            Transaction(_, transaction_id) => {
+                // Only a transaction which carried data has a payload.
+                let transaction = self.transaction(*transaction_id)?;
+                match &transaction.payload_byte_range {
+                    Some(range) => self.packet_data.get_range(range)?,
+                    None => Vec::new(),
+                }
+            },
+            Transfer(_) => self.item_bytes(item, limit)?,
+            Marker(_) => Vec::new(),
+        };
+        bytes.truncate(limit);
+        Ok(bytes)
+    }
+
     pub fn device_data(&self, id: &DeviceId)
         -> Result<Arc<DeviceData>, Error>
     {
@@ -1116,8 +3285,18 @@ impl ItemSource<TrafficItem> for CaptureReader {
         -> Result<TrafficItem, Error>
     {
         match parent {
+            None if !self.filter.is_empty() => self.filtered_item(index),
             None => {
-                let item_id = TrafficItemId::from(index);
+                let markers = self.shared.markers.load();
+                let before = markers
+                    .partition_point(|marker| marker.position < index);
+                if markers.get(before)
+                    .is_some_and(|marker| marker.position == index)
+                {
+                    let marker_id = MarkerId::from(before as u64);
+                    return Ok(TrafficItem::Marker(marker_id));
+                }
+                let item_id = TrafficItemId::from(index - before as u64);
                 let transfer_id = self.item_index.get(item_id)?;
                 Ok(TrafficItem::Transfer(transfer_id))
             },
@@ -1148,7 +3327,8 @@ impl ItemSource<TrafficItem> for CaptureReader {
             Transaction(transfer_id, transaction_id) =>
                 Packet(*transfer_id, *transaction_id, {
                     self.transaction_index.get(*transaction_id)? + index}),
-            Packet(..) => bail!("Packets have no child items")
+            Packet(..) => bail!("Packets have no child items"),
+            Marker(..) => bail!("Markers have no child items"),
         })
     }
 
@@ -1159,7 +3339,14 @@ impl ItemSource<TrafficItem> for CaptureReader {
         use CompletionStatus::*;
         Ok(match parent {
             None => {
-                (self.completion(), self.item_index.len())
+                let marker_count = self.shared.markers.load().len() as u64;
+                let item_count = if self.filter.is_empty() {
+                    self.item_index.len()
+                } else {
+                    self.update_filter()?;
+                    self.filtered_item_count()?
+                };
+                (self.completion(), item_count + marker_count)
             },
             Some(Transfer(transfer_id)) => {
                 let entry = self.transfer_index.get(*transfer_id)?;
@@ -1183,7 +3370,7 @@ impl ItemSource<TrafficItem> for CaptureReader {
                     (Ongoing, packet_count)
                 }
             },
-            Some(Packet(..)) => (Complete, 0),
+            Some(Packet(..) | Marker(..)) => (Complete, 0),
         })
     }
 
@@ -1195,123 +3382,158 @@ impl ItemSource<TrafficItem> for CaptureReader {
         use usb::StartComplete::*;
         let mut s = String::new();
         Ok(match item {
+            Marker(marker_id) => {
+                let marker = self.marker(*marker_id)?;
+                if detail {
+                    writeln!(s, "{}",
+                        tr!("Marker #{}", marker_id.value + 1))?;
+                    writeln!(s, "{}",
+                        tr!("Timestamp: {} ns from capture start",
+                            fmt_count(marker.timestamp)))?;
+                }
+                write!(s, "{}", marker.kind)?;
+                if detail && matches!(marker.kind, MarkerKind::Loss(_)) {
+                    s += tr!("\nThe analyzer could not keep up with the \
+                              bus, so traffic around this point is \
+                              incomplete.");
+                }
+                s
+            },
             Packet(.., packet_id) => {
                 let packet = self.packet(*packet_id)?;
                 let len = packet.len();
                 let too_long = len > 1027;
                 if detail {
-                    writeln!(s, "Packet #{} with {len} bytes",
-                        packet_id.value + 1)?;
-                    writeln!(s, "Timestamp: {} ns from capture start",
-                        fmt_count(self.packet_time(*packet_id)?))?;
+                    writeln!(s, "{}", tr!("Packet #{} with {} bytes",
+                        packet_id.value + 1, len))?;
+                    writeln!(s, "{}",
+                        tr!("Timestamp: {} ns from capture start",
+                            fmt_count(self.packet_time(*packet_id)?)))?;
+                    if let Some(source) = self.packet_source(*packet_id)? {
+                        writeln!(s, "{}", tr!("Captured in: {}", source))?;
+                    }
                 }
                 match validate_packet(&packet) {
                     Err(None) => {
-                        write!(s, "Malformed 0-byte packet")?;
+                        s += tr!("Malformed 0-byte packet");
                     },
                     Err(Some(pid)) => {
-                        write!(s, "Malformed packet")?;
-                        match pid {
-                            RSVD if too_long => write!(s,
-                                " (reserved PID, and too long)"),
-                            Malformed if too_long => write!(s,
-                                " (invalid PID, and too long)"),
-                            RSVD => write!(s,
-                                " (reserved PID)"),
-                            Malformed => write!(s,
-                                " (invalid PID)"),
-                            pid if too_long => write!(s,
-                                " (possibly {pid}, but too long)"),
-                            pid => write!(s,
-                                " (possibly {pid}, but {})",
+                        s += tr!("Malformed packet");
+                        s += &match pid {
+                            RSVD if too_long =>
+                                tr!(" (reserved PID, and too long)").into(),
+                            Malformed if too_long =>
+                                tr!(" (invalid PID, and too long)").into(),
+                            RSVD =>
+                                tr!(" (reserved PID)").into(),
+                            Malformed =>
+                                tr!(" (invalid PID)").into(),
+                            pid if too_long =>
+                                tr!(" (possibly {}, but too long)", pid),
+                            pid => tr!(" (possibly {}, but {})", pid,
                                 match pid {
                                     SOF|SETUP|IN|OUT|PING => {
                                         if len != 3 {
-                                            "wrong length"
+                                            tr!("wrong length")
                                         } else {
-                                            "bad CRC"
+                                            tr!("bad CRC")
                                         }
                                     },
                                     SPLIT => {
                                         if len != 4 {
-                                            "wrong length"
+                                            tr!("wrong length")
                                         } else {
-                                            "bad CRC"
+                                            tr!("bad CRC")
                                         }
                                     },
                                     DATA0|DATA1|DATA2|MDATA => {
                                         if len < 3 {
-                                            "too short"
+                                            tr!("too short")
                                         } else {
-                                            "bad CRC"
+                                            tr!("bad CRC")
                                         }
                                     },
-                                    ACK|NAK|NYET|STALL|ERR => "too long",
+                                    ACK|NAK|NYET|STALL|ERR => tr!("too long"),
                                     RSVD|Malformed => unreachable!(),
                                 }
                             ),
-                        }?;
-                        if len == 1 {
-                            write!(s, " of 1 byte")
+                        };
+                        s += &if len == 1 {
+                            tr!(" of 1 byte").into()
                         } else {
-                            write!(s, " of {len} bytes")
-                        }?;
+                            tr!(" of {} bytes", len)
+                        };
                         if detail {
-                            write!(s, "\nHex bytes: {}", Bytes::first(1024, &packet))
+                            s += &tr!("\nHex bytes: {}",
+                                Bytes::first(1024, &packet));
                         } else {
-                            write!(s, ": {}", Bytes::first(100, &packet))
-                        }?;
+                            write!(s, ": {}", Bytes::first(100, &packet))?;
+                        }
                     },
                     Ok(pid) => {
-                        write!(s, "{pid} packet")?;
+                        s += &tr!("{} packet", pid);
                         let fields = PacketFields::from_packet(&packet);
-                        match &fields {
-                            PacketFields::SOF(sof) => write!(s,
-                                " with frame number {}, CRC {:02X}",
+                        s += &match &fields {
+                            PacketFields::SOF(sof) => tr!(
+                                " with frame number {}, CRC {}",
                                 sof.frame_number(),
-                                sof.crc()),
-                            PacketFields::Token(token) => write!(s,
-                                " on {}.{}, CRC {:02X}",
+                                format!("{:02X}", sof.crc())),
+                            PacketFields::Token(token) => tr!(
+                                " on {}.{}, CRC {}",
                                 token.device_address(),
                                 token.endpoint_number(),
-                                token.crc()),
-                            PacketFields::Data(data) if len <= 3 => write!(s,
-                                " with CRC {:04X} and no data",
-                                data.crc),
-                            PacketFields::Data(data) => write!(s,
-                                " with CRC {:04X} and {} data bytes",
-                                data.crc,
+                                format!("{:02X}", token.crc())),
+                            PacketFields::Data(data) if len <= 3 => tr!(
+                                " with CRC {} and no data",
+                                format!("{:04X}", data.crc)),
+                            PacketFields::Data(data) => tr!(
+                                " with CRC {} and {} data bytes",
+                                format!("{:04X}", data.crc),
                                 len - 3),
-                            PacketFields::Split(split) => write!(s,
-                                " {} {} speed {} transaction on hub {} port {}",
+                            PacketFields::Split(split) => {
+                                let speed = format!("{:?}", split.speed())
+                                    .to_lowercase();
+                                let ep_type =
+                                    format!("{:?}", split.endpoint_type())
+                                        .to_lowercase();
+                                let (hub, port) =
+                                    (split.hub_address(), split.port());
                                 match split.sc() {
-                                    Start => "starting",
-                                    Complete => "completing",
-                                },
-                                format!("{:?}", split.speed())
-                                    .to_lowercase(),
-                                format!("{:?}", split.endpoint_type())
-                                    .to_lowercase(),
-                                split.hub_address(),
-                                split.port()),
-                            PacketFields::None => Ok(()),
-                        }?;
+                                    Start => tr!(
+                                        " starting {} speed {} transaction \
+                                         on hub {} port {}",
+                                        speed, ep_type, hub, port),
+                                    Complete => tr!(
+                                        " completing {} speed {} transaction \
+                                         on hub {} port {}",
+                                        speed, ep_type, hub, port),
+                                }
+                            },
+                            PacketFields::None => String::new(),
+                        };
                         if matches!(fields, PacketFields::Data(_)) && len > 3 {
                             let data = &packet[1 .. len - 2];
                             if detail {
-                                write!(s, concat!(
-                                    "\nHex bytes: [{:02X}, <payload>, {:02X}, {:02X}]",
-                                    "\nPayload: {}"),
-                                    packet[0], packet[len - 2], packet[len - 1],
-                                    Bytes::first(1024, data))
+                                write!(s,
+                                    "\nHex bytes: [{:02X}, {}, {:02X}, {:02X}]",
+                                    packet[0], tr!("<payload>"),
+                                    packet[len - 2], packet[len - 1])?;
+                                s += &tr!("\nPayload: {}",
+                                    Bytes::first(1024, data));
                             } else {
-                                write!(s, ": {}", Bytes::first(100, data))
-                            }?;
+                                write!(s, ": {}", Bytes::first(100, data))?;
+                            }
                         } else if detail {
-                            write!(s, "\nHex bytes: {packet:02X?}")?;
+                            s += &tr!("\nHex bytes: {}",
+                                format!("{:02X?}", &packet[..]));
                         }
                     }
                 }
+                if !detail {
+                    if let Some(source) = self.packet_source(*packet_id)? {
+                        s.insert_str(0, &format!("{source}: "));
+                    }
+                }
                 s
             },
             Transaction(transfer_id, transaction_id) => {
@@ -1324,27 +3546,31 @@ impl ItemSource<TrafficItem> for CaptureReader {
                 let start_packet = self.packet(start_packet_id)?;
                 let packet_count = packet_id_range.len();
                 if detail {
-                    writeln!(s, "Transaction #{} with {} {}",
-                        transaction_id.value + 1,
-                        packet_count,
-                        if packet_count == 1 {"packet"} else {"packets"})?;
-                    writeln!(s, "Timestamp: {} ns from capture start",
-                        fmt_count(self.packet_time(start_packet_id)?))?;
-                    write!(s, "Packets: #{}", packet_id_range.start + 1)?;
-                    if packet_count > 1 {
-                        write!(s, " to #{}", packet_id_range.end)?;
-                    }
-                    writeln!(s)?;
+                    let number = transaction_id.value + 1;
+                    writeln!(s, "{}", if packet_count == 1 {
+                        tr!("Transaction #{} with 1 packet", number)
+                    } else {
+                        tr!("Transaction #{} with {} packets",
+                            number, packet_count)
+                    })?;
+                    writeln!(s, "{}",
+                        tr!("Timestamp: {} ns from capture start",
+                            fmt_count(self.packet_time(start_packet_id)?)))?;
+                    let first = packet_id_range.start + 1;
+                    writeln!(s, "{}", if packet_count > 1 {
+                        tr!("Packets: #{} to #{}",
+                            first, packet_id_range.end)
+                    } else {
+                        tr!("Packets: #{}", first)
+                    })?;
                 }
                 if validate_packet(&start_packet).is_ok() {
                     let transaction = self.transaction(*transaction_id)?;
                     s += &transaction.description(self, &endpoint, detail)?
+                } else if packet_count == 1 {
+                    s += tr!("1 malformed packet");
                 } else {
-                    let packet_count = packet_id_range.len();
-                    write!(s,
-                        "{} malformed {}",
-                        packet_count,
-                        if packet_count == 1 {"packet"} else {"packets"})?;
+                    s += &tr!("{} malformed packets", packet_count);
                 }
                 s
             },
@@ -1368,42 +3594,51 @@ impl ItemSource<TrafficItem> for CaptureReader {
                         ep_traf.transaction_ids.get(start_ep_transaction_id)?;
                     let start_packet_id =
                         self.transaction_index.get(start_transaction_id)?;
-                    if count == 1 {
-                        writeln!(s, "Transaction group with 1 transaction")?;
+                    writeln!(s, "{}", if count == 1 {
+                        tr!("Transaction group with 1 transaction").into()
                     } else {
-                        writeln!(s, "Transaction group with {} transactions",
-                            count)?;
-                    }
-                    writeln!(s, "Timestamp: {} ns from start of capture",
-                        fmt_count(self.packet_time(start_packet_id)?))?;
-                    writeln!(s, "First transaction #{}, first packet #{}",
-                        start_transaction_id.value + 1,
-                        start_packet_id.value + 1)?;
+                        tr!("Transaction group with {} transactions", count)
+                    })?;
+                    writeln!(s, "{}",
+                        tr!("Timestamp: {} ns from start of capture",
+                            fmt_count(self.packet_time(start_packet_id)?)))?;
+                    writeln!(s, "{}",
+                        tr!("First transaction #{}, first packet #{}",
+                            start_transaction_id.value + 1,
+                            start_packet_id.value + 1))?;
                 }
                 match (ep_type, entry.is_start()) {
-                    (Invalid, true) => write!(s,
-                        "{count} invalid groups"),
-                    (Invalid, false) => write!(s,
-                        "End of invalid groups"),
-                    (Framing, true) => write!(s,
-                        "{count} SOF groups"),
-                    (Framing, false) => write!(s,
-                        "End of SOF groups"),
+                    (Invalid, true) => write!(s, "{}",
+                        tr!("{} invalid groups", count)),
+                    (Invalid, false) => write!(s, "{}",
+                        tr!("End of invalid groups")),
+                    (Framing, true) if self.filter.collapse_idle =>
+                        write!(s, "{}", tr!("{} idle periods", count)),
+                    (Framing, false) if self.filter.collapse_idle =>
+                        write!(s, "{}", tr!("End of idle periods")),
+                    (Framing, true) => write!(s, "{}",
+                        tr!("{} SOF groups", count)),
+                    (Framing, false) => write!(s, "{}",
+                        tr!("End of SOF groups")),
                     (Normal(Control), true) => {
                         let addr = endpoint.device_address();
-                        match self.control_transfer(addr, endpoint_id, range) {
-                            Ok(transfer) if detail => write!(s,
-                                "Control transfer on device {addr}\n{}",
+                        match self.control_transfer(
+                            addr, &dev_data, endpoint_id, range)
+                        {
+                            Ok(transfer) if detail => write!(s, "{}\n{}",
+                                tr!("Control transfer on device {}", addr),
                                 transfer.summary()),
                             Ok(transfer) => write!(s,
                                 "{}", transfer.summary()),
-                            Err(_) => write!(s,
-                                "Incomplete control transfer on device {addr}")
+                            Err(_) => write!(s, "{}",
+                                tr!("Incomplete control transfer on device {}",
+                                    addr))
                         }
                     },
                     (Normal(Control), false) => {
                         let addr = endpoint.device_address();
-                        write!(s, "End of control transfer on device {addr}")
+                        write!(s, "{}",
+                            tr!("End of control transfer on device {}", addr))
                     },
                     (endpoint_type, starting) => {
                         let ep_transfer_id = entry.transfer_id();
@@ -1416,6 +3651,12 @@ impl ItemSource<TrafficItem> for CaptureReader {
                             self.transaction(first_transaction_id)?;
                         let ep_type_string = format!("{endpoint_type}");
                         let ep_type_lower = ep_type_string.to_lowercase();
+                        // Label the endpoint with the function of its
+                        // interface, where known.
+                        let endpoint = match dev_data.endpoint_label(ep_addr) {
+                            Some(label) => format!("{endpoint} ({label})"),
+                            None => endpoint.to_string(),
+                        };
                         let count = if first_transaction.split.is_some() {
                             (count + 1) / 2
                         } else {
@@ -1438,21 +3679,72 @@ impl ItemSource<TrafficItem> for CaptureReader {
                                     partial: length > display_length as u64,
                                     bytes: &transfer_bytes,
                                 };
-                                write!(s, "{ep_type_string} transfer ")?;
-                                write!(s, "of {length_string} ")?;
-                                write!(s, "on endpoint {endpoint}")?;
-                                if detail {
-                                    write!(s, "\nPayload: {display_bytes}")
-                                } else {
-                                    write!(s, ": {display_bytes}")
+                                let decoder = dev_data
+                                    .endpoint_interface(ep_addr)
+                                    .and_then(|iface| dev_data.class_decoder(
+                                        iface.interface_number));
+                                let class_description = match (
+                                    endpoint_type, decoder)
+                                {
+                                    (Normal(usb_ep_type), Some(decoder)) => {
+                                        let transfer = class::Transfer {
+                                            ep_type: usb_ep_type,
+                                            endpoint: ep_addr,
+                                            payload: &transfer_bytes,
+                                            partial: display_bytes.partial,
+                                        };
+                                        if detail {
+                                            decoder.transfer_detail(&transfer)
+                                        } else {
+                                            decoder.transfer_summary(&transfer)
+                                        }
+                                    },
+                                    _ => None
+                                };
+                                match (class_description, detail) {
+                                    (Some(description), false) => write!(s,
+                                        "{}", tr!("{} on endpoint {}",
+                                            description, endpoint)),
+                                    (class_description, _) => {
+                                        s += &tr!(
+                                            "{} transfer of {} on endpoint {}",
+                                            ep_type_string, length_string,
+                                            endpoint);
+                                        if let Some(description) =
+                                            class_description
+                                        {
+                                            write!(s, "\n{description}")?;
+                                        }
+                                        if detail {
+                                            write!(s, "{}", tr!(
+                                                "\nPayload: {}",
+                                                display_bytes))
+                                        } else {
+                                            write!(s, ": {display_bytes}")
+                                        }
+                                    }
                                 }
                             },
-                            (true, false) => write!(s,
-                                "End of {ep_type_lower} transfer on endpoint {endpoint}"),
-                            (false, true) => write!(s,
-                                "Polling {count} times for {ep_type_lower} transfer on endpoint {endpoint}"),
-                            (false, false) => write!(s,
-                                "End polling for {ep_type_lower} transfer on endpoint {endpoint}"),
+                            (true, false) => write!(s, "{}", tr!(
+                                "End of {} transfer on endpoint {}",
+                                ep_type_lower, endpoint)),
+                            (false, true) if self.filter.collapse_polling &&
+                                !detail =>
+                            {
+                                let duration =
+                                    self.transfer_duration(endpoint_id, &range)?;
+                                write!(s, "{}", tr!(
+                                    "Endpoint {} polled {} times over {}",
+                                    endpoint, fmt_count(count),
+                                    fmt_duration(duration)))
+                            },
+                            (false, true) => write!(s, "{}", tr!(
+                                "Polling {} times for {} transfer on \
+                                 endpoint {}",
+                                count, ep_type_lower, endpoint)),
+                            (false, false) => write!(s, "{}", tr!(
+                                "End polling for {} transfer on endpoint {}",
+                                ep_type_lower, endpoint)),
                         }
                     }
                 }?;
@@ -1470,7 +3762,8 @@ impl ItemSource<TrafficItem> for CaptureReader {
         let max_string_length = endpoint_count + "    └──".len();
         let mut connectors = String::with_capacity(max_string_length);
         let transfer_id = match item {
-            Transfer(i) | Transaction(i, _) | Packet(i, ..) => *i
+            Transfer(i) | Transaction(i, _) | Packet(i, ..) => *i,
+            Marker(marker_id) => return self.marker_connectors(*marker_id),
         };
         let entry = self.transfer_index.get(transfer_id)?;
         let endpoint_id = entry.endpoint_id();
@@ -1532,7 +3825,8 @@ impl ItemSource<TrafficItem> for CaptureReader {
                         (true,  _,     false) => '│',
                         (true,  _,     true ) => ' ',
                     }
-                }
+                },
+                Marker(..) => unreachable!(),
             });
         };
         let state_length = endpoint_state.len();
@@ -1541,6 +3835,7 @@ impl ItemSource<TrafficItem> for CaptureReader {
                 Transfer(..)    => '─',
                 Transaction(..) => '─',
                 Packet(..)      => ' ',
+                Marker(..)      => unreachable!(),
             });
         }
         connectors.push_str(
@@ -1550,6 +3845,7 @@ impl ItemSource<TrafficItem> for CaptureReader {
                 (Transaction(..), _)                 => "───",
                 (Packet(..), false)                  => "    ├──",
                 (Packet(..), true)                   => "    └──",
+                (Marker(..), _)                      => unreachable!(),
             }
         );
         Ok(connectors)
@@ -1558,22 +3854,12 @@ impl ItemSource<TrafficItem> for CaptureReader {
     fn timestamp(&mut self, item: &TrafficItem)
         -> Result<Timestamp, Error>
     {
-        use TrafficItem::*;
-        let packet_id = match item {
-            Transfer(transfer_id) => {
-                let entry = self.transfer_index.get(*transfer_id)?;
-                let ep_traf = self.endpoint_traffic(entry.endpoint_id())?;
-                let ep_transaction_id =
-                    ep_traf.transfer_index.get(entry.transfer_id())?;
-                let transaction_id =
-                    ep_traf.transaction_ids.get(ep_transaction_id)?;
-                self.transaction_index.get(transaction_id)?
-            },
-            Transaction(.., transaction_id) =>
-                self.transaction_index.get(*transaction_id)?,
-            Packet(.., packet_id) => *packet_id,
-        };
-        self.packet_time(packet_id)
+        match (item, self.item_start_packet(item)?) {
+            (TrafficItem::Marker(marker_id), _) =>
+                Ok(self.marker(*marker_id)?.timestamp),
+            (_, Some(packet_id)) => self.packet_time(packet_id),
+            (_, None) => bail!("Item has no packets"),
+        }
     }
 }
 
@@ -1718,7 +4004,12 @@ impl ItemSource<DeviceItem> for CaptureReader {
             Device(dev, _version) => {
                 let device = self.devices.get(*dev)?;
                 let data = self.device_data(dev)?;
-                format!("Device {}: {}", device.address, data.description())
+                let mut s = format!(
+                    "Device {}: {}", device.address, data.description());
+                if let Some(progress) = data.dfu_progress.load().as_ref() {
+                    write!(s, " ({})", progress.summary())?;
+                }
+                s
             },
             DeviceDescriptor(dev) => {
                 match self.device_data(dev)?.device_descriptor.load().as_ref() {
@@ -1747,8 +4038,15 @@ impl ItemSource<DeviceItem> for CaptureReader {
                 let strings = data.strings.load();
                 config_descriptor.field_text(*field, strings.as_ref())
             },
-            Interface(_, _, iface) => format!(
-                "Interface {iface}"),
+            Interface(dev, conf, iface) => {
+                let data = self.device_data(dev)?;
+                let config = data.configuration(conf)?;
+                let interface = config.interface(iface)?;
+                match data.interface_label(&interface.descriptor) {
+                    Some(label) => format!("Interface {iface}: {label}"),
+                    None => format!("Interface {iface}"),
+                }
+            },
             InterfaceDescriptor(..) =>
                 "Interface descriptor".to_string(),
             InterfaceDescriptorField(dev, conf, iface, field, _ver) => {
@@ -1807,8 +4105,11 @@ mod tests {
     use std::fs::File;
     use std::io::{BufReader, BufWriter, BufRead, Write};
     use std::path::PathBuf;
-    use crate::decoder::Decoder;
-    use crate::pcap::Loader;
+    use crate::decoder::{
+        Decoder,
+        decode_test_capture,
+        load_test_capture,
+    };
     use itertools::Itertools;
 
     fn summarize_item(cap: &mut CaptureReader, item: &TrafficItem, depth: usize)
@@ -1854,26 +4155,15 @@ mod tests {
         list_path.push("tests.txt");
         let list_file = File::open(list_path).unwrap();
         for test_name in BufReader::new(list_file).lines() {
+            let test_name = test_name.unwrap();
             let mut test_path = test_dir.clone();
-            test_path.push(test_name.unwrap());
-            let mut cap_path = test_path.clone();
+            test_path.push(&test_name);
             let mut ref_path = test_path.clone();
             let mut out_path = test_path.clone();
-            cap_path.push("capture.pcap");
             ref_path.push("reference.txt");
             out_path.push("output.txt");
             {
-                let file = File::open(cap_path).unwrap();
-                let mut loader = Loader::open(file).unwrap();
-                let (writer, mut reader) = create_capture().unwrap();
-                let mut decoder = Decoder::new(writer).unwrap();
-                while let Some(result) = loader.next() {
-                    let (packet, timestamp_ns) = result.unwrap();
-                    decoder
-                        .handle_raw_packet(&packet.data, timestamp_ns)
-                        .unwrap();
-                }
-                decoder.finish().unwrap();
+                let mut reader = load_test_capture(&test_name);
                 let out_file = File::create(out_path.clone()).unwrap();
                 let mut out_writer = BufWriter::new(out_file);
                 let num_items = reader.item_index.len();
@@ -1894,6 +4184,743 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_timeline() {
+        let mut reader = load_test_capture("bad-crcs");
+        let packet_count = reader.packet_index.len();
+        let timeline = reader.timeline(100).unwrap();
+        assert_eq!(timeline.packets.len(), 100);
+        assert_eq!(timeline.packets.iter().sum::<u64>(), packet_count);
+        let errors: u64 = timeline.errors.iter().sum();
+        assert!(errors > 0 && errors < packet_count);
+        assert!(timeline.events.is_empty());
+        let start = timeline.time_range.start;
+        let end = timeline.time_range.end;
+        assert_eq!(start, reader.packet_time(PacketId::from(0)).unwrap());
+        assert_eq!(timeline.interval_start(0), start);
+        assert_eq!(timeline.interval(start), Some(0));
+        assert_eq!(timeline.interval(end - 1), Some(99));
+        assert_eq!(timeline.interval(end), None);
+        assert_eq!(create_capture().unwrap().1.timeline(100).unwrap(),
+                   Timeline::default());
+    }
+
+    #[test]
+    fn test_time_search() {
+        let mut reader = load_test_capture("mouse");
+        let packet_count = reader.packet_index.len();
+        let times: Vec<Timestamp> = (0..packet_count)
+            .map(|i| reader.packet_time(PacketId::from(i)).unwrap())
+            .collect();
+        let scan = |time: Timestamp| PacketId::from(
+            times.iter().take_while(|&&t| t < time).count() as u64);
+        for i in (0..times.len()).step_by(37) {
+            for time in [times[i].saturating_sub(1), times[i], times[i] + 1] {
+                assert_eq!(reader.packet_at_time(time).unwrap(), scan(time));
+            }
+        }
+        let last = times[times.len() - 1];
+        assert_eq!(reader.packet_at_time(0).unwrap(), PacketId::from(0));
+        assert_eq!(reader.packet_at_time(last + 1).unwrap(),
+                   PacketId::from(packet_count));
+        let (start, end) = (times[10], times[20]);
+        assert_eq!(reader.time_range_packets(&(start..end)).unwrap(),
+                   scan(start)..scan(end));
+        let packets = reader.time_range_packets(&(end..start)).unwrap();
+        assert!(packets.is_empty());
+    }
+
+    #[test]
+    fn test_endpoint_throughput() {
+        let mut reader = load_test_capture("mouse");
+        let time_range = reader.timeline(1).unwrap().time_range;
+        for i in 0..reader.endpoints.len() {
+            let endpoint_id = EndpointId::from(i);
+            let ep_traf = reader.endpoint_traffic(endpoint_id).unwrap();
+            let data_range = 0..ep_traf.data_transactions.len();
+            let total = ep_traf.transfer_data_length(&data_range).unwrap();
+            let throughput = reader
+                .endpoint_throughput(endpoint_id, &time_range, 50)
+                .unwrap();
+            assert_eq!(throughput.len(), 50);
+            assert_eq!(throughput.iter().sum::<u64>(), total);
+        }
+        // The interrupt endpoint of the mouse sends data throughout.
+        let endpoint_id = (0..reader.endpoints.len())
+            .map(EndpointId::from)
+            .find(|&id| {
+                let endpoint = reader.endpoints.get(id).unwrap();
+                endpoint.number() == EndpointNum(1) &&
+                    matches!(endpoint.direction(), Direction::In)
+            })
+            .unwrap();
+        let throughput = reader
+            .endpoint_throughput(endpoint_id, &time_range, 4)
+            .unwrap();
+        assert!(throughput.iter().filter(|&&bytes| bytes > 0).count() > 1);
+    }
+
+    fn summarize_devices(cap: &mut CaptureReader,
+                         parent: Option<&DeviceItem>,
+                         summary: &mut String)
+    {
+        let (_completion, num_children) =
+            cap.item_children(parent).unwrap();
+        for index in 0..num_children {
+            let item = cap.item(parent, index).unwrap();
+            *summary += &cap.description(&item, false).unwrap();
+            *summary += "\n";
+            summarize_devices(cap, Some(&item), summary);
+        }
+    }
+
+    fn summarize_capture(cap: &mut CaptureReader) -> String {
+        let mut summary = Vec::new();
+        let (_, item_count) = cap.item_children(None::<&TrafficItem>).unwrap();
+        for item_id in 0..item_count {
+            let item = cap.item(None, item_id).unwrap();
+            write_item(cap, &item, 0, &mut summary);
+        }
+        let mut summary = String::from_utf8(summary).unwrap();
+        summarize_devices(cap, None, &mut summary);
+        summary
+    }
+
+    #[test]
+    fn test_native_round_trip() {
+        for name in ["hackrf-dfu-enum", "dfu", "mouse", "uac"] {
+            let mut original = load_test_capture(name);
+            let mut saved = Vec::new();
+            crate::native::save(&mut original, &mut saved).unwrap();
+            let (writer, mut restored) = create_capture().unwrap();
+            crate::native::load(saved.as_slice(), writer).unwrap();
+            assert!(restored.shared.complete.load(Acquire));
+            assert_eq!(summarize_capture(&mut restored),
+                       summarize_capture(&mut original));
+            let original_data = original.shared.device_data.load();
+            let restored_data = restored.shared.device_data.load();
+            for (original, restored) in
+                original_data.into_iter().zip(restored_data.as_ref())
+            {
+                assert_eq!(restored.version(), original.version());
+                let progress = |data: &DeviceData| data.dfu_progress
+                    .load_full()
+                    .map(|progress| progress.download_bytes);
+                assert_eq!(progress(restored), progress(original));
+            }
+        }
+        let (writer, _reader) = create_capture().unwrap();
+        let error = crate::native::load(&b"PACKETRY\x07\0\0\0"[..], writer)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Native capture file has version 7, \
+                                       but only version 6 can be loaded");
+    }
+
+    #[test]
+    fn test_detail_fields() {
+        let mut cap = load_test_capture("hackrf-dfu-enum");
+        let (_, item_count) = cap.item_children(None::<&TrafficItem>).unwrap();
+        let transfer = (0..item_count)
+            .map(|index| {
+                let item = cap.item(None, index).unwrap();
+                (item, cap.description(&item, false).unwrap())
+            })
+            .find(|(_, summary)|
+                summary.starts_with("Getting device descriptor #0"))
+            .unwrap()
+            .0;
+        let fields = cap.detail_fields(&transfer).unwrap();
+        let field = |name| fields
+            .iter()
+            .find(|field| field.name == name)
+            .unwrap()
+            .clone();
+        assert_eq!(field("Endpoint type").value, "Control");
+        assert_eq!(field("Data").value, "18 bytes");
+        assert_eq!(field("Result").value, "Completed");
+        let setup = field("Setup data");
+        assert_eq!(setup.children[1].to_string(), "Request: 6 (GetDescriptor)");
+        let descriptor = field("Device descriptor");
+        assert_eq!(descriptor.value, "18 bytes");
+        assert_eq!(descriptor.children[7].to_string(),
+                   "Vendor ID: 0x1FC9: NXP Semiconductors");
+        let bytes = cap.item_bytes(&transfer, usize::MAX).unwrap();
+        assert_eq!(bytes.len(), 26);
+        assert_eq!(&bytes[..2], [0x80, 0x06]);
+        assert_eq!(descriptor.bytes, Some(8..26));
+        assert_eq!(bytes[descriptor.children[7].bytes.clone().unwrap()],
+                   [0xC9, 0x1F]);
+        assert_eq!(cap.item_bytes(&transfer, 10).unwrap(), bytes[..10]);
+        let setup_transaction = cap.child_item(&transfer, 0).unwrap();
+        let fields = cap.detail_fields(&setup_transaction).unwrap();
+        let packets = &fields[2];
+        assert_eq!(packets.to_string(), "Packets: 3");
+        assert_eq!(packets.children[0].value, "SETUP");
+        assert_eq!(packets.children[0].children[2].to_string(),
+                   "Device address: 11");
+        assert_eq!(fields[3].to_string(), "Payload: 8 bytes");
+        assert_eq!(fields[4].to_string(), "Outcome: ACK");
+        assert_eq!(fields[5].children[4].to_string(), "Length: 18");
+        let bytes = cap.item_bytes(&setup_transaction, usize::MAX).unwrap();
+        assert_eq!(bytes.len(), 15);
+        assert_eq!(fields[5].bytes, Some(4..12));
+        assert_eq!(bytes[fields[5].children[1].bytes.clone().unwrap()], [6]);
+        let packet = cap.child_item(&setup_transaction, 2).unwrap();
+        let fields = cap.detail_fields(&packet).unwrap();
+        assert_eq!(fields[2].to_string(), "Length: 1 byte");
+        assert_eq!(fields[3].to_string(), "PID: ACK (0xD2)");
+    }
+
+    #[test]
+    fn test_markers() {
+        let (writer, mut reader) = create_capture().unwrap();
+        let mut decoder = Decoder::new(writer).unwrap();
+        decode_test_capture("mouse", &mut decoder, |decoder, index, time| {
+            if index == 100 {
+                decoder.handle_marker("Button pressed".to_string(), time);
+            }
+        });
+        decoder.handle_loss(Some(3), u64::MAX);
+        decoder.finish().unwrap();
+
+        let mut plain = load_test_capture("mouse");
+        let transfer_count = plain.item_index.len();
+        let (_, item_count) =
+            reader.item_children(None::<&TrafficItem>).unwrap();
+        assert_eq!(item_count, transfer_count + 2);
+        let markers = reader.shared.markers.load_full();
+        let position = markers[0].position;
+        assert_eq!(markers[1].position, item_count - 1);
+        // Transfers are unchanged, with the markers placed among them.
+        for index in 0..item_count {
+            let item: TrafficItem = reader.item(None, index).unwrap();
+            let description = reader.description(&item, false).unwrap();
+            let transfer_index = match index {
+                i if i == position => {
+                    assert_eq!(description, "Marker: Button pressed");
+                    continue;
+                },
+                i if i == item_count - 1 => {
+                    assert_eq!(description, "3 packets lost");
+                    continue;
+                },
+                i if i > position => i - 1,
+                i => i,
+            };
+            let transfer: TrafficItem =
+                plain.item(None, transfer_index).unwrap();
+            assert_eq!(description,
+                       plain.description(&transfer, false).unwrap());
+        }
+        let marker: TrafficItem = reader.item(None, position).unwrap();
+        assert!(reader.child_item(&marker, 0).is_err());
+        assert_eq!(reader.timestamp(&marker).unwrap(),
+                   markers[0].timestamp);
+
+        let mut saved = Vec::new();
+        crate::native::save(&mut reader, &mut saved).unwrap();
+        let (writer, restored) = create_capture().unwrap();
+        crate::native::load(saved.as_slice(), writer).unwrap();
+        assert_eq!(restored.shared.markers.load_full(), markers);
+    }
+
+    /// Check the items shown by a filter against those found by checking
+    /// every item, returning the number of transfer items shown.
+    fn check_filter<F>(reader: &mut CaptureReader,
+                       filter: TrafficFilter,
+                       expected: F) -> u64
+        where F: Fn(&Endpoint, &str) -> bool
+    {
+        let mut plain = reader.clone();
+        plain.set_filter(TrafficFilter::default());
+        let (_, item_count) =
+            plain.item_children(None::<&TrafficItem>).unwrap();
+        let mut wanted = Vec::new();
+        let mut transfers_shown = 0;
+        for index in 0..item_count {
+            let item = plain.item(None, index).unwrap();
+            let description = plain.description(&item, false).unwrap();
+            if let TrafficItem::Transfer(transfer_id) = item {
+                let entry = plain.transfer_index.get(transfer_id).unwrap();
+                let endpoint =
+                    plain.endpoints.get(entry.endpoint_id()).unwrap();
+                if !expected(&endpoint, &description) {
+                    continue;
+                }
+                transfers_shown += 1;
+            }
+            wanted.push(description);
+        }
+        reader.set_filter(filter);
+        let (_, count) = reader.item_children(None::<&TrafficItem>).unwrap();
+        let shown: Vec<String> = (0..count)
+            .map(|index| {
+                let item: TrafficItem = reader.item(None, index).unwrap();
+                reader.description(&item, false).unwrap()
+            })
+            .collect();
+        assert_eq!(shown, wanted);
+        transfers_shown
+    }
+
+    #[test]
+    fn test_traffic_filter() {
+        fn polling(description: &str) -> bool {
+            description.starts_with("Polling") ||
+                description.starts_with("End polling")
+        }
+        let (writer, mut reader) = create_capture().unwrap();
+        let mut decoder = Decoder::new(writer).unwrap();
+        decode_test_capture("split-enum", &mut decoder, |decoder, index, time| {
+            if index == 50 {
+                decoder.handle_marker("Note".to_string(), time);
+            }
+        });
+        decoder.finish().unwrap();
+
+        // Each transfer is shown when filtering to its device.
+        let mut transfers_shown = 0;
+        for id in 0..reader.devices.len() {
+            let device_id = DeviceId::from(id);
+            let filter = TrafficFilter {
+                device: Some(device_id),
+                .. TrafficFilter::default()
+            };
+            transfers_shown += check_filter(&mut reader, filter,
+                |endpoint, _| endpoint.device_id() == device_id);
+        }
+        assert!(reader.devices.len() > 2);
+        assert_eq!(transfers_shown, reader.item_index.len());
+
+        let filter = TrafficFilter::parse("address=0 endpoint=0").unwrap();
+        check_filter(&mut reader, filter, |endpoint, _|
+            endpoint.device_address().0 == 0 && endpoint.number().0 == 0);
+        let filter = TrafficFilter::parse("ignore=0.0,1.0").unwrap();
+        check_filter(&mut reader, filter, |endpoint, _|
+            endpoint.device_address().0 > 1 || endpoint.number().0 != 0);
+        let filter = TrafficFilter::parse("hide=SOF,NAK").unwrap();
+        let shown = check_filter(&mut reader, filter, |_, description|
+            !description.contains("SOF groups") && !polling(description));
+        assert!(shown < reader.item_index.len());
+        let all = check_filter(&mut reader, TrafficFilter::default(),
+            |_, _| true);
+        assert_eq!(all, reader.item_index.len());
+
+        // Polling for the reports of a mouse is hidden.
+        let mut reader = load_test_capture("mouse");
+        let filter = TrafficFilter::parse("hide=NAK").unwrap();
+        let shown = check_filter(&mut reader, filter, |_, description|
+            !polling(description));
+        assert!(shown < reader.item_index.len());
+    }
+
+    #[test]
+    fn test_item_columns() {
+        let mut reader = load_test_capture("mouse");
+        let (_, count) = reader.item_children(None::<&TrafficItem>).unwrap();
+        let transfer = (0..count)
+            .map(|index| {
+                let item = reader.item(None, index).unwrap();
+                (item, reader.description(&item, false).unwrap())
+            })
+            .find(|(_, description)| description ==
+                "Getting device descriptor #0 for device 4, reading 18 bytes")
+            .unwrap()
+            .0;
+        assert_eq!(reader.item_number(&transfer), None);
+        let endpoint = reader.item_endpoint(&transfer).unwrap().unwrap();
+        assert!(endpoint.to_string().starts_with("4.0 "));
+        assert_eq!(reader.item_result(&transfer).unwrap(), Some(PID::ACK));
+        assert_eq!(reader.item_length(&transfer).unwrap(), Some(18));
+        assert_eq!(reader.item_preview(&transfer, 4).unwrap(),
+                   [0x80, 0x06, 0x00, 0x01]);
+
+        // The SETUP transaction carries the request.
+        let setup = reader.item(Some(&transfer), 0).unwrap();
+        let TrafficItem::Transaction(_, transaction_id) = setup else {
+            panic!("Expected a transaction");
+        };
+        assert_eq!(reader.item_number(&setup),
+                   Some(transaction_id.value + 1));
+        assert_eq!(reader.item_length(&setup).unwrap(), Some(8));
+        assert_eq!(reader.item_preview(&setup, 2).unwrap(), [0x80, 0x06]);
+        assert_eq!(reader.item_result(&setup).unwrap(), Some(PID::ACK));
+
+        // Its packets are a SETUP token, DATA0 and ACK.
+        let token = reader.item(Some(&setup), 0).unwrap();
+        let data = reader.item(Some(&setup), 1).unwrap();
+        let TrafficItem::Packet(.., packet_id) = token else {
+            panic!("Expected a packet");
+        };
+        assert_eq!(reader.item_result(&token).unwrap(), Some(PID::SETUP));
+        assert_eq!(reader.item_length(&token).unwrap(), Some(3));
+        assert!(reader.item_preview(&token, 16).unwrap().is_empty());
+        assert_eq!(reader.item_length(&data).unwrap(), Some(11));
+        assert_eq!(reader.item_preview(&data, 8).unwrap().len(), 8);
+        let delta = reader.item_delta(&data).unwrap().unwrap();
+        let start = reader.packet_time(packet_id).unwrap();
+        let end = reader.packet_time(packet_id + 1).unwrap();
+        assert_eq!(delta, end - start);
+        assert_eq!(reader.item_delta(&setup).unwrap(),
+                   reader.item_delta(&token).unwrap());
+
+        // The SETUP transaction lasts from its token to its handshake.
+        let handshake = reader.packet_time(packet_id + 2).unwrap();
+        let setup_duration = reader.item_duration(&setup).unwrap().unwrap();
+        assert_eq!(setup_duration, handshake - start);
+        let duration = reader.item_duration(&transfer).unwrap().unwrap();
+        assert!(duration > setup_duration);
+        assert_eq!(reader.item_duration(&token).unwrap(), None);
+
+        // The packets shared by the SETUP transaction and its token are
+        // counted once, but their lengths are both included.
+        let stats = reader.item_stats(&[setup, token]).unwrap();
+        assert_eq!(stats.items, 2);
+        assert_eq!(stats.packets, 3);
+        assert_eq!(stats.bytes, 8 + 3);
+        assert_eq!(stats.time_range, Some(start..(handshake + 1)));
+        let throughput = stats.throughput().unwrap();
+        assert_eq!(throughput, 11.0 * 1e9 / (handshake + 1 - start) as f64);
+        let stats = reader.item_stats(&[transfer, setup]).unwrap();
+        assert_eq!(stats.packets,
+                   reader.item_stats(&[transfer]).unwrap().packets);
+        assert_eq!(reader.item_stats(&[]).unwrap(), ItemStats::default());
+        assert_eq!(ItemStats::default().throughput(), None);
+    }
+
+    #[test]
+    fn test_item_preview_all() {
+        fn preview_all(reader: &mut CaptureReader,
+                       parent: Option<&TrafficItem>,
+                       naks: &mut usize)
+        {
+            let (_, count) = reader.item_children(parent).unwrap();
+            for index in 0..count {
+                let item = reader.item(parent, index).unwrap();
+                let preview = reader.item_preview(&item, 16).unwrap();
+                assert!(preview.len() <= 16);
+                if let TrafficItem::Transaction(..) = item {
+                    if reader.item_result(&item).unwrap() == Some(PID::NAK) {
+                        assert!(preview.is_empty());
+                        *naks += 1;
+                    }
+                }
+                preview_all(reader, Some(&item), naks);
+            }
+        }
+        let mut reader = load_test_capture("mouse");
+        let mut naks = 0;
+        preview_all(&mut reader, None, &mut naks);
+        assert!(naks > 0);
+    }
+
+    #[test]
+    fn test_item_paths() {
+        fn follow(reader: &mut CaptureReader, path: &[u64]) -> TrafficItem {
+            let mut item = reader.item(None, path[0]).unwrap();
+            for &index in &path[1..] {
+                item = reader.item(Some(&item), index).unwrap();
+            }
+            item
+        }
+        fn check_paths(reader: &mut CaptureReader, filter: &str) -> u64 {
+            reader.set_filter(TrafficFilter::parse(filter).unwrap());
+            let mut hidden = 0;
+            for id in 0..reader.packet_index.len() {
+                let item = reader.packet_item(PacketId::from(id)).unwrap();
+                match reader.item_path(&item).unwrap() {
+                    Some(path) => {
+                        assert_eq!(path.len(), 3);
+                        assert_eq!(follow(reader, &path), item);
+                    },
+                    None => hidden += 1,
+                }
+            }
+            hidden
+        }
+        let mut reader = load_test_capture("split-enum");
+        assert_eq!(check_paths(&mut reader, ""), 0);
+        assert!(check_paths(&mut reader, "hide=SOF") > 0);
+        let mut reader = load_test_capture("mouse");
+        assert_eq!(check_paths(&mut reader, ""), 0);
+        assert!(check_paths(&mut reader, "hide=NAK") > 0);
+
+        // Markers are found with and without a filter.
+        let (writer, mut reader) = create_capture().unwrap();
+        let mut decoder = Decoder::new(writer).unwrap();
+        let packets: [&[u8]; 3] = [&[0xa5, 0xde, 0x1e], &[0x2d, 0x00, 0x10],
+                                   &[0xa5, 0xdf, 0xde]];
+        decoder.handle_raw_packet(packets[0], 0).unwrap();
+        decoder.handle_marker("Note".to_string(), 1);
+        decoder.handle_raw_packet(packets[1], 2).unwrap();
+        decoder.handle_raw_packet(packets[2], 3).unwrap();
+        decoder.finish().unwrap();
+        let marker = TrafficItem::Marker(MarkerId::from(0));
+        for filter in ["", "hide=SOF"] {
+            reader.set_filter(TrafficFilter::parse(filter).unwrap());
+            let path = reader.item_path(&marker).unwrap().unwrap();
+            assert_eq!(follow(&mut reader, &path), marker);
+        }
+    }
+
+    #[test]
+    fn test_collapse_idle() {
+        let mut reader = load_test_capture("hackrf-connect");
+        reader.set_filter(TrafficFilter {
+            collapse_idle: true,
+            .. TrafficFilter::default()
+        });
+        let item: TrafficItem = reader.item(None, 0).unwrap();
+        assert_eq!(reader.description(&item, false).unwrap(),
+                   "7 idle periods");
+        // SOF packets with frame numbers 228 and 229.
+        let group = reader.item(Some(&item), 0).unwrap();
+        let description = reader.description(&group, false).unwrap();
+        assert!(description.starts_with("2 frames idle, "), "{description}");
+        // The details are unchanged.
+        let description = reader.description(&group, true).unwrap();
+        assert!(description.contains("13 SOF packets"), "{description}");
+        reader.set_filter(TrafficFilter::default());
+        assert_eq!(reader.description(&item, false).unwrap(),
+                   "7 SOF groups");
+    }
+
+    #[test]
+    fn test_collapse_polling() {
+        let mut reader = load_test_capture("mouse");
+        let (_, count) = reader.item_children(None::<&TrafficItem>).unwrap();
+        let polling: TrafficItem = (0..count)
+            .map(|index| {
+                let item = reader.item(None, index).unwrap();
+                (item, reader.description(&item, false).unwrap())
+            })
+            .find(|(_, description)| description.starts_with("Polling"))
+            .unwrap()
+            .0;
+        let polls = reader.item_children(Some(&polling)).unwrap().1;
+        reader.set_filter(TrafficFilter {
+            collapse_polling: true,
+            .. TrafficFilter::default()
+        });
+        let description = reader.description(&polling, false).unwrap();
+        assert!(description.starts_with("Endpoint ") &&
+                description.contains(&format!(" polled {polls} times over ")),
+                "{description}");
+        assert!(reader
+            .description(&polling, true)
+            .unwrap()
+            .contains("Polling"));
+    }
+
+    #[test]
+    fn test_traffic_filter_parse() {
+        let filter = TrafficFilter::parse("address=5,7 hide=sof").unwrap();
+        assert_eq!(filter, TrafficFilter {
+            addresses: vec![5, 7],
+            hide_sof: true,
+            .. TrafficFilter::default()
+        });
+        assert!(TrafficFilter::parse("").unwrap().is_empty());
+        let error = |text| TrafficFilter::parse(text).unwrap_err().to_string();
+        assert_eq!(error("endpoint=16"),
+                   "The endpoint must be no more than 15");
+        assert_eq!(error("hide=ACK"),
+                   "Cannot hide 'ACK', expected 'SOF' or 'NAK'");
+        assert_eq!(error("address"), "Invalid filter term 'address'");
+        assert_eq!(TrafficFilter::parse("ignore=5.1").unwrap().ignored,
+                   vec![(5, 1)]);
+        assert_eq!(error("ignore=5"), "Invalid endpoint '5' to ignore, \
+                                       expected address.endpoint");
+        assert_eq!(error("ignore=5.16"),
+                   "The endpoint must be no more than 15");
+    }
+
+    #[test]
+    fn test_packet_subsets() {
+        let mut reader = load_test_capture("hackrf-dfu-enum");
+        let packet_count = reader.packet_index.len();
+        let count = |reader: &mut CaptureReader, subset: &PacketSubset| {
+            reader
+                .subset_packet_ranges(subset)
+                .unwrap()
+                .iter()
+                .map(|range| range.len())
+                .sum::<u64>()
+        };
+        // Every packet belongs to exactly one device.
+        let device_total: u64 = (0..reader.devices.len())
+            .map(|id| count(&mut reader, &PacketSubset::Device(id.into())))
+            .sum();
+        assert_eq!(device_total, packet_count);
+        // A transfer contains the packets of all its transactions.
+        let item = reader.item(None, 1).unwrap();
+        let (_, transaction_count) = reader.item_children(Some(&item)).unwrap();
+        let mut transaction_total = 0;
+        for index in 0..transaction_count {
+            let child = reader.child_item(&item, index).unwrap();
+            transaction_total += count(&mut reader, &PacketSubset::Item(child));
+        }
+        let item_subset = PacketSubset::Item(item);
+        assert_eq!(count(&mut reader, &item_subset), transaction_total);
+        // The time span of the transfer includes at least its packets.
+        let time_range = reader.item_time_range(&item).unwrap();
+        let time_subset = PacketSubset::TimeRange(time_range);
+        assert!(count(&mut reader, &time_subset) >= transaction_total);
+        let all = PacketSubset::TimeRange(0..u64::MAX);
+        assert_eq!(count(&mut reader, &all), packet_count);
+        // An exported subset can be decoded again.
+        let packets = reader.subset_packets(&item_subset).unwrap();
+        let mut saved = Vec::new();
+        let exported = crate::native::save_packets(packets, &mut saved);
+        assert_eq!(exported.unwrap(), transaction_total);
+        let (writer, restored) = create_capture().unwrap();
+        crate::native::load(saved.as_slice(), writer).unwrap();
+        assert_eq!(restored.packet_index.len(), transaction_total);
+    }
+
+    #[test]
+    fn test_serial_data() {
+        let mut reader = load_test_capture("cdc-acm");
+        let device_id = DeviceId::from(1);
+        assert!(reader
+            .serial_data(device_id, ConfigNum(1), InterfaceNum(0))
+            .unwrap()
+            .is_none());
+        let text = reader
+            .serial_data(device_id, ConfigNum(1), InterfaceNum(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(text, concat!(
+            "Serial data on interface 7.1\n\n",
+            "Device to host on endpoint 1 (33 bytes):\n",
+            "\nOK\n\nSerial Adapter v1.0\n\\u{1b}[0m\n\n",
+            "Host to device on endpoint 2 (9 bytes):\n",
+            "AT\nATI\n"));
+    }
+
+    #[test]
+    fn test_ctap_messages() {
+        let mut reader = load_test_capture("fido");
+        let device_id = DeviceId::from(1);
+        let text = reader
+            .ctap_messages(device_id, ConfigNum(1), InterfaceNum(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(text, concat!(
+            "CTAPHID messages on interface 9.0\n",
+            "Host: INIT with nonce 0102030405060708\n",
+            "Device: INIT response, assigned channel 0x0A1B2C3D, ",
+            "protocol version 2\n",
+            "Host: CTAP2 authenticatorGetInfo\n",
+            "Device: KEEPALIVE, processing\n",
+            "Device: CTAP2 OK {1: [\"U2F_V2\", \"FIDO_2_0\"], ",
+            "2: [\"hmac-secret\"], 3: h'101112131415161718191A1B1C1D1E1F', ",
+            "4: {\"rk\": true, \"up\": true, \"plat\": false}}\n",
+            "Host: U2F VERSION\n",
+            "Device: U2F response 9000 (no error), version U2F_V2\n",
+            "Host: U2F REGISTER, 64 bytes of data\n",
+            "Device: U2F response 6985 ",
+            "(conditions not satisfied, user presence required)\n",
+            "Device: KEEPALIVE, user presence needed\n",
+            "Host: CANCEL\n",
+            "Device: ERROR, invalid channel"));
+    }
+
+    #[test]
+    fn test_input_reconstruction() {
+        let mut reader = load_test_capture("mouse");
+        let device_id = DeviceId::from(1);
+        let text = reader
+            .input_reconstruction(device_id, ConfigNum(1), InterfaceNum(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(text, concat!(
+            "Mouse input on interface 4.0\n",
+            "Total movement -27, -151\n\n",
+            "0.000377 s: Moved -27, -151 in 155 reports over 0 ms"));
+    }
+
+    #[test]
+    fn test_video_frames() {
+        let mut reader = load_test_capture("uvc");
+        let device_id = DeviceId::from(1);
+        let mut frames = Vec::new();
+        let bulk_ep = reader
+            .endpoint_id(device_id, EndpointAddr(0x81))
+            .unwrap()
+            .unwrap();
+        let count = reader
+            .video_frames(bulk_ep, |frame| { frames.push(frame); Ok(()) })
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(frames[0].len(), 772);
+        assert_eq!(frames[1].len(), 704);
+        assert_eq!(class::uvc::frame_extension(&frames[0]), "jpg");
+        frames.clear();
+        let iso_ep = reader
+            .endpoint_id(device_id, EndpointAddr(0x82))
+            .unwrap()
+            .unwrap();
+        let count = reader
+            .video_frames(iso_ep, |frame| { frames.push(frame); Ok(()) })
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(frames[2], vec![0x33; 150]);
+    }
+
+    #[test]
+    fn test_audio_data() {
+        let mut reader = load_test_capture("uac");
+        let endpoint_id = reader
+            .endpoint_id(DeviceId::from(1), EndpointAddr(0x81))
+            .unwrap()
+            .unwrap();
+        let (format, data) = reader.audio_data(endpoint_id).unwrap().unwrap();
+        assert_eq!(format.description(), "2 channels, 48000 Hz, 16-bit PCM");
+        assert_eq!(data.len(), 1920);
+        assert_eq!(&data[4..8], &[1, 0, 0xFF, 0xFF]);
+        assert_eq!(format.wav_file(&data).len(), 1964);
+    }
+
+    #[test]
+    fn test_hci_packets() {
+        use class::bt::PacketType;
+        let mut reader = load_test_capture("bluetooth");
+        let packets = reader.hci_packets(DeviceId::from(1)).unwrap();
+        let count = |packet_type| packets
+            .iter()
+            .filter(|packet| packet.packet_type == packet_type)
+            .count();
+        assert_eq!(count(PacketType::Command), 7);
+        assert_eq!(count(PacketType::Event), 10);
+        assert_eq!(count(PacketType::AclData), 2);
+        assert_eq!(packets[0].data, vec![0x03, 0x0C, 0]);
+        assert_eq!(packets[1].packet_type, PacketType::Event);
+        assert!(packets[1].received);
+        let mut output = Vec::new();
+        let mut writer = crate::btsnoop::Writer::open(&mut output).unwrap();
+        for packet in &packets {
+            writer.add_packet(packet).unwrap();
+        }
+        writer.close().unwrap();
+        let data_length: usize = packets
+            .iter()
+            .map(|packet| 25 + packet.data.len())
+            .sum();
+        assert_eq!(output.len(), 16 + data_length);
+        assert_eq!(&output[0..8], b"btsnoop\0");
+    }
+
+    #[test]
+    fn test_dfu_progress() {
+        let mut reader = load_test_capture("dfu");
+        let device: DeviceItem = reader.item(None, 0).unwrap();
+        assert_eq!(reader.description(&device, false).unwrap(), concat!(
+            "Device 9: DFU Bootloader (DFU, downloaded 1 KiB in 4 blocks, ",
+            "state dfuMANIFEST-WAIT-RESET)"));
+    }
 }
 
 pub mod prelude {
@@ -1914,6 +4941,8 @@ pub mod prelude {
         EndpointWriter,
         EndpointTransactionId,
         EndpointTransferId,
+        Marker,
+        MarkerKind,
         PacketId,
         TrafficItemId,
         TransactionId,
@@ -1923,5 +4952,6 @@ pub mod prelude {
         FRAMING_EP_NUM,
         INVALID_EP_ID,
         FRAMING_EP_ID,
+        DEFAULT_DEVICE_ID,
     };
 }
@@ -0,0 +1,388 @@
+//! Checks of captured traffic and descriptors against the USB
+//! specification.
+
+use std::io::Write;
+
+use anyhow::Error;
+use serde_json::{json, Value};
+
+use crate::capture::{
+    CaptureReader,
+    DeviceData,
+    DeviceId,
+    ItemSource,
+    MarkerKind,
+    Timestamp,
+    TrafficItem,
+    FRAMING_EP_NUM,
+    INVALID_EP_NUM,
+};
+use crate::timestamp::fmt_timestamp;
+use crate::usb::{
+    Configuration,
+    EndpointAddr,
+    EndpointDescriptor,
+    EndpointType,
+    RequestType,
+    StandardRequest,
+    PID,
+};
+use crate::util::fmt_count;
+
+/// A problem found in a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Name of the rule broken.
+    pub rule: &'static str,
+    /// Address of the device responsible, if known.
+    pub device: Option<u8>,
+    /// Time of the traffic at fault, if the problem is in traffic.
+    pub time: Option<Timestamp>,
+    pub message: String,
+}
+
+/// Check the descriptors of the devices at some addresses, or all if none
+/// are given, and the traffic in a selection of top-level items.
+pub fn check_capture(capture: &mut CaptureReader,
+                     addresses: &[u8],
+                     items: &[TrafficItem])
+    -> Result<Vec<Violation>, Error>
+{
+    let mut violations = Vec::new();
+    // The first device is a placeholder for traffic to address zero
+    // before a device is recognised.
+    for id in 1..capture.devices.len() {
+        let device_id = DeviceId::from(id);
+        let address = capture.devices.get(device_id)?.address.0;
+        if addresses.is_empty() || addresses.contains(&address) {
+            let data = capture.device_data(&device_id)?;
+            check_descriptors(&mut violations, address, &data);
+        }
+    }
+    for item in items {
+        check_item(capture, &mut violations, item)?;
+    }
+    Ok(violations)
+}
+
+/// Check the descriptors seen for a device.
+fn check_descriptors(violations: &mut Vec<Violation>,
+                     address: u8,
+                     data: &DeviceData)
+{
+    let mut report = |rule, message: String| violations.push(Violation {
+        rule,
+        device: Some(address),
+        time: None,
+        message,
+    });
+    if let Some(descriptor) = data.device_descriptor.load().as_ref() {
+        let rule = "device-descriptor";
+        if descriptor.length != 18 {
+            report(rule, format!(
+                "Device descriptor has length {}, not 18",
+                descriptor.length));
+        }
+        // SuperSpeed devices give the size as a power of two.
+        let max_size = descriptor.max_packet_size_0;
+        let superspeed = descriptor.usb_version.major >= 3;
+        if !(matches!(max_size, 8 | 16 | 32 | 64) ||
+             superspeed && max_size == 9)
+        {
+            report(rule, format!(
+                "Device descriptor has invalid maximum packet size {} \
+                 for endpoint 0", max_size));
+        }
+        if descriptor.num_configurations == 0 {
+            report(rule,
+                "Device descriptor gives no configurations".to_string());
+        }
+    }
+    let configurations = data.configurations.load_full();
+    for config in configurations.as_ref() {
+        let descriptor = &config.descriptor;
+        let number = descriptor.config_value;
+        let rule = "configuration-descriptor";
+        if descriptor.length != 9 {
+            report(rule, format!(
+                "Configuration {number} descriptor has length {}, not 9",
+                descriptor.length));
+        }
+        // Only the start of the descriptors may have been read, when
+        // the host first reads enough to find their total length.
+        let total_length = descriptor.total_length as usize;
+        if config.to_bytes().len() < total_length {
+            continue;
+        }
+        let interfaces = config.interfaces.into_iter().count();
+        if interfaces != descriptor.num_interfaces as usize {
+            report(rule, format!(
+                "Configuration {number} has {interfaces} interfaces, \
+                 but its descriptor gives {}", descriptor.num_interfaces));
+        }
+        check_interfaces(&mut report, config);
+    }
+}
+
+/// Check the interfaces and endpoints of a configuration.
+fn check_interfaces<F>(report: &mut F, config: &Configuration)
+    where F: FnMut(&'static str, String)
+{
+    for iface in &config.interfaces {
+        let descriptor = &iface.descriptor;
+        let number = descriptor.interface_number;
+        if descriptor.length != 9 {
+            report("interface-descriptor", format!(
+                "Interface {number} descriptor has length {}, not 9",
+                descriptor.length));
+        }
+        let endpoints = iface.endpoint_descriptors.into_iter().count();
+        if endpoints != descriptor.num_endpoints as usize {
+            report("interface-descriptor", format!(
+                "Interface {number} has {endpoints} endpoints, \
+                 but its descriptor gives {}", descriptor.num_endpoints));
+        }
+        for ep_desc in &iface.endpoint_descriptors {
+            if let Some(problem) = endpoint_problem(ep_desc) {
+                let address = ep_desc.endpoint_address;
+                report("endpoint-descriptor", format!(
+                    "Endpoint {} {} of interface {number} {problem}",
+                    address.number(), address.direction()));
+            }
+        }
+    }
+}
+
+/// What is wrong with an endpoint descriptor, if anything.
+fn endpoint_problem(descriptor: &EndpointDescriptor) -> Option<String> {
+    use EndpointType::*;
+    let length = descriptor.length;
+    let max_packet_size = descriptor.max_packet_size;
+    let size = max_packet_size & 0x7FF;
+    let extra_transactions = (max_packet_size >> 11) & 0x3;
+    let ep_type = descriptor.attributes.endpoint_type();
+    Some(if length < 7 {
+        format!("has descriptor length {length}, less than 7")
+    } else if descriptor.endpoint_address.number().0 == 0 {
+        "is numbered 0, which is reserved for control".to_string()
+    } else if max_packet_size >> 13 != 0 || extra_transactions == 3 {
+        format!("has invalid maximum packet size 0x{max_packet_size:04X}")
+    } else if match ep_type {
+        Control => !matches!(size, 8 | 16 | 32 | 64 | 512),
+        Bulk => !matches!(size, 8 | 16 | 32 | 64 | 512 | 1024),
+        Interrupt | Isochronous => size > 1024,
+    } || (extra_transactions != 0 && matches!(ep_type, Control | Bulk)) {
+        format!("has invalid maximum packet size {size} \
+                 for a {ep_type} endpoint")
+    } else {
+        return None
+    })
+}
+
+/// Check a top-level item, and the transactions within it.
+fn check_item(capture: &mut CaptureReader,
+              violations: &mut Vec<Violation>,
+              item: &TrafficItem)
+    -> Result<(), Error>
+{
+    let time = capture.item_time_range(item)?.start;
+    let Some(endpoint) = capture.item_endpoint(item)? else {
+        if let TrafficItem::Marker(marker_id) = item {
+            if let MarkerKind::Loss(_) = capture.marker(*marker_id)?.kind {
+                violations.push(Violation {
+                    rule: "packet-loss",
+                    device: None,
+                    time: Some(time),
+                    message: capture.description(item, false)?,
+                });
+            }
+        }
+        return Ok(());
+    };
+    if endpoint.number() == FRAMING_EP_NUM {
+        return Ok(());
+    }
+    let address = endpoint.device_address().0;
+    let malformed = endpoint.number() == INVALID_EP_NUM;
+    let device = (!malformed).then_some(address);
+    let mut report = |rule, time, message| violations.push(Violation {
+        rule,
+        device,
+        time: Some(time),
+        message,
+    });
+    if let Some(transfer) = capture.item_control_transfer(item)? {
+        let fields = &transfer.fields;
+        let size = transfer.data.len();
+        if size > fields.length as usize {
+            report("control-data-too-long", time, format!(
+                "{} bytes transferred, but only {} requested: {}",
+                size, fields.length, transfer.summary()));
+        }
+        let request = (fields.type_fields.request_type(),
+                       StandardRequest::from(fields.request));
+        if matches!(request,
+                    (RequestType::Standard, StandardRequest::SetAddress)) &&
+            fields.value > 127
+        {
+            report("invalid-address", time, transfer.summary());
+        }
+    }
+    let max_size = if malformed {
+        None
+    } else {
+        let data = capture.device_data(&endpoint.device_id())?;
+        let ep_addr = EndpointAddr::from_parts(
+            endpoint.number(), endpoint.direction());
+        data.endpoint_details(ep_addr).1.map(|size| size as u64 & 0x7FF)
+    };
+    let (_, count) = capture.item_children(Some(item))?;
+    for index in 0..count {
+        let child = capture.item(Some(item), index)?;
+        let time = capture.item_time_range(&child)?.start;
+        if malformed {
+            report("malformed-packet", time,
+                   capture.description(&child, false)?);
+            continue;
+        }
+        let length = capture.item_length(&child)?.unwrap_or(0);
+        if max_size.is_some_and(|max_size| length > max_size) {
+            report("packet-too-large", time, format!(
+                "{length} bytes sent on endpoint {endpoint} \
+                 with maximum packet size {}", max_size.unwrap()));
+        }
+        if capture.item_is_error(&child)? {
+            match capture.item_result(&child)? {
+                Some(PID::STALL) => {},
+                Some(PID::ERR) => report("error-handshake", time,
+                    capture.description(&child, false)?),
+                _ => report("no-response", time, format!(
+                    "No response from device: {}",
+                    capture.description(&child, false)?)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write violations as text, ending with the number found.
+pub fn write_violations_text(violations: &[Violation], mut dest: impl Write)
+    -> Result<(), Error>
+{
+    for violation in violations {
+        match violation.time {
+            Some(time) => write!(dest, "{}", fmt_timestamp(time))?,
+            None => write!(dest, "descriptors")?,
+        }
+        if let Some(address) = violation.device {
+            write!(dest, " device {address}")?;
+        }
+        writeln!(dest, ": [{}] {}", violation.rule, violation.message)?;
+    }
+    match violations.len() {
+        0 => writeln!(dest, "No violations found")?,
+        1 => writeln!(dest, "Found 1 violation")?,
+        n => writeln!(dest, "Found {} violations", fmt_count(n as u64))?,
+    }
+    dest.flush()?;
+    Ok(())
+}
+
+/// Write violations as JSON.
+///
+/// Times are in nanoseconds from the start of the capture, and are `null`
+/// for problems with descriptors, as is the device for problems not
+/// caused by one.
+pub fn write_violations_json(violations: &[Violation], mut dest: impl Write)
+    -> Result<(), Error>
+{
+    let list: Vec<Value> = violations
+        .iter()
+        .map(|violation| json!({
+            "rule": violation.rule,
+            "device": violation.device,
+            "time_ns": violation.time,
+            "message": violation.message,
+        }))
+        .collect();
+    let output = json!({
+        "violations": list,
+        "count": violations.len(),
+    });
+    serde_json::to_writer_pretty(&mut dest, &output)?;
+    writeln!(dest)?;
+    dest.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{load_capture, Selection};
+    use crate::usb::EndpointAttr;
+
+    #[test]
+    fn test_endpoint_problem() {
+        let descriptor = |address, attributes, max_packet_size| {
+            EndpointDescriptor {
+                length: 7,
+                descriptor_type: 5,
+                endpoint_address: EndpointAddr(address),
+                attributes: EndpointAttr(attributes),
+                max_packet_size,
+                interval: 1,
+            }
+        };
+        assert_eq!(endpoint_problem(&descriptor(0x81, 2, 512)), None);
+        assert_eq!(endpoint_problem(&descriptor(0x81, 3, 0x1400)), None);
+        assert_eq!(endpoint_problem(&descriptor(0x01, 1, 1023)), None);
+        assert!(endpoint_problem(&descriptor(0x80, 2, 64)).is_some());
+        assert!(endpoint_problem(&descriptor(0x81, 2, 100)).is_some());
+        assert!(endpoint_problem(&descriptor(0x81, 2, 0x0A00)).is_some());
+        assert!(endpoint_problem(&descriptor(0x81, 3, 0x1C00)).is_some());
+        assert!(endpoint_problem(&descriptor(0x81, 3, 0x2008)).is_some());
+        assert!(endpoint_problem(&descriptor(0x81, 1, 1025)).is_some());
+    }
+
+    #[test]
+    fn test_check_capture() {
+        let path = "./tests/mouse/capture.pcap";
+        let mut capture = load_capture(path, None).unwrap();
+        let items = Selection::default().items(&mut capture).unwrap();
+        let violations = check_capture(&mut capture, &[], &items).unwrap();
+        for violation in &violations {
+            assert!(violation.time.is_some());
+        }
+        let mut text = Vec::new();
+        write_violations_text(&violations, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.ends_with("found\n") || text.ends_with("violations\n")
+                || text.ends_with("violation\n"));
+        let mut json = Vec::new();
+        write_violations_json(&violations, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.ends_with(
+            &format!("\"count\": {}\n}}\n", violations.len())));
+        let violation = Violation {
+            rule: "no-response",
+            device: Some(3),
+            time: Some(1_500_000_000),
+            message: "No \"response\"".to_string(),
+        };
+        let mut text = Vec::new();
+        write_violations_text(std::slice::from_ref(&violation),
+                              &mut text).unwrap();
+        assert_eq!(String::from_utf8(text).unwrap(),
+                   "1.500000000 device 3: [no-response] No \"response\"\n\
+                    Found 1 violation\n");
+        let mut json = Vec::new();
+        write_violations_json(&[violation], &mut json).unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(),
+                   "{\n  \"violations\": [\n    {\n      \
+                    \"rule\": \"no-response\",\n      \
+                    \"device\": 3,\n      \
+                    \"time_ns\": 1500000000,\n      \
+                    \"message\": \"No \\\"response\\\"\"\n    }\n  ],\n  \
+                    \"count\": 1\n}\n");
+    }
+}
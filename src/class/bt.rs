@@ -0,0 +1,266 @@
+//! Bluetooth HCI over USB, as used by wireless controller class devices.
+//!
+//! HCI commands are sent as class requests on the control endpoint, events
+//! are received on the interrupt endpoint, and ACL data is exchanged on the
+//! bulk endpoints.
+
+use std::fmt::Write;
+
+use crate::usb::{EndpointType, InterfaceDescriptor, SetupFields};
+use super::{ClassDecoder, InterfaceInfo, Transfer};
+
+pub const CLASS: u8 = 0xE0;
+pub const SUBCLASS_RF: u8 = 0x01;
+pub const PROTOCOL_BLUETOOTH: u8 = 0x01;
+
+const EVENT_COMMAND_COMPLETE: u8 = 0x0E;
+const EVENT_COMMAND_STATUS: u8 = 0x0F;
+const EVENT_LE_META: u8 = 0x3E;
+
+/// Whether an interface is a Bluetooth controller interface.
+pub fn is_bluetooth(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS &&
+        iface.interface_subclass == SUBCLASS_RF &&
+        iface.interface_protocol == PROTOCOL_BLUETOOTH
+}
+
+/// Type of an HCI packet, as used in the H4 framing of btsnoop files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PacketType {
+    Command = 1,
+    AclData = 2,
+    Event = 4,
+}
+
+/// An HCI packet found in a capture.
+pub struct HciPacket {
+    pub timestamp_ns: u64,
+    pub packet_type: PacketType,
+    pub received: bool,
+    pub data: Vec<u8>,
+}
+
+fn command_name(opcode: u16) -> Option<&'static str> {
+    Some(match opcode {
+        0x0401 => "Inquiry",
+        0x0402 => "Inquiry Cancel",
+        0x0405 => "Create Connection",
+        0x0406 => "Disconnect",
+        0x0409 => "Accept Connection Request",
+        0x0419 => "Remote Name Request",
+        0x0C01 => "Set Event Mask",
+        0x0C03 => "Reset",
+        0x0C05 => "Set Event Filter",
+        0x0C13 => "Write Local Name",
+        0x0C14 => "Read Local Name",
+        0x0C1A => "Write Scan Enable",
+        0x0C24 => "Write Class of Device",
+        0x0C56 => "Write Simple Pairing Mode",
+        0x0C6D => "Write LE Host Support",
+        0x1001 => "Read Local Version Information",
+        0x1002 => "Read Local Supported Commands",
+        0x1003 => "Read Local Supported Features",
+        0x1005 => "Read Buffer Size",
+        0x1009 => "Read BD_ADDR",
+        0x2001 => "LE Set Event Mask",
+        0x2002 => "LE Read Buffer Size",
+        0x2005 => "LE Set Random Address",
+        0x2006 => "LE Set Advertising Parameters",
+        0x2008 => "LE Set Advertising Data",
+        0x200A => "LE Set Advertising Enable",
+        0x200B => "LE Set Scan Parameters",
+        0x200C => "LE Set Scan Enable",
+        0x200D => "LE Create Connection",
+        0x2016 => "LE Read Remote Features",
+        _ => return None
+    })
+}
+
+fn command_string(opcode: u16) -> String {
+    match command_name(opcode) {
+        Some(name) => name.to_string(),
+        None if opcode >> 10 == 0x3F =>
+            format!("vendor command 0x{:03X}", opcode & 0x3FF),
+        None => format!("command 0x{opcode:04X}"),
+    }
+}
+
+fn event_name(code: u8) -> Option<&'static str> {
+    Some(match code {
+        0x01 => "Inquiry Complete",
+        0x02 => "Inquiry Result",
+        0x03 => "Connection Complete",
+        0x04 => "Connection Request",
+        0x05 => "Disconnection Complete",
+        0x07 => "Remote Name Request Complete",
+        0x08 => "Encryption Change",
+        0x0E => "Command Complete",
+        0x0F => "Command Status",
+        0x13 => "Number Of Completed Packets",
+        0x3E => "LE Meta",
+        0xFF => "Vendor",
+        _ => return None
+    })
+}
+
+fn le_subevent_name(code: u8) -> Option<&'static str> {
+    Some(match code {
+        0x01 => "LE Connection Complete",
+        0x02 => "LE Advertising Report",
+        0x03 => "LE Connection Update Complete",
+        0x04 => "LE Read Remote Features Complete",
+        0x0A => "LE Enhanced Connection Complete",
+        _ => return None
+    })
+}
+
+fn status_string(status: u8) -> String {
+    match status {
+        0x00 => "success".to_string(),
+        0x01 => "unknown command".to_string(),
+        0x02 => "unknown connection identifier".to_string(),
+        0x05 => "authentication failure".to_string(),
+        0x08 => "connection timeout".to_string(),
+        0x0C => "command disallowed".to_string(),
+        0x12 => "invalid parameters".to_string(),
+        0x13 => "remote user terminated connection".to_string(),
+        0x16 => "connection terminated by local host".to_string(),
+        _ => format!("error 0x{status:02X}"),
+    }
+}
+
+fn channel_name(cid: u16) -> Option<&'static str> {
+    Some(match cid {
+        0x0001 => "signalling",
+        0x0004 => "ATT",
+        0x0005 => "LE signalling",
+        0x0006 => "SMP",
+        _ => return None
+    })
+}
+
+/// Describe an HCI command packet.
+pub fn command_description(data: &[u8]) -> Option<String> {
+    let opcode = u16::from_le_bytes([*data.first()?, *data.get(1)?]);
+    let length = *data.get(2)?;
+    let mut s = format!("HCI command {}", command_string(opcode));
+    if length > 0 {
+        write!(s, " with {length} bytes of parameters").ok()?;
+    }
+    Some(s)
+}
+
+/// Describe an HCI event packet.
+pub fn event_description(data: &[u8]) -> Option<String> {
+    let code = *data.first()?;
+    let params = data.get(2..)?;
+    let mut s = match event_name(code) {
+        Some(name) => format!("HCI event {name}"),
+        None => format!("HCI event 0x{code:02X}"),
+    };
+    match code {
+        EVENT_COMMAND_COMPLETE if params.len() >= 3 => {
+            let opcode = u16::from_le_bytes([params[1], params[2]]);
+            write!(s, " for {}", command_string(opcode)).ok()?;
+            if let Some(&status) = params.get(3) {
+                write!(s, ", {}", status_string(status)).ok()?;
+            }
+        },
+        EVENT_COMMAND_STATUS if params.len() >= 4 => {
+            let opcode = u16::from_le_bytes([params[2], params[3]]);
+            write!(s, " for {}, {}",
+                command_string(opcode), status_string(params[0])).ok()?;
+        },
+        EVENT_LE_META if !params.is_empty() => {
+            match le_subevent_name(params[0]) {
+                Some(name) => write!(s, ": {name}"),
+                None => write!(s, ": subevent 0x{:02X}", params[0]),
+            }.ok()?;
+        },
+        _ => {}
+    }
+    Some(s)
+}
+
+/// Describe an HCI ACL data packet.
+pub fn acl_description(data: &[u8]) -> Option<String> {
+    let header = u16::from_le_bytes([*data.first()?, *data.get(1)?]);
+    let length = u16::from_le_bytes([*data.get(2)?, *data.get(3)?]);
+    let handle = header & 0x0FFF;
+    let boundary = (header >> 12) & 0x3;
+    let mut s = format!(
+        "HCI ACL data for handle 0x{handle:03X}, {length} bytes");
+    // Only the first fragment of a packet carries the L2CAP header.
+    if boundary != 0x1 {
+        if let Some(l2cap) = data.get(4..8) {
+            let cid = u16::from_le_bytes([l2cap[2], l2cap[3]]);
+            match channel_name(cid) {
+                Some(name) => write!(s, ", L2CAP {name} channel"),
+                None => write!(s, ", L2CAP channel 0x{cid:04X}"),
+            }.ok()?;
+        }
+    } else {
+        s.push_str(", continuation");
+    }
+    Some(s)
+}
+
+/// Describe a transfer on an endpoint of a Bluetooth controller.
+pub fn transfer_description(ep_type: EndpointType, payload: &[u8])
+    -> Option<String>
+{
+    match ep_type {
+        EndpointType::Interrupt => event_description(payload),
+        EndpointType::Bulk => acl_description(payload),
+        _ => None
+    }
+}
+
+/// Describe an HCI command sent as a class request.
+pub fn request_description(fields: &SetupFields, data: &[u8])
+    -> Option<String>
+{
+    if fields.request != 0 {
+        return None
+    }
+    command_description(data)
+}
+
+/// Decoder for Bluetooth controller interfaces.
+pub struct Decoder;
+
+impl ClassDecoder for Decoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        is_bluetooth(iface.descriptor)
+    }
+
+    fn request_description(&self, fields: &SetupFields, data: &[u8])
+        -> Option<String>
+    {
+        request_description(fields, data)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.ep_type, transfer.payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hci() {
+        assert_eq!(command_description(&[0x03, 0x0C, 0]).unwrap(),
+                   "HCI command Reset");
+        assert_eq!(command_description(&[0x01, 0xFC, 2, 0, 0]).unwrap(),
+            "HCI command vendor command 0x001 with 2 bytes of parameters");
+        assert_eq!(event_description(&[0x0E, 4, 1, 0x03, 0x0C, 0]).unwrap(),
+                   "HCI event Command Complete for Reset, success");
+        assert_eq!(event_description(&[0x3E, 2, 0x02, 1]).unwrap(),
+                   "HCI event LE Meta: LE Advertising Report");
+        let acl = [0x40, 0x20, 7, 0, 3, 0, 4, 0, 0x0A, 0x01, 0x00];
+        assert_eq!(acl_description(&acl).unwrap(),
+                   "HCI ACL data for handle 0x040, 7 bytes, L2CAP ATT channel");
+    }
+}
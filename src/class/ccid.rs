@@ -0,0 +1,345 @@
+//! Smart card reader (CCID) class, with ISO 7816-4 APDU decoding.
+
+use std::fmt::Write;
+
+use num_enum::FromPrimitive;
+
+use crate::usb::InterfaceDescriptor;
+use super::{ClassDecoder, InterfaceInfo, Transfer};
+
+pub const CLASS: u8 = 0x0B;
+
+const HEADER_LENGTH: usize = 10;
+
+/// Whether an interface is a smart card reader interface.
+pub fn is_ccid(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    // Bulk messages from host to reader.
+    SetParameters = 0x61,
+    IccPowerOn = 0x62,
+    IccPowerOff = 0x63,
+    GetSlotStatus = 0x65,
+    Secure = 0x69,
+    T0Apdu = 0x6A,
+    Escape = 0x6B,
+    GetParameters = 0x6C,
+    ResetParameters = 0x6D,
+    IccClock = 0x6E,
+    XfrBlock = 0x6F,
+    Mechanical = 0x71,
+    Abort = 0x72,
+    SetDataRateAndClockFrequency = 0x73,
+    // Bulk messages from reader to host.
+    DataBlock = 0x80,
+    SlotStatus = 0x81,
+    Parameters = 0x82,
+    EscapeResponse = 0x83,
+    DataRateAndClockFrequency = 0x84,
+    // Interrupt messages from reader to host.
+    NotifySlotChange = 0x50,
+    HardwareError = 0x51,
+    #[default]
+    Unknown = 0xFF,
+}
+
+impl MessageType {
+    pub fn name(self) -> &'static str {
+        use MessageType::*;
+        match self {
+            SetParameters => "PC_to_RDR_SetParameters",
+            IccPowerOn => "PC_to_RDR_IccPowerOn",
+            IccPowerOff => "PC_to_RDR_IccPowerOff",
+            GetSlotStatus => "PC_to_RDR_GetSlotStatus",
+            Secure => "PC_to_RDR_Secure",
+            T0Apdu => "PC_to_RDR_T0APDU",
+            Escape => "PC_to_RDR_Escape",
+            GetParameters => "PC_to_RDR_GetParameters",
+            ResetParameters => "PC_to_RDR_ResetParameters",
+            IccClock => "PC_to_RDR_IccClock",
+            XfrBlock => "PC_to_RDR_XfrBlock",
+            Mechanical => "PC_to_RDR_Mechanical",
+            Abort => "PC_to_RDR_Abort",
+            SetDataRateAndClockFrequency =>
+                "PC_to_RDR_SetDataRateAndClockFrequency",
+            DataBlock => "RDR_to_PC_DataBlock",
+            SlotStatus => "RDR_to_PC_SlotStatus",
+            Parameters => "RDR_to_PC_Parameters",
+            EscapeResponse => "RDR_to_PC_Escape",
+            DataRateAndClockFrequency =>
+                "RDR_to_PC_DataRateAndClockFrequency",
+            NotifySlotChange => "RDR_to_PC_NotifySlotChange",
+            HardwareError => "RDR_to_PC_HardwareError",
+            Unknown => "unknown CCID message",
+        }
+    }
+
+    fn is_response(self) -> bool {
+        (self as u8) & 0x80 != 0 && self != MessageType::Unknown
+    }
+}
+
+/// Header common to all CCID bulk messages.
+pub struct Header {
+    pub length: u32,
+    pub slot: u8,
+    pub sequence: u8,
+    pub specific: [u8; 3],
+}
+
+impl Header {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LENGTH {
+            return None
+        }
+        Some(Header {
+            length: u32::from_le_bytes(bytes[1..5].try_into().ok()?),
+            slot: bytes[5],
+            sequence: bytes[6],
+            specific: [bytes[7], bytes[8], bytes[9]],
+        })
+    }
+}
+
+/// Name of an ISO 7816-4 instruction.
+fn instruction_name(ins: u8) -> Option<&'static str> {
+    Some(match ins {
+        0x0E => "ERASE BINARY",
+        0x20 => "VERIFY",
+        0x22 => "MANAGE SECURITY ENVIRONMENT",
+        0x24 => "CHANGE REFERENCE DATA",
+        0x2A => "PERFORM SECURITY OPERATION",
+        0x2C => "RESET RETRY COUNTER",
+        0x44 => "ACTIVATE FILE",
+        0x46 => "GENERATE ASYMMETRIC KEY PAIR",
+        0x70 => "MANAGE CHANNEL",
+        0x82 => "EXTERNAL AUTHENTICATE",
+        0x84 => "GET CHALLENGE",
+        0x88 => "INTERNAL AUTHENTICATE",
+        0xA4 => "SELECT",
+        0xB0 => "READ BINARY",
+        0xB2 => "READ RECORD",
+        0xC0 => "GET RESPONSE",
+        0xC2 => "ENVELOPE",
+        0xCA | 0xCB => "GET DATA",
+        0xD6 => "UPDATE BINARY",
+        0xDA | 0xDB => "PUT DATA",
+        0xDC => "UPDATE RECORD",
+        0xE2 => "APPEND RECORD",
+        _ => return None
+    })
+}
+
+/// Meaning of an ISO 7816-4 status word.
+fn status_meaning(sw1: u8, sw2: u8) -> String {
+    match (sw1, sw2) {
+        (0x90, 0x00) => "success".to_string(),
+        (0x61, n) => format!("success, {n} bytes available"),
+        (0x62, _) => "warning, state unchanged".to_string(),
+        (0x63, n) if n & 0xF0 == 0xC0 => format!(
+            "verification failed, {} retries left", n & 0x0F),
+        (0x63, _) => "warning, state changed".to_string(),
+        (0x64, _) => "execution error, state unchanged".to_string(),
+        (0x65, _) => "execution error, state changed".to_string(),
+        (0x67, 0x00) => "wrong length".to_string(),
+        (0x69, 0x82) => "security status not satisfied".to_string(),
+        (0x69, 0x83) => "authentication method blocked".to_string(),
+        (0x69, 0x85) => "conditions of use not satisfied".to_string(),
+        (0x69, _) => "command not allowed".to_string(),
+        (0x6A, 0x82) => "file or application not found".to_string(),
+        (0x6A, 0x86) => "incorrect parameters P1-P2".to_string(),
+        (0x6A, 0x88) => "referenced data not found".to_string(),
+        (0x6A, _) => "wrong parameters".to_string(),
+        (0x6C, n) => format!("wrong Le, {n} bytes available"),
+        (0x6D, 0x00) => "instruction not supported".to_string(),
+        (0x6E, 0x00) => "class not supported".to_string(),
+        (0x6F, 0x00) => "no precise diagnosis".to_string(),
+        _ => "unknown status".to_string(),
+    }
+}
+
+/// Describe an ISO 7816-4 command APDU.
+pub fn command_description(apdu: &[u8]) -> Option<String> {
+    let &[cla, ins, p1, p2] = apdu.get(0..4)? else { return None };
+    let body = &apdu[4..];
+    let mut s = match instruction_name(ins) {
+        Some(name) => name.to_string(),
+        None => format!("INS {ins:02X}"),
+    };
+    write!(s, " (CLA {cla:02X}, P1 {p1:02X}, P2 {p2:02X})").ok()?;
+    // Short APDUs only: Lc and Le are single bytes.
+    match body.len() {
+        0 => {},
+        1 => write!(s, ", Le {}", body[0]).ok()?,
+        n => {
+            let lc = body[0] as usize;
+            if n < 1 + lc {
+                return Some(s)
+            }
+            write!(s, ", {lc} bytes of data").ok()?;
+            if n == 2 + lc {
+                write!(s, ", Le {}", body[1 + lc]).ok()?;
+            }
+        }
+    }
+    Some(s)
+}
+
+/// Describe an ISO 7816-4 response APDU.
+///
+/// Returns None if the data does not end in a valid status word.
+pub fn response_description(apdu: &[u8]) -> Option<String> {
+    let length = apdu.len().checked_sub(2)?;
+    let (sw1, sw2) = (apdu[length], apdu[length + 1]);
+    if !matches!(sw1 & 0xF0, 0x60 | 0x90) {
+        return None
+    }
+    let mut s = format!("status {sw1:02X}{sw2:02X} ({})",
+                        status_meaning(sw1, sw2));
+    if length > 0 {
+        write!(s, " with {length} bytes of data").ok()?;
+    }
+    Some(s)
+}
+
+/// Describe a slot status byte from a response message.
+fn slot_status(status: u8, error: u8) -> String {
+    let icc = match status & 0x03 {
+        0 => "card active",
+        1 => "card inactive",
+        2 => "no card",
+        _ => "invalid card status",
+    };
+    match status >> 6 {
+        0 => icc.to_string(),
+        1 => format!("{icc}, failed with error 0x{error:02X}"),
+        2 => format!("{icc}, time extension requested"),
+        _ => format!("{icc}, invalid command status"),
+    }
+}
+
+/// Describe a transfer on a CCID bulk or interrupt endpoint.
+///
+/// The payload may be truncated, in which case APDUs are described only
+/// as far as they can be.
+pub fn transfer_description(payload: &[u8], detail: bool) -> Option<String> {
+    use MessageType::*;
+    let message_type = MessageType::from(*payload.first()?);
+    match message_type {
+        NotifySlotChange => {
+            let mut s = String::from("CCID slot change:");
+            for (i, &byte) in payload[1..].iter().enumerate() {
+                for bit in 0..4 {
+                    let state = byte >> (bit * 2);
+                    if state & 0x02 != 0 {
+                        write!(s, " slot {} {},", i * 4 + bit,
+                            if state & 0x01 != 0 {
+                                "card inserted"
+                            } else {
+                                "card removed"
+                            }).ok()?;
+                    }
+                }
+            }
+            Some(s.trim_end_matches(',').to_string())
+        },
+        HardwareError => Some(format!(
+            "CCID hardware error on slot {}, code 0x{:02X}",
+            payload.get(1)?, payload.get(3)?)),
+        Unknown => None,
+        _ => {
+            let header = Header::from_bytes(payload)?;
+            let data = &payload[HEADER_LENGTH..];
+            let complete = data.len() == header.length as usize;
+            let mut s = format!("CCID {}, slot {}, sequence {}",
+                message_type.name(), header.slot, header.sequence);
+            if message_type.is_response() {
+                let [status, error, _] = header.specific;
+                write!(s, ": {}", slot_status(status, error)).ok()?;
+            }
+            match message_type {
+                XfrBlock | T0Apdu if !data.is_empty() => {
+                    if let Some(command) = command_description(data) {
+                        write!(s, ", command {command}").ok()?;
+                    }
+                },
+                DataBlock if complete => {
+                    if let Some(response) = response_description(data) {
+                        write!(s, ", response {response}").ok()?;
+                    } else if matches!(data.first(), Some(0x3B | 0x3F)) {
+                        write!(s, ", answer to reset of {} bytes",
+                            header.length).ok()?;
+                    } else if header.length > 0 {
+                        write!(s, ", {} bytes of data", header.length).ok()?;
+                    }
+                },
+                _ => if header.length > 0 {
+                    write!(s, ", {} bytes of data", header.length).ok()?;
+                }
+            }
+            if detail {
+                write!(s, "\nMessage type 0x{:02X}, {} bytes of data",
+                    message_type as u8, header.length).ok()?;
+                if !complete {
+                    s.push_str(", not all shown");
+                }
+            }
+            Some(s)
+        }
+    }
+}
+
+/// Decoder for smart card reader interfaces.
+pub struct Decoder;
+
+impl ClassDecoder for Decoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        is_ccid(iface.descriptor)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, false)
+    }
+
+    fn transfer_detail(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apdu() {
+        let select = [0x00, 0xA4, 0x04, 0x00, 0x02, 0x3F, 0x00, 0x00];
+        assert_eq!(command_description(&select).unwrap(),
+            "SELECT (CLA 00, P1 04, P2 00), 2 bytes of data, Le 0");
+        assert_eq!(response_description(&[0x01, 0x02, 0x90, 0x00]).unwrap(),
+            "status 9000 (success) with 2 bytes of data");
+        assert_eq!(response_description(&[0x63, 0xC2]).unwrap(),
+            "status 63C2 (verification failed, 2 retries left)");
+        assert!(response_description(&[0x90]).is_none());
+        assert!(response_description(&[0x3B, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_messages() {
+        let xfr = [0x6F, 5, 0, 0, 0, 0, 7, 0, 0, 0,
+                   0x00, 0xB0, 0x00, 0x00, 0x10];
+        assert_eq!(transfer_description(&xfr, false).unwrap(), concat!(
+            "CCID PC_to_RDR_XfrBlock, slot 0, sequence 7, ",
+            "command READ BINARY (CLA 00, P1 00, P2 00), Le 16"));
+        let block = [0x80, 2, 0, 0, 0, 0, 7, 0x40, 0xFE, 0, 0x6A, 0x82];
+        assert_eq!(transfer_description(&block, false).unwrap(), concat!(
+            "CCID RDR_to_PC_DataBlock, slot 0, sequence 7: card active, ",
+            "failed with error 0xFE, response status 6A82 ",
+            "(file or application not found)"));
+        assert_eq!(transfer_description(&[0x50, 0x03], false).unwrap(),
+            "CCID slot change: slot 0 card inserted");
+    }
+}
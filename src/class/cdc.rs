@@ -0,0 +1,260 @@
+//! Communications device class, Abstract Control Model (serial ports).
+
+use std::fmt::Write;
+
+use num_enum::FromPrimitive;
+
+use crate::usb::{InterfaceDescriptor, SetupFields};
+use super::{ClassDecoder, InterfaceInfo, Transfer};
+
+pub const CLASS_COMMUNICATIONS: u8 = 0x02;
+pub const CLASS_DATA: u8 = 0x0A;
+pub const SUBCLASS_ACM: u8 = 0x02;
+
+const NOTIFICATION_REQUEST_TYPE: u8 = 0xA1;
+const NOTIFICATION_HEADER_LENGTH: usize = 8;
+const LINE_CODING_LENGTH: usize = 7;
+
+/// Whether an interface is the control interface of an ACM function.
+pub fn is_acm(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS_COMMUNICATIONS &&
+        iface.interface_subclass == SUBCLASS_ACM
+}
+
+/// Whether an interface is a CDC data interface.
+pub fn is_data(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS_DATA
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AcmRequest {
+    SendEncapsulatedCommand = 0x00,
+    GetEncapsulatedResponse = 0x01,
+    SetLineCoding = 0x20,
+    GetLineCoding = 0x21,
+    SetControlLineState = 0x22,
+    SendBreak = 0x23,
+    #[default]
+    Unknown = 0xFF,
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Notification {
+    NetworkConnection = 0x00,
+    ResponseAvailable = 0x01,
+    SerialState = 0x20,
+    #[default]
+    Unknown = 0xFF,
+}
+
+/// Line coding structure, as used by SET_LINE_CODING and GET_LINE_CODING.
+pub struct LineCoding {
+    pub baud_rate: u32,
+    pub stop_bits: u8,
+    pub parity: u8,
+    pub data_bits: u8,
+}
+
+impl LineCoding {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != LINE_CODING_LENGTH {
+            return None
+        }
+        Some(LineCoding {
+            baud_rate: u32::from_le_bytes(
+                [bytes[0], bytes[1], bytes[2], bytes[3]]),
+            stop_bits: bytes[4],
+            parity: bytes[5],
+            data_bits: bytes[6],
+        })
+    }
+}
+
+impl std::fmt::Display for LineCoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let parity = match self.parity {
+            0 => "N",
+            1 => "O",
+            2 => "E",
+            3 => "M",
+            4 => "S",
+            _ => "?",
+        };
+        let stop_bits = match self.stop_bits {
+            0 => "1",
+            1 => "1.5",
+            2 => "2",
+            _ => "?",
+        };
+        write!(f, "{} baud, {}{}{}",
+               self.baud_rate, self.data_bits, parity, stop_bits)
+    }
+}
+
+/// Describe a class request to an ACM control interface.
+pub fn request_description(fields: &SetupFields, data: &[u8])
+    -> Option<String>
+{
+    use AcmRequest::*;
+    let on_off = |bit: u16| if fields.value & bit != 0 { "on" } else { "off" };
+    Some(match AcmRequest::from(fields.request) {
+        SendEncapsulatedCommand => "Sending encapsulated command".to_string(),
+        GetEncapsulatedResponse => "Getting encapsulated response".to_string(),
+        SetLineCoding => match LineCoding::from_bytes(data) {
+            Some(coding) => format!("Setting line coding to {coding}"),
+            None => "Setting line coding".to_string(),
+        },
+        GetLineCoding => match LineCoding::from_bytes(data) {
+            Some(coding) => format!("Getting line coding: {coding}"),
+            None => "Getting line coding".to_string(),
+        },
+        SetControlLineState => format!(
+            "Setting control line state: DTR {}, RTS {}",
+            on_off(0x01), on_off(0x02)),
+        SendBreak => match fields.value {
+            0 => "Ending break".to_string(),
+            0xFFFF => "Sending break until cleared".to_string(),
+            ms => format!("Sending break for {ms} ms"),
+        },
+        Unknown => return None,
+    })
+}
+
+/// Describe a notification sent on an ACM interrupt endpoint.
+pub fn notification_description(payload: &[u8], detail: bool)
+    -> Option<String>
+{
+    use Notification::*;
+    if payload.len() < NOTIFICATION_HEADER_LENGTH ||
+        payload[0] != NOTIFICATION_REQUEST_TYPE
+    {
+        return None
+    }
+    let value = u16::from_le_bytes([payload[2], payload[3]]);
+    let data = &payload[NOTIFICATION_HEADER_LENGTH..];
+    let mut s = match Notification::from(payload[1]) {
+        NetworkConnection if value == 0 => "Network disconnected".to_string(),
+        NetworkConnection => "Network connected".to_string(),
+        ResponseAvailable => "Response available".to_string(),
+        SerialState if data.len() >= 2 => {
+            const LINES: [&str; 7] = [
+                "DCD", "DSR", "break", "ring",
+                "framing error", "parity error", "overrun",
+            ];
+            let state = u16::from_le_bytes([data[0], data[1]]);
+            let active: Vec<&str> = LINES
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| state & (1 << i) != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            if active.is_empty() {
+                "Serial state: no lines active".to_string()
+            } else {
+                format!("Serial state: {}", active.join(", "))
+            }
+        },
+        _ => format!("Notification 0x{:02X}", payload[1]),
+    };
+    if detail {
+        write!(s, "\nNotification for interface {}, value 0x{value:04X}",
+               u16::from_le_bytes([payload[4], payload[5]])).ok()?;
+    }
+    Some(s)
+}
+
+/// Describe a transfer on a CDC data interface.
+pub fn data_description(payload: &[u8], partial: bool) -> String {
+    let text = String::from_utf8_lossy(payload);
+    format!("Serial data {text:?}{}", if partial { "..." } else { "" })
+}
+
+/// Render a serial data stream as readable text.
+///
+/// Line endings are normalised, and other control characters escaped.
+pub fn stream_text(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len());
+    let text = String::from_utf8_lossy(data).replace("\r\n", "\n");
+    for c in text.chars() {
+        match c {
+            '\n' | '\t' => s.push(c),
+            c if c.is_control() => s.extend(c.escape_default()),
+            c => s.push(c),
+        }
+    }
+    s
+}
+
+/// Decoder for the communications interface of a serial (ACM) device.
+pub struct AcmDecoder;
+
+impl ClassDecoder for AcmDecoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        is_acm(iface.descriptor)
+    }
+
+    fn request_description(&self, fields: &SetupFields, data: &[u8])
+        -> Option<String>
+    {
+        request_description(fields, data)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        notification_description(transfer.payload, false)
+    }
+
+    fn transfer_detail(&self, transfer: &Transfer) -> Option<String> {
+        notification_description(transfer.payload, true)
+    }
+}
+
+/// Decoder for the data interface of a CDC device.
+pub struct DataDecoder;
+
+impl ClassDecoder for DataDecoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        is_data(iface.descriptor)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        Some(data_description(transfer.payload, transfer.partial))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(request: u8, value: u16) -> SetupFields {
+        let [value_lo, value_hi] = value.to_le_bytes();
+        SetupFields::from_data_packet(
+            &[0xC3, 0x21, request, value_lo, value_hi, 0, 0, 0, 0, 0, 0])
+    }
+
+    #[test]
+    fn test_requests() {
+        let coding = [0x00, 0xC2, 0x01, 0x00, 0, 0, 8];
+        assert_eq!(request_description(&setup(0x20, 0), &coding).unwrap(),
+                   "Setting line coding to 115200 baud, 8N1");
+        assert_eq!(request_description(&setup(0x22, 3), &[]).unwrap(),
+                   "Setting control line state: DTR on, RTS on");
+        assert_eq!(request_description(&setup(0x23, 250), &[]).unwrap(),
+                   "Sending break for 250 ms");
+        assert!(request_description(&setup(0x42, 0), &[]).is_none());
+    }
+
+    #[test]
+    fn test_serial_state() {
+        let notification = [0xA1, 0x20, 0, 0, 0, 0, 2, 0, 0x03, 0x00];
+        assert_eq!(notification_description(&notification, false).unwrap(),
+                   "Serial state: DCD, DSR");
+        assert!(notification_description(&notification[..6], false).is_none());
+    }
+
+    #[test]
+    fn test_stream_text() {
+        assert_eq!(stream_text(b"AT\r\nOK\r\n\x1b[0m"), "AT\nOK\n\\u{1b}[0m");
+    }
+}
@@ -0,0 +1,534 @@
+//! FIDO security keys, with CTAPHID framing of CTAP1 (U2F) and CTAP2
+//! messages.
+
+use std::cmp::min;
+use std::fmt::Write;
+
+use num_enum::FromPrimitive;
+
+use super::{hid, ClassDecoder, InterfaceInfo, Transfer};
+
+pub const USAGE_PAGE_FIDO: u16 = 0xF1D0;
+pub const REPORT_SIZE: usize = 64;
+
+const INIT_HEADER_LENGTH: usize = 7;
+const CONT_HEADER_LENGTH: usize = 5;
+const BYTES_SHOWN: usize = 16;
+const MAX_CBOR_DEPTH: usize = 16;
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Command {
+    Ping = 0x01,
+    Msg = 0x03,
+    Lock = 0x04,
+    Init = 0x06,
+    Wink = 0x08,
+    Cbor = 0x10,
+    Cancel = 0x11,
+    Keepalive = 0x3B,
+    Error = 0x3F,
+    #[default]
+    Unknown = 0x00,
+}
+
+fn command_string(command: u8) -> String {
+    use Command::*;
+    match Command::from(command) {
+        Ping => "PING",
+        Msg => "MSG",
+        Lock => "LOCK",
+        Init => "INIT",
+        Wink => "WINK",
+        Cbor => "CBOR",
+        Cancel => "CANCEL",
+        Keepalive => "KEEPALIVE",
+        Error => "ERROR",
+        Unknown => return format!("command 0x{command:02X}"),
+    }.to_string()
+}
+
+/// A single CTAPHID report.
+pub enum Packet<'p> {
+    Init { channel: u32, command: u8, length: usize, data: &'p [u8] },
+    Continuation { channel: u32, sequence: u8, data: &'p [u8] },
+}
+
+impl<'p> Packet<'p> {
+    pub fn from_bytes(bytes: &'p [u8]) -> Option<Self> {
+        let channel = u32::from_be_bytes(bytes.get(0..4)?.try_into().ok()?);
+        let byte = *bytes.get(4)?;
+        if byte & 0x80 != 0 {
+            let length = u16::from_be_bytes(
+                bytes.get(5..7)?.try_into().ok()?) as usize;
+            Some(Packet::Init {
+                channel,
+                command: byte & 0x7F,
+                length,
+                data: &bytes[INIT_HEADER_LENGTH..],
+            })
+        } else {
+            Some(Packet::Continuation {
+                channel,
+                sequence: byte,
+                data: &bytes[CONT_HEADER_LENGTH..],
+            })
+        }
+    }
+}
+
+/// Describe a transfer on a FIDO HID endpoint.
+///
+/// A transfer may contain several reports, of which only the first is
+/// described here. Messages are reassembled by [`Assembler`].
+pub fn transfer_description(payload: &[u8], partial: bool) -> Option<String> {
+    let mut reports = payload.chunks(REPORT_SIZE);
+    let mut s = match Packet::from_bytes(reports.next()?)? {
+        Packet::Init { channel, command, length, .. } => format!(
+            "CTAPHID {} on channel 0x{channel:08X}, {length} {}",
+            command_string(command),
+            if length == 1 { "byte" } else { "bytes" }),
+        Packet::Continuation { channel, sequence, .. } => format!(
+            "CTAPHID continuation {sequence} on channel 0x{channel:08X}"),
+    };
+    let more = reports.len();
+    if partial {
+        s.push_str(", and further packets");
+    } else if more > 0 {
+        write!(s, ", and {more} more {}",
+            if more == 1 { "packet" } else { "packets" }).ok()?;
+    }
+    Some(s)
+}
+
+/// A CTAPHID message, reassembled from one or more packets.
+pub struct Message {
+    pub channel: u32,
+    pub command: u8,
+    pub length: usize,
+    pub data: Vec<u8>,
+    next_sequence: u8,
+}
+
+impl Message {
+    pub fn complete(&self) -> bool {
+        self.data.len() == self.length
+    }
+}
+
+/// Reassembles CTAPHID messages from the reports sent in one direction.
+#[derive(Default)]
+pub struct Assembler {
+    pending: Option<Message>,
+}
+
+impl Assembler {
+    /// Add a report, returning any messages that are now finished.
+    ///
+    /// A message that is interrupted by a new message, or by a packet out
+    /// of sequence, is returned incomplete.
+    pub fn add_report(&mut self, report: &[u8]) -> Vec<Message> {
+        let mut finished = Vec::new();
+        match Packet::from_bytes(report) {
+            Some(Packet::Init { channel, command, length, data }) => {
+                finished.extend(self.pending.take());
+                let message = Message {
+                    channel,
+                    command,
+                    length,
+                    data: data[..min(length, data.len())].to_vec(),
+                    next_sequence: 0,
+                };
+                if message.complete() {
+                    finished.push(message);
+                } else {
+                    self.pending = Some(message);
+                }
+            },
+            Some(Packet::Continuation { channel, sequence, data }) => {
+                if let Some(mut message) = self.pending.take() {
+                    if message.channel == channel &&
+                        message.next_sequence == sequence
+                    {
+                        let remaining = message.length - message.data.len();
+                        let length = min(remaining, data.len());
+                        message.data.extend(&data[..length]);
+                        message.next_sequence += 1;
+                        if message.complete() {
+                            finished.push(message);
+                        } else {
+                            self.pending = Some(message);
+                        }
+                    } else {
+                        finished.push(message);
+                    }
+                }
+            },
+            None => {}
+        }
+        finished
+    }
+
+    /// Return any incomplete message remaining at the end of the capture.
+    pub fn finish(self) -> Option<Message> {
+        self.pending
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for byte in bytes.iter().take(BYTES_SHOWN) {
+        let _ = write!(s, "{byte:02X}");
+    }
+    if bytes.len() > BYTES_SHOWN {
+        s.push_str("...");
+    }
+    s
+}
+
+fn cbor_item(data: &[u8], pos: &mut usize, depth: usize, s: &mut String)
+    -> Option<()>
+{
+    if depth > MAX_CBOR_DEPTH {
+        return None
+    }
+    let initial = *data.get(*pos)?;
+    *pos += 1;
+    let major = initial >> 5;
+    let info = initial & 0x1F;
+    let arg_length = match info {
+        0..=23 => 0,
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        // Indefinite lengths are not used by CTAP2.
+        _ => return None
+    };
+    let arg_bytes = data.get(*pos..*pos + arg_length)?;
+    *pos += arg_length;
+    let arg = if arg_length == 0 {
+        info as u64
+    } else {
+        arg_bytes.iter().fold(0u64, |value, &byte| value << 8 | byte as u64)
+    };
+    let mut take = |length: u64| -> Option<&[u8]> {
+        let end = pos.checked_add(length.try_into().ok()?)?;
+        let bytes = data.get(*pos..end)?;
+        *pos = end;
+        Some(bytes)
+    };
+    match major {
+        0 => write!(s, "{arg}").ok()?,
+        1 => write!(s, "{}", -1 - arg as i128).ok()?,
+        2 => {
+            let bytes = take(arg)?;
+            write!(s, "h'{}'", hex_bytes(bytes)).ok()?
+        },
+        3 => {
+            let text = String::from_utf8_lossy(take(arg)?);
+            write!(s, "{text:?}").ok()?
+        },
+        4 => {
+            s.push('[');
+            for i in 0..arg {
+                if i > 0 {
+                    s.push_str(", ");
+                }
+                cbor_item(data, pos, depth + 1, s)?;
+            }
+            s.push(']');
+        },
+        5 => {
+            s.push('{');
+            for i in 0..arg {
+                if i > 0 {
+                    s.push_str(", ");
+                }
+                cbor_item(data, pos, depth + 1, s)?;
+                s.push_str(": ");
+                cbor_item(data, pos, depth + 1, s)?;
+            }
+            s.push('}');
+        },
+        6 => {
+            write!(s, "{arg}(").ok()?;
+            cbor_item(data, pos, depth + 1, s)?;
+            s.push(')');
+        },
+        _ => match (info, arg_length) {
+            (20, _) => s.push_str("false"),
+            (21, _) => s.push_str("true"),
+            (22, _) => s.push_str("null"),
+            (23, _) => s.push_str("undefined"),
+            (_, 4) => write!(s, "{}", f32::from_bits(arg as u32)).ok()?,
+            (_, 8) => write!(s, "{}", f64::from_bits(arg)).ok()?,
+            _ => write!(s, "simple({arg})").ok()?,
+        },
+    }
+    Some(())
+}
+
+/// Render CBOR data in diagnostic notation.
+pub fn cbor_diagnostic(data: &[u8]) -> Option<String> {
+    let mut s = String::new();
+    let mut pos = 0;
+    cbor_item(data, &mut pos, 0, &mut s)?;
+    if pos < data.len() {
+        write!(s, " and {} more bytes", data.len() - pos).ok()?;
+    }
+    Some(s)
+}
+
+fn ctap2_command_name(command: u8) -> Option<&'static str> {
+    Some(match command {
+        0x01 => "authenticatorMakeCredential",
+        0x02 => "authenticatorGetAssertion",
+        0x04 => "authenticatorGetInfo",
+        0x06 => "authenticatorClientPIN",
+        0x07 => "authenticatorReset",
+        0x08 => "authenticatorGetNextAssertion",
+        0x09 => "authenticatorBioEnrollment",
+        0x0A => "authenticatorCredentialManagement",
+        0x0B => "authenticatorSelection",
+        0x0C => "authenticatorLargeBlobs",
+        0x0D => "authenticatorConfig",
+        _ => return None
+    })
+}
+
+fn ctap2_status_name(status: u8) -> Option<&'static str> {
+    Some(match status {
+        0x00 => "OK",
+        0x01 => "invalid command",
+        0x02 => "invalid parameter",
+        0x03 => "invalid length",
+        0x11 => "CBOR unexpected type",
+        0x12 => "invalid CBOR",
+        0x14 => "missing parameter",
+        0x19 => "credential excluded",
+        0x22 => "invalid credential",
+        0x26 => "unsupported algorithm",
+        0x27 => "operation denied",
+        0x2E => "no credentials",
+        0x2F => "user action timeout",
+        0x31 => "PIN invalid",
+        0x32 => "PIN blocked",
+        0x35 => "PIN not set",
+        0x36 => "PIN required",
+        0x7F => "other error",
+        _ => return None
+    })
+}
+
+fn u2f_instruction_name(ins: u8) -> Option<&'static str> {
+    Some(match ins {
+        0x01 => "REGISTER",
+        0x02 => "AUTHENTICATE",
+        0x03 => "VERSION",
+        _ => return None
+    })
+}
+
+fn u2f_status_name(sw: u16) -> &'static str {
+    match sw {
+        0x9000 => "no error",
+        0x6985 => "conditions not satisfied, user presence required",
+        0x6A80 => "wrong data",
+        0x6700 => "wrong length",
+        0x6D00 => "instruction not supported",
+        0x6E00 => "class not supported",
+        _ => "unknown status",
+    }
+}
+
+fn ctaphid_error_name(code: u8) -> &'static str {
+    match code {
+        0x01 => "invalid command",
+        0x02 => "invalid parameter",
+        0x03 => "invalid length",
+        0x04 => "invalid sequence",
+        0x05 => "message timeout",
+        0x06 => "channel busy",
+        0x0A => "lock required",
+        0x0B => "invalid channel",
+        _ => "other error",
+    }
+}
+
+fn cbor_request(data: &[u8]) -> String {
+    let mut s = match data.first() {
+        None => return "CTAP2 empty request".to_string(),
+        Some(&command) => match ctap2_command_name(command) {
+            Some(name) => format!("CTAP2 {name}"),
+            None => format!("CTAP2 command 0x{command:02X}"),
+        }
+    };
+    if data.len() > 1 {
+        match cbor_diagnostic(&data[1..]) {
+            Some(params) => { let _ = write!(s, " {params}"); },
+            None => s.push_str(", invalid CBOR parameters"),
+        }
+    }
+    s
+}
+
+fn cbor_response(data: &[u8]) -> String {
+    let mut s = match data.first() {
+        None => return "CTAP2 empty response".to_string(),
+        Some(&status) => match ctap2_status_name(status) {
+            Some(name) => format!("CTAP2 {name}"),
+            None => format!("CTAP2 status 0x{status:02X}"),
+        }
+    };
+    if data.len() > 1 {
+        match cbor_diagnostic(&data[1..]) {
+            Some(response) => { let _ = write!(s, " {response}"); },
+            None => s.push_str(", invalid CBOR response"),
+        }
+    }
+    s
+}
+
+fn u2f_request(apdu: &[u8]) -> String {
+    let (cla, ins, p1) = match apdu {
+        [cla, ins, p1, _, ..] => (*cla, *ins, *p1),
+        _ => return "U2F short request".to_string(),
+    };
+    let mut s = match u2f_instruction_name(ins) {
+        Some(name) => format!("U2F {name}"),
+        None => format!("U2F instruction 0x{ins:02X}, class 0x{cla:02X}"),
+    };
+    if ins == 0x02 {
+        s.push_str(match p1 {
+            0x03 => ", enforcing user presence",
+            0x07 => ", checking key handle only",
+            0x08 => ", not enforcing user presence",
+            _ => "",
+        });
+    }
+    // Extended length encoding: a zero byte, then a 16-bit length.
+    if let Some(&[0, hi, lo]) = apdu.get(4..7) {
+        let length = u16::from_be_bytes([hi, lo]);
+        if length > 0 {
+            let _ = write!(s, ", {length} bytes of data");
+        }
+    }
+    s
+}
+
+fn u2f_response(data: &[u8]) -> String {
+    let length = match data.len().checked_sub(2) {
+        Some(length) => length,
+        None => return "U2F short response".to_string(),
+    };
+    let sw = u16::from_be_bytes([data[length], data[length + 1]]);
+    let mut s = format!("U2F response {sw:04X} ({})", u2f_status_name(sw));
+    let body = &data[..length];
+    if body.is_ascii() && body.starts_with(b"U2F_") {
+        let _ = write!(s, ", version {}", String::from_utf8_lossy(body));
+    } else if length > 0 {
+        let _ = write!(s, " with {length} bytes of data");
+    }
+    s
+}
+
+/// Describe a complete or partial CTAPHID message.
+pub fn message_description(message: &Message, from_device: bool) -> String {
+    use Command::*;
+    let data = &message.data;
+    let mut s = match (Command::from(message.command), from_device) {
+        (Msg, false) => u2f_request(data),
+        (Msg, true) => u2f_response(data),
+        (Cbor, false) => cbor_request(data),
+        (Cbor, true) => cbor_response(data),
+        (Init, false) => format!("INIT with nonce {}", hex_bytes(data)),
+        (Init, true) if data.len() >= 13 => format!(
+            "INIT response, assigned channel 0x{:08X}, protocol version {}",
+            u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            data[12]),
+        (Keepalive, true) => match data.first() {
+            Some(1) => "KEEPALIVE, processing".to_string(),
+            Some(2) => "KEEPALIVE, user presence needed".to_string(),
+            _ => "KEEPALIVE".to_string(),
+        },
+        (Error, true) => match data.first() {
+            Some(&code) => format!("ERROR, {}", ctaphid_error_name(code)),
+            None => "ERROR".to_string(),
+        },
+        (command, _) => {
+            let mut s = command_string(message.command);
+            if command == Unknown && message.command >= 0x40 {
+                s = format!("vendor {s}");
+            }
+            if !data.is_empty() {
+                let _ = write!(s, " with {} bytes of data", data.len());
+            }
+            s
+        }
+    };
+    if !message.complete() {
+        let _ = write!(s, " (incomplete, {} of {} bytes)",
+                       data.len(), message.length);
+    }
+    s
+}
+
+/// Decoder for the HID interfaces of FIDO security keys.
+pub struct Decoder;
+
+impl ClassDecoder for Decoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        hid::is_hid(iface.descriptor) &&
+            iface.hid_usage_page == Some(USAGE_PAGE_FIDO)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, transfer.partial)
+    }
+
+    fn label(&self) -> Option<String> {
+        Some("FIDO security key".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(header: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut report = header.to_vec();
+        report.extend(data);
+        report.resize(REPORT_SIZE, 0);
+        report
+    }
+
+    #[test]
+    fn test_assembly() {
+        let mut assembler = Assembler::default();
+        let payload: Vec<u8> = (0..100).collect();
+        let init = report(&[0, 0, 0, 1, 0x90, 0, 100], &payload[..57]);
+        let cont = report(&[0, 0, 0, 1, 0], &payload[57..]);
+        assert!(assembler.add_report(&init).is_empty());
+        let messages = assembler.add_report(&cont);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].data, payload);
+        assert!(messages[0].complete());
+        assert_eq!(transfer_description(&init, false).unwrap(),
+                   "CTAPHID CBOR on channel 0x00000001, 100 bytes");
+    }
+
+    #[test]
+    fn test_cbor() {
+        // {1: ["U2F_V2", "FIDO_2_0"], 3: h'0102', 4: {"rk": true}}
+        let data = [0xA3, 0x01, 0x82, 0x66, b'U', b'2', b'F', b'_', b'V', b'2',
+                    0x68, b'F', b'I', b'D', b'O', b'_', b'2', b'_', b'0',
+                    0x03, 0x42, 0x01, 0x02,
+                    0x04, 0xA1, 0x62, b'r', b'k', 0xF5];
+        assert_eq!(cbor_diagnostic(&data).unwrap(), concat!(
+            "{1: [\"U2F_V2\", \"FIDO_2_0\"], 3: h'0102', ",
+            "4: {\"rk\": true}}"));
+        assert_eq!(cbor_diagnostic(&[0x38, 0x63]).unwrap(), "-100");
+        assert!(cbor_diagnostic(&[0x5F]).is_none());
+    }
+}
@@ -0,0 +1,302 @@
+//! Device firmware upgrade class.
+
+use std::fmt::Write;
+
+use num_enum::FromPrimitive;
+
+use crate::usb::{InterfaceDescriptor, SetupFields};
+use crate::util::fmt_size;
+use super::{ClassDecoder, InterfaceInfo};
+
+pub const CLASS: u8 = 0xFE;
+pub const SUBCLASS_DFU: u8 = 0x01;
+
+const STATUS_LENGTH: usize = 6;
+
+/// Whether an interface is a DFU interface, in runtime or DFU mode.
+pub fn is_dfu(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS &&
+        iface.interface_subclass == SUBCLASS_DFU
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DfuRequest {
+    Detach = 0,
+    Download = 1,
+    Upload = 2,
+    GetStatus = 3,
+    ClearStatus = 4,
+    GetState = 5,
+    Abort = 6,
+    #[default]
+    Unknown = 0xFF,
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DfuState {
+    AppIdle = 0,
+    AppDetach = 1,
+    Idle = 2,
+    DownloadSync = 3,
+    DownloadBusy = 4,
+    DownloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    UploadIdle = 9,
+    Error = 10,
+    #[default]
+    Unknown = 0xFF,
+}
+
+impl DfuState {
+    pub fn name(self) -> &'static str {
+        use DfuState::*;
+        match self {
+            AppIdle => "appIDLE",
+            AppDetach => "appDETACH",
+            Idle => "dfuIDLE",
+            DownloadSync => "dfuDNLOAD-SYNC",
+            DownloadBusy => "dfuDNBUSY",
+            DownloadIdle => "dfuDNLOAD-IDLE",
+            ManifestSync => "dfuMANIFEST-SYNC",
+            Manifest => "dfuMANIFEST",
+            ManifestWaitReset => "dfuMANIFEST-WAIT-RESET",
+            UploadIdle => "dfuUPLOAD-IDLE",
+            Error => "dfuERROR",
+            Unknown => "unknown state",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DfuStatus {
+    Ok = 0x00,
+    Target = 0x01,
+    File = 0x02,
+    Write = 0x03,
+    Erase = 0x04,
+    CheckErased = 0x05,
+    Program = 0x06,
+    Verify = 0x07,
+    Address = 0x08,
+    NotDone = 0x09,
+    Firmware = 0x0A,
+    Vendor = 0x0B,
+    UsbReset = 0x0C,
+    PowerOnReset = 0x0D,
+    Unknown = 0x0E,
+    StalledPacket = 0x0F,
+    #[default]
+    Reserved = 0xFF,
+}
+
+impl DfuStatus {
+    pub fn name(self) -> &'static str {
+        use DfuStatus::*;
+        match self {
+            Ok => "OK",
+            Target => "errTARGET",
+            File => "errFILE",
+            Write => "errWRITE",
+            Erase => "errERASE",
+            CheckErased => "errCHECK_ERASED",
+            Program => "errPROG",
+            Verify => "errVERIFY",
+            Address => "errADDRESS",
+            NotDone => "errNOTDONE",
+            Firmware => "errFIRMWARE",
+            Vendor => "errVENDOR",
+            UsbReset => "errUSBR",
+            PowerOnReset => "errPOR",
+            Unknown => "errUNKNOWN",
+            StalledPacket => "errSTALLEDPKT",
+            Reserved => "reserved status",
+        }
+    }
+}
+
+/// Response to a DFU_GETSTATUS request.
+#[derive(Copy, Clone)]
+pub struct StatusResponse {
+    pub status: DfuStatus,
+    pub poll_timeout: u32,
+    pub state: DfuState,
+}
+
+impl StatusResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != STATUS_LENGTH {
+            return None
+        }
+        Some(StatusResponse {
+            status: DfuStatus::from(bytes[0]),
+            poll_timeout: u32::from_le_bytes([bytes[1], bytes[2], bytes[3], 0]),
+            state: DfuState::from(bytes[4]),
+        })
+    }
+}
+
+/// Describe a class request to a DFU interface.
+pub fn request_description(fields: &SetupFields, data: &[u8])
+    -> Option<String>
+{
+    use DfuRequest::*;
+    Some(match DfuRequest::from(fields.request) {
+        Detach => format!("DFU detach, timeout {} ms", fields.value),
+        Download if fields.length == 0 => format!(
+            "DFU download complete after block {}", fields.value),
+        Download => format!("DFU download block {}", fields.value),
+        Upload => format!("DFU upload block {}", fields.value),
+        GetStatus => match StatusResponse::from_bytes(data) {
+            Some(response) => format!(
+                "DFU get status: {}, state {}, poll timeout {} ms",
+                response.status.name(),
+                response.state.name(),
+                response.poll_timeout),
+            None => "DFU get status".to_string(),
+        },
+        ClearStatus => "DFU clear status".to_string(),
+        GetState => match data.first() {
+            Some(&state) => format!(
+                "DFU get state: {}", DfuState::from(state).name()),
+            None => "DFU get state".to_string(),
+        },
+        Abort => "DFU abort".to_string(),
+        Unknown => return None,
+    })
+}
+
+/// Progress of DFU operations on a device over the course of a capture.
+#[derive(Clone, Default)]
+pub struct Progress {
+    pub download_bytes: u64,
+    pub download_blocks: u64,
+    pub upload_bytes: u64,
+    pub state: Option<DfuState>,
+    pub status: Option<DfuStatus>,
+}
+
+impl Progress {
+    /// Update progress with a completed DFU request.
+    ///
+    /// The state is taken from the device's responses where available,
+    /// and otherwise follows the transitions of the DFU state machine.
+    pub fn update(&mut self, fields: &SetupFields, data: &[u8]) {
+        use DfuRequest::*;
+        use DfuState::*;
+        match DfuRequest::from(fields.request) {
+            Detach => self.state = Some(AppDetach),
+            Download if fields.length == 0 =>
+                self.state = Some(ManifestSync),
+            Download => {
+                self.download_bytes += data.len() as u64;
+                self.download_blocks += 1;
+                self.state = Some(DownloadSync);
+            },
+            Upload => {
+                self.upload_bytes += data.len() as u64;
+                self.state = Some(
+                    if data.len() < fields.length as usize {
+                        Idle
+                    } else {
+                        UploadIdle
+                    }
+                );
+            },
+            GetStatus => if let Some(response) =
+                StatusResponse::from_bytes(data)
+            {
+                self.state = Some(response.state);
+                self.status = Some(response.status);
+            },
+            ClearStatus | Abort => {
+                self.state = Some(Idle);
+                self.status = Some(DfuStatus::Ok);
+            },
+            GetState => if let Some(&state) = data.first() {
+                self.state = Some(DfuState::from(state));
+            },
+            DfuRequest::Unknown => {},
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let mut s = String::from("DFU");
+        if self.download_blocks > 0 {
+            let _ = write!(s, ", downloaded {} in {} {}",
+                fmt_size(self.download_bytes),
+                self.download_blocks,
+                if self.download_blocks == 1 { "block" } else { "blocks" });
+        }
+        if self.upload_bytes > 0 {
+            let _ = write!(s, ", uploaded {}", fmt_size(self.upload_bytes));
+        }
+        if let Some(state) = self.state {
+            let _ = write!(s, ", state {}", state.name());
+        }
+        match self.status {
+            None | Some(DfuStatus::Ok) => {},
+            Some(status) => {
+                let _ = write!(s, ", status {}", status.name());
+            }
+        }
+        s
+    }
+}
+
+/// Decoder for firmware upgrade interfaces.
+pub struct Decoder;
+
+impl ClassDecoder for Decoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        is_dfu(iface.descriptor)
+    }
+
+    fn request_description(&self, fields: &SetupFields, data: &[u8])
+        -> Option<String>
+    {
+        request_description(fields, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(request: u8, value: u16, length: u16) -> SetupFields {
+        let [value_lo, value_hi] = value.to_le_bytes();
+        let [length_lo, length_hi] = length.to_le_bytes();
+        SetupFields::from_data_packet(&[0xC3, 0x21, request,
+            value_lo, value_hi, 0, 0, length_lo, length_hi, 0, 0])
+    }
+
+    #[test]
+    fn test_download() {
+        let mut progress = Progress::default();
+        for block in 0..3 {
+            progress.update(&setup(1, block, 1024), &[0; 1024]);
+            progress.update(&setup(3, 0, 6), &[0, 5, 0, 0, 5, 0]);
+        }
+        assert_eq!(progress.state, Some(DfuState::DownloadIdle));
+        progress.update(&setup(1, 3, 0), &[]);
+        assert_eq!(progress.state, Some(DfuState::ManifestSync));
+        progress.update(&setup(3, 0, 6), &[0, 0, 0, 0, 8, 0]);
+        assert_eq!(progress.summary(),
+            "DFU, downloaded 3 KiB in 3 blocks, state dfuMANIFEST-WAIT-RESET");
+        progress.update(&setup(3, 0, 6), &[3, 0, 0, 0, 10, 0]);
+        assert_eq!(progress.status, Some(DfuStatus::Write));
+    }
+
+    #[test]
+    fn test_requests() {
+        assert_eq!(request_description(&setup(1, 7, 512), &[0; 512]).unwrap(),
+                   "DFU download block 7");
+        assert_eq!(request_description(&setup(3, 0, 6),
+                                       &[0, 0x10, 0x27, 0, 5, 0]).unwrap(),
+            "DFU get status: OK, state dfuDNLOAD-IDLE, poll timeout 10000 ms");
+    }
+}
@@ -0,0 +1,348 @@
+//! Human interface device class, and parsing of HID report descriptors.
+
+use crate::usb::InterfaceDescriptor;
+
+pub const CLASS: u8 = 0x03;
+pub const SUBCLASS_BOOT: u8 = 0x01;
+pub const PROTOCOL_KEYBOARD: u8 = 0x01;
+pub const PROTOCOL_MOUSE: u8 = 0x02;
+
+/// Descriptor type of a HID report descriptor.
+pub const REPORT_DESCRIPTOR: u8 = 0x22;
+
+pub const USAGE_PAGE_GENERIC_DESKTOP: u16 = 0x01;
+pub const USAGE_PAGE_KEYBOARD: u16 = 0x07;
+pub const USAGE_PAGE_BUTTON: u16 = 0x09;
+
+pub const USAGE_X: u32 = 0x30;
+pub const USAGE_Y: u32 = 0x31;
+pub const USAGE_WHEEL: u32 = 0x38;
+
+const ITEM_USAGE_PAGE: u8 = 0x04;
+const ITEM_LOGICAL_MINIMUM: u8 = 0x14;
+const ITEM_REPORT_SIZE: u8 = 0x74;
+const ITEM_REPORT_ID: u8 = 0x84;
+const ITEM_REPORT_COUNT: u8 = 0x94;
+const ITEM_PUSH: u8 = 0xA4;
+const ITEM_POP: u8 = 0xB4;
+const ITEM_USAGE: u8 = 0x08;
+const ITEM_USAGE_MINIMUM: u8 = 0x18;
+const ITEM_USAGE_MAXIMUM: u8 = 0x28;
+const ITEM_INPUT: u8 = 0x80;
+const ITEM_OUTPUT: u8 = 0x90;
+const ITEM_COLLECTION: u8 = 0xA0;
+const ITEM_FEATURE: u8 = 0xB0;
+const ITEM_END_COLLECTION: u8 = 0xC0;
+const ITEM_LONG: u8 = 0xFE;
+
+const INPUT_CONSTANT: u32 = 0x01;
+const INPUT_VARIABLE: u32 = 0x02;
+
+/// Report descriptor of the boot keyboard, from appendix B of the HID
+/// specification.
+const BOOT_KEYBOARD: [u8; 63] = [
+    0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7,
+    0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01,
+    0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01, 0x05, 0x08, 0x19, 0x01,
+    0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06,
+    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65,
+    0x81, 0x00, 0xC0,
+];
+
+/// Report descriptor of the boot mouse, from appendix B of the HID
+/// specification.
+const BOOT_MOUSE: [u8; 50] = [
+    0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x09, 0x01, 0xA1, 0x00, 0x05, 0x09,
+    0x19, 0x01, 0x29, 0x03, 0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01,
+    0x81, 0x02, 0x95, 0x01, 0x75, 0x05, 0x81, 0x01, 0x05, 0x01, 0x09, 0x30,
+    0x09, 0x31, 0x15, 0x81, 0x25, 0x7F, 0x75, 0x08, 0x95, 0x02, 0x81, 0x06,
+    0xC0, 0xC0,
+];
+
+/// Whether an interface is a HID interface.
+pub fn is_hid(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS
+}
+
+/// A short item of a report descriptor.
+struct Item {
+    /// Tag and type of the item, with the size bits cleared.
+    tag: u8,
+    /// Size of the item data in bytes.
+    size: usize,
+    /// Item data, zero-extended.
+    data: u32,
+}
+
+impl Item {
+    /// Item data, sign-extended.
+    fn signed(&self) -> i32 {
+        match self.size {
+            1 => self.data as u8 as i8 as i32,
+            2 => self.data as u16 as i16 as i32,
+            _ => self.data as i32,
+        }
+    }
+}
+
+/// Iterate over the short items of a report descriptor, skipping long
+/// items, and stopping at the first incomplete item.
+fn items(descriptor: &[u8]) -> impl Iterator<Item=Item> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || loop {
+        let prefix = *descriptor.get(offset)?;
+        if prefix == ITEM_LONG {
+            offset += 3 + *descriptor.get(offset + 1)? as usize;
+            continue;
+        }
+        let size = [0, 1, 2, 4][(prefix & 0x03) as usize];
+        let bytes = descriptor.get(offset + 1 .. offset + 1 + size)?;
+        offset += 1 + size;
+        let mut data = 0u32;
+        for (i, &byte) in bytes.iter().enumerate() {
+            data |= (byte as u32) << (8 * i);
+        }
+        return Some(Item { tag: prefix & 0xFC, size, data })
+    })
+}
+
+/// Find the first usage page declared in a report descriptor.
+pub fn report_usage_page(descriptor: &[u8]) -> Option<u16> {
+    items(descriptor)
+        .find(|item| item.tag == ITEM_USAGE_PAGE)
+        .map(|item| item.data as u16)
+}
+
+/// A field of an input report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    /// Report ID, or zero if the device does not use report IDs.
+    pub report_id: u8,
+    pub usage_page: u16,
+    /// Usage of a variable field, or the first usage of an array field.
+    pub usage: u32,
+    /// Offset of the field in bits, after any report ID.
+    pub offset: usize,
+    /// Size of the field in bits.
+    pub size: usize,
+    pub logical_minimum: i32,
+    /// Whether the field is an index into a range of usages.
+    pub array: bool,
+}
+
+impl Field {
+    /// Extract the value of this field from a report.
+    pub fn value(&self, data: &[u8]) -> Option<i64> {
+        if self.size == 0 || self.size > 32 {
+            return None
+        }
+        let mut value = 0u64;
+        for i in 0..self.size {
+            let bit = self.offset + i;
+            let byte = *data.get(bit / 8)?;
+            value |= (((byte >> (bit % 8)) & 1) as u64) << i;
+        }
+        if self.logical_minimum < 0 && value >> (self.size - 1) != 0 {
+            Some(value as i64 - (1 << self.size))
+        } else {
+            Some(value as i64)
+        }
+    }
+
+    /// Usage selected by the value of an array field.
+    pub fn array_usage(&self, data: &[u8]) -> Option<u32> {
+        let index = self.value(data)? - self.logical_minimum as i64;
+        u32::try_from(self.usage as i64 + index).ok()
+    }
+}
+
+#[derive(Clone, Default)]
+struct Globals {
+    usage_page: u16,
+    logical_minimum: i32,
+    report_size: usize,
+    report_count: usize,
+    report_id: u8,
+}
+
+/// The input report fields declared by a report descriptor.
+#[derive(Debug, Default)]
+pub struct ReportDescriptor {
+    pub fields: Vec<Field>,
+    pub report_ids: bool,
+}
+
+impl ReportDescriptor {
+    /// Parse a report descriptor.
+    pub fn parse(descriptor: &[u8]) -> ReportDescriptor {
+        let mut result = ReportDescriptor::default();
+        let mut globals = Globals::default();
+        let mut stack = Vec::new();
+        let mut usages: Vec<u32> = Vec::new();
+        let mut usage_minimum = None;
+        let mut usage_maximum = None;
+        let mut offsets: Vec<(u8, usize)> = Vec::new();
+        for item in items(descriptor) {
+            match item.tag {
+                ITEM_USAGE_PAGE => globals.usage_page = item.data as u16,
+                ITEM_LOGICAL_MINIMUM => globals.logical_minimum = item.signed(),
+                ITEM_REPORT_SIZE => globals.report_size = item.data as usize,
+                ITEM_REPORT_COUNT => globals.report_count = item.data as usize,
+                ITEM_REPORT_ID => {
+                    globals.report_id = item.data as u8;
+                    result.report_ids = true;
+                },
+                ITEM_PUSH => stack.push(globals.clone()),
+                ITEM_POP => globals = stack.pop().unwrap_or_default(),
+                ITEM_USAGE => usages.push(extended_usage(&item, &globals)),
+                ITEM_USAGE_MINIMUM =>
+                    usage_minimum = Some(extended_usage(&item, &globals)),
+                ITEM_USAGE_MAXIMUM =>
+                    usage_maximum = Some(extended_usage(&item, &globals)),
+                ITEM_INPUT => {
+                    let report_id = globals.report_id;
+                    let offset = match offsets
+                        .iter_mut()
+                        .find(|(id, _)| *id == report_id)
+                    {
+                        Some((_, offset)) => offset,
+                        None => {
+                            offsets.push((report_id, 0));
+                            &mut offsets.last_mut().unwrap().1
+                        }
+                    };
+                    let size = globals.report_size;
+                    let count = globals.report_count;
+                    if item.data & INPUT_CONSTANT == 0 {
+                        let variable = item.data & INPUT_VARIABLE != 0;
+                        for i in 0..count {
+                            let usage = if !variable {
+                                usage_minimum
+                                    .or(usages.first().copied())
+                                    .unwrap_or(0)
+                            } else if let Some(&usage) = usages.get(i) {
+                                usage
+                            } else if let Some(min) = usage_minimum {
+                                let max = usage_maximum.unwrap_or(u32::MAX);
+                                (min + i as u32).min(max)
+                            } else {
+                                usages.last().copied().unwrap_or(0)
+                            };
+                            result.fields.push(Field {
+                                report_id,
+                                usage_page: (usage >> 16) as u16,
+                                usage: usage & 0xFFFF,
+                                offset: *offset + i * size,
+                                size,
+                                logical_minimum: globals.logical_minimum,
+                                array: !variable,
+                            });
+                        }
+                    }
+                    *offset += size * count;
+                },
+                _ => {}
+            }
+            if matches!(item.tag,
+                ITEM_INPUT | ITEM_OUTPUT | ITEM_FEATURE |
+                ITEM_COLLECTION | ITEM_END_COLLECTION)
+            {
+                usages.clear();
+                usage_minimum = None;
+                usage_maximum = None;
+            }
+        }
+        result
+    }
+
+    /// The descriptor assumed for a boot interface whose own report
+    /// descriptor has not been seen.
+    pub fn boot(iface: &InterfaceDescriptor) -> Option<ReportDescriptor> {
+        if iface.interface_class != CLASS ||
+            iface.interface_subclass != SUBCLASS_BOOT
+        {
+            return None
+        }
+        match iface.interface_protocol {
+            PROTOCOL_KEYBOARD => Some(Self::parse(&BOOT_KEYBOARD)),
+            PROTOCOL_MOUSE => Some(Self::parse(&BOOT_MOUSE)),
+            _ => None
+        }
+    }
+
+    /// Split a report into its ID and data, and find the fields present.
+    pub fn report<'r>(&self, report: &'r [u8])
+        -> Option<(&'r [u8], impl Iterator<Item=&Field>)>
+    {
+        let (id, data) = if self.report_ids {
+            (*report.first()?, &report[1..])
+        } else {
+            (0, report)
+        };
+        Some((data, self.fields.iter().filter(move |f| f.report_id == id)))
+    }
+
+    /// Whether any input report contains fields with a given usage.
+    pub fn has_usage(&self, usage_page: u16, usage: Option<u32>) -> bool {
+        self.fields.iter().any(|field|
+            field.usage_page == usage_page &&
+                usage.map_or(true, |usage| field.usage == usage))
+    }
+}
+
+/// Combine a usage with the current usage page, unless it is a 32-bit
+/// usage which specifies its own page.
+fn extended_usage(item: &Item, globals: &Globals) -> u32 {
+    if item.size == 4 {
+        item.data
+    } else {
+        (globals.usage_page as u32) << 16 | item.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_page() {
+        let fido = [0x06, 0xD0, 0xF1, 0x09, 0x01, 0xA1, 0x01];
+        assert_eq!(report_usage_page(&fido), Some(0xF1D0));
+        let keyboard = [0x05, 0x01, 0x09, 0x06, 0xA1, 0x01];
+        assert_eq!(report_usage_page(&keyboard), Some(0x0001));
+        assert_eq!(report_usage_page(&[0x09, 0x01]), None);
+    }
+
+    #[test]
+    fn test_boot_mouse() {
+        let descriptor = ReportDescriptor::parse(&BOOT_MOUSE);
+        assert!(!descriptor.report_ids);
+        assert_eq!(descriptor.fields.len(), 5);
+        let (data, fields) = descriptor.report(&[0x01, 0xFE, 0x05]).unwrap();
+        let values: Vec<_> = fields
+            .map(|field| (field.usage_page, field.usage,
+                          field.value(data).unwrap()))
+            .collect();
+        assert_eq!(values, vec![
+            (USAGE_PAGE_BUTTON, 1, 1),
+            (USAGE_PAGE_BUTTON, 2, 0),
+            (USAGE_PAGE_BUTTON, 3, 0),
+            (USAGE_PAGE_GENERIC_DESKTOP, USAGE_X, -2),
+            (USAGE_PAGE_GENERIC_DESKTOP, USAGE_Y, 5),
+        ]);
+    }
+
+    #[test]
+    fn test_boot_keyboard() {
+        let descriptor = ReportDescriptor::parse(&BOOT_KEYBOARD);
+        let report = [0x02, 0x00, 0x0B, 0x0C, 0, 0, 0, 0];
+        let (data, fields) = descriptor.report(&report).unwrap();
+        let keys: Vec<_> = fields
+            .filter(|field| field.array)
+            .filter_map(|field| field.array_usage(data))
+            .filter(|&usage| usage != 0)
+            .collect();
+        assert_eq!(keys, vec![0x0B, 0x0C]);
+        assert!(descriptor.has_usage(USAGE_PAGE_KEYBOARD, None));
+    }
+}
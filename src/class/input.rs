@@ -0,0 +1,483 @@
+//! Reconstruction of keyboard and mouse input from HID reports.
+
+use std::fmt::Write;
+
+use super::hid::{
+    ReportDescriptor,
+    USAGE_PAGE_BUTTON,
+    USAGE_PAGE_GENERIC_DESKTOP,
+    USAGE_PAGE_KEYBOARD,
+    USAGE_WHEEL,
+    USAGE_X,
+    USAGE_Y,
+};
+
+const USAGE_ERROR_ROLLOVER: u32 = 0x01;
+const USAGE_LEFT_CONTROL: u32 = 0xE0;
+const USAGE_RIGHT_GUI: u32 = 0xE7;
+
+const KEY_ENTER: u8 = 0x28;
+const KEY_BACKSPACE: u8 = 0x2A;
+const KEY_TAB: u8 = 0x2B;
+const KEY_CAPS_LOCK: u8 = 0x39;
+const KEY_KEYPAD_ENTER: u8 = 0x58;
+
+const MODIFIER_CONTROL: u8 = 0x11;
+const MODIFIER_SHIFT: u8 = 0x22;
+const MODIFIER_ALT: u8 = 0x44;
+const MODIFIER_GUI: u8 = 0x88;
+
+/// Pause after which mouse movement is reported as a new movement.
+const MOVEMENT_GAP_NS: u64 = 250_000_000;
+
+const DIGITS: &[u8; 10] = b"1234567890";
+const SHIFTED_DIGITS: &[u8; 10] = b"!@#$%^&*()";
+const SYMBOLS: &[u8; 12] = b"-=[]\\#;'`,./";
+const SHIFTED_SYMBOLS: &[u8; 12] = b"_+{}|~:\"~<>?";
+const KEYPAD: &[u8; 16] = b"/*-+\n1234567890.";
+
+/// Whether a report descriptor describes a keyboard.
+pub fn is_keyboard(descriptor: &ReportDescriptor) -> bool {
+    descriptor.has_usage(USAGE_PAGE_KEYBOARD, None)
+}
+
+/// Whether a report descriptor describes a mouse or other pointer.
+pub fn is_mouse(descriptor: &ReportDescriptor) -> bool {
+    descriptor.has_usage(USAGE_PAGE_GENERIC_DESKTOP, Some(USAGE_X)) &&
+        descriptor.has_usage(USAGE_PAGE_GENERIC_DESKTOP, Some(USAGE_Y))
+}
+
+/// Format a timestamp in nanoseconds as seconds.
+pub fn fmt_seconds(timestamp_ns: u64) -> String {
+    format!("{}.{:06} s",
+            timestamp_ns / 1_000_000_000,
+            timestamp_ns % 1_000_000_000 / 1000)
+}
+
+/// Character produced by a key, if it is a printable one.
+fn key_char(key: u8, shift: bool) -> Option<char> {
+    let byte = match key {
+        0x04..=0x1D => {
+            let letter = b'a' + key - 0x04;
+            if shift { letter.to_ascii_uppercase() } else { letter }
+        },
+        0x1E..=0x27 => {
+            let table = if shift { SHIFTED_DIGITS } else { DIGITS };
+            table[(key - 0x1E) as usize]
+        },
+        0x2C => b' ',
+        0x2D..=0x38 => {
+            let table = if shift { SHIFTED_SYMBOLS } else { SYMBOLS };
+            table[(key - 0x2D) as usize]
+        },
+        0x54..=0x63 if key != KEY_KEYPAD_ENTER =>
+            KEYPAD[(key - 0x54) as usize],
+        _ => return None
+    };
+    Some(byte as char)
+}
+
+/// Name of a key that does not produce a printable character.
+fn key_name(key: u8) -> String {
+    match key {
+        KEY_ENTER => "Enter",
+        0x29 => "Escape",
+        KEY_BACKSPACE => "Backspace",
+        KEY_TAB => "Tab",
+        0x2C => "Space",
+        KEY_CAPS_LOCK => "Caps Lock",
+        0x3A..=0x45 => return format!("F{}", key - 0x39),
+        0x46 => "Print Screen",
+        0x47 => "Scroll Lock",
+        0x48 => "Pause",
+        0x49 => "Insert",
+        0x4A => "Home",
+        0x4B => "Page Up",
+        0x4C => "Delete",
+        0x4D => "End",
+        0x4E => "Page Down",
+        0x4F => "Right",
+        0x50 => "Left",
+        0x51 => "Down",
+        0x52 => "Up",
+        0x53 => "Num Lock",
+        KEY_KEYPAD_ENTER => "Keypad Enter",
+        0x65 => "Menu",
+        _ => match key_char(key, true) {
+            Some(c) => return c.to_string(),
+            None => return format!("Key 0x{key:02X}"),
+        }
+    }.to_string()
+}
+
+/// A key press found in keyboard reports.
+pub struct Keystroke {
+    pub timestamp_ns: u64,
+    pub key: u8,
+    pub modifiers: u8,
+    pub caps_lock: bool,
+}
+
+impl Keystroke {
+    fn shift(&self) -> bool {
+        self.modifiers & MODIFIER_SHIFT != 0
+    }
+
+    /// Whether a command modifier is held, so that no text is typed.
+    fn command(&self) -> bool {
+        self.modifiers & (MODIFIER_CONTROL | MODIFIER_ALT | MODIFIER_GUI) != 0
+    }
+
+    /// Character typed by this keystroke, if any.
+    pub fn char(&self) -> Option<char> {
+        if self.command() {
+            return None
+        }
+        let c = key_char(self.key, self.shift())?;
+        if self.caps_lock && c.is_ascii_alphabetic() {
+            if c.is_ascii_lowercase() {
+                Some(c.to_ascii_uppercase())
+            } else {
+                Some(c.to_ascii_lowercase())
+            }
+        } else {
+            Some(c)
+        }
+    }
+
+    /// Describe the keystroke, e.g. "'a'" or "Ctrl+C".
+    pub fn description(&self) -> String {
+        if let Some(c) = self.char() {
+            return match c {
+                ' ' => "Space".to_string(),
+                '\n' => "Keypad Enter".to_string(),
+                c => format!("'{c}'"),
+            }
+        }
+        let mut s = String::new();
+        for (mask, name) in [(MODIFIER_CONTROL, "Ctrl"),
+                             (MODIFIER_ALT, "Alt"),
+                             (MODIFIER_GUI, "GUI"),
+                             (MODIFIER_SHIFT, "Shift")]
+        {
+            if self.modifiers & mask != 0 {
+                write!(s, "{name}+").unwrap();
+            }
+        }
+        s.push_str(&key_name(self.key));
+        s
+    }
+}
+
+/// Reconstructs keystrokes and typed text from keyboard reports.
+#[derive(Default)]
+pub struct Keyboard {
+    pressed: Vec<u8>,
+    caps_lock: bool,
+    keystrokes: Vec<Keystroke>,
+    text: String,
+}
+
+impl Keyboard {
+    /// Process a report, recording any keys newly pressed.
+    pub fn add_report(&mut self,
+                      descriptor: &ReportDescriptor,
+                      timestamp_ns: u64,
+                      report: &[u8])
+    {
+        let (data, fields) = match descriptor.report(report) {
+            Some(report) => report,
+            None => return
+        };
+        let mut modifiers = 0;
+        let mut keys = Vec::new();
+        let mut keyboard_fields = false;
+        for field in fields.filter(|f| f.usage_page == USAGE_PAGE_KEYBOARD) {
+            keyboard_fields = true;
+            let usage = if field.array {
+                field.array_usage(data)
+            } else if field.value(data).unwrap_or(0) != 0 {
+                Some(field.usage)
+            } else {
+                None
+            };
+            match usage {
+                // A phantom state, in which the pressed keys are unknown.
+                Some(USAGE_ERROR_ROLLOVER) => return,
+                Some(usage @ USAGE_LEFT_CONTROL..=USAGE_RIGHT_GUI) =>
+                    modifiers |= 1 << (usage - USAGE_LEFT_CONTROL),
+                Some(usage @ 0x04..=0xFF) => keys.push(usage as u8),
+                _ => {}
+            }
+        }
+        if !keyboard_fields {
+            return
+        }
+        for &key in &keys {
+            if self.pressed.contains(&key) {
+                continue;
+            }
+            let keystroke = Keystroke {
+                timestamp_ns,
+                key,
+                modifiers,
+                caps_lock: self.caps_lock,
+            };
+            match (keystroke.char(), key) {
+                (Some(c), _) => self.text.push(c),
+                (None, KEY_ENTER | KEY_KEYPAD_ENTER)
+                    if !keystroke.command() => self.text.push('\n'),
+                (None, KEY_TAB) if !keystroke.command() =>
+                    self.text.push('\t'),
+                (None, KEY_BACKSPACE) if !keystroke.command() => {
+                    self.text.pop();
+                },
+                (None, KEY_CAPS_LOCK) => self.caps_lock = !self.caps_lock,
+                (None, _) => write!(self.text, "[{}]",
+                                    keystroke.description()).unwrap(),
+            }
+            self.keystrokes.push(keystroke);
+        }
+        self.pressed = keys;
+    }
+
+    pub fn keystrokes(&self) -> &[Keystroke] {
+        &self.keystrokes
+    }
+
+    /// The text typed, with other keystrokes shown in brackets.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Values decoded from a mouse report.
+#[derive(Default)]
+struct MouseReport {
+    buttons: u32,
+    x: i64,
+    y: i64,
+    wheel: i64,
+}
+
+/// Something the mouse did.
+pub struct MouseEvent {
+    pub timestamp_ns: u64,
+    pub description: String,
+}
+
+struct Movement {
+    start_ns: u64,
+    end_ns: u64,
+    reports: usize,
+    x: i64,
+    y: i64,
+    wheel: i64,
+}
+
+impl Movement {
+    fn event(&self) -> MouseEvent {
+        let mut s = String::new();
+        if self.x != 0 || self.y != 0 {
+            write!(s, "Moved {:+}, {:+}", self.x, self.y).unwrap();
+        }
+        if self.wheel != 0 {
+            if !s.is_empty() {
+                s.push_str(" and s");
+            } else {
+                s.push('S');
+            }
+            write!(s, "crolled {:+}", self.wheel).unwrap();
+        }
+        let duration_ms = (self.end_ns - self.start_ns) / 1_000_000;
+        write!(s, " in {} {} over {duration_ms} ms",
+               self.reports,
+               if self.reports == 1 { "report" } else { "reports" }).unwrap();
+        MouseEvent { timestamp_ns: self.start_ns, description: s }
+    }
+}
+
+fn button_name(button: u32) -> String {
+    match button {
+        1 => "Left button".to_string(),
+        2 => "Right button".to_string(),
+        3 => "Middle button".to_string(),
+        n => format!("Button {n}"),
+    }
+}
+
+/// Summarises mouse reports as movements and button presses.
+#[derive(Default)]
+pub struct Mouse {
+    buttons: u32,
+    movement: Option<Movement>,
+    events: Vec<MouseEvent>,
+    total_x: i64,
+    total_y: i64,
+}
+
+impl Mouse {
+    /// Process a report, recording any movement or button changes.
+    pub fn add_report(&mut self,
+                      descriptor: &ReportDescriptor,
+                      timestamp_ns: u64,
+                      report: &[u8])
+    {
+        let (data, fields) = match descriptor.report(report) {
+            Some(report) => report,
+            None => return
+        };
+        let mut values = MouseReport::default();
+        let mut pointer_fields = false;
+        for field in fields {
+            let value = match field.value(data) {
+                Some(value) => value,
+                None => continue
+            };
+            match (field.usage_page, field.usage) {
+                (USAGE_PAGE_BUTTON, button @ 1..=32) if value != 0 =>
+                    values.buttons |= 1 << (button - 1),
+                (USAGE_PAGE_GENERIC_DESKTOP, USAGE_X) => {
+                    values.x = value;
+                    pointer_fields = true;
+                },
+                (USAGE_PAGE_GENERIC_DESKTOP, USAGE_Y) => values.y = value,
+                (USAGE_PAGE_GENERIC_DESKTOP, USAGE_WHEEL) =>
+                    values.wheel = value,
+                _ => {}
+            }
+        }
+        if !pointer_fields {
+            return
+        }
+        if values.buttons != self.buttons {
+            self.end_movement();
+            let changed = values.buttons ^ self.buttons;
+            for bit in 0..32 {
+                if changed & (1 << bit) != 0 {
+                    let pressed = values.buttons & (1 << bit) != 0;
+                    self.events.push(MouseEvent {
+                        timestamp_ns,
+                        description: format!("{} {}",
+                            button_name(bit + 1),
+                            if pressed { "pressed" } else { "released" }),
+                    });
+                }
+            }
+            self.buttons = values.buttons;
+        }
+        if values.x == 0 && values.y == 0 && values.wheel == 0 {
+            return
+        }
+        if self.movement.as_ref().is_some_and(|movement|
+            timestamp_ns - movement.end_ns > MOVEMENT_GAP_NS)
+        {
+            self.end_movement();
+        }
+        let movement = self.movement.get_or_insert(Movement {
+            start_ns: timestamp_ns,
+            end_ns: timestamp_ns,
+            reports: 0,
+            x: 0,
+            y: 0,
+            wheel: 0,
+        });
+        movement.end_ns = timestamp_ns;
+        movement.reports += 1;
+        movement.x += values.x;
+        movement.y += values.y;
+        movement.wheel += values.wheel;
+        self.total_x += values.x;
+        self.total_y += values.y;
+    }
+
+    fn end_movement(&mut self) {
+        if let Some(movement) = self.movement.take() {
+            self.events.push(movement.event());
+        }
+    }
+
+    /// Finish processing, returning the events found and total movement.
+    pub fn finish(mut self) -> (Vec<MouseEvent>, (i64, i64)) {
+        self.end_movement();
+        (self.events, (self.total_x, self.total_y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Boot keyboard and mouse descriptors, as would be found on real
+    // devices, the mouse with a report ID and a wheel.
+    const KEYBOARD: &[u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x05, 0x07, 0x19, 0xE0, 0x29,
+        0xE7, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02,
+        0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x06, 0x75, 0x08, 0x15,
+        0x00, 0x25, 0x65, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xC0,
+    ];
+    const MOUSE: &[u8] = &[
+        0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x85, 0x02, 0x09, 0x01, 0xA1,
+        0x00, 0x05, 0x09, 0x19, 0x01, 0x29, 0x03, 0x15, 0x00, 0x25, 0x01,
+        0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05, 0x81,
+        0x01, 0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x09, 0x38, 0x15, 0x81,
+        0x25, 0x7F, 0x75, 0x08, 0x95, 0x03, 0x81, 0x06, 0xC0, 0xC0,
+    ];
+
+    #[test]
+    fn test_keyboard() {
+        let descriptor = ReportDescriptor::parse(KEYBOARD);
+        assert!(is_keyboard(&descriptor));
+        let mut keyboard = Keyboard::default();
+        let reports: [[u8; 8]; 9] = [
+            [0x02, 0, 0x0B, 0, 0, 0, 0, 0],    // Shift+H
+            [0, 0, 0x0C, 0, 0, 0, 0, 0],       // i
+            [0, 0, 0x0C, 0x1E, 0, 0, 0, 0],    // 1, with i held
+            [0, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 0x2A, 0, 0, 0, 0, 0],       // Backspace
+            [0x02, 0, 0x1E, 0, 0, 0, 0, 0],    // !
+            [0, 0, 0x28, 0, 0, 0, 0, 0],       // Enter
+            [0x01, 0, 0x06, 0, 0, 0, 0, 0],    // Ctrl+C
+            [0, 0, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01],
+        ];
+        for (i, report) in reports.iter().enumerate() {
+            keyboard.add_report(&descriptor, i as u64 * 1000, report);
+        }
+        assert_eq!(keyboard.text(), "Hi!\n[Ctrl+C]");
+        let descriptions: Vec<_> = keyboard
+            .keystrokes()
+            .iter()
+            .map(Keystroke::description)
+            .collect();
+        assert_eq!(descriptions, vec![
+            "'H'", "'i'", "'1'", "Backspace", "'!'", "Enter", "Ctrl+C"]);
+    }
+
+    #[test]
+    fn test_mouse() {
+        let descriptor = ReportDescriptor::parse(MOUSE);
+        assert!(is_mouse(&descriptor));
+        let mut mouse = Mouse::default();
+        let reports: [(u64, [u8; 5]); 5] = [
+            (0, [0x02, 0, 5, 0xFE, 0]),
+            (10_000_000, [0x02, 0, 5, 0xFE, 0]),
+            (20_000_000, [0x02, 1, 0, 0, 0]),
+            (30_000_000, [0x02, 0, 0, 0, 0]),
+            (900_000_000, [0x02, 0, 0, 0, 0xFF]),
+        ];
+        for (time, report) in reports {
+            mouse.add_report(&descriptor, time, &report);
+        }
+        let (events, total) = mouse.finish();
+        let descriptions: Vec<_> = events
+            .iter()
+            .map(|event| event.description.as_str())
+            .collect();
+        assert_eq!(descriptions, vec![
+            "Moved +10, -4 in 2 reports over 10 ms",
+            "Left button pressed",
+            "Left button released",
+            "Scrolled -1 in 1 report over 0 ms"]);
+        assert_eq!(total, (10, -4));
+    }
+}
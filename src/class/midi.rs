@@ -0,0 +1,225 @@
+//! USB MIDI streaming, decoding of USB-MIDI event packets.
+
+use std::fmt::Write;
+
+use crate::usb::InterfaceDescriptor;
+use super::{ClassDecoder, InterfaceInfo, Transfer};
+
+pub const CLASS: u8 = 0x01;
+pub const SUBCLASS_MIDI_STREAMING: u8 = 0x03;
+
+const EVENT_LENGTH: usize = 4;
+const SUMMARY_MESSAGES: usize = 3;
+const SYSEX_BYTES_SHOWN: usize = 16;
+
+const NOTE_NAMES: [&str; 12] =
+    ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Whether an interface is a MIDI streaming interface.
+pub fn is_midi(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS &&
+        iface.interface_subclass == SUBCLASS_MIDI_STREAMING
+}
+
+fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[(note % 12) as usize], (note / 12) as i8 - 1)
+}
+
+fn controller_name(controller: u8) -> Option<&'static str> {
+    Some(match controller {
+        0 => "bank select",
+        1 => "modulation",
+        7 => "volume",
+        10 => "pan",
+        11 => "expression",
+        64 => "sustain",
+        120 => "all sound off",
+        121 => "reset all controllers",
+        123 => "all notes off",
+        _ => return None
+    })
+}
+
+/// Describe a complete MIDI message.
+pub fn message_description(message: &[u8]) -> Option<String> {
+    let status = *message.first()?;
+    let data = |i: usize| message.get(i).copied().unwrap_or(0);
+    let channel = (status & 0x0F) + 1;
+    Some(match status & 0xF0 {
+        0x80 => format!("Note off {}, velocity {}, channel {channel}",
+                        note_name(data(1)), data(2)),
+        0x90 if data(2) == 0 => format!("Note off {}, channel {channel}",
+                                        note_name(data(1))),
+        0x90 => format!("Note on {}, velocity {}, channel {channel}",
+                        note_name(data(1)), data(2)),
+        0xA0 => format!("Key pressure {} {}, channel {channel}",
+                        note_name(data(1)), data(2)),
+        0xB0 => match controller_name(data(1)) {
+            Some(name) => format!(
+                "Control change {} ({name}) to {}, channel {channel}",
+                data(1), data(2)),
+            None => format!("Control change {} to {}, channel {channel}",
+                            data(1), data(2)),
+        },
+        0xC0 => format!("Program change {}, channel {channel}", data(1)),
+        0xD0 => format!("Channel pressure {}, channel {channel}", data(1)),
+        0xE0 => {
+            let value = ((data(2) as i16) << 7 | data(1) as i16) - 8192;
+            format!("Pitch bend {value:+}, channel {channel}")
+        },
+        _ => match status {
+            0xF0 => {
+                let mut s = format!("SysEx of {} bytes:", message.len());
+                for byte in message.iter().take(SYSEX_BYTES_SHOWN) {
+                    write!(s, " {byte:02X}").ok()?;
+                }
+                if message.len() > SYSEX_BYTES_SHOWN {
+                    s.push_str(" ...");
+                }
+                s
+            },
+            0xF1 => format!("MTC quarter frame 0x{:02X}", data(1)),
+            0xF2 => format!("Song position {}",
+                            (data(2) as u16) << 7 | data(1) as u16),
+            0xF3 => format!("Song select {}", data(1)),
+            0xF6 => "Tune request".to_string(),
+            0xF8 => "Timing clock".to_string(),
+            0xFA => "Start".to_string(),
+            0xFB => "Continue".to_string(),
+            0xFC => "Stop".to_string(),
+            0xFE => "Active sensing".to_string(),
+            0xFF => "System reset".to_string(),
+            _ => format!("Unknown MIDI status 0x{status:02X}"),
+        }
+    })
+}
+
+/// Parses USB-MIDI event packets into MIDI messages.
+///
+/// System exclusive messages are split across several event packets, and
+/// are reassembled before being returned.
+#[derive(Default)]
+pub struct Parser {
+    sysex: Vec<u8>,
+}
+
+impl Parser {
+    /// Parse an event packet, returning its cable number and any message
+    /// it completes.
+    pub fn add_event(&mut self, event: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let cable = event[0] >> 4;
+        let code_index = event[0] & 0x0F;
+        let bytes = &event[1..EVENT_LENGTH];
+        let length = match code_index {
+            // SysEx starts or continues.
+            0x4 => {
+                self.sysex.extend(bytes);
+                return None
+            },
+            // Single byte, or SysEx ends with the following bytes.
+            0x5 | 0xF => 1,
+            0x2 | 0x6 | 0xC | 0xD => 2,
+            0x3 | 0x7 | 0x8 | 0x9 | 0xA | 0xB | 0xE => 3,
+            // Reserved codes.
+            _ => return None
+        };
+        let bytes = &bytes[..length];
+        if !self.sysex.is_empty() && matches!(code_index, 0x5..=0x7) {
+            self.sysex.extend(bytes);
+            Some((cable, std::mem::take(&mut self.sysex)))
+        } else {
+            Some((cable, bytes.to_vec()))
+        }
+    }
+
+    /// Whether a SysEx message is incomplete.
+    pub fn in_sysex(&self) -> bool {
+        !self.sysex.is_empty()
+    }
+}
+
+/// Describe a transfer of USB-MIDI event packets.
+pub fn transfer_description(payload: &[u8], detail: bool) -> Option<String> {
+    if payload.is_empty() || payload.len() % EVENT_LENGTH != 0 {
+        return None
+    }
+    let mut parser = Parser::default();
+    let mut messages = Vec::new();
+    for event in payload.chunks_exact(EVENT_LENGTH) {
+        if let Some((cable, message)) = parser.add_event(event) {
+            let description = message_description(&message)?;
+            messages.push(match cable {
+                0 => description,
+                _ => format!("{description}, cable {cable}"),
+            });
+        }
+    }
+    if parser.in_sysex() {
+        messages.push("Incomplete SysEx".to_string());
+    }
+    let count = messages.len();
+    if count == 0 {
+        return None
+    }
+    let mut s = String::from("MIDI: ");
+    if detail {
+        let noun = if count == 1 { "message" } else { "messages" };
+        write!(s, "{count} {noun}").ok()?;
+        for message in messages {
+            write!(s, "\n{message}").ok()?;
+        }
+    } else if count > SUMMARY_MESSAGES + 1 {
+        s.push_str(&messages[..SUMMARY_MESSAGES].join("; "));
+        write!(s, "; and {} more", count - SUMMARY_MESSAGES).ok()?;
+    } else {
+        s.push_str(&messages.join("; "));
+    }
+    Some(s)
+}
+
+/// Decoder for MIDI streaming interfaces.
+pub struct Decoder;
+
+impl ClassDecoder for Decoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        is_midi(iface.descriptor)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, false)
+    }
+
+    fn transfer_detail(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let notes = [0x09, 0x90, 60, 100, 0x08, 0x80, 60, 64];
+        assert_eq!(transfer_description(&notes, false).unwrap(), concat!(
+            "MIDI: Note on C4, velocity 100, channel 1; ",
+            "Note off C4, velocity 64, channel 1"));
+        assert_eq!(message_description(&[0xB2, 7, 90]).unwrap(),
+                   "Control change 7 (volume) to 90, channel 3");
+        assert_eq!(message_description(&[0xE0, 0, 0x40]).unwrap(),
+                   "Pitch bend +0, channel 1");
+    }
+
+    #[test]
+    fn test_sysex() {
+        let mut parser = Parser::default();
+        assert!(parser.add_event(&[0x14, 0xF0, 0x7E, 0x7F]).is_none());
+        assert!(parser.add_event(&[0x14, 0x06, 0x01, 0x02]).is_none());
+        let (cable, message) =
+            parser.add_event(&[0x16, 0x03, 0xF7, 0]).unwrap();
+        assert_eq!(cable, 1);
+        assert_eq!(message,
+                   vec![0xF0, 0x7E, 0x7F, 0x06, 0x01, 0x02, 0x03, 0xF7]);
+        assert!(!parser.in_sysex());
+    }
+}
@@ -0,0 +1,192 @@
+//! Decoding of class-specific traffic.
+//!
+//! Each supported class provides a [`ClassDecoder`], and the decoders are
+//! listed in [`DECODERS`]. The first decoder that matches an interface is
+//! used for all class-specific traffic to and from that interface, with
+//! any user-supplied dissectors from [`script`] taking precedence.
+
+pub mod bt;
+pub mod ccid;
+pub mod cdc;
+pub mod ctap;
+pub mod dfu;
+pub mod hid;
+pub mod input;
+pub mod midi;
+pub mod msc;
+pub mod script;
+pub mod tmc;
+pub mod uac;
+pub mod uvc;
+
+use crate::usb::{
+    DeviceDescriptor,
+    EndpointAddr,
+    EndpointType,
+    InterfaceDescriptor,
+    SetupFields,
+};
+
+/// What is known about an interface, for matching it to a decoder.
+pub struct InterfaceInfo<'i> {
+    /// Descriptor of the interface.
+    pub descriptor: &'i InterfaceDescriptor,
+    /// Descriptor of the device, if seen.
+    pub device: Option<&'i DeviceDescriptor>,
+    /// Usage page from the report descriptor of a HID interface, if seen.
+    pub hid_usage_page: Option<u16>,
+}
+
+/// A transfer on an endpoint, to be described by a decoder.
+pub struct Transfer<'t> {
+    /// Type of the endpoint.
+    pub ep_type: EndpointType,
+    /// Address of the endpoint.
+    pub endpoint: EndpointAddr,
+    /// Payload of the transfer.
+    pub payload: &'t [u8],
+    /// Whether the payload has been truncated for display.
+    pub partial: bool,
+}
+
+/// Decoder for the class-specific traffic of an interface.
+pub trait ClassDecoder: Sync {
+    /// Whether this decoder handles traffic for an interface.
+    fn matches(&self, iface: &InterfaceInfo) -> bool;
+
+    /// Describe a class-specific control request.
+    fn request_description(&self, _fields: &SetupFields, _data: &[u8])
+        -> Option<String>
+    {
+        None
+    }
+
+    /// Summarise a transfer, for display in the traffic tree.
+    fn transfer_summary(&self, _transfer: &Transfer) -> Option<String> {
+        None
+    }
+
+    /// Describe a transfer in full, for display in the detail pane.
+    fn transfer_detail(&self, transfer: &Transfer) -> Option<String> {
+        self.transfer_summary(transfer)
+    }
+
+    /// Label for an interface, where more specific than its class code.
+    fn label(&self) -> Option<String> {
+        None
+    }
+}
+
+/// All available class decoders, in order of precedence.
+pub static DECODERS: &[&dyn ClassDecoder] = &[
+    &msc::Decoder,
+    &cdc::AcmDecoder,
+    &cdc::DataDecoder,
+    &uvc::Decoder,
+    &ccid::Decoder,
+    &tmc::Decoder,
+    &bt::Decoder,
+    &midi::Decoder,
+    &dfu::Decoder,
+    &ctap::Decoder,
+];
+
+/// Find the decoder for an interface, if its class is supported.
+pub fn find_decoder(iface: &InterfaceInfo)
+    -> Option<&'static dyn ClassDecoder>
+{
+    let user = script::dissectors()
+        .iter()
+        .map(|dissector| dissector as &dyn ClassDecoder);
+    user.chain(DECODERS.iter().copied())
+        .find(|decoder| decoder.matches(iface))
+}
+
+/// Label for the function of an interface, based on its class codes.
+fn class_label(iface: &InterfaceDescriptor) -> Option<&'static str> {
+    Some(match (iface.interface_class,
+                iface.interface_subclass,
+                iface.interface_protocol)
+    {
+        (0x01, 0x01, _) => "Audio control",
+        (0x01, 0x02, _) => "Audio streaming",
+        (0x01, 0x03, _) => "MIDI streaming",
+        (0x01, ..) => "Audio",
+        (0x02, 0x02, _) => "CDC-ACM serial",
+        (0x02, 0x06, _) => "CDC Ethernet",
+        (0x02, 0x0D, _) => "CDC network",
+        (0x02, ..) => "Communications",
+        (0x03, 0x01, 0x01) => "HID keyboard",
+        (0x03, 0x01, 0x02) => "HID mouse",
+        (0x03, ..) => "HID",
+        (0x05, ..) => "Physical",
+        (0x06, ..) => "Still image",
+        (0x07, ..) => "Printer",
+        (0x08, ..) => "Mass storage",
+        (0x09, ..) => "Hub",
+        (0x0A, ..) => "CDC data",
+        (0x0B, ..) => "Smart card reader",
+        (0x0D, ..) => "Content security",
+        (0x0E, 0x01, _) => "Video control",
+        (0x0E, 0x02, _) => "Video streaming",
+        (0x0E, ..) => "Video",
+        (0x0F, ..) => "Personal healthcare",
+        (0x10, ..) => "Audio/video",
+        (0x11, ..) => "Billboard",
+        (0x12, ..) => "Type-C bridge",
+        (0xDC, ..) => "Diagnostic",
+        (0xE0, 0x01, 0x01) => "Bluetooth controller",
+        (0xE0, ..) => "Wireless controller",
+        (0xEF, ..) => "Miscellaneous",
+        (0xFE, 0x01, _) => "Firmware upgrade",
+        (0xFE, 0x02, _) => "IrDA bridge",
+        (0xFE, 0x03, _) => "Test and measurement",
+        (0xFE, ..) => "Application specific",
+        (0xFF, ..) => "Vendor specific",
+        _ => return None
+    })
+}
+
+/// Label for the function of an interface, e.g. "HID keyboard".
+///
+/// A label supplied by the decoder for the interface is preferred to one
+/// derived from its class codes.
+pub fn interface_label(iface: &InterfaceInfo) -> Option<String> {
+    find_decoder(iface)
+        .and_then(|decoder| decoder.label())
+        .or_else(|| class_label(iface.descriptor).map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_decoder() {
+        let descriptor = InterfaceDescriptor {
+            interface_class: hid::CLASS,
+            ..Default::default()
+        };
+        let mut iface = InterfaceInfo {
+            descriptor: &descriptor,
+            device: None,
+            hid_usage_page: None,
+        };
+        assert!(find_decoder(&iface).is_none());
+        iface.hid_usage_page = Some(ctap::USAGE_PAGE_FIDO);
+        let decoder = find_decoder(&iface).unwrap();
+        let mut payload = vec![0xFF, 0xFF, 0xFF, 0xFF, 0x86, 0x00, 0x08];
+        payload.resize(ctap::REPORT_SIZE, 0);
+        let transfer = Transfer {
+            ep_type: EndpointType::Interrupt,
+            endpoint: EndpointAddr(0x81),
+            payload: &payload,
+            partial: false,
+        };
+        assert_eq!(decoder.transfer_summary(&transfer).unwrap(),
+                   "CTAPHID INIT on channel 0xFFFFFFFF, 8 bytes");
+        assert_eq!(interface_label(&iface).unwrap(), "FIDO security key");
+        iface.hid_usage_page = None;
+        assert_eq!(interface_label(&iface).unwrap(), "HID");
+    }
+}
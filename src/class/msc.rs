@@ -0,0 +1,267 @@
+//! Mass storage class, Bulk-Only Transport with SCSI commands.
+
+use std::fmt::Write;
+
+use num_enum::FromPrimitive;
+
+use crate::usb::{Direction, InterfaceDescriptor};
+use super::{ClassDecoder, InterfaceInfo, Transfer};
+
+pub const CLASS: u8 = 0x08;
+pub const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const CBW_SIGNATURE: &[u8] = b"USBC";
+const CSW_SIGNATURE: &[u8] = b"USBS";
+const CBW_LENGTH: usize = 31;
+const CSW_LENGTH: usize = 13;
+
+/// Whether an interface uses the Bulk-Only Transport.
+pub fn is_bulk_only(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS &&
+        iface.interface_protocol == PROTOCOL_BULK_ONLY
+}
+
+/// Command Block Wrapper, sent by the host to start a command.
+pub struct CommandBlockWrapper<'b> {
+    pub tag: u32,
+    pub data_length: u32,
+    pub direction: Direction,
+    pub lun: u8,
+    pub command: &'b [u8],
+}
+
+impl<'b> CommandBlockWrapper<'b> {
+    pub fn from_bytes(bytes: &'b [u8]) -> Option<Self> {
+        if bytes.len() != CBW_LENGTH || &bytes[0..4] != CBW_SIGNATURE {
+            return None
+        }
+        let command_length = (bytes[14] & 0x1F) as usize;
+        if !(1..=16).contains(&command_length) {
+            return None
+        }
+        Some(CommandBlockWrapper {
+            tag: u32_le(&bytes[4..8]),
+            data_length: u32_le(&bytes[8..12]),
+            direction: Direction::from(bytes[12] >> 7),
+            lun: bytes[13] & 0x0F,
+            command: &bytes[15 .. 15 + command_length],
+        })
+    }
+}
+
+/// Command Status Wrapper, sent by the device to complete a command.
+pub struct CommandStatusWrapper {
+    pub tag: u32,
+    pub residue: u32,
+    pub status: CommandStatus,
+}
+
+impl CommandStatusWrapper {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != CSW_LENGTH || &bytes[0..4] != CSW_SIGNATURE {
+            return None
+        }
+        Some(CommandStatusWrapper {
+            tag: u32_le(&bytes[4..8]),
+            residue: u32_le(&bytes[8..12]),
+            status: CommandStatus::from(bytes[12]),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CommandStatus {
+    Passed = 0,
+    Failed = 1,
+    PhaseError = 2,
+    #[default]
+    Reserved = 3,
+}
+
+impl CommandStatus {
+    pub fn description(self) -> &'static str {
+        use CommandStatus::*;
+        match self {
+            Passed => "passed",
+            Failed => "failed",
+            PhaseError => "phase error",
+            Reserved => "reserved status",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScsiOpcode {
+    TestUnitReady = 0x00,
+    RequestSense = 0x03,
+    Inquiry = 0x12,
+    ModeSense6 = 0x1A,
+    StartStopUnit = 0x1B,
+    PreventAllowMediumRemoval = 0x1E,
+    ReadFormatCapacities = 0x23,
+    ReadCapacity10 = 0x25,
+    Read10 = 0x28,
+    Write10 = 0x2A,
+    Verify10 = 0x2F,
+    SynchronizeCache10 = 0x35,
+    ModeSense10 = 0x5A,
+    ServiceActionIn16 = 0x9E,
+    #[default]
+    Unknown = 0xFF,
+}
+
+impl ScsiOpcode {
+    pub fn name(self) -> &'static str {
+        use ScsiOpcode::*;
+        match self {
+            TestUnitReady => "TEST UNIT READY",
+            RequestSense => "REQUEST SENSE",
+            Inquiry => "INQUIRY",
+            ModeSense6 => "MODE SENSE(6)",
+            StartStopUnit => "START STOP UNIT",
+            PreventAllowMediumRemoval => "PREVENT ALLOW MEDIUM REMOVAL",
+            ReadFormatCapacities => "READ FORMAT CAPACITIES",
+            ReadCapacity10 => "READ CAPACITY(10)",
+            Read10 => "READ(10)",
+            Write10 => "WRITE(10)",
+            Verify10 => "VERIFY(10)",
+            SynchronizeCache10 => "SYNCHRONIZE CACHE(10)",
+            ModeSense10 => "MODE SENSE(10)",
+            ServiceActionIn16 => "SERVICE ACTION IN(16)",
+            Unknown => "unknown SCSI command",
+        }
+    }
+}
+
+/// Describe a SCSI command descriptor block.
+pub fn command_description(cdb: &[u8]) -> String {
+    use ScsiOpcode::*;
+    let opcode = ScsiOpcode::from(cdb[0]);
+    match (opcode, cdb.len()) {
+        (Inquiry, 6..) if cdb[1] & 0x01 != 0 => format!(
+            "INQUIRY for VPD page 0x{:02X}, {} bytes", cdb[2],
+            u16::from_be_bytes([cdb[3], cdb[4]])),
+        (Inquiry, 6..) => format!(
+            "INQUIRY, {} bytes", u16::from_be_bytes([cdb[3], cdb[4]])),
+        (Read10 | Write10 | Verify10, 10..) => {
+            let lba = u32::from_be_bytes([cdb[2], cdb[3], cdb[4], cdb[5]]);
+            let blocks = u16::from_be_bytes([cdb[7], cdb[8]]);
+            format!("{} LBA 0x{lba:X}, {blocks} {}", opcode.name(),
+                    if blocks == 1 { "block" } else { "blocks" })
+        },
+        (ServiceActionIn16, 16..) if cdb[1] & 0x1F == 0x10 =>
+            "READ CAPACITY(16)".to_string(),
+        (Unknown, _) => format!("SCSI command 0x{:02X}", cdb[0]),
+        (opcode, _) => opcode.name().to_string(),
+    }
+}
+
+/// Describe a transfer on a Bulk-Only Transport endpoint.
+pub fn transfer_description(payload: &[u8], detail: bool) -> Option<String> {
+    let mut s = String::new();
+    if let Some(cbw) = CommandBlockWrapper::from_bytes(payload) {
+        s.push_str(&command_description(cbw.command));
+        if cbw.data_length > 0 {
+            write!(s, ", {} {} bytes",
+                match cbw.direction {
+                    Direction::In => "reading",
+                    Direction::Out => "writing",
+                },
+                cbw.data_length).ok()?;
+        }
+        if detail {
+            write!(s, concat!(
+                "\nCommand block wrapper, tag 0x{:08X}, LUN {}",
+                "\nCommand block: {:02X?}"),
+                cbw.tag, cbw.lun, cbw.command).ok()?;
+        }
+        Some(s)
+    } else if let Some(csw) = CommandStatusWrapper::from_bytes(payload) {
+        write!(s, "Command {}", csw.status.description()).ok()?;
+        if csw.residue > 0 {
+            write!(s, ", {} bytes not transferred", csw.residue).ok()?;
+        }
+        if detail {
+            write!(s, "\nCommand status wrapper, tag 0x{:08X}",
+                   csw.tag).ok()?;
+        }
+        Some(s)
+    } else {
+        None
+    }
+}
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Decoder for mass storage bulk-only transport interfaces.
+pub struct Decoder;
+
+impl ClassDecoder for Decoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        is_bulk_only(iface.descriptor)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, false)
+    }
+
+    fn transfer_detail(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cbw(direction: u8, data_length: u32, command: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(CBW_SIGNATURE);
+        bytes.extend(0x12345678_u32.to_le_bytes());
+        bytes.extend(data_length.to_le_bytes());
+        bytes.push(direction);
+        bytes.push(0);
+        bytes.push(command.len() as u8);
+        bytes.extend(command);
+        bytes.resize(CBW_LENGTH, 0);
+        bytes
+    }
+
+    #[test]
+    fn test_read10() {
+        let bytes = cbw(0x80, 4096,
+            &[0x28, 0, 0, 0, 0x12, 0x34, 0, 0, 8, 0]);
+        let cbw = CommandBlockWrapper::from_bytes(&bytes).unwrap();
+        assert_eq!(cbw.tag, 0x12345678);
+        assert_eq!(cbw.data_length, 4096);
+        assert_eq!(command_description(cbw.command),
+                   "READ(10) LBA 0x1234, 8 blocks");
+        assert_eq!(transfer_description(&bytes, false).unwrap(),
+                   "READ(10) LBA 0x1234, 8 blocks, reading 4096 bytes");
+    }
+
+    #[test]
+    fn test_inquiry() {
+        let bytes = cbw(0x80, 36, &[0x12, 0, 0, 0, 36, 0]);
+        assert_eq!(transfer_description(&bytes, false).unwrap(),
+                   "INQUIRY, 36 bytes, reading 36 bytes");
+    }
+
+    #[test]
+    fn test_csw() {
+        let mut bytes = Vec::new();
+        bytes.extend(CSW_SIGNATURE);
+        bytes.extend(1_u32.to_le_bytes());
+        bytes.extend(512_u32.to_le_bytes());
+        bytes.push(1);
+        let csw = CommandStatusWrapper::from_bytes(&bytes).unwrap();
+        assert_eq!(csw.status, CommandStatus::Failed);
+        assert_eq!(transfer_description(&bytes, false).unwrap(),
+                   "Command failed, 512 bytes not transferred");
+        assert!(transfer_description(&bytes[..12], false).is_none());
+    }
+}
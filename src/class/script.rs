@@ -0,0 +1,386 @@
+//! User-supplied dissectors for vendor-specific protocols.
+
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Error, bail};
+
+use crate::usb::EndpointAddr;
+
+use super::{ClassDecoder, InterfaceInfo, Transfer};
+
+pub const ENV_VAR: &str = "PACKETRY_DISSECTORS";
+
+const EXTENSION: &str = "dissector";
+const BYTES_SHOWN: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FieldType {
+    U8,
+    U16Le,
+    U16Be,
+    U32Le,
+    U32Be,
+    Bytes(usize),
+    Text(usize),
+    Rest,
+}
+
+impl FieldType {
+    fn parse(s: &str) -> Result<FieldType, Error> {
+        use FieldType::*;
+        Ok(match s {
+            "u8" => U8,
+            "u16le" => U16Le,
+            "u16be" => U16Be,
+            "u32le" => U32Le,
+            "u32be" => U32Be,
+            "rest" => Rest,
+            _ => match s.split_once(':') {
+                Some(("bytes", n)) => Bytes(n.parse()?),
+                Some(("text", n)) => Text(n.parse()?),
+                _ => bail!("Unknown field type '{s}'")
+            }
+        })
+    }
+}
+
+struct Field {
+    name: String,
+    field_type: FieldType,
+    values: Vec<(u64, String)>,
+}
+
+/// A field decoded from a payload.
+struct FieldValue<'d> {
+    name: &'d str,
+    value: String,
+}
+
+/// A dissector loaded from a user-supplied script.
+#[derive(Default)]
+pub struct Dissector {
+    name: String,
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    interface_class: Option<u8>,
+    interface_number: Option<u8>,
+    endpoint: Option<EndpointAddr>,
+    fields: Vec<Field>,
+    summary: Option<String>,
+}
+
+fn parse_number<T: TryFrom<u64>>(s: &str) -> Result<T, Error> {
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16)?,
+        None => s.parse()?,
+    };
+    T::try_from(value).ok().with_context(|| format!("{s} is out of range"))
+}
+
+impl Dissector {
+    /// Parse the text of a dissector script.
+    pub fn parse(text: &str) -> Result<Dissector, Error> {
+        let mut dissector = Dissector {
+            name: "Vendor".to_string(),
+            ..Default::default()
+        };
+        for (i, line) in text.lines().enumerate() {
+            dissector.parse_line(line.trim())
+                .with_context(|| format!("Line {}", i + 1))?;
+        }
+        if dissector.vendor_id.is_none() &&
+            dissector.interface_class.is_none()
+        {
+            bail!("A dissector must match a vendor ID or interface class");
+        }
+        Ok(dissector)
+    }
+
+    /// Load a dissector script from a file.
+    pub fn load(path: &Path) -> Result<Dissector, Error> {
+        let text = std::fs::read_to_string(path)?;
+        Dissector::parse(&text)
+            .with_context(|| format!("In {}", path.display()))
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), Error> {
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        match keyword {
+            "name" => self.name = rest.to_string(),
+            "vendor" => self.vendor_id = Some(parse_number(rest)?),
+            "product" => self.product_id = Some(parse_number(rest)?),
+            "class" => self.interface_class = Some(parse_number(rest)?),
+            "interface" => self.interface_number = Some(parse_number(rest)?),
+            "endpoint" =>
+                self.endpoint = Some(EndpointAddr(parse_number(rest)?)),
+            "summary" => self.summary = Some(rest.to_string()),
+            "field" => {
+                let (name, field_type) = rest.split_once(' ')
+                    .context("Expected a field name and type")?;
+                if self.fields.last().is_some_and(|field|
+                    field.field_type == FieldType::Rest)
+                {
+                    bail!("No fields can follow a 'rest' field");
+                }
+                self.fields.push(Field {
+                    name: name.to_string(),
+                    field_type: FieldType::parse(field_type.trim())?,
+                    values: Vec::new(),
+                });
+            },
+            "value" => {
+                let mut parts = rest.splitn(3, ' ');
+                let (name, value, meaning) =
+                    match (parts.next(), parts.next(), parts.next()) {
+                        (Some(name), Some(value), Some(meaning)) =>
+                            (name, value, meaning),
+                        _ => bail!("Expected a field name, value and meaning")
+                    };
+                let field = self.fields
+                    .iter_mut()
+                    .find(|field| field.name == name)
+                    .with_context(|| format!("No field named '{name}'"))?;
+                field.values.push((parse_number(value)?, meaning.to_string()));
+            },
+            _ => bail!("Unknown keyword '{keyword}'")
+        }
+        Ok(())
+    }
+
+    /// Decode the fields present in a payload.
+    fn decode<'d>(&'d self, payload: &[u8]) -> Vec<FieldValue<'d>> {
+        use FieldType::*;
+        let mut offset = 0;
+        let mut decoded = Vec::new();
+        for field in &self.fields {
+            let length = match field.field_type {
+                U8 => 1,
+                U16Le | U16Be => 2,
+                U32Le | U32Be => 4,
+                Bytes(n) | Text(n) => n,
+                Rest => payload.len().saturating_sub(offset),
+            };
+            let bytes = match payload.get(offset..offset + length) {
+                Some(bytes) => bytes,
+                None => break
+            };
+            offset += length;
+            let number = match field.field_type {
+                U8 => bytes[0] as u64,
+                U16Le => u16::from_le_bytes([bytes[0], bytes[1]]) as u64,
+                U16Be => u16::from_be_bytes([bytes[0], bytes[1]]) as u64,
+                U32Le => u32::from_le_bytes(
+                    [bytes[0], bytes[1], bytes[2], bytes[3]]) as u64,
+                U32Be => u32::from_be_bytes(
+                    [bytes[0], bytes[1], bytes[2], bytes[3]]) as u64,
+                Text(_) => {
+                    let text = String::from_utf8_lossy(bytes);
+                    let text = text.trim_end_matches('\0');
+                    decoded.push(FieldValue {
+                        name: &field.name,
+                        value: format!("{text:?}"),
+                    });
+                    continue;
+                },
+                Bytes(_) | Rest => {
+                    let mut value = String::new();
+                    for byte in bytes.iter().take(BYTES_SHOWN) {
+                        let _ = write!(value, "{byte:02X} ");
+                    }
+                    if bytes.len() > BYTES_SHOWN {
+                        value.push_str("...");
+                    }
+                    decoded.push(FieldValue {
+                        name: &field.name,
+                        value: value.trim_end().to_string(),
+                    });
+                    continue;
+                },
+            };
+            let value = match field.values
+                .iter()
+                .find(|(value, _)| *value == number)
+            {
+                Some((_, meaning)) => meaning.clone(),
+                None => number.to_string(),
+            };
+            decoded.push(FieldValue { name: &field.name, value });
+        }
+        decoded
+    }
+
+    fn summary(&self, fields: &[FieldValue]) -> String {
+        match &self.summary {
+            Some(template) => {
+                let mut s = template.clone();
+                for field in &self.fields {
+                    let placeholder = format!("{{{}}}", field.name);
+                    let value = fields
+                        .iter()
+                        .find(|value| value.name == field.name)
+                        .map_or("?", |value| &value.value);
+                    s = s.replace(&placeholder, value);
+                }
+                s
+            },
+            None => {
+                let mut s = self.name.clone();
+                for (i, field) in fields.iter().enumerate() {
+                    let separator = if i == 0 { ": " } else { ", " };
+                    let _ = write!(s, "{separator}{} {}",
+                                   field.name, field.value);
+                }
+                s
+            }
+        }
+    }
+
+    fn applies_to(&self, transfer: &Transfer) -> bool {
+        !transfer.payload.is_empty() &&
+            self.endpoint.map_or(true, |addr| addr.0 == transfer.endpoint.0)
+    }
+}
+
+impl ClassDecoder for Dissector {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        let device = iface.device;
+        let descriptor = iface.descriptor;
+        self.vendor_id.map_or(true, |id|
+            device.is_some_and(|device| device.vendor_id == id)) &&
+        self.product_id.map_or(true, |id|
+            device.is_some_and(|device| device.product_id == id)) &&
+        self.interface_class.map_or(true, |class|
+            descriptor.interface_class == class) &&
+        self.interface_number.map_or(true, |number|
+            descriptor.interface_number.0 == number)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        if !self.applies_to(transfer) {
+            return None
+        }
+        Some(self.summary(&self.decode(transfer.payload)))
+    }
+
+    fn transfer_detail(&self, transfer: &Transfer) -> Option<String> {
+        if !self.applies_to(transfer) {
+            return None
+        }
+        let fields = self.decode(transfer.payload);
+        let mut s = self.summary(&fields);
+        for field in &fields {
+            let _ = write!(s, "\n{}: {}", field.name, field.value);
+        }
+        Some(s)
+    }
+
+    fn label(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+}
+
+fn load_dissectors() -> Vec<Dissector> {
+    let mut dissectors = Vec::new();
+    let paths = match std::env::var_os(ENV_VAR) {
+        Some(paths) => paths,
+        None => return dissectors
+    };
+    let mut load = |path: &Path| match Dissector::load(path) {
+        Ok(dissector) => dissectors.push(dissector),
+        Err(e) => eprintln!("Failed to load dissector: {e:#}"),
+    };
+    for path in std::env::split_paths(&paths) {
+        if path.is_dir() {
+            let mut files: Vec<_> = match std::fs::read_dir(&path) {
+                Ok(entries) => entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension()
+                        .is_some_and(|ext| ext == EXTENSION))
+                    .collect(),
+                Err(e) => {
+                    eprintln!("Failed to read {}: {e}", path.display());
+                    continue;
+                }
+            };
+            files.sort();
+            for file in files {
+                load(&file);
+            }
+        } else {
+            load(&path);
+        }
+    }
+    dissectors
+}
+
+/// The user-supplied dissectors, loaded on first use.
+pub fn dissectors() -> &'static [Dissector] {
+    static DISSECTORS: OnceLock<Vec<Dissector>> = OnceLock::new();
+    DISSECTORS.get_or_init(load_dissectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::{DeviceDescriptor, EndpointType, InterfaceDescriptor};
+
+    const SCRIPT: &str = "
+        # Example vendor protocol.
+        name Widget
+        vendor 0x1d50
+        endpoint 0x02
+        field command u8
+        field length u16le
+        field data rest
+        value command 0x01 read register
+        summary Widget {command} of {length} bytes
+    ";
+
+    #[test]
+    fn test_dissector() {
+        let dissector = Dissector::parse(SCRIPT).unwrap();
+        let device = DeviceDescriptor {
+            vendor_id: 0x1d50,
+            ..Default::default()
+        };
+        let descriptor = InterfaceDescriptor::default();
+        let iface = InterfaceInfo {
+            descriptor: &descriptor,
+            device: Some(&device),
+            hid_usage_page: None,
+        };
+        assert!(dissector.matches(&iface));
+        let payload = [0x01, 0x02, 0x00, 0xAA, 0xBB];
+        let transfer = Transfer {
+            ep_type: EndpointType::Bulk,
+            endpoint: EndpointAddr(0x02),
+            payload: &payload,
+            partial: false,
+        };
+        assert_eq!(dissector.transfer_detail(&transfer).unwrap(), concat!(
+            "Widget read register of 2 bytes\n",
+            "command: read register\n",
+            "length: 2\n",
+            "data: AA BB"));
+        let transfer = Transfer {
+            endpoint: EndpointAddr(0x81),
+            ..transfer
+        };
+        assert!(dissector.transfer_summary(&transfer).is_none());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Dissector::parse("field a u8").is_err());
+        assert!(Dissector::parse("vendor 1\nfield a u12").is_err());
+        assert!(Dissector::parse("vendor 1\nvalue a 1 one").is_err());
+        assert!(Dissector::parse("vendor 0x10000").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! USB test and measurement class (USBTMC), carrying SCPI messages.
+
+use std::fmt::Write;
+
+use num_enum::FromPrimitive;
+
+use crate::usb::{InterfaceDescriptor, SetupFields};
+use super::{ClassDecoder, InterfaceInfo, Transfer};
+
+pub const CLASS: u8 = 0xFE;
+pub const SUBCLASS_TMC: u8 = 0x03;
+
+const HEADER_LENGTH: usize = 12;
+const ATTR_EOM: u8 = 0x01;
+const ATTR_TERM_CHAR: u8 = 0x02;
+
+/// Whether an interface is a USBTMC interface.
+pub fn is_tmc(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS &&
+        iface.interface_subclass == SUBCLASS_TMC
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageId {
+    DevDepMsgOut = 1,
+    DevDepMsgIn = 2,
+    VendorSpecificOut = 126,
+    VendorSpecificIn = 127,
+    Trigger = 128,
+    #[default]
+    Unknown = 0,
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TmcRequest {
+    InitiateAbortBulkOut = 1,
+    CheckAbortBulkOutStatus = 2,
+    InitiateAbortBulkIn = 3,
+    CheckAbortBulkInStatus = 4,
+    InitiateClear = 5,
+    CheckClearStatus = 6,
+    GetCapabilities = 7,
+    IndicatorPulse = 64,
+    ReadStatusByte = 128,
+    RenControl = 160,
+    GoToLocal = 161,
+    LocalLockout = 162,
+    #[default]
+    Unknown = 0,
+}
+
+impl TmcRequest {
+    pub fn description(self) -> &'static str {
+        use TmcRequest::*;
+        match self {
+            InitiateAbortBulkOut => "USBTMC initiate abort bulk out",
+            CheckAbortBulkOutStatus => "USBTMC check abort bulk out status",
+            InitiateAbortBulkIn => "USBTMC initiate abort bulk in",
+            CheckAbortBulkInStatus => "USBTMC check abort bulk in status",
+            InitiateClear => "USBTMC initiate clear",
+            CheckClearStatus => "USBTMC check clear status",
+            GetCapabilities => "USBTMC get capabilities",
+            IndicatorPulse => "USBTMC indicator pulse",
+            ReadStatusByte => "USB488 read status byte",
+            RenControl => "USB488 remote enable control",
+            GoToLocal => "USB488 go to local",
+            LocalLockout => "USB488 local lockout",
+            Unknown => "unknown USBTMC request",
+        }
+    }
+}
+
+fn status_name(status: u8) -> &'static str {
+    match status {
+        0x01 => "success",
+        0x02 => "pending",
+        0x80 => "failed",
+        0x81 => "transfer not in progress",
+        0x82 => "split not in progress",
+        0x83 => "split in progress",
+        _ => "unknown status",
+    }
+}
+
+/// Describe a class request to a USBTMC interface.
+pub fn request_description(fields: &SetupFields, data: &[u8])
+    -> Option<String>
+{
+    let request = TmcRequest::from(fields.request);
+    if request == TmcRequest::Unknown {
+        return None
+    }
+    let mut s = request.description().to_string();
+    if let Some(&status) = data.first() {
+        write!(s, ": {}", status_name(status)).ok()?;
+        if request == TmcRequest::ReadStatusByte {
+            if let Some(status_byte) = data.get(2) {
+                write!(s, ", status byte 0x{status_byte:02X}").ok()?;
+            }
+        }
+    }
+    Some(s)
+}
+
+/// Render a message as text, without its terminating newline.
+fn message_text(data: &[u8]) -> String {
+    let text = String::from_utf8_lossy(data);
+    let text = text.strip_suffix('\n').unwrap_or(&text);
+    let text = text.strip_suffix('\r').unwrap_or(text);
+    format!("{text:?}")
+}
+
+/// Describe a transfer on a USBTMC bulk endpoint.
+///
+/// The message ID for a request for device data is the same as that for
+/// the device data itself. They are told apart by the request having no
+/// message data, but a non-zero transfer size.
+pub fn transfer_description(payload: &[u8], partial: bool, detail: bool)
+    -> Option<String>
+{
+    use MessageId::*;
+    if payload.len() < HEADER_LENGTH {
+        return None
+    }
+    let message_id = MessageId::from(payload[0]);
+    let tag = payload[1];
+    if payload[2] != !tag {
+        return None
+    }
+    let size = u32::from_le_bytes(payload[4..8].try_into().ok()?);
+    let attributes = payload[8];
+    let data = &payload[HEADER_LENGTH..];
+    let data = &data[..data.len().min(size as usize)];
+    let ellipsis = if partial { "..." } else { "" };
+    let mut s = match message_id {
+        DevDepMsgOut => format!(
+            "SCPI command {}{ellipsis}", message_text(data)),
+        DevDepMsgIn if payload.len() == HEADER_LENGTH && size > 0 => {
+            let mut s = format!("Requesting up to {size} bytes of response");
+            if attributes & ATTR_TERM_CHAR != 0 {
+                write!(s, ", terminated by 0x{:02X}", payload[9]).ok()?;
+            }
+            s
+        },
+        DevDepMsgIn => format!(
+            "SCPI response {}{ellipsis}", message_text(data)),
+        VendorSpecificOut => format!(
+            "USBTMC vendor specific message, {size} bytes"),
+        VendorSpecificIn if payload.len() == HEADER_LENGTH =>
+            format!("Requesting up to {size} bytes of vendor specific data"),
+        VendorSpecificIn => format!(
+            "USBTMC vendor specific response, {size} bytes"),
+        Trigger => "USB488 trigger".to_string(),
+        Unknown => return None,
+    };
+    if detail {
+        write!(s, "\nMessage ID {}, tag {tag}, transfer size {size}",
+               payload[0]).ok()?;
+        if matches!(message_id, DevDepMsgOut | DevDepMsgIn) &&
+            attributes & ATTR_EOM != 0
+        {
+            s.push_str(", end of message");
+        }
+    }
+    Some(s)
+}
+
+/// Decoder for test and measurement interfaces.
+pub struct Decoder;
+
+impl ClassDecoder for Decoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        is_tmc(iface.descriptor)
+    }
+
+    fn request_description(&self, fields: &SetupFields, data: &[u8])
+        -> Option<String>
+    {
+        request_description(fields, data)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, transfer.partial, false)
+    }
+
+    fn transfer_detail(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, transfer.partial, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u8, tag: u8, size: u32, attributes: u8, data: &[u8])
+        -> Vec<u8>
+    {
+        let mut bytes = vec![id, tag, !tag, 0];
+        bytes.extend(size.to_le_bytes());
+        bytes.extend([attributes, 0, 0, 0]);
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn test_messages() {
+        let command = message(1, 1, 6, 1, b"*IDN?\n\0\0");
+        assert_eq!(transfer_description(&command, false, true).unwrap(),
+            "SCPI command \"*IDN?\"\nMessage ID 1, tag 1, transfer size 6, \
+             end of message");
+        let request = message(2, 2, 1024, 0, &[]);
+        assert_eq!(transfer_description(&request, false, false).unwrap(),
+            "Requesting up to 1024 bytes of response");
+        let response = message(2, 2, 3, 1, b"1.5\n");
+        assert_eq!(transfer_description(&response, false, false).unwrap(),
+            "SCPI response \"1.5\"");
+        let mut bad_tag = command.clone();
+        bad_tag[2] = 0;
+        assert!(transfer_description(&bad_tag, false, false).is_none());
+    }
+}
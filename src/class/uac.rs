@@ -0,0 +1,190 @@
+//! USB audio class, streaming formats and WAV export.
+
+use crate::usb::{Interface, InterfaceDescriptor};
+
+pub const CLASS: u8 = 0x01;
+pub const SUBCLASS_STREAMING: u8 = 0x02;
+pub const PROTOCOL_UAC2: u8 = 0x20;
+
+const CS_INTERFACE: u8 = 0x24;
+const AS_GENERAL: u8 = 0x01;
+const FORMAT_TYPE: u8 = 0x02;
+const FORMAT_TYPE_I: u8 = 0x01;
+
+const UAC1_PCM: u16 = 0x0001;
+const UAC1_PCM8: u16 = 0x0002;
+const UAC1_IEEE_FLOAT: u16 = 0x0003;
+
+const UAC2_PCM: u32 = 1 << 0;
+const UAC2_PCM8: u32 = 1 << 1;
+const UAC2_IEEE_FLOAT: u32 = 1 << 2;
+
+const WAVE_PCM: u16 = 0x0001;
+const WAVE_IEEE_FLOAT: u16 = 0x0003;
+
+/// Sampling rate assumed for UAC2 streams.
+///
+/// In UAC2 the rate is set through a clock source entity rather than
+/// described by the streaming interface.
+const UAC2_DEFAULT_RATE: u32 = 48000;
+
+/// Whether an interface is an audio streaming interface.
+pub fn is_streaming(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS &&
+        iface.interface_subclass == SUBCLASS_STREAMING
+}
+
+/// Sample format of an audio stream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Format {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub subframe_size: u16,
+    pub bit_resolution: u16,
+    pub float: bool,
+}
+
+impl Format {
+    /// Determine the format of a streaming interface from its class
+    /// specific descriptors.
+    ///
+    /// Returns None for interfaces without a Type I format descriptor,
+    /// or with a format that cannot be stored in a WAV file.
+    pub fn from_interface(iface: &Interface) -> Option<Format> {
+        if !is_streaming(&iface.descriptor) {
+            return None
+        }
+        let uac2 = iface.descriptor.interface_protocol == PROTOCOL_UAC2;
+        let mut general = None;
+        let mut format_type = None;
+        for desc in &iface.class_descriptors {
+            match (desc[1], desc[2]) {
+                (CS_INTERFACE, AS_GENERAL) => general = Some(desc),
+                (CS_INTERFACE, FORMAT_TYPE) => format_type = Some(desc),
+                _ => {}
+            }
+        }
+        let general = general?;
+        let format_type = format_type?;
+        if *format_type.get(3)? != FORMAT_TYPE_I {
+            return None
+        }
+        if uac2 {
+            let formats =
+                u32::from_le_bytes(general.get(6..10)?.try_into().ok()?);
+            let float = formats & UAC2_IEEE_FLOAT != 0;
+            if formats & (UAC2_PCM | UAC2_PCM8) == 0 && !float {
+                return None
+            }
+            Some(Format {
+                channels: *general.get(10)? as u16,
+                sample_rate: UAC2_DEFAULT_RATE,
+                subframe_size: *format_type.get(4)? as u16,
+                bit_resolution: *format_type.get(5)? as u16,
+                float,
+            })
+        } else {
+            let tag = u16::from_le_bytes(general.get(5..7)?.try_into().ok()?);
+            let float = tag == UAC1_IEEE_FLOAT;
+            if tag != UAC1_PCM && tag != UAC1_PCM8 && !float {
+                return None
+            }
+            // Use the first discrete rate, or the lower bound of a
+            // continuous range.
+            let rate = format_type.get(8..11)?;
+            let sample_rate =
+                u32::from_le_bytes([rate[0], rate[1], rate[2], 0]);
+            Some(Format {
+                channels: *format_type.get(4)? as u16,
+                sample_rate,
+                subframe_size: *format_type.get(5)? as u16,
+                bit_resolution: *format_type.get(6)? as u16,
+                float,
+            })
+        }
+    }
+
+    /// Size of one sample for all channels, in bytes.
+    pub fn frame_size(&self) -> u16 {
+        self.channels * self.subframe_size
+    }
+
+    pub fn description(&self) -> String {
+        format!("{} channel{}, {} Hz, {}-bit {}",
+            self.channels,
+            if self.channels == 1 { "" } else { "s" },
+            self.sample_rate,
+            self.bit_resolution,
+            if self.float { "float" } else { "PCM" })
+    }
+
+    /// Build a WAV file containing the given sample data.
+    ///
+    /// Any partial sample frame at the end of the data is dropped.
+    pub fn wav_file(&self, samples: &[u8]) -> Vec<u8> {
+        let frame_size = self.frame_size().max(1) as usize;
+        let data = &samples[..samples.len() - samples.len() % frame_size];
+        let data_length = data.len() as u32;
+        let byte_rate = self.sample_rate * self.frame_size() as u32;
+        let mut wav = Vec::with_capacity(44 + data.len());
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_length).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(
+            if self.float { WAVE_IEEE_FLOAT } else { WAVE_PCM }.to_le_bytes());
+        wav.extend(self.channels.to_le_bytes());
+        wav.extend(self.sample_rate.to_le_bytes());
+        wav.extend(byte_rate.to_le_bytes());
+        wav.extend(self.frame_size().to_le_bytes());
+        wav.extend((self.subframe_size * 8).to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_length.to_le_bytes());
+        wav.extend(data);
+        wav
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::pod_read_unaligned;
+    use crate::usb::EndpointDescriptor;
+    use crate::vec_map::VecMap;
+
+    fn interface(protocol: u8, class_descriptors: Vec<Vec<u8>>) -> Interface {
+        let descriptor = pod_read_unaligned::<InterfaceDescriptor>(
+            &[9, 4, 1, 1, 1, CLASS, SUBCLASS_STREAMING, protocol, 0]);
+        Interface {
+            descriptor,
+            endpoint_descriptors: VecMap::<_, EndpointDescriptor>::new(),
+            class_descriptors,
+        }
+    }
+
+    #[test]
+    fn test_uac1_format() {
+        let iface = interface(0, vec![
+            vec![7, 0x24, 0x01, 1, 1, 0x01, 0x00],
+            vec![11, 0x24, 0x02, 1, 2, 2, 16, 1, 0x44, 0xAC, 0x00],
+        ]);
+        let format = Format::from_interface(&iface).unwrap();
+        assert_eq!(format.description(), "2 channels, 44100 Hz, 16-bit PCM");
+        let wav = format.wav_file(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(wav.len(), 48);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[28..32], &176400u32.to_le_bytes());
+        assert_eq!(&wav[40..44], &4u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_uac2_format() {
+        let iface = interface(PROTOCOL_UAC2, vec![
+            vec![16, 0x24, 0x01, 2, 0, 1, 0x01, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+            vec![6, 0x24, 0x02, 1, 3, 24],
+        ]);
+        let format = Format::from_interface(&iface).unwrap();
+        assert_eq!(format.description(), "1 channel, 48000 Hz, 24-bit PCM");
+        assert_eq!(format.frame_size(), 3);
+    }
+}
@@ -0,0 +1,193 @@
+//! USB video class, payload headers and frame reassembly.
+
+use std::fmt::Write;
+use std::mem::take;
+
+use crate::usb::InterfaceDescriptor;
+use super::{ClassDecoder, InterfaceInfo, Transfer};
+
+pub const CLASS: u8 = 0x0E;
+pub const SUBCLASS_STREAMING: u8 = 0x02;
+
+const HEADER_FID: u8 = 0x01;
+const HEADER_EOF: u8 = 0x02;
+const HEADER_PTS: u8 = 0x04;
+const HEADER_SCR: u8 = 0x08;
+const HEADER_STI: u8 = 0x20;
+const HEADER_ERR: u8 = 0x40;
+
+/// Whether an interface is a video streaming interface.
+pub fn is_streaming(iface: &InterfaceDescriptor) -> bool {
+    iface.interface_class == CLASS &&
+        iface.interface_subclass == SUBCLASS_STREAMING
+}
+
+/// Header at the start of each video payload transfer.
+pub struct PayloadHeader {
+    pub length: usize,
+    pub frame_id: bool,
+    pub end_of_frame: bool,
+    pub still_image: bool,
+    pub error: bool,
+    pub pts: Option<u32>,
+    pub scr: Option<(u32, u16)>,
+}
+
+impl PayloadHeader {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let length = *bytes.first()? as usize;
+        let info = *bytes.get(1)?;
+        let mut offset = 2;
+        let pts = if info & HEADER_PTS != 0 {
+            offset += 4;
+            Some(u32::from_le_bytes(bytes.get(2..6)?.try_into().ok()?))
+        } else {
+            None
+        };
+        let scr = if info & HEADER_SCR != 0 {
+            let scr = bytes.get(offset..offset + 6)?;
+            offset += 6;
+            Some((u32::from_le_bytes([scr[0], scr[1], scr[2], scr[3]]),
+                  u16::from_le_bytes([scr[4], scr[5]]) & 0x7FF))
+        } else {
+            None
+        };
+        if length < offset || length > bytes.len() {
+            return None
+        }
+        Some(PayloadHeader {
+            length,
+            frame_id: info & HEADER_FID != 0,
+            end_of_frame: info & HEADER_EOF != 0,
+            still_image: info & HEADER_STI != 0,
+            error: info & HEADER_ERR != 0,
+            pts,
+            scr,
+        })
+    }
+}
+
+/// Describe a transfer on a video streaming endpoint.
+pub fn transfer_description(payload: &[u8], detail: bool) -> Option<String> {
+    let header = PayloadHeader::from_bytes(payload)?;
+    let mut s = format!("Video payload, FID {}", header.frame_id as u8);
+    if header.end_of_frame {
+        s.push_str(", end of frame");
+    }
+    if header.still_image {
+        s.push_str(", still image");
+    }
+    if header.error {
+        s.push_str(", error");
+    }
+    if let Some(pts) = header.pts {
+        write!(s, ", PTS 0x{pts:08X}").ok()?;
+    }
+    if detail {
+        write!(s, "\nPayload header of {} bytes", header.length).ok()?;
+        if let Some((stc, sof)) = header.scr {
+            write!(s, ", SCR with STC 0x{stc:08X} at SOF {sof}").ok()?;
+        }
+    }
+    Some(s)
+}
+
+/// Reassembles video frames from a sequence of payload transfers.
+///
+/// A frame ends either when a payload has the end of frame bit set, or
+/// when the frame ID toggles.
+#[derive(Default)]
+pub struct FrameAssembler {
+    frame: Vec<u8>,
+    frame_id: Option<bool>,
+}
+
+impl FrameAssembler {
+    /// Add a complete payload transfer, beginning with a payload header.
+    ///
+    /// Returns any frames completed by this payload. Payloads without a
+    /// valid header are ignored.
+    pub fn add_payload(&mut self, payload: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        if let Some(header) = PayloadHeader::from_bytes(payload) {
+            if self.frame_id.is_some_and(|id| id != header.frame_id) &&
+                !self.frame.is_empty()
+            {
+                frames.push(take(&mut self.frame));
+            }
+            self.frame_id = Some(header.frame_id);
+            self.frame.extend(&payload[header.length..]);
+            if header.end_of_frame && !self.frame.is_empty() {
+                frames.push(take(&mut self.frame));
+            }
+        }
+        frames
+    }
+
+    /// Return any incomplete frame remaining at the end of the stream.
+    pub fn finish(self) -> Option<Vec<u8>> {
+        if self.frame.is_empty() {
+            None
+        } else {
+            Some(self.frame)
+        }
+    }
+}
+
+/// Suggest a file extension for a reconstructed frame.
+pub fn frame_extension(frame: &[u8]) -> &'static str {
+    if frame.starts_with(&[0xFF, 0xD8]) {
+        "jpg"
+    } else {
+        "raw"
+    }
+}
+
+/// Decoder for video streaming interfaces.
+pub struct Decoder;
+
+impl ClassDecoder for Decoder {
+    fn matches(&self, iface: &InterfaceInfo) -> bool {
+        is_streaming(iface.descriptor)
+    }
+
+    fn transfer_summary(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, false)
+    }
+
+    fn transfer_detail(&self, transfer: &Transfer) -> Option<String> {
+        transfer_description(transfer.payload, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let payload = [12, 0x8D, 0x78, 0x56, 0x34, 0x12,
+                       1, 0, 0, 0, 0x10, 0x00, 0xAB];
+        let header = PayloadHeader::from_bytes(&payload).unwrap();
+        assert_eq!(header.length, 12);
+        assert!(header.frame_id);
+        assert_eq!(header.pts, Some(0x12345678));
+        assert_eq!(header.scr, Some((1, 16)));
+        assert_eq!(transfer_description(&payload, false).unwrap(),
+                   "Video payload, FID 1, PTS 0x12345678");
+        assert!(PayloadHeader::from_bytes(&payload[..8]).is_none());
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut assembler = FrameAssembler::default();
+        assert!(assembler.add_payload(&[2, 0x80, 0xFF, 0xD8, 1, 2]).is_empty());
+        let frames = assembler.add_payload(&[2, 0x82, 3]);
+        assert_eq!(frames, vec![vec![0xFF, 0xD8, 1, 2, 3]]);
+        assert!(assembler.add_payload(&[2, 0x81, 4]).is_empty());
+        let frames = assembler.add_payload(&[2, 0x80, 5]);
+        assert_eq!(frames, vec![vec![4]]);
+        assert_eq!(assembler.finish(), Some(vec![5]));
+        assert_eq!(frame_extension(&[0xFF, 0xD8, 0xFF]), "jpg");
+    }
+}
@@ -0,0 +1,600 @@
+//! Commands run from the command line, without the GUI, for use in
+//! scripts.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::time::Duration;
+
+use anyhow::{Context, Error, bail};
+
+use crate::backend::{self, Speed, handle_thread_panic};
+use crate::capture::{
+    create_capture,
+    CaptureReader,
+    EndpointId,
+    ItemSource,
+    Timestamp,
+    TrafficFilter,
+    TrafficItem,
+    TransferId,
+    FRAMING_EP_NUM,
+    INVALID_EP_NUM,
+};
+use crate::check::{
+    check_capture,
+    write_violations_json,
+    write_violations_text,
+};
+use crate::decoder::{self, Decoder};
+use crate::events::EventStream;
+use crate::hex_text::parse_time;
+use crate::iti1480a;
+use crate::pcap::InputFormat;
+use crate::query::{
+    parse_endpoint,
+    parse_time_range,
+    query,
+    Criteria,
+};
+use crate::report::write_items_report;
+use crate::stats::{capture_stats, write_stats_json, write_stats_text};
+use crate::usb::Direction;
+use crate::util::fmt_size;
+
+/// Names of the commands, as given before their options.
+pub const COMMANDS: [&str; 5] =
+    ["dump", "stats", "extract", "check", "capture"];
+
+/// Options taking a value, which every command accepts.
+const COMMON_OPTIONS: [&str; 8] = [
+    "--filter", "--device", "--endpoint", "--time-range", "--max-items",
+    "--query", "--format", "--output",
+];
+
+/// Status to exit with when the check command finds violations, as
+/// distinct from 1 for failing to run.
+const VIOLATIONS_FOUND: i32 = 2;
+
+/// Whether an argument names a command.
+pub fn is_command(name: &str) -> bool {
+    COMMANDS.contains(&name)
+}
+
+/// Run a command with the arguments following its name, returning the
+/// status to exit with.
+pub fn run(name: &str, args: &[String]) -> i32 {
+    match execute(name, args) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("packetry {name}: {e:#}");
+            1
+        }
+    }
+}
+
+fn execute(name: &str, args: &[String]) -> Result<i32, Error> {
+    let (values, flags): (&[&str], &[&str]) = match name {
+        "dump" => (&[], &["--hex"]),
+        "stats" => (&[], &["--json"]),
+        "extract" => (&[], &[]),
+        "check" => (&[], &["--json"]),
+        "capture" => (&["--speed", "--duration"], &[]),
+        _ => bail!("Unknown command '{name}'"),
+    };
+    let options = Options::parse(args, values, flags)?;
+    if options.flag("--help") {
+        println!("{}", usage(name));
+        return Ok(0);
+    }
+    if name == "capture" {
+        capture_events(&options)?;
+        return Ok(0);
+    }
+    let file = options.file.as_deref()
+        .with_context(|| format!("No capture file given\n{}", usage(name)))?;
+    let format = options.value("--format")
+        .map(InputFormat::from_name)
+        .transpose()?;
+    let selection = Selection::from_options(&options)?;
+    if name == "extract" {
+        for name in ["--filter", "--time-range", "--max-items", "--query"]
+        {
+            if options.value(name).is_some() {
+                bail!("The {name} option cannot be used with extract");
+            }
+        }
+        for name in ["--device", "--endpoint"] {
+            if options.value(name).is_none() {
+                bail!("The {name} option must be given for extract");
+            }
+        }
+    }
+    let mut capture = load_capture(file, format)?;
+    let dest = output(&options)?;
+    match name {
+        "dump" => {
+            let items = selection.items(&mut capture)?;
+            write_items_report(&mut capture, &items,
+                               options.flag("--hex"), dest)?;
+        },
+        "stats" => {
+            let items = selection.items(&mut capture)?;
+            let stats = capture_stats(&mut capture, &items)?;
+            if options.flag("--json") {
+                write_stats_json(&stats, dest)?;
+            } else {
+                write_stats_text(&stats, dest)?;
+            }
+        },
+        "extract" => {
+            let size = extract(&mut capture, &selection, dest)?;
+            eprintln!("Extracted {} of endpoint data", fmt_size(size));
+        },
+        "check" => {
+            let items = selection.items(&mut capture)?;
+            let violations = check_capture(
+                &mut capture, &selection.filter.addresses, &items)?;
+            if options.flag("--json") {
+                write_violations_json(&violations, dest)?;
+            } else {
+                write_violations_text(&violations, dest)?;
+            }
+            if !violations.is_empty() {
+                return Ok(VIOLATIONS_FOUND);
+            }
+        },
+        _ => unreachable!(),
+    }
+    Ok(0)
+}
+
+/// Usage of a command.
+fn usage(name: &str) -> String {
+    if name == "capture" {
+        return "Usage: packetry capture [OPTIONS]\n\
+                \n\
+                Options:\n  \
+                --speed SPEED           Capture at high, full, low or auto \
+                speed\n  \
+                --duration SECONDS      Stop capturing after this long\n  \
+                -o, --output DEST       Write to a file, tcp:HOST:PORT or \
+                unix:PATH,\n                          \
+                not standard output\n\
+                \n\
+                Each transaction and transfer is written as a line of JSON \
+                as it completes."
+            .to_string();
+    }
+    let options = match name {
+        "dump" => "\n  --hex                   Show the bytes of each packet",
+        "stats" => "\n  --json                  Write JSON, not text",
+        "check" => "\n  --json                  Write JSON, not text\n\n\
+                    Exits with status 2 if any violations are found.",
+        "extract" => "\n\nAll the data sent on the endpoint given with \
+                      --device and --endpoint\nis written as a binary file.",
+        _ => "",
+    };
+    format!("Usage: packetry {name} [OPTIONS] FILE\n\
+             \n\
+             Options:\n  \
+             --filter EXPR           Show only traffic matching a filter\n  \
+             --device ADDRESS        Show only traffic of one device\n  \
+             --endpoint NUMBER[DIR]  Show only traffic on one endpoint\n  \
+             --time-range START..END Show only traffic in a time span\n  \
+             --max-items N           Show at most N top-level items\n  \
+             --query EXPR            Show only transfers matching a query\n  \
+             --format FORMAT         Format of the capture file\n  \
+             -o, --output FILE       Write to a file, not standard output\
+             {options}")
+}
+
+/// The options given to a command.
+#[derive(Debug, Default)]
+struct Options {
+    values: Vec<(String, String)>,
+    flags: Vec<String>,
+    file: Option<String>,
+}
+
+impl Options {
+    /// Parse the arguments of a command, which takes the common options
+    /// and the further options and flags given.
+    fn parse(args: &[String], values: &[&str], flags: &[&str])
+        -> Result<Options, Error>
+    {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let arg = if arg == "-o" { "--output" } else { arg.as_str() };
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg, None),
+            };
+            if COMMON_OPTIONS.contains(&name) || values.contains(&name) {
+                let value = match inline {
+                    Some(value) => value,
+                    None => args.next().cloned().with_context(||
+                        format!("No value given for {name}"))?,
+                };
+                options.values.push((name.to_string(), value));
+            } else if flags.contains(&arg) || arg == "--help" {
+                options.flags.push(arg.to_string());
+            } else if arg.starts_with("--") {
+                bail!("Unknown option '{arg}'");
+            } else if options.file.is_some() {
+                bail!("Only one capture file may be given");
+            } else {
+                options.file = Some(arg.to_string());
+            }
+        }
+        Ok(options)
+    }
+
+    /// The value given for an option, the last if given more than once.
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(option, _)| option == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+}
+
+/// The traffic a command works on.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub filter: TrafficFilter,
+    /// Direction of the endpoints to include, if not both.
+    pub direction: Option<Direction>,
+    /// Span of time in which items must fall, if limited.
+    pub time_range: Option<Range<Timestamp>>,
+    /// Maximum number of top-level items, if limited.
+    pub max_items: Option<u64>,
+    /// Criteria which a transaction of each transfer must meet, if any.
+    pub query: Option<Criteria>,
+}
+
+impl Selection {
+    fn from_options(options: &Options) -> Result<Selection, Error> {
+        let mut selection = Selection::default();
+        if let Some(text) = options.value("--filter") {
+            selection.filter = TrafficFilter::parse(text)?;
+        }
+        if let Some(text) = options.value("--device") {
+            let address: u8 = text.parse()
+                .ok()
+                .filter(|address| *address <= 127)
+                .with_context(|| format!(
+                    "Invalid device address '{text}', \
+                     expected a number from 0 to 127"))?;
+            selection.filter.addresses = vec![address];
+        }
+        if let Some(text) = options.value("--endpoint") {
+            let (number, direction) = parse_endpoint(text)?;
+            selection.filter.endpoints = vec![number];
+            selection.direction = direction;
+        }
+        if let Some(text) = options.value("--time-range") {
+            selection.time_range = Some(parse_time_range(text)?);
+        }
+        if let Some(text) = options.value("--max-items") {
+            selection.max_items = Some(text.parse().with_context(||
+                format!("Invalid maximum number of items '{text}'"))?);
+        }
+        if let Some(text) = options.value("--query") {
+            selection.query = Some(Criteria::parse(text)?);
+        }
+        Ok(selection)
+    }
+
+    /// The top-level items of a capture which are selected.
+    pub fn items(&self, capture: &mut CaptureReader)
+        -> Result<Vec<TrafficItem>, Error>
+    {
+        // Transfers with a transaction matching the query, if given.
+        let matched = match &self.query {
+            Some(criteria) => {
+                let mut transfer_ids: Vec<TransferId> =
+                    query(capture, criteria)?
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        TrafficItem::Transaction(transfer_id, _) =>
+                            Some(transfer_id),
+                        _ => None,
+                    })
+                    .collect();
+                transfer_ids.sort_unstable();
+                transfer_ids.dedup();
+                Some(transfer_ids)
+            },
+            None => None,
+        };
+        capture.set_filter(self.filter.clone());
+        let mut items = Vec::new();
+        let (_, count) = capture.item_children(None::<&TrafficItem>)?;
+        for index in 0..count {
+            if self.max_items.is_some_and(|max| items.len() as u64 >= max) {
+                break;
+            }
+            let item = capture.item(None, index)?;
+            if let Some(range) = &self.time_range {
+                let span = capture.item_time_range(&item)?;
+                if span.start >= range.end {
+                    // Items are in order of their start times.
+                    break;
+                }
+                if span.end <= range.start {
+                    continue;
+                }
+            }
+            if let Some(direction) = self.direction {
+                // Control transfers go both ways.
+                let other_direction = capture
+                    .item_endpoint(&item)?
+                    .is_some_and(|endpoint|
+                        endpoint.number().0 != 0 &&
+                        endpoint.direction() != direction);
+                if other_direction {
+                    continue;
+                }
+            }
+            if let (Some(matched), TrafficItem::Transfer(transfer_id)) =
+                (&matched, item)
+            {
+                if matched.binary_search(&transfer_id).is_err() {
+                    continue;
+                }
+            }
+            items.push(item);
+        }
+        Ok(items)
+    }
+}
+
+/// Write all the data sent on the endpoint chosen, returning its size.
+fn extract(capture: &mut CaptureReader,
+           selection: &Selection,
+           mut dest: impl Write)
+    -> Result<u64, Error>
+{
+    let address = *selection.filter.addresses.first()
+        .context("No device given")?;
+    let number = *selection.filter.endpoints.first()
+        .context("No endpoint given")?;
+    let mut endpoint_ids = Vec::new();
+    for i in 0..capture.endpoints.len() {
+        let endpoint_id = EndpointId::from(i);
+        let endpoint = capture.endpoints.get(endpoint_id)?;
+        if [INVALID_EP_NUM, FRAMING_EP_NUM].contains(&endpoint.number()) ||
+            endpoint.device_address().0 != address ||
+            endpoint.number().0 != number
+        {
+            continue;
+        }
+        // Control transfers go both ways.
+        let other_direction = selection.direction
+            .is_some_and(|direction| direction != endpoint.direction());
+        if number == 0 || !other_direction {
+            endpoint_ids.push((endpoint_id, endpoint.direction()));
+        }
+    }
+    match endpoint_ids.as_slice() {
+        [] => bail!("No traffic found on endpoint {number} \
+                     of device {address}"),
+        [(_, first), rest @ ..] if rest.iter().any(|(_, d)| d != first) =>
+            bail!("Endpoint {number} of device {address} has traffic in \
+                   both directions, choose one with {number}in or \
+                   {number}out"),
+        _ => {},
+    }
+    // The same address may have been used by more than one device, if one
+    // was reconnected, in which case their data is written in turn.
+    let mut size = 0;
+    for (endpoint_id, _) in endpoint_ids {
+        capture.endpoint_payloads(endpoint_id, |payload| {
+            dest.write_all(&payload)?;
+            size += payload.len() as u64;
+            Ok(())
+        })?;
+    }
+    dest.flush()?;
+    Ok(size)
+}
+
+/// Capture from the first usable analyzer, writing events as the traffic
+/// is decoded, until the duration given has passed or the analyzer stops.
+fn capture_events(options: &Options) -> Result<(), Error> {
+    for name in ["--filter", "--device", "--endpoint", "--time-range",
+                 "--max-items", "--query", "--format"]
+    {
+        if options.value(name).is_some() {
+            bail!("The {name} option cannot be used with capture");
+        }
+    }
+    if let Some(file) = &options.file {
+        bail!("Unexpected argument '{file}', capture reads no file");
+    }
+    let speed = match options.value("--speed") {
+        Some(name) => Speed::from_name(name).with_context(|| format!(
+            "Invalid speed '{name}', expected high, full, low or auto"))?,
+        None => Speed::High,
+    };
+    let duration = options.value("--duration")
+        .map(|text| parse_time(text).map(Duration::from_nanos))
+        .transpose()?;
+    let events = EventStream::open(options.value("--output").unwrap_or("-"))?;
+    let device = backend::scan()?
+        .into_iter()
+        .find(|device| device.speeds().is_ok())
+        .context("No usable analyzer found")?;
+    if !device.speeds().is_ok_and(|speeds| speeds.contains(&speed)) {
+        bail!("The {} analyzer cannot capture at {} speed",
+              device.name(), speed.name());
+    }
+    let handle = device.open()?;
+    let (mut stream, stop) = handle.start(speed, Box::new(|result| {
+        if let Err(e) = result {
+            eprintln!("packetry capture: {e:#}");
+        }
+    }))?;
+    eprintln!("Capturing from {} at {}", device.name(), speed.description());
+    // Stop from another thread, as no packets may arrive meanwhile.
+    let (timer, _stop) = match duration {
+        Some(duration) => (Some(std::thread::spawn(move || {
+            std::thread::sleep(duration);
+            stop.stop()
+        })), None),
+        None => (None, Some(stop)),
+    };
+    let (writer, _reader) = create_capture()?;
+    let mut decoder = Decoder::new(writer)?;
+    decoder.set_events(events);
+    let mut last_ns = 0;
+    while let Some(packet) = stream.next() {
+        last_ns = packet.timestamp_ns;
+        for count in stream.take_losses() {
+            decoder.handle_loss(count, packet.timestamp_ns);
+        }
+        decoder.handle_raw_packet(&packet.bytes, packet.timestamp_ns)?;
+    }
+    for count in stream.take_losses() {
+        decoder.handle_loss(count, last_ns);
+    }
+    decoder.finish()?;
+    if let Some(timer) = timer {
+        handle_thread_panic(timer.join())??;
+    }
+    Ok(())
+}
+
+/// Load a capture file, or standard input if named "-".
+pub fn load_capture(name: &str, format: Option<InputFormat>)
+    -> Result<CaptureReader, Error>
+{
+    let source: Box<dyn Read> = if name == "-" {
+        Box::new(std::io::stdin())
+    } else {
+        Box::new(File::open(name)
+            .with_context(|| format!("Failed to open {name}"))?)
+    };
+    let format = format.or_else(||
+        iti1480a::is_capture_name(name).then_some(InputFormat::Iti1480a));
+    decoder::load_capture(source, format)
+        .with_context(|| format!("Failed to load {name}"))
+}
+
+/// Where to write the output of a command.
+fn output(options: &Options) -> Result<Box<dyn Write>, Error> {
+    Ok(match options.value("--output") {
+        None | Some("-") => Box::new(std::io::stdout()),
+        Some(path) => Box::new(File::create(path)
+            .with_context(|| format!("Failed to create {path}"))?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_options() {
+        let options = Options::parse(
+            &args("--device 3 --endpoint=1in -o out.txt --hex capture.pcap"),
+            &[], &["--hex"]).unwrap();
+        assert_eq!(options.value("--device"), Some("3"));
+        assert_eq!(options.value("--endpoint"), Some("1in"));
+        assert_eq!(options.value("--output"), Some("out.txt"));
+        assert!(options.flag("--hex"));
+        assert_eq!(options.file.as_deref(), Some("capture.pcap"));
+        for bad in ["--bogus a.pcap", "a.pcap b.pcap", "--device"] {
+            assert!(Options::parse(&args(bad), &[], &[]).is_err());
+        }
+        assert_eq!(parse_endpoint("1in").unwrap(), (1, Some(Direction::In)));
+        assert_eq!(parse_endpoint("2OUT").unwrap(),
+                   (2, Some(Direction::Out)));
+        assert_eq!(parse_endpoint("0").unwrap(), (0, None));
+        assert!(parse_endpoint("16").is_err());
+        assert!(parse_endpoint("in").is_err());
+        assert_eq!(parse_time_range("1.5..2").unwrap(),
+                   1_500_000_000..2_000_000_000);
+        assert_eq!(parse_time_range("..0.001").unwrap(), 0..1_000_000);
+        assert_eq!(parse_time_range("3..").unwrap(),
+                   3_000_000_000..Timestamp::MAX);
+        assert!(parse_time_range("2..1").is_err());
+        assert!(parse_time_range("2").is_err());
+    }
+
+    #[test]
+    fn test_selection() {
+        let path = "./tests/mouse/capture.pcap";
+        let mut capture = load_capture(path, None).unwrap();
+        let all = Selection::default().items(&mut capture).unwrap();
+        assert!(all.len() > 3);
+        let limited = Selection {
+            max_items: Some(3),
+            .. Selection::default()
+        };
+        assert_eq!(limited.items(&mut capture).unwrap(), all[..3]);
+        let first = capture.item_time_range(&all[0]).unwrap();
+        let early = Selection {
+            time_range: Some(0..first.end),
+            .. Selection::default()
+        };
+        let items = early.items(&mut capture).unwrap();
+        assert_eq!(items[0], all[0]);
+        assert!(items.len() < all.len());
+        let options = Options::parse(
+            &args("--filter hide=SOF --endpoint 1in"), &[], &[]).unwrap();
+        let selection = Selection::from_options(&options).unwrap();
+        assert!(selection.filter.hide_sof);
+        for item in selection.items(&mut capture).unwrap() {
+            if let Some(endpoint) = capture.item_endpoint(&item).unwrap() {
+                assert_eq!(endpoint.number().0, 1);
+                assert_eq!(endpoint.direction(), Direction::In);
+            }
+        }
+        let setups = Selection {
+            query: Some(Criteria::parse("pid=SETUP").unwrap()),
+            .. Selection::default()
+        };
+        let items = setups.items(&mut capture).unwrap();
+        assert!(!items.is_empty());
+        assert!(items.len() < all.len());
+        for item in &items {
+            assert!(capture.item_control_transfer(item).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_extract() {
+        let path = "./tests/mouse/capture.pcap";
+        let mut capture = load_capture(path, None).unwrap();
+        let mut extract_data = |endpoint: &str| {
+            let options = Options::parse(
+                &args(&format!("--device 4 --endpoint {endpoint}")),
+                &[], &[]).unwrap();
+            let selection = Selection::from_options(&options).unwrap();
+            let mut data = Vec::new();
+            extract(&mut capture, &selection, &mut data)
+                .map(|size| {
+                    assert_eq!(size, data.len() as u64);
+                    data
+                })
+        };
+        let data = extract_data("1in").unwrap();
+        assert!(!data.is_empty());
+        assert_eq!(extract_data("1").unwrap(), data);
+        assert!(!extract_data("0").unwrap().is_empty());
+        assert!(extract_data("1out").is_err());
+        assert!(extract_data("2").is_err());
+    }
+}
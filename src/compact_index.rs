@@ -1,5 +1,6 @@
 use std::cmp::max;
 use std::fmt::Debug;
+use std::io::{Read, Write};
 use std::iter::{Peekable, once};
 use std::marker::PhantomData;
 use std::ops::{Add, AddAssign, Range, Sub, SubAssign};
@@ -12,11 +13,15 @@ use itertools::{structs::Zip, multizip};
 use crate::data_stream::{data_stream, DataReader, DataWriter, DataIterator};
 use crate::id::Id;
 use crate::index_stream::{index_stream, IndexReader, IndexWriter, IndexIterator};
+use crate::stream::read_length;
 use crate::util::{fmt_count, fmt_size};
 
 type Offset = Id<u8>;
 type SegmentId = Id<u8>;
 
+/// Number of complete segments cached by each reader.
+const SEGMENT_CACHE_SIZE: usize = 8;
+
 /// Unique handle for append-only write access to a compact index stream.
 pub struct CompactWriter<Position, Value, const MIN_WIDTH: usize = 1> {
     /// Committed length of this index available to readers.
@@ -57,6 +62,8 @@ pub struct CompactReader<Position, Value> {
     segment_width_reader: DataReader<u8>,
     /// Byte stream containing deltas for all segments.
     data_reader: DataReader<u8>,
+    /// Recently used complete segments, most recent first.
+    segment_cache: Vec<CachedSegment<Position, Value>>,
 }
 
 /// Iterator over values in a compact index stream.
@@ -92,6 +99,27 @@ struct Segment<Position, Value> {
     delta_width: Option<u8>,
 }
 
+/// A complete segment cached by a reader.
+///
+/// Only segments which have been followed by another are cached, since
+/// the last segment may still be extended by the writer.
+#[derive(Clone)]
+struct CachedSegment<Position, Value> {
+    positions: Range<Position>,
+    base_value: Value,
+    data_offset: Offset,
+    delta_width: u8,
+}
+
+/// The segment in which a range of positions starts.
+enum SegmentLookup<Position, Value> {
+    /// A complete segment containing the whole range.
+    Complete(CachedSegment<Position, Value>),
+    /// The ID and start position of a segment which the range extends
+    /// beyond, or which may still be extended by the writer.
+    Partial(SegmentId, Position),
+}
+
 type CompactPair<P, V, const W: usize> =
     (CompactWriter<P, V, W>, CompactReader<P, V>);
 
@@ -128,6 +156,7 @@ pub fn compact_index<P, V, const W: usize>()
         segment_offset_reader,
         segment_width_reader,
         data_reader,
+        segment_cache: Vec::new(),
     };
     Ok((writer, reader))
 }
@@ -196,6 +225,40 @@ where Position: Copy + From<u64> + Into<u64>,
     }
 }
 
+impl<Position, Value, const MIN_WIDTH: usize>
+CompactWriter<Position, Value, MIN_WIDTH>
+where Position: From<u64> + Into<u64>,
+      Value: From<u64> + Into<u64>
+{
+    /// Restore the contents of an index saved by [CompactReader::save].
+    ///
+    /// The index must be empty.
+    pub fn restore(&mut self, source: &mut impl Read) -> Result<(), Error> {
+        if self.length != 0 {
+            bail!("Cannot restore into an index which is not empty")
+        }
+        let length = read_length(source)?;
+        self.segment_start_writer.restore(source)?;
+        self.segment_base_writer.restore(source)?;
+        self.segment_offset_writer.restore(source)?;
+        self.segment_width_writer.restore(source)?;
+        self.data_writer.restore(source)?;
+        let base_value = read_length(source)?;
+        let mut delta_width = [0];
+        source.read_exact(&mut delta_width)
+            .context("Saved index is truncated")?;
+        self.data_offset = Offset::from(self.data_writer.size());
+        self.current_base_value = (length > 0).then(|| base_value.into());
+        self.current_delta_width = match delta_width[0] {
+            0 => None,
+            width => Some(width as usize),
+        };
+        self.length = length;
+        self.shared_length.store(length, Release);
+        Ok(())
+    }
+}
+
 impl<Position, Value> CompactReader<Position, Value>
 where
     Position: Copy + From<u64> + Into<u64> + Ord
@@ -220,16 +283,54 @@ where
             self.data_reader.size()
     }
 
+    /// Write the current contents of the index.
+    ///
+    /// The state of the last segment is included, so that a writer
+    /// restored from the saved index can continue to append to it.
+    pub fn save(&mut self, dest: &mut impl Write) -> Result<(), Error> {
+        dest.write_all(&self.len().to_le_bytes())?;
+        self.segment_start_reader.save(dest)?;
+        self.segment_base_reader.save(dest)?;
+        self.segment_offset_reader.save(dest)?;
+        self.segment_width_reader.save(dest)?;
+        self.data_reader.save(dest)?;
+        let segment_count = self.segment_base_reader.len();
+        let (base_value, delta_width) = match segment_count {
+            0 => (0, 0),
+            count => {
+                let last_segment = SegmentId::from(count - 1);
+                let base_value = self.segment_base_reader.get(last_segment)?;
+                // A segment's delta width is only stored once it has
+                // a second value.
+                let delta_width =
+                    if self.segment_width_reader.len() == count {
+                        self.segment_width_reader.get(last_segment)?
+                    } else {
+                        0
+                    };
+                (base_value.into(), delta_width)
+            }
+        };
+        dest.write_all(&u64::to_le_bytes(base_value))?;
+        dest.write_all(&[delta_width])?;
+        Ok(())
+    }
+
     /// Get a single value from the index, by position.
     pub fn get(&mut self, position: Position) -> Result<Value, Error> {
+        use SegmentLookup::*;
         // Check position is valid.
         let length = self.len();
         if position.into() >= length {
             bail!("requested position {position:?} but index length is {length}")
         }
-        // Find the segment required.
-        let segment_id = self.segment_start_reader.bisect_right(&position)? - 1;
-        let segment_start = self.segment_start_reader.get(segment_id)?;
+        // Use a cached segment if possible.
+        let range = position..(position + 1);
+        let (segment_id, segment_start) = match self.lookup_segment(&range)? {
+            Complete(segment) =>
+                return Ok(self.segment_values(&segment, &range)?[0]),
+            Partial(segment_id, segment_start) => (segment_id, segment_start),
+        };
         let base_value = self.segment_base_reader.get(segment_id)?;
         // If we only need the base value, return it.
         if position == segment_start {
@@ -254,16 +355,24 @@ where
     pub fn get_range(&mut self, range: &Range<Position>)
         -> Result<Vec<Value>, Error>
     {
+        use SegmentLookup::*;
         // Check range is valid.
         let length = self.len();
         if range.end.into() > length {
             bail!("requested range {range:?} but index length is {length}")
         }
+        if range.is_empty() {
+            return Ok(Vec::new())
+        }
+        // If the range lies within a single complete segment, use the cache.
+        let first = match self.lookup_segment(range)? {
+            Complete(segment) => return self.segment_values(&segment, range),
+            Partial(segment_id, _) => segment_id,
+        };
         // Allocate space for the result.
         let total_count: usize = (range.end - range.start).try_into().unwrap();
         let mut values = Vec::with_capacity(total_count);
         // Determine which segments we need to read from.
-        let first = self.segment_start_reader.bisect_right(&range.start)? - 1;
         let last = self.segment_start_reader.bisect_left(&range.end)?;
         let seg_range = first..last;
         let segment_starts = self.segment_start_reader.get_range(&seg_range)?;
@@ -334,6 +443,81 @@ where
         Ok(values)
     }
 
+    /// Find the segment in which a non-empty range of positions starts,
+    /// and cache it if it is complete and contains the whole range.
+    fn lookup_segment(&mut self, range: &Range<Position>)
+        -> Result<SegmentLookup<Position, Value>, Error>
+    {
+        use SegmentLookup::*;
+        // Look for the segment in the cache, and move it to the front.
+        if let Some(index) = self.segment_cache
+            .iter()
+            .position(|segment|
+                segment.positions.start <= range.start &&
+                range.end <= segment.positions.end)
+        {
+            self.segment_cache[..=index].rotate_right(1);
+            return Ok(Complete(self.segment_cache[0].clone()))
+        }
+        // Otherwise, find which segment the range starts in.
+        let segment_id =
+            self.segment_start_reader.bisect_right(&range.start)? - 1;
+        let start = self.segment_start_reader.get(segment_id)?;
+        let next_id = segment_id + 1;
+        // The last segment may still be growing, so is not cached.
+        if next_id.value >= self.segment_start_reader.len() {
+            return Ok(Partial(segment_id, start))
+        }
+        let end = self.segment_start_reader.get(next_id)?;
+        if range.end > end {
+            return Ok(Partial(segment_id, start))
+        }
+        let segment = CachedSegment {
+            positions: start..end,
+            base_value: self.segment_base_reader.get(segment_id)?,
+            data_offset: self.segment_offset_reader.get(segment_id)?,
+            delta_width: self.segment_width_reader.get(segment_id)?,
+        };
+        self.segment_cache.truncate(SEGMENT_CACHE_SIZE - 1);
+        self.segment_cache.insert(0, segment.clone());
+        Ok(Complete(segment))
+    }
+
+    /// Get values for a range of positions within a single segment.
+    fn segment_values(&mut self,
+                      segment: &CachedSegment<Position, Value>,
+                      range: &Range<Position>)
+        -> Result<Vec<Value>, Error>
+    {
+        let count: usize = (range.end - range.start).try_into().unwrap();
+        let mut values = Vec::with_capacity(count);
+        let mut first = range.start;
+        // Include the base value in the result if needed.
+        if first == segment.positions.start {
+            values.push(segment.base_value);
+            first += 1;
+        }
+        if first < range.end {
+            // Fetch all the required delta bytes.
+            let width = segment.delta_width as usize;
+            let first_delta = first - segment.positions.start - 1;
+            let delta_start =
+                segment.data_offset + first_delta * width as u64;
+            let delta_end = delta_start + (range.end - first) * width as u64;
+            let all_delta_bytes =
+                self.data_reader.get_range(&(delta_start..delta_end))?;
+            // Reconstruct deltas and values to include in result.
+            let mut delta_bytes = [0; 8];
+            for low_bytes in all_delta_bytes.chunks_exact(width) {
+                delta_bytes[..width].copy_from_slice(low_bytes);
+                let delta = u64::from_le_bytes(delta_bytes);
+                values.push(segment.base_value + delta);
+            }
+        }
+        assert!(values.len() == count);
+        Ok(values)
+    }
+
     /// Create an iterator over values from the index.
     pub fn iter(&mut self, range: &Range<Position>)
         -> Result<CompactIterator<Position, Value>, Error>
@@ -685,4 +869,66 @@ mod tests {
         let bl = reader.bisect_left(&big).unwrap();
         assert!(bl == end);
     }
+
+    #[test]
+    fn test_segment_cache() {
+        type Pair = CompactPair<Id<Id<u8>>, Id<u8>, 1>;
+        let (mut writer, mut reader): Pair = compact_index().unwrap();
+        let values: Vec<Id<u8>> = [5, 6, 8, 300, 301, 70000, 70001]
+            .into_iter()
+            .map(Id::<u8>::from)
+            .collect();
+        for value in &values {
+            writer.push(*value).unwrap();
+        }
+        let position = Id::<Id<u8>>::from;
+        // Values in complete segments are cached.
+        assert_eq!(reader.get(position(1)).unwrap(), values[1]);
+        assert_eq!(reader.segment_cache.len(), 1);
+        assert_eq!(reader.get(position(2)).unwrap(), values[2]);
+        assert_eq!(reader.segment_cache.len(), 1);
+        let range = position(3)..position(5);
+        assert_eq!(reader.get_range(&range).unwrap(), values[3..5]);
+        assert_eq!(reader.segment_cache.len(), 2);
+        assert_eq!(reader.segment_cache[0].positions, range);
+        // A range spanning segments is not served from the cache.
+        let range = position(1)..position(4);
+        assert_eq!(reader.get_range(&range).unwrap(), values[1..4]);
+        assert_eq!(reader.segment_cache.len(), 2);
+        assert_eq!(reader.segment_cache[0].positions, position(3)..position(5));
+        // The last segment may still grow, so is not cached.
+        assert_eq!(reader.get(position(5)).unwrap(), values[5]);
+        assert_eq!(reader.get(position(6)).unwrap(), values[6]);
+        let range = position(5)..position(7);
+        assert_eq!(reader.get_range(&range).unwrap(), values[5..7]);
+        assert_eq!(reader.segment_cache.len(), 2);
+    }
+
+    #[test]
+    fn test_save_restore() {
+        type Pair = CompactPair<Id<Id<u8>>, Id<u8>, 1>;
+        let (mut writer, mut reader): Pair = compact_index().unwrap();
+        let values: Vec<Id<u8>> = [5, 6, 8, 300, 301, 70000, 70001]
+            .into_iter()
+            .map(Id::<u8>::from)
+            .collect();
+        for split in 0..values.len() {
+            let (mut restored, mut restored_reader): Pair =
+                compact_index().unwrap();
+            let mut saved = Vec::new();
+            reader.save(&mut saved).unwrap();
+            restored.restore(&mut saved.as_slice()).unwrap();
+            assert_eq!(restored.len(), split as u64);
+            assert_eq!(restored.size(), writer.size());
+            // Appending to the restored index must give the same result
+            // as appending to the original.
+            for value in &values[split..] {
+                restored.push(*value).unwrap();
+            }
+            let range = Id::<Id<u8>>::from(0)..
+                Id::<Id<u8>>::from(values.len() as u64);
+            assert_eq!(restored_reader.get_range(&range).unwrap(), values);
+            writer.push(values[split]).unwrap();
+        }
+    }
 }
@@ -0,0 +1,353 @@
+//! Comparison of the control requests made in two captures.
+//!
+//! The control transfers of each capture are listed in order, and the two
+//! lists aligned so that the same requests are paired up, as in a diff.
+//! Requests are matched by their setup fields, ignoring the address given
+//! to the device, and a matched pair differs if the length, result or data
+//! of the transfers differ. This shows where a device's behaviour in one
+//! capture diverges from that in another, such as a failing unit compared
+//! with a working one.
+
+use std::fmt::Write;
+
+use anyhow::{Error, bail};
+use bytemuck::bytes_of;
+
+use crate::capture::{CaptureReader, TrafficItem, TrafficItemId};
+use crate::usb::{
+    ControlResult,
+    ControlTransfer,
+    RequestType,
+    StandardRequest,
+};
+
+/// Largest number of differences found between two captures.
+const MAX_DIFFERENCES: usize = 10_000;
+
+/// Indices of elements paired by an alignment, or None where an element
+/// has no counterpart.
+type Pair = (Option<usize>, Option<usize>);
+
+/// Which requests to align two captures by.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Alignment {
+    /// The standard requests with which devices are enumerated.
+    Enumeration,
+    /// All control requests, including class and vendor requests.
+    Requests,
+}
+
+impl Alignment {
+    pub const ALL: [Alignment; 2] = [
+        Alignment::Enumeration,
+        Alignment::Requests,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Alignment::Enumeration => "enumeration",
+            Alignment::Requests => "requests",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Alignment::Enumeration => "Enumeration sequence",
+            Alignment::Requests => "All control requests",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Alignment> {
+        Alignment::ALL.into_iter().find(|alignment| alignment.name() == name)
+    }
+
+    fn includes(&self, transfer: &ControlTransfer) -> bool {
+        match self {
+            Alignment::Enumeration => matches!(
+                transfer.fields.type_fields.request_type(),
+                RequestType::Standard),
+            Alignment::Requests => true,
+        }
+    }
+}
+
+/// A control request made in a capture.
+pub struct Request {
+    /// The transfer item for the request.
+    pub item: TrafficItem,
+    /// Summary of the request, as shown in the traffic view.
+    pub summary: String,
+    /// Setup fields that identify the request.
+    key: [u8; 6],
+    length: u16,
+    result: ControlResult,
+    data: Vec<u8>,
+}
+
+impl Request {
+    fn new(item: TrafficItem, transfer: ControlTransfer) -> Request {
+        let fields = &transfer.fields;
+        // The address given to a device may differ between captures.
+        let value = match (fields.type_fields.request_type(),
+                           StandardRequest::from(fields.request))
+        {
+            (RequestType::Standard, StandardRequest::SetAddress) => 0,
+            _ => fields.value,
+        };
+        let type_byte = bytes_of(&fields.type_fields)[0];
+        let mut key = [type_byte, fields.request, 0, 0, 0, 0];
+        key[2..4].copy_from_slice(&value.to_le_bytes());
+        key[4..6].copy_from_slice(&fields.index.to_le_bytes());
+        Request {
+            item,
+            summary: transfer.summary(),
+            key,
+            length: fields.length,
+            result: transfer.result,
+            data: transfer.data,
+        }
+    }
+
+    /// Whether the request was made and answered the same way.
+    fn same(&self, other: &Request) -> bool {
+        self.length == other.length &&
+            self.result == other.result &&
+            self.data == other.data
+    }
+}
+
+/// A row of the comparison, pairing requests from each capture.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Row {
+    /// The same request, made and answered the same way in both.
+    Same(usize, usize),
+    /// The same request, which differs in its length, result or data.
+    Differs(usize, usize),
+    /// A request made only in the first capture.
+    First(usize),
+    /// A request made only in the second capture.
+    Second(usize),
+}
+
+impl Row {
+    /// Whether the captures diverge at this row.
+    pub fn diverges(&self) -> bool {
+        !matches!(self, Row::Same(..))
+    }
+}
+
+/// The result of comparing two captures.
+pub struct Comparison {
+    pub first: Vec<Request>,
+    pub second: Vec<Request>,
+    pub rows: Vec<Row>,
+}
+
+impl Comparison {
+    /// Index of the first row at which the captures diverge, if any.
+    pub fn divergence(&self) -> Option<usize> {
+        self.rows.iter().position(Row::diverges)
+    }
+
+    /// Describe the comparison, one row per line.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for row in &self.rows {
+            let (mark, request) = match *row {
+                Row::Same(i, _) => (' ', &self.first[i]),
+                Row::First(i) => ('-', &self.first[i]),
+                Row::Second(j) => ('+', &self.second[j]),
+                Row::Differs(i, j) => {
+                    writeln!(text, "- {}", self.first[i].summary).unwrap();
+                    ('+', &self.second[j])
+                },
+            };
+            writeln!(text, "{mark} {}", request.summary).unwrap();
+        }
+        text
+    }
+}
+
+/// List the control requests made in a capture.
+pub fn requests(capture: &mut CaptureReader, alignment: Alignment)
+    -> Result<Vec<Request>, Error>
+{
+    let mut requests = Vec::new();
+    for index in 0..capture.item_index.len() {
+        let item_id = TrafficItemId::from(index);
+        let item = TrafficItem::Transfer(capture.item_index.get(item_id)?);
+        if let Some(transfer) = capture.item_control_transfer(&item)? {
+            if alignment.includes(&transfer) {
+                requests.push(Request::new(item, transfer));
+            }
+        }
+    }
+    Ok(requests)
+}
+
+/// Compare the control requests made in two captures.
+pub fn compare(first: &mut CaptureReader,
+               second: &mut CaptureReader,
+               alignment: Alignment)
+    -> Result<Comparison, Error>
+{
+    let first = requests(first, alignment)?;
+    let second = requests(second, alignment)?;
+    let keys = |requests: &[Request]| -> Vec<[u8; 6]> {
+        requests.iter().map(|request| request.key).collect()
+    };
+    let rows = align(&keys(&first), &keys(&second))?
+        .into_iter()
+        .map(|pair| match pair {
+            (Some(i), Some(j)) if first[i].same(&second[j]) =>
+                Row::Same(i, j),
+            (Some(i), Some(j)) => Row::Differs(i, j),
+            (Some(i), None) => Row::First(i),
+            (None, Some(j)) => Row::Second(j),
+            (None, None) => unreachable!(),
+        })
+        .collect();
+    Ok(Comparison { first, second, rows })
+}
+
+/// Align two sequences, pairing up as many equal elements as possible, in
+/// order. Elements left unpaired are paired with None.
+///
+/// This is Myers' difference algorithm, which takes time proportional to
+/// the number of differences times the length of the sequences.
+fn align<T: PartialEq>(a: &[T], b: &[T]) -> Result<Vec<Pair>, Error>
+{
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    // The furthest point reached along each diagonal k = x - y, after each
+    // number of differences d, indexed by k + d.
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'search: for d in 0..=(n + m) {
+        if d as usize > MAX_DIFFERENCES {
+            bail!("The captures differ in more than {MAX_DIFFERENCES} \
+                   requests");
+        }
+        let mut reached = vec![0; 2 * d as usize + 1];
+        for k in (-d..=d).step_by(2) {
+            let mut x = match trace.last() {
+                None => 0,
+                Some(previous) => {
+                    let from = |k: isize| previous[(k + d - 1) as usize];
+                    if k == -d || (k != d && from(k - 1) < from(k + 1)) {
+                        from(k + 1)
+                    } else {
+                        from(k - 1) + 1
+                    }
+                }
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            reached[(k + d) as usize] = x;
+            if x >= n && y >= m {
+                trace.push(reached);
+                break 'search;
+            }
+        }
+        trace.push(reached);
+    }
+    // Work back from the end to find the path taken.
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        let k = x - y;
+        let (previous_x, previous_y) = if d == 0 {
+            (0, 0)
+        } else {
+            let previous = &trace[d as usize - 1];
+            let from = |k: isize| previous[(k + d - 1) as usize];
+            let previous_k =
+                if k == -d || (k != d && from(k - 1) < from(k + 1)) {
+                    k + 1
+                } else {
+                    k - 1
+                };
+            let previous_x = from(previous_k);
+            (previous_x, previous_x - previous_k)
+        };
+        // Equal elements, then the one element added or removed.
+        while x > previous_x && y > previous_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((Some(x as usize), Some(y as usize)));
+        }
+        if d > 0 {
+            if x == previous_x {
+                pairs.push((None, Some(previous_y as usize)));
+            } else {
+                pairs.push((Some(previous_x as usize), None));
+            }
+        }
+        (x, y) = (previous_x, previous_y);
+    }
+    pairs.reverse();
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::load_test_capture;
+
+    #[test]
+    fn test_align() {
+        let align = |a: &str, b: &str| {
+            let a: Vec<char> = a.chars().collect();
+            let b: Vec<char> = b.chars().collect();
+            align(&a, &b)
+                .unwrap()
+                .into_iter()
+                .map(|pair| match pair {
+                    (Some(i), Some(_)) => a[i].to_string(),
+                    (Some(i), None) => format!("-{}", a[i]),
+                    (None, Some(j)) => format!("+{}", b[j]),
+                    (None, None) => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(align("", ""), "");
+        assert_eq!(align("abc", "abc"), "a b c");
+        assert_eq!(align("abc", ""), "-a -b -c");
+        assert_eq!(align("", "xy"), "+x +y");
+        assert_eq!(align("abcabba", "cbabac"), "-a -b c +b a b -b a +c");
+        assert_eq!(align("axbyc", "abc"), "a -x b -y c");
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut first = load_test_capture("hackrf-connect");
+        let mut second = load_test_capture("hackrf-connect");
+        let comparison =
+            compare(&mut first, &mut second, Alignment::Requests).unwrap();
+        assert!(!comparison.rows.is_empty());
+        assert_eq!(comparison.divergence(), None);
+        assert_eq!(comparison.first.len(), comparison.second.len());
+
+        let mut second = load_test_capture("mouse");
+        for alignment in Alignment::ALL {
+            let comparison =
+                compare(&mut first, &mut second, alignment).unwrap();
+            let index = comparison.divergence().unwrap();
+            assert!(comparison.rows[index].diverges());
+            // Every request appears once, in order.
+            let firsts: Vec<usize> = comparison.rows
+                .iter()
+                .filter_map(|row| match *row {
+                    Row::Same(i, _) | Row::Differs(i, _) | Row::First(i) =>
+                        Some(i),
+                    Row::Second(_) => None,
+                })
+                .collect();
+            assert_eq!(firsts, (0..comparison.first.len()).collect::<Vec<_>>());
+            let text = comparison.to_text();
+            assert!(text.contains("\n+ ") || text.starts_with("+ "));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Decompression of compressed capture files as they are read.
+//!
+//! Files compressed with gzip, zstd or xz are recognised by the magic
+//! bytes at their start. Any other file is read as it is.
+
+use std::io::{BufRead, BufReader, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Error;
+use flate2::bufread::MultiGzDecoder;
+use xz2::bufread::XzDecoder;
+
+/// Methods by which a file may be compressed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// Detect the compression of a file from its first bytes.
+    pub fn detect(start: &[u8]) -> Option<Compression> {
+        if start.starts_with(&[0x1F, 0x8B]) {
+            Some(Compression::Gzip)
+        } else if start.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            Some(Compression::Zstd)
+        } else if start.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Counts the bytes read from a source.
+pub struct Counter<Source: Read> {
+    source: Source,
+    count: Arc<AtomicU64>,
+}
+
+impl<Source: Read> Read for Counter<Source> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = self.source.read(buf)?;
+        self.count.fetch_add(length as u64, Ordering::Relaxed);
+        Ok(length)
+    }
+}
+
+type Input<Source> = BufReader<Counter<Source>>;
+
+/// Reads a file, decompressing it if it was compressed.
+pub enum Decompressor<Source: Read> {
+    Plain(Input<Source>),
+    Gzip(MultiGzDecoder<Input<Source>>),
+    Zstd(zstd::Decoder<'static, Input<Source>>),
+    Xz(XzDecoder<Input<Source>>),
+}
+
+impl<Source: Read> Decompressor<Source> {
+    /// Start reading a file, detecting how it was compressed.
+    ///
+    /// Also returns the number of bytes read from the file, which will be
+    /// updated as it is read, if the file was compressed.
+    pub fn new(source: Source)
+        -> Result<(Decompressor<Source>, Option<Arc<AtomicU64>>), Error>
+    {
+        let count = Arc::new(AtomicU64::new(0));
+        let counter = Counter { source, count: count.clone() };
+        let mut input = BufReader::new(counter);
+        let compression = Compression::detect(input.fill_buf()?);
+        let decompressor = match compression {
+            None =>
+                Decompressor::Plain(input),
+            Some(Compression::Gzip) =>
+                Decompressor::Gzip(MultiGzDecoder::new(input)),
+            Some(Compression::Zstd) =>
+                Decompressor::Zstd(zstd::Decoder::with_buffer(input)?),
+            Some(Compression::Xz) =>
+                Decompressor::Xz(XzDecoder::new_multi_decoder(input)),
+        };
+        Ok((decompressor, compression.map(|_| count)))
+    }
+}
+
+impl<Source: Read> Read for Decompressor<Source> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Decompressor::Plain(input) => input.read(buf),
+            Decompressor::Gzip(decoder) => decoder.read(buf),
+            Decompressor::Zstd(decoder) => decoder.read(buf),
+            Decompressor::Xz(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::pcap::Loader;
+
+    #[test]
+    fn test_compressed_import() {
+        let text = "\
+1.000100: 2D 00 10
+1.000103: C3 80 06 00 01 00 00 12 00 E0 F4
+1.000110: D2
+";
+        let mut gzip = flate2::write::GzEncoder::new(
+            Vec::new(), flate2::Compression::default());
+        gzip.write_all(text.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(text.as_bytes(), 0).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(text.as_bytes()).unwrap();
+        let xz = xz.finish().unwrap();
+        for (data, compression) in [
+            (&gzip, Compression::Gzip),
+            (&zstd, Compression::Zstd),
+            (&xz, Compression::Xz),
+        ] {
+            assert_eq!(Compression::detect(data), Some(compression));
+            let mut loader = Loader::open(data.as_slice()).unwrap();
+            let mut packets = Vec::new();
+            while let Some(result) = loader.next() {
+                let (packet, timestamp) = result.unwrap();
+                packets.push((packet.data.to_vec(), timestamp));
+            }
+            assert_eq!(packets.len(), 3, "{compression:?}");
+            assert_eq!(packets[2], (vec![0xD2], 10_000));
+            // Progress is measured through the compressed file.
+            assert_eq!(loader.bytes_read, text.len() as u64);
+            assert_eq!(loader.file_bytes_read(), data.len() as u64);
+        }
+        assert_eq!(Compression::detect(text.as_bytes()), None);
+    }
+}
@@ -0,0 +1,199 @@
+//! The menu of actions on an item, shown by right-clicking it in the
+//! traffic view.
+
+use anyhow::Error;
+use gtk::prelude::*;
+use gtk::gio::{self, Cancellable, FileCreateFlags, Menu};
+use gtk::{
+    Align,
+    ColumnView,
+    GestureClick,
+    Label,
+    Orientation,
+    ScrolledWindow,
+    SignalListItemFactory,
+    TextView,
+};
+
+use crate::capture::Endpoint;
+use crate::hex_dump::hex_dump;
+use crate::util::fmt_size;
+
+/// Ways to change the traffic filter from the endpoint of an item.
+#[derive(Clone, Copy)]
+pub enum EndpointFilter {
+    /// Show only the traffic of the endpoint's device.
+    Device,
+    /// Show only the traffic of the endpoint.
+    Endpoint,
+    /// Hide the traffic of the endpoint.
+    Ignore,
+}
+
+impl EndpointFilter {
+    pub const ALL: [EndpointFilter; 3] = [
+        EndpointFilter::Device,
+        EndpointFilter::Endpoint,
+        EndpointFilter::Ignore,
+    ];
+
+    /// Name of the action which changes the filter.
+    pub fn action(&self) -> &'static str {
+        use EndpointFilter::*;
+        match self {
+            Device => "filter-device",
+            Endpoint => "filter-endpoint",
+            Ignore => "ignore-endpoint",
+        }
+    }
+
+    /// Label of the action in the menu.
+    fn label(&self) -> &'static str {
+        use EndpointFilter::*;
+        match self {
+            Device => "Show only this device",
+            Endpoint => "Show only this endpoint",
+            Ignore => "Ignore this endpoint",
+        }
+    }
+
+    /// Change the text of a filter to apply to an endpoint, given by its
+    /// device address and endpoint number.
+    pub fn apply(&self, text: &str, address: u8, number: u8) -> String {
+        use EndpointFilter::*;
+        // Showing only one device or endpoint replaces any other choice
+        // of them, but keeps the rest of the filter.
+        let mut terms: Vec<String> = text
+            .split_whitespace()
+            .filter(|term| match self {
+                Device | Endpoint => !term.starts_with("address=") &&
+                    !term.starts_with("endpoint="),
+                Ignore => true,
+            })
+            .map(str::to_string)
+            .collect();
+        match self {
+            Device => terms.push(format!("address={address}")),
+            Endpoint => terms.extend([
+                format!("address={address}"),
+                format!("endpoint={number}")]),
+            Ignore => terms.push(format!("ignore={address}.{number}")),
+        }
+        terms.join(" ")
+    }
+}
+
+/// Create the menu of actions on an item, to be shown over a widget.
+pub fn traffic_menu(parent: &impl IsA<gtk::Widget>) -> gtk::PopoverMenu {
+    let item_menu = Menu::new();
+    let filter_section = Menu::new();
+    for filter in EndpointFilter::ALL {
+        let action = format!("actions.{}", filter.action());
+        filter_section.append(Some(filter.label()), Some(&action));
+    }
+    item_menu.append_section(None, &filter_section);
+    let data_section = Menu::new();
+    data_section.append(Some("Follow stream"), Some("actions.follow-stream"));
+    data_section.append(
+        Some("Export payload..."), Some("actions.export-payload"));
+    item_menu.append_section(None, &data_section);
+    let note_section = Menu::new();
+    note_section.append(Some("Copy summary"), Some("actions.copy-text"));
+    note_section.append(Some("Add bookmark"), Some("actions.bookmark"));
+    item_menu.append_section(None, &note_section);
+    let menu = gtk::PopoverMenu::from_model(Some(&item_menu));
+    menu.set_has_arrow(false);
+    menu.set_halign(Align::Start);
+    menu.set_parent(parent);
+    menu
+}
+
+/// Show the menu of actions on an item when a row of a traffic view is
+/// right-clicked, after selecting the row.
+pub fn add_context_menu(view: &ColumnView,
+                        factories: &[SignalListItemFactory],
+                        menu: &gtk::PopoverMenu)
+{
+    for factory in factories {
+        // The view holds the factory, so must not be held by it.
+        let view = view.downgrade();
+        let menu = menu.clone();
+        factory.connect_setup(move |_, list_item| {
+            let Some(widget) = list_item.child() else { return };
+            let click = GestureClick::builder()
+                .button(gtk::gdk::BUTTON_SECONDARY)
+                .build();
+            let list_item = list_item.downgrade();
+            let view = view.clone();
+            let menu = menu.clone();
+            click.connect_pressed(move |click, _, x, y| {
+                let (Some(list_item), Some(view)) =
+                    (list_item.upgrade(), view.upgrade())
+                else {
+                    return
+                };
+                if let Some(selection) = view.model() {
+                    selection.select_item(list_item.position(), true);
+                }
+                let Some(parent) = menu.parent() else { return };
+                if let Some((x, y)) =
+                    click.widget().translate_coordinates(&parent, x, y)
+                {
+                    menu.set_pointing_to(Some(
+                        &gtk::gdk::Rectangle::new(x as i32, y as i32, 1, 1)));
+                    menu.popup();
+                }
+            });
+            widget.add_controller(click);
+        });
+    }
+}
+
+/// Show the data sent on an endpoint as a hex dump, given the start of
+/// the data and the length of all of it.
+pub fn stream_view(endpoint: &Endpoint, data: &[u8], length: usize)
+    -> gtk::Box
+{
+    let summary = if length > data.len() {
+        format!("First {} of {} sent on endpoint {endpoint}",
+                fmt_size(data.len() as u64), fmt_size(length as u64))
+    } else {
+        format!("{} sent on endpoint {endpoint}", fmt_size(length as u64))
+    };
+    let text_view = TextView::builder()
+        .editable(false)
+        .monospace(true)
+        .build();
+    text_view.buffer().set_text(&hex_dump(data));
+    let scrolled = ScrolledWindow::builder()
+        .min_content_width(640)
+        .min_content_height(400)
+        .vexpand(true)
+        .child(&text_view)
+        .build();
+    let content = gtk::Box::builder()
+        .orientation(Orientation::Vertical)
+        .spacing(5)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_top(5)
+        .margin_bottom(5)
+        .build();
+    content.append(&Label::builder()
+        .label(summary)
+        .halign(Align::Start)
+        .build());
+    content.append(&scrolled);
+    content
+}
+
+/// Save the data carried by an item to a file.
+pub fn save_payload(file: &gio::File, data: &[u8]) -> Result<(), Error> {
+    use std::io::Write;
+    let mut dest = file
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write();
+    dest.write_all(data)?;
+    dest.flush()?;
+    Ok(())
+}
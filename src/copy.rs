@@ -0,0 +1,202 @@
+//! Copying traffic items as text.
+//!
+//! An item can be copied as its summary, as a hex dump of the raw bytes
+//! of its packets, or with the data it carries written as a C array or a
+//! Python bytes literal. The data of a packet or transaction is the
+//! payload of its data packet. The data of a transfer is that transferred,
+//! preceded by the setup data for a control transfer.
+
+use anyhow::Error;
+
+use crate::capture::{
+    CaptureReader,
+    ItemSource,
+    PacketId,
+    PacketSubset,
+    TrafficItem,
+};
+use crate::hex_dump::hex_dump;
+use crate::search::payload;
+
+/// Maximum number of bytes copied.
+const COPY_LIMIT: usize = 1 << 20;
+
+/// Number of bytes written on each line of a C array.
+const C_BYTES_PER_LINE: usize = 12;
+
+/// A form in which to copy an item.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CopyFormat {
+    Summary,
+    HexDump,
+    CArray,
+    PythonBytes,
+}
+
+impl CopyFormat {
+    pub const ALL: [CopyFormat; 4] = [
+        CopyFormat::Summary,
+        CopyFormat::HexDump,
+        CopyFormat::CArray,
+        CopyFormat::PythonBytes,
+    ];
+
+    /// Label for the menu item.
+    pub fn label(&self) -> &'static str {
+        use CopyFormat::*;
+        match self {
+            Summary => "Copy as text",
+            HexDump => "Copy as hex dump",
+            CArray => "Copy data as C array",
+            PythonBytes => "Copy data as Python bytes",
+        }
+    }
+
+    /// Name of the action that copies in this form.
+    pub fn action(&self) -> &'static str {
+        use CopyFormat::*;
+        match self {
+            Summary => "copy-text",
+            HexDump => "copy-hex",
+            CArray => "copy-c-array",
+            PythonBytes => "copy-python-bytes",
+        }
+    }
+}
+
+/// The text to copy for an item.
+pub fn copy_text(capture: &mut CaptureReader,
+                 item: &TrafficItem,
+                 format: CopyFormat)
+    -> Result<String, Error>
+{
+    use CopyFormat::*;
+    Ok(match format {
+        Summary => capture.description(item, false)?,
+        HexDump => packet_dumps(capture, item)?,
+        CArray => c_array(&item_data(capture, item)?),
+        PythonBytes => python_bytes(&item_data(capture, item)?),
+    })
+}
+
+/// Hex dumps of the packets of an item, separated by blank lines.
+fn packet_dumps(capture: &mut CaptureReader, item: &TrafficItem)
+    -> Result<String, Error>
+{
+    let mut dumps = Vec::new();
+    let mut length = 0;
+    let ranges = capture.subset_packet_ranges(&PacketSubset::Item(*item))?;
+    for range in ranges {
+        for packet_id in (range.start.value..range.end.value)
+            .map(PacketId::from)
+        {
+            if length >= COPY_LIMIT {
+                break;
+            }
+            let packet = capture.packet(packet_id)?;
+            length += packet.len();
+            dumps.push(hex_dump(&packet));
+        }
+    }
+    Ok(dumps.join("\n\n"))
+}
+
+/// The data carried by an item.
+pub fn item_data(capture: &mut CaptureReader, item: &TrafficItem)
+    -> Result<Vec<u8>, Error>
+{
+    use TrafficItem::*;
+    let mut data = Vec::new();
+    match item {
+        Marker(_) => {},
+        Transfer(_) => data = capture.item_bytes(item, COPY_LIMIT)?,
+        Packet(..) | Transaction(..) => {
+            let subset = PacketSubset::Item(*item);
+            for range in capture.subset_packet_ranges(&subset)? {
+                for packet_id in (range.start.value..range.end.value)
+                    .map(PacketId::from)
+                {
+                    if let Some(bytes) = payload(&capture.packet(packet_id)?) {
+                        data.extend(bytes);
+                    }
+                }
+            }
+        },
+    }
+    Ok(data)
+}
+
+/// Write bytes as a C array.
+fn c_array(bytes: &[u8]) -> String {
+    let mut text = String::from("const uint8_t data[] = {\n");
+    for chunk in bytes.chunks(C_BYTES_PER_LINE) {
+        let line = chunk
+            .iter()
+            .map(|byte| format!("0x{byte:02x},"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        text += &format!("    {line}\n");
+    }
+    text += "};";
+    text
+}
+
+/// Write bytes as a Python bytes literal.
+fn python_bytes(bytes: &[u8]) -> String {
+    let mut text = String::from("b'");
+    for byte in bytes {
+        text += &format!("\\x{byte:02x}");
+    }
+    text.push('\'');
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::load_test_capture;
+
+    #[test]
+    fn test_copy_formats() {
+        assert_eq!(c_array(&[]), "const uint8_t data[] = {\n};");
+        assert_eq!(c_array(&(0..14).collect::<Vec<u8>>()),
+                   "const uint8_t data[] = {\n    \
+                    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, \
+                    0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,\n    \
+                    0x0c, 0x0d,\n};");
+        assert_eq!(python_bytes(&[]), "b''");
+        assert_eq!(python_bytes(&[0x80, 0x06, 0x41]), "b'\\x80\\x06\\x41'");
+
+        let mut reader = load_test_capture("mouse");
+
+        let summary =
+            "Getting device descriptor #0 for device 4, reading 18 bytes";
+        let (_, count) = reader.item_children(None::<&TrafficItem>).unwrap();
+        let transfer = (0..count)
+            .map(|index| {
+                let item = reader.item(None, index).unwrap();
+                (item, reader.description(&item, false).unwrap())
+            })
+            .find(|(_, description)| description == summary)
+            .unwrap()
+            .0;
+        let setup = reader.item(Some(&transfer), 0).unwrap();
+        let copy = |reader: &mut CaptureReader, item, format|
+            copy_text(reader, item, format).unwrap();
+
+        assert_eq!(copy(&mut reader, &transfer, CopyFormat::Summary),
+                   summary);
+        // The setup data, then the descriptor read.
+        let data = copy(&mut reader, &transfer, CopyFormat::PythonBytes);
+        assert!(data.starts_with(
+            "b'\\x80\\x06\\x00\\x01\\x00\\x00\\x12\\x00\\x12\\x01"));
+        assert_eq!(data.len(), "b''".len() + (8 + 18) * 4);
+        // The setup transaction carries only the setup data.
+        assert_eq!(copy(&mut reader, &setup, CopyFormat::PythonBytes),
+                   "b'\\x80\\x06\\x00\\x01\\x00\\x00\\x12\\x00'");
+        // Its SETUP, DATA0 and ACK packets.
+        let dumps = copy(&mut reader, &setup, CopyFormat::HexDump);
+        assert_eq!(dumps.split("\n\n").count(), 3);
+        assert!(dumps.starts_with("00000000  2D "));
+    }
+}
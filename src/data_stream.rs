@@ -1,3 +1,4 @@
+use std::io::{Read, Write};
 use std::marker::PhantomData;
 use std::mem::size_of;
 use std::ops::{Deref, Range};
@@ -35,6 +36,14 @@ struct Values<Data, Value> where Data: Deref<Target=[u8]> {
     data: Data,
 }
 
+/// Values from the stream, accessed in place where possible.
+enum Slice<Data, Value> where Data: Deref<Target=[u8]> {
+    /// Values within a single block.
+    InPlace(Values<Data, Value>),
+    /// Values copied from more than one block.
+    Copied(Vec<Value>),
+}
+
 /// Construct a new data stream with the default block size.
 ///
 /// Returns a unique writer and a cloneable reader.
@@ -98,6 +107,11 @@ where Value: Pod + Default
         let end = Id::<Value>::from_offset(size);
         Ok(start..end)
     }
+
+    /// Append the contents of a stream saved by [DataReader::save].
+    pub fn restore(&mut self, source: &mut impl Read) -> Result<(), Error> {
+        self.stream_writer.restore(source)
+    }
 }
 
 impl<Value, const S: usize> DataReader<Value, S>
@@ -118,6 +132,11 @@ where Value: Pod + Default
         self.stream_reader.len()
     }
 
+    /// Write the current contents of the stream.
+    pub fn save(&mut self, dest: &mut impl Write) -> Result<(), Error> {
+        self.stream_reader.save(dest)
+    }
+
     /// Get a single item from the stream.
     pub fn get(&mut self, id: Id<Value>) -> Result<Value, Error> {
         let byte_range = id.offset_range();
@@ -157,6 +176,26 @@ where Value: Pod + Default
         })
     }
 
+    /// Access a range of values in the stream.
+    ///
+    /// Values within a single block are accessed in place, and are only
+    /// copied if the range spans more than one block.
+    ///
+    pub fn slice(&mut self, range: &Range<Id<Value>>)
+        -> Result<impl Deref<Target=[Value]>, Error>
+    {
+        if range.is_empty() {
+            return Ok(Slice::Copied(Vec::new()))
+        }
+        let byte_range = range.start.offset()..range.end.offset();
+        let data = self.stream_reader.access(&byte_range)?;
+        if data.len() as u64 == byte_range.end - byte_range.start {
+            Ok(Slice::InPlace(Values { marker: PhantomData, data }))
+        } else {
+            Ok(Slice::Copied(self.get_range(range)?))
+        }
+    }
+
     /// Create an iterator over values in the stream.
     pub fn iter(&self, range: &Range<Id<Value>>) -> DataIterator<Value, S> {
         DataIterator {
@@ -178,6 +217,20 @@ where Data: Deref<Target=[u8]>,
     }
 }
 
+impl<Data, Value> Deref for Slice<Data, Value>
+where Data: Deref<Target=[u8]>,
+      Value: Pod
+{
+    type Target = [Value];
+
+    fn deref(&self) -> &[Value] {
+        match self {
+            Slice::InPlace(values) => values,
+            Slice::Copied(values) => values,
+        }
+    }
+}
+
 impl<Value, const S: usize> std::fmt::Display for DataWriter<Value, S>
 where Value: Pod + Default
 {
@@ -290,4 +343,26 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn test_data_stream_slice() {
+        let (mut writer, mut reader) = data_stream::<u8>().unwrap();
+        let data: Vec<u8> = (0..(MIN_BLOCK * 3))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        writer.append(&data).unwrap();
+        let block = MIN_BLOCK as u64;
+        // Ranges within a block, spanning blocks, and empty.
+        for (start, end) in [
+            (10, 20),
+            (block, block * 2),
+            (block - 5, block + 5),
+            (100, block * 3),
+            (block, block),
+        ] {
+            let range = Id::<u8>::from(start)..Id::<u8>::from(end);
+            let slice = reader.slice(&range).unwrap();
+            assert_eq!(&slice[..], &data[start as usize..end as usize]);
+        }
+    }
 }
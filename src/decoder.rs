@@ -1,20 +1,29 @@
+use std::cmp::max;
+use std::io::{BufRead, BufReader, Read};
+use std::mem::swap;
 use std::sync::atomic::Ordering::Release;
 use std::sync::Arc;
 
 use anyhow::{Context, Error, bail};
 
 use crate::capture::prelude::*;
+use crate::events::EventStream;
+use crate::hooks::{self, Hooks, TransactionEnd, TransferEnd};
+use crate::native;
+use crate::pcap::{InputFormat, Loader};
 use crate::rcu::SingleWriterRcu;
 use crate::usb::{self, prelude::*, validate_packet};
 use crate::vec_map::{VecMap, Key};
 
 struct EndpointData {
+    endpoint: Endpoint,
     device_id: DeviceId,
     address: EndpointAddr,
     writer: EndpointWriter,
     early_start: Option<EndpointTransferId>,
     active: Option<TransferState>,
     ended: Option<EndpointTransferId>,
+    last_item_id: Option<TrafficItemId>,
     last_success: bool,
     setup: Option<SetupFields>,
     payload: Vec<u8>,
@@ -26,6 +35,12 @@ struct TransferState {
     id: EndpointTransferId,
     first: PID,
     last: Option<PID>,
+    /// Whether this is a group of NAKed transactions.
+    polling: bool,
+    /// Time of the first packet of the transfer.
+    start_time: u64,
+    /// Total data on the endpoint before the transfer.
+    start_data: u64,
 }
 
 #[derive(PartialEq, Eq)]
@@ -49,18 +64,17 @@ enum TransactionStatus {
 }
 
 impl EndpointData {
-    fn new(device_id: DeviceId,
-           address: EndpointAddr,
-           writer: EndpointWriter)
-        -> EndpointData
-    {
+    fn new(endpoint: Endpoint, writer: EndpointWriter) -> EndpointData {
         EndpointData {
-            address,
-            device_id,
+            endpoint,
+            address: EndpointAddr::from_parts(
+                endpoint.number(), endpoint.direction()),
+            device_id: endpoint.device_id(),
             writer,
             early_start: None,
             active: None,
             ended: None,
+            last_item_id: None,
             last_success: false,
             setup: None,
             payload: Vec::new(),
@@ -83,6 +97,8 @@ struct TransactionState {
     ep_transaction_id: Option<EndpointTransactionId>,
     setup: Option<SetupFields>,
     payload: Option<Vec<u8>>,
+    /// Time of the first packet of the transaction.
+    start_time: u64,
 }
 
 fn transaction_status(state: &Option<TransactionState>, packet: &[u8])
@@ -515,6 +531,16 @@ impl Key for EndpointKey {
     }
 }
 
+/// Devices and endpoints seen by one source of a merged capture.
+///
+/// Each source is a separate bus, on which addresses are assigned
+/// independently, so devices and endpoints are looked up per source.
+#[derive(Default)]
+struct SourceState {
+    device_index: VecMap<DeviceAddr, DeviceId>,
+    endpoint_index: VecMap<EndpointKey, EndpointId>,
+}
+
 pub struct Decoder {
     capture: CaptureWriter,
     device_index: VecMap<DeviceAddr, DeviceId>,
@@ -522,11 +548,26 @@ pub struct Decoder {
     endpoint_data: VecMap<EndpointId, EndpointData>,
     last_endpoint_state: Vec<u8>,
     last_item_endpoint: Option<EndpointId>,
+    /// Whether the last item is a transfer not yet known to be polling.
+    pending_item: bool,
     transaction_state: Option<TransactionState>,
+    source: u8,
+    other_sources: Vec<SourceState>,
+    hooks: Option<Hooks>,
+    events: Option<EventStream>,
+    /// Time of the packet being decoded.
+    timestamp: u64,
 }
 
 impl Decoder {
+    /// Create a decoder, with hooks for any user-supplied scripts.
     pub fn new(capture: CaptureWriter) -> Result<Decoder, Error> {
+        Decoder::with_hooks(capture, hooks::load())
+    }
+
+    pub fn with_hooks(capture: CaptureWriter, hooks: Option<Hooks>)
+        -> Result<Decoder, Error>
+    {
         // Create the decoder.
         let mut decoder = Decoder {
             capture,
@@ -535,7 +576,13 @@ impl Decoder {
             endpoint_data: VecMap::new(),
             last_endpoint_state: Vec::new(),
             last_item_endpoint: None,
+            pending_item: false,
             transaction_state: None,
+            source: 0,
+            other_sources: Vec::new(),
+            hooks,
+            events: None,
+            timestamp: 0,
         };
 
         // Add the default device.
@@ -556,11 +603,9 @@ impl Decoder {
             endpoint.set_number(ep_number);
             endpoint.set_direction(Direction::Out);
             let endpoint_id = decoder.capture.endpoints.push(&endpoint)?;
-            let endpoint_addr =
-                EndpointAddr::from_parts(ep_number, Direction::Out);
             decoder.endpoint_data.set(
                 endpoint_id,
-                EndpointData::new(default_id, endpoint_addr, writer)
+                EndpointData::new(endpoint, writer)
             );
             let ep_state = EndpointState::Idle as u8;
             decoder.last_endpoint_state.push(ep_state);
@@ -582,12 +627,126 @@ impl Decoder {
         let data_range = self.capture.packet_data.append(packet)?;
         let packet_id = self.capture.packet_index.push(data_range.start)?;
         self.capture.packet_times.push(timestamp_ns)?;
+        self.timestamp = timestamp_ns;
+        if let Some(hooks) = &mut self.hooks {
+            hooks.packet(packet_id.value, timestamp_ns, packet);
+        }
         self.transaction_update(packet_id, packet)?;
+        self.add_script_output();
+        Ok(())
+    }
+
+    /// Handle a packet from one of several sources being merged.
+    ///
+    /// Packets must be passed in time order across all sources.
+    pub fn handle_merged_packet(&mut self,
+                                source: u8,
+                                packet: &[u8],
+                                timestamp_ns: u64)
+        -> Result<(), Error>
+    {
+        if source != self.source {
+            self.switch_source(source)?;
+        }
+        self.capture.packet_sources.push(&source)?;
+        self.handle_raw_packet(packet, timestamp_ns)
+    }
+
+    /// Handle a packet from a capture which may have several sources.
+    ///
+    /// Packets are only tagged with their sources once a packet from a
+    /// source other than the first is seen.
+    pub fn handle_packet_from(&mut self,
+                              source: u8,
+                              packet: &[u8],
+                              timestamp_ns: u64)
+        -> Result<(), Error>
+    {
+        let sources = &mut self.capture.packet_sources;
+        if source == 0 && sources.len() == 0 {
+            return self.handle_raw_packet(packet, timestamp_ns);
+        }
+        // The packets decoded so far came from the first source.
+        while sources.len() < self.capture.packet_index.len() {
+            sources.push(&0)?;
+        }
+        self.handle_merged_packet(source, packet, timestamp_ns)
+    }
+
+    /// Switch to looking up devices and endpoints for another source.
+    fn switch_source(&mut self, source: u8) -> Result<(), Error> {
+        // The packets of a transaction must be consecutive, so a
+        // transaction interrupted by another source's packet is ended.
+        self.transaction_end(false, false)?;
+        let needed = max(self.source, source) as usize + 1;
+        while self.other_sources.len() < needed {
+            // Each source starts with only the default device.
+            let mut state = SourceState::default();
+            state.device_index.set(DeviceAddr(0), DEFAULT_DEVICE_ID);
+            self.other_sources.push(state);
+        }
+        let current = &mut self.other_sources[self.source as usize];
+        swap(&mut self.device_index, &mut current.device_index);
+        swap(&mut self.endpoint_index, &mut current.endpoint_index);
+        let next = &mut self.other_sources[source as usize];
+        swap(&mut self.device_index, &mut next.device_index);
+        swap(&mut self.endpoint_index, &mut next.endpoint_index);
+        self.source = source;
         Ok(())
     }
 
+    /// Write events for the traffic as it is decoded.
+    pub fn set_events(&mut self, events: EventStream) {
+        self.events = Some(events);
+    }
+
+    /// Stop writing events, returning where they were being written.
+    pub fn take_events(&mut self) -> Option<EventStream> {
+        self.events.take()
+    }
+
+    /// Add a note to the timeline, after the items decoded so far.
+    pub fn handle_marker(&mut self, text: String, timestamp_ns: u64) {
+        self.add_marker(MarkerKind::Note(text), timestamp_ns);
+    }
+
+    /// Record that the analyzer lost packets after those decoded so far,
+    /// and how many if it is known.
+    pub fn handle_loss(&mut self, count: Option<u64>, timestamp_ns: u64) {
+        self.add_marker(MarkerKind::Loss(count), timestamp_ns);
+    }
+
+    fn add_marker(&mut self, kind: MarkerKind, timestamp: u64) {
+        if let Some(events) = &mut self.events {
+            match &kind {
+                MarkerKind::Note(text) => events.note(text, timestamp),
+                MarkerKind::Loss(count) => events.loss(*count, timestamp),
+            }
+        }
+        let shared = &self.capture.shared;
+        let mut markers = shared.markers.load().as_ref().clone();
+        let position = self.capture.item_index.len() + markers.len() as u64;
+        markers.push(Marker { kind, timestamp, position });
+        shared.markers.swap(Arc::new(markers));
+    }
+
+    /// Add the events emitted by scripts to the timeline, and publish
+    /// any changes to their metrics.
+    fn add_script_output(&mut self) {
+        let Some(hooks) = &mut self.hooks else {
+            return;
+        };
+        if let Some(metrics) = hooks.changed_metrics() {
+            self.capture.shared.metrics.swap(Arc::new(metrics));
+        }
+        for text in hooks.take_events() {
+            self.add_marker(MarkerKind::Note(text), self.timestamp);
+        }
+    }
+
     pub fn finish(mut self) -> Result<CaptureWriter, Error> {
         self.transaction_end(false, false)?;
+        self.add_script_output();
         self.capture.shared.complete.store(true, Release);
         Ok(self.capture)
     }
@@ -699,6 +858,7 @@ impl Decoder {
             ep_transaction_id: None,
             setup: None,
             payload: None,
+            start_time: self.timestamp,
         };
         // Some packets start a new transfer immediately.
         self.transfer_early_start(&mut state, pid)?;
@@ -739,12 +899,40 @@ impl Decoder {
     {
         if let Some(mut state) = self.transaction_state.take() {
             if state.endpoint_id.is_some() {
+                self.report_transaction(&state, success)?;
                 self.transfer_update(&mut state, success, complete)?;
             }
         }
         Ok(())
     }
 
+    fn report_transaction(&mut self, state: &TransactionState, success: bool)
+        -> Result<(), Error>
+    {
+        if self.hooks.is_none() && self.events.is_none() {
+            return Ok(());
+        }
+        let ep_data = &self.endpoint_data[state.endpoint_id()?];
+        let dev_data = self.capture.device_data(ep_data.device_id)?;
+        let (ep_type, _) = dev_data.endpoint_details(ep_data.address);
+        let transaction = TransactionEnd {
+            endpoint: ep_data.endpoint,
+            ep_type,
+            timestamp: state.start_time,
+            pid: state.start_pid()?,
+            outcome: state.last,
+            success,
+            payload: state.payload.as_deref(),
+        };
+        if let Some(events) = &mut self.events {
+            events.transaction(&transaction);
+        }
+        if let Some(hooks) = &mut self.hooks {
+            hooks.transaction(&transaction);
+        }
+        Ok(())
+    }
+
     fn add_device(&mut self, address: DeviceAddr)
         -> Result<DeviceId, Error>
     {
@@ -774,8 +962,7 @@ impl Decoder {
         endpoint.set_number(number);
         endpoint.set_direction(direction);
         let endpoint_id = self.capture.endpoints.push(&endpoint)?;
-        let endpoint_addr = EndpointAddr::from_parts(number, direction);
-        let endpoint_data = EndpointData::new(device_id, endpoint_addr, writer);
+        let endpoint_data = EndpointData::new(endpoint, writer);
         let endpoint_state = EndpointState::Idle as u8;
         self.last_endpoint_state.push(endpoint_state);
         self.endpoint_data.set(endpoint_id, endpoint_data);
@@ -809,6 +996,9 @@ impl Decoder {
         };
 
         if let Some(endpoint_id) = start_early {
+            // Whether this transfer is polling is known only once the
+            // transaction ends.
+            self.pending_item = true;
             let ep_transfer_id =
                 self.add_transfer(endpoint_id, transaction)?;
             let ep_data = &mut self.endpoint_data[endpoint_id];
@@ -870,30 +1060,44 @@ impl Decoder {
         let dev_data = self.capture.device_data(ep_data.device_id)?;
         let (status, effect) = ep_data.transfer_status(
             dev_data.as_ref(), transaction, success, complete)?;
-        match status {
+        let ended = match status {
             Single => {
                 self.transfer_start(transaction, true)?;
-                self.transfer_end(transaction)?;
+                self.transfer_end(transaction)?
             },
             New => {
                 self.transfer_start(transaction, true)?;
+                self.transfer_polling(endpoint_id, success)?;
+                None
             },
             Continue => {
                 self.transfer_append(transaction, true)?;
+                None
             },
             Retry => {
                 self.transfer_append(transaction, false)?;
+                None
             },
             Done => {
                 self.transfer_append(transaction, true)?;
-                self.transfer_end(transaction)?;
+                self.transfer_end(transaction)?
             },
             Invalid => {
                 self.transfer_start(transaction, false)?;
-                self.transfer_end(transaction)?;
+                self.transfer_polling(endpoint_id, success)?;
+                self.transfer_end(transaction)?
             }
-        }
+        };
         self.endpoint_data[endpoint_id].apply_effect(transaction, effect)?;
+        // The data of the last transaction is counted only now.
+        if let Some(transfer) = ended {
+            self.report_transfer(endpoint_id, &transfer)?;
+        }
+        if self.pending_item {
+            self.pending_item = false;
+            let item_count = self.capture.item_index.len();
+            self.capture.shared.settled_items.store(item_count, Release);
+        }
         Ok(())
     }
 
@@ -917,12 +1121,35 @@ impl Decoder {
                 id: ep_transfer_id,
                 first: transaction_type,
                 last: if done { Some(transaction_type) } else { None },
+                polling: false,
+                start_time: transaction.start_time,
+                start_data: ep_data.total_data,
             }
         );
         ep_data.payload.clear();
         Ok(())
     }
 
+    /// Record whether the transfer just started on an endpoint is a
+    /// group of NAKed transactions, so that it can be hidden from view.
+    fn transfer_polling(&mut self, endpoint_id: EndpointId, success: bool)
+        -> Result<(), Error>
+    {
+        use EndpointType::{Framing, Invalid, Normal};
+        let ep_data = &mut self.endpoint_data[endpoint_id];
+        let dev_data = self.capture.device_data(ep_data.device_id)?;
+        let (ep_type, _) = dev_data.endpoint_details(ep_data.address);
+        let polling = !success && !matches!(ep_type,
+            Framing | Invalid | Normal(usb::EndpointType::Control));
+        if let (true, Some(transfer), Some(item_id)) =
+            (polling, &mut ep_data.active, ep_data.last_item_id)
+        {
+            transfer.polling = true;
+            ep_data.writer.polling_item_ids.push(item_id)?;
+        }
+        Ok(())
+    }
+
     fn transfer_append(&mut self,
                        transaction: &mut TransactionState,
                        done: bool)
@@ -945,21 +1172,54 @@ impl Decoder {
         Ok(())
     }
 
+    /// End the active transfer on an endpoint, returning its state.
     fn transfer_end(&mut self, transaction: &TransactionState)
-        -> Result<(), Error>
+        -> Result<Option<TransferState>, Error>
     {
         let endpoint_id = transaction.endpoint_id()?;
         let ep_data = &mut self.endpoint_data[endpoint_id];
         ep_data.payload.clear();
-        if let Some(transfer) = ep_data.active.take() {
+        let ended = ep_data.active.take();
+        if let Some(transfer) = &ended {
             let ep_transfer_id = transfer.id;
             ep_data.ended = Some(ep_transfer_id);
             let transfer_end_id =
                 self.add_transfer_entry(endpoint_id, ep_transfer_id, false)?;
             if self.last_item_endpoint != Some(endpoint_id) {
-                self.add_item(endpoint_id, transfer_end_id)?;
+                let item_id = self.add_item(endpoint_id, transfer_end_id)?;
+                if transfer.polling {
+                    self.endpoint_data[endpoint_id].writer
+                        .polling_item_ids.push(item_id)?;
+                }
             }
         }
+        Ok(ended)
+    }
+
+    fn report_transfer(&mut self,
+                     endpoint_id: EndpointId,
+                     transfer: &TransferState)
+        -> Result<(), Error>
+    {
+        if self.hooks.is_none() && self.events.is_none() {
+            return Ok(());
+        }
+        let ep_data = &self.endpoint_data[endpoint_id];
+        let dev_data = self.capture.device_data(ep_data.device_id)?;
+        let (ep_type, _) = dev_data.endpoint_details(ep_data.address);
+        let transfer = TransferEnd {
+            endpoint: ep_data.endpoint,
+            ep_type,
+            timestamp: transfer.start_time,
+            bytes: ep_data.total_data - transfer.start_data,
+            polling: transfer.polling,
+        };
+        if let Some(events) = &mut self.events {
+            events.transfer(&transfer);
+        }
+        if let Some(hooks) = &mut self.hooks {
+            hooks.transfer(&transfer);
+        }
         Ok(())
     }
 
@@ -972,6 +1232,7 @@ impl Decoder {
         if let Some(transfer) = ep_data.active.take() {
             ep_data.ended = Some(transfer.id);
             self.add_transfer_entry(endpoint_id, transfer.id, false)?;
+            self.report_transfer(endpoint_id, &transfer)?;
         }
         let ep_transaction_id =
             if let Some(ep_transaction_id) = transaction.ep_transaction_id {
@@ -1039,6 +1300,13 @@ impl Decoder {
     {
         let item_id = self.capture.item_index.push(transfer_id)?;
         self.last_item_endpoint = Some(item_endpoint_id);
+        let ep_data = &mut self.endpoint_data[item_endpoint_id];
+        ep_data.writer.item_ids.push(item_id)?;
+        ep_data.last_item_id = Some(item_id);
+        if !self.pending_item {
+            self.capture.shared.settled_items
+                .store(item_id.value + 1, Release);
+        }
 
         // Look for ended transfers which still need to be linked to an item.
         let endpoint_count = self.capture.endpoints.len();
@@ -1055,3 +1323,70 @@ impl Decoder {
         Ok(item_id)
     }
 }
+
+/// Load a capture, decoding its packets unless it is in Packetry's own
+/// format. The format is detected from the capture's contents, unless
+/// one is given.
+pub fn load_capture<Source: Read>(source: Source,
+                                  format: Option<InputFormat>)
+    -> Result<CaptureReader, Error>
+{
+    let mut source = BufReader::new(source);
+    let (writer, reader) = create_capture()?;
+    if format.is_none() && native::is_native(source.fill_buf()?) {
+        native::load(source, writer)?;
+        return Ok(reader);
+    }
+    let mut loader = match format {
+        Some(format) => Loader::open_as(source, format),
+        None => Loader::open(source),
+    }?;
+    let shared = writer.shared.clone();
+    let mut decoder = Decoder::new(writer)?;
+    let mut overflows = 0;
+    while let Some(result) = loader.next() {
+        let (packet, timestamp_ns) = result?;
+        decoder.handle_packet_from(
+            packet.source, &packet.data, timestamp_ns)?;
+        while overflows < loader.overflows() {
+            decoder.handle_loss(None, timestamp_ns);
+            overflows += 1;
+        }
+        if let Some(start_time) = loader.start_time() {
+            shared.start_time.get_or_init(|| start_time);
+        }
+    }
+    shared.sources.swap(Arc::new(loader.source_names()));
+    decoder.finish()?;
+    Ok(reader)
+}
+
+/// Load one of the captures in the tests directory.
+///
+/// This and [decode_test_capture] are built outside of `cfg(test)` so
+/// that the tests of the application can use them too.
+#[doc(hidden)]
+pub fn load_test_capture(name: &str) -> CaptureReader {
+    let path = format!("./tests/{name}/capture.pcap");
+    load_capture(std::fs::File::open(path).unwrap(), None).unwrap()
+}
+
+/// Pass the packets of a test capture to a decoder set up by the caller,
+/// calling `before_packet` with the index and time of each packet first.
+#[doc(hidden)]
+pub fn decode_test_capture<F>(name: &str,
+                              decoder: &mut Decoder,
+                              mut before_packet: F)
+    where F: FnMut(&mut Decoder, usize, u64)
+{
+    let path = format!("./tests/{name}/capture.pcap");
+    let file = std::fs::File::open(path).unwrap();
+    let mut loader = Loader::open(BufReader::new(file)).unwrap();
+    let mut index = 0;
+    while let Some(result) = loader.next() {
+        let (packet, timestamp_ns) = result.unwrap();
+        before_packet(decoder, index, timestamp_ns);
+        decoder.handle_raw_packet(&packet.data, timestamp_ns).unwrap();
+        index += 1;
+    }
+}
@@ -0,0 +1,434 @@
+//! Export of the descriptors of the devices seen in a capture.
+//!
+//! Descriptors can be written as text in the style of `lsusb -v`, or as C
+//! initializers using the structures from Linux's `<linux/usb/ch9.h>`,
+//! for comparison with those intended by a device's firmware.
+
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use anyhow::Error;
+use usb_ids::FromId;
+
+use crate::capture::{CaptureReader, DeviceData, DeviceId};
+use crate::usb::{
+    ConfigDescriptor,
+    Configuration,
+    DeviceDescriptor,
+    EndpointDescriptor,
+    InterfaceDescriptor,
+    StringId,
+};
+
+/// Width of the field name column in lsusb style output.
+const NAME_WIDTH: usize = 20;
+
+/// Width of the value column in lsusb style output.
+const VALUE_WIDTH: usize = 5;
+
+/// Devices seen in a capture, with their addresses and data.
+fn devices(capture: &mut CaptureReader)
+    -> Result<Vec<(u8, Arc<DeviceData>)>, Error>
+{
+    // The first device is a placeholder for traffic to address zero
+    // before a device is recognised.
+    let mut devices = Vec::new();
+    for id in 1..capture.devices.len() {
+        let device_id = DeviceId::from(id);
+        let address = capture.devices.get(device_id)?.address;
+        devices.push((address.0, capture.device_data(&device_id)?));
+    }
+    Ok(devices)
+}
+
+/// Look up a string descriptor seen for a device.
+pub fn string(data: &DeviceData, id: StringId) -> String {
+    if id.0 == 0 {
+        return String::new();
+    }
+    match data.strings.load().get(id) {
+        Some(utf16) => String::from_utf16_lossy(&utf16.chars()),
+        None => String::new(),
+    }
+}
+
+fn class_name(class: u8) -> String {
+    usb_ids::Class::from_id(class)
+        .map_or_else(String::new, |c| c.name().to_string())
+}
+
+fn subclass_name(class: u8, subclass: u8) -> String {
+    usb_ids::SubClass::from_cid_scid(class, subclass)
+        .map_or_else(String::new, |s| s.name().to_string())
+}
+
+fn protocol_name(class: u8, subclass: u8, protocol: u8) -> String {
+    usb_ids::Protocol::from_cid_scid_pid(class, subclass, protocol)
+        .map_or_else(String::new, |p| p.name().to_string())
+}
+
+/// Write one field of a descriptor in lsusb style.
+fn field(dest: &mut dyn Write,
+         indent: usize,
+         name: &str,
+         value: impl std::fmt::Display,
+         note: &str)
+    -> Result<(), Error>
+{
+    let line = format!("{:indent$}{name:<NAME_WIDTH$}{value:>VALUE_WIDTH$} \
+                        {note}", "");
+    writeln!(dest, "{}", line.trim_end())?;
+    Ok(())
+}
+
+/// Write the descriptors of every device in a capture in the style of
+/// `lsusb -v`.
+///
+/// Returns the number of devices written.
+pub fn write_lsusb<Dest: Write>(capture: &mut CaptureReader, dest: Dest)
+    -> Result<u64, Error>
+{
+    let mut dest = BufWriter::new(dest);
+    let mut count = 0;
+    for (address, data) in devices(capture)? {
+        if count > 0 {
+            writeln!(dest)?;
+        }
+        count += 1;
+        let Some(device) = data.device_descriptor.load_full() else {
+            writeln!(dest, "Device {address:03}: no descriptors captured")?;
+            continue;
+        };
+        let vendor = usb_ids::Vendor::from_id(device.vendor_id);
+        let product = usb_ids::Device::from_vid_pid(
+            device.vendor_id, device.product_id);
+        let names: Vec<&str> = [
+            vendor.map(|v| v.name()),
+            product.map(|p| p.name()),
+        ].into_iter().flatten().collect();
+        writeln!(dest, "{}", format!(
+            "Device {address:03}: ID {:04x}:{:04x} {}",
+            device.vendor_id, device.product_id, names.join(" ")).trim_end())?;
+        write_lsusb_device(&mut dest, &data, &device)?;
+        let configurations = data.configurations.load();
+        for config in configurations.as_ref() {
+            write_lsusb_config(&mut dest, &data, config)?;
+        }
+    }
+    dest.flush()?;
+    Ok(count)
+}
+
+fn write_lsusb_device(dest: &mut dyn Write,
+                      data: &DeviceData,
+                      device: &DeviceDescriptor)
+    -> Result<(), Error>
+{
+    let class = device.device_class;
+    let subclass = device.device_subclass;
+    let protocol = device.device_protocol;
+    writeln!(dest, "Device Descriptor:")?;
+    field(dest, 2, "bLength", device.length, "")?;
+    field(dest, 2, "bDescriptorType", device.descriptor_type, "")?;
+    field(dest, 2, "bcdUSB", device.usb_version, "")?;
+    field(dest, 2, "bDeviceClass", class, &class_name(class))?;
+    field(dest, 2, "bDeviceSubClass", subclass,
+          &subclass_name(class, subclass))?;
+    field(dest, 2, "bDeviceProtocol", protocol,
+          &protocol_name(class, subclass, protocol))?;
+    field(dest, 2, "bMaxPacketSize0", device.max_packet_size_0, "")?;
+    field(dest, 2, "idVendor", format!("0x{:04x}", device.vendor_id),
+          usb_ids::Vendor::from_id(device.vendor_id)
+              .map_or("", |v| v.name()))?;
+    field(dest, 2, "idProduct", format!("0x{:04x}", device.product_id),
+          usb_ids::Device::from_vid_pid(device.vendor_id, device.product_id)
+              .map_or("", |d| d.name()))?;
+    field(dest, 2, "bcdDevice", device.device_version, "")?;
+    for (name, id) in [
+        ("iManufacturer", device.manufacturer_str_id),
+        ("iProduct", device.product_str_id),
+        ("iSerial", device.serial_str_id),
+    ] {
+        field(dest, 2, name, id.0, &string(data, id))?;
+    }
+    field(dest, 2, "bNumConfigurations", device.num_configurations, "")?;
+    Ok(())
+}
+
+fn write_lsusb_config(dest: &mut dyn Write,
+                      data: &DeviceData,
+                      config: &Configuration)
+    -> Result<(), Error>
+{
+    let desc = &config.descriptor;
+    let total_length = desc.total_length;
+    writeln!(dest, "  Configuration Descriptor:")?;
+    field(dest, 4, "bLength", desc.length, "")?;
+    field(dest, 4, "bDescriptorType", desc.descriptor_type, "")?;
+    field(dest, 4, "wTotalLength", format!("0x{total_length:04x}"), "")?;
+    field(dest, 4, "bNumInterfaces", desc.num_interfaces, "")?;
+    field(dest, 4, "bConfigurationValue", desc.config_value, "")?;
+    field(dest, 4, "iConfiguration", desc.config_str_id.0,
+          &string(data, desc.config_str_id))?;
+    field(dest, 4, "bmAttributes", format!("0x{:02x}", desc.attributes), "")?;
+    if desc.attributes & 0x40 != 0 {
+        writeln!(dest, "      Self Powered")?;
+    } else {
+        writeln!(dest, "      (Bus Powered)")?;
+    }
+    if desc.attributes & 0x20 != 0 {
+        writeln!(dest, "      Remote Wakeup")?;
+    }
+    field(dest, 4, "MaxPower", format!("{}mA", desc.max_power as u16 * 2),
+          "")?;
+    for iface in &config.interfaces {
+        let desc = &iface.descriptor;
+        let class = desc.interface_class;
+        let subclass = desc.interface_subclass;
+        let protocol = desc.interface_protocol;
+        writeln!(dest, "    Interface Descriptor:")?;
+        field(dest, 6, "bLength", desc.length, "")?;
+        field(dest, 6, "bDescriptorType", desc.descriptor_type, "")?;
+        field(dest, 6, "bInterfaceNumber", desc.interface_number, "")?;
+        field(dest, 6, "bAlternateSetting", desc.alternate_setting, "")?;
+        field(dest, 6, "bNumEndpoints", desc.num_endpoints, "")?;
+        field(dest, 6, "bInterfaceClass", class, &class_name(class))?;
+        field(dest, 6, "bInterfaceSubClass", subclass,
+              &subclass_name(class, subclass))?;
+        field(dest, 6, "bInterfaceProtocol", protocol,
+              &protocol_name(class, subclass, protocol))?;
+        field(dest, 6, "iInterface", desc.interface_str_id.0,
+              &string(data, desc.interface_str_id))?;
+        // Class-specific descriptors are not decoded, as in lsusb.
+        for class_desc in &iface.class_descriptors {
+            let hex: Vec<String> = class_desc
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            writeln!(dest, "      ** UNRECOGNIZED:  {}", hex.join(" "))?;
+        }
+        for ep_desc in &iface.endpoint_descriptors {
+            write_lsusb_endpoint(dest, ep_desc)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_lsusb_endpoint(dest: &mut dyn Write, desc: &EndpointDescriptor)
+    -> Result<(), Error>
+{
+    const TRANSFER_TYPES: [&str; 4] =
+        ["Control", "Isochronous", "Bulk", "Interrupt"];
+    const SYNC_TYPES: [&str; 4] =
+        ["None", "Asynchronous", "Adaptive", "Synchronous"];
+    const USAGE_TYPES: [&str; 4] =
+        ["Data", "Feedback", "Implicit feedback Data", "(reserved)"];
+    let address = desc.endpoint_address;
+    let attributes = desc.attributes.0;
+    let max_packet_size = desc.max_packet_size;
+    writeln!(dest, "      Endpoint Descriptor:")?;
+    field(dest, 8, "bLength", desc.length, "")?;
+    field(dest, 8, "bDescriptorType", desc.descriptor_type, "")?;
+    field(dest, 8, "bEndpointAddress", format!("0x{:02x}", address.0),
+          &format!(" EP {} {}", address.number(),
+                   format!("{:?}", address.direction()).to_uppercase()))?;
+    field(dest, 8, "bmAttributes", attributes, "")?;
+    let sub_fields = [
+        ("Transfer Type", TRANSFER_TYPES[(attributes & 0x03) as usize]),
+        ("Synch Type", SYNC_TYPES[((attributes >> 2) & 0x03) as usize]),
+        ("Usage Type", USAGE_TYPES[((attributes >> 4) & 0x03) as usize]),
+    ];
+    for (name, value) in sub_fields {
+        writeln!(dest, "          {name:<25}{value}")?;
+    }
+    field(dest, 8, "wMaxPacketSize", format!("0x{max_packet_size:04x}"),
+          &format!(" {}x {} bytes",
+                   ((max_packet_size >> 11) & 0x03) + 1,
+                   max_packet_size & 0x7FF))?;
+    field(dest, 8, "bInterval", desc.interval, "")?;
+    Ok(())
+}
+
+/// Write the descriptors of every device in a capture as C initializers.
+///
+/// Returns the number of devices written.
+pub fn write_c_initializers<Dest: Write>(capture: &mut CaptureReader,
+                                         dest: Dest)
+    -> Result<u64, Error>
+{
+    let mut dest = BufWriter::new(dest);
+    let mut count = 0;
+    writeln!(dest, "#include <linux/usb/ch9.h>")?;
+    for (address, data) in devices(capture)? {
+        let Some(device) = data.device_descriptor.load_full() else {
+            continue;
+        };
+        count += 1;
+        let prefix = format!("device_{address}");
+        writeln!(dest, "\n/* Device {address}: ID {:04x}:{:04x} */",
+                 device.vendor_id, device.product_id)?;
+        write_c_device(&mut dest, &prefix, &device)?;
+        let configurations = data.configurations.load();
+        for config in configurations.as_ref() {
+            write_c_config(&mut dest, &prefix, config)?;
+        }
+        let strings = data.strings.load();
+        for (id, string) in strings.entries() {
+            // String zero lists the supported languages.
+            if id.0 == 0 {
+                continue;
+            }
+            let chars = String::from_utf16_lossy(&string.chars());
+            writeln!(dest, "\n/* String {}: {} */", id.0,
+                     chars.escape_default().to_string().replace("*/", "*\\/"))?;
+        }
+    }
+    dest.flush()?;
+    Ok(count)
+}
+
+/// Write the members of a structure initializer.
+fn c_struct(dest: &mut dyn Write,
+            kind: &str,
+            name: &str,
+            members: &[(&str, String)])
+    -> Result<(), Error>
+{
+    writeln!(dest, "\nstatic const struct {kind} {name} = {{")?;
+    for (member, value) in members {
+        writeln!(dest, "\t.{member} = {value},")?;
+    }
+    writeln!(dest, "}};")?;
+    Ok(())
+}
+
+fn write_c_device(dest: &mut dyn Write,
+                  prefix: &str,
+                  desc: &DeviceDescriptor)
+    -> Result<(), Error>
+{
+    let bcd = |version: crate::usb::BCDVersion|
+        format!("0x{:02X}{:02X}", version.major, version.minor);
+    c_struct(dest, "usb_device_descriptor", &format!("{prefix}_descriptor"), &[
+        ("bLength", "USB_DT_DEVICE_SIZE".to_string()),
+        ("bDescriptorType", "USB_DT_DEVICE".to_string()),
+        ("bcdUSB", format!("cpu_to_le16({})", bcd(desc.usb_version))),
+        ("bDeviceClass", format!("0x{:02X}", desc.device_class)),
+        ("bDeviceSubClass", format!("0x{:02X}", desc.device_subclass)),
+        ("bDeviceProtocol", format!("0x{:02X}", desc.device_protocol)),
+        ("bMaxPacketSize0", desc.max_packet_size_0.to_string()),
+        ("idVendor", format!("cpu_to_le16(0x{:04X})", desc.vendor_id)),
+        ("idProduct", format!("cpu_to_le16(0x{:04X})", desc.product_id)),
+        ("bcdDevice", format!("cpu_to_le16({})", bcd(desc.device_version))),
+        ("iManufacturer", desc.manufacturer_str_id.0.to_string()),
+        ("iProduct", desc.product_str_id.0.to_string()),
+        ("iSerialNumber", desc.serial_str_id.0.to_string()),
+        ("bNumConfigurations", desc.num_configurations.to_string()),
+    ])
+}
+
+fn write_c_config(dest: &mut dyn Write,
+                  prefix: &str,
+                  config: &Configuration)
+    -> Result<(), Error>
+{
+    let desc: &ConfigDescriptor = &config.descriptor;
+    let total_length = desc.total_length;
+    let prefix = format!("{prefix}_config_{}", desc.config_value);
+    c_struct(dest, "usb_config_descriptor", &prefix, &[
+        ("bLength", "USB_DT_CONFIG_SIZE".to_string()),
+        ("bDescriptorType", "USB_DT_CONFIG".to_string()),
+        ("wTotalLength", format!("cpu_to_le16({total_length})")),
+        ("bNumInterfaces", desc.num_interfaces.to_string()),
+        ("bConfigurationValue", desc.config_value.to_string()),
+        ("iConfiguration", desc.config_str_id.0.to_string()),
+        ("bmAttributes", format!("0x{:02X}", desc.attributes)),
+        ("bMaxPower", desc.max_power.to_string()),
+    ])?;
+    for iface in &config.interfaces {
+        let desc: &InterfaceDescriptor = &iface.descriptor;
+        let prefix = format!("{prefix}_interface_{}_{}",
+                             desc.interface_number, desc.alternate_setting);
+        c_struct(dest, "usb_interface_descriptor", &prefix, &[
+            ("bLength", "USB_DT_INTERFACE_SIZE".to_string()),
+            ("bDescriptorType", "USB_DT_INTERFACE".to_string()),
+            ("bInterfaceNumber", desc.interface_number.to_string()),
+            ("bAlternateSetting", desc.alternate_setting.to_string()),
+            ("bNumEndpoints", desc.num_endpoints.to_string()),
+            ("bInterfaceClass", format!("0x{:02X}", desc.interface_class)),
+            ("bInterfaceSubClass",
+                format!("0x{:02X}", desc.interface_subclass)),
+            ("bInterfaceProtocol",
+                format!("0x{:02X}", desc.interface_protocol)),
+            ("iInterface", desc.interface_str_id.0.to_string()),
+        ])?;
+        for (index, class_desc) in iface.class_descriptors.iter().enumerate()
+        {
+            writeln!(dest, "\nstatic const __u8 {prefix}_class_{index}[] = \
+                            {{")?;
+            for chunk in class_desc.chunks(8) {
+                let hex: Vec<String> = chunk
+                    .iter()
+                    .map(|byte| format!("0x{byte:02X},"))
+                    .collect();
+                writeln!(dest, "\t{}", hex.join(" "))?;
+            }
+            writeln!(dest, "}};")?;
+        }
+        for ep_desc in &iface.endpoint_descriptors {
+            let address = ep_desc.endpoint_address;
+            let max_packet_size = ep_desc.max_packet_size;
+            let direction = match address.0 & 0x80 {
+                0 => "USB_DIR_OUT",
+                _ => "USB_DIR_IN",
+            };
+            c_struct(dest, "usb_endpoint_descriptor",
+                     &format!("{prefix}_endpoint_{:02x}", address.0), &[
+                ("bLength", "USB_DT_ENDPOINT_SIZE".to_string()),
+                ("bDescriptorType", "USB_DT_ENDPOINT".to_string()),
+                ("bEndpointAddress",
+                    format!("{direction} | {}", address.number())),
+                ("bmAttributes", format!("0x{:02X}", ep_desc.attributes.0)),
+                ("wMaxPacketSize", format!("cpu_to_le16({max_packet_size})")),
+                ("bInterval", ep_desc.interval.to_string()),
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::load_test_capture;
+
+    #[test]
+    fn test_descriptor_export() {
+        let mut reader = load_test_capture("mouse");
+        let mut text = Vec::new();
+        let count = write_lsusb(&mut reader, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(count, 1);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "Device 004: ID 1bcf:0005 \
+                              Sunplus Innovation Technology Inc. \
+                              Optical Mouse");
+        for line in [
+            "  iProduct                2 USB Optical Mouse",
+            "    MaxPower             98mA",
+            "      bInterfaceProtocol      2 Mouse",
+            "      ** UNRECOGNIZED:  09 21 10 01 00 01 22 4b 00",
+            "        bEndpointAddress     0x81  EP 1 IN",
+            "          Transfer Type            Interrupt",
+            "        wMaxPacketSize      0x0007  1x 7 bytes",
+        ] {
+            assert!(lines.contains(&line), "missing line: {line}");
+        }
+        let mut c = Vec::new();
+        assert_eq!(write_c_initializers(&mut reader, &mut c).unwrap(), 1);
+        let c = String::from_utf8(c).unwrap();
+        assert!(c.contains("\t.idVendor = cpu_to_le16(0x1BCF),\n"));
+        assert!(c.contains("\t.bEndpointAddress = USB_DIR_IN | 1,\n"));
+        assert!(c.contains("/* String 2: USB Optical Mouse */"));
+    }
+}
@@ -0,0 +1,416 @@
+//! Structured details of traffic items.
+//!
+//! The fields decoded from a packet, transaction or transfer are arranged
+//! as a tree, which the detail pane displays with expandable rows. Each
+//! field may record which of the item's raw bytes it was decoded from, so
+//! that they can be highlighted in the hex view.
+
+use std::iter::once;
+use std::mem::size_of;
+use std::ops::Range;
+
+use bytemuck::{bytes_of, pod_read_unaligned};
+
+use crate::usb::{
+    prelude::*,
+    validate_packet,
+};
+use crate::util::fmt_size;
+use crate::vec_map::VecMap;
+
+/// A field decoded from a traffic item, with any fields nested within it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub value: String,
+    /// Range of the item's raw bytes that this field was decoded from.
+    pub bytes: Option<Range<usize>>,
+    pub children: Vec<Field>,
+}
+
+impl Field {
+    pub fn new(name: &str, value: impl ToString) -> Field {
+        Field {
+            name: name.to_string(),
+            value: value.to_string(),
+            bytes: None,
+            children: Vec::new(),
+        }
+    }
+
+    /// The same field, decoded from the given range of bytes.
+    pub fn at(self, bytes: Range<usize>) -> Field {
+        Field { bytes: Some(bytes), ..self }
+    }
+
+    /// The same field, with the given fields nested within it.
+    pub fn with_children(self, children: Vec<Field>) -> Field {
+        Field { children, ..self }
+    }
+
+    /// The same field, with its byte ranges moved along by an offset.
+    pub fn offset(self, offset: usize) -> Field {
+        Field {
+            bytes: self.bytes.map(|range|
+                (range.start + offset)..(range.end + offset)),
+            children: offset_fields(self.children, offset),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.value.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}: {}", self.name, self.value)
+        }
+    }
+}
+
+/// Move the byte ranges of a list of fields along by an offset.
+pub fn offset_fields(fields: Vec<Field>, offset: usize) -> Vec<Field> {
+    fields
+        .into_iter()
+        .map(|field| field.offset(offset))
+        .collect()
+}
+
+/// Indices of the most specific field decoded from the byte at an offset,
+/// starting from the top level of the tree.
+pub fn field_path(fields: &[Field], offset: usize) -> Vec<usize> {
+    // Prefer the deepest field, then the one decoded from fewest bytes.
+    fn search(fields: &[Field], offset: usize) -> (Vec<usize>, usize) {
+        let mut best = (Vec::new(), usize::MAX);
+        for (index, field) in fields.iter().enumerate() {
+            let Some(range) = &field.bytes else {
+                continue;
+            };
+            if !range.contains(&offset) {
+                continue;
+            }
+            let (child_path, child_width) = search(&field.children, offset);
+            let width = if child_path.is_empty() {
+                range.len()
+            } else {
+                child_width
+            };
+            let path: Vec<usize> = once(index).chain(child_path).collect();
+            if path.len() > best.0.len() ||
+                (path.len() == best.0.len() && width < best.1)
+            {
+                best = (path, width);
+            }
+        }
+        best
+    }
+    search(fields, offset).0
+}
+
+/// Describe a tree of fields, one per line, indented by depth, up to a
+/// limit on the number of lines.
+pub fn fields_text(fields: &[Field], max_lines: usize) -> String {
+    fn add(lines: &mut Vec<String>, fields: &[Field], depth: usize) {
+        for field in fields {
+            lines.push(format!("{}{field}", "  ".repeat(depth)));
+            add(lines, &field.children, depth + 1);
+        }
+    }
+    let mut lines = Vec::new();
+    add(&mut lines, fields, 0);
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        lines.push(String::from("…"));
+    }
+    lines.join("\n")
+}
+
+/// Fields of a single packet.
+pub fn packet_fields(packet: &[u8]) -> Vec<Field> {
+    let len = packet.len();
+    let mut fields = vec![Field::new("Length", fmt_size(len as u64))];
+    let Some(pid_byte) = packet.first() else {
+        return fields;
+    };
+    let pid = match validate_packet(packet) {
+        Ok(pid) => pid,
+        Err(_) => {
+            fields.push(
+                Field::new("PID", format!("0x{pid_byte:02X}")).at(0..1));
+            fields.push(Field::new("Error", "Malformed packet"));
+            return fields;
+        }
+    };
+    fields.push(
+        Field::new("PID", format!("{pid} (0x{pid_byte:02X})")).at(0..1));
+    match PacketFields::from_packet(packet) {
+        PacketFields::SOF(sof) => fields.extend([
+            Field::new("Frame number", sof.frame_number()).at(1..3),
+            Field::new("CRC", format!("0x{:02X}", sof.crc())).at(2..3),
+        ]),
+        PacketFields::Token(token) => fields.extend([
+            Field::new("Device address", token.device_address()).at(1..2),
+            Field::new("Endpoint number", token.endpoint_number()).at(1..3),
+            Field::new("CRC", format!("0x{:02X}", token.crc())).at(2..3),
+        ]),
+        PacketFields::Data(data) => fields.extend([
+            Field::new("Payload", fmt_size(len as u64 - 3)).at(1..(len - 2)),
+            Field::new("CRC", format!("0x{:04X}", data.crc)).at((len - 2)..len),
+        ]),
+        PacketFields::Split(split) => fields.extend([
+            Field::new("Hub address", split.hub_address()).at(1..2),
+            Field::new("Split", format!("{:?}", split.sc())).at(1..2),
+            Field::new("Port", split.port()).at(2..3),
+            Field::new("Speed", format!("{:?}", split.speed())).at(2..3),
+            Field::new("Endpoint type", split.endpoint_type()).at(3..4),
+            Field::new("CRC", format!("0x{:02X}", split.crc())).at(3..4),
+        ]),
+        PacketFields::None => {},
+    }
+    fields
+}
+
+/// Fields of the eight bytes of setup data of a control transfer.
+pub fn setup_fields(fields: &SetupFields) -> Vec<Field> {
+    let type_fields = fields.type_fields;
+    let request_type = type_fields.request_type();
+    let request = match request_type {
+        RequestType::Standard => format!(
+            "{} ({:?})", fields.request, StandardRequest::from(fields.request)),
+        _ => fields.request.to_string(),
+    };
+    let type_byte = bytes_of(&type_fields)[0];
+    vec![
+        Field::new("Request type", format!("0x{type_byte:02X}"))
+            .at(0..1)
+            .with_children(vec![
+                Field::new("Direction", type_fields.direction()).at(0..1),
+                Field::new("Type", format!("{request_type:?}")).at(0..1),
+                Field::new("Recipient",
+                    format!("{:?}", type_fields.recipient())).at(0..1),
+            ]),
+        Field::new("Request", request).at(1..2),
+        Field::new("Value", format!("0x{:04X}", fields.value)).at(2..4),
+        Field::new("Index", format!("0x{:04X}", fields.index)).at(4..6),
+        Field::new("Length", fields.length).at(6..8),
+    ]
+}
+
+/// Fields of the descriptors returned by a GET_DESCRIPTOR request.
+pub fn descriptor_fields(data: &[u8],
+                         strings: &VecMap<StringId, UTF16ByteVec>)
+    -> Vec<Field>
+{
+    let mut fields = Vec::new();
+    let mut offset = 0;
+    while offset + 2 <= data.len() {
+        let length = data[offset] as usize;
+        if length < 2 {
+            break;
+        }
+        let end = std::cmp::min(offset + length, data.len());
+        fields.push(descriptor_field(&data[offset..end], strings)
+            .offset(offset));
+        offset += length;
+    }
+    fields
+}
+
+/// Sizes in bytes of the fields of the standard descriptors.
+const DEVICE_FIELD_SIZES: [usize; DeviceDescriptor::NUM_FIELDS] =
+    [1, 1, 2, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1];
+const CONFIG_FIELD_SIZES: [usize; ConfigDescriptor::NUM_FIELDS] =
+    [1, 1, 2, 1, 1, 1, 1, 1];
+const INTERFACE_FIELD_SIZES: [usize; InterfaceDescriptor::NUM_FIELDS] =
+    [1; InterfaceDescriptor::NUM_FIELDS];
+const ENDPOINT_FIELD_SIZES: [usize; EndpointDescriptor::NUM_FIELDS] =
+    [1, 1, 1, 1, 2, 1];
+
+/// Fields of a single descriptor, which may have been truncated.
+fn descriptor_field(bytes: &[u8], strings: &VecMap<StringId, UTF16ByteVec>)
+    -> Field
+{
+    let length = bytes[0] as usize;
+    let type_byte = bytes[1];
+    let desc_type = DescriptorType::from(type_byte);
+    let name = match desc_type {
+        DescriptorType::Unknown =>
+            format!("Descriptor of type 0x{type_byte:02X}"),
+        _ => {
+            let mut name = format!("{} descriptor", desc_type.description());
+            name[..1].make_ascii_uppercase();
+            name
+        }
+    };
+    if bytes.len() < length {
+        return Field::new(&name, format!("{} of {length} bytes", bytes.len()))
+            .at(0..bytes.len());
+    }
+    // Split the text of each field into its name and value, and place the
+    // fields one after another.
+    fn text_fields(texts: impl Iterator<Item=String>, sizes: &[usize])
+        -> Vec<Field>
+    {
+        let mut offset = 0;
+        texts
+            .zip(sizes)
+            .map(|(text, size)| {
+                let field = match text.split_once(": ") {
+                    Some((name, value)) => Field::new(name, value),
+                    None => Field::new(&text, ""),
+                };
+                offset += size;
+                field.at((offset - size)..offset)
+            })
+            .collect()
+    }
+    let children = match desc_type {
+        DescriptorType::Device => bytes
+            .get(..size_of::<DeviceDescriptor>())
+            .map(DeviceDescriptor::from_bytes)
+            .map(|desc| text_fields((0..DeviceDescriptor::NUM_FIELDS)
+                .map(|i| desc.field_text(DeviceField(i as u8), strings)),
+                &DEVICE_FIELD_SIZES)),
+        DescriptorType::Configuration => bytes
+            .get(..size_of::<ConfigDescriptor>())
+            .map(pod_read_unaligned::<ConfigDescriptor>)
+            .map(|desc| text_fields((0..ConfigDescriptor::NUM_FIELDS)
+                .map(|i| desc.field_text(ConfigField(i as u8), strings)),
+                &CONFIG_FIELD_SIZES)),
+        DescriptorType::Interface => bytes
+            .get(..size_of::<InterfaceDescriptor>())
+            .map(pod_read_unaligned::<InterfaceDescriptor>)
+            .map(|desc| text_fields((0..InterfaceDescriptor::NUM_FIELDS)
+                .map(|i| desc.field_text(InterfaceField(i as u8), strings)),
+                &INTERFACE_FIELD_SIZES)),
+        DescriptorType::Endpoint => bytes
+            .get(..size_of::<EndpointDescriptor>())
+            .map(pod_read_unaligned::<EndpointDescriptor>)
+            .map(|desc| text_fields((0..EndpointDescriptor::NUM_FIELDS)
+                .map(|i| desc.field_text(EndpointField(i as u8))),
+                &ENDPOINT_FIELD_SIZES)),
+        DescriptorType::String => Some(vec![
+            Field::new("String", UTF16ByteVec(bytes[2..].to_vec()))
+                .at(2..length)
+        ]),
+        _ => None,
+    };
+    Field::new(&name, fmt_size(length as u64))
+        .at(0..length)
+        .with_children(children.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::{data_packet, token_packet};
+
+    fn names_and_values(fields: &[Field]) -> Vec<String> {
+        fields.iter().map(|field| field.to_string()).collect()
+    }
+
+    #[test]
+    fn test_packet_fields() {
+        let token = token_packet(PID::IN, DeviceAddr(3), EndpointNum(1));
+        assert_eq!(names_and_values(&packet_fields(&token)), [
+            "Length: 3 bytes",
+            "PID: IN (0x69)",
+            "Device address: 3",
+            "Endpoint number: 1",
+            format!("CRC: 0x{:02X}", token[2] >> 3).as_str(),
+        ]);
+        let data = data_packet(PID::DATA1, &[1, 2, 3, 4]);
+        let fields = packet_fields(&data);
+        assert_eq!(fields[1].value, "DATA1 (0x4B)");
+        assert_eq!(fields[2].to_string(), "Payload: 4 bytes");
+        assert_eq!(fields[2].bytes, Some(1..5));
+        assert_eq!(fields[3].bytes, Some(5..7));
+        assert_eq!(names_and_values(&packet_fields(&[0x69, 0, 0])), [
+            "Length: 3 bytes",
+            "PID: 0x69",
+            "Error: Malformed packet",
+        ]);
+        assert_eq!(names_and_values(&packet_fields(&[])), ["Length: 0 bytes"]);
+    }
+
+    #[test]
+    fn test_setup_fields() {
+        let data = data_packet(PID::DATA0,
+            &[0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00]);
+        let fields = setup_fields(&SetupFields::from_data_packet(&data));
+        assert_eq!(names_and_values(&fields), [
+            "Request type: 0x80",
+            "Request: 6 (GetDescriptor)",
+            "Value: 0x0100",
+            "Index: 0x0000",
+            "Length: 18",
+        ]);
+        assert_eq!(names_and_values(&fields[0].children), [
+            "Direction: IN",
+            "Type: Standard",
+            "Recipient: Device",
+        ]);
+    }
+
+    #[test]
+    fn test_descriptor_fields() {
+        let strings = VecMap::new();
+        let device = [
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40,
+            0x50, 0x1d, 0x5c, 0x61, 0x00, 0x01, 0x01, 0x02,
+            0x00, 0x01,
+        ];
+        let fields = descriptor_fields(&device, &strings);
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].to_string(), "Device descriptor: 18 bytes");
+        assert_eq!(fields[0].children.len(), DeviceDescriptor::NUM_FIELDS);
+        assert_eq!(fields[0].children[7].name, "Vendor ID");
+        assert!(fields[0].children[7].value.starts_with("0x1D50"));
+        assert_eq!(fields[0].children[7].bytes, Some(8..10));
+        assert_eq!(fields[0].children[12].bytes, Some(16..17));
+        assert_eq!(fields[0].children[12].to_string(),
+                   "Serial string: (none)");
+        let fields = descriptor_fields(&device[..8], &strings);
+        assert_eq!(names_and_values(&fields),
+                   ["Device descriptor: 8 of 18 bytes"]);
+        let string = [0x08, 0x03, b'a', 0, b'b', 0, b'c', 0];
+        let fields = descriptor_fields(&string, &strings);
+        assert_eq!(names_and_values(&fields[0].children), ["String: 'abc'"]);
+        let fields = descriptor_fields(&[string, string].concat(), &strings);
+        assert_eq!(fields[1].bytes, Some(8..16));
+        assert_eq!(fields[1].children[0].bytes, Some(10..16));
+        let class = [0x05, 0x24, 0x00, 0x10, 0x01];
+        let fields = descriptor_fields(&class, &strings);
+        assert_eq!(names_and_values(&fields),
+                   ["Descriptor of type 0x24: 5 bytes"]);
+    }
+
+    #[test]
+    fn test_field_path() {
+        let data = data_packet(PID::DATA0,
+            &[0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00]);
+        let setup = SetupFields::from_data_packet(&data);
+        let fields = vec![
+            Field::new("Packet", "DATA0")
+                .at(0..11)
+                .with_children(packet_fields(&data)),
+            Field::new("Setup data", "")
+                .at(1..9)
+                .with_children(offset_fields(setup_fields(&setup), 1)),
+        ];
+        assert_eq!(field_path(&fields, 0), [0, 1]);
+        assert_eq!(field_path(&fields, 1), [1, 0, 0]);
+        assert_eq!(field_path(&fields, 4), [1, 2]);
+        assert_eq!(field_path(&fields, 10), [0, 3]);
+        assert!(field_path(&fields, 11).is_empty());
+
+        let text = fields_text(&fields, 100);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "Packet: DATA0");
+        assert_eq!(lines[1], "  Length: 11 bytes");
+        assert!(lines.contains(&"Setup data"));
+        assert_eq!(fields_text(&fields, 2),
+                   "Packet: DATA0\n  Length: 11 bytes\n…");
+    }
+}
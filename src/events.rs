@@ -0,0 +1,226 @@
+//! Decoded traffic written as newline-delimited JSON, as each transaction
+//! and transfer completes during a live capture.
+//!
+//! Each line is a JSON object with an `event` field giving its kind:
+//!
+//! ```text
+//! {"event":"transaction","timestamp":1250,"device":4,"number":1,...}
+//! {"event":"transfer","timestamp":1250,"device":4,"number":1,...}
+//! {"event":"loss","timestamp":2000,"count":null}
+//! {"event":"note","timestamp":3000,"text":"Button pressed"}
+//! ```
+//!
+//! Events can be written to standard output, given as `-`, to a file, or
+//! to a socket, given as `tcp:HOST:PORT` or, on Unix, `unix:PATH`. Each
+//! line is flushed as it is written, so that another program can act on
+//! events as they happen.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::TcpStream;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Error};
+use serde_json::{json, Value};
+
+use crate::capture::{Endpoint, EndpointType};
+use crate::hooks::{TransactionEnd, TransferEnd};
+
+static DESTINATION: OnceLock<String> = OnceLock::new();
+
+/// Set where live captures made in the UI write their events.
+pub fn set_destination(dest: String) {
+    let _ = DESTINATION.set(dest);
+}
+
+/// A stream of events for the destination set, if one was.
+pub fn configured() -> Result<Option<EventStream>, Error> {
+    DESTINATION.get().map(|dest| EventStream::open(dest)).transpose()
+}
+
+/// Where events are written.
+pub struct EventStream {
+    dests: Vec<Box<dyn Write + Send>>,
+}
+
+impl EventStream {
+    pub fn new(dest: Box<dyn Write + Send>) -> EventStream {
+        EventStream { dests: vec![dest] }
+    }
+
+    /// Write events to a further destination.
+    pub fn add(&mut self, dest: Box<dyn Write + Send>) {
+        self.dests.push(dest);
+    }
+
+    /// Open a destination for events, as described above.
+    pub fn open(dest: &str) -> Result<EventStream, Error> {
+        let dest: Box<dyn Write + Send> = if dest == "-" {
+            Box::new(std::io::stdout())
+        } else if let Some(address) = dest.strip_prefix("tcp:") {
+            Box::new(BufWriter::new(TcpStream::connect(address)
+                .with_context(|| format!("Failed to connect to {address}"))?))
+        } else if let Some(path) = dest.strip_prefix("unix:") {
+            connect_unix(path)?
+        } else {
+            Box::new(BufWriter::new(File::create(dest)
+                .with_context(|| format!("Failed to create {dest}"))?))
+        };
+        Ok(EventStream::new(dest))
+    }
+
+    /// Write a transaction that has just been completed.
+    pub fn transaction(&mut self, transaction: &TransactionEnd) {
+        let payload = transaction.payload.map(|payload| payload
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>());
+        let mut event = start("transaction", transaction.timestamp);
+        endpoint_fields(&mut event,
+                        &transaction.endpoint, transaction.ep_type);
+        event["pid"] = json!(transaction.pid.to_string());
+        event["outcome"] = json!(transaction.outcome.to_string());
+        event["success"] = json!(transaction.success);
+        event["payload"] = json!(payload);
+        self.write(event);
+    }
+
+    /// Write a transfer that has just been completed.
+    pub fn transfer(&mut self, transfer: &TransferEnd) {
+        let mut event = start("transfer", transfer.timestamp);
+        endpoint_fields(&mut event, &transfer.endpoint, transfer.ep_type);
+        event["bytes"] = json!(transfer.bytes);
+        event["polling"] = json!(transfer.polling);
+        self.write(event);
+    }
+
+    /// Write that packets were lost, and how many if known.
+    pub fn loss(&mut self, count: Option<u64>, timestamp: u64) {
+        let mut event = start("loss", timestamp);
+        event["count"] = json!(count);
+        self.write(event);
+    }
+
+    /// Write a note added to the capture.
+    pub fn note(&mut self, text: &str, timestamp: u64) {
+        let mut event = start("note", timestamp);
+        event["text"] = json!(text);
+        self.write(event);
+    }
+
+    /// Write one event. If a destination fails, such as because the
+    /// program reading the events has exited, the capture carries on
+    /// without writing further events there.
+    fn write(&mut self, event: Value) {
+        let line = format!("{event}\n");
+        self.dests.retain_mut(|dest| {
+            let result = dest
+                .write_all(line.as_bytes())
+                .and_then(|()| dest.flush());
+            if let Err(e) = &result {
+                eprintln!(
+                    "Failed to write events, no more will be written: {e}");
+            }
+            result.is_ok()
+        });
+    }
+}
+
+/// The fields with which every event starts.
+fn start(event: &str, timestamp: u64) -> Value {
+    json!({"event": event, "timestamp": timestamp})
+}
+
+fn endpoint_fields(event: &mut Value,
+                   endpoint: &Endpoint,
+                   ep_type: EndpointType)
+{
+    event["device"] = json!(endpoint.device_address().0);
+    event["number"] = json!(endpoint.number().0);
+    event["direction"] = json!(endpoint.direction().to_string());
+    event["type"] = json!(ep_type.to_string());
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> Result<Box<dyn Write + Send>, Error> {
+    use std::os::unix::net::UnixStream;
+    Ok(Box::new(BufWriter::new(UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to {path}"))?)))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(path: &str) -> Result<Box<dyn Write + Send>, Error> {
+    anyhow::bail!("Unix sockets are not supported, so {path} cannot be used")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::capture::create_capture;
+    use crate::decoder::{Decoder, decode_test_capture};
+
+    /// A destination shared with the test, to read back what was written.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let output = Shared::default();
+        let (writer, reader) = create_capture().unwrap();
+        let mut decoder = Decoder::with_hooks(writer, None).unwrap();
+        decoder.set_events(EventStream::new(Box::new(output.clone())));
+        decode_test_capture("mouse", &mut decoder, |_, _, _| {});
+        decoder.handle_loss(Some(3), 1000);
+        decoder.handle_marker("Test \"note\"".to_string(), 2000);
+        decoder.finish().unwrap();
+        let text = String::from_utf8(output.0.lock().unwrap().clone())
+            .unwrap();
+        assert!(text.ends_with('\n'));
+        let events: Vec<Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let count = |kind: &str| events
+            .iter()
+            .filter(|event| event["event"] == kind)
+            .count();
+        assert_eq!(count("transaction") as u64,
+                   reader.transaction_index.len());
+        assert!(count("transfer") > 0);
+        let mut last = 0;
+        let mut mouse_data = 0;
+        for event in &events {
+            if event["event"] == "transaction" {
+                let timestamp = event["timestamp"].as_u64().unwrap();
+                assert!(timestamp >= last);
+                last = timestamp;
+            }
+            if event["event"] == "transfer" &&
+                event["device"] == 4 && event["number"] == 1
+            {
+                assert_eq!(event["direction"], "IN");
+                assert_eq!(event["type"], "Interrupt");
+                mouse_data += event["bytes"].as_u64().unwrap();
+            }
+        }
+        assert!(mouse_data > 0);
+        let find = |kind: &str| events
+            .iter()
+            .find(|event| event["event"] == kind)
+            .unwrap();
+        assert_eq!(find("loss")["count"], 3);
+        assert_eq!(find("note")["text"], "Test \"note\"");
+        assert_eq!(find("note")["timestamp"], 2000);
+    }
+}
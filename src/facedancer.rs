@@ -0,0 +1,476 @@
+//! Export of a device seen in a capture as a Facedancer script.
+//!
+//! The script describes the device with the descriptors it presented,
+//! answers the class and vendor requests seen in the capture with the
+//! responses the device gave, and replays the data sent on its IN
+//! endpoints. It is a starting point for emulating the device, to be
+//! edited to give the behaviour needed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufWriter, Write};
+
+use anyhow::{Context, Error};
+
+use crate::capture::{CaptureReader, DeviceData, DeviceId};
+use crate::descriptors::string;
+use crate::usb::{
+    Configuration,
+    ControlResult,
+    ControlTransfer,
+    Direction,
+    EndpointDescriptor,
+    EndpointType,
+    Interface,
+    Recipient,
+    RequestType,
+    StandardRequest,
+    StringId,
+};
+
+/// Most packets replayed on each IN endpoint, to keep the script readable.
+const MAX_PACKETS: usize = 256;
+
+/// Code used by the request handlers of every script.
+const PRELUDE: &str = r#"import logging
+
+from facedancer import *
+from facedancer import main
+
+# Response to a request which the device stalled.
+STALL = object()
+
+
+def respond(request, responses):
+    """Respond to a request as the device did, by its value and index."""
+    response = responses.get((request.value, request.index), STALL)
+    if response is STALL:
+        request.stall()
+    elif response is None:
+        request.ack()
+    else:
+        request.reply(response[:request.length])
+"#;
+
+/// A control request handler, for requests of one type and number.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Handler {
+    /// Interface the requests are made to, if made to an interface.
+    interface: Option<u8>,
+    /// Kind of request: standard, class or vendor.
+    kind: &'static str,
+    /// Recipient of the requests, as decoded from bmRequestType.
+    recipient: &'static str,
+    /// Direction of the data stage, IN or OUT.
+    direction: &'static str,
+    /// The bRequest field of the requests.
+    request: u8,
+}
+
+/// How the device responded to a request.
+enum Response {
+    Data(Vec<u8>),
+    Ack,
+    Stall,
+}
+
+/// Responses for each handler, by the wValue and wIndex of the request.
+type Responses = BTreeMap<Handler, BTreeMap<(u16, u16), Response>>;
+
+/// Write a Facedancer script which emulates a device seen in a capture.
+///
+/// Returns the number of control responses and packets replayed.
+pub fn write_script<Dest: Write>(capture: &mut CaptureReader,
+                                 device_id: DeviceId,
+                                 dest: Dest)
+    -> Result<usize, Error>
+{
+    let data = capture.device_data(&device_id)?;
+    let device = data.device_descriptor
+        .load_full()
+        .context("The device's descriptor was not captured")?;
+    let transfers = capture.control_transfers(device_id)?;
+    let mut responses = collect_responses(transfers);
+    let mut count = responses.values().map(BTreeMap::len).sum();
+    let configurations = data.configurations.load();
+    let mut dest = BufWriter::new(dest);
+    writeln!(dest, "#!/usr/bin/env python3")?;
+    writeln!(dest, "#")?;
+    writeln!(dest, "# Emulation of USB device {:04x}:{:04x}, {}, \
+                    as captured by Packetry.",
+             device.vendor_id, device.product_id, data.description())?;
+    writeln!(dest, "#\n# Requests and IN endpoints are answered with the \
+                    data seen in the capture.\n")?;
+    writeln!(dest, "{PRELUDE}\n")?;
+    writeln!(dest, "@use_inner_classes_automatically")?;
+    writeln!(dest, "class CapturedDevice(USBDevice):")?;
+    let bcd = |version: crate::usb::BCDVersion|
+        format!("0x{:02X}{:02X}", version.major, version.minor);
+    fields(&mut dest, 1, &[
+        ("name", "str", python_string(&data.description())),
+        ("device_class", "int", format!("0x{:02X}", device.device_class)),
+        ("device_subclass", "int",
+            format!("0x{:02X}", device.device_subclass)),
+        ("protocol_revision_number", "int",
+            format!("0x{:02X}", device.device_protocol)),
+        ("max_packet_size_ep0", "int", device.max_packet_size_0.to_string()),
+        ("vendor_id", "int", format!("0x{:04X}", device.vendor_id)),
+        ("product_id", "int", format!("0x{:04X}", device.product_id)),
+        ("manufacturer_string", "str",
+            optional_string(&data, device.manufacturer_str_id)),
+        ("product_string", "str",
+            optional_string(&data, device.product_str_id)),
+        ("serial_number_string", "str",
+            optional_string(&data, device.serial_str_id)),
+        ("device_revision", "int", bcd(device.device_version)),
+        ("usb_spec_version", "int", bcd(device.usb_version)),
+    ])?;
+    // Requests to an interface are handled by the first class for it.
+    let mut interfaces = BTreeSet::new();
+    for config in configurations.as_ref() {
+        for iface in &config.interfaces {
+            interfaces.insert(iface.descriptor.interface_number.0);
+        }
+    }
+    let device_handlers = take_handlers(&mut responses, |handler| {
+        !handler.interface.is_some_and(|number| interfaces.contains(&number))
+    });
+    write_handlers(&mut dest, 1, &device_handlers)?;
+    for config in configurations.as_ref() {
+        count += write_config(&mut dest, capture, device_id, &data, config,
+                              &mut responses)?;
+    }
+    writeln!(dest, "\n\nmain(CapturedDevice)")?;
+    dest.flush()?;
+    Ok(count)
+}
+
+/// Collect the responses to the requests which the script must answer,
+/// keeping the first seen for each request.
+fn collect_responses(transfers: Vec<ControlTransfer>) -> Responses {
+    let mut responses = Responses::new();
+    for transfer in transfers {
+        let fields = &transfer.fields;
+        let recipient = fields.type_fields.recipient();
+        let kind = match fields.type_fields.request_type() {
+            RequestType::Class => "class",
+            RequestType::Vendor => "vendor",
+            // Descriptors such as HID report descriptors are requested
+            // from interfaces, and must be given by the script.
+            RequestType::Standard if
+                matches!(recipient, Recipient::Interface) &&
+                fields.request == StandardRequest::GetDescriptor as u8
+                => "standard",
+            _ => continue,
+        };
+        let recipient_name = match recipient {
+            Recipient::Device => "device",
+            Recipient::Interface => "interface",
+            Recipient::Endpoint => "endpoint",
+            Recipient::Other => "other",
+            Recipient::Reserved => continue,
+        };
+        let direction = fields.type_fields.direction();
+        let response = match (transfer.result, direction) {
+            (ControlResult::Completed, Direction::In) =>
+                Response::Data(transfer.data),
+            (ControlResult::Completed, Direction::Out) => Response::Ack,
+            (ControlResult::Stalled, _) => Response::Stall,
+            (ControlResult::Incomplete, _) => continue,
+        };
+        let handler = Handler {
+            interface: matches!(recipient, Recipient::Interface)
+                .then_some(fields.index as u8),
+            kind,
+            recipient: recipient_name,
+            direction: match direction {
+                Direction::In => "IN",
+                Direction::Out => "OUT",
+            },
+            request: fields.request,
+        };
+        responses
+            .entry(handler)
+            .or_default()
+            .entry((fields.value, fields.index))
+            .or_insert(response);
+    }
+    responses
+}
+
+fn write_config(dest: &mut dyn Write,
+                capture: &mut CaptureReader,
+                device_id: DeviceId,
+                data: &DeviceData,
+                config: &Configuration,
+                responses: &mut Responses)
+    -> Result<usize, Error>
+{
+    let desc = &config.descriptor;
+    let mut count = 0;
+    writeln!(dest, "\n    class Configuration_{}(USBConfiguration):",
+             desc.config_value)?;
+    fields(dest, 2, &[
+        ("number", "int", desc.config_value.to_string()),
+        ("configuration_string", "str",
+            optional_string(data, desc.config_str_id)),
+        ("max_power", "int", (desc.max_power as u16 * 2).to_string()),
+        ("self_powered", "bool", python_bool(desc.attributes & 0x40 != 0)),
+        ("supports_remote_wakeup", "bool",
+            python_bool(desc.attributes & 0x20 != 0)),
+    ])?;
+    for iface in &config.interfaces {
+        write_interface(dest, data, iface, responses)?;
+        for ep_desc in &iface.endpoint_descriptors {
+            count += write_endpoint(dest, capture, device_id, ep_desc)?;
+        }
+    }
+    Ok(count)
+}
+
+fn write_interface(dest: &mut dyn Write,
+                   data: &DeviceData,
+                   iface: &Interface,
+                   responses: &mut Responses)
+    -> Result<(), Error>
+{
+    let desc = &iface.descriptor;
+    let number = desc.interface_number.0;
+    writeln!(dest, "\n        class Interface_{number}_{}(USBInterface):",
+             desc.alternate_setting)?;
+    fields(dest, 3, &[
+        ("number", "int", number.to_string()),
+        ("alternate", "int", desc.alternate_setting.to_string()),
+        ("class_number", "int", format!("0x{:02X}", desc.interface_class)),
+        ("subclass_number", "int",
+            format!("0x{:02X}", desc.interface_subclass)),
+        ("protocol_number", "int",
+            format!("0x{:02X}", desc.interface_protocol)),
+        ("interface_string", "str",
+            optional_string(data, desc.interface_str_id)),
+    ])?;
+    for (index, class_desc) in iface.class_descriptors.iter().enumerate() {
+        let descriptor_type = class_desc.get(1).copied().unwrap_or_default();
+        writeln!(dest, "\n            \
+                        class ClassDescriptor_{index}(USBClassDescriptor):")?;
+        fields(dest, 4, &[
+            ("number", "int", format!("0x{:02X}", descriptor_type)),
+            ("raw", "bytes", python_bytes(class_desc)),
+        ])?;
+    }
+    let handlers = take_handlers(responses, |handler| {
+        handler.interface == Some(number)
+    });
+    write_handlers(dest, 3, &handlers)
+}
+
+/// Write an endpoint, returning the number of packets it replays.
+fn write_endpoint(dest: &mut dyn Write,
+                  capture: &mut CaptureReader,
+                  device_id: DeviceId,
+                  desc: &EndpointDescriptor)
+    -> Result<usize, Error>
+{
+    let address = desc.endpoint_address;
+    let direction = address.direction();
+    let transfer_type = match desc.attributes.endpoint_type() {
+        EndpointType::Control => "CONTROL",
+        EndpointType::Isochronous => "ISOCHRONOUS",
+        EndpointType::Bulk => "BULK",
+        EndpointType::Interrupt => "INTERRUPT",
+    };
+    writeln!(dest, "\n            class Endpoint_{:02x}(USBEndpoint):",
+             address.0)?;
+    fields(dest, 4, &[
+        ("number", "int", address.number().0.to_string()),
+        ("direction", "USBDirection", format!("USBDirection.{direction}")),
+        ("transfer_type", "USBTransferType",
+            format!("USBTransferType.{transfer_type}")),
+        ("max_packet_size", "int", (desc.max_packet_size & 0x7FF).to_string()),
+        ("interval", "int", desc.interval.to_string()),
+    ])?;
+    let indent = "                ";
+    if matches!(direction, Direction::Out) {
+        writeln!(dest, "\n{indent}def handle_data_received(self, data):")?;
+        writeln!(dest, "{indent}    logging.info(f\"Received {{data.hex()}} \
+                        on endpoint {}\")", address.number().0)?;
+        return Ok(0);
+    }
+    let mut packets = Vec::new();
+    let mut skipped = 0;
+    if let Some(endpoint_id) = capture.endpoint_id(device_id, address)? {
+        capture.endpoint_payloads(endpoint_id, |payload| {
+            if packets.len() < MAX_PACKETS {
+                packets.push(payload);
+            } else {
+                skipped += 1;
+            }
+            Ok(())
+        })?;
+    }
+    if packets.is_empty() {
+        writeln!(dest, "\n{indent}# No data was captured from this \
+                        endpoint.")?;
+        return Ok(0);
+    }
+    writeln!(dest, "\n{indent}# The packets sent in the capture, in order.")?;
+    if skipped > 0 {
+        writeln!(dest, "{indent}# A further {skipped} packets were not \
+                        included.")?;
+    }
+    writeln!(dest, "{indent}packets = iter([")?;
+    for packet in &packets {
+        writeln!(dest, "{indent}    {},", python_bytes(packet))?;
+    }
+    writeln!(dest, "{indent}])")?;
+    writeln!(dest, "\n{indent}def handle_data_requested(self):")?;
+    writeln!(dest, "{indent}    packet = next(self.packets, None)")?;
+    writeln!(dest, "{indent}    if packet is not None:")?;
+    writeln!(dest, "{indent}        self.send(packet)")?;
+    Ok(packets.len())
+}
+
+/// Remove the handlers which match a predicate, returning them.
+fn take_handlers<P>(responses: &mut Responses, predicate: P) -> Responses
+    where P: Fn(&Handler) -> bool
+{
+    let (taken, rest) = std::mem::take(responses)
+        .into_iter()
+        .partition(|(handler, _)| predicate(handler));
+    *responses = rest;
+    taken
+}
+
+/// Write a request handler for each set of responses.
+fn write_handlers(dest: &mut dyn Write,
+                  level: usize,
+                  responses: &Responses)
+    -> Result<(), Error>
+{
+    let indent = "    ".repeat(level);
+    for (handler, responses) in responses {
+        let Handler { kind, direction, request, .. } = handler;
+        let recipient = match handler.recipient {
+            "interface" if handler.interface.is_some() && level > 1 =>
+                "to_this_interface",
+            "interface" => "to_any_interface",
+            "endpoint" => "to_any_endpoint",
+            "other" => "to_other",
+            _ => "to_device",
+        };
+        let suffix = direction.to_lowercase();
+        writeln!(dest, "\n{indent}@{kind}_request_handler(\
+                        number=0x{request:02X}, \
+                        direction=USBDirection.{direction})")?;
+        writeln!(dest, "{indent}@{recipient}")?;
+        writeln!(dest, "{indent}def handle_{kind}_request_\
+                        {request:02x}_{suffix}(self, request):")?;
+        writeln!(dest, "{indent}    respond(request, {{")?;
+        for ((value, index), response) in responses {
+            let response = match response {
+                Response::Data(data) => python_bytes(data),
+                Response::Ack => "None".to_string(),
+                Response::Stall => "STALL".to_string(),
+            };
+            writeln!(dest, "{indent}        (0x{value:04X}, 0x{index:04X}): \
+                            {response},")?;
+        }
+        writeln!(dest, "{indent}    }})")?;
+    }
+    Ok(())
+}
+
+/// Write the fields of a class, with their types, aligned.
+fn fields(dest: &mut dyn Write,
+          level: usize,
+          fields: &[(&str, &str, String)])
+    -> Result<(), Error>
+{
+    let indent = "    ".repeat(level);
+    let width = fields
+        .iter()
+        .map(|(name, ..)| name.len())
+        .max()
+        .unwrap_or_default();
+    for (name, kind, value) in fields {
+        writeln!(dest, "{indent}{name:width$} : {kind} = {value}")?;
+    }
+    Ok(())
+}
+
+/// A string descriptor seen for a device as a Python string, or None.
+fn optional_string(data: &DeviceData, id: StringId) -> String {
+    let text = string(data, id);
+    if text.is_empty() {
+        "None".to_string()
+    } else {
+        python_string(&text)
+    }
+}
+
+fn python_string(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\\' | '"' => { literal.push('\\'); literal.push(c); },
+            c if c.is_control() =>
+                literal.push_str(&format!("\\u{:04x}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn python_bytes(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("bytes.fromhex(\"{hex}\")")
+}
+
+fn python_bool(value: bool) -> String {
+    if value { "True" } else { "False" }.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::load_test_capture;
+
+    #[test]
+    fn test_script() {
+        let mut reader = load_test_capture("mouse");
+        let mut script = Vec::new();
+        let count = write_script(&mut reader, DeviceId::from(1), &mut script)
+            .unwrap();
+        let script = String::from_utf8(script).unwrap();
+        let lines: Vec<&str> = script.lines().collect();
+        for line in [
+            "    vendor_id                : int = 0x1BCF",
+            "    product_string           : str = \"USB Optical Mouse\"",
+            "        max_power              : int = 98",
+            "            class_number     : int = 0x03",
+            "                raw    : bytes = \
+                         bytes.fromhex(\"092110010001224b00\")",
+            "            @class_request_handler(number=0x0A, \
+                         direction=USBDirection.OUT)",
+            "                    (0x0000, 0x0000): None,",
+            "            class Endpoint_81(USBEndpoint):",
+            "                transfer_type   : USBTransferType = \
+                         USBTransferType.INTERRUPT",
+            "                    bytes.fromhex(\"0100ff0f000000\"),",
+            "main(CapturedDevice)",
+        ] {
+            assert!(lines.contains(&line), "missing line: {line}");
+        }
+        // The HID report descriptor is given, as well as each report.
+        let report_descriptor = lines
+            .iter()
+            .find(|line| line.contains("(0x2200, 0x0000): bytes.fromhex"))
+            .unwrap();
+        assert!(report_descriptor.contains("\"05010902a101"));
+        let reports = lines
+            .iter()
+            .filter(|line| line.starts_with("                    bytes"))
+            .count();
+        assert_eq!(count, reports + 2);
+    }
+}
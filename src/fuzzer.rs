@@ -1,28 +1,11 @@
 //! Fuzz the USB packet decoder.
 
-#![allow(dead_code)]
 #![no_main]
 
-#[macro_use]
-extern crate bitfield;
-
 use libfuzzer_sys::{arbitrary::{Arbitrary, Unstructured}, fuzz_target};
 
-mod capture;
-mod compact_index;
-mod data_stream;
-mod decoder;
-mod id;
-mod index_stream;
-mod pcap;
-mod rcu;
-mod stream;
-mod usb;
-mod util;
-mod vec_map;
-
-use capture::create_capture;
-use decoder::Decoder;
+use packetry::capture::create_capture;
+use packetry::decoder::Decoder;
 
 fuzz_target!(|data: &[u8]| {
     let mut input = Unstructured::new(data);
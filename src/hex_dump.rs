@@ -0,0 +1,124 @@
+//! Hex dump of the raw bytes of a traffic item.
+//!
+//! Each line shows the offset of its first byte, then up to sixteen bytes
+//! in hexadecimal, then the same bytes as ASCII. The functions here map
+//! between byte offsets and positions in the text, so that the bytes of a
+//! field can be highlighted, and a field found from a byte clicked on.
+
+use std::cmp::min;
+use std::ops::Range;
+
+/// Number of bytes shown on each line.
+pub const BYTES_PER_LINE: usize = 16;
+
+/// Column at which the hexadecimal bytes start.
+const HEX_START: usize = 10;
+
+/// Column at which the ASCII bytes start.
+const ASCII_START: usize = HEX_START + BYTES_PER_LINE * 3 + 1;
+
+/// Format bytes as a hex dump.
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut lines = Vec::new();
+    for (index, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        let mut line = format!("{:08X}  ", index * BYTES_PER_LINE);
+        for byte in chunk {
+            line += &format!("{byte:02X} ");
+        }
+        line += &"   ".repeat(BYTES_PER_LINE - chunk.len());
+        line.push(' ');
+        line.extend(chunk.iter().map(|&byte|
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }));
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// Offset of the byte shown at a line and column of the hex dump of
+/// `length` bytes, if any.
+pub fn byte_at(length: usize, line: usize, column: usize) -> Option<usize> {
+    let hex_columns = HEX_START..(ASCII_START - 1);
+    let ascii_columns = ASCII_START..(ASCII_START + BYTES_PER_LINE);
+    let index = if hex_columns.contains(&column) {
+        (column - HEX_START) / 3
+    } else if ascii_columns.contains(&column) {
+        column - ASCII_START
+    } else {
+        return None;
+    };
+    let offset = line * BYTES_PER_LINE + index;
+    (offset < length).then_some(offset)
+}
+
+/// Spans of text showing a range of the `length` bytes in the hex dump,
+/// as line numbers and ranges of columns.
+///
+/// Each line of the range has one span for the hexadecimal form of its
+/// bytes, followed by one for their ASCII form.
+pub fn text_spans(range: &Range<usize>, length: usize)
+    -> Vec<(usize, Range<usize>)>
+{
+    let end = min(range.end, length);
+    let mut spans = Vec::new();
+    let mut start = range.start;
+    while start < end {
+        let line = start / BYTES_PER_LINE;
+        let line_end = min(end, (line + 1) * BYTES_PER_LINE);
+        let first = start % BYTES_PER_LINE;
+        let last = first + (line_end - start);
+        // Cover the digits of each byte and the spaces between them.
+        let hex_columns = (HEX_START + first * 3)..(HEX_START + last * 3 - 1);
+        spans.push((line, hex_columns));
+        spans.push((line, (ASCII_START + first)..(ASCII_START + last)));
+        start = line_end;
+    }
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump() {
+        let bytes: Vec<u8> = (0x3E..0x50).collect();
+        let text = hex_dump(&bytes);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, [
+            "00000000  3E 3F 40 41 42 43 44 45 46 47 48 49 4A 4B 4C 4D  \
+             >?@ABCDEFGHIJKLM",
+            "00000010  4E 4F                                            NO",
+        ]);
+        assert_eq!(hex_dump(&[0x00, 0x7F]),
+            format!("00000000  00 7F {} ..", "   ".repeat(14)));
+        assert_eq!(hex_dump(&[]), "");
+        // Each byte can be found from both of its forms.
+        for offset in 0..bytes.len() {
+            let spans = text_spans(&(offset..(offset + 1)), bytes.len());
+            assert_eq!(spans.len(), 2);
+            for (line, columns) in spans {
+                let text = &lines[line][columns.clone()];
+                assert_eq!(byte_at(bytes.len(), line, columns.start),
+                           Some(offset), "{text}");
+            }
+        }
+        assert_eq!(byte_at(bytes.len(), 1, ASCII_START + 2), None);
+        assert_eq!(byte_at(bytes.len(), 0, 0), None);
+    }
+
+    #[test]
+    fn test_text_spans() {
+        assert_eq!(text_spans(&(14..18), 100), [
+            (0, 52..57),
+            (0, 73..75),
+            (1, 10..15),
+            (1, 59..61),
+        ]);
+        assert_eq!(text_spans(&(14..18), 15), [(0, 52..54), (0, 73..74)]);
+        assert!(text_spans(&(20..30), 15).is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+//! Import of raw packets written as hexadecimal text.
+//!
+//! Each line holds one packet, as the hex bytes of the packet from its
+//! PID to its CRC, which may be separated by spaces. The line may start
+//! with a timestamp in seconds followed by a colon, for example:
+//!
+//! ```text
+//! # SETUP, DATA0 and ACK.
+//! 0.000100: 2D 00 10
+//! 0.000103: C3 80 06 00 01 00 00 12 00 E0 F4
+//! 0.000110: D2
+//! ```
+//!
+//! A packet without a timestamp is given that of the packet before it.
+//! Blank lines, and lines starting with `#`, are ignored.
+
+use anyhow::{Context, Error, bail};
+
+/// Whether the start of a file appears to be hex text.
+pub fn is_hex_text(start: &[u8]) -> bool {
+    for line in start.split(|&b| b == b'\n') {
+        match std::str::from_utf8(line).map(parse_line) {
+            Ok(Ok(Some(_))) => return true,
+            Ok(Ok(None)) => continue,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// A packet read from a line of hex text.
+pub struct Line {
+    /// Timestamp of the packet in nanoseconds, if given.
+    pub timestamp: Option<u64>,
+    /// The bytes of the packet.
+    pub packet: Vec<u8>,
+}
+
+/// Parse a line, returning its packet unless the line is blank or a
+/// comment.
+pub fn parse_line(line: &str) -> Result<Option<Line>, Error> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (timestamp, hex) = match line.split_once(':') {
+        Some((time, hex)) => (Some(parse_time(time.trim())?), hex),
+        None => (None, line),
+    };
+    let digits: Vec<u8> = hex
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    if digits.is_empty() {
+        bail!("No packet bytes given");
+    }
+    if digits.len() % 2 != 0 {
+        bail!("Odd number of hex digits");
+    }
+    let mut packet = Vec::with_capacity(digits.len() / 2);
+    for pair in digits.chunks(2) {
+        let pair = std::str::from_utf8(pair)?;
+        packet.push(u8::from_str_radix(pair, 16)
+            .with_context(|| format!("Invalid hex byte '{pair}'"))?);
+    }
+    Ok(Some(Line { timestamp, packet }))
+}
+
+/// Parse a timestamp in seconds, e.g. "1.000250", returning nanoseconds.
+pub fn parse_time(time: &str) -> Result<u64, Error> {
+    let invalid = || format!("Invalid timestamp '{time}'");
+    let (seconds, fraction) = time.split_once('.').unwrap_or((time, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        bail!(invalid());
+    }
+    let seconds: u64 = seconds.parse().with_context(invalid)?;
+    let fraction: u64 = format!("{fraction:0<9}").parse()?;
+    Ok(seconds * 1_000_000_000 + fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::Loader;
+
+    #[test]
+    fn test_hex_text_import() {
+        let text = "\
+# SETUP, DATA0 and ACK.
+1.000100: 2D 00 10
+1.000103: C380060001000012 00E0F4
+
+D2
+";
+        assert!(is_hex_text(text.as_bytes()));
+        assert!(!is_hex_text(b"Hello world\n"));
+        assert!(!is_hex_text(b"# Only a comment\n"));
+        let mut loader = Loader::open(text.as_bytes()).unwrap();
+        let mut packets = Vec::new();
+        while let Some(result) = loader.next() {
+            let (packet, timestamp) = result.unwrap();
+            packets.push((packet.data.to_vec(), timestamp));
+        }
+        assert_eq!(packets, [
+            (vec![0x2D, 0x00, 0x10], 0),
+            (vec![0xC3, 0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00,
+                  0xE0, 0xF4], 3_000),
+            (vec![0xD2], 3_000),
+        ]);
+        assert_eq!(loader.bytes_read, text.len() as u64);
+
+        let invalid = "2D 00 10\n2D 0\n";
+        let mut loader = Loader::open(invalid.as_bytes()).unwrap();
+        assert!(loader.next().unwrap().is_ok());
+        let error = loader.next().unwrap().err().unwrap();
+        assert_eq!(error.to_string(), "Invalid hex text on line 2");
+        assert_eq!(parse_time("2").unwrap(), 2_000_000_000);
+        assert!(parse_time("1.5e3").is_err());
+    }
+}
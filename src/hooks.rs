@@ -0,0 +1,413 @@
+//! User-supplied Rhai scripts run on traffic as it is decoded.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{Context, Error, anyhow};
+use rhai::{
+    Blob,
+    CallFnOptions,
+    Dynamic,
+    Engine,
+    Map,
+    Scope,
+    AST,
+    INT,
+};
+
+use crate::capture::{Endpoint, EndpointType};
+use crate::usb::{PID, validate_packet};
+
+pub const ENV_VAR: &str = "PACKETRY_SCRIPTS";
+
+const EXTENSION: &str = "rhai";
+
+/// Limit on the operations a script may take in one call, so that a
+/// script stuck in a loop cannot stall decoding.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// The points in decoding at which scripts are called.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Hook {
+    Packet,
+    Transaction,
+    Transfer,
+}
+
+impl Hook {
+    fn function(&self) -> &'static str {
+        match self {
+            Hook::Packet => "on_packet",
+            Hook::Transaction => "on_transaction",
+            Hook::Transfer => "on_transfer",
+        }
+    }
+}
+
+/// A transaction that has just been completed.
+pub struct TransactionEnd<'p> {
+    pub endpoint: Endpoint,
+    pub ep_type: EndpointType,
+    /// Time of the first packet of the transaction.
+    pub timestamp: u64,
+    /// PID of the first packet of the transaction.
+    pub pid: PID,
+    /// PID of the last packet of the transaction.
+    pub outcome: PID,
+    pub success: bool,
+    pub payload: Option<&'p [u8]>,
+}
+
+/// A transfer that has just been completed.
+pub struct TransferEnd {
+    pub endpoint: Endpoint,
+    pub ep_type: EndpointType,
+    /// Time of the first packet of the transfer.
+    pub timestamp: u64,
+    /// Number of bytes of data transferred.
+    pub bytes: u64,
+    /// Whether this is a group of NAKed transactions.
+    pub polling: bool,
+}
+
+/// Output of the scripts, collected as they run.
+#[derive(Default)]
+struct Output {
+    events: Vec<String>,
+    metrics: BTreeMap<String, Dynamic>,
+    metrics_changed: bool,
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+    hooks: Vec<Hook>,
+    state: Dynamic,
+    failed: bool,
+}
+
+/// The scripts run on one capture as it is decoded.
+pub struct Hooks {
+    engine: Engine,
+    scripts: Vec<Script>,
+    output: Arc<Mutex<Output>>,
+}
+
+impl Hooks {
+    /// Set up hooks for scripts compiled from the sources given, each
+    /// with its name.
+    pub fn new(sources: &[(String, AST)]) -> Hooks {
+        let output = Arc::new(Mutex::new(Output::default()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let events = output.clone();
+        engine.register_fn("event", move |text: &str| {
+            events.lock().unwrap().events.push(text.to_string());
+        });
+        let metrics = output.clone();
+        engine.register_fn("metric", move |name: &str, value: Dynamic| {
+            let mut output = metrics.lock().unwrap();
+            output.metrics.insert(name.to_string(), value);
+            output.metrics_changed = true;
+        });
+        let counts = output.clone();
+        engine.register_fn("count", move |name: &str| {
+            let mut output = counts.lock().unwrap();
+            let value = output.metrics
+                .entry(name.to_string())
+                .or_insert(Dynamic::from(0 as INT));
+            *value = Dynamic::from(value.as_int().unwrap_or(0) + 1);
+            output.metrics_changed = true;
+        });
+        let mut hooks = Hooks {
+            engine,
+            scripts: Vec::new(),
+            output,
+        };
+        for (name, ast) in sources {
+            let defined = ast.iter_functions()
+                .map(|function| function.name)
+                .collect::<Vec<_>>();
+            let mut script = Script {
+                name: name.clone(),
+                ast: ast.clone(),
+                hooks: [Hook::Packet, Hook::Transaction, Hook::Transfer]
+                    .into_iter()
+                    .filter(|hook| defined.contains(&hook.function()))
+                    .collect(),
+                state: Dynamic::from_map(Map::new()),
+                failed: false,
+            };
+            // Run the script's top-level statements once, at the start.
+            if let Err(e) = hooks.engine.run_ast(&script.ast) {
+                report(&hooks.output, &mut script, &e.to_string());
+            }
+            hooks.scripts.push(script);
+        }
+        hooks
+    }
+
+    /// Whether any script defines a hook.
+    fn wants(&self, hook: Hook) -> bool {
+        self.scripts.iter().any(|script| script.hooks.contains(&hook))
+    }
+
+    fn call(&mut self, hook: Hook, fields: Map) {
+        let argument = Dynamic::from_map(fields);
+        for script in &mut self.scripts {
+            if script.failed || !script.hooks.contains(&hook) {
+                continue;
+            }
+            let options = CallFnOptions::new()
+                .eval_ast(false)
+                .bind_this_ptr(&mut script.state);
+            let result = self.engine.call_fn_with_options::<Dynamic>(
+                options,
+                &mut Scope::new(),
+                &script.ast,
+                hook.function(),
+                (argument.clone(),));
+            if let Err(e) = result {
+                report(&self.output, script, &e.to_string());
+            }
+        }
+    }
+
+    /// Call scripts on a packet that has just been decoded.
+    pub fn packet(&mut self, index: u64, timestamp: u64, packet: &[u8]) {
+        if !self.wants(Hook::Packet) {
+            return;
+        }
+        let pid = validate_packet(packet).unwrap_or(PID::Malformed);
+        let mut fields = Map::new();
+        fields.insert("index".into(), (index as INT).into());
+        fields.insert("timestamp".into(), (timestamp as INT).into());
+        fields.insert("pid".into(), pid.to_string().into());
+        fields.insert("bytes".into(),
+                      Dynamic::from_blob(Blob::from(packet)));
+        self.call(Hook::Packet, fields);
+    }
+
+    /// Call scripts on a transaction that has just been completed.
+    pub fn transaction(&mut self, transaction: &TransactionEnd) {
+        if !self.wants(Hook::Transaction) {
+            return;
+        }
+        let mut fields = endpoint_fields(
+            &transaction.endpoint, transaction.ep_type);
+        let payload = match transaction.payload {
+            Some(payload) => Dynamic::from_blob(Blob::from(payload)),
+            None => Dynamic::UNIT,
+        };
+        fields.insert("timestamp".into(),
+                      (transaction.timestamp as INT).into());
+        fields.insert("pid".into(), transaction.pid.to_string().into());
+        fields.insert("outcome".into(),
+                      transaction.outcome.to_string().into());
+        fields.insert("success".into(), transaction.success.into());
+        fields.insert("payload".into(), payload);
+        self.call(Hook::Transaction, fields);
+    }
+
+    /// Call scripts on a transfer that has just been completed.
+    pub fn transfer(&mut self, transfer: &TransferEnd) {
+        if !self.wants(Hook::Transfer) {
+            return;
+        }
+        let mut fields = endpoint_fields(
+            &transfer.endpoint, transfer.ep_type);
+        fields.insert("timestamp".into(),
+                      (transfer.timestamp as INT).into());
+        fields.insert("bytes".into(), (transfer.bytes as INT).into());
+        fields.insert("polling".into(), transfer.polling.into());
+        self.call(Hook::Transfer, fields);
+    }
+
+    /// Take the events emitted by scripts since the last call.
+    pub fn take_events(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.output.lock().unwrap().events)
+    }
+
+    /// The values of all metrics, by name, if any have changed since the
+    /// last call.
+    pub fn changed_metrics(&mut self) -> Option<Vec<(String, String)>> {
+        let mut output = self.output.lock().unwrap();
+        if !output.metrics_changed {
+            return None;
+        }
+        output.metrics_changed = false;
+        Some(output.metrics
+            .iter()
+            .map(|(name, value)| (name.clone(), value.to_string()))
+            .collect())
+    }
+}
+
+/// Stop running a script after an error, and note why.
+fn report(output: &Mutex<Output>, script: &mut Script, message: &str) {
+    script.failed = true;
+    output.lock().unwrap().events.push(
+        format!("Script {} failed: {message}", script.name));
+}
+
+/// Fields describing the endpoint of a transaction or transfer.
+fn endpoint_fields(endpoint: &Endpoint, ep_type: EndpointType) -> Map {
+    let mut fields = Map::new();
+    fields.insert("endpoint".into(), endpoint.to_string().into());
+    fields.insert("device".into(),
+                  (endpoint.device_address().0 as INT).into());
+    fields.insert("number".into(), (endpoint.number().0 as INT).into());
+    fields.insert("direction".into(),
+                  endpoint.direction().to_string().into());
+    fields.insert("type".into(), ep_type.to_string().into());
+    fields
+}
+
+/// Compile a script.
+pub fn compile(source: &str) -> Result<AST, Error> {
+    Engine::new().compile(source).map_err(|e| anyhow!("{e}"))
+}
+
+fn load_file(path: &Path) -> Result<(String, AST), Error> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let ast = compile(&source)
+        .with_context(|| format!("Failed to compile {}", path.display()))?;
+    let name = path.file_stem()
+        .map_or_else(|| path.display().to_string(),
+                     |stem| stem.to_string_lossy().to_string());
+    Ok((name, ast))
+}
+
+fn load_scripts() -> Vec<(String, AST)> {
+    let mut scripts = Vec::new();
+    let paths = match std::env::var_os(ENV_VAR) {
+        Some(paths) => paths,
+        None => return scripts
+    };
+    let mut load = |path: &Path| match load_file(path) {
+        Ok(script) => scripts.push(script),
+        Err(e) => eprintln!("Failed to load script: {e:#}"),
+    };
+    for path in std::env::split_paths(&paths) {
+        if path.is_dir() {
+            let mut files: Vec<_> = match std::fs::read_dir(&path) {
+                Ok(entries) => entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension()
+                        .is_some_and(|ext| ext == EXTENSION))
+                    .collect(),
+                Err(e) => {
+                    eprintln!("Failed to read {}: {e}", path.display());
+                    continue;
+                }
+            };
+            files.sort();
+            for file in files {
+                load(&file);
+            }
+        } else {
+            load(&path);
+        }
+    }
+    scripts
+}
+
+/// Hooks for the user-supplied scripts, if there are any. The scripts
+/// are compiled on first use, and each capture gets its own hooks, so
+/// that scripts start afresh on each.
+pub fn load() -> Option<Hooks> {
+    static SCRIPTS: OnceLock<Vec<(String, AST)>> = OnceLock::new();
+    let scripts = SCRIPTS.get_or_init(load_scripts);
+    if scripts.is_empty() {
+        None
+    } else {
+        Some(Hooks::new(scripts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{create_capture, CaptureReader, MarkerKind};
+    use crate::decoder::{Decoder, decode_test_capture};
+
+    const SCRIPT: &str = r#"
+        metric("loaded", true);
+
+        fn on_packet(p) {
+            count("packets");
+        }
+
+        fn on_transaction(t) {
+            if t.outcome == "NAK" {
+                count(`NAKs on ${t.endpoint}`);
+            }
+        }
+
+        fn on_transfer(t) {
+            if t.polling {
+                return;
+            }
+            if !(t.endpoint in this) {
+                this[t.endpoint] = 0;
+                event(`First transfer on ${t.endpoint} (${t.type})`);
+            }
+            this[t.endpoint] += t.bytes;
+            metric(`Bytes on ${t.endpoint}`, this[t.endpoint]);
+        }
+    "#;
+
+    fn decode(sources: &[(String, AST)]) -> CaptureReader {
+        let (writer, reader) = create_capture().unwrap();
+        let hooks = Hooks::new(sources);
+        let mut decoder = Decoder::with_hooks(writer, Some(hooks)).unwrap();
+        decode_test_capture("mouse", &mut decoder, |_, _, _| {});
+        decoder.finish().unwrap();
+        reader
+    }
+
+    fn notes(capture: &CaptureReader) -> Vec<String> {
+        capture.shared.markers
+            .load()
+            .iter()
+            .filter_map(|marker| match &marker.kind {
+                MarkerKind::Note(text) => Some(text.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hooks() {
+        let ast = compile(SCRIPT).unwrap();
+        let capture = decode(&[("test".to_string(), ast)]);
+        let metrics = capture.shared.metrics.load();
+        let metric = |name: &str| metrics
+            .iter()
+            .find(|(metric, _)| metric == name)
+            .map(|(_, value)| value.clone());
+        let packets = capture.packet_index.len();
+        assert_eq!(metric("packets"), Some(packets.to_string()));
+        assert_eq!(metric("loaded"), Some("true".to_string()));
+        assert!(metric("NAKs on 4.1 IN").is_some());
+        let bytes: u64 = metric("Bytes on 4.1 IN").unwrap().parse().unwrap();
+        assert!(bytes > 0);
+        let notes = notes(&capture);
+        assert!(notes.contains(
+            &"First transfer on 4.1 IN (Interrupt)".to_string()));
+    }
+
+    #[test]
+    fn test_failure() {
+        assert!(compile("fn on_packet(p) {").is_err());
+        let ast = compile("fn on_packet(p) { p.missing() }").unwrap();
+        let capture = decode(&[("broken".to_string(), ast)]);
+        let notes = notes(&capture);
+        assert_eq!(notes.len(), 1);
+        assert!(notes[0].starts_with("Script broken failed: "));
+        assert!(capture.shared.metrics.load().is_empty());
+    }
+}
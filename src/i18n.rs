@@ -0,0 +1,294 @@
+//! Translation of user-visible text, using message catalogs in the GNU
+//! gettext PO format.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{Context, Error, bail};
+
+/// Subdirectory of each data directory in which catalogs are kept.
+pub const LOCALE_DIR: &str = "packetry/locale";
+
+/// Extension of catalog files.
+const EXTENSION: &str = "po";
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+/// Translate a message.
+///
+/// With only a message, gives the translation as a `&'static str`. With
+/// values, gives a `String` with the values put in place of the `{}`
+/// placeholders, formatted as by `format!` when there is no translation.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! tr {
+    ($msg:literal) => {
+        $crate::i18n::translate($msg)
+    };
+    ($msg:literal, $($arg:expr),+ $(,)?) => {
+        match $crate::i18n::translation($msg) {
+            Some(template) => $crate::i18n::substitute(template,
+                &[$(&$arg as &dyn std::fmt::Display),+]),
+            None => format!($msg, $($arg),+),
+        }
+    };
+}
+
+#[doc(hidden)]
+pub use tr;
+
+/// Translations of messages, as loaded from a catalog.
+#[derive(Default)]
+pub struct Catalog {
+    messages: HashMap<String, String>,
+}
+
+/// The part of a catalog entry being read.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Part {
+    None,
+    Id,
+    Str,
+}
+
+impl Catalog {
+    /// Read a catalog in PO format.
+    pub fn load(source: impl Read) -> Result<Catalog, Error> {
+        let mut catalog = Catalog::default();
+        let mut id = String::new();
+        let mut text = String::new();
+        let mut part = Part::None;
+        for (index, line) in BufReader::new(source).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            let number = index + 1;
+            let (keyword, quoted) = match line.split_once(' ') {
+                _ if line.is_empty() || line.starts_with('#') => continue,
+                _ if line.starts_with('"') => ("", line),
+                Some((keyword, quoted)) => (keyword, quoted.trim_start()),
+                None => bail!("Invalid line {number} in catalog"),
+            };
+            let value = unquote(quoted)
+                .with_context(|| format!("Invalid line {number} in catalog"))?;
+            match (keyword, part) {
+                ("msgid", _) => {
+                    catalog.add(&id, &text);
+                    id = value;
+                    text.clear();
+                    part = Part::Id;
+                },
+                ("msgstr", Part::Id) => {
+                    text = value;
+                    part = Part::Str;
+                },
+                ("", Part::Id) => id.push_str(&value),
+                ("", Part::Str) => text.push_str(&value),
+                ("msgstr" | "", _) =>
+                    bail!("Unexpected text on line {number} of catalog"),
+                (keyword, _) =>
+                    bail!("Unsupported keyword '{keyword}' on line {number} \
+                           of catalog"),
+            }
+        }
+        catalog.add(&id, &text);
+        Ok(catalog)
+    }
+
+    fn add(&mut self, id: &str, text: &str) {
+        // The entry with an empty ID is the catalog header, and entries
+        // with empty text are not yet translated.
+        if !id.is_empty() && !text.is_empty() {
+            self.messages.insert(id.to_string(), text.to_string());
+        }
+    }
+
+    /// The translation of a message, if the catalog has one.
+    pub fn translation(&self, msgid: &str) -> Option<&str> {
+        self.messages.get(msgid).map(String::as_str)
+    }
+}
+
+/// Remove the quotes and escapes from a PO string.
+fn unquote(quoted: &str) -> Result<String, Error> {
+    let inner = quoted
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .context("String not in quotes")?;
+    let mut value = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        value.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(other) => bail!("Unsupported escape '\\{other}'"),
+            None => bail!("String ends with '\\'"),
+        });
+    }
+    Ok(value)
+}
+
+/// Put values in place of the placeholders in a message.
+///
+/// Each `{}` takes the next value, and `{N}` takes value N, counting from
+/// zero. `{{` and `}}` stand for single braces. Placeholders without a
+/// value are left as they are.
+pub fn substitute(template: &str, args: &[&dyn Display]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut next = 0;
+    let mut rest = template;
+    while let Some(offset) = rest.find(['{', '}']) {
+        result.push_str(&rest[..offset]);
+        rest = &rest[offset..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            result.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        let placeholder = rest
+            .strip_prefix('{')
+            .and_then(|inner| inner.split_once('}'))
+            .and_then(|(index, _)| match index {
+                "" => {
+                    next += 1;
+                    Some(next - 1)
+                },
+                index => index.parse::<usize>().ok()
+            })
+            .and_then(|index| args.get(index));
+        match placeholder {
+            Some(arg) => {
+                let _ = write!(result, "{arg}");
+                rest = &rest[rest.find('}').unwrap() + 1..];
+            },
+            None => {
+                result.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// The translation of a message in the loaded catalog, if any.
+pub fn translation(msgid: &str) -> Option<&'static str> {
+    CATALOG.get()?.translation(msgid)
+}
+
+/// The translation of a message, or the message itself if none.
+pub fn translate(msgid: &'static str) -> &'static str {
+    translation(msgid).unwrap_or(msgid)
+}
+
+/// The user's preferred languages, most preferred first, each followed
+/// by its language without the region, e.g. `de_AT` then `de`.
+fn languages() -> Vec<String> {
+    let setting = ["LANGUAGE", "LC_ALL", "LC_MESSAGES", "LANG"]
+        .into_iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .unwrap_or_default();
+    let mut languages = Vec::new();
+    for locale in setting.split(':') {
+        // Drop any encoding and modifier, e.g. from "de_AT.UTF-8@euro".
+        let locale = locale.split(['.', '@']).next().unwrap_or_default();
+        if matches!(locale, "" | "C" | "POSIX") {
+            continue;
+        }
+        let language = locale.split('_').next().unwrap_or_default();
+        for name in [locale, language] {
+            if !languages.iter().any(|known| known == name) {
+                languages.push(name.to_string());
+            }
+        }
+    }
+    languages
+}
+
+/// Find the catalog file for the user's language, searching the
+/// directories given in order.
+fn catalog_file(dirs: &[PathBuf]) -> Option<PathBuf> {
+    languages().iter().find_map(|language|
+        dirs.iter()
+            .map(|dir| dir.join(language).with_extension(EXTENSION))
+            .find(|path| path.is_file()))
+}
+
+/// Load the catalog for the user's language, if there is one in any of
+/// the directories given.
+pub fn load(dirs: &[PathBuf]) -> Result<(), Error> {
+    if let Some(path) = catalog_file(dirs) {
+        let catalog = load_file(&path)?;
+        let _ = CATALOG.set(catalog);
+    }
+    Ok(())
+}
+
+fn load_file(path: &Path) -> Result<Catalog, Error> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    Catalog::load(file)
+        .with_context(|| format!("Failed to load {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog() {
+        let text = "# Test catalog\n\
+                    msgid \"\"\n\
+                    msgstr \"Content-Type: text/plain; charset=UTF-8\\n\"\n\
+                    \n\
+                    msgid \"Getting status\"\n\
+                    msgstr \"Status \"\n\
+                    \"abfragen\"\n\
+                    \n\
+                    msgid \"Setting interface {} to {}\"\n\
+                    msgstr \"Schnittstelle {0} auf {1} setzen\"\n\
+                    \n\
+                    msgid \"Not translated\"\n\
+                    msgstr \"\"\n\
+                    \n\
+                    msgid \"Quote \\\"{}\\\"\\n\"\n\
+                    msgstr \"Zitat \\\"{}\\\"\\n\"\n";
+        let catalog = Catalog::load(text.as_bytes()).unwrap();
+        assert_eq!(catalog.messages.len(), 3);
+        assert_eq!(catalog.translation("Getting status"),
+                   Some("Status abfragen"));
+        assert_eq!(catalog.translation("Not translated"), None);
+        assert_eq!(catalog.translation(""), None);
+        assert_eq!(catalog.translation("Quote \"{}\"\n"),
+                   Some("Zitat \"{}\"\n"));
+        let template =
+            catalog.translation("Setting interface {} to {}").unwrap();
+        assert_eq!(substitute(template, &[&1, &2]),
+                   "Schnittstelle 1 auf 2 setzen");
+        assert!(Catalog::load("msgstr \"x\"".as_bytes()).is_err());
+        assert!(Catalog::load("msgid \"x\\q\"".as_bytes()).is_err());
+        assert!(Catalog::load("msgctxt \"x\"".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_substitute() {
+        assert_eq!(substitute("{} of {}", &[&3, &"four"]), "3 of four");
+        assert_eq!(substitute("{1} before {0}", &[&"a", &"b"]),
+                   "b before a");
+        assert_eq!(substitute("{{{}}}", &[&5]), "{5}");
+        assert_eq!(substitute("{} and {}", &[&1]), "1 and {}");
+        assert_eq!(substitute("{x} {", &[&1]), "{x} {");
+        // Without a catalog loaded, messages are formatted as usual.
+        assert_eq!(tr!("Setting address to {}", 5), "Setting address to 5");
+        assert_eq!(tr!("Getting status"), "Getting status");
+    }
+}
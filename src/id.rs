@@ -4,12 +4,20 @@ use std::mem::size_of;
 use std::ops::{Add, AddAssign, Sub, SubAssign};
 use std::ops::Range;
 
-#[derive(Copy, Clone)]
 pub struct Id<T> {
    _marker: PhantomData<T>,
    pub value: u64
 }
 
+// Implemented by hand, so that IDs can be copied whatever they refer to.
+impl<T> Copy for Id<T> {}
+
+impl<T> Clone for Id<T> {
+   fn clone(&self) -> Self {
+      *self
+   }
+}
+
 impl<T> Eq for Id<T> {}
 
 impl<T> Ord for Id<T> {
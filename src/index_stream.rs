@@ -1,4 +1,5 @@
 use std::cmp::min;
+use std::io::{Read, Write};
 use std::marker::PhantomData;
 use std::ops::Range;
 
@@ -70,6 +71,11 @@ where Position: From<u64>, Value: Into<u64>
         let position = Position::from(id.into());
         Ok(position)
     }
+
+    /// Append the contents of an index saved by [IndexReader::save].
+    pub fn restore(&mut self, source: &mut impl Read) -> Result<(), Error> {
+        self.data_writer.restore(source)
+    }
 }
 
 impl<Position, Value, const S: usize> IndexReader<Position, Value, S>
@@ -86,6 +92,11 @@ where Position: Copy + From<u64> + Into<u64>,
         self.data_reader.size()
     }
 
+    /// Write the current contents of the index.
+    pub fn save(&mut self, dest: &mut impl Write) -> Result<(), Error> {
+        self.data_reader.save(dest)
+    }
+
     /// Get a single value from the index, by position.
     pub fn get(&mut self, position: Position) -> Result<Value, Error> {
         let id = Id::<u64>::from(position.into());
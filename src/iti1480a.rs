@@ -0,0 +1,157 @@
+//! Import of captures made with the International Test Instruments
+//! ITI1480A analyzer.
+//!
+//! The analyzer monitors the bus through a ULPI PHY, and reports what the
+//! PHY sees as a stream of 16-bit records, each stored on disk with its
+//! bytes swapped. The high byte of each record holds the record type in
+//! its upper four bits and a time count in its lower four bits, and the
+//! low byte holds the payload.
+//!
+//! The time count gives the number of 60MHz ULPI clock cycles since the
+//! previous record. Longer intervals are given by time records, whose
+//! twelve bits of count are in units of 16 cycles.
+//!
+//! RXCMD records report changes in the PHY's receive state, and data
+//! records the bytes received while it is active. Each period of activity
+//! is one packet on the bus.
+
+use anyhow::{Error, bail};
+
+/// File name extension used for ITI1480A captures.
+const EXTENSION: &str = ".usb";
+
+/// Record types, found in the upper four bits of the high byte.
+const TYPE_TIME: u8 = 0x8;
+const TYPE_RXCMD: u8 = 0xC;
+const TYPE_DATA: u8 = 0xE;
+const TYPE_EVENT: u8 = 0xF;
+
+/// Mask and values of the RxEvent field of a ULPI RXCMD byte.
+const RX_EVENT_MASK: u8 = 0x30;
+const RX_ACTIVE: u8 = 0x10;
+const RX_ERROR: u8 = 0x30;
+
+/// Check whether a file name has the ITI1480A capture file extension.
+pub fn is_capture_name(name: &str) -> bool {
+    name.to_ascii_lowercase().ends_with(EXTENSION)
+}
+
+/// Convert a count of 60MHz clock cycles to nanoseconds.
+fn cycles_to_ns(cycles: u64) -> u64 {
+    cycles * 50 / 3
+}
+
+/// Reassembles packets from the records of an ITI1480A capture.
+#[derive(Default)]
+pub struct Parser {
+    /// Clock cycles since the start of the capture.
+    cycles: u64,
+    /// The packet being received, with the cycle count at its start.
+    packet: Option<(Vec<u8>, u64)>,
+}
+
+impl Parser {
+    /// Handle a record as stored on disk, returning a packet with its
+    /// timestamp in nanoseconds if the record completed one.
+    pub fn handle(&mut self, bytes: [u8; 2])
+        -> Result<Option<(Vec<u8>, u64)>, Error>
+    {
+        let [payload, head] = bytes;
+        let record_type = head >> 4;
+        let count = (head & 0x0F) as u64;
+        if record_type == TYPE_TIME {
+            self.cycles += (count << 8 | payload as u64) << 4;
+            return Ok(None);
+        }
+        self.cycles += count;
+        match record_type {
+            TYPE_RXCMD => match payload & RX_EVENT_MASK {
+                // On an error, the bytes received so far are kept, so
+                // that the decoder can show the malformed packet.
+                RX_ACTIVE | RX_ERROR => {
+                    if self.packet.is_none() {
+                        self.packet = Some((Vec::new(), self.cycles));
+                    }
+                    Ok(None)
+                },
+                _ => Ok(self.finish()),
+            },
+            TYPE_DATA => {
+                let cycles = self.cycles;
+                self.packet
+                    .get_or_insert_with(|| (Vec::new(), cycles))
+                    .0
+                    .push(payload);
+                Ok(None)
+            },
+            // Events report analyzer state, not bus traffic.
+            TYPE_EVENT => Ok(None),
+            _ => bail!("Unknown ITI1480A record type {record_type:X}")
+        }
+    }
+
+    /// Finish receiving the current packet, if any bytes were received.
+    pub fn finish(&mut self) -> Option<(Vec<u8>, u64)> {
+        match self.packet.take() {
+            Some((data, cycles)) if !data.is_empty() =>
+                Some((data, cycles_to_ns(cycles))),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::{InputFormat, Loader};
+
+    /// Build a record as stored on disk.
+    fn record(record_type: u8, count: u8, payload: u8) -> [u8; 2] {
+        [payload, record_type << 4 | count]
+    }
+
+    #[test]
+    fn test_iti1480a_import() {
+        let records = [
+            record(TYPE_EVENT, 0, 0x01),
+            // An IN token, 60 cycles from the start.
+            record(TYPE_TIME, 0, 3),
+            record(TYPE_RXCMD, 12, RX_ACTIVE),
+            record(TYPE_DATA, 1, 0x69),
+            record(TYPE_DATA, 1, 0x82),
+            record(TYPE_DATA, 1, 0x18),
+            record(TYPE_RXCMD, 1, 0x00),
+            // A NAK handshake, with a line state change in between.
+            record(TYPE_RXCMD, 8, 0x01),
+            record(TYPE_RXCMD, 6, RX_ACTIVE),
+            record(TYPE_DATA, 1, 0x5A),
+            record(TYPE_RXCMD, 1, RX_ERROR),
+            record(TYPE_RXCMD, 1, 0x00),
+        ];
+        let data: Vec<u8> = records.concat();
+        let mut loader =
+            Loader::open_as(data.as_slice(), InputFormat::Iti1480a).unwrap();
+        let mut packets = Vec::new();
+        while let Some(result) = loader.next() {
+            let (packet, timestamp) = result.unwrap();
+            packets.push((packet.data.to_vec(), timestamp));
+        }
+        assert_eq!(packets, [
+            (vec![0x69, 0x82, 0x18], 0),
+            (vec![0x5A], 300),
+        ]);
+        assert_eq!(loader.bytes_read, data.len() as u64);
+
+        // A capture ending part way through a record is truncated.
+        let truncated = &data[..data.len() - 1];
+        let mut loader =
+            Loader::open_as(truncated, InputFormat::Iti1480a).unwrap();
+        let result: Result<Vec<_>, Error> = std::iter::from_fn(|| {
+            loader.next().map(|result| result.map(|_| ()))
+        }).collect();
+        assert!(result.is_err());
+
+        assert!(is_capture_name("trace.USB"));
+        assert!(!is_capture_name("trace.pcap"));
+    }
+}
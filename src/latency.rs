@@ -0,0 +1,313 @@
+//! Analysis and display of the latency of transfers on an endpoint.
+//!
+//! For interrupt and bulk endpoints, the time a transfer takes from the
+//! host's first attempt to its completion is measured, including any
+//! transactions the device NAKed while it had nothing to send or no room
+//! to receive. The time spent in each run of NAKed transactions before a
+//! transaction succeeds is measured separately. Each set of times is
+//! collected into a distribution, from which percentiles and histograms
+//! are taken.
+
+use std::ops::Range;
+
+use anyhow::{Error, bail};
+use gtk::prelude::*;
+use gtk::{glib, Align, Label, Orientation};
+
+use crate::capture::{
+    CaptureReader,
+    EndpointId,
+    EndpointTransactionId,
+    EndpointTransferId,
+    EndpointType,
+    Timestamp,
+};
+use crate::timeline::{set_color, Palette};
+use crate::usb::{self, PID};
+use crate::util::{fmt_count, fmt_duration};
+
+/// A set of durations in nanoseconds.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Distribution {
+    /// The durations, in ascending order.
+    samples: Vec<Timestamp>,
+}
+
+impl Distribution {
+    /// Collect a set of durations.
+    pub fn new(mut samples: Vec<Timestamp>) -> Distribution {
+        samples.sort_unstable();
+        Distribution { samples }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn min(&self) -> Option<Timestamp> {
+        self.samples.first().copied()
+    }
+
+    pub fn max(&self) -> Option<Timestamp> {
+        self.samples.last().copied()
+    }
+
+    /// The duration that the given percentage of durations are no longer
+    /// than, by the nearest-rank method.
+    pub fn percentile(&self, percent: f64) -> Option<Timestamp> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let count = self.samples.len();
+        let rank = (percent / 100.0 * count as f64).ceil() as usize;
+        Some(self.samples[rank.clamp(1, count) - 1])
+    }
+
+    /// Count the durations in a number of equal ranges, covering the
+    /// shortest duration to the longest.
+    pub fn histogram(&self, bins: usize) -> Vec<(Range<Timestamp>, usize)> {
+        let (Some(min), Some(max)) = (self.min(), self.max()) else {
+            return Vec::new();
+        };
+        let bins = bins.max(1) as u64;
+        let width = ((max - min) / bins + 1).max(1);
+        let mut histogram: Vec<(Range<Timestamp>, usize)> = (0..bins)
+            .map(|index| {
+                let start = min + index * width;
+                (start..(start + width), 0)
+            })
+            .collect();
+        for &sample in &self.samples {
+            histogram[((sample - min) / width) as usize].1 += 1;
+        }
+        histogram
+    }
+}
+
+impl std::fmt::Display for Distribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (Some(min), Some(max)) = (self.min(), self.max()) else {
+            return write!(f, "none");
+        };
+        write!(f, "{}: min {}", fmt_count(self.len() as u64),
+               fmt_duration(min))?;
+        for percent in [50, 95, 99] {
+            if let Some(duration) = self.percentile(percent as f64) {
+                write!(f, ", p{percent} {}", fmt_duration(duration))?;
+            }
+        }
+        write!(f, ", max {}", fmt_duration(max))
+    }
+}
+
+/// Latency of the transfers on an endpoint.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EndpointLatency {
+    /// Time from the first attempt at each completed transfer to the end
+    /// of its last transaction.
+    pub transfers: Distribution,
+    /// Time from the first of each run of NAKed transactions to the start
+    /// of the transaction that then succeeded.
+    pub nak_retries: Distribution,
+}
+
+/// Measure the latency of the transfers on an interrupt or bulk endpoint.
+pub fn endpoint_latency(capture: &mut CaptureReader, endpoint_id: EndpointId)
+    -> Result<EndpointLatency, Error>
+{
+    use usb::EndpointType::{Bulk, Interrupt};
+    match capture.endpoint_type(endpoint_id)? {
+        EndpointType::Normal(Bulk | Interrupt) => {},
+        ep_type => bail!("Latency is measured for bulk and interrupt \
+                          endpoints, but this endpoint is {ep_type}"),
+    }
+    let mut transfers = Vec::new();
+    let mut nak_retries = Vec::new();
+    // Start of the first attempt at the current transfer.
+    let mut request_start = None;
+    // Start of the current run of NAKed transactions.
+    let mut retry_start = None;
+    let ep_traf = capture.endpoint_traffic(endpoint_id)?;
+    let transaction_count = ep_traf.transaction_ids.len();
+    let transfer_count = ep_traf.transfer_index.len();
+    for i in 0..transfer_count {
+        let ep_transaction_ids = capture
+            .endpoint_traffic(endpoint_id)?
+            .transfer_index
+            .target_range(EndpointTransferId::from(i), transaction_count)?;
+        let mut completed = None;
+        let mut all_naked = true;
+        for j in ep_transaction_ids.start.value..ep_transaction_ids.end.value {
+            let transaction_id = capture
+                .endpoint_traffic(endpoint_id)?
+                .transaction_ids
+                .get(EndpointTransactionId::from(j))?;
+            let transaction = capture.transaction(transaction_id)?;
+            let packets = &transaction.packet_id_range;
+            let start = capture.packet_time(packets.start)?;
+            request_start.get_or_insert(start);
+            if transaction.outcome() == Some(PID::NAK) {
+                retry_start.get_or_insert(start);
+                continue;
+            }
+            all_naked = false;
+            if let Some(retry_start) = retry_start.take() {
+                nak_retries.push(start - retry_start);
+            }
+            if transaction.successful() {
+                completed = Some(capture.packet_time(packets.end - 1)?);
+            }
+        }
+        match (completed, request_start) {
+            (Some(end), Some(start)) => {
+                transfers.push(end - start);
+                request_start = None;
+            },
+            // A group of NAKed polls is part of the attempt at the next
+            // transfer; anything else ends the attempt.
+            _ if all_naked => {},
+            _ => request_start = None,
+        }
+    }
+    Ok(EndpointLatency {
+        transfers: Distribution::new(transfers),
+        nak_retries: Distribution::new(nak_retries),
+    })
+}
+
+/// Number of ranges of durations in the latency histograms.
+const HISTOGRAM_BINS: usize = 30;
+
+/// Draw a histogram of a distribution of durations, labelled with the
+/// shortest and longest durations below it.
+fn draw_histogram(distribution: &Distribution,
+                  context: &gtk::cairo::Context,
+                  width: i32,
+                  height: i32)
+    -> Result<(), gtk::cairo::Error>
+{
+    let (width, height) = (width as f64, height as f64);
+    let palette = Palette::current();
+    set_color(context, palette.background);
+    context.paint()?;
+    let histogram = distribution.histogram(HISTOGRAM_BINS);
+    let (Some((first, _)), Some((last, _))) =
+        (histogram.first(), histogram.last())
+    else {
+        return Ok(());
+    };
+    let max = histogram.iter().map(|(_, count)| *count).max().unwrap_or(1);
+    // Leave space at the bottom for the labels.
+    let bottom = height - 16.0;
+    let bar_width = width / histogram.len() as f64;
+    context.set_source_rgb(0.3, 0.5, 0.8);
+    for (index, (_, count)) in histogram.iter().enumerate() {
+        let bar = bottom * *count as f64 / max as f64;
+        context.rectangle(
+            index as f64 * bar_width + 1.0, bottom - bar,
+            (bar_width - 2.0).max(1.0), bar);
+    }
+    context.fill()?;
+    set_color(context, palette.text);
+    context.move_to(2.0, height - 4.0);
+    context.show_text(&fmt_duration(first.start))?;
+    let text = fmt_duration(last.end - 1);
+    let extents = context.text_extents(&text)?;
+    context.move_to(width - extents.width() - 2.0, height - 4.0);
+    context.show_text(&text)?;
+    Ok(())
+}
+
+/// Show the distributions of the latency of an endpoint as histograms.
+pub fn latency_view(latency: EndpointLatency) -> gtk::Box {
+    let content = gtk::Box::builder()
+        .orientation(Orientation::Vertical)
+        .spacing(5)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_top(5)
+        .margin_bottom(5)
+        .build();
+    for (title, distribution) in [
+        ("Request to completion", latency.transfers),
+        ("NAK retries", latency.nak_retries),
+    ] {
+        let label = Label::builder().halign(Align::Start).build();
+        label.set_markup(&format!(
+            "<b>{title}</b>\n{}",
+            glib::markup_escape_text(&distribution.to_string())));
+        let area = gtk::DrawingArea::builder()
+            .content_width(480)
+            .content_height(160)
+            .hexpand(true)
+            .vexpand(true)
+            .build();
+        area.set_draw_func(move |_, context, width, height| {
+            // Nothing can be done about a failure to draw.
+            let _ = draw_histogram(&distribution, context, width, height);
+        });
+        content.append(&label);
+        content.append(&area);
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::load_test_capture;
+    use crate::usb::EndpointNum;
+
+    #[test]
+    fn test_distribution() {
+        let distribution = Distribution::new((1..=100).rev().collect());
+        assert_eq!(distribution.len(), 100);
+        assert_eq!(distribution.min(), Some(1));
+        assert_eq!(distribution.max(), Some(100));
+        assert_eq!(distribution.percentile(50.0), Some(50));
+        assert_eq!(distribution.percentile(95.0), Some(95));
+        assert_eq!(distribution.percentile(99.0), Some(99));
+        assert_eq!(distribution.percentile(0.0), Some(1));
+        assert_eq!(distribution.percentile(100.0), Some(100));
+        let histogram = distribution.histogram(4);
+        assert_eq!(histogram.len(), 4);
+        assert_eq!(histogram[0].0, 1..26);
+        assert_eq!(histogram.iter().map(|(_, count)| count).sum::<usize>(),
+                   100);
+        assert!(histogram.iter().all(|(_, count)| *count == 25));
+        assert_eq!(Distribution::new(vec![500, 1500, 2_500_000]).to_string(),
+                   "3: min 500 ns, p50 1.5 µs, p95 2.500 ms, p99 2.500 ms, \
+                    max 2.500 ms");
+        assert_eq!(Distribution::default().to_string(), "none");
+
+        let single = Distribution::new(vec![7]);
+        assert_eq!(single.percentile(99.0), Some(7));
+        assert_eq!(single.histogram(3)[0], (7..8, 1));
+        assert!(Distribution::default().histogram(3).is_empty());
+        assert_eq!(Distribution::default().percentile(50.0), None);
+    }
+
+    #[test]
+    fn test_endpoint_latency() {
+        let mut reader = load_test_capture("mouse");
+
+        let mut measured = false;
+        for i in 0..reader.endpoints.len() {
+            let endpoint_id = EndpointId::from(i);
+            let endpoint = reader.endpoints.get(endpoint_id).unwrap();
+            let result = endpoint_latency(&mut reader, endpoint_id);
+            if endpoint.number() != EndpointNum(1) {
+                assert!(result.is_err());
+                continue;
+            }
+            // The mouse's interrupt endpoint is polled until it has a
+            // report to send.
+            let latency = result.unwrap();
+            assert!(!latency.transfers.samples.is_empty());
+            assert!(!latency.nak_retries.samples.is_empty());
+            assert!(latency.nak_retries.max() <= latency.transfers.max());
+            measured = true;
+        }
+        assert!(measured);
+    }
+}
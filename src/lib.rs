@@ -0,0 +1,118 @@
+//! Packetry's USB decoding engine, for use by other programs.
+//!
+//! This library loads captures in any of the formats that the Packetry
+//! application can open, and decodes their packets into the same
+//! transactions and transfers as shown in its Traffic pane:
+//!
+//! ```no_run
+//! use packetry::Capture;
+//!
+//! let mut capture = Capture::open("capture.pcap")?;
+//! for transfer in capture.transfers() {
+//!     let transfer = transfer?;
+//!     println!("{}", capture.transfer_summary(&transfer)?);
+//!     for transaction in capture.transfer_transactions(&transfer) {
+//!         for packet in capture.transaction_packets(&transaction?) {
+//!             let packet = packet?;
+//!             println!("  {}: {:?}", packet.timestamp(), packet.fields());
+//!         }
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The iterators read items from the capture as they are reached, so
+//! large captures can be processed without loading them into memory.
+//! Transactions can also be found with [Capture::query], by the
+//! [Criteria] they meet.
+//!
+//! The items exported here follow semantic versioning. The modules behind
+//! them are shared with the application, and may change at any time.
+
+// The engine's types are public only for the application's use, so are
+// not held to the conventions expected of a public API.
+#![allow(clippy::len_without_is_empty, clippy::should_implement_trait)]
+
+// We need the bitfield macro.
+#[macro_use]
+extern crate bitfield;
+
+mod api;
+
+// The modules of the engine are also used by the application, but are
+// not part of the library's API.
+#[doc(hidden)]
+pub mod backend;
+#[doc(hidden)]
+pub mod beagle;
+#[doc(hidden)]
+pub mod btsnoop;
+#[doc(hidden)]
+pub mod capture;
+#[doc(hidden)]
+pub mod class;
+#[doc(hidden)]
+pub mod compact_index;
+#[doc(hidden)]
+pub mod compression;
+#[doc(hidden)]
+pub mod data_stream;
+#[doc(hidden)]
+pub mod decoder;
+#[doc(hidden)]
+pub mod detail;
+#[doc(hidden)]
+pub mod events;
+#[doc(hidden)]
+pub mod hex_text;
+#[doc(hidden)]
+pub mod hooks;
+#[doc(hidden)]
+pub mod i18n;
+#[doc(hidden)]
+pub mod id;
+#[doc(hidden)]
+pub mod index_stream;
+#[doc(hidden)]
+pub mod iti1480a;
+#[doc(hidden)]
+pub mod native;
+#[doc(hidden)]
+pub mod openvizsla;
+#[doc(hidden)]
+pub mod pcap;
+#[doc(hidden)]
+pub mod query;
+#[doc(hidden)]
+pub mod rcu;
+#[doc(hidden)]
+pub mod search;
+#[doc(hidden)]
+pub mod stream;
+#[doc(hidden)]
+pub mod usb;
+#[doc(hidden)]
+pub mod usbip;
+#[doc(hidden)]
+pub mod usbmon;
+#[doc(hidden)]
+pub mod util;
+#[doc(hidden)]
+pub mod vec_map;
+
+pub use api::{
+    Capture,
+    Endpoint,
+    Fields,
+    Packet,
+    Packets,
+    Setup,
+    Transaction,
+    Transactions,
+    Transfer,
+    TransferKind,
+    Transfers,
+};
+pub use query::Criteria;
+pub use search::BytePattern;
+pub use usb::{Direction, PID};
@@ -1,10 +1,6 @@
 // On Windows, produce a GUI app rather than a console one.
 #![windows_subsystem = "windows"]
 
-// We need the bitfield macro.
-#[macro_use]
-extern crate bitfield;
-
 // We need the ctor macro for the replay test on macOS.
 #[cfg(all(test, target_os="macos"))]
 #[allow(unused_imports)]
@@ -17,27 +13,58 @@ pub mod built {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
-// Declare all modules used.
-mod backend;
-mod capture;
-mod compact_index;
-mod data_stream;
-mod decoder;
-mod id;
-mod index_stream;
+// Use the decoding engine from the library.
+use packetry::{
+    backend,
+    btsnoop,
+    capture,
+    class,
+    decoder,
+    detail,
+    events,
+    hex_text,
+    hooks,
+    i18n,
+    iti1480a,
+    native,
+    pcap,
+    query,
+    search,
+    stream,
+    usb,
+    util,
+};
+
+// Declare the modules of the application.
+mod check;
+mod commands;
+mod compare;
+mod context_menu;
+mod copy;
+mod descriptors;
+mod facedancer;
+mod hex_dump;
 mod item_widget;
+mod latency;
+mod merge;
 mod model;
-mod pcap;
-mod rcu;
+mod preferences;
+mod preferences_dialog;
+mod recovery;
+mod report;
 mod row_data;
-mod stream;
+mod rpc;
+mod session;
+mod stats;
+mod swimlane;
 mod test_cynthion;
+mod throughput;
+mod timeline;
+mod timestamp;
 mod tree_list_model;
 mod ui;
-mod usb;
-mod util;
-mod vec_map;
 mod version;
+mod view_export;
 
 // Declare optional modules.
 #[cfg(any(test, feature="record-ui-test"))]
@@ -45,14 +72,20 @@ mod record_ui;
 #[cfg(test)]
 mod test_replay;
 
+use std::sync::Arc;
+
 use gtk::prelude::*;
 use gtk::gio::ApplicationFlags;
 use gtk::glib::{self, OptionArg, OptionFlags};
 
+use preferences::Preferences;
 use ui::{
     activate,
+    apply_theme,
     display_error,
+    load_preferences,
     open,
+    rpc_request,
     stop_operation
 };
 use version::{version, version_info};
@@ -61,6 +94,19 @@ fn have_argument(name: &str) -> bool {
     std::env::args().any(|arg| arg == name)
 }
 
+fn argument_value(name: &str) -> Option<String> {
+    let prefix = format!("{name}=");
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        } else if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
 fn main() {
     // On Windows, this env var will be set by the packetry-cli wrapper.
     #[cfg(windows)]
@@ -69,7 +115,34 @@ fn main() {
         unsafe {AttachConsole(ATTACH_PARENT_PROCESS)};
     }
 
-    if have_argument("--version") {
+    // Translate messages into the user's language, if a catalog exists.
+    let locale_dirs: Vec<_> = std::iter::once(glib::user_data_dir())
+        .chain(glib::system_data_dirs())
+        .map(|dir| dir.join(i18n::LOCALE_DIR))
+        .collect();
+    if let Err(e) = i18n::load(&locale_dirs) {
+        eprintln!("{e:#}");
+    }
+
+    // Keep captured data where the preferences say, in every mode.
+    let preferences = load_preferences().unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        Preferences::default()
+    });
+    stream::set_temp_dir(preferences.temp_dir.clone());
+    stream::set_memory_limit(
+        preferences.memory_limit.saturating_mul(1 << 20));
+
+    // Write events from live captures, if asked to.
+    if let Some(dest) = argument_value("--events") {
+        events::set_destination(dest);
+    }
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(name) = args.first().filter(|name| commands::is_command(name))
+    {
+        std::process::exit(commands::run(name, &args[1..]));
+    } else if have_argument("--version") {
         println!("Packetry version {}\n\n{}",
                  version(),
                  version_info(have_argument("--dependencies")));
@@ -81,21 +154,14 @@ fn main() {
             eprintln!("Failed to initialize GTK");
             std::process::exit(1);
         }
-        if let Some(settings) = gtk::Settings::default() {
-            settings.set_gtk_application_prefer_dark_theme(
-                matches!(
-                    dark_light::detect(),
-                    dark_light::Mode::Dark
-                )
-            );
-        }
+        apply_theme(preferences.theme);
         let application = gtk::Application::new(
             Some("com.greatscottgadgets.packetry"),
             ApplicationFlags::NON_UNIQUE |
             ApplicationFlags::HANDLES_OPEN
         );
         application.set_option_context_parameter_string(
-            Some("[filename.pcap]"));
+            Some("[filename.pcap...]"));
         application.add_main_option(
             "version", glib::Char::from(0),
             OptionFlags::NONE, OptionArg::None,
@@ -104,11 +170,43 @@ fn main() {
             "test-cynthion", glib::Char::from(0),
             OptionFlags::NONE, OptionArg::None,
             "Test an attached Cynthion USB analyzer", None);
+        application.add_main_option(
+            "format", glib::Char::from(0),
+            OptionFlags::NONE, OptionArg::String,
+            "Format of the files to open, if not detected automatically",
+            Some("FORMAT"));
+        application.add_main_option(
+            "events", glib::Char::from(0),
+            OptionFlags::NONE, OptionArg::String,
+            "Write live capture events as JSON lines to a file, \
+             tcp:HOST:PORT, unix:PATH or - for standard output",
+            Some("DEST"));
+        application.add_main_option(
+            "control", glib::Char::from(0),
+            OptionFlags::NONE, OptionArg::String,
+            "Accept JSON-RPC requests over WebSocket at HOST:PORT",
+            Some("ADDRESS"));
+        application.add_main_option(
+            "control-remote", glib::Char::from(0),
+            OptionFlags::NONE, OptionArg::None,
+            "Allow a --control address which is not a loopback address",
+            None);
+        if let Some(address) = argument_value("--control") {
+            let allow_remote = have_argument("--control-remote");
+            match rpc::start(&address, allow_remote, Arc::new(rpc_request)) {
+                Ok(address) => eprintln!("Listening on ws://{address}"),
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    std::process::exit(1);
+                }
+            }
+        }
         application.connect_activate(|app| display_error(activate(app)));
         application.connect_open(|app, files, _hint| {
             app.activate();
-            if let Some(file) = files.first() {
-                display_error(open(file));
+            if !files.is_empty() {
+                let format = argument_value("--format");
+                display_error(open(files, format.as_deref()));
             }
         });
         application.run();
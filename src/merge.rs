@@ -0,0 +1,281 @@
+//! Merging of captures made at several points onto a common timeline.
+//!
+//! Each capture is read with its own [Loader], and packets are taken from
+//! whichever capture has the earliest next packet. Captures are aligned
+//! by the times at which they started, as recorded in each file, so their
+//! timestamps should come from a common clock. Each source within each
+//! capture becomes a source of the merged capture.
+
+use std::io::Read;
+
+use anyhow::{Error, bail};
+
+use crate::pcap::Loader;
+
+/// A capture being merged, with its next packet.
+struct Source<Reader: Read> {
+    loader: Loader<Reader>,
+    next: Option<(Vec<u8>, u8, u64)>,
+    offset: u64,
+}
+
+impl<Reader: Read> Source<Reader> {
+    fn advance(&mut self) -> Result<(), Error> {
+        self.next = match self.loader.next() {
+            Some(result) => {
+                let (packet, timestamp) = result?;
+                Some((packet.data.into_owned(), packet.source, timestamp))
+            },
+            None => None,
+        };
+        Ok(())
+    }
+}
+
+/// Merges the packets of several captures in time order.
+pub struct Merger<Reader: Read> {
+    sources: Vec<Source<Reader>>,
+    start_time: Option<u64>,
+    /// Capture index and source within it of each merged source. The
+    /// first source of each capture is numbered as the capture, and any
+    /// others follow in the order they are seen.
+    merged_sources: Vec<(usize, u8)>,
+}
+
+impl<Reader: Read> Merger<Reader> {
+    pub fn new(loaders: Vec<Loader<Reader>>) -> Result<Self, Error> {
+        if loaders.len() > u8::MAX as usize + 1 {
+            bail!("Cannot merge more than {} captures", u8::MAX as usize + 1);
+        }
+        let mut sources = Vec::with_capacity(loaders.len());
+        for loader in loaders {
+            let mut source = Source { loader, next: None, offset: 0 };
+            source.advance()?;
+            sources.push(source);
+        }
+        // Align the captures relative to the first to start.
+        let start_time = |source: &Source<Reader>| {
+            source.next.as_ref().and(source.loader.start_time())
+        };
+        let earliest = sources.iter().filter_map(start_time).min();
+        if let Some(earliest) = earliest {
+            for source in sources.iter_mut() {
+                source.offset = start_time(source).unwrap_or(earliest)
+                    - earliest;
+            }
+        }
+        let merged_sources = (0..sources.len())
+            .map(|index| (index, 0))
+            .collect();
+        Ok(Merger { sources, start_time: earliest, merged_sources })
+    }
+
+    /// Time at which the first of the captures started, in nanoseconds,
+    /// if recorded in the files.
+    pub fn start_time(&self) -> Option<u64> {
+        self.start_time
+    }
+
+    /// Fetch the next packet, with the merged source it came from and its
+    /// timestamp in nanoseconds on the common timeline.
+    pub fn next(&mut self) -> Result<Option<(u8, Vec<u8>, u64)>, Error> {
+        let earliest = self.sources
+            .iter()
+            .enumerate()
+            .filter_map(|(index, source)| {
+                let (_, _, timestamp) = source.next.as_ref()?;
+                Some((timestamp + source.offset, index))
+            })
+            .min();
+        let Some((timestamp, index)) = earliest else {
+            return Ok(None);
+        };
+        let source = &mut self.sources[index];
+        let (packet, loader_source, _) = source.next.take().unwrap();
+        source.advance()?;
+        let key = (index, loader_source);
+        let merged_source = match self.merged_sources
+            .iter()
+            .position(|merged| *merged == key)
+        {
+            Some(position) => position,
+            None if self.merged_sources.len() > u8::MAX as usize =>
+                bail!("Cannot merge more than {} sources",
+                      u8::MAX as usize + 1),
+            None => {
+                self.merged_sources.push(key);
+                self.merged_sources.len() - 1
+            },
+        };
+        Ok(Some((merged_source as u8, packet, timestamp)))
+    }
+
+    /// Names of the merged sources found so far, given the names of the
+    /// captures.
+    pub fn source_names(&self, names: &[String]) -> Vec<String> {
+        self.merged_sources
+            .iter()
+            .map(|&(index, source)| {
+                let name = &names[index];
+                let loader = &self.sources[index].loader;
+                match loader.source_names().get(source as usize) {
+                    Some(source_name) => format!("{name} {source_name}"),
+                    None => name.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Total bytes read from all captures.
+    pub fn bytes_read(&self) -> u64 {
+        self.sources
+            .iter()
+            .map(|source| source.loader.file_bytes_read())
+            .sum()
+    }
+
+    /// The loaders of the captures being merged.
+    pub fn loaders(&self) -> impl Iterator<Item=&Loader<Reader>> {
+        self.sources.iter().map(|source| &source.loader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write;
+    use std::sync::Arc;
+    use crate::capture::{create_capture, ItemSource, PacketId, TrafficItem};
+    use crate::decoder::{load_capture, Decoder};
+    use crate::pcap::{FileFormat, Writer};
+    use crate::usb::{data_packet, token_packet, DeviceAddr, EndpointNum, PID};
+
+    /// The packets of a SETUP transaction to address 0.
+    fn setup_packets() -> [Vec<u8>; 3] {
+        let request = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        [
+            token_packet(PID::SETUP, DeviceAddr(0), EndpointNum(0)),
+            data_packet(PID::DATA0, &request),
+            vec![PID::ACK.into()],
+        ]
+    }
+
+    /// Write a SETUP transaction to address 0 as hex text.
+    fn setup_transaction(start_us: u64) -> String {
+        let mut text = String::new();
+        for (i, packet) in setup_packets().iter().enumerate() {
+            write!(text, "1.{:06}:", start_us + i as u64).unwrap();
+            for byte in packet {
+                write!(text, " {byte:02X}").unwrap();
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    #[test]
+    fn test_merge() {
+        let first = setup_transaction(20);
+        let second = setup_transaction(10);
+        let loaders = vec![
+            Loader::open(first.as_bytes()).unwrap(),
+            Loader::open(second.as_bytes()).unwrap(),
+        ];
+        let mut merger = Merger::new(loaders).unwrap();
+        let (writer, mut reader) = create_capture().unwrap();
+        writer.shared.sources.swap(Arc::new(vec![
+            "host.pcap".to_string(),
+            "device.pcap".to_string(),
+        ]));
+        let mut decoder = Decoder::new(writer).unwrap();
+        let mut order = Vec::new();
+        while let Some((source, packet, timestamp)) = merger.next().unwrap() {
+            order.push((source, timestamp));
+            decoder.handle_merged_packet(source, &packet, timestamp).unwrap();
+        }
+        decoder.finish().unwrap();
+        assert_eq!(merger.bytes_read(),
+                   (first.len() + second.len()) as u64);
+        assert_eq!(order, [
+            (1, 0), (1, 1_000), (1, 2_000),
+            (0, 10_000), (0, 11_000), (0, 12_000),
+        ]);
+
+        // Each capture's transaction is decoded separately.
+        let (_, item_count) =
+            reader.item_children(None::<&TrafficItem>).unwrap();
+        assert_eq!(item_count, 2);
+        let item: TrafficItem = reader.item(None, 0).unwrap();
+        let transaction = reader.child_item(&item, 0).unwrap();
+        let packet = reader.child_item(&transaction, 0).unwrap();
+        let description = reader.description(&packet, false).unwrap();
+        assert!(description.starts_with("device.pcap: SETUP packet on 0.0"),
+                "{description}");
+        let item: TrafficItem = reader.item(None, 1).unwrap();
+        let transaction = reader.child_item(&item, 0).unwrap();
+        let TrafficItem::Packet(.., last_id) =
+            reader.child_item(&transaction, 2).unwrap()
+        else {
+            panic!("Expected a packet")
+        };
+        assert_eq!(reader.packet_source(last_id).unwrap().as_deref(),
+                   Some("host.pcap"));
+
+        // The sources are kept when saved in native format.
+        let mut saved = Vec::new();
+        crate::native::save(&mut reader, &mut saved).unwrap();
+        let (writer, mut restored) = create_capture().unwrap();
+        crate::native::load(saved.as_slice(), writer).unwrap();
+        assert_eq!(restored.packet_source(last_id).unwrap().as_deref(),
+                   Some("host.pcap"));
+    }
+
+    #[test]
+    fn test_interfaces() {
+        // A pcapng file with a transaction on each of two interfaces.
+        let mut data = Vec::new();
+        let mut writer = Writer::open(&mut data, FileFormat::PcapNg).unwrap();
+        for (interface, start_ns) in [(0, 0), (1, 5_000)] {
+            for (i, packet) in setup_packets().iter().enumerate() {
+                let timestamp_ns = start_ns + i as u64 * 1_000;
+                writer.add_packet_from(interface, packet, timestamp_ns)
+                    .unwrap();
+            }
+        }
+        writer.close().unwrap();
+
+        // Each interface is loaded as a source of its own.
+        let mut reader = load_capture(data.as_slice(), None).unwrap();
+        let (_, item_count) =
+            reader.item_children(None::<&TrafficItem>).unwrap();
+        assert_eq!(item_count, 2);
+        let sources: Vec<_> = (0..6)
+            .map(|id| reader.packet_source(PacketId::from(id)).unwrap())
+            .collect();
+        let first = Some("interface 0".to_string());
+        let second = Some("interface 1".to_string());
+        assert_eq!(sources, [
+            first.clone(), first.clone(), first,
+            second.clone(), second.clone(), second,
+        ]);
+
+        // When merged, the sources of each capture are kept apart.
+        let text = setup_transaction(10);
+        let loaders = vec![
+            Loader::open(data.as_slice()).unwrap(),
+            Loader::open(text.as_bytes()).unwrap(),
+        ];
+        let mut merger = Merger::new(loaders).unwrap();
+        let mut seen = Vec::new();
+        while let Some((source, ..)) = merger.next().unwrap() {
+            if !seen.contains(&source) {
+                seen.push(source);
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, [0, 1, 2]);
+        let names = ["a.pcapng".to_string(), "b.txt".to_string()];
+        assert_eq!(merger.source_names(&names), [
+            "a.pcapng interface 0", "b.txt", "a.pcapng interface 1"]);
+    }
+}
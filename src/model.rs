@@ -16,9 +16,6 @@ use crate::tree_list_model::{TreeListModel, ItemNodeRc};
 
 /// Trait implemented by each of our ListModel implementations.
 pub trait GenericModel<Item> where Self: Sized {
-    /// Whether this model has timestamps.
-    const HAS_TIMES: bool;
-
     /// Create a new model instance for the given capture.
     fn new(capture: CaptureReader,
            #[cfg(any(test, feature="record-ui-test"))]
@@ -32,6 +29,19 @@ pub trait GenericModel<Item> where Self: Sized {
                     expanded: bool)
         -> Result<(), Error>;
 
+    /// Set whether a tree node and all the nodes below it are expanded.
+    fn set_subtree_expanded(&self,
+                            node: &ItemNodeRc<Item>,
+                            position: u32,
+                            expanded: bool)
+        -> Result<(), Error>;
+
+    /// Set whether every node in the tree is expanded.
+    fn set_all_expanded(&self, expanded: bool) -> Result<(), Error>;
+
+    /// Expand the nodes leading to an item, and return its position.
+    fn reveal(&self, path: &[u64]) -> Result<u32, Error>;
+
     /// Update the model with new data from the capture.
     ///
     /// Returns true if there will be further updates in future.
@@ -40,16 +50,19 @@ pub trait GenericModel<Item> where Self: Sized {
     /// Fetch the description for a given item.
     fn description(&self, item: &Item, detail: bool) -> String;
 
+    /// Fetch the summary for the item at a given position.
+    fn summary(&self, position: u32, item: &Item) -> String;
+
     /// Fetch the timestamp for a given item.
     fn timestamp(&self, item: &Item) -> u64;
 
-    /// Fetch the connecting lines for a given item.
-    fn connectors(&self, item: &Item) -> String;
+    /// Fetch the connecting lines for the item at a given position.
+    fn connectors(&self, position: u32, item: &Item) -> String;
 }
 
 /// Define the outer type exposed to our Rust code.
 macro_rules! model {
-    ($model: ident, $item: ident, $has_times: literal) => {
+    ($model: ident, $item: ident) => {
 
         glib::wrapper! {
             pub struct $model(ObjectSubclass<imp::$model>)
@@ -57,8 +70,6 @@ macro_rules! model {
         }
 
         impl GenericModel<$item> for $model {
-            const HAS_TIMES: bool = $has_times;
-
             fn new(capture: CaptureReader,
                    #[cfg(any(test, feature="record-ui-test"))]
                    on_item_update: Rc<RefCell<dyn FnMut(u32, String)>>)
@@ -84,6 +95,31 @@ macro_rules! model {
                 tree.set_expanded(self, node, position as u64, expanded)
             }
 
+            fn set_subtree_expanded(&self,
+                                    node: &ItemNodeRc<$item>,
+                                    position: u32,
+                                    expanded: bool)
+                -> Result<(), Error>
+            {
+                let tree_opt = self.imp().tree.borrow();
+                let tree = tree_opt.as_ref().unwrap();
+                tree.set_subtree_expanded(
+                    self, node, position as u64, expanded)
+            }
+
+            fn set_all_expanded(&self, expanded: bool) -> Result<(), Error> {
+                let tree_opt = self.imp().tree.borrow();
+                let tree = tree_opt.as_ref().unwrap();
+                tree.set_all_expanded(self, expanded)
+            }
+
+            fn reveal(&self, path: &[u64]) -> Result<u32, Error> {
+                let tree_opt = self.imp().tree.borrow();
+                let tree = tree_opt.as_ref().unwrap();
+                let position = tree.reveal(self, path)?;
+                Ok(u32::try_from(position)?)
+            }
+
             fn update(&self) -> Result<bool, Error> {
                 let tree_opt = self.imp().tree.borrow();
                 let tree = tree_opt.as_ref().unwrap();
@@ -96,24 +132,30 @@ macro_rules! model {
                 tree.description(item, detail)
             }
 
+            fn summary(&self, position: u32, item: &$item) -> String {
+                let tree_opt = self.imp().tree.borrow();
+                let tree = tree_opt.as_ref().unwrap();
+                tree.summary(position as u64, item)
+            }
+
             fn timestamp(&self, item: &$item) -> u64 {
                 let tree_opt = self.imp().tree.borrow();
                 let tree = tree_opt.as_ref().unwrap();
                 tree.timestamp(item)
             }
 
-            fn connectors(&self, item: &$item) -> String {
+            fn connectors(&self, position: u32, item: &$item) -> String {
                 let tree_opt = self.imp().tree.borrow();
                 let tree = tree_opt.as_ref().unwrap();
-                tree.connectors(item)
+                tree.connectors(position as u64, item)
             }
         }
     }
 }
 
 // Repeat the above boilerplate for each model.
-model!(TrafficModel, TrafficItem, true);
-model!(DeviceModel, DeviceItem, false);
+model!(TrafficModel, TrafficItem);
+model!(DeviceModel, DeviceItem);
 
 /// The internal implementation module.
 mod imp {
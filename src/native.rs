@@ -0,0 +1,387 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::Ordering::{Acquire, Release};
+
+use anyhow::{Context, Error, bail};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use bytemuck::bytes_of;
+
+use crate::capture::{
+    create_capture,
+    CaptureReader,
+    CaptureWriter,
+    DeviceData,
+    DeviceId,
+    Marker,
+    MarkerKind,
+};
+use crate::decoder::Decoder;
+use crate::class::dfu::{DfuState, DfuStatus, Progress};
+use crate::stream::read_length;
+use crate::usb::{
+    ConfigNum,
+    Configuration,
+    DeviceDescriptor,
+    EndpointAddr,
+    EndpointType,
+    InterfaceNum,
+    StringId,
+    UTF16ByteVec,
+};
+use crate::vec_map::{Key, VecMap};
+
+/// Bytes found at the start of a native capture file.
+const MAGIC: &[u8; 8] = b"PACKETRY";
+
+/// Version of the native capture file format.
+///
+/// This must be incremented whenever the layout of the file, or of any
+/// of the streams stored in it, is changed.
+const VERSION: u32 = 6;
+
+/// Types of marker records.
+const MARKER_NOTE: u8 = 0;
+const MARKER_LOSS: u8 = 1;
+
+/// Count stored for a loss of an unknown number of packets.
+const UNKNOWN_LOSS: u64 = u64::MAX;
+
+/// File name extension used for native capture files.
+const EXTENSION: &str = ".packetry";
+
+/// Check whether data is the start of a native capture file.
+pub fn is_native(start: &[u8]) -> bool {
+    start.starts_with(MAGIC)
+}
+
+/// Check whether a file name has the native capture file extension.
+pub fn is_native_name(name: &str) -> bool {
+    name.to_ascii_lowercase().ends_with(EXTENSION)
+}
+
+/// Save a complete capture in native format.
+///
+/// The packet data is stored along with all the indices built while
+/// decoding it, so that the capture can be loaded again without having
+/// to decode it again.
+pub fn save<Dest: Write>(capture: &mut CaptureReader, dest: Dest)
+    -> Result<(), Error>
+{
+    let mut dest = BufWriter::new(dest);
+    dest.write_all(MAGIC)?;
+    dest.write_all(&VERSION.to_le_bytes())?;
+    capture.save_streams(&mut dest)
+        .context("Capture must be complete to save it in native format")?;
+    let device_data = capture.shared.device_data.load_full();
+    for id in 0..capture.devices.len() {
+        let device_id = DeviceId::from(id);
+        let data = device_data
+            .get(device_id)
+            .with_context(|| format!("No data for device {id}"))?;
+        let record = encode_device_data(data);
+        dest.write_all(&(record.len() as u64).to_le_bytes())?;
+        dest.write_all(&record)?;
+    }
+    let sources = capture.shared.sources.load_full();
+    dest.write_all(&(sources.len() as u64).to_le_bytes())?;
+    for name in sources.iter() {
+        dest.write_all(&(name.len() as u64).to_le_bytes())?;
+        dest.write_all(name.as_bytes())?;
+    }
+    let markers = capture.shared.markers.load_full();
+    dest.write_all(&(markers.len() as u64).to_le_bytes())?;
+    for marker in markers.iter() {
+        dest.write_all(&marker.timestamp.to_le_bytes())?;
+        dest.write_all(&marker.position.to_le_bytes())?;
+        match &marker.kind {
+            MarkerKind::Note(text) => {
+                dest.write_all(&[MARKER_NOTE])?;
+                dest.write_all(&(text.len() as u64).to_le_bytes())?;
+                dest.write_all(text.as_bytes())?;
+            },
+            MarkerKind::Loss(count) => {
+                dest.write_all(&[MARKER_LOSS])?;
+                let count = count.unwrap_or(UNKNOWN_LOSS);
+                dest.write_all(&count.to_le_bytes())?;
+            },
+        }
+    }
+    dest.flush()?;
+    Ok(())
+}
+
+/// Decode packets into a new capture, and save it in native format.
+///
+/// Returns the number of packets saved.
+pub fn save_packets<Dest, Packets, Data>(packets: Packets, dest: Dest)
+    -> Result<u64, Error>
+where Dest: Write,
+      Packets: Iterator<Item=Result<(u64, Data), Error>>,
+      Data: Deref<Target=[u8]>
+{
+    let (writer, mut reader) = create_capture()?;
+    let mut decoder = Decoder::new(writer)?;
+    let mut count = 0;
+    for result in packets {
+        let (timestamp_ns, packet) = result?;
+        decoder.handle_raw_packet(&packet, timestamp_ns)?;
+        count += 1;
+    }
+    decoder.finish()?;
+    save(&mut reader, dest)?;
+    Ok(count)
+}
+
+/// Load a capture saved in native format into an empty capture.
+pub fn load<Source: Read>(source: Source, mut capture: CaptureWriter)
+    -> Result<CaptureWriter, Error>
+{
+    let mut source = BufReader::new(source);
+    let mut magic = [0; MAGIC.len()];
+    source.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Not a native capture file")
+    }
+    let mut version = [0; 4];
+    source.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != VERSION {
+        bail!("Native capture file has version {version}, \
+               but only version {VERSION} can be loaded")
+    }
+    capture.restore_streams(&mut source)?;
+    let mut device_data = VecMap::new();
+    for id in 0..capture.devices.len() {
+        let length = read_length(&mut source)? as usize;
+        let mut record = vec![0; length];
+        source.read_exact(&mut record)
+            .context("Native capture file is truncated")?;
+        let data = decode_device_data(&record)
+            .with_context(|| format!("Invalid data for device {id}"))?;
+        device_data.set(DeviceId::from(id), Arc::new(data));
+    }
+    capture.shared.device_data.swap(Arc::new(device_data));
+    let mut sources = Vec::new();
+    for _ in 0..read_length(&mut source)? {
+        let length = read_length(&mut source)? as usize;
+        let mut name = vec![0; length];
+        source.read_exact(&mut name)
+            .context("Native capture file is truncated")?;
+        sources.push(String::from_utf8(name)
+            .context("Invalid capture source name")?);
+    }
+    capture.shared.sources.swap(Arc::new(sources));
+    let mut markers = Vec::new();
+    for _ in 0..read_length(&mut source)? {
+        let timestamp = read_length(&mut source)?;
+        let position = read_length(&mut source)?;
+        let mut kind = [0];
+        source.read_exact(&mut kind)
+            .context("Native capture file is truncated")?;
+        let kind = match kind[0] {
+            MARKER_NOTE => {
+                let length = read_length(&mut source)? as usize;
+                let mut text = vec![0; length];
+                source.read_exact(&mut text)
+                    .context("Native capture file is truncated")?;
+                MarkerKind::Note(String::from_utf8(text)
+                    .context("Invalid marker text")?)
+            },
+            MARKER_LOSS => MarkerKind::Loss(
+                Some(read_length(&mut source)?)
+                    .filter(|&count| count != UNKNOWN_LOSS)),
+            other => bail!("Invalid marker type {other}"),
+        };
+        markers.push(Marker { kind, timestamp, position });
+    }
+    capture.shared.markers.swap(Arc::new(markers));
+    capture.shared.complete.store(true, Release);
+    Ok(capture)
+}
+
+/// Append a length-prefixed field.
+fn put_bytes(record: &mut Vec<u8>, bytes: &[u8]) {
+    record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    record.extend_from_slice(bytes);
+}
+
+/// Append an optional field, stored as a length-prefixed field
+/// which is empty if the value is absent.
+fn put_option(record: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    put_bytes(record, bytes.unwrap_or_default());
+}
+
+/// Append an optional byte.
+fn put_byte_option(record: &mut Vec<u8>, byte: Option<u8>) {
+    put_option(record, byte.as_ref().map(std::slice::from_ref));
+}
+
+/// Append the number of entries in a map.
+fn put_count<K: Key, V>(record: &mut Vec<u8>, map: &VecMap<K, V>) {
+    let count = map.entries().count() as u32;
+    record.extend_from_slice(&count.to_le_bytes());
+}
+
+/// Encode what was learned about a device as a record.
+fn encode_device_data(data: &DeviceData) -> Vec<u8> {
+    let mut record = Vec::new();
+    let device_descriptor = data.device_descriptor.load_full();
+    put_option(&mut record, device_descriptor.as_deref().map(bytes_of));
+    let configurations = data.configurations.load();
+    put_count(&mut record, &configurations);
+    for (number, config) in configurations.entries() {
+        record.push(number.0);
+        put_bytes(&mut record, &config.to_bytes());
+    }
+    let config_number = data.config_number.load_full();
+    put_byte_option(&mut record, config_number.map(|number| number.0));
+    let endpoint_details = data.endpoint_details.load();
+    put_count(&mut record, &endpoint_details);
+    for (addr, (ep_type, ep_max)) in endpoint_details.entries() {
+        record.push(addr.0);
+        record.push(*ep_type as u8);
+        let ep_max = ep_max.map(|max| (max as u64).to_le_bytes());
+        put_option(&mut record, ep_max.as_ref().map(|bytes| &bytes[..]));
+    }
+    let strings = data.strings.load();
+    put_count(&mut record, &strings);
+    for (id, string) in strings.entries() {
+        record.push(id.0);
+        put_bytes(&mut record, &string.0);
+    }
+    let dfu_progress = data.dfu_progress.load_full();
+    put_option(&mut record,
+        dfu_progress.as_deref().map(encode_dfu_progress).as_deref());
+    let hid_report_descriptors = data.hid_report_descriptors.load();
+    put_count(&mut record, &hid_report_descriptors);
+    for (number, descriptor) in hid_report_descriptors.entries() {
+        record.push(number.0);
+        put_bytes(&mut record, descriptor);
+    }
+    record.extend_from_slice(&data.version.load(Acquire).to_le_bytes());
+    record
+}
+
+/// Decode a record written by [encode_device_data].
+fn decode_device_data(record: &[u8]) -> Result<DeviceData, Error> {
+    let mut fields = Fields { bytes: record };
+    let data = DeviceData::default();
+    let device_descriptor = fields.bytes()?;
+    if device_descriptor.len() == size_of::<DeviceDescriptor>() {
+        data.device_descriptor.swap(Some(Arc::new(
+            DeviceDescriptor::from_bytes(device_descriptor))));
+    }
+    let mut configurations = VecMap::new();
+    for _ in 0..fields.u32()? {
+        let number = ConfigNum(fields.u8()?);
+        let config = Configuration::from_bytes(fields.bytes()?)
+            .context("Invalid configuration descriptor")?;
+        configurations.set(number, Arc::new(config));
+    }
+    if let [number] = fields.bytes()? {
+        data.config_number.swap(Some(Arc::new(ConfigNum(*number))));
+    }
+    let mut endpoint_details = VecMap::new();
+    for _ in 0..fields.u32()? {
+        let addr = EndpointAddr(fields.u8()?);
+        let ep_type = EndpointType::from(fields.u8()?);
+        let ep_max = match fields.bytes()? {
+            [] => None,
+            bytes => Some(u64::from_le_bytes(bytes.try_into()?) as usize),
+        };
+        endpoint_details.set(addr, (ep_type, ep_max));
+    }
+    let mut strings = VecMap::new();
+    for _ in 0..fields.u32()? {
+        let id = StringId(fields.u8()?);
+        strings.set(id, UTF16ByteVec(fields.bytes()?.to_vec()));
+    }
+    let dfu_progress = match fields.bytes()? {
+        [] => None,
+        bytes => Some(Arc::new(decode_dfu_progress(bytes)?)),
+    };
+    let mut hid_report_descriptors = VecMap::new();
+    for _ in 0..fields.u32()? {
+        let number = InterfaceNum(fields.u8()?);
+        hid_report_descriptors.set(number, fields.bytes()?.to_vec());
+    }
+    let version = fields.u32()?;
+    Ok(DeviceData {
+        configurations: ArcSwap::new(Arc::new(configurations)),
+        endpoint_details: ArcSwap::new(Arc::new(endpoint_details)),
+        strings: ArcSwap::new(Arc::new(strings)),
+        dfu_progress: ArcSwapOption::new(dfu_progress),
+        hid_report_descriptors:
+            ArcSwap::new(Arc::new(hid_report_descriptors)),
+        version: version.into(),
+        .. data
+    })
+}
+
+/// Encode the progress of DFU operations.
+fn encode_dfu_progress(progress: &Progress) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for count in [
+        progress.download_bytes,
+        progress.download_blocks,
+        progress.upload_bytes,
+    ] {
+        bytes.extend_from_slice(&count.to_le_bytes());
+    }
+    put_byte_option(&mut bytes, progress.state.map(|state| state as u8));
+    put_byte_option(&mut bytes, progress.status.map(|status| status as u8));
+    bytes
+}
+
+/// Decode the progress of DFU operations.
+fn decode_dfu_progress(bytes: &[u8]) -> Result<Progress, Error> {
+    let mut fields = Fields { bytes };
+    Ok(Progress {
+        download_bytes: fields.u64()?,
+        download_blocks: fields.u64()?,
+        upload_bytes: fields.u64()?,
+        state: match fields.bytes()? {
+            [state] => Some(DfuState::from(*state)),
+            _ => None,
+        },
+        status: match fields.bytes()? {
+            [status] => Some(DfuStatus::from(*status)),
+            _ => None,
+        },
+    })
+}
+
+/// Reader for the fields of an encoded record.
+struct Fields<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Fields<'b> {
+    fn take(&mut self, length: usize) -> Result<&'b [u8], Error> {
+        if length > self.bytes.len() {
+            bail!("Record is truncated")
+        }
+        let (taken, remaining) = self.bytes.split_at(length);
+        self.bytes = remaining;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'b [u8], Error> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+}
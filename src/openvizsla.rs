@@ -0,0 +1,159 @@
+//! Import of packet logs saved by the OpenVizsla host tools.
+//!
+//! The OpenVizsla sniffer sends each packet it sees on the bus as a
+//! record starting with a fixed header: a magic byte, two bytes of flags,
+//! the length of the packet as a little-endian 16-bit value, and a 24-bit
+//! little-endian timestamp counting cycles of the 60MHz ULPI clock. The
+//! bytes of the packet follow.
+
+use std::io::BufRead;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Error, bail};
+
+use crate::backend::clock::HardwareClock;
+
+/// First byte of each packet record.
+pub const MAGIC: u8 = 0xA0;
+
+/// Size of the header of each packet record.
+pub const HEADER_SIZE: usize = 8;
+
+/// Flag set if the sniffer's buffer overflowed before this packet.
+const FLAG_OVERFLOW: u16 = 0x02;
+
+/// Width of the timestamp counter, and the frequency it counts at.
+const TIMESTAMP_BITS: u32 = 24;
+const CLOCK_HZ: u64 = 60_000_000;
+
+/// Whether the start of a file appears to be an OpenVizsla packet log.
+pub fn is_log(start: &[u8]) -> bool {
+    match start {
+        [MAGIC, _, _, length_lo, length_hi, _, _, _, rest @ ..] => {
+            // Expect the next record to follow this one, if it's here.
+            let length = u16::from_le_bytes([*length_lo, *length_hi]);
+            rest.get(length as usize).map_or(true, |&byte| byte == MAGIC)
+        },
+        _ => false
+    }
+}
+
+/// Length of the packet following a packet record header.
+pub fn packet_length(header: &[u8; HEADER_SIZE]) -> Result<usize, Error> {
+    if header[0] != MAGIC {
+        bail!("Invalid OpenVizsla packet record type {:02X}", header[0]);
+    }
+    Ok(u16::from_le_bytes([header[3], header[4]]) as usize)
+}
+
+/// Reads packets from an OpenVizsla packet log.
+pub struct Parser {
+    /// Clock counted by the packet timestamps.
+    clock: HardwareClock,
+    /// Number of times the sniffer's buffer overflowed.
+    overflows: Arc<AtomicU64>,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser {
+            clock: HardwareClock::new(CLOCK_HZ, TIMESTAMP_BITS),
+            overflows: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Parser {
+    /// Read the next packet, returning its data, its timestamp in
+    /// nanoseconds and the number of bytes read.
+    pub fn next_packet<Source: BufRead>(&mut self, source: &mut Source)
+        -> Result<Option<(Vec<u8>, u64, usize)>, Error>
+    {
+        if source.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut header = [0; HEADER_SIZE];
+        source.read_exact(&mut header)
+            .context("OpenVizsla packet log is truncated")?;
+        let length = packet_length(&header)?;
+        let mut data = vec![0; length];
+        source.read_exact(&mut data)
+            .context("OpenVizsla packet log is truncated")?;
+        let timestamp_ns = self.timestamp_ns(&header);
+        Ok(Some((data, timestamp_ns, HEADER_SIZE + length)))
+    }
+
+    /// Timestamp in nanoseconds of the packet with the given header,
+    /// which must follow that of the previous packet.
+    pub fn timestamp_ns(&mut self, header: &[u8; HEADER_SIZE]) -> u64 {
+        let flags = u16::from_le_bytes([header[1], header[2]]);
+        let timestamp = u32::from_le_bytes(
+            [header[5], header[6], header[7], 0]) as u64;
+        if flags & FLAG_OVERFLOW != 0 {
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+        }
+        // The counter wraps about every 280ms, so assume packets are
+        // never further apart than that.
+        self.clock.count(timestamp)
+    }
+
+    /// Number of times packets were lost due to the sniffer's buffer
+    /// overflowing.
+    pub fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::Relaxed)
+    }
+
+    /// Shared count of overflows, which can be read while a packet
+    /// returned by the parser is still in use.
+    pub fn overflow_count(&self) -> Arc<AtomicU64> {
+        self.overflows.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::Loader;
+
+    /// Build a packet record.
+    fn record(flags: u16, timestamp: u32, data: &[u8]) -> Vec<u8> {
+        let mut record = vec![MAGIC];
+        record.extend_from_slice(&flags.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        record.extend_from_slice(&timestamp.to_le_bytes()[..3]);
+        record.extend_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn test_openvizsla_import() {
+        let log = [
+            record(0, 0xFFFFF0, &[0x69, 0x82, 0x18]),
+            record(0, 0x000002, &[0x5A]),
+            record(FLAG_OVERFLOW, 0x00003E, &[0xA5, 0x23, 0xD0]),
+        ].concat();
+        assert!(is_log(&log));
+        assert!(!is_log(&[0xA0, 0, 0, 5, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6]));
+        let mut loader = Loader::open(log.as_slice()).unwrap();
+        let mut packets = Vec::new();
+        while let Some(result) = loader.next() {
+            let (packet, timestamp) = result.unwrap();
+            packets.push((packet.data.to_vec(), timestamp));
+        }
+        assert_eq!(packets, [
+            (vec![0x69, 0x82, 0x18], 0),
+            (vec![0x5A], 300),
+            (vec![0xA5, 0x23, 0xD0], 1_300),
+        ]);
+        assert_eq!(loader.bytes_read, log.len() as u64);
+        assert_eq!(loader.overflows(), 1);
+
+        let truncated = &log[..log.len() - 1];
+        let mut loader = Loader::open(truncated).unwrap();
+        let result: Result<Vec<_>, Error> = std::iter::from_fn(|| {
+            loader.next().map(|result| result.map(|_| ()))
+        }).collect();
+        assert!(result.is_err());
+    }
+}
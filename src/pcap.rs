@@ -1,93 +1,1135 @@
 use std::borrow::Cow;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::mem::size_of;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, BufReader, BufWriter, Lines, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 use pcap_file::{
     pcap::{PcapReader, PcapWriter, PcapHeader, RawPcapPacket},
+    pcapng::{
+        blocks::{
+            interface_description::{
+                InterfaceDescriptionBlock,
+                InterfaceDescriptionOption,
+            },
+            ENHANCED_PACKET_BLOCK,
+            INTERFACE_DESCRIPTION_BLOCK,
+            SECTION_HEADER_BLOCK,
+        },
+        blocks::enhanced_packet::EnhancedPacketBlock,
+        PcapNgReader,
+        PcapNgWriter,
+    },
     DataLink,
+    Endianness,
     TsResolution,
 };
 
-use anyhow::{Context, Error};
+use anyhow::{Context, Error, bail};
 
+use crate::beagle;
+use crate::compression::Decompressor;
+use crate::hex_text;
+use crate::iti1480a;
+use crate::openvizsla;
+use crate::usbip;
+use crate::usbmon;
+
+/// Size of the pcap file header.
+const FILE_HEADER_SIZE: u64 = 24;
+
+/// Size of the header preceding each packet in a pcap file.
+const PACKET_HEADER_SIZE: usize = 16;
+
+/// Block type found at the start of a pcapng file.
+const PCAPNG_MAGIC: [u8; 4] = SECTION_HEADER_BLOCK.to_le_bytes();
+
+/// Size of the fixed fields of an enhanced packet block.
+const ENHANCED_PACKET_HEADER_SIZE: usize = 20;
+
+/// How USB traffic is represented under a link type.
+#[derive(Copy, Clone)]
+enum Encapsulation {
+    /// Raw USB 2.0 packets, as handled by the decoder.
+    Packets,
+    /// Linux usbmon records, with a header of the given size.
+    Usbmon(usize),
+    /// Network frames, which may carry usbip traffic.
+    Usbip(DataLink),
+}
+
+impl Encapsulation {
+    fn from_link_type(datalink: DataLink) -> Option<Encapsulation> {
+        use DataLink::*;
+        match datalink {
+            // The speed-specific types are used by some capture tools.
+            USB_2_0 |
+            USB_2_0_LOW_SPEED |
+            USB_2_0_FULL_SPEED |
+            USB_2_0_HIGH_SPEED =>
+                Some(Encapsulation::Packets),
+            USB_LINUX =>
+                Some(Encapsulation::Usbmon(usbmon::HEADER_SIZE)),
+            USB_LINUX_MMAPPED =>
+                Some(Encapsulation::Usbmon(usbmon::MMAPPED_HEADER_SIZE)),
+            ETHERNET | LINUX_SLL | LINUX_SLL2 | NULL | LOOP |
+            RAW | IPV4 | IPV6 =>
+                Some(Encapsulation::Usbip(datalink)),
+            _ => None
+        }
+    }
+}
+
+/// A record read from a capture file.
+enum Record {
+    /// A raw USB 2.0 packet.
+    Packet(Vec<u8>),
+    /// A binary usbmon record, with a header of the given size.
+    Usbmon(Vec<u8>, usize),
+    /// A URB event parsed from usbmon text or usbip messages.
+    Urb(usbmon::Urb<'static>),
+    /// A network frame, with its link type.
+    Frame(Vec<u8>, DataLink),
+}
+
+impl Record {
+    fn new(data: Vec<u8>, encapsulation: Encapsulation) -> Record {
+        match encapsulation {
+            Encapsulation::Packets => Record::Packet(data),
+            Encapsulation::Usbmon(header_size) =>
+                Record::Usbmon(data, header_size),
+            Encapsulation::Usbip(datalink) =>
+                Record::Frame(data, datalink),
+        }
+    }
+}
+
+/// An interface described in a pcapng file.
+pub struct Interface {
+    /// Link type of the packets captured on the interface.
+    pub link_type: DataLink,
+    /// Name of the interface, if given.
+    pub name: Option<String>,
+    /// Description of the interface, if given.
+    pub description: Option<String>,
+    /// Timestamp units per second.
+    ts_units: u128,
+    /// Offset to add to timestamps, in seconds.
+    ts_offset: u64,
+}
+
+impl Interface {
+    fn from_block(block: &InterfaceDescriptionBlock) -> Interface {
+        use InterfaceDescriptionOption::*;
+        let mut interface = Interface {
+            link_type: block.linktype,
+            name: None,
+            description: None,
+            ts_units: 1_000_000,
+            ts_offset: 0,
+        };
+        for option in &block.options {
+            match option {
+                IfName(name) =>
+                    interface.name = Some(name.to_string()),
+                IfDescription(description) =>
+                    interface.description = Some(description.to_string()),
+                // The top bit selects a power of two rather than of ten.
+                IfTsResol(resol) if resol & 0x80 != 0 =>
+                    interface.ts_units = 1 << (resol & 0x7F).min(96),
+                IfTsResol(resol) =>
+                    interface.ts_units = 10u128.pow((*resol).min(24).into()),
+                IfTsOffset(offset) =>
+                    interface.ts_offset = *offset,
+                _ => {}
+            }
+        }
+        interface
+    }
+
+    /// Convert a timestamp in this interface's units to nanoseconds.
+    fn timestamp_ns(&self, timestamp: u64) -> u64 {
+        let ns = timestamp as u128 * 1_000_000_000 / self.ts_units;
+        let offset_ns = self.ts_offset as u128 * 1_000_000_000;
+        (ns + offset_ns).try_into().unwrap_or(u64::MAX)
+    }
+
+    /// Describe the interface, e.g. "interface 1 (usbmon0)".
+    pub fn describe(&self, index: usize) -> String {
+        match self.description.as_ref().or(self.name.as_ref()) {
+            Some(name) => format!("interface {index} ({name})"),
+            None => format!("interface {index}"),
+        }
+    }
+}
+
+/// A packet read from a capture file.
+pub struct Packet<'p> {
+    /// The raw bytes of the packet.
+    pub data: Cow<'p, [u8]>,
+    /// The source of the packet, if the file has several.
+    pub source: u8,
+}
+
+enum Format<Source: Read> {
+    Pcap {
+        reader: PcapReader<BufReader<Source>>,
+        frac_ns: u64,
+        encapsulation: Encapsulation,
+    },
+    PcapNg {
+        reader: PcapNgReader<BufReader<Source>>,
+        endianness: Endianness,
+    },
+    UsbmonText {
+        lines: Lines<BufReader<Source>>,
+        line_number: usize,
+    },
+    Iti1480a {
+        reader: BufReader<Source>,
+        parser: iti1480a::Parser,
+    },
+    BeagleCsv {
+        reader: BufReader<Source>,
+        line_number: usize,
+        columns: Option<beagle::Columns>,
+    },
+    OpenVizsla {
+        reader: BufReader<Source>,
+        parser: openvizsla::Parser,
+    },
+    HexText {
+        reader: BufReader<Source>,
+        line_number: usize,
+        timestamp: u64,
+    },
+    UsbipStream {
+        reader: BufReader<Source>,
+        session: usbip::Session,
+        buffer: Vec<u8>,
+    },
+}
+
+/// Formats from which packets can be loaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    Pcap,
+    PcapNg,
+    UsbmonText,
+    Iti1480a,
+    BeagleCsv,
+    OpenVizsla,
+    HexText,
+    UsbipStream,
+}
+
+impl InputFormat {
+    pub const ALL: [InputFormat; 8] = [
+        InputFormat::Pcap,
+        InputFormat::PcapNg,
+        InputFormat::UsbmonText,
+        InputFormat::Iti1480a,
+        InputFormat::BeagleCsv,
+        InputFormat::OpenVizsla,
+        InputFormat::HexText,
+        InputFormat::UsbipStream,
+    ];
+
+    /// Short name by which the format can be chosen.
+    pub fn name(&self) -> &'static str {
+        use InputFormat::*;
+        match self {
+            Pcap => "pcap",
+            PcapNg => "pcapng",
+            UsbmonText => "usbmon",
+            Iti1480a => "iti1480a",
+            BeagleCsv => "beagle",
+            OpenVizsla => "openvizsla",
+            HexText => "hex",
+            UsbipStream => "usbip",
+        }
+    }
+
+    /// Description of the format, for display.
+    pub fn description(&self) -> &'static str {
+        use InputFormat::*;
+        match self {
+            Pcap => "pcap file",
+            PcapNg => "pcapng file",
+            UsbmonText => "usbmon text",
+            Iti1480a => "ITI1480A capture",
+            BeagleCsv => "Beagle CSV export",
+            OpenVizsla => "OpenVizsla packet log",
+            HexText => "Packets as hex text",
+            UsbipStream => "usbip message dump",
+        }
+    }
+
+    /// Find a format by its short name.
+    pub fn from_name(name: &str) -> Result<InputFormat, Error> {
+        match InputFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == name)
+        {
+            Some(format) => Ok(format),
+            None => {
+                let names: Vec<&str> = InputFormat::ALL
+                    .iter()
+                    .map(InputFormat::name)
+                    .collect();
+                bail!("Unknown input format '{name}', expected one of: {}",
+                      names.join(", "))
+            }
+        }
+    }
+
+    /// Detect the format of a file from its first bytes.
+    ///
+    /// ITI1480A captures have no header by which they can be recognised,
+    /// so are never detected. Files not recognised as anything else are
+    /// assumed to be pcap files.
+    pub fn detect(start: &[u8]) -> InputFormat {
+        if start.starts_with(&PCAPNG_MAGIC) {
+            InputFormat::PcapNg
+        } else if usbmon::is_text(start) {
+            InputFormat::UsbmonText
+        } else if beagle::is_csv(start) {
+            InputFormat::BeagleCsv
+        } else if hex_text::is_hex_text(start) {
+            InputFormat::HexText
+        } else if openvizsla::is_log(start) {
+            InputFormat::OpenVizsla
+        } else if usbip::is_stream(start) {
+            InputFormat::UsbipStream
+        } else {
+            InputFormat::Pcap
+        }
+    }
+}
+
+/// Loads packets from a pcap or pcapng file, from usbmon text, from an
+/// ITI1480A capture, from a Beagle CSV export, from an OpenVizsla packet
+/// log, from packets written as hex text, or from a dump of usbip
+/// messages.
+///
+/// A pcapng file may describe several interfaces. Each interface carrying
+/// USB traffic is a source of the capture, numbered in the order they are
+/// described, and packets on other interfaces are skipped. A network
+/// interface, on which usbip traffic may be found, is only used if no USB
+/// interface is described before the first packet.
+///
+/// Linux usbmon records and usbip messages are converted to the packets
+/// they represent.
+///
+/// Files compressed with gzip, zstd or xz are decompressed as they are
+/// read.
 pub struct Loader<Source: Read> {
-    pcap: PcapReader<BufReader<Source>>,
+    format: Format<Decompressor<Source>>,
+    /// Bytes of the decompressed file read so far.
     pub bytes_read: u64,
-    frac_ns: u64,
+    /// Bytes of the file read so far, if it is compressed.
+    compressed_bytes_read: Option<Arc<AtomicU64>>,
     start_time: Option<u64>,
-}
-
-pub struct Writer<Dest: Write> {
-    pcap: PcapWriter<BufWriter<Dest>>,
+    interfaces: Vec<Interface>,
+    /// Interface index and encapsulation of each source.
+    sources: Vec<(usize, Encapsulation)>,
+    /// Source of the last record read.
+    record_source: u8,
+    skipped: BTreeMap<usize, u64>,
+    converter: usbmon::Converter,
+    dissector: usbip::Dissector,
+    converted: VecDeque<(Vec<u8>, u64, u8)>,
 }
 
 impl<Source> Loader<Source> where Source: Read {
+    /// Open a capture, detecting its format from its contents.
     pub fn open(source: Source)
         -> Result<Loader<Source>, Error>
     {
-        let reader = BufReader::new(source);
-        let pcap = PcapReader::new(reader)?;
-        let header = pcap.header();
-        let bytes_read = size_of::<PcapHeader>() as u64;
-        let frac_ns = match header.ts_resolution {
-            TsResolution::MicroSecond => 1_000,
-            TsResolution::NanoSecond => 1,
+        Loader::open_reader(source, None)
+    }
+
+    /// Open a capture in the given format.
+    pub fn open_as(source: Source, format: InputFormat)
+        -> Result<Loader<Source>, Error>
+    {
+        Loader::open_reader(source, Some(format))
+    }
+
+    fn open_reader(source: Source, format: Option<InputFormat>)
+        -> Result<Loader<Source>, Error>
+    {
+        let (decompressor, compressed_bytes_read) =
+            Decompressor::new(source)?;
+        let mut reader = BufReader::new(decompressor);
+        let format = match format {
+            Some(format) => format,
+            None => InputFormat::detect(reader.fill_buf()?),
         };
-        let start_time = None;
-        Ok(Loader{pcap, bytes_read, frac_ns, start_time})
+        let (format, bytes_read) = match format {
+            InputFormat::PcapNg => {
+                // The reader does not report the length of the section
+                // header block, so take it from the start of the file.
+                let start = reader.fill_buf()?;
+                let header_length = match (start.get(4..8), start.get(8..12))
+                {
+                    (Some(length), Some([0x4D, 0x3C, 0x2B, 0x1A])) =>
+                        u32::from_le_bytes(length.try_into()?),
+                    (Some(length), Some(_)) =>
+                        u32::from_be_bytes(length.try_into()?),
+                    _ => 0,
+                };
+                let reader = PcapNgReader::new(reader)?;
+                let endianness = reader.section().endianness;
+                (Format::PcapNg { reader, endianness }, header_length as u64)
+            },
+            InputFormat::UsbmonText => {
+                let lines = reader.lines();
+                (Format::UsbmonText { lines, line_number: 0 }, 0)
+            },
+            InputFormat::Iti1480a => {
+                let parser = iti1480a::Parser::default();
+                (Format::Iti1480a { reader, parser }, 0)
+            },
+            InputFormat::BeagleCsv => {
+                let format = Format::BeagleCsv {
+                    reader,
+                    line_number: 0,
+                    columns: None,
+                };
+                (format, 0)
+            },
+            InputFormat::HexText => {
+                let format = Format::HexText {
+                    reader,
+                    line_number: 0,
+                    timestamp: 0,
+                };
+                (format, 0)
+            },
+            InputFormat::OpenVizsla => {
+                let parser = openvizsla::Parser::default();
+                (Format::OpenVizsla { reader, parser }, 0)
+            },
+            InputFormat::UsbipStream => {
+                let format = Format::UsbipStream {
+                    reader,
+                    session: usbip::Session::new(0),
+                    buffer: Vec::new(),
+                };
+                (format, 0)
+            },
+            InputFormat::Pcap => {
+                let reader = PcapReader::new(reader)?;
+                let header = reader.header();
+                let encapsulation =
+                    match Encapsulation::from_link_type(header.datalink) {
+                        Some(encapsulation) => encapsulation,
+                        None => bail!(
+                            "Unsupported link type {:?}: only captures of \
+                             USB 2.0 packets, Linux usbmon records or \
+                             network traffic can be loaded",
+                            header.datalink)
+                    };
+                let frac_ns = match header.ts_resolution {
+                    TsResolution::MicroSecond => 1_000,
+                    TsResolution::NanoSecond => 1,
+                };
+                let format = Format::Pcap { reader, frac_ns, encapsulation };
+                (format, FILE_HEADER_SIZE)
+            },
+        };
+        Ok(Loader {
+            format,
+            bytes_read,
+            compressed_bytes_read,
+            start_time: None,
+            interfaces: Vec::new(),
+            sources: Vec::new(),
+            record_source: 0,
+            skipped: BTreeMap::new(),
+            converter: usbmon::Converter::default(),
+            dissector: usbip::Dissector::default(),
+            converted: VecDeque::new(),
+        })
     }
 
-    pub fn next(&mut self) -> Option<Result<(RawPcapPacket, u64), Error>> {
-        match self.pcap.next_raw_packet() {
-            None => None,
-            Some(Err(e)) => Some(Err(Error::from(e))),
-            Some(Ok(packet)) => {
-                let raw_timestamp =
-                    packet.ts_sec as u64 * 1_000_000_000 +
-                    packet.ts_frac as u64 * self.frac_ns;
-                let timestamp = if let Some(start) = self.start_time {
-                    raw_timestamp - start
-                } else {
-                    self.start_time = Some(raw_timestamp);
-                    0
+    /// Bytes of the file read so far. If the file is compressed, this
+    /// counts its compressed bytes, not those decompressed from it.
+    pub fn file_bytes_read(&self) -> u64 {
+        match &self.compressed_bytes_read {
+            Some(count) => count.load(Ordering::Relaxed),
+            None => self.bytes_read,
+        }
+    }
+
+    pub fn next(&mut self) -> Option<Result<(Packet<'_>, u64), Error>> {
+        // Packets in a pcap file can be passed on without copying them.
+        if !matches!(self.format, Format::Pcap {
+            encapsulation: Encapsulation::Packets, ..
+        }) {
+            return self.next_converted().transpose();
+        }
+        let Format::Pcap { reader, frac_ns, .. } = &mut self.format else {
+            unreachable!()
+        };
+        let packet = match reader.next_raw_packet()? {
+            Ok(packet) => packet,
+            Err(e) => return Some(Err(Error::from(e)))
+        };
+        let raw_timestamp =
+            packet.ts_sec as u64 * 1_000_000_000 +
+            packet.ts_frac as u64 * *frac_ns;
+        let timestamp = relative_time(&mut self.start_time, raw_timestamp);
+        let size = PACKET_HEADER_SIZE + packet.data.len();
+        self.bytes_read += size as u64;
+        Some(Ok((Packet { data: packet.data, source: 0 }, timestamp)))
+    }
+
+    /// Fetch the next packet, converting usbmon records as necessary.
+    fn next_converted(&mut self)
+        -> Result<Option<(Packet<'static>, u64)>, Error>
+    {
+        loop {
+            if let Some((data, timestamp, source)) =
+                self.converted.pop_front()
+            {
+                let packet = Packet { data: Cow::from(data), source };
+                return Ok(Some((packet, timestamp)));
+            }
+            let (record, raw_timestamp) = match self.next_record()? {
+                Some(record) => record,
+                None => return Ok(None)
+            };
+            let timestamp = relative_time(&mut self.start_time, raw_timestamp);
+            let source = self.record_source;
+            let packets = match record {
+                Record::Packet(data) => {
+                    let packet = Packet { data: Cow::from(data), source };
+                    return Ok(Some((packet, timestamp)));
+                },
+                Record::Usbmon(data, header_size) =>
+                    self.converter.convert(&data, header_size)?,
+                Record::Urb(urb) =>
+                    self.converter.convert_urb(&urb),
+                Record::Frame(data, datalink) => self.dissector
+                    .handle_frame(&data, datalink)?
+                    .iter()
+                    .flat_map(|urb| self.converter.convert_urb(urb))
+                    .collect(),
+            };
+            self.converted.extend(packets
+                .into_iter()
+                .map(|packet| (packet, timestamp, source)));
+        }
+    }
+
+    /// Fetch the next record from the capture source, with its timestamp
+    /// in nanoseconds.
+    fn next_record(&mut self) -> Result<Option<(Record, u64)>, Error> {
+        match &mut self.format {
+            Format::Pcap { reader, frac_ns, encapsulation } => {
+                let packet = match reader.next_raw_packet() {
+                    Some(result) => result?,
+                    None => return Ok(None)
                 };
-                let size = 16 + packet.data.len();
+                let timestamp =
+                    packet.ts_sec as u64 * 1_000_000_000 +
+                    packet.ts_frac as u64 * *frac_ns;
+                let size = PACKET_HEADER_SIZE + packet.data.len();
                 self.bytes_read += size as u64;
-                Some(Ok((packet, timestamp)))
-            }
+                let data = packet.data.to_vec();
+                Ok(Some((Record::new(data, *encapsulation), timestamp)))
+            },
+            Format::PcapNg { reader, endianness } => {
+                while let Some(result) = reader.next_raw_block() {
+                    let block = result?;
+                    self.bytes_read += block.initial_len as u64;
+                    match block.type_ {
+                        SECTION_HEADER_BLOCK => {
+                            // Interfaces are numbered afresh in each section.
+                            *endianness = reader.section().endianness;
+                            self.interfaces.clear();
+                            self.sources.clear();
+                        },
+                        INTERFACE_DESCRIPTION_BLOCK => {
+                            use Encapsulation::Usbip;
+                            let interface = Interface::from_block(
+                                reader.interfaces().last().context(
+                                    "Interface description not recorded")?);
+                            let encapsulation =
+                                Encapsulation::from_link_type(
+                                    interface.link_type);
+                            let index = self.interfaces.len();
+                            self.interfaces.push(interface);
+                            match (self.sources.first(), encapsulation) {
+                                (_, None) => {},
+                                // A network interface is only used if there
+                                // is no other source.
+                                (None, Some(network @ Usbip(_))) =>
+                                    self.sources.push((index, network)),
+                                (Some(_), Some(Usbip(_))) => {},
+                                // A USB interface replaces a network
+                                // interface if no packets have been read.
+                                (Some((_, Usbip(_))), Some(usb)) => {
+                                    if self.start_time.is_none() {
+                                        self.sources = vec![(index, usb)];
+                                    }
+                                },
+                                (_, Some(usb)) => {
+                                    if self.sources.len() > u8::MAX as usize
+                                    {
+                                        bail!("Too many USB interfaces \
+                                               in pcapng file");
+                                    }
+                                    self.sources.push((index, usb));
+                                },
+                            }
+                        },
+                        ENHANCED_PACKET_BLOCK => {
+                            let body = &block.body;
+                            if body.len() < ENHANCED_PACKET_HEADER_SIZE {
+                                bail!("Enhanced packet block too short");
+                            }
+                            let field = |i: usize| {
+                                let bytes =
+                                    body[i * 4..][..4].try_into().unwrap();
+                                match endianness {
+                                    Endianness::Little =>
+                                        u32::from_le_bytes(bytes),
+                                    Endianness::Big =>
+                                        u32::from_be_bytes(bytes),
+                                }
+                            };
+                            let interface_id = field(0) as usize;
+                            let timestamp =
+                                (field(1) as u64) << 32 | field(2) as u64;
+                            let length = field(3) as usize;
+                            let data = body
+                                .get(ENHANCED_PACKET_HEADER_SIZE..)
+                                .and_then(|data| data.get(..length))
+                                .context("Enhanced packet block truncated")?;
+                            let source = self.sources
+                                .iter()
+                                .position(|(index, _)| *index == interface_id);
+                            match source {
+                                Some(source) => {
+                                    let (index, encapsulation) =
+                                        self.sources[source];
+                                    let timestamp = self.interfaces[index]
+                                        .timestamp_ns(timestamp);
+                                    let record = Record::new(
+                                        data.to_vec(), encapsulation);
+                                    self.record_source = source as u8;
+                                    return Ok(Some((record, timestamp)));
+                                },
+                                None => {
+                                    *self.skipped
+                                        .entry(interface_id)
+                                        .or_default() += 1;
+                                }
+                            }
+                        },
+                        _ => {}
+                    }
+                }
+                if self.sources.is_empty() {
+                    bail!("No interface carrying USB traffic found in file");
+                }
+                Ok(None)
+            },
+            Format::UsbmonText { lines, line_number } => {
+                for line in lines {
+                    let line = line?;
+                    *line_number += 1;
+                    self.bytes_read += line.len() as u64 + 1;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let (urb, timestamp) = usbmon::Urb::parse_text(&line)
+                        .with_context(|| format!(
+                            "Invalid usbmon text on line {line_number}"))?;
+                    return Ok(Some((Record::Urb(urb), timestamp)));
+                }
+                Ok(None)
+            },
+            Format::Iti1480a { reader, parser } => {
+                loop {
+                    let mut bytes = [0; 2];
+                    let length = reader.read(&mut bytes)?;
+                    if length == 1 {
+                        reader.read_exact(&mut bytes[1..])
+                            .context("ITI1480A capture is truncated")?;
+                    }
+                    let packet = if length == 0 {
+                        // Pass on any packet still being received.
+                        match parser.finish() {
+                            Some(packet) => packet,
+                            None => return Ok(None)
+                        }
+                    } else {
+                        self.bytes_read += 2;
+                        match parser.handle(bytes)? {
+                            Some(packet) => packet,
+                            None => continue
+                        }
+                    };
+                    let (data, timestamp) = packet;
+                    return Ok(Some((Record::Packet(data), timestamp)));
+                }
+            },
+            Format::BeagleCsv { reader, line_number, columns } => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    let length = reader.read_line(&mut line)?;
+                    if length == 0 {
+                        return Ok(None);
+                    }
+                    *line_number += 1;
+                    self.bytes_read += length as u64;
+                    let line = line.trim_end();
+                    // Rows before the header row are skipped.
+                    let Some(columns) = columns else {
+                        *columns = beagle::Columns::from_header(line);
+                        continue;
+                    };
+                    if line.is_empty() {
+                        continue;
+                    }
+                    let row = columns.parse(line).with_context(|| format!(
+                        "Invalid Beagle CSV export on line {line_number}"))?;
+                    if let Some((data, timestamp)) = row {
+                        return Ok(Some((Record::Packet(data), timestamp)));
+                    }
+                }
+            },
+            Format::HexText { reader, line_number, timestamp } => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    let length = reader.read_line(&mut line)?;
+                    if length == 0 {
+                        return Ok(None);
+                    }
+                    *line_number += 1;
+                    self.bytes_read += length as u64;
+                    let parsed = hex_text::parse_line(&line)
+                        .with_context(|| format!(
+                            "Invalid hex text on line {line_number}"))?;
+                    if let Some(line) = parsed {
+                        if let Some(line_timestamp) = line.timestamp {
+                            *timestamp = line_timestamp;
+                        }
+                        let record = Record::Packet(line.packet);
+                        return Ok(Some((record, *timestamp)));
+                    }
+                }
+            },
+            Format::OpenVizsla { reader, parser } => {
+                match parser.next_packet(reader)? {
+                    Some((data, timestamp, length)) => {
+                        self.bytes_read += length as u64;
+                        Ok(Some((Record::Packet(data), timestamp)))
+                    },
+                    None => Ok(None)
+                }
+            },
+            Format::UsbipStream { reader, session, buffer } => {
+                // The messages carry no timestamps.
+                loop {
+                    if let Some(urb) = session.next_urb(buffer)? {
+                        return Ok(Some((Record::Urb(urb), 0)));
+                    }
+                    let bytes = reader.fill_buf()?;
+                    if bytes.is_empty() {
+                        if !buffer.is_empty() {
+                            bail!("usbip message dump is truncated");
+                        }
+                        return Ok(None);
+                    }
+                    let length = bytes.len();
+                    buffer.extend_from_slice(bytes);
+                    reader.consume(length);
+                    self.bytes_read += length as u64;
+                }
+            },
         }
     }
+
+    /// Timestamp of the first packet, in nanoseconds, before it was made
+    /// relative to the start of the capture.
+    ///
+    /// This is only known once the first packet has been read.
+    pub fn start_time(&self) -> Option<u64> {
+        self.start_time
+    }
+
+    /// Number of times the capture device reported that packets were lost
+    /// because its buffer overflowed.
+    pub fn overflows(&self) -> u64 {
+        match &self.format {
+            Format::OpenVizsla { parser, .. } => parser.overflows(),
+            _ => 0
+        }
+    }
+
+    /// Shared count of buffer overflows, if the format reports them,
+    /// which can be read while a packet from the loader is in use.
+    pub fn overflow_count(&self) -> Option<Arc<AtomicU64>> {
+        match &self.format {
+            Format::OpenVizsla { parser, .. } => Some(parser.overflow_count()),
+            _ => None
+        }
+    }
+
+    /// Names of the sources of the capture found so far, if it has more
+    /// than one.
+    pub fn source_names(&self) -> Vec<String> {
+        if self.sources.len() < 2 {
+            return Vec::new();
+        }
+        self.sources
+            .iter()
+            .map(|(index, _)| self.interfaces[*index].describe(*index))
+            .collect()
+    }
+
+    /// Traffic skipped from sources other than the one loaded, with a
+    /// description of each source and the number of packets or records.
+    pub fn skipped(&self) -> impl Iterator<Item=(String, u64)> + '_ {
+        let interfaces = self.skipped.iter().map(|(&index, &count)| {
+            let description = match self.interfaces.get(index) {
+                Some(interface) => interface.describe(index),
+                None => format!("undescribed interface {index}"),
+            };
+            (description, count)
+        });
+        let buses = self.converter
+            .skipped()
+            .map(|(bus, count)| (format!("usbmon bus {bus}"), count));
+        interfaces.chain(buses)
+    }
+}
+
+/// Convert a timestamp to one relative to the first in the capture.
+fn relative_time(start_time: &mut Option<u64>, timestamp: u64) -> u64 {
+    match start_time {
+        Some(start) => timestamp.saturating_sub(*start),
+        None => {
+            *start_time = Some(timestamp);
+            0
+        }
+    }
+}
+
+/// File formats in which a capture can be saved.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    Pcap,
+    PcapNg,
+}
+
+impl FileFormat {
+    /// Choose the format for a file name, based on its extension.
+    pub fn from_file_name(name: &str) -> FileFormat {
+        if name.to_ascii_lowercase().ends_with(".pcapng") {
+            FileFormat::PcapNg
+        } else {
+            FileFormat::Pcap
+        }
+    }
+}
+
+enum Output<Dest: Write> {
+    Pcap(PcapWriter<BufWriter<Dest>>),
+    PcapNg(PcapNgWriter<BufWriter<Dest>>),
+}
+
+/// Writes packets to a pcap or pcapng file.
+pub struct Writer<Dest: Write> {
+    output: Output<Dest>,
+    /// Number of interfaces described in the file so far.
+    interfaces: usize,
 }
 
 impl<Dest> Writer<Dest> where Dest: Write {
-    pub fn open(dest: Dest) -> Result<Writer<Dest>, Error> {
+    pub fn open(dest: Dest, format: FileFormat)
+        -> Result<Writer<Dest>, Error>
+    {
         let writer = BufWriter::new(dest);
-        let header = PcapHeader {
-            datalink: DataLink::USB_2_0,
-            ts_resolution: TsResolution::NanoSecond,
-            .. PcapHeader::default()
+        let output = match format {
+            FileFormat::Pcap => {
+                let header = PcapHeader {
+                    datalink: DataLink::USB_2_0,
+                    ts_resolution: TsResolution::NanoSecond,
+                    .. PcapHeader::default()
+                };
+                Output::Pcap(PcapWriter::with_header(writer, header)?)
+            },
+            FileFormat::PcapNg =>
+                Output::PcapNg(PcapNgWriter::new(writer)?),
         };
-        Ok(Writer{pcap: PcapWriter::with_header(writer, header)?})
+        let mut writer = Writer { output, interfaces: 0 };
+        writer.add_interfaces(1)?;
+        Ok(writer)
+    }
+
+    /// Describe further interfaces, until there are the number given.
+    fn add_interfaces(&mut self, count: usize) -> Result<(), Error> {
+        match &mut self.output {
+            Output::Pcap(_) if count > 1 =>
+                bail!("A pcap file can only have one interface"),
+            Output::Pcap(_) => {},
+            Output::PcapNg(pcapng) => while self.interfaces < count {
+                // Each interface has timestamps in nanoseconds.
+                pcapng.write_pcapng_block(InterfaceDescriptionBlock {
+                    linktype: DataLink::USB_2_0,
+                    snaplen: 0,
+                    options: vec![InterfaceDescriptionOption::IfTsResol(9)],
+                })?;
+                self.interfaces += 1;
+            },
+        }
+        Ok(())
+    }
+
+    pub fn add_packet(&mut self, bytes: &[u8], timestamp_ns: u64)
+        -> Result<(), Error>
+    {
+        self.add_packet_from(0, bytes, timestamp_ns)
     }
 
-    pub fn add_packet(&mut self, bytes: &[u8], timestamp_ns: u64) -> Result<(), Error> {
+    /// Add a packet captured on one of several interfaces.
+    ///
+    /// Only pcapng output can have more than one interface.
+    pub fn add_packet_from(&mut self,
+                           interface: usize,
+                           bytes: &[u8],
+                           timestamp_ns: u64)
+        -> Result<(), Error>
+    {
+        self.add_interfaces(interface + 1)?;
         let length: u32 = bytes
             .len()
             .try_into()
             .context("Packet too large for pcap file")?;
-        let packet = RawPcapPacket {
-            ts_sec: (timestamp_ns / 1_000_000_000) as u32,
-            ts_frac: (timestamp_ns % 1_000_000_000) as u32,
-            incl_len: length,
-            orig_len: length,
-            data: Cow::from(bytes)
-        };
-        self.pcap.write_raw_packet(&packet)?;
+        match &mut self.output {
+            Output::Pcap(pcap) => {
+                let packet = RawPcapPacket {
+                    ts_sec: (timestamp_ns / 1_000_000_000) as u32,
+                    ts_frac: (timestamp_ns % 1_000_000_000) as u32,
+                    incl_len: length,
+                    orig_len: length,
+                    data: Cow::from(bytes)
+                };
+                pcap.write_raw_packet(&packet)?;
+            },
+            Output::PcapNg(pcapng) => {
+                // The timestamp is written in the units of the interface.
+                pcapng.write_pcapng_block(EnhancedPacketBlock {
+                    interface_id: interface as u32,
+                    timestamp: Duration::from_nanos(timestamp_ns),
+                    original_len: length,
+                    data: Cow::from(bytes),
+                    options: vec![],
+                })?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Write out the packets held in the writer's buffer.
+    ///
+    /// Only pcapng output can be flushed before the writer is closed.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        match &mut self.output {
+            Output::Pcap(_) => bail!("Cannot flush pcap output"),
+            Output::PcapNg(pcapng) => pcapng.get_mut().flush()?,
+        }
         Ok(())
     }
 
     pub fn close(self) -> Result<(), Error> {
-        self.pcap.into_writer().flush()?;
+        match self.output {
+            Output::Pcap(pcap) => pcap.into_writer().flush()?,
+            Output::PcapNg(pcapng) => pcapng.into_inner().flush()?,
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(datalink: DataLink) -> Vec<u8> {
+        let header = PcapHeader {
+            datalink,
+            ts_resolution: TsResolution::MicroSecond,
+            .. PcapHeader::default()
+        };
+        let mut writer = PcapWriter::with_header(Vec::new(), header).unwrap();
+        for (ts_sec, ts_frac) in [(100, 5), (101, 7)] {
+            let packet = RawPcapPacket {
+                ts_sec,
+                ts_frac,
+                incl_len: 1,
+                orig_len: 1,
+                data: Cow::from(&[0xA5][..]),
+            };
+            writer.write_raw_packet(&packet).unwrap();
+        }
+        writer.into_writer()
+    }
+
+    #[test]
+    fn test_link_types() {
+        for datalink in [DataLink::USB_2_0, DataLink::USB_2_0_HIGH_SPEED] {
+            let data = capture(datalink);
+            let mut loader = Loader::open(data.as_slice()).unwrap();
+            let (packet, timestamp) = loader.next().unwrap().unwrap();
+            assert_eq!(packet.data.as_ref(), &[0xA5]);
+            assert_eq!(timestamp, 0);
+            let (_, timestamp) = loader.next().unwrap().unwrap();
+            assert_eq!(timestamp, 1_000_002_000);
+            assert!(loader.next().is_none());
+            assert_eq!(loader.bytes_read, data.len() as u64);
+        }
+        let data = capture(DataLink::IEEE802_11);
+        assert!(Loader::open(data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_pcapng() {
+        let mut writer = PcapNgWriter::new(Vec::new()).unwrap();
+        let ethernet = InterfaceDescriptionBlock::new(DataLink::ETHERNET, 0);
+        let usb = InterfaceDescriptionBlock {
+            linktype: DataLink::USB_2_0,
+            snaplen: 0,
+            options: vec![
+                InterfaceDescriptionOption::IfName(Cow::from("usb0")),
+                InterfaceDescriptionOption::IfTsResol(9),
+            ],
+        };
+        let second_usb = InterfaceDescriptionBlock {
+            options: vec![InterfaceDescriptionOption::IfTsResol(9)],
+            .. InterfaceDescriptionBlock::new(DataLink::USB_2_0, 0)
+        };
+        writer.write_pcapng_block(ethernet).unwrap();
+        writer.write_pcapng_block(usb).unwrap();
+        writer.write_pcapng_block(second_usb).unwrap();
+        // Timestamps are written in the units of each interface.
+        let packets = [(1, 1_000), (0, 2), (2, 1_500), (1, 1_750)];
+        for (interface_id, timestamp) in packets {
+            writer.write_pcapng_block(EnhancedPacketBlock {
+                interface_id,
+                timestamp: Duration::from_nanos(timestamp),
+                original_len: 3,
+                data: Cow::from(&[0xC3, 0x01, 0x02][..]),
+                options: vec![],
+            }).unwrap();
+        }
+        let data = writer.into_inner();
+        let mut loader = Loader::open(data.as_slice()).unwrap();
+        let mut timestamps = Vec::new();
+        while let Some(result) = loader.next() {
+            let (packet, timestamp) = result.unwrap();
+            assert_eq!(packet.data.as_ref(), &[0xC3, 0x01, 0x02]);
+            timestamps.push((packet.source, timestamp));
+        }
+        // Each USB interface is a source of its own.
+        assert_eq!(timestamps, vec![(0, 0), (1, 500), (0, 750)]);
+        assert_eq!(loader.source_names(),
+                   vec!["interface 1 (usb0)", "interface 2"]);
+        assert_eq!(loader.bytes_read, data.len() as u64);
+        let skipped: Vec<_> = loader.skipped().collect();
+        assert_eq!(skipped, vec![("interface 0".to_string(), 1)]);
+        let name = loader.interfaces[1].describe(1);
+        assert_eq!(name, "interface 1 (usb0)");
+    }
+
+    #[test]
+    fn test_usbmon_text() {
+        let text = "\
+d5ea89a0 3575914555 S Ci:1:001:0 s a3 00 0000 0003 0004 4 <
+d5ea89a0 3575914560 C Ci:1:001:0 0 4 = 01050000
+
+d5ea8a40 3575915555 S Bo:1:005:2 -115 4 = 55534243
+d5ea8a40 3575915600 C Bo:1:005:2 0 4 >
+";
+        let mut loader = Loader::open(text.as_bytes()).unwrap();
+        let mut packets = Vec::new();
+        while let Some(result) = loader.next() {
+            let (packet, timestamp) = result.unwrap();
+            packets.push((packet.data.into_owned(), timestamp));
+        }
+        let pids: Vec<u8> = packets.iter().map(|(data, _)| data[0]).collect();
+        assert_eq!(pids, vec![
+            // SETUP, DATA0, ACK; IN, DATA1, ACK; OUT, DATA1, ACK
+            0x2D, 0xC3, 0xD2, 0x69, 0x4B, 0xD2, 0xE1, 0x4B, 0xD2,
+            // OUT, DATA0, ACK
+            0xE1, 0xC3, 0xD2,
+        ]);
+        assert_eq!(&packets[4].0[1..5], &[0x01, 0x05, 0x00, 0x00]);
+        assert_eq!(packets[0].1, 5_000);
+        assert_eq!(packets[11].1, 1_045_000);
+        assert_eq!(loader.bytes_read, text.len() as u64);
+        let invalid = format!("{}\nbogus\n", text.lines().next().unwrap());
+        let mut loader = Loader::open(invalid.as_bytes()).unwrap();
+        let error = loop {
+            match loader.next() {
+                Some(Ok(_)) => continue,
+                Some(Err(error)) => break error,
+                None => panic!("no error for invalid line"),
+            }
+        };
+        assert_eq!(error.to_string(), "Invalid usbmon text on line 2");
+    }
+
+    #[test]
+    fn test_input_formats() {
+        for format in InputFormat::ALL {
+            assert_eq!(InputFormat::from_name(format.name()).unwrap(), format);
+        }
+        let error = InputFormat::from_name("bogus").err().unwrap();
+        assert!(error.to_string().starts_with(
+            "Unknown input format 'bogus', expected one of: pcap, pcapng"));
+        let text = "2D 00 10\n";
+        assert_eq!(InputFormat::detect(text.as_bytes()), InputFormat::HexText);
+        assert!(Loader::open_as(text.as_bytes(), InputFormat::Pcap).is_err());
+        let data = capture(DataLink::USB_2_0);
+        assert_eq!(InputFormat::detect(&data), InputFormat::Pcap);
+        let mut loader = Loader::open_as(data.as_slice(), InputFormat::Pcap)
+            .unwrap();
+        assert!(loader.next().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let packets: [(&[u8], u64); 3] = [
+            (&[0xA5, 0x01, 0x02], 0),
+            (&[0xD2], 1_234),
+            (&[0xC3, 0x80, 0x06, 0x00, 0x01], 5_000_000_001),
+        ];
+        for format in [FileFormat::Pcap, FileFormat::PcapNg] {
+            let mut data = Vec::new();
+            let mut writer = Writer::open(&mut data, format).unwrap();
+            for (bytes, timestamp) in packets {
+                writer.add_packet(bytes, timestamp).unwrap();
+            }
+            writer.close().unwrap();
+            let mut loader = Loader::open(data.as_slice()).unwrap();
+            for (bytes, timestamp) in packets {
+                let (packet, loaded) = loader.next().unwrap().unwrap();
+                assert_eq!(packet.data.as_ref(), bytes);
+                assert_eq!(loaded, timestamp);
+            }
+            assert!(loader.next().is_none());
+        }
+        assert_eq!(FileFormat::from_file_name("usb.PcapNG"),
+                   FileFormat::PcapNg);
+        assert_eq!(FileFormat::from_file_name("usb.pcap"), FileFormat::Pcap);
+    }
+}
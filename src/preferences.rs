@@ -0,0 +1,482 @@
+//! Preferences, kept between runs of Packetry in a text file with one
+//! entry per line.
+
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Error, bail};
+
+use crate::backend::Speed;
+use crate::capture::{CaptureReader, TrafficItem};
+use crate::usb::PID;
+
+/// Name of the preferences file in the configuration directory.
+pub const FILE_NAME: &str = "preferences";
+
+/// Number of recently opened files remembered.
+const MAX_RECENT_FILES: usize = 10;
+
+/// Line written at the start of a preferences file.
+const HEADER: &str = "# Packetry preferences";
+
+/// Names of the optional columns of the Traffic pane.
+pub const COLUMNS: [&str; 6] =
+    ["number", "delta", "endpoint", "result", "length", "data"];
+
+/// How much of the traffic is summarised rather than shown in full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Summary {
+    /// Show all the traffic.
+    #[default]
+    Full,
+    /// Collapse idle time and polling.
+    Collapsed,
+    /// Hide SOF packets, and collapse polling.
+    Brief,
+}
+
+impl Summary {
+    pub const ALL: [Summary; 3] =
+        [Summary::Full, Summary::Collapsed, Summary::Brief];
+
+    /// Name of the setting in the preferences file.
+    pub fn name(&self) -> &'static str {
+        use Summary::*;
+        match self {
+            Full => "full",
+            Collapsed => "collapsed",
+            Brief => "brief",
+        }
+    }
+
+    /// The setting with a name.
+    pub fn from_name(name: &str) -> Option<Summary> {
+        Summary::ALL.into_iter().find(|summary| summary.name() == name)
+    }
+
+    /// Description of the setting.
+    pub fn label(&self) -> &'static str {
+        use Summary::*;
+        match self {
+            Full => "Show all traffic",
+            Collapsed => "Collapse idle time and polling",
+            Brief => "Hide SOF packets and collapse polling",
+        }
+    }
+
+    /// Whether groups of SOF packets are hidden.
+    pub fn hide_sof(&self) -> bool {
+        *self == Summary::Brief
+    }
+
+    /// Whether groups of SOF packets are shown as idle time.
+    pub fn collapse_idle(&self) -> bool {
+        *self == Summary::Collapsed
+    }
+
+    /// Whether groups of NAKed transactions are collapsed.
+    pub fn collapse_polling(&self) -> bool {
+        *self != Summary::Full
+    }
+}
+
+/// Whether Packetry is shown with light or dark colors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Theme {
+    /// Follow the system's setting.
+    #[default]
+    System,
+    Light,
+    Dark,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 3] = [Theme::System, Theme::Light, Theme::Dark];
+
+    /// Name of the setting in the preferences file.
+    pub fn name(&self) -> &'static str {
+        use Theme::*;
+        match self {
+            System => "system",
+            Light => "light",
+            Dark => "dark",
+        }
+    }
+
+    /// The setting with a name.
+    pub fn from_name(name: &str) -> Option<Theme> {
+        Theme::ALL.into_iter().find(|theme| theme.name() == name)
+    }
+
+    /// Description of the setting.
+    pub fn label(&self) -> &'static str {
+        use Theme::*;
+        match self {
+            System => "System default",
+            Light => "Light",
+            Dark => "Dark",
+        }
+    }
+}
+
+/// A color to show the items matching some conditions in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColorRule {
+    pub color: String,
+    /// The conditions, as entered.
+    pub conditions: String,
+    /// Device addresses to match, or all if empty.
+    addresses: Vec<u8>,
+    /// Endpoint numbers to match, or all if empty.
+    endpoints: Vec<u8>,
+    /// Packet PIDs or results to match, or all if empty.
+    pids: Vec<PID>,
+}
+
+impl ColorRule {
+    /// Parse a rule from a color followed by its conditions.
+    pub fn parse(text: &str) -> Result<ColorRule, Error> {
+        let text = text.trim();
+        let (color, conditions) = text
+            .split_once(char::is_whitespace)
+            .with_context(|| format!("No conditions given for '{text}'"))?;
+        let valid_color = match color.strip_prefix('#') {
+            Some(hex) => hex.len() == 6 &&
+                hex.chars().all(|c| c.is_ascii_hexdigit()),
+            None => color.chars().all(|c| c.is_ascii_alphabetic()),
+        };
+        if !valid_color {
+            bail!("Invalid color '{color}', expected a name or #rrggbb");
+        }
+        let mut rule = ColorRule {
+            color: color.to_string(),
+            conditions: conditions.trim().to_string(),
+            addresses: Vec::new(),
+            endpoints: Vec::new(),
+            pids: Vec::new(),
+        };
+        for term in conditions.split_whitespace() {
+            let (key, values) = term
+                .split_once('=')
+                .with_context(|| format!("Invalid condition '{term}'"))?;
+            for value in values.split(',') {
+                let number = |limit: u8| -> Result<u8, Error> {
+                    let number: u8 = value.parse().with_context(||
+                        format!("Invalid {key} '{value}' in condition"))?;
+                    if number > limit {
+                        bail!("The {key} must be no more than {limit}");
+                    }
+                    Ok(number)
+                };
+                match key {
+                    "address" => rule.addresses.push(number(127)?),
+                    "endpoint" => rule.endpoints.push(number(15)?),
+                    "pid" => rule.pids.push(
+                        PID::from_name(&value.to_ascii_uppercase())
+                            .with_context(|| format!(
+                                "Unknown PID '{value}' in condition"))?),
+                    _ => bail!("Unknown condition '{key}', \
+                                expected 'address', 'endpoint' or 'pid'"),
+                }
+            }
+        }
+        Ok(rule)
+    }
+
+    /// Whether an item meets the conditions of this rule.
+    pub fn matches(&self, capture: &mut CaptureReader, item: &TrafficItem)
+        -> Result<bool, Error>
+    {
+        if !self.addresses.is_empty() || !self.endpoints.is_empty() {
+            let Some(endpoint) = capture.item_endpoint(item)? else {
+                return Ok(false);
+            };
+            let address = endpoint.device_address().0;
+            let number = endpoint.number().0;
+            if !(self.addresses.is_empty() ||
+                 self.addresses.contains(&address)) ||
+               !(self.endpoints.is_empty() ||
+                 self.endpoints.contains(&number))
+            {
+                return Ok(false);
+            }
+        }
+        if !self.pids.is_empty() {
+            let Some(pid) = capture.item_result(item)? else {
+                return Ok(false);
+            };
+            if !self.pids.contains(&pid) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl std::fmt::Display for ColorRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", self.color, self.conditions)
+    }
+}
+
+/// Everything kept between runs.
+#[derive(Clone, Debug, Default)]
+pub struct Preferences {
+    /// Directory for temporary files, if not the system's default.
+    pub temp_dir: Option<PathBuf>,
+    /// Megabytes of captured data to keep in memory instead of files.
+    pub memory_limit: u64,
+    /// Speed selected for capture, if supported.
+    pub speed: Option<Speed>,
+    /// Names of the optional columns shown at first.
+    pub columns: Vec<String>,
+    /// How much of the traffic is summarised at first.
+    pub summary: Summary,
+    /// Whether to use light or dark colors.
+    pub theme: Theme,
+    /// Whether to not record live captures for recovery after a crash.
+    pub no_recovery: bool,
+    pub color_rules: Vec<ColorRule>,
+    /// URIs of the capture files opened recently, most recent first.
+    pub recent_files: Vec<String>,
+}
+
+impl Preferences {
+    /// Index of the first color rule an item matches, if any.
+    pub fn color_rule(&self, capture: &mut CaptureReader, item: &TrafficItem)
+        -> Result<Option<usize>, Error>
+    {
+        for (index, rule) in self.color_rules.iter().enumerate() {
+            if rule.matches(capture, item)? {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record that a file has been opened, moving it to the top of the
+    /// recent files.
+    pub fn add_recent_file(&mut self, uri: &str) {
+        self.recent_files.retain(|recent| recent != uri);
+        self.recent_files.insert(0, uri.to_string());
+        self.recent_files.truncate(MAX_RECENT_FILES);
+    }
+
+    /// Load a preferences file.
+    pub fn load<Source: Read>(source: Source) -> Result<Preferences, Error> {
+        let mut preferences = Preferences::default();
+        for (index, line) in BufReader::new(source).lines().enumerate() {
+            let line = line?;
+            preferences.parse_line(&line).with_context(|| format!(
+                "Invalid preferences file entry on line {}", index + 1))?;
+        }
+        Ok(preferences)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), Error> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "temp-dir" if rest.is_empty() =>
+                bail!("No directory given for temp-dir entry"),
+            "temp-dir" => self.temp_dir = Some(PathBuf::from(rest)),
+            "memory-limit" => self.memory_limit = rest.parse()
+                .with_context(|| format!("Invalid memory limit '{rest}', \
+                    expected a number of megabytes"))?,
+            "speed" => self.speed = Some(Speed::from_name(rest)
+                .with_context(|| format!("Unknown speed '{rest}', \
+                    expected 'high', 'full', 'low' or 'auto'"))?),
+            "columns" => {
+                self.columns.clear();
+                for name in rest.split_whitespace() {
+                    if !COLUMNS.contains(&name) {
+                        bail!("Unknown column '{name}'");
+                    }
+                    self.columns.push(name.to_string());
+                }
+            },
+            "summary" => self.summary = Summary::from_name(rest)
+                .with_context(|| format!("Unknown summary '{rest}', \
+                    expected 'full', 'collapsed' or 'brief'"))?,
+            "theme" => self.theme = Theme::from_name(rest)
+                .with_context(|| format!("Unknown theme '{rest}', \
+                    expected 'system', 'light' or 'dark'"))?,
+            "recovery" => self.no_recovery = match rest {
+                "on" => false,
+                "off" => true,
+                _ => bail!("Unknown recovery setting '{rest}', \
+                    expected 'on' or 'off'"),
+            },
+            "color" => self.color_rules.push(ColorRule::parse(rest)?),
+            "recent" if rest.is_empty() =>
+                bail!("No file given for recent entry"),
+            "recent" => self.recent_files.push(rest.to_string()),
+            _ => bail!("Unknown entry '{keyword}'"),
+        }
+        Ok(())
+    }
+
+    /// Save the preferences.
+    pub fn save<Dest: Write>(&self, dest: Dest) -> Result<(), Error> {
+        let mut dest = BufWriter::new(dest);
+        writeln!(dest, "{HEADER}")?;
+        if let Some(dir) = &self.temp_dir {
+            writeln!(dest, "temp-dir {}", dir.display())?;
+        }
+        if self.memory_limit != 0 {
+            writeln!(dest, "memory-limit {}", self.memory_limit)?;
+        }
+        if let Some(speed) = self.speed {
+            writeln!(dest, "speed {}", speed.name())?;
+        }
+        if !self.columns.is_empty() {
+            writeln!(dest, "columns {}", self.columns.join(" "))?;
+        }
+        if self.summary != Summary::default() {
+            writeln!(dest, "summary {}", self.summary.name())?;
+        }
+        if self.theme != Theme::default() {
+            writeln!(dest, "theme {}", self.theme.name())?;
+        }
+        if self.no_recovery {
+            writeln!(dest, "recovery off")?;
+        }
+        for rule in &self.color_rules {
+            writeln!(dest, "color {rule}")?;
+        }
+        for uri in &self.recent_files {
+            writeln!(dest, "recent {uri}")?;
+        }
+        dest.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::ItemSource;
+    use crate::decoder::load_test_capture;
+
+    #[test]
+    fn test_color_rules() {
+        let text = "# Packetry preferences\n\
+                    color red pid=stall\n\
+                    color #00a000 address=4 endpoint=0\n";
+        let preferences = Preferences::load(text.as_bytes()).unwrap();
+        assert_eq!(preferences.color_rules.len(), 2);
+        assert_eq!(preferences.color_rules[0].pids, vec![PID::STALL]);
+        let mut saved = Vec::new();
+        preferences.save(&mut saved).unwrap();
+        assert_eq!(String::from_utf8(saved).unwrap(), text);
+
+        assert!(ColorRule::parse("red").is_err());
+        assert!(ColorRule::parse("red; pid=ACK").is_err());
+        assert!(ColorRule::parse("#12345 pid=ACK").is_err());
+        assert!(ColorRule::parse("red pid=FOO").is_err());
+        assert!(ColorRule::parse("red address=200").is_err());
+        assert!(ColorRule::parse("red device=1").is_err());
+
+        let mut reader = load_test_capture("mouse");
+
+        let (_, count) = reader.item_children(None::<&TrafficItem>).unwrap();
+        let transfer = (0..count)
+            .map(|index| {
+                let item = reader.item(None, index).unwrap();
+                (item, reader.description(&item, false).unwrap())
+            })
+            .find(|(_, description)| description ==
+                "Getting device descriptor #0 for device 4, reading 18 bytes")
+            .unwrap()
+            .0;
+        let setup = reader.item(Some(&transfer), 0).unwrap();
+        let token = reader.item(Some(&setup), 0).unwrap();
+        let rules = |text: &str| Preferences {
+            color_rules: text.lines().map(|line|
+                ColorRule::parse(line).unwrap()).collect(),
+            ..Preferences::default()
+        };
+        let preferences = rules("red pid=STALL\nblue address=4");
+        for item in [&transfer, &setup, &token] {
+            assert_eq!(preferences.color_rule(&mut reader, item).unwrap(),
+                       Some(1));
+        }
+        let preferences = rules("red address=5\nblue endpoint=1\n\
+                                 green pid=SETUP address=4");
+        assert_eq!(preferences.color_rule(&mut reader, &transfer).unwrap(),
+                   None);
+        assert_eq!(preferences.color_rule(&mut reader, &token).unwrap(),
+                   Some(2));
+    }
+
+    #[test]
+    fn test_recent_files() {
+        let text = "# Packetry preferences\n\
+                    color red pid=stall\n\
+                    recent file:///tmp/b.pcap\n\
+                    recent file:///tmp/a%20b.pcap\n";
+        let mut preferences = Preferences::load(text.as_bytes()).unwrap();
+        assert_eq!(preferences.recent_files,
+                   vec!["file:///tmp/b.pcap", "file:///tmp/a%20b.pcap"]);
+        let mut saved = Vec::new();
+        preferences.save(&mut saved).unwrap();
+        assert_eq!(String::from_utf8(saved).unwrap(), text);
+        assert!(Preferences::load("recent".as_bytes()).is_err());
+
+        preferences.add_recent_file("file:///tmp/a%20b.pcap");
+        assert_eq!(preferences.recent_files,
+                   vec!["file:///tmp/a%20b.pcap", "file:///tmp/b.pcap"]);
+        for index in 0..20 {
+            preferences.add_recent_file(&format!("file:///tmp/{index}"));
+        }
+        assert_eq!(preferences.recent_files.len(), MAX_RECENT_FILES);
+        assert_eq!(preferences.recent_files[0], "file:///tmp/19");
+    }
+
+    #[test]
+    fn test_settings() {
+        let text = "# Packetry preferences\n\
+                    temp-dir /var/tmp/packetry\n\
+                    memory-limit 64\n\
+                    speed full\n\
+                    columns number result\n\
+                    summary brief\n\
+                    theme dark\n\
+                    recovery off\n\
+                    color red pid=stall\n";
+        let preferences = Preferences::load(text.as_bytes()).unwrap();
+        assert_eq!(preferences.temp_dir,
+                   Some(PathBuf::from("/var/tmp/packetry")));
+        assert_eq!(preferences.memory_limit, 64);
+        assert_eq!(preferences.speed, Some(Speed::Full));
+        assert_eq!(preferences.columns, vec!["number", "result"]);
+        assert_eq!(preferences.summary, Summary::Brief);
+        assert!(preferences.summary.hide_sof());
+        assert!(!preferences.summary.collapse_idle());
+        assert!(preferences.summary.collapse_polling());
+        assert_eq!(preferences.theme, Theme::Dark);
+        assert!(preferences.no_recovery);
+        let mut saved = Vec::new();
+        preferences.save(&mut saved).unwrap();
+        assert_eq!(String::from_utf8(saved).unwrap(), text);
+
+        let defaults = Preferences::load("".as_bytes()).unwrap();
+        assert_eq!(defaults.temp_dir, None);
+        assert_eq!(defaults.memory_limit, 0);
+        assert_eq!(defaults.speed, None);
+        assert!(defaults.columns.is_empty());
+        assert_eq!(defaults.summary, Summary::Full);
+        assert_eq!(defaults.theme, Theme::System);
+        assert!(!defaults.no_recovery);
+
+        for bad in ["temp-dir", "memory-limit", "memory-limit 1G",
+                    "speed fast", "columns number time",
+                    "summary none", "theme blue", "recovery no"]
+        {
+            assert!(Preferences::load(bad.as_bytes()).is_err());
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! The dialog in which the settings kept in the preferences are edited.
+
+use std::path::PathBuf;
+
+use anyhow::Error;
+use gtk::prelude::*;
+use gtk::{
+    Align,
+    ApplicationWindow,
+    CheckButton,
+    DropDown,
+    Entry,
+    Label,
+    Orientation,
+    ResponseType,
+};
+
+use crate::backend::Speed;
+use crate::preferences::{Preferences, Summary, Theme};
+use crate::ui::display_error;
+
+/// Create a dialog to edit the preferences, given the names and labels
+/// of the optional columns, which calls `apply` with the settings chosen.
+pub fn preferences_dialog<F>(parent: Option<&ApplicationWindow>,
+                             preferences: &Preferences,
+                             columns: &[(&'static str, &'static str)],
+                             apply: F)
+    -> gtk::Dialog
+    where F: Fn(Preferences) -> Result<(), Error> + 'static
+{
+    let dialog = gtk::Dialog::with_buttons(
+        Some("Preferences"),
+        parent,
+        gtk::DialogFlags::MODAL,
+        &[("Cancel", ResponseType::Cancel),
+          ("Apply", ResponseType::Accept)]);
+    dialog.set_default_response(ResponseType::Accept);
+    let grid = gtk::Grid::builder()
+        .row_spacing(5)
+        .column_spacing(10)
+        .margin_start(10)
+        .margin_end(10)
+        .margin_top(10)
+        .margin_bottom(10)
+        .build();
+    let add_row = |row: i32, text: &str, widget: &gtk::Widget| {
+        let label = Label::builder()
+            .label(text)
+            .halign(Align::Start)
+            .build();
+        grid.attach(&label, 0, row, 1, 1);
+        grid.attach(widget, 1, row, 1, 1);
+    };
+    let temp_entry = Entry::builder()
+        .placeholder_text("System default")
+        .tooltip_text("Directory in which to keep captured data")
+        .hexpand(true)
+        .build();
+    if let Some(dir) = &preferences.temp_dir {
+        temp_entry.set_text(&dir.display().to_string());
+    }
+    add_row(0, "Temporary files:", temp_entry.upcast_ref());
+    let speed_strings: Vec<&str> = std::iter::once("First offered")
+        .chain(Speed::ALL.iter().map(Speed::description))
+        .collect();
+    let speed_dropdown = DropDown::from_strings(&speed_strings);
+    let speed_index = preferences.speed
+        .and_then(|speed| Speed::ALL.iter().position(|s| *s == speed))
+        .map_or(0, |index| index + 1);
+    speed_dropdown.set_selected(speed_index as u32);
+    add_row(1, "Capture speed:", speed_dropdown.upcast_ref());
+    let column_box = gtk::Box::builder()
+        .orientation(Orientation::Horizontal)
+        .spacing(5)
+        .build();
+    let column_toggles: Vec<(&str, CheckButton)> = columns
+        .iter()
+        .map(|&(name, label)| {
+            let toggle = CheckButton::builder()
+                .label(label)
+                .active(preferences.columns.iter().any(|shown|
+                    shown == name))
+                .build();
+            column_box.append(&toggle);
+            (name, toggle)
+        })
+        .collect();
+    add_row(2, "Columns shown:", column_box.upcast_ref());
+    let summary_strings = Summary::ALL.map(|summary| summary.label());
+    let summary_dropdown = DropDown::from_strings(&summary_strings);
+    let summary_index = Summary::ALL
+        .iter()
+        .position(|summary| *summary == preferences.summary)
+        .unwrap_or(0);
+    summary_dropdown.set_selected(summary_index as u32);
+    add_row(3, "Traffic summary:", summary_dropdown.upcast_ref());
+    let theme_strings = Theme::ALL.map(|theme| theme.label());
+    let theme_dropdown = DropDown::from_strings(&theme_strings);
+    let theme_index = Theme::ALL
+        .iter()
+        .position(|theme| *theme == preferences.theme)
+        .unwrap_or(0);
+    theme_dropdown.set_selected(theme_index as u32);
+    add_row(4, "Theme:", theme_dropdown.upcast_ref());
+    let recovery_toggle = CheckButton::builder()
+        .label("Record live captures for recovery after a crash")
+        .active(!preferences.no_recovery)
+        .build();
+    add_row(5, "Recovery:", recovery_toggle.upcast_ref());
+    let colors_button = gtk::Button::builder()
+        .label("Edit color rules...")
+        .action_name("actions.color-rules")
+        .halign(Align::Start)
+        .build();
+    add_row(6, "Colors:", colors_button.upcast_ref());
+    dialog.content_area().append(&grid);
+    let preferences = preferences.clone();
+    dialog.connect_response(move |dialog, response| {
+        if response == ResponseType::Accept {
+            let mut chosen = preferences.clone();
+            let text = temp_entry.text();
+            chosen.temp_dir = (!text.is_empty())
+                .then(|| PathBuf::from(text.as_str()));
+            chosen.speed = (speed_dropdown.selected() as usize)
+                .checked_sub(1)
+                .and_then(|index| Speed::ALL.get(index).copied());
+            chosen.columns = column_toggles
+                .iter()
+                .filter(|(_, toggle)| toggle.is_active())
+                .map(|(name, _)| name.to_string())
+                .collect();
+            chosen.summary = Summary::ALL
+                .get(summary_dropdown.selected() as usize)
+                .copied()
+                .unwrap_or_default();
+            chosen.theme = Theme::ALL
+                .get(theme_dropdown.selected() as usize)
+                .copied()
+                .unwrap_or_default();
+            chosen.no_recovery = !recovery_toggle.is_active();
+            let result = apply(chosen);
+            if result.is_err() {
+                // Leave the dialog open to correct the settings.
+                display_error(result);
+                return;
+            }
+        }
+        dialog.destroy();
+    });
+    dialog
+}
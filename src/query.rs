@@ -0,0 +1,342 @@
+//! Queries for the transactions in a capture which meet some criteria.
+
+use std::ops::{Range, RangeInclusive};
+
+use anyhow::{Context, Error, bail};
+
+use crate::capture::{
+    CaptureReader,
+    Endpoint,
+    EndpointId,
+    ItemSource,
+    Timestamp,
+    TrafficItem,
+    TransferId,
+    FRAMING_EP_NUM,
+    INVALID_EP_NUM,
+};
+use crate::hex_text::parse_time;
+use crate::search::{payload, BytePattern};
+use crate::usb::{Direction, PID};
+
+/// Criteria which transactions must all meet to match a query.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Criteria {
+    /// Address of the device, if limited to one.
+    pub device: Option<u8>,
+    /// Endpoint number, if limited to one.
+    pub endpoint: Option<u8>,
+    /// Direction of the endpoints, if not both.
+    pub direction: Option<Direction>,
+    /// PIDs of which a packet must have one, or any if empty.
+    pub pids: Vec<PID>,
+    /// Handshakes of which the transaction must end with one, or any if
+    /// empty.
+    pub results: Vec<PID>,
+    /// Number of bytes of payload, if limited.
+    pub length: Option<RangeInclusive<u64>>,
+    /// Span of time which transactions must overlap, if limited.
+    pub time_range: Option<Range<Timestamp>>,
+    /// Pattern which a data packet must match, if any.
+    pub pattern: Option<BytePattern>,
+}
+
+impl Criteria {
+    /// Parse criteria from text.
+    pub fn parse(text: &str) -> Result<Criteria, Error> {
+        let mut criteria = Criteria::default();
+        for term in text.split_whitespace() {
+            let (key, value) = term
+                .split_once('=')
+                .with_context(|| format!("Invalid query term '{term}'"))?;
+            match key {
+                "device" => criteria.device = Some(value
+                    .parse()
+                    .ok()
+                    .filter(|address| *address <= 127)
+                    .with_context(|| format!(
+                        "Invalid device address '{value}', \
+                         expected a number from 0 to 127"))?),
+                "endpoint" => {
+                    let (number, direction) = parse_endpoint(value)?;
+                    criteria.endpoint = Some(number);
+                    criteria.direction = direction;
+                },
+                "pid" | "result" => {
+                    for name in value.split(',') {
+                        let pid = PID::from_name(name)
+                            .with_context(|| format!("Unknown PID '{name}'"))?;
+                        if key == "pid" {
+                            criteria.pids.push(pid);
+                        } else {
+                            criteria.results.push(pid);
+                        }
+                    }
+                },
+                "length" => criteria.length = Some(parse_length(value)?),
+                "time" => criteria.time_range = Some(parse_time_range(value)?),
+                "payload" =>
+                    criteria.pattern = Some(BytePattern::parse(value)?),
+                _ => bail!("Unknown query term '{key}', expected 'device', \
+                            'endpoint', 'pid', 'result', 'length', 'time' \
+                            or 'payload'"),
+            }
+        }
+        Ok(criteria)
+    }
+
+    /// Whether traffic on an endpoint can match these criteria.
+    fn matches_endpoint(&self, endpoint: &Endpoint) -> bool {
+        if self.device.is_none() &&
+            self.endpoint.is_none() &&
+            self.direction.is_none()
+        {
+            return true;
+        }
+        let number = endpoint.number();
+        if [INVALID_EP_NUM, FRAMING_EP_NUM].contains(&number) {
+            return false;
+        }
+        // Control transfers go both ways.
+        self.device.map_or(true, |address|
+                endpoint.device_address().0 == address) &&
+            self.endpoint.map_or(true, |wanted| number.0 == wanted) &&
+            self.direction.map_or(true, |direction|
+                number.0 == 0 || endpoint.direction() == direction)
+    }
+
+    /// Whether the packets of a transaction must be read to match it.
+    fn needs_packets(&self) -> bool {
+        !self.pids.is_empty() || self.length.is_some() || self.pattern.is_some()
+    }
+}
+
+/// Find the transactions in a capture which match some criteria, in the
+/// order they happened.
+pub fn query(capture: &mut CaptureReader, criteria: &Criteria)
+    -> Result<Vec<TrafficItem>, Error>
+{
+    let mut endpoint_matches = Vec::new();
+    let mut matches = Vec::new();
+    for id in 0..capture.transfer_index.len() {
+        let transfer_id = TransferId::from(id);
+        let entry = capture.transfer_index.get(transfer_id)?;
+        // Transfers interleaved with others have further entries where
+        // they continue, but all their transactions follow their starts.
+        if !entry.is_start() {
+            continue;
+        }
+        let index = entry.endpoint_id().value as usize;
+        while endpoint_matches.len() <= index {
+            let endpoint_id = EndpointId::from(endpoint_matches.len() as u64);
+            let endpoint = capture.endpoints.get(endpoint_id)?;
+            endpoint_matches.push(criteria.matches_endpoint(&endpoint));
+        }
+        if !endpoint_matches[index] {
+            continue;
+        }
+        let transfer = TrafficItem::Transfer(transfer_id);
+        if let Some(range) = &criteria.time_range {
+            let span = capture.item_time_range(&transfer)?;
+            if span.start >= range.end {
+                // Transfers are in order of their start times.
+                break;
+            }
+            if span.end <= range.start {
+                continue;
+            }
+        }
+        let (_, count) = capture.item_children(Some(&transfer))?;
+        for index in 0..count {
+            let item = capture.child_item(&transfer, index)?;
+            if let TrafficItem::Transaction(_, transaction_id) = item {
+                if transaction_matches(capture, criteria, &item)? {
+                    matches.push((transaction_id, item));
+                }
+            }
+        }
+    }
+    matches.sort_unstable_by_key(|(transaction_id, _)| *transaction_id);
+    Ok(matches.into_iter().map(|(_, item)| item).collect())
+}
+
+/// Whether a transaction, on an endpoint already matched, meets the rest
+/// of the criteria.
+fn transaction_matches(capture: &mut CaptureReader,
+                       criteria: &Criteria,
+                       item: &TrafficItem)
+    -> Result<bool, Error>
+{
+    let TrafficItem::Transaction(_, transaction_id) = item else {
+        bail!("Item is not a transaction");
+    };
+    let transaction = capture.transaction(*transaction_id)?;
+    if !criteria.results.is_empty() &&
+        !transaction.outcome()
+            .is_some_and(|pid| criteria.results.contains(&pid))
+    {
+        return Ok(false);
+    }
+    if let Some(range) = &criteria.time_range {
+        let span = capture.item_time_range(item)?;
+        if span.start >= range.end || span.end <= range.start {
+            return Ok(false);
+        }
+    }
+    if !criteria.needs_packets() {
+        return Ok(true);
+    }
+    let mut pid_found = criteria.pids.is_empty();
+    let mut pattern_found = criteria.pattern.is_none();
+    let mut length = 0;
+    for packet in capture.packet_range(&transaction.packet_id_range)? {
+        let packet = packet?;
+        if let Some(pid) = packet.first().map(PID::from) {
+            pid_found |= criteria.pids.contains(&pid);
+        }
+        if let Some(data) = payload(&packet) {
+            length += data.len() as u64;
+            pattern_found |= criteria.pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.find(data).is_some());
+        }
+    }
+    let length_in_range = criteria.length
+        .as_ref()
+        .map_or(true, |range| range.contains(&length));
+    Ok(pid_found && pattern_found && length_in_range)
+}
+
+/// Parse an endpoint number, optionally followed by its direction.
+pub fn parse_endpoint(text: &str)
+    -> Result<(u8, Option<Direction>), Error>
+{
+    let lower = text.to_ascii_lowercase();
+    let (number, direction) =
+        if let Some(number) = lower.strip_suffix("in") {
+            (number, Some(Direction::In))
+        } else if let Some(number) = lower.strip_suffix("out") {
+            (number, Some(Direction::Out))
+        } else {
+            (lower.as_str(), None)
+        };
+    let invalid = || format!(
+        "Invalid endpoint '{text}', expected a number from 0 to 15, \
+         optionally followed by 'in' or 'out'");
+    let number: u8 = number.trim().parse().with_context(invalid)?;
+    if number > 15 {
+        bail!(invalid());
+    }
+    Ok((number, direction))
+}
+
+/// Parse a span of time in seconds, either end of which may be left out.
+pub fn parse_time_range(text: &str) -> Result<Range<Timestamp>, Error> {
+    let (start, end) = text.split_once("..").with_context(||
+        format!("Invalid time range '{text}', expected START..END"))?;
+    let start = match start.trim() {
+        "" => 0,
+        start => parse_time(start)?,
+    };
+    let end = match end.trim() {
+        "" => Timestamp::MAX,
+        end => parse_time(end)?,
+    };
+    if end <= start {
+        bail!("The time range '{text}' is empty");
+    }
+    Ok(start..end)
+}
+
+/// Parse a number of bytes, or a range of them including both ends.
+fn parse_length(text: &str) -> Result<RangeInclusive<u64>, Error> {
+    let number = |text: &str, default: u64| match text.trim() {
+        "" => Ok(default),
+        text => text.parse::<u64>().with_context(||
+            format!("Invalid length '{text}'")),
+    };
+    let range = match text.split_once("..") {
+        Some((min, max)) => number(min, 0)?..=number(max, u64::MAX)?,
+        None => {
+            let length = number(text, 0)?;
+            length..=length
+        },
+    };
+    if range.is_empty() {
+        bail!("The length range '{text}' is empty");
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::load_capture;
+    use std::fs::File;
+
+    #[test]
+    fn test_parse() {
+        let criteria = Criteria::parse(
+            "device=4 endpoint=1in pid=IN,DATA1 result=ACK length=1..8 \
+             time=..2 payload=01??").unwrap();
+        assert_eq!(criteria.device, Some(4));
+        assert_eq!(criteria.endpoint, Some(1));
+        assert_eq!(criteria.direction, Some(Direction::In));
+        assert_eq!(criteria.pids, vec![PID::IN, PID::DATA1]);
+        assert_eq!(criteria.results, vec![PID::ACK]);
+        assert_eq!(criteria.length, Some(1..=8));
+        assert_eq!(criteria.time_range, Some(0..2_000_000_000));
+        assert!(criteria.pattern.is_some());
+        assert_eq!(parse_length("4").unwrap(), 4..=4);
+        assert_eq!(parse_length("4..").unwrap(), 4..=u64::MAX);
+        assert!(parse_length("8..4").is_err());
+        assert!(Criteria::parse("pid=FOO").is_err());
+        assert!(Criteria::parse("colour=red").is_err());
+        assert!(Criteria::parse("device").is_err());
+    }
+
+    #[test]
+    fn test_query() {
+        let file = File::open("./tests/mouse/capture.pcap").unwrap();
+        let mut capture = load_capture(file, None).unwrap();
+        let all = query(&mut capture, &Criteria::default()).unwrap();
+        assert_eq!(all.len() as u64, capture.transaction_index.len());
+        let transaction_ids: Vec<_> = all
+            .iter()
+            .map(|item| match item {
+                TrafficItem::Transaction(_, id) => id.value,
+                _ => panic!("Query found an item which is not a transaction"),
+            })
+            .collect();
+        assert!(transaction_ids.windows(2).all(|pair| pair[0] < pair[1]));
+        let criteria = Criteria::parse(
+            "device=4 endpoint=1in result=ACK length=1..").unwrap();
+        let reports = query(&mut capture, &criteria).unwrap();
+        assert!(!reports.is_empty());
+        for item in &reports {
+            let endpoint = capture.item_endpoint(item).unwrap().unwrap();
+            assert_eq!(endpoint.device_address().0, 4);
+            assert_eq!(endpoint.number().0, 1);
+            assert_eq!(capture.item_result(item).unwrap(), Some(PID::ACK));
+            assert!(capture.item_length(item).unwrap().unwrap() > 0);
+        }
+        let setups = query(&mut capture, &Criteria::parse(
+            "pid=SETUP payload=8006").unwrap()).unwrap();
+        assert!(!setups.is_empty());
+        assert!(setups.len() < all.len());
+        let middle = capture.item_time_range(&reports[reports.len() / 2])
+            .unwrap();
+        let later: Vec<TrafficItem> = reports
+            .iter()
+            .filter(|item| capture.item_time_range(item).unwrap().end >
+                           middle.start)
+            .copied()
+            .collect();
+        assert!(later.len() < reports.len());
+        let criteria = Criteria {
+            time_range: Some(middle.start..Timestamp::MAX),
+            ..criteria
+        };
+        assert_eq!(query(&mut capture, &criteria).unwrap(), later);
+    }
+}
@@ -0,0 +1,248 @@
+//! Recovery of live captures interrupted by a crash or power loss.
+//!
+//! The data of a capture is kept in anonymous temporary files, which are
+//! lost if Packetry exits unexpectedly. While capturing, the packets are
+//! also recorded to a pcapng file in a recovery directory, which is
+//! written out to storage every second and removed when the capture ends
+//! normally. The packets of each analyzer are recorded on their own
+//! interface of the file. A recording left behind can be opened like any
+//! capture file, and its packets decoded again.
+//!
+//! Each recording is locked while its capture is in progress, so that
+//! recordings in use by other instances of Packetry can be told apart
+//! from those left by captures that were interrupted.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Error};
+use fs2::FileExt;
+
+use crate::pcap::{FileFormat, Writer};
+
+/// Interval at which recorded packets are written out to storage.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Start of the names of recordings of captures in progress.
+const RECORDING_PREFIX: &str = "capture-";
+
+/// Start of the names of recordings kept after being found.
+const KEPT_PREFIX: &str = "recovered-";
+
+/// Extension of the names of recordings.
+const EXTENSION: &str = ".pcapng";
+
+/// Records the packets of a live capture, so that they can be recovered
+/// if the capture is interrupted.
+pub struct Recorder {
+    path: PathBuf,
+    /// Output shared with the thread that writes it out to storage.
+    output: Arc<Mutex<Output>>,
+    /// Sender which stops the sync thread when dropped.
+    stop: Option<Sender<()>>,
+    /// Thread which writes out recorded packets at intervals.
+    sync_thread: Option<JoinHandle<()>>,
+}
+
+/// The output of a recording.
+struct Output {
+    /// The locked file and the writer to it, until the recording ends.
+    file: Option<(File, Writer<File>)>,
+    /// Whether packets have been recorded since the last sync.
+    unsynced: bool,
+    /// Error that occurred while writing out the recording, if any.
+    error: Option<Error>,
+}
+
+impl Output {
+    /// Write out the packets recorded since the last sync.
+    fn sync(&mut self) -> Result<(), Error> {
+        if let Some((file, writer)) = &mut self.file {
+            if self.unsynced {
+                writer.flush()?;
+                file.sync_data().context("Failed to sync recovery file")?;
+                self.unsynced = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Recorder {
+    /// Start a new recording in a directory, creating it if needed.
+    pub fn create(dir: &Path) -> Result<Recorder, Error> {
+        fs::create_dir_all(dir).with_context(|| format!(
+            "Failed to create recovery directory {}", dir.display()))?;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos());
+        let name = format!(
+            "{RECORDING_PREFIX}{time}-{}{EXTENSION}", std::process::id());
+        let path = dir.join(name);
+        let file = File::create(&path).with_context(|| format!(
+            "Failed to create recovery file {}", path.display()))?;
+        file.try_lock_exclusive()
+            .context("Failed to lock recovery file")?;
+        let writer = Writer::open(file.try_clone()?, FileFormat::PcapNg)?;
+        let output = Arc::new(Mutex::new(Output {
+            file: Some((file, writer)),
+            unsynced: false,
+            error: None,
+        }));
+        // Write out recorded packets at intervals, even if no more arrive.
+        let (stop, stopped) = channel::<()>();
+        let sync_output = output.clone();
+        let sync_thread = spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(SYNC_INTERVAL)
+            {
+                let mut output = sync_output.lock().unwrap();
+                if let Err(e) = output.sync() {
+                    output.error = Some(e);
+                    break;
+                }
+            }
+        });
+        Ok(Recorder {
+            path,
+            output,
+            stop: Some(stop),
+            sync_thread: Some(sync_thread),
+        })
+    }
+
+    /// Record a packet from one of the analyzers in use.
+    ///
+    /// Returns any error that occurred in writing out earlier packets.
+    pub fn add_packet(&mut self, source: u8, bytes: &[u8], timestamp_ns: u64)
+        -> Result<(), Error>
+    {
+        let mut output = self.output.lock().unwrap();
+        if let Some(e) = output.error.take() {
+            return Err(e);
+        }
+        if let Some((_, writer)) = &mut output.file {
+            writer.add_packet_from(source as usize, bytes, timestamp_ns)?;
+            output.unsynced = true;
+        }
+        Ok(())
+    }
+}
+
+// When the capture ends, whether normally or with an error, its data is
+// still held by the application, so the recording is no longer needed.
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.sync_thread.take() {
+            thread.join().ok();
+        }
+        // The file must be closed before it can be removed on Windows.
+        self.output.lock().unwrap().file.take();
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove recovery file {}: {e}",
+                      self.path.display());
+        }
+    }
+}
+
+/// Find recordings left by captures that were interrupted.
+pub fn interrupted(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name|
+                name.starts_with(RECORDING_PREFIX) &&
+                name.ends_with(EXTENSION)))
+        // A recording that can be locked is no longer in use.
+        .filter(|path| File::open(path)
+            .is_ok_and(|file| file.try_lock_exclusive().is_ok()))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Keep a recording that was found, so that it is not found again.
+///
+/// Returns the new path of the recording.
+pub fn keep(path: &Path) -> Result<PathBuf, Error> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(RECORDING_PREFIX))
+        .with_context(|| format!(
+            "{} is not a recovery file", path.display()))?;
+    let new_path = path.with_file_name(format!("{KEPT_PREFIX}{name}"));
+    fs::rename(path, &new_path).with_context(|| format!(
+        "Failed to rename recovery file {}", path.display()))?;
+    Ok(new_path)
+}
+
+/// Discard a recording that was found.
+pub fn discard(path: &Path) -> Result<(), Error> {
+    fs::remove_file(path).with_context(|| format!(
+        "Failed to remove recovery file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::Loader;
+
+    #[test]
+    fn test_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut recorder = Recorder::create(dir.path()).unwrap();
+        recorder.add_packet(0, &[0xA5, 0x01, 0x10], 1000).unwrap();
+        recorder.add_packet(1, &[0x69, 0x82, 0x18], 2000).unwrap();
+        recorder.add_packet(0, &[0xD2], 3000).unwrap();
+        // A recording in progress is not found.
+        assert!(interrupted(dir.path()).is_empty());
+        // The packets are written out without waiting for more.
+        let path = recorder.path.clone();
+        std::thread::sleep(SYNC_INTERVAL * 2);
+        assert!(fs::metadata(&path).unwrap().len() > 0);
+        // Leave the recording behind, as if the capture was interrupted.
+        recorder.stop.take();
+        recorder.sync_thread.take().unwrap().join().unwrap();
+        let (_, writer) = recorder.output.lock().unwrap().file.take().unwrap();
+        writer.close().unwrap();
+        std::mem::forget(recorder);
+        assert_eq!(interrupted(dir.path()), vec![path.clone()]);
+
+        let kept = keep(&path).unwrap();
+        assert!(interrupted(dir.path()).is_empty());
+        let mut loader = Loader::open(File::open(&kept).unwrap()).unwrap();
+        let (packet, first_ns) = loader.next().unwrap().unwrap();
+        assert_eq!(&packet.data[..], &[0xA5, 0x01, 0x10]);
+        assert_eq!(packet.source, 0);
+        // The packet from the second analyzer is on its own interface.
+        let (packet, _) = loader.next().unwrap().unwrap();
+        assert_eq!(&packet.data[..], &[0x69, 0x82, 0x18]);
+        assert_eq!(packet.source, 1);
+        let (packet, third_ns) = loader.next().unwrap().unwrap();
+        assert_eq!(&packet.data[..], &[0xD2]);
+        assert_eq!(third_ns - first_ns, 2000);
+        assert!(loader.next().is_none());
+        drop(loader);
+        discard(&kept).unwrap();
+        assert!(!kept.exists());
+
+        // A recording is removed when its capture ends normally.
+        let recorder = Recorder::create(dir.path()).unwrap();
+        let path = recorder.path.clone();
+        assert!(path.exists());
+        drop(recorder);
+        assert!(!path.exists());
+    }
+}
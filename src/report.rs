@@ -0,0 +1,407 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::Error;
+
+use crate::capture::{CaptureReader, ItemSource, TrafficItem};
+use crate::preferences::Preferences;
+
+/// Number of bytes shown on each line of a hex dump.
+const HEX_DUMP_WIDTH: usize = 16;
+
+/// Style sheet embedded in HTML reports.
+const HTML_STYLE: &str = "\
+body { font-family: sans-serif; }
+details, div.item { margin-left: 1.5em; }
+summary { cursor: pointer; }
+pre { margin: 0.25em 0 0.25em 3em; color: #444; }
+";
+
+/// Size of the text in images of the traffic view, in pixels.
+pub const VIEW_FONT_SIZE: f64 = 12.0;
+
+/// Height of each row in images of the traffic view, in pixels.
+pub const VIEW_ROW_HEIGHT: f64 = 16.0;
+
+/// Width of each character of the monospace text in images of the
+/// traffic view, in pixels.
+pub const VIEW_CHAR_WIDTH: f64 = 7.2;
+
+/// Margin around images of the traffic view, in pixels.
+pub const VIEW_MARGIN: f64 = 10.0;
+
+/// Script embedded in HTML reports, to expand or collapse all items.
+const HTML_SCRIPT: &str = "\
+function expandAll(open) {
+  for (const item of document.querySelectorAll('details')) {
+    item.open = open;
+  }
+}
+";
+
+/// Write the traffic in a capture as a text report.
+///
+/// Every item is shown expanded, as it would appear in the Traffic pane,
+/// with the same connectors drawn alongside it. If `hex_dump` is set, the
+/// bytes of each packet are shown below it.
+///
+/// Returns the number of items written.
+pub fn write_traffic_report<Dest: Write>(capture: &mut CaptureReader,
+                                         hex_dump: bool,
+                                         dest: Dest)
+    -> Result<u64, Error>
+{
+    let mut dest = BufWriter::new(dest);
+    let mut count = 0;
+    let (_, item_count) = capture.item_children(None::<&TrafficItem>)?;
+    for index in 0..item_count {
+        let item = capture.item(None, index)?;
+        count += write_item(capture, &item, hex_dump, &mut dest)?;
+    }
+    dest.flush()?;
+    Ok(count)
+}
+
+/// Write the given top-level items of a capture, and all the items within
+/// them, as a text report in the same form as [write_traffic_report].
+///
+/// Returns the number of items written.
+pub fn write_items_report<Dest: Write>(capture: &mut CaptureReader,
+                                       items: &[TrafficItem],
+                                       hex_dump: bool,
+                                       dest: Dest)
+    -> Result<u64, Error>
+{
+    let mut dest = BufWriter::new(dest);
+    let mut count = 0;
+    for item in items {
+        count += write_item(capture, item, hex_dump, &mut dest)?;
+    }
+    dest.flush()?;
+    Ok(count)
+}
+
+/// Write an item and all its descendants.
+fn write_item(capture: &mut CaptureReader,
+              item: &TrafficItem,
+              hex_dump: bool,
+              dest: &mut dyn Write)
+    -> Result<u64, Error>
+{
+    let connectors = capture.connectors(item)?;
+    let description = capture.description(item, false)?;
+    writeln!(dest, "{connectors} {description}")?;
+    let mut count = 1;
+    if let (true, TrafficItem::Packet(.., packet_id)) = (hex_dump, item) {
+        // Continue the connectors down beside the dump.
+        let prefix = connectors
+            .replace('├', "│")
+            .replace(['└', '─'], " ");
+        let packet = capture.packet(*packet_id)?;
+        for line in hex_dump_lines(&packet) {
+            writeln!(dest, "{prefix}   {line}")?;
+        }
+    }
+    let (_, child_count) = capture.item_children(Some(item))?;
+    for index in 0..child_count {
+        let child = capture.child_item(item, index)?;
+        count += write_item(capture, &child, hex_dump, dest)?;
+    }
+    Ok(count)
+}
+
+/// Format the bytes of a packet as lines of a hex dump, each giving the
+/// offset, the bytes in hex, and the bytes as ASCII.
+fn hex_dump_lines(packet: &[u8]) -> impl Iterator<Item=String> + '_ {
+    packet.chunks(HEX_DUMP_WIDTH).enumerate().map(|(line, chunk)| {
+        let hex: Vec<String> = chunk
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| match byte {
+                0x20..=0x7E => byte as char,
+                _ => '.',
+            })
+            .collect();
+        format!("{:04X}  {:<width$}  {ascii}",
+                line * HEX_DUMP_WIDTH,
+                hex.join(" "),
+                width = HEX_DUMP_WIDTH * 3 - 1)
+    })
+}
+
+/// Write the traffic in a capture as a standalone HTML page.
+///
+/// Items are shown as a tree in which each can be expanded or collapsed,
+/// with the bytes of each packet below it. If `root` is given, only that
+/// item and its descendants are written.
+///
+/// Returns the number of items written.
+pub fn write_html_report<Dest: Write>(capture: &mut CaptureReader,
+                                      root: Option<&TrafficItem>,
+                                      title: &str,
+                                      dest: Dest)
+    -> Result<u64, Error>
+{
+    let mut dest = BufWriter::new(dest);
+    let title = escape_html(title);
+    writeln!(dest, "<!DOCTYPE html>")?;
+    writeln!(dest, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+    writeln!(dest, "<title>{title}</title>")?;
+    writeln!(dest, "<style>\n{HTML_STYLE}</style>")?;
+    writeln!(dest, "<script>\n{HTML_SCRIPT}</script>")?;
+    writeln!(dest, "</head>\n<body>\n<h1>{title}</h1>")?;
+    writeln!(dest, "<p><button onclick=\"expandAll(true)\">Expand all\
+                    </button> <button onclick=\"expandAll(false)\">\
+                    Collapse all</button></p>")?;
+    let count = match root {
+        Some(item) => write_html_item(capture, item, &mut dest)?,
+        None => {
+            let mut count = 0;
+            let (_, item_count) =
+                capture.item_children(None::<&TrafficItem>)?;
+            for index in 0..item_count {
+                let item = capture.item(None, index)?;
+                count += write_html_item(capture, &item, &mut dest)?;
+            }
+            count
+        }
+    };
+    writeln!(dest, "</body>\n</html>")?;
+    dest.flush()?;
+    Ok(count)
+}
+
+/// Write an item and all its descendants as HTML.
+fn write_html_item(capture: &mut CaptureReader,
+                   item: &TrafficItem,
+                   dest: &mut dyn Write)
+    -> Result<u64, Error>
+{
+    let description = escape_html(&capture.description(item, false)?);
+    let (_, child_count) = capture.item_children(Some(item))?;
+    let packet_id = match item {
+        TrafficItem::Packet(.., packet_id) => Some(*packet_id),
+        _ => None,
+    };
+    if child_count == 0 && packet_id.is_none() {
+        writeln!(dest, "<div class=\"item\">{description}</div>")?;
+        return Ok(1);
+    }
+    writeln!(dest, "<details><summary>{description}</summary>")?;
+    if let Some(packet_id) = packet_id {
+        let packet = capture.packet(packet_id)?;
+        writeln!(dest, "<pre>")?;
+        for line in hex_dump_lines(&packet) {
+            writeln!(dest, "{}", escape_html(&line))?;
+        }
+        writeln!(dest, "</pre>")?;
+    }
+    let mut count = 1;
+    for index in 0..child_count {
+        let child = capture.child_item(item, index)?;
+        count += write_html_item(capture, &child, dest)?;
+    }
+    writeln!(dest, "</details>")?;
+    Ok(count)
+}
+
+/// A row of the traffic view, as drawn in an image or printed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ViewRow {
+    /// The connectors and description of the item.
+    pub text: String,
+    /// Color of the text, if the item matches a color rule.
+    pub color: Option<String>,
+}
+
+/// The rows showing some items of the traffic view, in their colors. If
+/// `expand` is set, each item is followed by all its descendants.
+pub fn view_rows(capture: &mut CaptureReader,
+                 items: &[TrafficItem],
+                 expand: bool,
+                 preferences: &Preferences)
+    -> Result<Vec<ViewRow>, Error>
+{
+    let mut rows = Vec::new();
+    let mut stack: Vec<TrafficItem> = items.iter().rev().copied().collect();
+    while let Some(item) = stack.pop() {
+        let connectors = capture.connectors(&item)?;
+        let description = capture.description(&item, false)?;
+        let color = preferences
+            .color_rule(capture, &item)?
+            .map(|index| preferences.color_rules[index].color.clone());
+        let text = format!("{connectors} {description}");
+        rows.push(ViewRow { text, color });
+        if expand {
+            let (_, child_count) = capture.item_children(Some(&item))?;
+            for index in (0..child_count).rev() {
+                stack.push(capture.child_item(&item, index)?);
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// Width and height of an image of rows of the traffic view, in pixels.
+pub fn view_size(rows: &[ViewRow]) -> (f64, f64) {
+    let columns = rows
+        .iter()
+        .map(|row| row.text.chars().count())
+        .max()
+        .unwrap_or(0);
+    (columns as f64 * VIEW_CHAR_WIDTH + 2.0 * VIEW_MARGIN,
+     rows.len() as f64 * VIEW_ROW_HEIGHT + 2.0 * VIEW_MARGIN)
+}
+
+/// Write rows of the traffic view as an SVG image.
+pub fn write_svg_image<Dest: Write>(rows: &[ViewRow], dest: Dest)
+    -> Result<(), Error>
+{
+    let mut dest = BufWriter::new(dest);
+    let (width, height) = view_size(rows);
+    writeln!(dest, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(dest, "<svg xmlns=\"http://www.w3.org/2000/svg\" \
+                    width=\"{width}\" height=\"{height}\">")?;
+    writeln!(dest, "<rect width=\"100%\" height=\"100%\" fill=\"white\"/>")?;
+    writeln!(dest, "<g font-family=\"monospace\" \
+                    font-size=\"{VIEW_FONT_SIZE}\" \
+                    xml:space=\"preserve\">")?;
+    for (index, row) in rows.iter().enumerate() {
+        let y = VIEW_MARGIN + (index as f64 + 0.75) * VIEW_ROW_HEIGHT;
+        let fill = row.color.as_deref().unwrap_or("black");
+        writeln!(dest, "<text x=\"{VIEW_MARGIN}\" y=\"{y}\" \
+                        fill=\"{}\">{}</text>",
+                 escape_html(fill), escape_html(&row.text))?;
+    }
+    writeln!(dest, "</g>\n</svg>")?;
+    dest.flush()?;
+    Ok(())
+}
+
+/// Escape text for inclusion in HTML.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::load_test_capture;
+
+    #[test]
+    fn test_traffic_report() {
+        let mut reader = load_test_capture("split-poll");
+        let mut report = Vec::new();
+        let count = write_traffic_report(&mut reader, false, &mut report)
+            .unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert_eq!(report.lines().count() as u64, count);
+        let mut dump = Vec::new();
+        write_traffic_report(&mut reader, true, &mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        let lines: Vec<&str> = dump.lines().take(6).collect();
+        assert_eq!(lines[0], "  ○── Polling 4 times for interrupt transfer \
+                              on endpoint 14.1 IN");
+        assert_eq!(lines[1], "  ├──── Starting IN transaction on 14.1");
+        assert_eq!(lines[2], "  │     ├── SPLIT packet starting low speed \
+                              interrupt transaction on hub 12 port 2");
+        assert!(lines[3].starts_with("  │     │     0000  78 0C 82 3E  "));
+        assert!(lines[3].ends_with("  x..>"));
+        assert_eq!(lines[4], "  │     └── IN packet on 14.1, CRC 0A");
+        assert!(lines[5].starts_with("  │           0000  69 8E 50  "));
+        assert!(lines[5].ends_with("  i.P"));
+        // The report without hex dumps has the same item lines.
+        let items: Vec<&str> = dump
+            .lines()
+            .filter(|line| !line.contains("  0000  "))
+            .collect();
+        assert_eq!(items, report.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_view_image() {
+        let mut reader = load_test_capture("split-poll");
+        let mut text = Vec::new();
+        write_traffic_report(&mut reader, false, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let item: TrafficItem = reader.item(None, 0).unwrap();
+        let preferences = Preferences::load(
+            "color red pid=IN".as_bytes()).unwrap();
+
+        // Expanded, the rows are those of the text report.
+        let rows = view_rows(&mut reader, &[item], true, &preferences)
+            .unwrap();
+        assert!(rows.len() > 1);
+        for (row, line) in rows.iter().zip(text.lines()) {
+            assert_eq!(row.text, line);
+        }
+        assert!(rows.iter().any(|row| row.color.as_deref() == Some("red")));
+        assert!(rows.iter().any(|row| row.color.is_none()));
+        let collapsed = view_rows(&mut reader, &[item], false, &preferences)
+            .unwrap();
+        assert_eq!(collapsed, rows[..1]);
+
+        let (width, height) = view_size(&rows);
+        assert_eq!(height,
+                   rows.len() as f64 * VIEW_ROW_HEIGHT + 2.0 * VIEW_MARGIN);
+        let longest = rows
+            .iter()
+            .map(|row| row.text.chars().count())
+            .max()
+            .unwrap();
+        assert_eq!(width,
+                   longest as f64 * VIEW_CHAR_WIDTH + 2.0 * VIEW_MARGIN);
+        let mut svg = Vec::new();
+        write_svg_image(&rows, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.starts_with("<?xml"));
+        assert_eq!(svg.matches("<text ").count(), rows.len());
+        assert!(svg.contains("fill=\"red\""));
+        assert!(svg.contains("○── Polling 4 times"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+    }
+
+    #[test]
+    fn test_html_report() {
+        let mut reader = load_test_capture("split-poll");
+        let mut text = Vec::new();
+        let text_count = write_traffic_report(&mut reader, false, &mut text)
+            .unwrap();
+        let mut html = Vec::new();
+        let count = write_html_report(
+            &mut reader, None, "split <poll>", &mut html).unwrap();
+        assert_eq!(count, text_count);
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>split &lt;poll&gt;</title>"));
+        assert!(html.contains("<details><summary>Polling 4 times for \
+                               interrupt transfer on endpoint 14.1 IN\
+                               </summary>"));
+        assert!(html.contains("<pre>\n0000  78 0C 82 3E  "));
+        assert_eq!(html.matches("<details>").count(),
+                   html.matches("</details>").count());
+
+        // Only the chosen item and its descendants are written.
+        let item: TrafficItem = reader.item(None, 0).unwrap();
+        let mut html = Vec::new();
+        let count = write_html_report(
+            &mut reader, Some(&item), "item", &mut html).unwrap();
+        assert!(count > 1 && count < text_count);
+        let html = String::from_utf8(html).unwrap();
+        assert_eq!(html.matches("<details>").count() +
+                   html.matches("<div class=\"item\">").count(),
+                   count as usize);
+    }
+}
@@ -0,0 +1,290 @@
+//! A JSON-RPC server, through which other programs can control the
+//! application over WebSocket.
+
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Error, bail};
+use serde_json::{json, Value};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::{accept_hdr, Message, WebSocket};
+
+/// How often a connection waiting for requests checks for events.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const METHOD_FAILED: i64 = -32000;
+
+/// Handles a method call, or returns None if there is no such method.
+pub type Handler =
+    dyn Fn(&str, Value) -> Option<Result<Value, Error>> + Send + Sync;
+
+static SUBSCRIBERS: OnceLock<Mutex<Vec<Sender<String>>>> = OnceLock::new();
+
+/// Start the server on an address, with a handler for its methods.
+///
+/// Any program which can connect to the server can control the
+/// application, so only loopback addresses are accepted unless
+/// `allow_remote` is set.
+pub fn start(address: &str, allow_remote: bool, handler: Arc<Handler>)
+    -> Result<SocketAddr, Error>
+{
+    let addresses: Vec<SocketAddr> = address
+        .to_socket_addrs()
+        .with_context(|| format!("Invalid address {address}"))?
+        .collect();
+    if !allow_remote && !addresses.iter().all(|addr| addr.ip().is_loopback())
+    {
+        bail!("Refusing to listen on {address}, which is not a loopback \
+               address. Use --control-remote to allow this.");
+    }
+    let listener = TcpListener::bind(&addresses[..])
+        .with_context(|| format!("Failed to listen on {address}"))?;
+    let local_address = listener.local_addr()?;
+    SUBSCRIBERS.get_or_init(Default::default);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let handler = handler.clone();
+            match stream {
+                Ok(stream) => { thread::spawn(move || {
+                    if let Err(e) = serve(stream, handler.as_ref()) {
+                        eprintln!("Control connection failed: {e:#}");
+                    }
+                }); },
+                Err(e) => eprintln!("Failed to accept connection: {e}"),
+            }
+        }
+    });
+    Ok(local_address)
+}
+
+/// A destination for events, which sends them to subscribed clients, if
+/// the server was started.
+pub fn event_sink() -> Option<Box<dyn Write + Send>> {
+    SUBSCRIBERS.get().map(|_| {
+        Box::new(EventSink::default()) as Box<dyn Write + Send>
+    })
+}
+
+/// Refuse connections made by web pages.
+///
+/// Browsers send an Origin header with every WebSocket handshake, so that
+/// a page cannot use the server from the user's browser. Other clients do
+/// not need to send one.
+#[allow(clippy::result_large_err)]
+fn check_origin(request: &Request, response: Response)
+    -> Result<Response, ErrorResponse>
+{
+    if request.headers().contains_key("Origin") {
+        let mut refusal = ErrorResponse::new(
+            Some("Connections from web pages are not accepted".to_string()));
+        *refusal.status_mut() = StatusCode::FORBIDDEN;
+        return Err(refusal);
+    }
+    Ok(response)
+}
+
+/// Serve one client until it disconnects.
+fn serve(stream: TcpStream, handler: &Handler) -> Result<(), Error> {
+    let mut socket = accept_hdr(stream, check_origin)
+        .map_err(|e| Error::msg(e.to_string()))
+        .context("WebSocket handshake failed")?;
+    socket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut events: Option<Receiver<String>> = None;
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                if let Some(reply) = respond(handler, &text, &mut events) {
+                    socket.send(Message::Text(reply))?;
+                }
+            },
+            Ok(Message::Close(_)) => return finish(&mut socket),
+            Ok(_) => {},
+            Err(tungstenite::Error::Io(e)) if matches!(
+                e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+            Err(tungstenite::Error::ConnectionClosed |
+                tungstenite::Error::AlreadyClosed) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(receiver) = &events {
+            while let Ok(notification) = receiver.try_recv() {
+                socket.send(Message::Text(notification))?;
+            }
+        }
+    }
+}
+
+/// Complete the closing handshake started by the client.
+fn finish(socket: &mut WebSocket<TcpStream>) -> Result<(), Error> {
+    match socket.flush() {
+        Ok(()) |
+        Err(tungstenite::Error::ConnectionClosed) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The reply to a request, unless it was a notification.
+fn respond(handler: &Handler,
+           text: &str,
+           events: &mut Option<Receiver<String>>)
+    -> Option<String>
+{
+    let request: Value = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => return Some(error(Value::Null, PARSE_ERROR, e)),
+    };
+    let id = request.get("id").cloned();
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return Some(error(id.unwrap_or(Value::Null), INVALID_REQUEST,
+                          "Request has no method"));
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    let result = match method {
+        "subscribe" => Ok(subscribe(events)),
+        _ => match handler(method, params) {
+            Some(result) => result.map_err(|e| (METHOD_FAILED, e)),
+            None => Err((METHOD_NOT_FOUND,
+                         Error::msg(format!("Unknown method '{method}'")))),
+        },
+    };
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result})
+            .to_string(),
+        Err((code, e)) => error(id, code, format!("{e:#}")),
+    })
+}
+
+/// Start sending events to a client.
+fn subscribe(events: &mut Option<Receiver<String>>) -> Value {
+    if events.is_none() {
+        let (sender, receiver) = channel();
+        if let Some(subscribers) = SUBSCRIBERS.get() {
+            subscribers.lock().unwrap().push(sender);
+        }
+        *events = Some(receiver);
+    }
+    Value::Bool(true)
+}
+
+fn error(id: Value, code: i64, message: impl ToString) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": code, "message": message.to_string()},
+    }).to_string()
+}
+
+/// Receives events as lines of JSON, and sends each to the subscribers.
+#[derive(Default)]
+struct EventSink {
+    line: Vec<u8>,
+}
+
+impl Write for EventSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &byte in buf {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let event = serde_json::from_slice::<Value>(&self.line);
+            self.line.clear();
+            // Each line is an event written by an EventStream.
+            let Ok(event) = event else {
+                continue;
+            };
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "event",
+                "params": event,
+            }).to_string();
+            if let Some(subscribers) = SUBSCRIBERS.get() {
+                // Clients which have disconnected are dropped.
+                subscribers
+                    .lock()
+                    .unwrap()
+                    .retain(|sender| sender.send(notification.clone()).is_ok());
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::connect;
+
+    #[test]
+    fn test_server() {
+        let handler: Arc<Handler> = Arc::new(|method, params| {
+            match method {
+                "add" => Some(params
+                    .as_array()
+                    .and_then(|values| values
+                        .iter()
+                        .map(Value::as_i64)
+                        .sum::<Option<i64>>())
+                    .map(Value::from)
+                    .context("Expected an array of numbers")),
+                _ => None,
+            }
+        });
+        assert!(start("0.0.0.0:0", false, handler.clone()).is_err());
+        let address = start("127.0.0.1:0", false, handler).unwrap();
+        // Web pages may not connect.
+        let mut request =
+            format!("ws://{address}").into_client_request().unwrap();
+        request.headers_mut().insert(
+            "Origin", "https://example.com".parse().unwrap());
+        assert!(connect(request).is_err());
+        let (mut socket, _) = connect(format!("ws://{address}")).unwrap();
+        let mut call = |request: Value| {
+            socket.send(Message::Text(request.to_string())).unwrap();
+            loop {
+                if let Message::Text(text) = socket.read().unwrap() {
+                    break serde_json::from_str::<Value>(&text).unwrap();
+                }
+            }
+        };
+        let reply = call(json!({
+            "jsonrpc": "2.0", "id": 1, "method": "add", "params": [2, 3]}));
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["result"], 5);
+        let reply = call(json!({
+            "jsonrpc": "2.0", "id": 2, "method": "add", "params": "x"}));
+        assert_eq!(reply["error"]["code"], METHOD_FAILED);
+        let reply = call(json!({"jsonrpc": "2.0", "id": 3, "method": "x"}));
+        assert_eq!(reply["error"]["code"], METHOD_NOT_FOUND);
+        let reply = call(json!({"jsonrpc": "2.0", "id": 4}));
+        assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+        let reply = call(json!(
+            {"jsonrpc": "2.0", "id": 5, "method": "subscribe"}));
+        assert_eq!(reply["result"], true);
+        let mut sink = event_sink().unwrap();
+        sink.write_all(b"{\"event\":\"note\",\"text\":\"Hi\"}\n").unwrap();
+        let notification = loop {
+            if let Message::Text(text) = socket.read().unwrap() {
+                break serde_json::from_str::<Value>(&text).unwrap();
+            }
+        };
+        assert_eq!(notification["method"], "event");
+        assert_eq!(notification["params"]["text"], "Hi");
+        assert!(notification.get("id").is_none());
+        socket.close(None).unwrap();
+    }
+}
@@ -0,0 +1,618 @@
+//! Search of the traffic in a capture, for text or payload bytes, or for
+//! a place given by number or time.
+
+use crate::capture::{
+    CaptureReader,
+    ItemSource,
+    PacketId,
+    Timestamp,
+    TrafficItem,
+    TransactionId,
+};
+use crate::hex_text::parse_time;
+use crate::usb::{PacketFields, PID};
+
+use anyhow::{Context, Error, bail};
+use regex::Regex;
+
+/// Number of packets read at a time when searching backwards.
+const BACKWARD_CHUNK: u64 = 4096;
+
+/// A pattern of bytes to search for, with a mask of the bits to compare.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BytePattern {
+    bytes: Vec<u8>,
+    mask: Vec<u8>,
+}
+
+impl BytePattern {
+    /// Parse a pattern from quoted text or masked hex.
+    pub fn parse(text: &str) -> Result<BytePattern, Error> {
+        let text = text.trim();
+        let pattern = match text.strip_prefix('"') {
+            Some(quoted) => {
+                let Some(literal) = quoted.strip_suffix('"') else {
+                    bail!("Missing closing quote in search pattern")
+                };
+                let bytes = literal.as_bytes().to_vec();
+                let mask = vec![0xFF; bytes.len()];
+                BytePattern { bytes, mask }
+            },
+            None => {
+                let (hex, mask_hex) = match text.split_once('/') {
+                    Some((hex, mask_hex)) => (hex, Some(mask_hex)),
+                    None => (text, None),
+                };
+                let (bytes, mut mask) = parse_hex(hex, true)?;
+                if let Some(mask_hex) = mask_hex {
+                    let (extra, _) = parse_hex(mask_hex, false)?;
+                    if extra.len() != bytes.len() {
+                        bail!("The mask must be the same length as the bytes");
+                    }
+                    for (mask, extra) in mask.iter_mut().zip(extra) {
+                        *mask &= extra;
+                    }
+                }
+                let bytes = bytes
+                    .iter()
+                    .zip(&mask)
+                    .map(|(byte, mask)| byte & mask)
+                    .collect();
+                BytePattern { bytes, mask }
+            }
+        };
+        if pattern.bytes.is_empty() {
+            bail!("Empty search pattern");
+        }
+        Ok(pattern)
+    }
+
+    /// Offset of the first match of the pattern in some data.
+    pub fn find(&self, data: &[u8]) -> Option<usize> {
+        data.windows(self.bytes.len()).position(|window|
+            window
+                .iter()
+                .zip(&self.bytes)
+                .zip(&self.mask)
+                .all(|((data, byte), mask)| data & mask == *byte))
+    }
+}
+
+/// Parse hex digits into bytes and a mask, optionally allowing `?`.
+fn parse_hex(text: &str, wildcards: bool)
+    -> Result<(Vec<u8>, Vec<u8>), Error>
+{
+    let digits: Vec<char> = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    if digits.len() % 2 != 0 {
+        bail!("Odd number of hex digits in search pattern");
+    }
+    let mut bytes = Vec::with_capacity(digits.len() / 2);
+    let mut mask = Vec::with_capacity(digits.len() / 2);
+    for pair in digits.chunks(2) {
+        let (mut byte, mut bits) = (0, 0);
+        for &digit in pair {
+            let (value, nibble_mask) = match digit {
+                '?' if wildcards => (0, 0),
+                _ => (digit.to_digit(16).with_context(||
+                        format!("Invalid hex digit '{digit}'"))? as u8, 0xF),
+            };
+            byte = (byte << 4) | value;
+            bits = (bits << 4) | nibble_mask;
+        }
+        bytes.push(byte);
+        mask.push(bits);
+    }
+    Ok((bytes, mask))
+}
+
+/// The payload of a data packet, without its PID and CRC.
+pub fn payload(packet: &[u8]) -> Option<&[u8]> {
+    use PID::*;
+    match packet.first().map(|&byte| PID::from(byte)) {
+        Some(DATA0 | DATA1 | DATA2 | MDATA) if packet.len() >= 3 =>
+            Some(&packet[1..packet.len() - 2]),
+        _ => None
+    }
+}
+
+/// Find the next data packet whose payload matches a pattern.
+///
+/// The search starts after the given packet, or from the start of the
+/// capture if none is given. When searching backwards, it starts before
+/// the given packet, or from the end of the capture.
+pub fn find_packet(capture: &mut CaptureReader,
+                   pattern: &BytePattern,
+                   from: Option<PacketId>,
+                   forward: bool)
+    -> Result<Option<PacketId>, Error>
+{
+    find_packet_where(capture, from, forward, |packet|
+        payload(packet).is_some_and(|data| pattern.find(data).is_some()))
+}
+
+/// Find the next packet for which a predicate holds, in the same way
+/// as find_packet.
+fn find_packet_where<F>(capture: &mut CaptureReader,
+                        from: Option<PacketId>,
+                        forward: bool,
+                        mut predicate: F)
+    -> Result<Option<PacketId>, Error>
+    where F: FnMut(&[u8]) -> bool
+{
+    let packet_count = capture.packet_index.len();
+    if forward {
+        let start = from.map_or(0, |id| id.value + 1).min(packet_count);
+        let range = PacketId::from(start)..PacketId::from(packet_count);
+        for (id, packet) in (start..).zip(capture.packet_range(&range)?) {
+            if predicate(&packet?) {
+                return Ok(Some(PacketId::from(id)));
+            }
+        }
+    } else {
+        let mut end = from.map_or(packet_count, |id| id.value)
+            .min(packet_count);
+        while end > 0 {
+            let start = end.saturating_sub(BACKWARD_CHUNK);
+            let range = PacketId::from(start)..PacketId::from(end);
+            let mut found = None;
+            for (id, packet) in (start..).zip(capture.packet_range(&range)?) {
+                if predicate(&packet?) {
+                    found = Some(PacketId::from(id));
+                }
+            }
+            if found.is_some() {
+                return Ok(found);
+            }
+            end = start;
+        }
+    }
+    Ok(None)
+}
+
+/// A place in a capture to go to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Packet(PacketId),
+    Transaction(TransactionId),
+    /// An SOF frame number.
+    Frame(u16),
+    Time(Timestamp),
+}
+
+impl Target {
+    /// Parse a target, given as its kind followed by a number. A number
+    /// alone is taken to be a packet number.
+    pub fn parse(text: &str) -> Result<Target, Error> {
+        let text = text.trim();
+        let (kind, value) = match text.split_once(char::is_whitespace) {
+            Some((kind, value)) => (kind.to_lowercase(), value.trim()),
+            None => ("packet".to_string(), text),
+        };
+        let value = value.trim_start_matches('#');
+        let invalid = || format!("Invalid {kind} number '{value}'");
+        // Packets and transactions are numbered from 1 when shown.
+        let index = || -> Result<u64, Error> {
+            let number: u64 = value.parse().with_context(invalid)?;
+            number.checked_sub(1).with_context(invalid)
+        };
+        Ok(match kind.as_str() {
+            "packet" => Target::Packet(PacketId::from(index()?)),
+            "transaction" =>
+                Target::Transaction(TransactionId::from(index()?)),
+            "frame" => {
+                let frame: u16 = value.parse().with_context(invalid)?;
+                if frame > 0x7FF {
+                    bail!("The frame number must be no more than 2047");
+                }
+                Target::Frame(frame)
+            },
+            "time" => Target::Time(parse_time(value.trim_end_matches('s'))?),
+            _ => bail!("Unknown place '{kind}', expected 'packet', \
+                        'transaction', 'frame' or 'time'"),
+        })
+    }
+}
+
+/// Find the item to go to for a target.
+///
+/// A frame number is searched for after the given packet, continuing
+/// from the start of the capture if it is not found.
+pub fn target_item(capture: &mut CaptureReader,
+                   target: Target,
+                   from: Option<PacketId>)
+    -> Result<TrafficItem, Error>
+{
+    let packet_id = match target {
+        Target::Packet(packet_id) => {
+            let count = capture.packet_index.len();
+            if packet_id.value >= count {
+                bail!("Packet number must be no more than {count}");
+            }
+            packet_id
+        },
+        Target::Transaction(transaction_id) => {
+            let count = capture.transaction_index.len();
+            if transaction_id.value >= count {
+                bail!("Transaction number must be no more than {count}");
+            }
+            let packet_id = capture.transaction_index.get(transaction_id)?;
+            let TrafficItem::Packet(transfer_id, transaction_id, _) =
+                capture.packet_item(packet_id)?
+            else {
+                bail!("Packet {packet_id} is not in a transaction");
+            };
+            return Ok(TrafficItem::Transaction(transfer_id, transaction_id));
+        },
+        Target::Frame(frame) => {
+            let is_frame = |packet: &[u8]| packet.len() == 3 &&
+                matches!(PacketFields::from_packet(packet),
+                         PacketFields::SOF(sof) if sof.frame_number() == frame);
+            match find_packet_where(capture, from, true, is_frame)? {
+                Some(packet_id) => packet_id,
+                None => find_packet_where(capture, None, true, is_frame)?
+                    .with_context(|| format!("No SOF for frame {frame}"))?,
+            }
+        },
+        Target::Time(time) => {
+            let packet_id = capture.packet_at_time(time)?;
+            if packet_id.value >= capture.packet_index.len() {
+                bail!("No packets at or after that time");
+            }
+            packet_id
+        },
+    };
+    capture.packet_item(packet_id)
+}
+
+/// A pattern to search for in the summaries of items.
+pub enum TextPattern {
+    /// Text to find, ignoring case.
+    Text(String),
+    /// A regular expression to match.
+    Regex(Regex),
+}
+
+impl TextPattern {
+    /// Create a pattern from text, or from a regular expression.
+    pub fn new(text: &str, regex: bool) -> Result<TextPattern, Error> {
+        if text.is_empty() {
+            bail!("Empty search text");
+        }
+        Ok(if regex {
+            TextPattern::Regex(Regex::new(text)?)
+        } else {
+            TextPattern::Text(text.to_lowercase())
+        })
+    }
+
+    /// Whether the pattern is found in some text.
+    pub fn matches(&self, text: &str) -> bool {
+        match self {
+            TextPattern::Text(lower) => text.to_lowercase().contains(lower),
+            TextPattern::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+/// Position of a transfer or transaction in the traffic view, as the
+/// index of its top-level item and the index of the transaction.
+type SummaryPosition = (u64, Option<u64>);
+
+/// Find the next transfer or transaction whose summary matches a pattern.
+///
+/// The items are searched in the order shown by the traffic view, using
+/// the capture's filter. The search starts after the item at the given
+/// path, or from the start if none is given, or when searching backwards,
+/// before that item or from the end. Returns the path to the item found.
+pub fn find_item(capture: &mut CaptureReader,
+                 pattern: &TextPattern,
+                 from: Option<&[u64]>,
+                 forward: bool)
+    -> Result<Option<Vec<u64>>, Error>
+{
+    find_item_where(capture, from, forward, |capture, item|
+        Ok(pattern.matches(&capture.description(item, false)?)))
+}
+
+/// Find the next transaction or marker flagged as an error, such as a
+/// malformed packet, a STALL, a timeout or a loss of packets, in the same
+/// way as find_item.
+pub fn find_error(capture: &mut CaptureReader,
+                  from: Option<&[u64]>,
+                  forward: bool)
+    -> Result<Option<Vec<u64>>, Error>
+{
+    find_item_where(capture, from, forward, CaptureReader::item_is_error)
+}
+
+/// Find the next transfer or transaction for which a predicate holds, in
+/// the same way as find_item.
+fn find_item_where<F>(capture: &mut CaptureReader,
+                      from: Option<&[u64]>,
+                      forward: bool,
+                      mut predicate: F)
+    -> Result<Option<Vec<u64>>, Error>
+    where F: FnMut(&mut CaptureReader, &TrafficItem) -> Result<bool, Error>
+{
+    let mut position = from.map(|path| (path[0], path.get(1).copied()));
+    loop {
+        position = match step(capture, position, forward)? {
+            Some(position) => Some(position),
+            None => return Ok(None),
+        };
+        let (index, transaction) = position.unwrap();
+        let mut item: TrafficItem = capture.item(None, index)?;
+        if let Some(transaction_index) = transaction {
+            item = capture.item(Some(&item), transaction_index)?;
+        }
+        if predicate(capture, &item)? {
+            return Ok(Some(match transaction {
+                Some(transaction_index) => vec![index, transaction_index],
+                None => vec![index],
+            }));
+        }
+    }
+}
+
+/// The next transfer or transaction position, in either direction.
+fn step(capture: &mut CaptureReader,
+        position: Option<SummaryPosition>,
+        forward: bool)
+    -> Result<Option<SummaryPosition>, Error>
+{
+    let (_, root_count) = capture.item_children(None::<&TrafficItem>)?;
+    // Number of transactions shown below a top-level item.
+    let mut transactions = |index: u64| -> Result<u64, Error> {
+        let item: TrafficItem = capture.item(None, index)?;
+        Ok(match item {
+            TrafficItem::Transfer(_) => capture.item_children(Some(&item))?.1,
+            _ => 0,
+        })
+    };
+    Ok(match (position, forward) {
+        (None, true) => (root_count > 0).then_some((0, None)),
+        (None, false) | (Some((_, None)), false) => {
+            let end = match position {
+                Some((index, _)) => index,
+                None => root_count,
+            };
+            if end == 0 {
+                None
+            } else {
+                let index = end - 1;
+                let count = transactions(index)?;
+                Some((index, count.checked_sub(1)))
+            }
+        },
+        (Some((index, Some(transaction))), false) =>
+            Some((index, transaction.checked_sub(1))),
+        (Some((index, transaction)), true) => {
+            let next = transaction.map_or(0, |transaction| transaction + 1);
+            if next < transactions(index)? {
+                Some((index, Some(next)))
+            } else if index + 1 < root_count {
+                Some((index + 1, None))
+            } else {
+                None
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::TrafficFilter;
+    use crate::decoder::load_test_capture;
+
+    #[test]
+    fn test_byte_pattern() {
+        let pattern = BytePattern::parse("\"HID\"").unwrap();
+        assert_eq!(pattern.find(b"USB HID class"), Some(4));
+        assert_eq!(pattern.find(b"USB hid class"), None);
+        let pattern = BytePattern::parse("80 06 ?? 01").unwrap();
+        assert_eq!(pattern.find(&[0x00, 0x80, 0x06, 0x42, 0x01]), Some(1));
+        assert_eq!(pattern.find(&[0x80, 0x06, 0x42, 0x02]), None);
+        let pattern = BytePattern::parse("0102/0F0F").unwrap();
+        assert_eq!(pattern.find(&[0xF1, 0x32]), Some(0));
+        let pattern = BytePattern::parse("1?").unwrap();
+        assert_eq!(pattern.find(&[0x21, 0x1A]), Some(1));
+        assert_eq!(pattern.find(&[]), None);
+        let error = |text| BytePattern::parse(text).unwrap_err().to_string();
+        assert_eq!(error(""), "Empty search pattern");
+        assert_eq!(error("\"HID"), "Missing closing quote in search pattern");
+        assert_eq!(error("123"),
+                   "Odd number of hex digits in search pattern");
+        assert_eq!(error("12/3?"), "Invalid hex digit '?'");
+        assert_eq!(error("1234/FF"),
+                   "The mask must be the same length as the bytes");
+    }
+
+    #[test]
+    fn test_find_item() {
+        let mut reader = load_test_capture("split-enum");
+
+        fn find_all(reader: &mut CaptureReader,
+                    pattern: &TextPattern,
+                    forward: bool) -> Vec<String>
+        {
+            let mut found = Vec::new();
+            let mut from = None;
+            while let Some(path) =
+                find_item(reader, pattern, from.as_deref(), forward).unwrap()
+            {
+                let mut item: TrafficItem =
+                    reader.item(None, path[0]).unwrap();
+                if let Some(&index) = path.get(1) {
+                    item = reader.item(Some(&item), index).unwrap();
+                }
+                found.push(reader.description(&item, false).unwrap());
+                from = Some(path);
+            }
+            if !forward {
+                found.reverse();
+            }
+            found
+        }
+
+        let pattern = TextPattern::new("DEVICE 14", false).unwrap();
+        let found = find_all(&mut reader, &pattern, true);
+        assert!(found.len() > 2);
+        assert!(found.iter().all(|text| text.contains("device 14")));
+        assert!(found[0].starts_with("Getting device descriptor"));
+        assert_eq!(find_all(&mut reader, &pattern, false), found);
+
+        // Transactions are searched as well as transfers.
+        let pattern = TextPattern::new("^(SETUP|Class request #[01],)",
+                                       true).unwrap();
+        let found = find_all(&mut reader, &pattern, true);
+        assert!(found.iter().any(|text| text.starts_with("SETUP")));
+        assert!(found.iter().any(|text| text.starts_with("Class request")));
+        assert_eq!(find_all(&mut reader, &pattern, false), found);
+
+        // Items hidden by the filter are not found.
+        reader.set_filter(TrafficFilter::parse("address=0").unwrap());
+        let pattern = TextPattern::new("device 14", false).unwrap();
+        assert!(find_all(&mut reader, &pattern, true).is_empty());
+
+        let error = |text, regex| TextPattern::new(text, regex)
+            .err().unwrap().to_string();
+        assert_eq!(error("", false), "Empty search text");
+        assert!(error("(", true).contains("unclosed group"));
+    }
+
+    #[test]
+    fn test_find_error() {
+        let mut reader = load_test_capture("bad-crcs");
+
+        let mut found = Vec::new();
+        let mut from = None;
+        while let Some(path) =
+            find_error(&mut reader, from.as_deref(), true).unwrap()
+        {
+            let transfer: TrafficItem = reader.item(None, path[0]).unwrap();
+            let item = reader.item(Some(&transfer), path[1]).unwrap();
+            assert!(reader.item_is_error(&item).unwrap());
+            found.push(path.clone());
+            from = Some(path);
+        }
+        assert!(!found.is_empty());
+        // Searching backwards finds the same errors in reverse.
+        let mut from = None;
+        for path in found.iter().rev() {
+            let previous =
+                find_error(&mut reader, from.as_deref(), false).unwrap();
+            assert_eq!(previous.as_ref(), Some(path));
+            from = previous;
+        }
+        assert_eq!(find_error(&mut reader, from.as_deref(), false).unwrap(),
+                   None);
+    }
+
+    #[test]
+    fn test_target() {
+        assert_eq!(Target::parse("12").unwrap(),
+                   Target::Packet(PacketId::from(11)));
+        assert_eq!(Target::parse("Transaction #3").unwrap(),
+                   Target::Transaction(TransactionId::from(2)));
+        assert_eq!(Target::parse("frame 1787").unwrap(), Target::Frame(1787));
+        assert_eq!(Target::parse("time 1.5s").unwrap(),
+                   Target::Time(1_500_000_000));
+        let error = |text| Target::parse(text).unwrap_err().to_string();
+        assert_eq!(error("packet 0"), "Invalid packet number '0'");
+        assert_eq!(error("frame 2048"),
+                   "The frame number must be no more than 2047");
+        assert_eq!(error("byte 5"), "Unknown place 'byte', expected \
+                   'packet', 'transaction', 'frame' or 'time'");
+
+        let mut reader = load_test_capture("split-enum");
+
+        fn go(reader: &mut CaptureReader, text: &str, from: Option<PacketId>)
+            -> Result<TrafficItem, Error>
+        {
+            target_item(reader, Target::parse(text)?, from)
+        }
+        let Ok(TrafficItem::Packet(transfer_id, transaction_id, packet_id)) =
+            go(&mut reader, "packet 400", None)
+        else {
+            panic!("Packet not found");
+        };
+        assert_eq!(packet_id, PacketId::from(399));
+        let text = format!("transaction {}", transaction_id.value + 1);
+        let transaction = go(&mut reader, &text, None);
+        assert_eq!(transaction.unwrap(),
+                   TrafficItem::Transaction(transfer_id, transaction_id));
+        assert!(go(&mut reader, "packet 1000000", None).is_err());
+
+        // The first SOF in the capture has frame number 1787.
+        let Ok(TrafficItem::Packet(.., first)) =
+            go(&mut reader, "frame 1787", None)
+        else {
+            panic!("Frame not found");
+        };
+        let packet = reader.packet(first).unwrap();
+        assert_eq!(PID::from(packet[0]), PID::SOF);
+        let Ok(TrafficItem::Packet(.., next)) =
+            go(&mut reader, "frame 1787", Some(first))
+        else {
+            panic!("Frame not found");
+        };
+        assert!(next > first);
+
+        let time = reader.packet_time(PacketId::from(399)).unwrap();
+        let text = format!("time {}.{:09}",
+                           time / 1_000_000_000, time % 1_000_000_000);
+        let Ok(TrafficItem::Packet(.., packet_id)) =
+            go(&mut reader, &text, None)
+        else {
+            panic!("Time not found");
+        };
+        assert!(packet_id <= PacketId::from(399));
+        assert_eq!(reader.packet_time(packet_id).unwrap(), time);
+    }
+
+    #[test]
+    fn test_find_packet() {
+        let mut reader = load_test_capture("split-enum");
+
+        // Find the packets with GET_DESCRIPTOR requests.
+        let pattern = BytePattern::parse("80 06").unwrap();
+        let packet_count = reader.packet_index.len();
+        let range = PacketId::from(0)..PacketId::from(packet_count);
+        let expected: Vec<PacketId> = reader
+            .packet_range(&range)
+            .unwrap()
+            .enumerate()
+            .filter_map(|(id, packet)| {
+                let packet = packet.unwrap();
+                let data = payload(&packet)?;
+                pattern.find(data).map(|_| PacketId::from(id as u64))
+            })
+            .collect();
+        assert!(expected.len() > 1);
+
+        let mut found = Vec::new();
+        let mut from = None;
+        while let Some(id) =
+            find_packet(&mut reader, &pattern, from, true).unwrap()
+        {
+            found.push(id);
+            from = Some(id);
+        }
+        assert_eq!(found, expected);
+
+        let mut found = Vec::new();
+        let mut from = None;
+        while let Some(id) =
+            find_packet(&mut reader, &pattern, from, false).unwrap()
+        {
+            found.push(id);
+            from = Some(id);
+        }
+        found.reverse();
+        assert_eq!(found, expected);
+    }
+}
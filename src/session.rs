@@ -0,0 +1,330 @@
+//! Sessions, recording the work done in analysing a capture.
+//!
+//! A session is saved as a text file alongside its capture, named after
+//! the capture file with an added extension, e.g. `usb.pcap.session`.
+//! Each line is one entry:
+//!
+//! ```text
+//! # Packetry session
+//! bookmark transaction 3 12 Enumeration starts here
+//! comment packet 3 12 40 Why is this CRC wrong?
+//! filter device 5
+//! column 120 shown Time
+//! pane traffic 640
+//! ```
+//!
+//! Items are identified by the indices of the transfer, transaction and
+//! packet that lead to them, which are the same each time a capture is
+//! decoded. Newlines and backslashes in text are escaped with backslashes.
+
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+use anyhow::{Context, Error, bail};
+
+use crate::capture::{
+    MarkerId,
+    PacketId,
+    TrafficItem,
+    TransactionId,
+    TransferId,
+};
+
+/// Extension added to the name of a capture file to name its session.
+pub const EXTENSION: &str = ".session";
+
+/// Line written at the start of a session file.
+const HEADER: &str = "# Packetry session";
+
+/// Text attached to an item in the Traffic pane.
+#[derive(Clone, Debug)]
+pub struct Annotation {
+    pub item: TrafficItem,
+    pub text: String,
+}
+
+/// Layout of a column in the Traffic pane.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    /// Width in pixels, or -1 if not fixed.
+    pub width: i32,
+    pub visible: bool,
+}
+
+/// Everything recorded about the analysis of a capture.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    pub bookmarks: Vec<Annotation>,
+    pub comments: Vec<Annotation>,
+    /// Filters applied to the view, as entered.
+    pub filters: Vec<String>,
+    pub columns: Vec<Column>,
+    /// Positions of the dividers between panes, by name.
+    pub panes: Vec<(String, i32)>,
+}
+
+impl Session {
+    /// Position recorded for the divider of a pane.
+    pub fn pane(&self, name: &str) -> Option<i32> {
+        self.panes
+            .iter()
+            .find(|(pane, _)| pane == name)
+            .map(|(_, position)| *position)
+    }
+
+    /// Bookmark an item, replacing any existing bookmark of it.
+    pub fn add_bookmark(&mut self, item: TrafficItem, text: String) {
+        annotate(&mut self.bookmarks, item, text);
+    }
+
+    /// Comment on an item, replacing any existing comment on it, or
+    /// removing the comment if the text is empty.
+    pub fn set_comment(&mut self, item: TrafficItem, text: String) {
+        if text.is_empty() {
+            self.comments.retain(|comment| comment.item != item);
+        } else {
+            annotate(&mut self.comments, item, text);
+        }
+    }
+
+    /// The comment on an item, if any.
+    pub fn comment(&self, item: &TrafficItem) -> Option<&str> {
+        self.comments
+            .iter()
+            .find(|comment| &comment.item == item)
+            .map(|comment| comment.text.as_str())
+    }
+
+    /// Load a session file.
+    pub fn load<Source: Read>(source: Source) -> Result<Session, Error> {
+        let mut session = Session::default();
+        for (index, line) in BufReader::new(source).lines().enumerate() {
+            let line = line?;
+            session.parse_line(&line).with_context(|| format!(
+                "Invalid session file entry on line {}", index + 1))?;
+        }
+        Ok(session)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), Error> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "bookmark" => {
+                let (item, text) = parse_item(rest)?;
+                self.bookmarks.push(Annotation { item, text: unescape(text) });
+            },
+            "comment" => {
+                let (item, text) = parse_item(rest)?;
+                self.comments.push(Annotation { item, text: unescape(text) });
+            },
+            "filter" => self.filters.push(unescape(rest)),
+            "column" => {
+                let mut fields = rest.splitn(3, ' ');
+                let width = fields.next().unwrap_or_default().parse()?;
+                let visible = match fields.next() {
+                    Some("shown") => true,
+                    Some("hidden") => false,
+                    _ => bail!("Column must be shown or hidden"),
+                };
+                let name = unescape(fields.next().unwrap_or_default());
+                self.columns.push(Column { name, width, visible });
+            },
+            "pane" => {
+                let (name, position) = rest
+                    .rsplit_once(' ')
+                    .context("Pane position missing")?;
+                self.panes.push((unescape(name), position.parse()?));
+            },
+            _ => bail!("Unknown entry '{keyword}'"),
+        }
+        Ok(())
+    }
+
+    /// Save the session.
+    pub fn save<Dest: Write>(&self, dest: Dest) -> Result<(), Error> {
+        let mut dest = BufWriter::new(dest);
+        writeln!(dest, "{HEADER}")?;
+        for bookmark in &self.bookmarks {
+            writeln!(dest, "bookmark {} {}",
+                     format_item(&bookmark.item), escape(&bookmark.text))?;
+        }
+        for comment in &self.comments {
+            writeln!(dest, "comment {} {}",
+                     format_item(&comment.item), escape(&comment.text))?;
+        }
+        for filter in &self.filters {
+            writeln!(dest, "filter {}", escape(filter))?;
+        }
+        for column in &self.columns {
+            writeln!(dest, "column {} {} {}",
+                     column.width,
+                     if column.visible { "shown" } else { "hidden" },
+                     escape(&column.name))?;
+        }
+        for (name, position) in &self.panes {
+            writeln!(dest, "pane {} {position}", escape(name))?;
+        }
+        dest.flush()?;
+        Ok(())
+    }
+}
+
+/// Attach text to an item, replacing any text it already has.
+fn annotate(annotations: &mut Vec<Annotation>,
+            item: TrafficItem,
+            text: String)
+{
+    match annotations.iter_mut().find(|annotation| annotation.item == item) {
+        Some(annotation) => annotation.text = text,
+        None => annotations.push(Annotation { item, text }),
+    }
+}
+
+/// Name of the session file for a capture file.
+pub fn file_name(capture_name: &str) -> String {
+    format!("{capture_name}{EXTENSION}")
+}
+
+/// Format an item as its kind followed by the indices leading to it.
+fn format_item(item: &TrafficItem) -> String {
+    use TrafficItem::*;
+    match item {
+        Transfer(transfer) =>
+            format!("transfer {}", transfer.value),
+        Transaction(transfer, transaction) =>
+            format!("transaction {} {}", transfer.value, transaction.value),
+        Packet(transfer, transaction, packet) =>
+            format!("packet {} {} {}",
+                    transfer.value, transaction.value, packet.value),
+        Marker(marker) =>
+            format!("marker {}", marker.value),
+    }
+}
+
+/// Parse an item from the start of an entry, returning it with the text
+/// that follows.
+fn parse_item(text: &str) -> Result<(TrafficItem, &str), Error> {
+    let (kind, rest) = text.split_once(' ').context("Item missing")?;
+    let count = match kind {
+        "transfer" | "marker" => 1,
+        "transaction" => 2,
+        "packet" => 3,
+        _ => bail!("Unknown item type '{kind}'"),
+    };
+    let mut fields = rest.splitn(count + 1, ' ');
+    let mut ids = [0; 3];
+    for id in ids.iter_mut().take(count) {
+        *id = fields.next().context("Item index missing")?.parse()?;
+    }
+    let item = match count {
+        1 if kind == "marker" => TrafficItem::Marker(MarkerId::from(ids[0])),
+        1 => TrafficItem::Transfer(TransferId::from(ids[0])),
+        2 => TrafficItem::Transaction(
+            TransferId::from(ids[0]), TransactionId::from(ids[1])),
+        _ => TrafficItem::Packet(
+            TransferId::from(ids[0]),
+            TransactionId::from(ids[1]),
+            PacketId::from(ids[2])),
+    };
+    Ok((item, fields.next().unwrap_or_default()))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                result.push('\n');
+                chars.next();
+            },
+            ('\\', Some('\\')) => {
+                result.push('\\');
+                chars.next();
+            },
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_round_trip() {
+        let session = Session {
+            bookmarks: vec![Annotation {
+                item: TrafficItem::Transaction(
+                    TransferId::from(3), TransactionId::from(12)),
+                text: "Enumeration starts here".to_string(),
+            }, Annotation {
+                item: TrafficItem::Marker(MarkerId::from(2)),
+                text: "Button pressed".to_string(),
+            }],
+            comments: vec![Annotation {
+                item: TrafficItem::Packet(
+                    TransferId::from(3),
+                    TransactionId::from(12),
+                    PacketId::from(40)),
+                text: "Why is this\nCRC wrong? C:\\temp".to_string(),
+            }],
+            filters: vec!["device 5".to_string()],
+            columns: vec![Column {
+                name: "Time".to_string(),
+                width: 120,
+                visible: true,
+            }],
+            panes: vec![("traffic".to_string(), 640)],
+        };
+        let mut saved = Vec::new();
+        session.save(&mut saved).unwrap();
+        let text = String::from_utf8(saved.clone()).unwrap();
+        assert_eq!(text.lines().nth(2),
+                   Some("bookmark marker 2 Button pressed"));
+        assert_eq!(text.lines().nth(3),
+                   Some("comment packet 3 12 40 Why is this\\nCRC wrong? \
+                         C:\\\\temp"));
+        let loaded = Session::load(saved.as_slice()).unwrap();
+        let mut resaved = Vec::new();
+        loaded.save(&mut resaved).unwrap();
+        assert_eq!(resaved, saved);
+        assert_eq!(loaded.comments[0].text, session.comments[0].text);
+        assert_eq!(loaded.pane("traffic"), Some(640));
+
+        let error = Session::load("bogus 1\n".as_bytes()).err().unwrap();
+        assert_eq!(error.to_string(),
+                   "Invalid session file entry on line 1");
+        assert_eq!(file_name("usb.pcap"), "usb.pcap.session");
+    }
+
+    #[test]
+    fn test_annotations() {
+        let mut session = Session::default();
+        let transfer = TrafficItem::Transfer(TransferId::from(1));
+        let marker = TrafficItem::Marker(MarkerId::from(0));
+        session.add_bookmark(transfer, "Start".to_string());
+        session.add_bookmark(marker, "Button".to_string());
+        session.add_bookmark(transfer, "Enumeration".to_string());
+        let texts: Vec<&str> = session.bookmarks
+            .iter()
+            .map(|bookmark| bookmark.text.as_str())
+            .collect();
+        assert_eq!(texts, ["Enumeration", "Button"]);
+        session.set_comment(marker, "Pressed".to_string());
+        session.set_comment(marker, "Pressed twice".to_string());
+        assert_eq!(session.comment(&marker), Some("Pressed twice"));
+        assert_eq!(session.comment(&transfer), None);
+        session.set_comment(marker, String::new());
+        assert!(session.comments.is_empty());
+    }
+}
@@ -0,0 +1,313 @@
+//! Statistics on the traffic of each device and endpoint in a capture.
+//!
+//! For each endpoint with traffic, and each device as a whole, these are
+//! the numbers of transfers, packets and bytes, the number of errors as
+//! flagged in the Traffic pane, and the throughput over the time from the
+//! first packet to the last. They can be written as text to read, or as
+//! JSON for scripts to compare between runs.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::Error;
+use serde_json::{json, Map, Value};
+
+use crate::capture::{
+    CaptureReader,
+    DeviceId,
+    Endpoint,
+    EndpointId,
+    ItemSource,
+    ItemStats,
+    TrafficItem,
+    FRAMING_EP_NUM,
+    INVALID_EP_NUM,
+};
+use crate::util::{fmt_count, fmt_duration, fmt_size};
+
+/// Totals over some part of the traffic.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Totals {
+    /// Transfers, packets, bytes and time span, as counted for a
+    /// selection of items, with each transfer counted as one item.
+    pub stats: ItemStats,
+    /// Number of transactions flagged as errors.
+    pub errors: u64,
+}
+
+/// Statistics on the traffic of one endpoint.
+#[derive(Clone, Debug)]
+pub struct EndpointStats {
+    pub endpoint: Endpoint,
+    pub totals: Totals,
+}
+
+/// Statistics on the traffic of one device.
+#[derive(Clone, Debug)]
+pub struct DeviceStats {
+    pub address: u8,
+    pub description: String,
+    pub totals: Totals,
+    /// The endpoints of the device which had traffic.
+    pub endpoints: Vec<EndpointStats>,
+}
+
+/// Statistics on the traffic in a capture.
+#[derive(Clone, Debug)]
+pub struct CaptureStats {
+    /// Devices with traffic, in order of their addresses.
+    pub devices: Vec<DeviceStats>,
+    /// Totals over all traffic, including malformed packets and SOFs.
+    pub totals: Totals,
+    /// Number of times packets were lost by the capture device.
+    pub losses: u64,
+    /// Values computed by user-supplied scripts, by name.
+    pub metrics: Vec<(String, String)>,
+}
+
+/// Count the traffic in a selection of top-level items.
+pub fn capture_stats(capture: &mut CaptureReader, items: &[TrafficItem])
+    -> Result<CaptureStats, Error>
+{
+    let mut traffic = Vec::new();
+    let mut endpoint_items: BTreeMap<EndpointId, Vec<TrafficItem>> =
+        BTreeMap::new();
+    let mut losses = 0;
+    for item in items {
+        match capture.item_endpoint_id(item)? {
+            Some(endpoint_id) => {
+                traffic.push(*item);
+                endpoint_items.entry(endpoint_id).or_default().push(*item);
+            },
+            None => losses += capture.item_is_error(item)? as u64,
+        }
+    }
+    let mut errors = 0;
+    let mut endpoints = Vec::new();
+    let mut device_items: BTreeMap<DeviceId, Vec<TrafficItem>> =
+        BTreeMap::new();
+    for (endpoint_id, items) in endpoint_items {
+        let endpoint = capture.endpoints.get(endpoint_id)?;
+        let totals = totals(capture, &items)?;
+        errors += totals.errors;
+        if [INVALID_EP_NUM, FRAMING_EP_NUM].contains(&endpoint.number()) {
+            continue;
+        }
+        device_items
+            .entry(endpoint.device_id())
+            .or_default()
+            .extend(items);
+        endpoints.push(EndpointStats { endpoint, totals });
+    }
+    endpoints.sort_by_key(|stats| {
+        let endpoint = &stats.endpoint;
+        (endpoint.number().0, u8::from(endpoint.direction()))
+    });
+    let mut devices = Vec::new();
+    for (device_id, items) in device_items {
+        let endpoints: Vec<EndpointStats> = endpoints
+            .iter()
+            .filter(|stats| stats.endpoint.device_id() == device_id)
+            .cloned()
+            .collect();
+        let totals = Totals {
+            stats: capture.item_stats(&items)?,
+            errors: endpoints.iter().map(|stats| stats.totals.errors).sum(),
+        };
+        devices.push(DeviceStats {
+            address: endpoints[0].endpoint.device_address().0,
+            description: capture.device_data(&device_id)?.description(),
+            totals,
+            endpoints,
+        });
+    }
+    devices.sort_by_key(|device| device.address);
+    let totals = Totals {
+        stats: capture.item_stats(&traffic)?,
+        errors,
+    };
+    let metrics = capture.shared.metrics.load().as_ref().clone();
+    Ok(CaptureStats { devices, totals, losses, metrics })
+}
+
+/// Count the traffic in some items.
+fn totals(capture: &mut CaptureReader, items: &[TrafficItem])
+    -> Result<Totals, Error>
+{
+    let mut errors = 0;
+    for item in items {
+        errors += item_errors(capture, item)?;
+    }
+    Ok(Totals {
+        stats: capture.item_stats(items)?,
+        errors,
+    })
+}
+
+/// Count the errors in an item, including those among its transactions.
+fn item_errors(capture: &mut CaptureReader, item: &TrafficItem)
+    -> Result<u64, Error>
+{
+    let mut errors = capture.item_is_error(item)? as u64;
+    if let TrafficItem::Transfer(_) = item {
+        let (_, count) = capture.item_children(Some(item))?;
+        for index in 0..count {
+            let child = capture.item(Some(item), index)?;
+            errors += capture.item_is_error(&child)? as u64;
+        }
+    }
+    Ok(errors)
+}
+
+/// A count followed by the name of what is counted.
+fn counted(count: u64, name: &str) -> String {
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{} {name}{plural}", fmt_count(count))
+}
+
+impl Totals {
+    fn text(&self) -> String {
+        let stats = &self.stats;
+        let mut text = format!("{}, {}, {}, {}",
+            counted(stats.items, "transfer"),
+            counted(stats.packets, "packet"),
+            fmt_size(stats.bytes),
+            counted(self.errors, "error"));
+        if let Some(range) = &stats.time_range {
+            text.push_str(&format!(
+                " over {}", fmt_duration(range.end - range.start)));
+        }
+        if let Some(rate) = stats.throughput() {
+            text.push_str(&format!(", {}/s", fmt_size(rate as u64)));
+        }
+        text
+    }
+
+    /// Add the fields giving the totals to a JSON object.
+    fn add_json_fields(&self, object: &mut Value) {
+        let stats = &self.stats;
+        let range = stats.time_range.as_ref();
+        object["transfers"] = json!(stats.items);
+        object["packets"] = json!(stats.packets);
+        object["bytes"] = json!(stats.bytes);
+        object["errors"] = json!(self.errors);
+        object["start_ns"] = json!(range.map(|range| range.start));
+        object["end_ns"] = json!(range.map(|range| range.end));
+        object["throughput"] =
+            json!(stats.throughput().map(|rate| rate.round() as u64));
+    }
+}
+
+/// Write statistics as text.
+pub fn write_stats_text(stats: &CaptureStats, mut dest: impl Write)
+    -> Result<(), Error>
+{
+    for device in &stats.devices {
+        writeln!(dest, "Device {} ({}): {}",
+                 device.address, device.description, device.totals.text())?;
+        for endpoint in &device.endpoints {
+            writeln!(dest, "  Endpoint {}: {}",
+                     endpoint.endpoint, endpoint.totals.text())?;
+        }
+    }
+    writeln!(dest, "Total: {}", stats.totals.text())?;
+    if stats.losses > 0 {
+        writeln!(dest, "Packets were lost {}",
+                 counted(stats.losses, "time"))?;
+    }
+    for (name, value) in &stats.metrics {
+        writeln!(dest, "Metric {name}: {value}")?;
+    }
+    dest.flush()?;
+    Ok(())
+}
+
+/// Write statistics as JSON.
+///
+/// Throughput is in bytes per second, and times are in nanoseconds from
+/// the start of the capture. Values that cannot be given for lack of
+/// traffic are `null`. Metrics computed by scripts are given as strings.
+pub fn write_stats_json(stats: &CaptureStats, mut dest: impl Write)
+    -> Result<(), Error>
+{
+    let devices: Vec<Value> = stats.devices
+        .iter()
+        .map(|device| {
+            let endpoints: Vec<Value> = device.endpoints
+                .iter()
+                .map(|endpoint| {
+                    let ep = &endpoint.endpoint;
+                    let mut object = json!({
+                        "number": ep.number().0,
+                        "direction": ep.direction().to_string(),
+                    });
+                    endpoint.totals.add_json_fields(&mut object);
+                    object
+                })
+                .collect();
+            let mut object = json!({
+                "address": device.address,
+                "description": device.description,
+            });
+            device.totals.add_json_fields(&mut object);
+            object["endpoints"] = json!(endpoints);
+            object
+        })
+        .collect();
+    let mut total = json!({});
+    stats.totals.add_json_fields(&mut total);
+    let metrics: Map<String, Value> = stats.metrics
+        .iter()
+        .map(|(name, value)| (name.clone(), json!(value)))
+        .collect();
+    let output = json!({
+        "devices": devices,
+        "total": total,
+        "metrics": metrics,
+        "losses": stats.losses,
+    });
+    serde_json::to_writer_pretty(&mut dest, &output)?;
+    writeln!(dest)?;
+    dest.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{load_capture, Selection};
+
+    #[test]
+    fn test_stats() {
+        let path = "./tests/mouse/capture.pcap";
+        let mut capture = load_capture(path, None).unwrap();
+        let items = Selection::default().items(&mut capture).unwrap();
+        let stats = capture_stats(&mut capture, &items).unwrap();
+        assert!(!stats.devices.is_empty());
+        let mut transfers = 0;
+        for device in &stats.devices {
+            let device_transfers: u64 = device.endpoints
+                .iter()
+                .map(|endpoint| endpoint.totals.stats.items)
+                .sum();
+            assert_eq!(device_transfers, device.totals.stats.items);
+            assert!(device.totals.stats.packets <=
+                    stats.totals.stats.packets);
+            transfers += device_transfers;
+        }
+        assert!(transfers <= stats.totals.stats.items);
+        assert_eq!(stats.totals.stats.items + stats.losses,
+                   items.len() as u64);
+        let mut text = Vec::new();
+        write_stats_text(&stats, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("Device "));
+        assert!(text.contains("\nTotal: "));
+        let mut json = Vec::new();
+        write_stats_json(&stats, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\n  \"devices\": [\n"));
+        assert!(json.ends_with("  \"metrics\": {},\n  \"losses\": 0\n}\n"));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+    }
+}
@@ -3,28 +3,159 @@
 use std::alloc::{GlobalAlloc, Layout, System};
 use std::cmp::min;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::ops::{Deref, Range};
+use std::path::PathBuf;
 use std::ptr::copy_nonoverlapping;
 use std::slice;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering::{Acquire, Release}};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{
+    AtomicBool,
+    AtomicU64,
+    Ordering::{Acquire, Relaxed, Release},
+};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Error, bail};
 use arc_swap::{ArcSwap, ArcSwapOption};
 use lrumap::{LruMap, LruBTreeMap};
 use memmap2::{Mmap, MmapOptions};
-use tempfile::tempfile;
+use tempfile::{tempfile, tempfile_in};
 
 /// Minimum block size, defined by largest minimum page size on target systems.
 pub const MIN_BLOCK: usize = 0x4000; // 16KB (Apple M1/M2)
 
+/// Directory in which to create files, if not the system's default.
+static TEMP_DIR: ArcSwapOption<PathBuf> = ArcSwapOption::const_empty();
+
+/// Set the directory in which the files of new streams are created.
+pub fn set_temp_dir(dir: Option<PathBuf>) {
+    TEMP_DIR.store(dir.map(Arc::new));
+}
+
+/// Free space for stream files below which a capture should warn.
+const LOW_SPACE: u64 = 1 << 30;
+
+/// Free space for stream files below which a capture should stop.
+const MIN_SPACE: u64 = 256 << 20;
+
+/// Interval between checks of the free space for stream files.
+const SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Free space on the filesystem where the files of new streams are created.
+pub fn available_space() -> Result<u64, Error> {
+    let dir = match TEMP_DIR.load_full() {
+        Some(dir) => dir.as_ref().clone(),
+        None => std::env::temp_dir(),
+    };
+    fs2::available_space(&dir).with_context(||
+        format!("Failed to find free space in {}", dir.display()))
+}
+
+/// Result of a check on the free space for stream files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FreeSpace {
+    /// Space is running low, with this many bytes remaining.
+    Low(u64),
+    /// Space has run out, with this many bytes remaining.
+    Exhausted(u64),
+}
+
+/// Periodic check on the free space for stream files, during a capture.
+#[derive(Default)]
+pub struct SpaceCheck {
+    last_check: Option<Instant>,
+    warned: bool,
+}
+
+impl SpaceCheck {
+    /// Check the free space if it is time to, returning a result when
+    /// it is first found to be low, and whenever it has run out.
+    pub fn check(&mut self) -> Option<FreeSpace> {
+        let now = Instant::now();
+        if self.last_check.is_some_and(|last|
+            now.duration_since(last) < SPACE_CHECK_INTERVAL)
+        {
+            return None;
+        }
+        self.last_check = Some(now);
+        // If the free space cannot be found, carry on regardless.
+        self.update(available_space().ok()?)
+    }
+
+    fn update(&mut self, available: u64) -> Option<FreeSpace> {
+        if available < MIN_SPACE {
+            Some(FreeSpace::Exhausted(available))
+        } else if available < LOW_SPACE && !self.warned {
+            self.warned = true;
+            Some(FreeSpace::Low(available))
+        } else {
+            None
+        }
+    }
+}
+
+/// Memory that may be shared by streams to hold blocks instead of files.
+///
+/// While within the pool's limit, streams keep their completed blocks in
+/// memory and do not create files. Once a stream would exceed it, that
+/// stream's blocks are moved to a file.
+pub struct MemoryPool {
+    /// Total size of blocks that may be kept in memory.
+    limit: AtomicU64,
+    /// Total size of blocks currently kept in memory.
+    used: AtomicU64,
+}
+
+impl MemoryPool {
+    /// Create a pool with the given limit, in bytes.
+    pub const fn new(limit: u64) -> Self {
+        MemoryPool {
+            limit: AtomicU64::new(limit),
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Set the total size of blocks that may be kept in memory.
+    pub fn set_limit(&self, bytes: u64) {
+        self.limit.store(bytes, Relaxed);
+    }
+
+    /// Reserve memory for blocks, if within the limit.
+    fn reserve(&self, bytes: u64) -> bool {
+        let limit = self.limit.load(Relaxed);
+        self.used
+            .fetch_update(Relaxed, Relaxed, |used|
+                used.checked_add(bytes).filter(|total| *total <= limit))
+            .is_ok()
+    }
+
+    /// Release memory that was reserved for blocks.
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Relaxed);
+    }
+}
+
+/// Memory pool used by streams constructed with [stream].
+static MEMORY: MemoryPool = MemoryPool::new(0);
+
+/// Set the total size of stream blocks that may be kept in memory.
+pub fn set_memory_limit(bytes: u64) {
+    MEMORY.set_limit(bytes);
+}
+
 /// Private data shared by the writer and multiple readers.
 struct Shared<const S: usize> {
     /// Available length of the stream, including data in both file and buffer.
     length: AtomicU64,
     /// File handle used by readers to create mappings.
     file: ArcSwapOption<File>,
+    /// Whether mapping the file has failed, so blocks must be read instead.
+    mapping_failed: AtomicBool,
+    /// Completed blocks kept in memory, while the stream has no file.
+    memory_blocks: RwLock<Vec<Arc<Block>>>,
+    /// Pool from which memory for these blocks is reserved.
+    memory: &'static MemoryPool,
     /// Buffer currently in use for newly appended data.
     current_buffer: ArcSwap<Buffer<S>>,
 }
@@ -49,8 +180,16 @@ pub struct StreamWriter<const S: usize = MIN_BLOCK> {
 pub struct StreamReader<const S: usize = MIN_BLOCK> {
     /// Shared data.
     shared: Arc<Shared<S>>,
-    /// Cache of existing mappings into the file.
-    mappings: LruBTreeMap<u64, Arc<Mmap>>,
+    /// Cache of recently accessed blocks of the file.
+    blocks: LruBTreeMap<u64, Arc<Block>>,
+}
+
+/// A completed block of the stream.
+pub enum Block {
+    /// The block mapped into memory from the file.
+    Mapped(Mmap),
+    /// A copy of the block, kept in memory or read from the file.
+    Copied(Box<[u8]>),
 }
 
 /// Data that is part of a stream and currently in memory.
@@ -63,14 +202,14 @@ pub struct Buffer<const S: usize> {
 
 /// A read-only handle to any data that is part of a stream.
 pub enum Data<const S: usize> {
-    /// Data in the file, accessed through a mapping.
-    Mapped(Arc<Mmap>, Range<usize>),
+    /// Data in a completed block, accessed through a mapping or a copy.
+    Stored(Arc<Block>, Range<usize>),
     /// Data in memory, accessed within a buffer.
     Buffered(Arc<Buffer<S>>, Range<usize>),
 }
 
-// Number of most recent file mappings retained by each reader.
-const MAP_CACHE_PER_READER: usize = 4;
+// Number of most recently accessed file blocks retained by each reader.
+const BLOCK_CACHE_PER_READER: usize = 4;
 
 type StreamPair<const S: usize> = (StreamWriter<S>, StreamReader<S>);
 
@@ -80,6 +219,13 @@ type StreamPair<const S: usize> = (StreamWriter<S>, StreamReader<S>);
 ///
 pub fn stream<const BLOCK_SIZE: usize>()
     -> Result<StreamPair<BLOCK_SIZE>, Error>
+{
+    stream_in(&MEMORY)
+}
+
+/// Construct a new stream, which may keep blocks in the given memory pool.
+pub fn stream_in<const BLOCK_SIZE: usize>(memory: &'static MemoryPool)
+    -> Result<StreamPair<BLOCK_SIZE>, Error>
 {
     let page_size = page_size::get();
     if BLOCK_SIZE < page_size {
@@ -90,6 +236,9 @@ pub fn stream<const BLOCK_SIZE: usize>()
     let shared = Arc::new(Shared {
         length: AtomicU64::from(0),
         file: ArcSwapOption::empty(),
+        mapping_failed: AtomicBool::new(false),
+        memory_blocks: RwLock::new(Vec::new()),
+        memory,
         current_buffer: ArcSwap::new(buffer.clone()),
     });
     let writer = StreamWriter {
@@ -102,7 +251,7 @@ pub fn stream<const BLOCK_SIZE: usize>()
     };
     let reader = StreamReader {
         shared,
-        mappings: LruBTreeMap::new(MAP_CACHE_PER_READER),
+        blocks: LruBTreeMap::new(BLOCK_CACHE_PER_READER),
     };
     Ok((writer, reader))
 }
@@ -115,6 +264,10 @@ impl<const BLOCK_SIZE: usize> StreamWriter<BLOCK_SIZE> {
 
     /// Append data to the end of the stream.
     ///
+    /// Data is collected in memory and only stored in whole blocks, so
+    /// small appends do not each result in a write to the file. Appended
+    /// data is available to readers as soon as this method returns.
+    ///
     /// Returns the new stream length.
     ///
     pub fn append(&mut self, mut data: &[u8]) -> Result<u64, Error> {
@@ -124,8 +277,8 @@ impl<const BLOCK_SIZE: usize> StreamWriter<BLOCK_SIZE> {
             // All the data will fit in the existing buffer.
             unsafe { self.write_to_buffer(data, length) };
             if self.length as usize & Self::block_mask() == 0 {
-                // Buffer is now full, and can be written to file.
-                unsafe { self.write_buffer_to_file()? };
+                // Buffer is now full, and can be stored.
+                unsafe { self.store_buffer()? };
             }
         } else {
             // The data will fill the existing buffer.
@@ -133,17 +286,19 @@ impl<const BLOCK_SIZE: usize> StreamWriter<BLOCK_SIZE> {
                 // The buffer is partly used. Fill it and write it out.
                 let length = Self::block_size() - buffered;
                 unsafe { self.write_to_buffer(data, length) };
-                // Buffer is now full, and can be written to file.
-                unsafe { self.write_buffer_to_file()? };
+                // Buffer is now full, and can be stored.
+                unsafe { self.store_buffer()? };
                 data = &data[length..];
             }
-            // The buffer is curently empty, so we are free to write whole
-            // blocks of data directly to the file, bypassing the buffer.
+            // The buffer is curently empty, so we are free to store whole
+            // blocks of data directly, bypassing the buffer.
             let direct = data.len() & !Self::block_mask();
             if direct > 0 {
-                unsafe { self.write_to_file(&data[..direct])? };
-                data = &data[direct..];
+                // The length must be updated first, as the buffer for the
+                // next block is set up when the data is stored.
                 self.length += direct as u64;
+                unsafe { self.store_blocks(&data[..direct])? };
+                data = &data[direct..];
             }
             let length = data.len();
             if length > 0 {
@@ -156,6 +311,20 @@ impl<const BLOCK_SIZE: usize> StreamWriter<BLOCK_SIZE> {
         Ok(self.length)
     }
 
+    /// Append the contents of a stream saved by [StreamReader::save].
+    pub fn restore(&mut self, source: &mut impl Read) -> Result<(), Error> {
+        let mut remaining = read_length(source)?;
+        let mut buf = vec![0; Self::block_size()];
+        while remaining > 0 {
+            let length = min(remaining, Self::block_size() as u64) as usize;
+            source.read_exact(&mut buf[..length])
+                .context("Saved stream data is truncated")?;
+            self.append(&buf[..length])?;
+            remaining -= length as u64;
+        }
+        Ok(())
+    }
+
     /// Helper method for writing data to buffer.
     ///
     /// Safety: The data must fit within the space remaining in the buffer.
@@ -169,37 +338,41 @@ impl<const BLOCK_SIZE: usize> StreamWriter<BLOCK_SIZE> {
         self.length += length as u64;
     }
 
-    /// Helper method for writing buffer to file.
+    /// Helper method for storing the buffer.
     ///
     /// Safety: The buffer must be full.
     ///
     #[inline(always)]
-    unsafe fn write_buffer_to_file(&mut self) -> Result<(), Error> {
+    unsafe fn store_buffer(&mut self) -> Result<(), Error> {
         unsafe {
             let buf = slice::from_raw_parts(self.buf, Self::block_size());
-            self.write_to_file(buf)
+            self.store_blocks(buf)
         }
     }
 
-    /// Helper method for writing data to file.
+    /// Helper method for storing blocks of data, in memory or to file.
     ///
     /// Safety: The data must be a multiple of the block size.
     ///
-    unsafe fn write_to_file(&mut self, data: &[u8]) -> Result<(), Error> {
-
-        // Create the file if it does not exist yet.
-        let file = match &mut self.file {
-            None => {
-                let file = tempfile().context("Failed creating temporary file")?;
-                self.shared.file.store(Some(Arc::new(
-                    file.try_clone().context("Failed cloning file handle")?)));
-                self.file.insert(file)
-            },
-            Some(file) => file
-        };
-
-        // Write the data to file.
-        file.write(data).context("Failed writing to stream file")?;
+    unsafe fn store_blocks(&mut self, data: &[u8]) -> Result<(), Error> {
+
+        if self.file.is_none() &&
+            self.shared.memory.reserve(data.len() as u64) {
+            // Keep the blocks in memory.
+            let new_blocks = data
+                .chunks_exact(Self::block_size())
+                .map(|block| Arc::new(Block::Copied(block.into())));
+            self.shared.memory_blocks.write().unwrap().extend(new_blocks);
+        } else {
+            // Write the data to file, creating it if it does not exist yet.
+            let file = match self.file.take() {
+                None => self.create_file()?,
+                Some(file) => file
+            };
+            self.file.insert(file)
+                .write_all(data)
+                .context("Failed writing to stream file")?;
+        }
 
         // We must change the stream's current buffer to one for the new block.
         let block_base = self.length;
@@ -234,6 +407,27 @@ impl<const BLOCK_SIZE: usize> StreamWriter<BLOCK_SIZE> {
         Ok(())
     }
 
+    /// Create the file for the stream, moving any blocks kept in memory
+    /// to it.
+    fn create_file(&self) -> Result<File, Error> {
+        let mut file = match TEMP_DIR.load_full() {
+            Some(dir) => tempfile_in(dir.as_path()),
+            None => tempfile(),
+        }.context("Failed creating temporary file")?;
+        let memory_blocks = self.shared.memory_blocks.read().unwrap().clone();
+        for block in &memory_blocks {
+            file.write_all(block).context("Failed writing to stream file")?;
+        }
+        // Readers must be able to find the blocks in the file before they
+        // are removed from memory.
+        self.shared.file.store(Some(Arc::new(
+            file.try_clone().context("Failed cloning file handle")?)));
+        self.shared.memory_blocks.write().unwrap().clear();
+        self.shared.memory.release(
+            (memory_blocks.len() * Self::block_size()) as u64);
+        Ok(file)
+    }
+
     /// Block size in bytes.
     pub const fn block_size() -> usize {
         BLOCK_SIZE
@@ -283,38 +477,76 @@ impl<const BLOCK_SIZE: usize> StreamReader<BLOCK_SIZE> {
             // Return a handle to access the data in this buffer.
             Ok(Buffered(buffer.clone(), range_in_block))
         } else {
-            // The requested block was already written to the file.
-            // Look for an existing mapping of the block.
-            let existing_mmap = self.mappings.get(&block_base);
+            // The requested block was already stored.
+            // Look for an existing mapping or copy of the block.
+            let existing_block = self.blocks.get(&block_base);
 
-            // If there is no existing mapping, create one.
-            let mmap = match existing_mmap {
-                Some(mmap) => Arc::clone(mmap),
+            // If there is no existing block, find it in memory or load it.
+            let block = match existing_block {
+                Some(block) => Arc::clone(block),
                 None => {
-                    // Get the file handle to be used for mapping.
-                    // The writer sets this before writing the first block.
-                    let file_guard = self.shared.file.load();
-                    let file_option = file_guard.deref().as_ref();
-                    let file_arc = file_option.unwrap();
-                    let file = file_arc.deref();
-                    // The block was already written to the file and will not
-                    // be modified by the writer, so it is safe to map it.
-                    let mmap_result = unsafe {
-                        MmapOptions::new()
-                            .offset(block_base)
-                            .len(Self::block_size())
-                            .map(file)
+                    let new_block = match self.memory_block(block_base) {
+                        Some(block) => block,
+                        None => Arc::new(self.load_block(block_base)?),
                     };
-                    let new_mmap = Arc::new(
-                        mmap_result.context("Failed mapping stream file")?);
-                    self.mappings.push(block_base, Arc::clone(&new_mmap));
-                    new_mmap
+                    self.blocks.push(block_base, Arc::clone(&new_block));
+                    new_block
                 }
             };
 
-            // Return a handle to access the data through this mapping.
-            Ok(Mapped(mmap, range_in_block))
+            // Return a handle to access the data in this block.
+            Ok(Stored(block, range_in_block))
+        }
+    }
+
+    /// Find a block kept in memory, if the stream has no file yet.
+    fn memory_block(&self, block_base: u64) -> Option<Arc<Block>> {
+        let index = block_base as usize / Self::block_size();
+        self.shared.memory_blocks.read().unwrap().get(index).cloned()
+    }
+
+    /// Map a block of the file, or read a copy of it if the file cannot
+    /// be mapped.
+    fn load_block(&self, block_base: u64) -> Result<Block, Error> {
+        // Get the file handle to be used for mapping.
+        // The writer sets this before writing the first block.
+        let file_guard = self.shared.file.load();
+        let file_option = file_guard.deref().as_ref();
+        let file_arc = file_option.unwrap();
+        let file = file_arc.deref();
+        if !self.shared.mapping_failed.load(Relaxed) {
+            // The block was already written to the file and will not
+            // be modified by the writer, so it is safe to map it.
+            let mmap_result = unsafe {
+                MmapOptions::new()
+                    .offset(block_base)
+                    .len(Self::block_size())
+                    .map(file)
+            };
+            match mmap_result {
+                Ok(mmap) => return Ok(Block::Mapped(mmap)),
+                // Some filesystems do not support mapping. Read the blocks
+                // of this stream instead, without trying again each time.
+                Err(_) => self.shared.mapping_failed.store(true, Relaxed),
+            }
+        }
+        let mut copy = vec![0; Self::block_size()].into_boxed_slice();
+        read_at(file, &mut copy, block_base)
+            .context("Failed reading stream file")?;
+        Ok(Block::Copied(copy))
+    }
+
+    /// Write the current contents of the stream, preceded by its length.
+    pub fn save(&mut self, dest: &mut impl Write) -> Result<(), Error> {
+        let length = self.len();
+        dest.write_all(&length.to_le_bytes())?;
+        let mut position = 0;
+        while position < length {
+            let data = self.access(&(position..length))?;
+            dest.write_all(&data)?;
+            position += data.len() as u64;
         }
+        Ok(())
     }
 
     /// Block size in bytes.
@@ -328,6 +560,39 @@ impl<const BLOCK_SIZE: usize> StreamReader<BLOCK_SIZE> {
     }
 }
 
+/// Read data from a position in a file, without moving the position
+/// shared by other handles to the file.
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64)
+    -> std::io::Result<()>
+{
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            count => {
+                let rest = buf;
+                buf = &mut rest[count..];
+                offset += count as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a length written by [StreamReader::save].
+pub fn read_length(source: &mut impl Read) -> Result<u64, Error> {
+    let mut bytes = [0; 8];
+    source.read_exact(&mut bytes).context("Saved stream is truncated")?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
 impl<const BLOCK_SIZE: usize> Buffer<BLOCK_SIZE> {
     /// Create a new buffer for the specified block.
     fn new(block_base: u64) -> Result<Self, Error> {
@@ -365,6 +630,15 @@ impl<const BLOCK_SIZE: usize> Buffer<BLOCK_SIZE> {
     }
 }
 
+// Dropping the shared data must release the memory used by its blocks.
+impl<const S: usize> Drop for Shared<S> {
+    fn drop(&mut self) {
+        if let Ok(blocks) = self.memory_blocks.get_mut() {
+            self.memory.release((blocks.len() * S) as u64);
+        }
+    }
+}
+
 // Dropping a Buffer must free the allocated block.
 impl<const S: usize> Drop for Buffer<S> {
     fn drop(&mut self) {
@@ -390,7 +664,7 @@ impl<const S: usize> Deref for Data<S> {
     fn deref(&self) -> &[u8] {
         use Data::*;
         match self {
-            Mapped(mmap, range) => &mmap[range.clone()],
+            Stored(block, range) => &block[range.clone()],
             // This variant is constructed only by StreamReader::access, which
             // is responsible for ensuring that the range contains valid data.
             Buffered(buffer, range) => unsafe {
@@ -402,17 +676,29 @@ impl<const S: usize> Deref for Data<S> {
     }
 }
 
+// A block can be dereferenced to access its data.
+impl Deref for Block {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Block::Mapped(mmap) => mmap,
+            Block::Copied(copy) => copy,
+        }
+    }
+}
+
 // StreamReader can be cloned to set up multiple readers.
 impl<const S: usize> Clone for StreamReader<S> {
     fn clone(&self) -> StreamReader<S> {
         StreamReader {
             shared: self.shared.clone(),
-            mappings: {
-                let mut new_mappings = LruBTreeMap::new(MAP_CACHE_PER_READER);
-                for (key, value) in self.mappings.iter().rev() {
-                    new_mappings.push(*key, value.clone());
+            blocks: {
+                let mut new_blocks = LruBTreeMap::new(BLOCK_CACHE_PER_READER);
+                for (key, value) in self.blocks.iter().rev() {
+                    new_blocks.push(*key, value.clone());
                 }
-                new_mappings
+                new_blocks
             }
         }
     }
@@ -517,4 +803,90 @@ mod tests {
             thread.join().unwrap();
         }
     }
+
+    #[test]
+    fn test_copied_blocks() {
+        const BLOCK_SIZE: usize = 0x4000;
+        let (mut writer, mut reader) = stream::<BLOCK_SIZE>().unwrap();
+
+        // Read blocks as if the file could not be mapped.
+        reader.shared.mapping_failed.store(true, Relaxed);
+        let reference: Vec<u8> = (0..BLOCK_SIZE * 3 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        // Appending several blocks at once writes them directly to file.
+        writer.append(&reference).unwrap();
+
+        // Read across the stored blocks and into the buffered one.
+        let mut position = 100;
+        let end = reference.len() as u64;
+        while position < end {
+            let data = reader.access(&(position..end)).unwrap();
+            let start = position as usize;
+            assert_eq!(&data[..], &reference[start..start + data.len()]);
+            if start < BLOCK_SIZE * 3 {
+                assert!(matches!(&data,
+                    Data::Stored(block, _) if matches!(**block,
+                                                       Block::Copied(_))));
+            }
+            position += data.len() as u64;
+        }
+    }
+
+    #[test]
+    fn test_memory_blocks() {
+        const BLOCK_SIZE: usize = 0x4000;
+        static MEMORY: MemoryPool = MemoryPool::new(u64::MAX);
+        let (mut writer, mut reader) =
+            stream_in::<BLOCK_SIZE>(&MEMORY).unwrap();
+        let reference: Vec<u8> = (0..BLOCK_SIZE * 3 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let check = |reader: &mut StreamReader<BLOCK_SIZE>, end: usize| {
+            let mut position = 0;
+            while position < end {
+                let data = reader.access(&(position as u64..end as u64))
+                    .unwrap();
+                assert_eq!(&data[..], &reference[position..][..data.len()]);
+                position += data.len();
+            }
+        };
+
+        // Within the limit, completed blocks are kept in memory.
+        writer.append(&reference[..BLOCK_SIZE * 2 + 50]).unwrap();
+        assert!(writer.file.is_none());
+        assert_eq!(reader.shared.memory_blocks.read().unwrap().len(), 2);
+        check(&mut reader, BLOCK_SIZE * 2 + 50);
+
+        // Beyond the limit, the blocks are moved to a file.
+        MEMORY.set_limit(0);
+        writer.append(&reference[BLOCK_SIZE * 2 + 50..]).unwrap();
+        assert!(writer.file.is_some());
+        assert!(reader.shared.memory_blocks.read().unwrap().is_empty());
+        assert_eq!(MEMORY.used.load(Relaxed), 0);
+        let mut new_reader = StreamReader {
+            shared: reader.shared.clone(),
+            blocks: LruBTreeMap::new(BLOCK_CACHE_PER_READER),
+        };
+        check(&mut new_reader, reference.len());
+        check(&mut reader, reference.len());
+    }
+
+    #[test]
+    fn test_space_check() {
+        assert!(available_space().is_ok());
+        let mut check = SpaceCheck::default();
+        assert_eq!(check.update(LOW_SPACE), None);
+        assert_eq!(check.update(LOW_SPACE - 1),
+                   Some(FreeSpace::Low(LOW_SPACE - 1)));
+        // Low space is only reported once.
+        assert_eq!(check.update(LOW_SPACE - 2), None);
+        assert_eq!(check.update(MIN_SPACE - 1),
+                   Some(FreeSpace::Exhausted(MIN_SPACE - 1)));
+        assert_eq!(check.update(MIN_SPACE - 2),
+                   Some(FreeSpace::Exhausted(MIN_SPACE - 2)));
+        // The space is only checked once per interval.
+        check.check();
+        assert_eq!(check.check(), None);
+    }
 }
@@ -0,0 +1,320 @@
+//! Swimlanes, showing when transfers were in progress on each endpoint.
+//!
+//! Each endpoint with traffic has a lane, along which its transfers are
+//! shown as spans of time, from the first of their transactions that was
+//! not NAKed to the last. Laid out together over the same time axis, the
+//! lanes make it clear which endpoints were busy at the same time. NAKed
+//! transactions are left out so that an endpoint being polled is not shown
+//! as continuously busy, and spans too close together to be shown apart
+//! are merged into one.
+
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+
+use anyhow::{Error, bail};
+use gtk::prelude::*;
+use gtk::{GestureClick, ScrolledWindow};
+
+use crate::capture::{
+    CaptureReader,
+    EndpointId,
+    EndpointTransactionId,
+    EndpointTransferId,
+    Timestamp,
+    FRAMING_EP_NUM,
+    INVALID_EP_NUM,
+};
+use crate::throughput::{GRAPH_COLORS, THROUGHPUT_INTERVALS};
+use crate::timeline::{set_color, Palette, TimelineState};
+use crate::usb::PID;
+use crate::util::{fmt_count, fmt_duration};
+
+/// A span of time in which one or more transfers were in progress.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub time_range: Range<Timestamp>,
+    /// Number of transfers merged into the span.
+    pub transfers: usize,
+    /// Whether any of the transfers was stalled.
+    pub stalled: bool,
+}
+
+/// The transfers on an endpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lane {
+    pub name: String,
+    /// Spans of transfers, in time order.
+    pub spans: Vec<Span>,
+}
+
+/// Find the spans of the transfers on each endpoint, merging those less
+/// than the given resolution apart.
+pub fn swimlanes(capture: &mut CaptureReader, resolution: Timestamp)
+    -> Result<Vec<Lane>, Error>
+{
+    let mut lanes = Vec::new();
+    for i in 0..capture.endpoints.len() {
+        let endpoint_id = EndpointId::from(i);
+        let endpoint = capture.endpoints.get(endpoint_id)?;
+        if [INVALID_EP_NUM, FRAMING_EP_NUM].contains(&endpoint.number()) {
+            continue;
+        }
+        let spans = endpoint_spans(capture, endpoint_id, resolution)?;
+        if !spans.is_empty() {
+            let name = endpoint.to_string();
+            lanes.push(Lane { name, spans });
+        }
+    }
+    Ok(lanes)
+}
+
+fn endpoint_spans(capture: &mut CaptureReader,
+                  endpoint_id: EndpointId,
+                  resolution: Timestamp)
+    -> Result<Vec<Span>, Error>
+{
+    let mut spans: Vec<Span> = Vec::new();
+    let ep_traf = capture.endpoint_traffic(endpoint_id)?;
+    let transaction_count = ep_traf.transaction_ids.len();
+    let transfer_count = ep_traf.transfer_index.len();
+    for i in 0..transfer_count {
+        let ep_transaction_ids = capture
+            .endpoint_traffic(endpoint_id)?
+            .transfer_index
+            .target_range(EndpointTransferId::from(i), transaction_count)?;
+        let mut time_range: Option<Range<Timestamp>> = None;
+        let mut stalled = false;
+        for j in ep_transaction_ids.start.value..ep_transaction_ids.end.value {
+            let transaction_id = capture
+                .endpoint_traffic(endpoint_id)?
+                .transaction_ids
+                .get(EndpointTransactionId::from(j))?;
+            let transaction = capture.transaction(transaction_id)?;
+            match transaction.outcome() {
+                Some(PID::NAK) => continue,
+                Some(PID::STALL) => stalled = true,
+                _ => {},
+            }
+            let packets = &transaction.packet_id_range;
+            let start = capture.packet_time(packets.start)?;
+            let end = capture.packet_time(packets.end - 1)?;
+            time_range.get_or_insert(start..end).end = end;
+        }
+        let Some(time_range) = time_range else {
+            continue;
+        };
+        match spans.last_mut() {
+            Some(last) if time_range.start <=
+                last.time_range.end.saturating_add(resolution) =>
+            {
+                last.time_range.end = last.time_range.end.max(time_range.end);
+                last.transfers += 1;
+                last.stalled |= stalled;
+            },
+            _ => spans.push(Span { time_range, transfers: 1, stalled }),
+        }
+    }
+    Ok(spans)
+}
+
+/// Number of intervals of time that swimlanes resolve transfers to.
+const SWIMLANE_INTERVALS: u64 = 1000;
+
+/// Height of each lane in the swimlane view, in pixels.
+const LANE_HEIGHT: f64 = 24.0;
+
+/// Width of the endpoint names at the left of the swimlanes, in pixels.
+const LANE_LABEL_WIDTH: f64 = 100.0;
+
+/// What is shown in the swimlane view.
+#[derive(Default)]
+pub struct SwimlaneState {
+    /// Overview of the capture, for its time range and markers.
+    overview: TimelineState,
+    lanes: Vec<Lane>,
+}
+
+/// Draw the swimlanes, with a lane for each endpoint in which its
+/// transfers are shown as bars, stalled ones in red, and the markers in
+/// the capture as lines across all the lanes.
+fn draw_swimlanes(state: &SwimlaneState,
+                  context: &gtk::cairo::Context,
+                  width: i32,
+                  height: i32)
+    -> Result<(), gtk::cairo::Error>
+{
+    let (width, height) = (width as f64, height as f64);
+    let palette = Palette::current();
+    set_color(context, palette.background);
+    context.paint()?;
+    if state.overview.timeline.time_range.is_empty() {
+        return Ok(());
+    }
+    for (index, lane) in state.lanes.iter().enumerate() {
+        let top = index as f64 * LANE_HEIGHT;
+        if index % 2 == 1 {
+            set_color(context, palette.stripe);
+            context.rectangle(0.0, top, width, LANE_HEIGHT);
+            context.fill()?;
+        }
+        set_color(context, palette.text);
+        context.move_to(5.0, top + LANE_HEIGHT / 2.0 + 4.0);
+        context.show_text(&lane.name)?;
+        let (red, green, blue) = GRAPH_COLORS[index % GRAPH_COLORS.len()];
+        for stalled in [false, true] {
+            if stalled {
+                context.set_source_rgb(0.9, 0.1, 0.1);
+            } else {
+                context.set_source_rgb(red, green, blue);
+            }
+            let spans = lane.spans
+                .iter()
+                .filter(|span| span.stalled == stalled);
+            for span in spans {
+                let start = state.overview.x(span.time_range.start);
+                let end = state.overview.x(span.time_range.end);
+                context.rectangle(
+                    LANE_LABEL_WIDTH + start, top + 4.0,
+                    (end - start).max(1.0), LANE_HEIGHT - 8.0);
+            }
+            context.fill()?;
+        }
+    }
+    context.set_source_rgb(0.9, 0.6, 0.0);
+    context.set_line_width(1.0);
+    for &timestamp in &state.overview.timeline.events {
+        let x = LANE_LABEL_WIDTH + state.overview.x(timestamp).round() + 0.5;
+        context.move_to(x, 0.0);
+        context.line_to(x, height);
+    }
+    context.stroke()?;
+    Ok(())
+}
+
+impl SwimlaneState {
+    /// Find the transfers on each endpoint, over the time of a capture.
+    pub fn new(capture: &mut CaptureReader)
+        -> Result<SwimlaneState, Error>
+    {
+        let timeline = capture.timeline(THROUGHPUT_INTERVALS)?;
+        if timeline.time_range.is_empty() {
+            bail!("There is no traffic to show");
+        }
+        let range = &timeline.time_range;
+        let resolution = (range.end - range.start) / SWIMLANE_INTERVALS;
+        let lanes = swimlanes(capture, resolution)?;
+        if lanes.is_empty() {
+            bail!("There are no transfers to show");
+        }
+        let overview = TimelineState { timeline, ..Default::default() };
+        Ok(SwimlaneState { overview, lanes })
+    }
+
+    /// Describe the span of transfers at a position, if there is one.
+    fn describe(&self, x: f64, y: f64) -> Option<String> {
+        let lane = self.lanes.get((y / LANE_HEIGHT) as usize)?;
+        let x = x - LANE_LABEL_WIDTH;
+        let span = lane.spans.iter().find(|span|
+            self.overview.x(span.time_range.start) - 1.0 <= x &&
+                x <= self.overview.x(span.time_range.end) + 1.0)?;
+        let duration = span.time_range.end - span.time_range.start;
+        let transfers = match span.transfers {
+            1 => String::from("1 transfer"),
+            count => format!("{} transfers", fmt_count(count as u64)),
+        };
+        let stalled = if span.stalled {", including stalls"} else {""};
+        Some(format!("{}: {transfers} over {}{stalled}",
+                     lane.name, fmt_duration(duration)))
+    }
+}
+
+/// Create the swimlane view, calling `go_to` with the time at which it is
+/// clicked.
+pub fn swimlane_view<F>(state: SwimlaneState, go_to: F) -> ScrolledWindow
+    where F: Fn(Timestamp) + 'static
+{
+    let area = gtk::DrawingArea::builder()
+        .content_width(800)
+        .content_height((state.lanes.len() as f64 * LANE_HEIGHT) as i32)
+        .hexpand(true)
+        .has_tooltip(true)
+        .build();
+    let state = Rc::new(RefCell::new(state));
+    let tooltip_state = state.clone();
+    area.connect_query_tooltip(move |_, x, y, _, tooltip| {
+        let text = tooltip_state
+            .borrow()
+            .describe(x as f64, y as f64)
+            .unwrap_or_else(|| String::from("Click to go to a time"));
+        tooltip.set_text(Some(&text));
+        true
+    });
+    let draw_state = state.clone();
+    area.set_draw_func(move |_, context, width, height| {
+        let mut state = draw_state.borrow_mut();
+        state.overview.width = width - LANE_LABEL_WIDTH as i32;
+        // Nothing can be done about a failure to draw.
+        let _ = draw_swimlanes(&state, context, width, height);
+    });
+    let click = GestureClick::new();
+    click.connect_pressed(move |_, _, x, _| {
+        if x < LANE_LABEL_WIDTH {
+            return;
+        }
+        let time = state.borrow().overview.time_at(x - LANE_LABEL_WIDTH);
+        if let Some(time) = time {
+            go_to(time);
+        }
+    });
+    area.add_controller(click);
+    ScrolledWindow::builder()
+        .child(&area)
+        .hscrollbar_policy(gtk::PolicyType::Never)
+        .min_content_height(320)
+        .vexpand(true)
+        .margin_top(5)
+        .margin_bottom(5)
+        .margin_start(5)
+        .margin_end(5)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::load_test_capture;
+
+    #[test]
+    fn test_swimlanes() {
+        let mut reader = load_test_capture("hackrf-connect");
+
+        let lanes = swimlanes(&mut reader, 0).unwrap();
+        assert!(!lanes.is_empty());
+        for lane in &lanes {
+            assert!(!lane.spans.is_empty());
+            for span in &lane.spans {
+                assert!(span.time_range.start <= span.time_range.end);
+            }
+            for pair in lane.spans.windows(2) {
+                assert!(pair[0].time_range.end < pair[1].time_range.start);
+            }
+        }
+
+        // At a coarse enough resolution, each lane is a single span.
+        let merged = swimlanes(&mut reader, Timestamp::MAX).unwrap();
+        assert_eq!(merged.len(), lanes.len());
+        for (lane, merged) in lanes.iter().zip(&merged) {
+            assert_eq!(merged.name, lane.name);
+            assert_eq!(merged.spans.len(), 1);
+            let span = &merged.spans[0];
+            let transfers: usize =
+                lane.spans.iter().map(|span| span.transfers).sum();
+            assert_eq!(span.transfers, transfers);
+            assert_eq!(span.time_range.start,
+                       lane.spans[0].time_range.start);
+            assert_eq!(span.stalled,
+                       lane.spans.iter().any(|span| span.stalled));
+        }
+    }
+}
@@ -1,8 +1,8 @@
+use crate::backend::Speed;
 use crate::backend::cynthion::{
     CynthionDevice,
     CynthionUsability,
     CynthionHandle,
-    Speed
 };
 use crate::capture::{
     create_capture,
@@ -13,7 +13,7 @@ use crate::capture::{
     PacketId,
 };
 use crate::decoder::Decoder;
-use crate::pcap::Writer;
+use crate::pcap::{FileFormat, Writer};
 
 use anyhow::{Context, Error, ensure};
 use futures_lite::future::block_on;
@@ -101,7 +101,7 @@ fn test(save_capture: bool,
         // Write the capture to a file.
         let path = PathBuf::from(format!("./HITL-{name}.pcap"));
         let file = File::create(path)?;
-        let mut writer = Writer::open(file)?;
+        let mut writer = Writer::open(file, FileFormat::Pcap)?;
         for i in 0..reader.packet_index.len() {
             let packet_id = PacketId::from(i);
             let packet = reader.packet(packet_id)?;
@@ -0,0 +1,235 @@
+//! Drawing of a graph of the data transferred on each endpoint over the
+//! time of a capture.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::{Error, bail};
+use gtk::prelude::*;
+use gtk::{CheckButton, GestureClick, Label, Orientation, ScrolledWindow};
+
+use crate::capture::{
+    CaptureReader,
+    Endpoint,
+    EndpointId,
+    Timestamp,
+    FRAMING_EP_NUM,
+    INVALID_EP_NUM,
+};
+use crate::timeline::{set_color, Palette, TimelineState};
+use crate::util::fmt_size;
+
+/// Number of intervals the throughput graph divides the capture into.
+pub const THROUGHPUT_INTERVALS: usize = 250;
+
+/// Colors of the lines in the throughput graph, used in turn.
+pub const GRAPH_COLORS: [(f64, f64, f64); 6] = [
+    (0.12, 0.47, 0.71),
+    (1.0, 0.5, 0.05),
+    (0.17, 0.63, 0.17),
+    (0.84, 0.15, 0.16),
+    (0.58, 0.4, 0.74),
+    (0.55, 0.34, 0.29),
+];
+
+/// The data transferred on an endpoint, as a line on the throughput graph.
+pub struct GraphSeries {
+    name: String,
+    /// Bytes transferred in each interval.
+    bytes: Vec<u64>,
+    shown: bool,
+}
+
+/// What is shown in the throughput graph.
+pub struct ThroughputState {
+    /// Overview of the capture, for the errors and markers in it.
+    overview: TimelineState,
+    series: Vec<GraphSeries>,
+}
+
+/// Draw the throughput graph, with a line for each endpoint shown, the
+/// markers in the capture as lines, and its errors as ticks below.
+fn draw_throughput(state: &ThroughputState,
+                   context: &gtk::cairo::Context,
+                   width: i32,
+                   height: i32)
+    -> Result<(), gtk::cairo::Error>
+{
+    let (width, height) = (width as f64, height as f64);
+    let palette = Palette::current();
+    set_color(context, palette.background);
+    context.paint()?;
+    let timeline = &state.overview.timeline;
+    let intervals = timeline.packets.len();
+    if timeline.time_range.is_empty() || intervals == 0 {
+        return Ok(());
+    }
+    let interval_width = width / intervals as f64;
+    context.set_source_rgb(0.9, 0.1, 0.1);
+    for (index, &errors) in timeline.errors.iter().enumerate() {
+        if errors > 0 {
+            context.rectangle(
+                index as f64 * interval_width, height - 4.0,
+                interval_width.max(1.0), 4.0);
+        }
+    }
+    context.fill()?;
+    context.set_source_rgb(0.9, 0.6, 0.0);
+    context.set_line_width(1.0);
+    for &timestamp in &timeline.events {
+        let x = state.overview.x(timestamp).round() + 0.5;
+        context.move_to(x, 0.0);
+        context.line_to(x, height);
+    }
+    context.stroke()?;
+    let max = state.series
+        .iter()
+        .filter(|series| series.shown)
+        .flat_map(|series| series.bytes.iter().copied())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    // Leave space at the top for the scale, and at the bottom for errors.
+    let (top, bottom) = (20.0, height - 6.0);
+    context.set_line_width(1.5);
+    for (index, series) in state.series.iter().enumerate() {
+        if !series.shown {
+            continue;
+        }
+        let (red, green, blue) = GRAPH_COLORS[index % GRAPH_COLORS.len()];
+        context.set_source_rgb(red, green, blue);
+        for (interval, &bytes) in series.bytes.iter().enumerate() {
+            let x = (interval as f64 + 0.5) * interval_width;
+            let y = bottom - (bottom - top) * bytes as f64 / max as f64;
+            if interval == 0 {
+                context.move_to(x, y);
+            } else {
+                context.line_to(x, y);
+            }
+        }
+        context.stroke()?;
+    }
+    let span = timeline.time_range.end - timeline.time_range.start;
+    let interval_ns = (span as f64 / intervals as f64).max(1.0);
+    let peak_rate = max as f64 * 1e9 / interval_ns;
+    set_color(context, palette.text);
+    context.move_to(5.0, 14.0);
+    context.show_text(&format!(
+        "Peak {}/s, over {}.{:09} s",
+        fmt_size(peak_rate as u64),
+        span / 1_000_000_000,
+        span % 1_000_000_000))?;
+    Ok(())
+}
+
+impl ThroughputState {
+    /// Measure the data transferred on each endpoint, showing only the
+    /// selected endpoint at first if one is given.
+    pub fn new(capture: &mut CaptureReader, selected: Option<Endpoint>)
+        -> Result<ThroughputState, Error>
+    {
+        let timeline = capture.timeline(THROUGHPUT_INTERVALS)?;
+        if timeline.time_range.is_empty() {
+            bail!("There is no traffic to graph");
+        }
+        let mut series = Vec::new();
+        for i in 0..capture.endpoints.len() {
+            let endpoint_id = EndpointId::from(i);
+            let endpoint = capture.endpoints.get(endpoint_id)?;
+            if [INVALID_EP_NUM, FRAMING_EP_NUM].contains(&endpoint.number()) {
+                continue;
+            }
+            let bytes = capture.endpoint_throughput(
+                endpoint_id, &timeline.time_range, THROUGHPUT_INTERVALS)?;
+            if bytes.iter().all(|&count| count == 0) {
+                continue;
+            }
+            let name = endpoint.to_string();
+            let shown = selected.map_or(true, |selected|
+                selected.device_id() == endpoint.device_id() &&
+                    selected.to_string() == name);
+            series.push(GraphSeries { name, bytes, shown });
+        }
+        if series.is_empty() {
+            bail!("No data has been transferred on any endpoint");
+        }
+        Ok(ThroughputState {
+            overview: TimelineState { timeline, ..Default::default() },
+            series,
+        })
+    }
+}
+
+/// Create the graph, with check boxes to choose the endpoints shown,
+/// calling `go_to` with the time at which it is clicked.
+pub fn throughput_view<F>(state: ThroughputState, go_to: F) -> gtk::Box
+    where F: Fn(Timestamp) + 'static
+{
+    let area = gtk::DrawingArea::builder()
+        .content_width(640)
+        .content_height(320)
+        .hexpand(true)
+        .vexpand(true)
+        .tooltip_text("Click to go to a time")
+        .build();
+    let endpoint_list = gtk::Box::builder()
+        .orientation(Orientation::Vertical)
+        .spacing(2)
+        .margin_start(5)
+        .margin_end(5)
+        .build();
+    let state = Rc::new(RefCell::new(state));
+    for (index, series) in state.borrow().series.iter().enumerate() {
+        let (red, green, blue) = GRAPH_COLORS[index % GRAPH_COLORS.len()];
+        let key = Label::new(None);
+        key.set_markup(&format!(
+            "<span foreground=\"#{:02x}{:02x}{:02x}\">━━</span>",
+            (red * 255.0) as u8, (green * 255.0) as u8, (blue * 255.0) as u8));
+        let check = CheckButton::builder()
+            .label(&series.name)
+            .active(series.shown)
+            .build();
+        let toggle_state = state.clone();
+        let area = area.clone();
+        check.connect_toggled(move |check| {
+            toggle_state.borrow_mut().series[index].shown = check.is_active();
+            area.queue_draw();
+        });
+        let row = gtk::Box::builder()
+            .orientation(Orientation::Horizontal)
+            .spacing(5)
+            .build();
+        row.append(&key);
+        row.append(&check);
+        endpoint_list.append(&row);
+    }
+    let draw_state = state.clone();
+    area.set_draw_func(move |_, context, width, height| {
+        let mut state = draw_state.borrow_mut();
+        state.overview.width = width;
+        // Nothing can be done about a failure to draw.
+        let _ = draw_throughput(&state, context, width, height);
+    });
+    let click = GestureClick::new();
+    click.connect_pressed(move |_, _, x, _| {
+        let time = state.borrow().overview.time_at(x);
+        if let Some(time) = time {
+            go_to(time);
+        }
+    });
+    area.add_controller(click);
+
+    let endpoint_window = ScrolledWindow::builder()
+        .child(&endpoint_list)
+        .hscrollbar_policy(gtk::PolicyType::Never)
+        .build();
+    let content = gtk::Box::builder()
+        .orientation(Orientation::Horizontal)
+        .margin_top(5)
+        .margin_bottom(5)
+        .margin_end(5)
+        .build();
+    content.append(&endpoint_window);
+    content.append(&area);
+    content
+}
@@ -0,0 +1,188 @@
+//! Drawing of the timeline overview of a capture, and of the colors
+//! shared by the graphs shown over the time of a capture.
+
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+
+use gtk::prelude::*;
+use gtk::GestureClick;
+
+use crate::capture::{Timeline, Timestamp};
+
+/// Height of the timeline in pixels.
+pub const TIMELINE_HEIGHT: i32 = 40;
+
+/// Number of intervals the timeline divides the capture into.
+pub const TIMELINE_INTERVALS: usize = 500;
+
+/// What is shown in the timeline above the traffic view.
+#[derive(Default)]
+pub struct TimelineState {
+    pub timeline: Timeline,
+    /// Time span of the rows visible in the traffic view.
+    pub visible: Option<Range<Timestamp>>,
+    /// Width of the timeline when last drawn.
+    pub width: i32,
+}
+
+impl TimelineState {
+    /// Position of a time across the timeline.
+    pub fn x(&self, timestamp: Timestamp) -> f64 {
+        let range = &self.timeline.time_range;
+        let span = (range.end - range.start) as f64;
+        let offset = timestamp.saturating_sub(range.start) as f64;
+        (offset / span).min(1.0) * self.width as f64
+    }
+
+    /// Time at a position across the timeline, if there is a capture.
+    pub fn time_at(&self, x: f64) -> Option<Timestamp> {
+        let range = &self.timeline.time_range;
+        if range.is_empty() || self.width <= 0 {
+            return None;
+        }
+        let fraction = (x / self.width as f64).clamp(0.0, 1.0);
+        let span = (range.end - range.start - 1) as f64;
+        Some(range.start + (fraction * span) as Timestamp)
+    }
+}
+
+/// A color for drawing, as red, green and blue components.
+pub type Rgb = (f64, f64, f64);
+
+/// Colors used to draw graphs, to suit a light or dark theme.
+pub struct Palette {
+    /// Behind the graph.
+    pub background: Rgb,
+    /// Behind alternate lanes, and the timeline.
+    pub stripe: Rgb,
+    /// Labels and scales.
+    pub text: Rgb,
+    /// Shading over the time span visible in the traffic view.
+    pub shade: Rgb,
+}
+
+impl Palette {
+    const LIGHT: Palette = Palette {
+        background: (1.0, 1.0, 1.0),
+        stripe: (0.95, 0.95, 0.95),
+        text: (0.3, 0.3, 0.3),
+        shade: (0.0, 0.0, 0.0),
+    };
+
+    const DARK: Palette = Palette {
+        background: (0.14, 0.14, 0.14),
+        stripe: (0.19, 0.19, 0.19),
+        text: (0.8, 0.8, 0.8),
+        shade: (1.0, 1.0, 1.0),
+    };
+
+    /// The palette for the theme in use.
+    pub fn current() -> &'static Palette {
+        if dark_theme() {
+            &Palette::DARK
+        } else {
+            &Palette::LIGHT
+        }
+    }
+}
+
+/// Set the source of a cairo context to a color.
+pub fn set_color(context: &gtk::cairo::Context, (red, green, blue): Rgb) {
+    context.set_source_rgb(red, green, blue);
+}
+
+/// Whether the theme in use has dark colors.
+pub fn dark_theme() -> bool {
+    gtk::Settings::default().is_some_and(|settings|
+        settings.is_gtk_application_prefer_dark_theme() ||
+        settings.gtk_theme_name().is_some_and(|name|
+            name.to_lowercase().ends_with("-dark")))
+}
+
+/// Draw the timeline, with the packets in each interval as a bar, the
+/// errors in red over it, markers as lines, and a box around the time
+/// span of the rows visible in the traffic view.
+pub fn draw_timeline(state: &TimelineState,
+                     context: &gtk::cairo::Context,
+                     width: i32,
+                     height: i32)
+    -> Result<(), gtk::cairo::Error>
+{
+    let (width, height) = (width as f64, height as f64);
+    let palette = Palette::current();
+    set_color(context, palette.stripe);
+    context.paint()?;
+    let timeline = &state.timeline;
+    if timeline.time_range.is_empty() {
+        return Ok(());
+    }
+    let max = timeline.packets.iter().copied().max().unwrap_or(0).max(1);
+    let bar_width = width / timeline.packets.len() as f64;
+    let bar_height = |count: u64| match count {
+        0 => 0.0,
+        _ => (height * count as f64 / max as f64).max(2.0),
+    };
+    for (counts, (red, green, blue)) in [
+        (&timeline.packets, (0.3, 0.5, 0.8)),
+        (&timeline.errors, (0.9, 0.1, 0.1)),
+    ] {
+        context.set_source_rgb(red, green, blue);
+        for (index, &count) in counts.iter().enumerate() {
+            let bar = bar_height(count);
+            context.rectangle(
+                index as f64 * bar_width, height - bar, bar_width, bar);
+        }
+        context.fill()?;
+    }
+    context.set_source_rgb(0.9, 0.6, 0.0);
+    context.set_line_width(1.0);
+    for &timestamp in &timeline.events {
+        let x = state.x(timestamp).round() + 0.5;
+        context.move_to(x, 0.0);
+        context.line_to(x, height);
+    }
+    context.stroke()?;
+    if let Some(visible) = &state.visible {
+        let start = state.x(visible.start);
+        let end = state.x(visible.end).max(start + 2.0);
+        let (red, green, blue) = palette.shade;
+        context.set_source_rgba(red, green, blue, 0.15);
+        context.rectangle(start, 0.0, end - start, height);
+        context.fill_preserve()?;
+        context.set_source_rgba(red, green, blue, 0.6);
+        context.stroke()?;
+    }
+    Ok(())
+}
+
+/// Create the area in which the timeline is drawn, calling `go_to` with
+/// the time at which it is clicked.
+pub fn timeline_area<F>(state: &Rc<RefCell<TimelineState>>, go_to: F)
+    -> gtk::DrawingArea
+    where F: Fn(Timestamp) + 'static
+{
+    let area = gtk::DrawingArea::builder()
+        .content_height(TIMELINE_HEIGHT)
+        .hexpand(true)
+        .tooltip_text(
+            "Packets, errors and markers over the time of the capture. \
+             Click to go to a time.")
+        .build();
+    let draw_state = state.clone();
+    area.set_draw_func(move |_, context, width, height| {
+        let mut state = draw_state.borrow_mut();
+        state.width = width;
+        // Nothing can be done about a failure to draw.
+        let _ = draw_timeline(&state, context, width, height);
+    });
+    let click = GestureClick::new();
+    let state = state.clone();
+    click.connect_pressed(move |_, _, x, _| {
+        if let Some(time) = state.borrow().time_at(x) {
+            go_to(time);
+        }
+    });
+    area.add_controller(click);
+    area
+}
@@ -0,0 +1,185 @@
+//! Formatting of the timestamps shown in the traffic view.
+//!
+//! Each item's time can be shown relative to the start of the capture, as
+//! a time of day, relative to the item shown before it, or as the bus time
+//! given by the frame and microframe numbers of the SOF packets.
+
+use anyhow::Error;
+
+use crate::capture::{CaptureReader, ItemSource, Timestamp, TrafficItem};
+
+/// Nanoseconds in a day.
+const DAY_NS: i128 = 86_400_000_000_000;
+
+/// How timestamps are shown.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimeMode {
+    #[default]
+    Relative,
+    TimeOfDay,
+    Delta,
+    BusTime,
+}
+
+impl TimeMode {
+    pub const ALL: [TimeMode; 4] = [
+        TimeMode::Relative,
+        TimeMode::TimeOfDay,
+        TimeMode::Delta,
+        TimeMode::BusTime,
+    ];
+
+    /// Label for the menu item.
+    pub fn label(&self) -> &'static str {
+        use TimeMode::*;
+        match self {
+            Relative => "Time since capture start",
+            TimeOfDay => "Time of day",
+            Delta => "Time since previous item",
+            BusTime => "Bus time (frame.microframe)",
+        }
+    }
+
+    /// Name used for this mode in the time mode action.
+    pub fn name(&self) -> &'static str {
+        use TimeMode::*;
+        match self {
+            Relative => "relative",
+            TimeOfDay => "time-of-day",
+            Delta => "delta",
+            BusTime => "bus-time",
+        }
+    }
+
+    /// Look up a mode by its name.
+    pub fn from_name(name: &str) -> Option<TimeMode> {
+        TimeMode::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+}
+
+/// Settings for formatting timestamps.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TimeFormat {
+    pub mode: TimeMode,
+    /// Offset of local time from UTC, in seconds.
+    pub utc_offset: i64,
+}
+
+impl TimeFormat {
+    /// The text shown for an item's timestamp.
+    ///
+    /// If the time of day at which a capture started is not known, times
+    /// are shown relative to its start instead.
+    pub fn text(&self, capture: &mut CaptureReader, item: &TrafficItem)
+        -> Result<String, Error>
+    {
+        use TimeMode::*;
+        let timestamp = capture.timestamp(item)?;
+        Ok(match (self.mode, capture.start_time()) {
+            (Relative, _) | (TimeOfDay, None) => fmt_timestamp(timestamp),
+            (TimeOfDay, Some(start)) =>
+                fmt_time_of_day(start + timestamp, self.utc_offset),
+            (Delta, _) => match capture.item_previous(item)? {
+                Some(previous) => {
+                    let previous = capture.timestamp(&previous)?;
+                    fmt_delta(timestamp as i128 - previous as i128)
+                },
+                None => fmt_timestamp(0),
+            },
+            (BusTime, _) => match capture.item_bus_time(item)? {
+                Some((frame, microframe)) => format!("{frame}.{microframe}"),
+                None => String::from("-"),
+            },
+        })
+    }
+}
+
+/// Format a timestamp as seconds, to nanosecond precision.
+pub fn fmt_timestamp(timestamp: Timestamp) -> String {
+    format!("{}.{:09}", timestamp / 1_000_000_000, timestamp % 1_000_000_000)
+}
+
+/// Format a difference between timestamps, which may be negative.
+fn fmt_delta(delta: i128) -> String {
+    let sign = if delta < 0 {"-"} else {""};
+    format!("{sign}{}", fmt_timestamp(delta.unsigned_abs() as u64))
+}
+
+/// Format a time in nanoseconds since the Unix epoch as a time of day,
+/// given the offset of the local time zone from UTC in seconds.
+fn fmt_time_of_day(time: u64, utc_offset: i64) -> String {
+    let local = time as i128 + utc_offset as i128 * 1_000_000_000;
+    let ns = local.rem_euclid(DAY_NS) as u64;
+    let seconds = ns / 1_000_000_000;
+    format!("{:02}:{:02}:{:02}.{:09}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            ns % 1_000_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::create_capture;
+    use crate::decoder::{Decoder, decode_test_capture, load_test_capture};
+
+    #[test]
+    fn test_time_formats() {
+        assert_eq!(fmt_timestamp(0), "0.000000000");
+        assert_eq!(fmt_timestamp(12_000_000_345), "12.000000345");
+        assert_eq!(fmt_delta(-1_500_000_000), "-1.500000000");
+        // 2024-03-01 23:59:59.25 UTC.
+        let time = 1_709_337_599_250_000_000;
+        assert_eq!(fmt_time_of_day(time, 0), "23:59:59.250000000");
+        assert_eq!(fmt_time_of_day(time, 3600), "00:59:59.250000000");
+        assert_eq!(fmt_time_of_day(time, -5 * 3600), "18:59:59.250000000");
+        for mode in TimeMode::ALL {
+            assert_eq!(TimeMode::from_name(mode.name()), Some(mode));
+        }
+    }
+
+    #[test]
+    fn test_time_modes() {
+        let (writer, mut reader) = create_capture().unwrap();
+        let shared = writer.shared.clone();
+        let mut decoder = Decoder::new(writer).unwrap();
+        decode_test_capture("hackrf-connect", &mut decoder, |_, _, _| {});
+        decoder.finish().unwrap();
+        let start = load_test_capture("hackrf-connect").start_time().unwrap();
+
+        let text = |reader: &mut CaptureReader, item, mode| {
+            let format = TimeFormat { mode, utc_offset: 0 };
+            format.text(reader, item).unwrap()
+        };
+        let first: TrafficItem = reader.item(None, 0).unwrap();
+        let second = reader.item(None, 1).unwrap();
+        let time = reader.timestamp(&second).unwrap();
+        let delta = time - reader.timestamp(&first).unwrap();
+
+        assert_eq!(text(&mut reader, &second, TimeMode::Relative),
+                   fmt_timestamp(time));
+        // Without a known start time, the time of day cannot be shown.
+        assert_eq!(text(&mut reader, &second, TimeMode::TimeOfDay),
+                   fmt_timestamp(time));
+        shared.start_time.set(start).unwrap();
+        assert_eq!(text(&mut reader, &second, TimeMode::TimeOfDay),
+                   fmt_time_of_day(start + time, 0));
+        assert_eq!(text(&mut reader, &first, TimeMode::Delta),
+                   fmt_timestamp(0));
+        assert_eq!(text(&mut reader, &second, TimeMode::Delta),
+                   fmt_timestamp(delta));
+        // A first child is timed from its parent.
+        let child = reader.item(Some(&second), 0).unwrap();
+        assert_eq!(text(&mut reader, &child, TimeMode::Delta),
+                   fmt_timestamp(0));
+
+        // Every item after the first SOF has a bus time.
+        let (frame, microframe) =
+            reader.item_bus_time(&second).unwrap().unwrap();
+        assert!(frame < 2048);
+        assert!(microframe < 8);
+        assert_eq!(text(&mut reader, &second, TimeMode::BusTime),
+                   format!("{frame}.{microframe}"));
+    }
+}
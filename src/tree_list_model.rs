@@ -20,6 +20,9 @@ use crate::model::GenericModel;
 use crate::row_data::GenericRowData;
 use crate::item_widget::ItemWidget;
 
+/// Number of rows to keep text for, around the most recently displayed row.
+const ROW_CACHE_SIZE: usize = 1000;
+
 type RootNodeRc<Item> = Rc<RefCell<RootNode<Item>>>;
 pub type ItemNodeRc<Item> = Rc<RefCell<ItemNode<Item>>>;
 pub type ItemNodeWeak<Item> = Weak<RefCell<ItemNode<Item>>>;
@@ -351,6 +354,10 @@ pub struct TreeListModel<Item, Model, RowData> {
     capture: RefCell<CaptureReader>,
     root: RootNodeRc<Item>,
     regions: RefCell<BTreeMap<u64, Region<Item>>>,
+    /// Summaries of recently displayed rows, by position.
+    summaries: RefCell<BTreeMap<u64, String>>,
+    /// Connectors of recently displayed rows, by position.
+    connectors: RefCell<BTreeMap<u64, String>>,
     #[cfg(any(test, feature="record-ui-test"))]
     on_item_update: Rc<RefCell<dyn FnMut(u32, String)>>,
 }
@@ -375,6 +382,8 @@ where Item: 'static + Copy + Debug,
                 complete: completion.is_complete(),
             })),
             regions: RefCell::new(BTreeMap::new()),
+            summaries: RefCell::new(BTreeMap::new()),
+            connectors: RefCell::new(BTreeMap::new()),
             #[cfg(any(test, feature="record-ui-test"))]
             on_item_update,
         })
@@ -479,6 +488,140 @@ where Item: 'static + Copy + Debug,
         Ok(())
     }
 
+    /// Expand or collapse a node and all the nodes below it.
+    ///
+    /// The region map is rebuilt once afterwards, and the model notified
+    /// of a single change, rather than of each row in turn.
+    pub fn set_subtree_expanded(&self,
+                                model: &Model,
+                                node_ref: &ItemNodeRc<Item>,
+                                position: u64,
+                                expanded: bool)
+        -> Result<(), Error>
+    {
+        let node = node_ref.borrow();
+        let was_expanded = node.expanded();
+        let rows_before = if was_expanded {
+            node.children.total_count
+        } else {
+            0
+        };
+        let parent_rc = node.parent
+            .upgrade()
+            .context("Parent dropped")?;
+        drop(node);
+
+        if !(was_expanded || expanded) {
+            return Ok(());
+        }
+
+        let any_node_ref: AnyNodeRc<Item> = node_ref.clone();
+        let rows_after = self.set_children_expanded(&any_node_ref, expanded)?;
+        let rows_after = if expanded { rows_after } else { 0 };
+
+        if was_expanded != expanded {
+            if !expanded {
+                node_ref.borrow_mut().children.incomplete.clear();
+            }
+            parent_rc
+                .borrow_mut()
+                .children_mut()
+                .set_expanded(node_ref, expanded);
+        }
+
+        if rows_after > rows_before {
+            node_ref.update_total(true, rows_after - rows_before)?;
+        } else {
+            node_ref.update_total(false, rows_before - rows_after)?;
+        }
+
+        self.rebuild_regions();
+        self.check()?;
+
+        self.apply_update(model, position + 1, ModelUpdate {
+            rows_added: rows_after,
+            rows_removed: rows_before,
+            rows_changed: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Expand or collapse every node in the tree.
+    pub fn set_all_expanded(&self, model: &Model, expanded: bool)
+        -> Result<(), Error>
+    {
+        let rows_before = self.row_count();
+        let root: AnyNodeRc<Item> = self.root.clone();
+        let rows_after = self.set_children_expanded(&root, expanded)?;
+
+        self.rebuild_regions();
+        self.check()?;
+
+        self.apply_update(model, 0, ModelUpdate {
+            rows_added: rows_after,
+            rows_removed: rows_before,
+            rows_changed: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Recursively expand or collapse all children of a node, without
+    /// updating the region map or the model, and return the node's new
+    /// total row count.
+    fn set_children_expanded(&self, node_rc: &AnyNodeRc<Item>, expanded: bool)
+        -> Result<u64, Error>
+    {
+        let direct_count = node_rc.borrow().children().direct_count;
+        let mut total_count = direct_count;
+        if expanded {
+            for index in 0..direct_count {
+                let child_rc = self.child(node_rc, index)?;
+                if child_rc.borrow().children.direct_count == 0 {
+                    continue;
+                }
+                node_rc
+                    .borrow_mut()
+                    .children_mut()
+                    .set_expanded(&child_rc, true);
+                let any_child_rc: AnyNodeRc<Item> = child_rc;
+                total_count +=
+                    self.set_children_expanded(&any_child_rc, true)?;
+            }
+        } else {
+            let expanded_children = std::mem::take(
+                &mut node_rc.borrow_mut().children_mut().expanded);
+            for child_rc in expanded_children.into_values() {
+                // Children of a collapsed node are no longer visible.
+                child_rc.borrow_mut().children.incomplete.clear();
+                let any_child_rc: AnyNodeRc<Item> = child_rc;
+                self.set_children_expanded(&any_child_rc, false)?;
+            }
+        }
+        node_rc.borrow_mut().children_mut().total_count = total_count;
+        Ok(total_count)
+    }
+
+    /// Expand the nodes leading to an item, given the index of each node
+    /// within its parent, and return the position of the item's row.
+    pub fn reveal(&self, model: &Model, path: &[u64]) -> Result<u64, Error> {
+        let mut parent_ref: AnyNodeRc<Item> = self.root.clone();
+        let mut position = 0;
+        for (depth, &index) in path.iter().enumerate() {
+            position += parent_ref.borrow().children().rows_between(0, index);
+            if depth + 1 == path.len() {
+                break;
+            }
+            let node_ref = self.fetch(position)?;
+            self.set_expanded(model, &node_ref, position, true)?;
+            parent_ref = node_ref;
+            // The node's children appear after its own row.
+            position += 1;
+        }
+        Ok(position)
+    }
+
     fn expand(&self, position: u64, node_ref: &ItemNodeRc<Item>)
         -> Result<ModelUpdate, Error>
     {
@@ -677,6 +820,23 @@ where Item: 'static + Copy + Debug,
         Ok(update)
     }
 
+    /// Replace the region map with one built from the expanded nodes.
+    fn rebuild_regions(&self) {
+        let mut regions = BTreeMap::new();
+        let root: AnyNodeRc<Item> = self.root.clone();
+        add_regions(&mut regions, &root, TopLevelItems(), &mut 0);
+        self.regions.replace(regions);
+        self.merge_regions();
+
+        #[cfg(feature="debug-region-map")] {
+            println!();
+            println!("Region map after rebuild:");
+            for (start, region) in self.regions.borrow().iter() {
+                println!("{}: {:?}", start, region);
+            }
+        }
+    }
+
     fn merge_regions(&self) {
         #[cfg(feature="debug-region-map")] {
             println!();
@@ -709,6 +869,8 @@ where Item: 'static + Copy + Debug,
     }
 
     pub fn update(&self, model: &Model) -> Result<bool, Error> {
+        // New data may extend the connecting lines of any existing row.
+        self.connectors.borrow_mut().clear();
         #[cfg(feature="debug-region-map")]
         let rows_before = self.row_count();
         self.update_node(&self.root, 0, model)?;
@@ -774,6 +936,11 @@ where Item: 'static + Copy + Debug,
             if item_updated {
                 // The node's description may change.
                 let summary = cap.description(&item_node.item, false)?;
+                if let Some(cached) =
+                    self.summaries.borrow_mut().get_mut(&position)
+                {
+                    cached.clone_from(&summary);
+                }
                 #[cfg(any(test, feature="record-ui-test"))]
                 if let Ok(position) = u32::try_from(position) {
                     let mut on_item_update = self.on_item_update.borrow_mut();
@@ -902,13 +1069,20 @@ where Item: 'static + Copy + Debug,
             ChildrenOf(node_ref) => node_ref,
         };
 
+        self.child(&parent_ref, relative_position)
+    }
+
+    /// Get the node for a child of a parent node.
+    fn child(&self, parent_ref: &AnyNodeRc<Item>, index: u64)
+        -> Result<ItemNodeRc<Item>, Error>
+    {
         // Check if we already have a node for this item in the parent's
         // expanded children.
         if let Some(node_rc) = parent_ref
             .borrow()
             .children()
             .expanded
-            .get(&relative_position)
+            .get(&index)
         {
             return Ok(node_rc.clone())
         }
@@ -917,7 +1091,7 @@ where Item: 'static + Copy + Debug,
         if let Some(node_rc) = parent_ref
             .borrow()
             .children()
-            .fetch_incomplete(relative_position)
+            .fetch_incomplete(index)
         {
             return Ok(node_rc)
         }
@@ -925,12 +1099,12 @@ where Item: 'static + Copy + Debug,
         // Otherwise, fetch it from the database.
         let mut cap = self.capture.borrow_mut();
         let mut parent = parent_ref.borrow_mut();
-        let item = cap.item(parent.item(), relative_position)?;
+        let item = cap.item(parent.item(), index)?;
         let (completion, child_count) = cap.item_children(Some(&item))?;
         let node = ItemNode {
             item,
-            parent: Rc::downgrade(&parent_ref),
-            item_index: relative_position,
+            parent: Rc::downgrade(parent_ref),
+            item_index: index,
             children: Children::new(child_count),
             widgets: RefCell::new(HashSet::new()),
         };
@@ -938,7 +1112,7 @@ where Item: 'static + Copy + Debug,
         if !completion.is_complete() {
             parent
                 .children_mut()
-                .add_incomplete(relative_position, &node_rc);
+                .add_incomplete(index, &node_rc);
         }
         Ok(node_rc)
     }
@@ -956,16 +1130,29 @@ where Item: 'static + Copy + Debug,
         }
     }
 
-    pub fn connectors(&self, item: &Item) -> String {
-        let mut cap = self.capture.borrow_mut();
-        match cap.connectors(item) {
-            Ok(string) => string,
-            Err(e) => format!("Error: {e:?}")
-        }
+    /// Summary of the item at a position, reusing it if displayed recently.
+    pub fn summary(&self, position: u64, item: &Item) -> String {
+        cached_row_text(&self.summaries, position, ||
+            self.description(item, false))
+    }
+
+    /// Connectors of the item at a position, reusing them if displayed
+    /// recently.
+    pub fn connectors(&self, position: u64, item: &Item) -> String {
+        cached_row_text(&self.connectors, position, || {
+            let mut cap = self.capture.borrow_mut();
+            match cap.connectors(item) {
+                Ok(string) => string,
+                Err(e) => format!("Error: {e:?}")
+            }
+        })
     }
 
     fn apply_update(&self, model: &Model, position: u64, update: ModelUpdate)
     {
+        // Rows from this position onwards may now show different items.
+        self.summaries.borrow_mut().split_off(&position);
+        self.connectors.borrow_mut().split_off(&position);
         if let Ok(position) = u32::try_from(position) {
             let rows_addressable = u32::MAX - position;
             let rows_removed = clamp(
@@ -998,6 +1185,71 @@ where Item: 'static + Copy + Debug,
     }
 }
 
+/// Fetch text for a row from a cache, or generate and cache it.
+fn cached_row_text<F>(cache: &RefCell<BTreeMap<u64, String>>,
+                      position: u64,
+                      generate: F)
+    -> String
+    where F: FnOnce() -> String
+{
+    if let Some(text) = cache.borrow().get(&position) {
+        return text.clone();
+    }
+    let text = generate();
+    let mut cache = cache.borrow_mut();
+    cache.insert(position, text.clone());
+    // Evict whichever cached row is furthest from this one.
+    while cache.len() > ROW_CACHE_SIZE {
+        let (Some((&first, _)), Some((&last, _))) =
+            (cache.first_key_value(), cache.last_key_value())
+        else {
+            break;
+        };
+        if position - first > last - position {
+            cache.remove(&first);
+        } else {
+            cache.remove(&last);
+        }
+    }
+    text
+}
+
+/// Add regions for the rows below a node, starting at a position.
+fn add_regions<Item>(regions: &mut BTreeMap<u64, Region<Item>>,
+                     node_rc: &AnyNodeRc<Item>,
+                     source: Source<Item>,
+                     position: &mut u64)
+    where Item: Copy + 'static
+{
+    let node = node_rc.borrow();
+    let children = node.children();
+    let mut offset = 0;
+    for (&index, child_rc) in children.expanded.iter() {
+        // The rows up to and including the expanded child.
+        let length = index + 1 - offset;
+        regions.insert(*position, Region {
+            source: source.clone(),
+            offset,
+            length,
+        });
+        *position += length;
+        // Then the rows below the expanded child.
+        let any_child_rc: AnyNodeRc<Item> = child_rc.clone();
+        add_regions(regions, &any_child_rc,
+                    ChildrenOf(child_rc.clone()), position);
+        offset = index + 1;
+    }
+    if offset < children.direct_count {
+        let length = children.direct_count - offset;
+        regions.insert(*position, Region {
+            source,
+            offset,
+            length,
+        });
+        *position += length;
+    }
+}
+
 fn clamp(value: u64, max: u32) -> u32 {
     min(value, max as u64) as u32
 }
@@ -1,14 +1,20 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::io::{BufRead, BufReader, Read};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[cfg(feature="step-decoder")]
-use std::{io::Read, net::TcpListener};
+use std::net::TcpListener;
 
 #[cfg(feature="record-ui-test")]
 use std::sync::Mutex;
 
 use anyhow::{Context as ErrorContext, Error, bail};
+use serde_json::Value;
 
 use gtk::gio::{
     self,
@@ -21,7 +27,7 @@ use gtk::gio::{
     MenuItem,
     SimpleActionGroup
 };
-use gtk::glib::{Object, SignalHandlerId};
+use gtk::glib::{self as glib, BoxedAnyObject, Object, SignalHandlerId};
 use gtk::{
     prelude::*,
     AboutDialog,
@@ -29,15 +35,22 @@ use gtk::{
     Application,
     ApplicationWindow,
     Button,
+    CheckButton,
     DropDown,
+    Entry,
     InfoBar,
     Label,
     License,
+    GestureClick,
+    ListBox,
     ListItem,
+    ListView,
     ColumnView,
     ColumnViewColumn,
     MenuButton,
     MessageType,
+    MultiSelection,
+    Notebook,
     ProgressBar,
     ResponseType,
     ScrolledWindow,
@@ -46,6 +59,12 @@ use gtk::{
     SingleSelection,
     StringList,
     TextBuffer,
+    TextTag,
+    TextView,
+    TextWindowType,
+    TreeExpander,
+    TreeListModel,
+    TreeListRow,
     Orientation,
     WrapMode,
 };
@@ -57,44 +76,115 @@ use gtk::{
     ButtonsType,
 };
 
-use crate::backend::cynthion::{
-    CynthionDevice,
-    CynthionHandle,
-    CynthionStop,
-    CynthionUsability::*,
-    Speed};
+use crate::btsnoop;
+use crate::backend::{
+    self,
+    BackendDevice,
+    BackendHandle,
+    BackendStop,
+    PacketStream,
+    Speed,
+    SpeedCheck,
+    TimestampedPacket,
+    control::{capture_control, CaptureControl},
+    filter::CaptureFilter,
+    multi::start_all,
+    rolling::{RollingLimit, RollingWindow},
+    trigger::{CaptureTrigger, TriggeredStream}};
+#[cfg(not(test))]
+use crate::backend::DeviceWatcher;
+#[cfg(not(test))]
+use crate::recovery;
 
 use crate::capture::{
     create_capture,
     CaptureReader,
     CaptureWriter,
+    DeviceId,
+    EndpointId,
     ItemSource,
+    ItemStats,
+    PacketId,
+    PacketSubset,
+    Timestamp,
+    TrafficFilter,
+    FRAMING_EP_NUM,
+    INVALID_EP_NUM,
     TrafficItem,
     DeviceItem,
 };
+use crate::class::{uac, uvc};
+use crate::context_menu::{
+    add_context_menu,
+    save_payload,
+    stream_view,
+    traffic_menu,
+    EndpointFilter,
+};
+use crate::compare::{compare, Alignment, Comparison, Row};
+use crate::copy::{CopyFormat, copy_text, item_data};
 use crate::decoder::Decoder;
+use crate::descriptors;
+use crate::detail::{field_path, fields_text, Field};
+use crate::events::{self, EventStream};
+use crate::facedancer;
+use crate::hex_dump::{byte_at, hex_dump, text_spans};
+use crate::hooks;
+use crate::i18n::tr;
 use crate::item_widget::ItemWidget;
-use crate::pcap::{Loader, Writer};
+use crate::iti1480a;
+use crate::latency::{endpoint_latency, latency_view};
+use crate::merge::Merger;
+use crate::pcap::{FileFormat, InputFormat, Loader, Writer};
+use crate::preferences::{self, ColorRule, Preferences, Theme};
+use crate::preferences_dialog::preferences_dialog;
 use crate::model::{GenericModel, TrafficModel, DeviceModel};
+use crate::native;
+use crate::recovery::Recorder;
+use crate::report::{self, ViewRow};
+use crate::session::{self, Annotation, Column, Session};
+use crate::stream::{self, FreeSpace, SpaceCheck};
+use crate::swimlane::{swimlane_view, SwimlaneState};
+use crate::throughput::{throughput_view, ThroughputState};
+use crate::timeline::{
+    dark_theme,
+    timeline_area,
+    TimelineState,
+    TIMELINE_INTERVALS,
+};
+use crate::timestamp::{fmt_timestamp, TimeFormat, TimeMode};
+use crate::query::{query, Criteria};
+use crate::search::{
+    find_error,
+    find_item,
+    find_packet,
+    target_item,
+    BytePattern,
+    Target,
+    TextPattern,
+};
+use crate::rpc;
 use crate::row_data::{
     GenericRowData,
     ToGenericRowData,
     TrafficRowData,
     DeviceRowData};
-use crate::util::{fmt_count, fmt_size};
+use crate::usb::InterfaceDescriptor;
+use crate::util::{fmt_count, fmt_duration, fmt_size};
 use crate::version::{version, version_info};
+use crate::view_export::{self, save_view_image, ViewContent};
 
 #[cfg(any(test, feature="record-ui-test"))]
-use {
-    std::rc::Rc,
-    crate::record_ui::Recording,
-};
+use crate::record_ui::Recording;
 
 static TOTAL: AtomicU64 = AtomicU64::new(0);
 static CURRENT: AtomicU64 = AtomicU64::new(0);
 static STOP: AtomicBool = AtomicBool::new(false);
 static UPDATE_INTERVAL: Duration = Duration::from_millis(10);
 
+/// Maximum number of bytes of an item to show in the hex view.
+const HEX_LIMIT: usize = 0x10000;
+
 #[cfg(feature="record-ui-test")]
 static UPDATE_LOCK: Mutex<()> = Mutex::new(());
 
@@ -114,15 +204,22 @@ enum FileAction {
 enum StopState {
     Disabled,
     Pcap(Cancellable),
-    Cynthion(CynthionStop),
+    Backend(Box<dyn BackendStop>),
 }
 
 struct DeviceSelector {
-    devices: Vec<CynthionDevice>,
+    devices: Vec<Box<dyn BackendDevice>>,
     dev_strings: Vec<String>,
     dev_speeds: Vec<Vec<&'static str>>,
+    /// Speeds at which all usable analyzers can capture together.
+    common_speeds: Vec<Speed>,
     dev_dropdown: DropDown,
     speed_dropdown: DropDown,
+    filter_entry: Entry,
+    trigger_entry: Entry,
+    keep_entry: Entry,
+    /// Speed to select, if the device supports it.
+    default_speed: Option<Speed>,
     change_handler: Option<SignalHandlerId>,
     container: gtk::Box,
 }
@@ -133,20 +230,40 @@ impl DeviceSelector {
             devices: vec![],
             dev_strings: vec![],
             dev_speeds: vec![],
+            common_speeds: vec![],
             dev_dropdown: DropDown::from_strings(&[]),
             speed_dropdown: DropDown::from_strings(&[]),
+            filter_entry: Entry::builder()
+                .placeholder_text("address=5 endpoint=1")
+                .tooltip_text(
+                    "Capture only these device addresses and endpoints")
+                .build(),
+            trigger_entry: Entry::builder()
+                .placeholder_text("request=6 packets=1000")
+                .tooltip_text(
+                    "Start recording when a packet matches, and stop after \
+                     a number of packets, bytes or seconds")
+                .build(),
+            keep_entry: Entry::builder()
+                .placeholder_text("all")
+                .width_chars(6)
+                .tooltip_text(
+                    "Keep only the most recent packets, up to a size such \
+                     as 100M or a time such as 30m")
+                .build(),
+            default_speed: None,
             change_handler: None,
             container: gtk::Box::builder()
                 .orientation(Orientation::Horizontal)
                 .build()
         };
         let device_label = Label::builder()
-            .label("Device: ")
+            .label(tr!("Device: "))
             .margin_start(2)
             .margin_end(2)
             .build();
         let speed_label = Label::builder()
-            .label(" Speed: ")
+            .label(tr!(" Speed: "))
             .margin_start(2)
             .margin_end(2)
             .build();
@@ -154,34 +271,75 @@ impl DeviceSelector {
         selector.container.append(&selector.dev_dropdown);
         selector.container.append(&speed_label);
         selector.container.append(&selector.speed_dropdown);
+        let filter_label = Label::builder()
+            .label(tr!(" Filter: "))
+            .margin_start(2)
+            .margin_end(2)
+            .build();
+        selector.container.append(&filter_label);
+        selector.container.append(&selector.filter_entry);
+        let trigger_label = Label::builder()
+            .label(tr!(" Trigger: "))
+            .margin_start(2)
+            .margin_end(2)
+            .build();
+        selector.container.append(&trigger_label);
+        selector.container.append(&selector.trigger_entry);
+        let keep_label = Label::builder()
+            .label(tr!(" Keep: "))
+            .margin_start(2)
+            .margin_end(2)
+            .build();
+        selector.container.append(&keep_label);
+        selector.container.append(&selector.keep_entry);
         Ok(selector)
     }
 
-    fn current_device(&self) -> Option<&CynthionDevice> {
-        if self.devices.is_empty() {
-            None
+    fn current_device(&self) -> Option<&dyn BackendDevice> {
+        self.devices
+            .get(self.dev_dropdown.selected() as usize)
+            .map(AsRef::as_ref)
+    }
+
+    /// Whether capturing from all usable analyzers at once is selected.
+    fn all_selected(&self) -> bool {
+        !self.common_speeds.is_empty() &&
+            self.dev_dropdown.selected() as usize == self.devices.len()
+    }
+
+    /// The usable analyzers, with their names.
+    fn usable_devices(&self)
+        -> impl Iterator<Item=(&String, &dyn BackendDevice)>
+    {
+        self.dev_strings
+            .iter()
+            .zip(self.devices.iter().map(AsRef::as_ref))
+            .filter(|(_, device)| device.speeds().is_ok())
+    }
+
+    /// Names for the analyzers capturing, if there are several.
+    fn source_names(&self) -> Vec<String> {
+        if self.all_selected() {
+            self.usable_devices().map(|(name, _)| name.clone()).collect()
         } else {
-            Some(&self.devices[self.dev_dropdown.selected() as usize])
+            vec![]
         }
     }
 
     fn device_available(&self) -> bool {
+        if self.all_selected() {
+            return true;
+        }
         match self.current_device() {
             None => false,
-            Some(device) => match device.usability {
-                Usable(..) => true,
-                Unusable(..) => false,
-            }
+            Some(device) => device.speeds().is_ok()
         }
     }
 
     fn device_unusable(&self) -> Option<&str> {
         match self.current_device() {
             None => None,
-            Some(device) => match &device.usability {
-                Usable(..) => None,
-                Unusable(string) => Some(string),
-            }
+            Some(device) => device.speeds().err()
         }
     }
 
@@ -193,32 +351,36 @@ impl DeviceSelector {
             self.dev_dropdown.set_sensitive(false);
             self.speed_dropdown.set_sensitive(false);
         }
+        self.filter_entry.set_sensitive(sensitive);
+        self.trigger_entry.set_sensitive(sensitive);
+        self.keep_entry.set_sensitive(sensitive);
     }
 
     fn scan(&mut self) -> Result<(), Error> {
         if let Some(handler) = self.change_handler.take() {
             self.dev_dropdown.disconnect(handler);
         }
-        self.devices = CynthionDevice::scan()?;
+        self.devices = backend::scan()?;
         let count = self.devices.len();
         self.dev_strings = Vec::with_capacity(count);
         self.dev_speeds = Vec::with_capacity(count);
         for device in self.devices.iter() {
             self.dev_strings.push(
                 if count <= 1 {
-                    String::from("Cynthion")
+                    String::from(device.name())
                 } else {
-                    let info = &device.device_info;
+                    let info = device.device_info();
                     if let Some(serial) = info.serial_number() {
-                        format!("Cynthion #{}", serial)
+                        format!("{} #{}", device.name(), serial)
                     } else {
-                        format!("Cynthion (bus {}, device {})",
+                        format!("{} (bus {}, device {})",
+                            device.name(),
                             info.bus_number(),
                             info.device_address())
                     }
                 }
             );
-            if let Usable(_, speeds) = &device.usability {
+            if let Ok(speeds) = device.speeds() {
                 self.dev_speeds.push(
                     speeds.iter().map(Speed::description).collect()
                 )
@@ -226,10 +388,29 @@ impl DeviceSelector {
                 self.dev_speeds.push(vec![]);
             }
         }
+        // Offer to capture from all usable analyzers at once, at the
+        // speeds they have in common.
+        let usable: Vec<&[Speed]> = self.usable_devices()
+            .filter_map(|(_, device)| device.speeds().ok())
+            .collect();
+        self.common_speeds = match usable.as_slice() {
+            [first, rest @ ..] if !rest.is_empty() => first
+                .iter()
+                .copied()
+                .filter(|speed| rest.iter().all(|s| s.contains(speed)))
+                .collect(),
+            _ => vec![],
+        };
+        if !self.common_speeds.is_empty() {
+            self.dev_strings.push(String::from("All analyzers"));
+            self.dev_speeds.push(
+                self.common_speeds.iter().map(Speed::description).collect());
+        }
         let no_speeds = vec![];
         let speed_strings = self.dev_speeds.first().unwrap_or(&no_speeds);
         self.replace_dropdown(&self.dev_dropdown, &self.dev_strings);
         self.replace_dropdown(&self.speed_dropdown, speed_strings);
+        self.select_default_speed(speed_strings);
         self.dev_dropdown.set_sensitive(!self.devices.is_empty());
         self.speed_dropdown.set_sensitive(!speed_strings.is_empty());
         self.change_handler = Some(
@@ -242,20 +423,48 @@ impl DeviceSelector {
         let index = self.dev_dropdown.selected() as usize;
         let speed_strings = &self.dev_speeds[index];
         self.replace_dropdown(&self.speed_dropdown, speed_strings);
+        self.select_default_speed(speed_strings);
         self.speed_dropdown.set_sensitive(!speed_strings.is_empty());
     }
 
-    fn open(&self) -> Result<(CynthionHandle, Speed), Error> {
+    /// Select the default speed, if it is one of those offered.
+    fn select_default_speed(&self, speed_strings: &[&str]) {
+        if let Some(index) = self.default_speed.and_then(|speed|
+            speed_strings.iter().position(|s| *s == speed.description()))
+        {
+            self.speed_dropdown.set_selected(index as u32);
+        }
+    }
+
+    fn filter(&self) -> Result<CaptureFilter, Error> {
+        CaptureFilter::parse(self.filter_entry.text().as_str())
+    }
+
+    fn trigger(&self) -> Result<CaptureTrigger, Error> {
+        CaptureTrigger::parse(self.trigger_entry.text().as_str())
+    }
+
+    fn rolling_limit(&self) -> Result<Option<RollingLimit>, Error> {
+        RollingLimit::parse(self.keep_entry.text().as_str())
+    }
+
+    fn open(&self) -> Result<(Vec<Box<dyn BackendHandle>>, Speed), Error> {
+        let speed_id = self.speed_dropdown.selected() as usize;
+        if self.all_selected() {
+            let handles = self.usable_devices()
+                .map(|(_, device)| device.open())
+                .collect::<Result<Vec<_>, Error>>()?;
+            return Ok((handles, self.common_speeds[speed_id]));
+        }
         let device_id = self.dev_dropdown.selected();
         let device = &self.devices[device_id as usize];
-        match &device.usability {
-            Usable(_, speeds) => {
-                let speed_id = self.speed_dropdown.selected() as usize;
+        match device.speeds() {
+            Ok(speeds) => {
                 let speed = speeds[speed_id];
-                let cynthion = device.open()?;
-                Ok((cynthion, speed))
+                let handle = device.open()?;
+                Ok((vec![handle], speed))
             },
-            Unusable(reason) => {
+            Err(reason) => {
                 bail!("Device not usable: {}", reason)
             }
         }
@@ -302,43 +511,522 @@ impl DeviceWarning {
 
     fn update(&self, warning: Option<&str>) {
         if let Some(reason) = warning {
-            self.info_bar.set_message_type(MessageType::Warning);
-            self.label.set_text(&format!(
+            self.show(&format!(
                 "This device is not usable because: {reason}"));
-            self.info_bar.set_revealed(true);
         } else {
             self.info_bar.set_revealed(false);
         }
     }
+
+    fn show(&self, message: &str) {
+        self.info_bar.set_message_type(MessageType::Warning);
+        self.label.set_text(message);
+        self.info_bar.set_revealed(true);
+    }
 }
 
 pub struct UserInterface {
     pub capture: CaptureReader,
     selector: DeviceSelector,
     file_name: Option<String>,
+    capture_file: Option<gio::File>,
+    session: Session,
+    preferences: Preferences,
+    /// Menu of the capture files opened recently.
+    recent_menu: Menu,
+    /// Styles showing the items matching each color rule.
+    color_css: gtk::CssProvider,
     stop_state: StopState,
+    /// Speed of the bus being captured, if detected.
+    bus_speed: Option<Speed>,
+    /// Whether capture is waiting for its trigger to start recording.
+    waiting_for_trigger: bool,
+    /// Control of the capture in progress, if any.
+    capture_control: Option<CaptureControl>,
+    /// Whether the capture in progress is paused.
+    paused: bool,
+    /// Number of markers added to the capture in progress.
+    marker_count: u32,
     traffic_window: ScrolledWindow,
+    /// Overview of the capture above the traffic view.
+    timeline_area: gtk::DrawingArea,
+    timeline: Rc<RefCell<TimelineState>>,
+    /// Number of packets when the timeline was last updated, and when.
+    timeline_packets: u64,
+    timeline_updated: Instant,
+    /// Following of new traffic during live capture.
+    follow: Rc<FollowState>,
+    /// How timestamps are shown in the traffic view.
+    time_format: TimeFormat,
     device_window: ScrolledWindow,
+    /// Whether to show only the traffic of the selected device.
+    device_filter: CheckButton,
+    /// Whether to hide groups of SOF packets.
+    hide_sof: CheckButton,
+    /// Whether to describe groups of SOF packets as idle periods.
+    collapse_idle: CheckButton,
+    /// Whether to describe groups of NAKed transactions by their count.
+    collapse_polling: CheckButton,
+    /// Filter entered for the traffic view.
+    view_filter: TrafficFilter,
+    /// Filter applied to the traffic view, including any device filter.
+    traffic_filter: TrafficFilter,
+    /// Bookmarks and comments of the session, in that order.
+    bookmark_list: ListBox,
+    /// Text to attach to the selected item as a bookmark or comment.
+    note_entry: Entry,
+    /// Entry for the filter of the traffic view.
+    view_filter_entry: Entry,
+    /// Menu of actions on an item, shown by right-clicking it.
+    traffic_menu: gtk::PopoverMenu,
     pub traffic_model: Option<TrafficModel>,
     pub device_model: Option<DeviceModel>,
+    selected_device_item: Option<DeviceItem>,
+    selected_traffic_item: Option<TrafficItem>,
+    /// Totals over the items selected, if there are several.
+    selection_stats: Option<ItemStats>,
     detail_text: TextBuffer,
+    detail_tree: ListView,
+    /// Fields of the item shown in the detail pane.
+    detail_fields: Vec<Field>,
+    hex_view: TextView,
+    /// Number of bytes shown in the hex view.
+    hex_length: usize,
     endpoint_count: u16,
     show_progress: Option<FileAction>,
+    /// When the operation whose progress is shown started.
+    progress_start: Instant,
     progress_bar: ProgressBar,
+    /// The progress bar, with a button to cancel the operation.
+    progress_box: gtk::Box,
     separator: Separator,
     vbox: gtk::Box,
+    horizontal_panes: gtk::Paned,
     vertical_panes: gtk::Paned,
+    detail_panes: gtk::Paned,
+    hex_panes: gtk::Paned,
     open_button: Button,
     save_button: Button,
     scan_button: Button,
     capture_button: Button,
     stop_button: Button,
+    pause_button: Button,
+    marker_button: Button,
     status_label: Label,
     warning: DeviceWarning,
+    /// Actions of the main menu.
+    actions: SimpleActionGroup,
     #[cfg(any(test, feature="record-ui-test"))]
     pub recording: Rc<RefCell<Recording>>,
 }
 
+impl UserInterface {
+    /// The columns of the Traffic pane.
+    fn traffic_columns(&self) -> Vec<ColumnViewColumn> {
+        let Some(view) = self.traffic_window
+            .child()
+            .and_then(|child| child.downcast::<ColumnView>().ok())
+        else {
+            return Vec::new();
+        };
+        let columns = view.columns();
+        (0..columns.n_items())
+            .filter_map(|i| columns.item(i))
+            .filter_map(|column| column.downcast::<ColumnViewColumn>().ok())
+            .collect()
+    }
+
+    /// Show the fields and raw bytes of an item in the detail pane.
+    fn show_detail(&mut self, fields: Vec<Field>, bytes: &[u8]) {
+        let mut text = hex_dump(bytes);
+        if bytes.len() >= HEX_LIMIT {
+            text += &format!("\n(first {} shown)", fmt_size(HEX_LIMIT as u64));
+        }
+        self.hex_view.buffer().set_text(&text);
+        self.hex_length = bytes.len();
+        show_fields(&self.detail_tree, &self.hex_view, &fields, bytes.len());
+        self.detail_fields = fields;
+    }
+
+    /// Select the field decoded from a byte shown in the hex view.
+    fn select_field_at(&self, x: f64, y: f64) {
+        let (x, y) = self.hex_view.window_to_buffer_coords(
+            TextWindowType::Widget, x as i32, y as i32);
+        let Some(iter) = self.hex_view.iter_at_location(x, y) else {
+            return;
+        };
+        let line = iter.line() as usize;
+        let column = iter.line_offset() as usize;
+        let Some(offset) = byte_at(self.hex_length, line, column) else {
+            return;
+        };
+        let path = field_path(&self.detail_fields, offset);
+        let Some(selection) = self.detail_tree
+            .model()
+            .and_downcast::<SingleSelection>()
+        else {
+            return;
+        };
+        let Some(tree) = selection.model().and_downcast::<TreeListModel>()
+        else {
+            return;
+        };
+        if let Some(position) = expand_path(&tree, &path) {
+            selection.set_selected(position);
+        }
+    }
+
+    /// Record the current layout of the window in the session.
+    fn record_layout(&mut self) {
+        self.session.panes = vec![
+            ("traffic".to_string(), self.horizontal_panes.position()),
+            ("detail".to_string(), self.vertical_panes.position()),
+            ("fields".to_string(), self.detail_panes.position()),
+            ("hex".to_string(), self.hex_panes.position()),
+        ];
+        self.session.columns = self
+            .traffic_columns()
+            .iter()
+            .map(|column| Column {
+                name: column.title().map(String::from).unwrap_or_default(),
+                width: column.fixed_width(),
+                visible: column.is_visible(),
+            })
+            .collect();
+        let filter = self.view_filter_entry.text();
+        self.session.filters = if filter.is_empty() {
+            Vec::new()
+        } else {
+            vec![filter.to_string()]
+        };
+    }
+
+    /// Restore the layout of the window recorded in the session.
+    fn apply_layout(&mut self) {
+        if let Some(position) = self.session.pane("traffic") {
+            self.horizontal_panes.set_position(position);
+        }
+        if let Some(position) = self.session.pane("detail") {
+            self.vertical_panes.set_position(position);
+        }
+        if let Some(position) = self.session.pane("fields") {
+            self.detail_panes.set_position(position);
+        }
+        if let Some(position) = self.session.pane("hex") {
+            self.hex_panes.set_position(position);
+        }
+        for column in self.traffic_columns() {
+            let title = column.title().map(String::from).unwrap_or_default();
+            if let Some(layout) = self.session.columns
+                .iter()
+                .find(|layout| layout.name == title)
+            {
+                column.set_fixed_width(layout.width);
+                column.set_visible(layout.visible);
+            }
+        }
+        self.update_column_actions();
+        if let Some(filter) = self.session.filters.first().cloned() {
+            self.view_filter_entry.set_text(&filter);
+            display_error(TrafficFilter::parse(&filter).and_then(|filter| {
+                self.view_filter = filter;
+                self.update_traffic_filter()
+            }));
+        }
+    }
+
+    /// Update the session saved alongside the capture file with the
+    /// current layout and filter, if a session has been saved for it.
+    fn update_session(&mut self) -> Result<(), Error> {
+        if let Some(file) = self.capture_file.clone() {
+            if session_file(&file)?.query_exists(Cancellable::NONE) {
+                self.record_layout();
+                save_session(&file, &self.session)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record that a capture file has been opened or saved.
+    fn add_recent_file(&mut self, file: &gio::File) -> Result<(), Error> {
+        self.preferences.add_recent_file(&file.uri());
+        self.update_recent_menu();
+        save_preferences(&self.preferences)
+    }
+
+    /// List the recently opened files in their menu.
+    fn update_recent_menu(&self) {
+        self.recent_menu.remove_all();
+        for uri in &self.preferences.recent_files {
+            let name = gio::File::for_uri(uri).parse_name();
+            let item = MenuItem::new(Some(&name), None);
+            item.set_action_and_target_value(
+                Some("actions.open-recent"), Some(&uri.to_variant()));
+            self.recent_menu.append_item(&item);
+        }
+    }
+
+    /// Update the overview of the capture shown in the timeline.
+    fn update_timeline(&mut self) -> Result<(), Error> {
+        self.timeline.borrow_mut().timeline =
+            self.capture.timeline(TIMELINE_INTERVALS)?;
+        self.timeline_packets = self.capture.packet_index.len();
+        self.timeline_updated = Instant::now();
+        self.timeline_area.queue_draw();
+        Ok(())
+    }
+
+    /// Time span of the rows visible in the traffic view.
+    fn visible_time_range(&self) -> Option<Range<Timestamp>> {
+        let model = self.traffic_model.clone()?;
+        let rows = model.n_items();
+        let adjustment = self.traffic_window.vadjustment();
+        let upper = adjustment.upper();
+        if rows == 0 || upper <= 0.0 {
+            return None;
+        }
+        let top = adjustment.value() / upper;
+        let bottom = (adjustment.value() + adjustment.page_size()) / upper;
+        let first = ((top * rows as f64) as u32).min(rows - 1);
+        let last = ((bottom * rows as f64).ceil() as u32)
+            .clamp(first + 1, rows) - 1;
+        let mut times = Vec::new();
+        for position in [first, last] {
+            let row = model.item(position).and_downcast::<TrafficRowData>()?;
+            let item: TrafficItem = row.node().ok()?.borrow().item;
+            times.push(model.timestamp(&item));
+        }
+        Some(times[0]..(times[1] + 1))
+    }
+
+    /// Show or hide the Traffic column with the given title.
+    fn set_column_visible(&self, title: &str, visible: bool) {
+        for column in self.traffic_columns() {
+            if column.title().as_deref() == Some(title) {
+                column.set_visible(visible);
+            }
+        }
+    }
+
+    /// Update the states of the column actions to match the columns shown.
+    fn update_column_actions(&self) {
+        for column in self.traffic_columns() {
+            let Some(title) = column.title() else { continue };
+            let Some(traffic_column) = TrafficColumn::ALL
+                .into_iter()
+                .find(|c| c.title() == title.as_str())
+            else {
+                continue
+            };
+            if let Some(action) = self.actions
+                .lookup_action(traffic_column.action())
+                .and_downcast::<gio::SimpleAction>()
+            {
+                action.set_state(&column.is_visible().to_variant());
+            }
+        }
+    }
+
+    /// The bookmarks and comments of the session, in the order listed.
+    fn annotations(&self) -> impl Iterator<Item=(bool, &Annotation)> {
+        let bookmarks = self.session.bookmarks
+            .iter()
+            .map(|bookmark| (true, bookmark));
+        let comments = self.session.comments
+            .iter()
+            .map(|comment| (false, comment));
+        bookmarks.chain(comments)
+    }
+
+    /// List the bookmarks and comments of the session.
+    fn show_bookmarks(&self) {
+        while let Some(row) = self.bookmark_list.row_at_index(0) {
+            self.bookmark_list.remove(&row);
+        }
+        let mut capture = self.capture.clone();
+        for (bookmark, annotation) in self.annotations() {
+            let description = capture
+                .description(&annotation.item, false)
+                .unwrap_or_else(|_| "Item not loaded".to_string());
+            let label = Label::builder()
+                .label(format!("{}: {}\n{description}",
+                               if bookmark { "Bookmark" } else { "Comment" },
+                               annotation.text))
+                .halign(Align::Start)
+                .xalign(0.0)
+                .wrap(true)
+                .margin_start(3)
+                .margin_top(2)
+                .margin_bottom(2)
+                .build();
+            self.bookmark_list.append(&label);
+        }
+    }
+
+    /// Attach the text entered as a bookmark of, or a comment on, the
+    /// selected item.
+    fn annotate(&mut self, bookmark: bool) -> Result<(), Error> {
+        let item = self.selected_traffic_item()?;
+        let text = self.note_entry.text().trim().to_string();
+        if bookmark {
+            let text = if text.is_empty() {
+                self.capture.description(&item, false)?
+            } else {
+                text
+            };
+            self.session.add_bookmark(item, text);
+        } else {
+            if text.is_empty() {
+                bail!("Enter the text of the comment first")
+            }
+            self.session.set_comment(item, text);
+        }
+        self.note_entry.set_text("");
+        self.show_bookmarks();
+        Ok(())
+    }
+
+    /// Remove the bookmark or comment selected in the list.
+    fn remove_annotation(&mut self) -> Result<(), Error> {
+        let row = self.bookmark_list
+            .selected_row()
+            .context("Select a bookmark or comment first")?;
+        let index = row.index() as usize;
+        let bookmarks = self.session.bookmarks.len();
+        if index < bookmarks {
+            self.session.bookmarks.remove(index);
+        } else if index - bookmarks < self.session.comments.len() {
+            self.session.comments.remove(index - bookmarks);
+        }
+        self.show_bookmarks();
+        Ok(())
+    }
+
+    /// Find the row of an item in the traffic view, to be selected with
+    /// select_traffic_row once outside of with_ui.
+    fn traffic_row(&self, path: Vec<u64>) -> Result<TrafficRow, Error> {
+        let model = self.traffic_model.clone().context("No traffic shown")?;
+        Ok(TrafficRow {
+            model,
+            window: self.traffic_window.clone(),
+            path,
+        })
+    }
+
+    fn selected_device(&self) -> Result<DeviceId, Error> {
+        use DeviceItem::*;
+        Ok(match self.selected_device_item {
+            Some(Device(dev, _) |
+                 DeviceDescriptor(dev) |
+                 DeviceDescriptorField(dev, ..) |
+                 Configuration(dev, _) |
+                 ConfigurationDescriptor(dev, _) |
+                 ConfigurationDescriptorField(dev, ..) |
+                 Interface(dev, ..) |
+                 InterfaceDescriptor(dev, ..) |
+                 InterfaceDescriptorField(dev, ..) |
+                 EndpointDescriptor(dev, ..) |
+                 EndpointDescriptorField(dev, ..)) => dev,
+            None => bail!("Select a device in the Devices pane first")
+        })
+    }
+
+    /// Apply the filter entered to the traffic view, showing only the
+    /// traffic of the selected device if that is enabled, and hiding or
+    /// collapsing SOF groups and polling as chosen.
+    fn update_traffic_filter(&mut self) -> Result<(), Error> {
+        let mut filter = self.view_filter.clone();
+        if self.device_filter.is_active() {
+            filter.device = self.selected_device().ok();
+        }
+        self.apply_toggles(&mut filter);
+        if filter != self.traffic_filter {
+            self.traffic_filter = filter;
+            show_traffic(self);
+        }
+        Ok(())
+    }
+
+    /// Show the totals of the capture, and of the items selected if there
+    /// are several, in the status bar.
+    fn update_status(&self) {
+        let (devices, endpoints, transactions, packets) = {
+            let cap = &self.capture;
+            let devices = cap.devices.len().saturating_sub(1);
+            let endpoints = cap.endpoints.len().saturating_sub(2);
+            let transactions = cap.transaction_index.len();
+            let packets = cap.packet_index.len();
+            (devices, endpoints, transactions, packets)
+        };
+        let mut status = tr!(
+            "{}: {} devices, {} endpoints, {} transactions, {} packets",
+            self.file_name.as_deref().unwrap_or(tr!("Unsaved capture")),
+            fmt_count(devices),
+            fmt_count(endpoints),
+            fmt_count(transactions),
+            fmt_count(packets)
+        );
+        if let Some(speed) = self.bus_speed {
+            status.push_str(&tr!(", bus speed {}", speed.description()));
+        }
+        if self.waiting_for_trigger {
+            status.push_str(tr!(", waiting for trigger"));
+        }
+        if self.paused {
+            status.push_str(tr!(", paused"));
+        }
+        if let Some(stats) = &self.selection_stats {
+            status.push_str(&tr!(
+                "; {} selected: {} packets, {}",
+                fmt_count(stats.items),
+                fmt_count(stats.packets),
+                fmt_size(stats.bytes)));
+            if let Some(range) = &stats.time_range {
+                status.push_str(&tr!(
+                    " over {}", fmt_duration(range.end - range.start)));
+            }
+            if let Some(rate) = stats.throughput() {
+                status.push_str(&format!(", {}/s", fmt_size(rate as u64)));
+            }
+        }
+        self.status_label.set_text(&status);
+    }
+
+    /// Add the hiding and collapsing chosen with the toggles to a filter.
+    fn apply_toggles(&self, filter: &mut TrafficFilter) {
+        filter.hide_sof |= self.hide_sof.is_active();
+        filter.collapse_idle = self.collapse_idle.is_active();
+        filter.collapse_polling = self.collapse_polling.is_active();
+    }
+
+    fn selected_traffic_item(&self) -> Result<TrafficItem, Error> {
+        self.selected_traffic_item
+            .context("Select an item in the Traffic pane first")
+    }
+
+    fn selected_endpoint(&mut self)
+        -> Result<(EndpointId, InterfaceDescriptor), Error>
+    {
+        use DeviceItem::*;
+        match self.selected_device_item {
+            Some(EndpointDescriptor(dev, conf, iface, ep) |
+                 EndpointDescriptorField(dev, conf, iface, ep, ..)) =>
+            {
+                let config = self.capture
+                    .try_configuration(&dev, &conf)
+                    .context("No descriptor for selected configuration")?;
+                let interface = config.interface(&iface)?;
+                let ep_desc = interface.endpoint_descriptor(&ep)?;
+                let endpoint_id = self.capture
+                    .endpoint_id(dev, ep_desc.endpoint_address)?
+                    .context("No traffic seen on the selected endpoint")?;
+                Ok((endpoint_id, interface.descriptor))
+            },
+            _ => bail!("Select an endpoint in the Devices pane first")
+        }
+    }
+}
+
 pub fn with_ui<F>(f: F) -> Result<(), Error>
     where F: FnOnce(&mut UserInterface) -> Result<(), Error>
 {
@@ -379,8 +1067,10 @@ pub fn activate(application: &Application) -> Result<(), Error> {
         button_action!("open", open_button, choose_file(Load)),
         button_action!("save", save_button, choose_file(Save)),
         button_action!("scan", scan_button, detect_hardware()),
-        button_action!("capture", capture_button, start_cynthion()),
+        button_action!("capture", capture_button, start_capture()),
         button_action!("stop", stop_button, stop_operation()),
+        button_action!("pause", pause_button, pause_capture()),
+        button_action!("mark", marker_button, add_marker()),
     ]);
 
     #[cfg(not(target_os="macos"))]
@@ -390,8 +1080,27 @@ pub fn activate(application: &Application) -> Result<(), Error> {
         application.set_accels_for_action("win.scan", &["<Ctrl>r", "F5"]);
         application.set_accels_for_action("win.capture", &["<Ctrl>b"]);
         application.set_accels_for_action("win.stop", &["<Ctrl>e"]);
+        application.set_accels_for_action("win.pause", &["<Ctrl>p"]);
+        application.set_accels_for_action("win.mark", &["<Ctrl>m"]);
+        application.set_accels_for_action("actions.go-to", &["<Ctrl>g"]);
+        application.set_accels_for_action("actions.copy-text",
+            &["<Ctrl><Shift>c"]);
+        application.set_accels_for_action("actions.copy-hex",
+            &["<Ctrl><Shift>h"]);
+        application.set_accels_for_action("actions.expand-subtree",
+            &["<Ctrl>plus", "<Ctrl>KP_Add"]);
+        application.set_accels_for_action("actions.collapse-subtree",
+            &["<Ctrl>minus", "<Ctrl>KP_Subtract"]);
+        application.set_accels_for_action("actions.expand-all",
+            &["<Ctrl>asterisk", "<Ctrl>KP_Multiply"]);
+        application.set_accels_for_action("actions.collapse-all",
+            &["<Ctrl>slash", "<Ctrl>KP_Divide"]);
     }
 
+    application.set_accels_for_action("actions.next-error", &["F8"]);
+    application.set_accels_for_action("actions.previous-error",
+        &["<Shift>F8"]);
+
     #[cfg(target_os="macos")]
     {
         application.set_accels_for_action("win.open", &["<Meta>o"]);
@@ -399,54 +1108,412 @@ pub fn activate(application: &Application) -> Result<(), Error> {
         application.set_accels_for_action("win.scan", &["<Meta>r", "F5"]);
         application.set_accels_for_action("win.capture", &["<Meta>b"]);
         application.set_accels_for_action("win.stop", &["<Meta>e"]);
+        application.set_accels_for_action("win.pause", &["<Meta>p"]);
+        application.set_accels_for_action("win.mark", &["<Meta>m"]);
+        application.set_accels_for_action("actions.go-to", &["<Meta>g"]);
+        application.set_accels_for_action("actions.copy-text",
+            &["<Meta><Shift>c"]);
+        application.set_accels_for_action("actions.copy-hex",
+            &["<Meta><Shift>h"]);
+        application.set_accels_for_action("actions.expand-subtree",
+            &["<Meta>plus", "<Meta>KP_Add"]);
+        application.set_accels_for_action("actions.collapse-subtree",
+            &["<Meta>minus", "<Meta>KP_Subtract"]);
+        application.set_accels_for_action("actions.expand-all",
+            &["<Meta>asterisk", "<Meta>KP_Multiply"]);
+        application.set_accels_for_action("actions.collapse-all",
+            &["<Meta>slash", "<Meta>KP_Divide"]);
     }
 
     let action_bar = gtk::ActionBar::new();
 
     let open_button = gtk::Button::builder()
         .icon_name("document-open")
-        .tooltip_text("Open")
+        .tooltip_text(tr!("Open"))
         .action_name("win.open")
         .build();
     let save_button = gtk::Button::builder()
         .icon_name("document-save")
-        .tooltip_text("Save")
+        .tooltip_text(tr!("Save"))
         .action_name("win.save")
         .build();
     let scan_button = gtk::Button::builder()
         .icon_name("view-refresh")
-        .tooltip_text("Scan for devices")
+        .tooltip_text(tr!("Scan for devices"))
         .action_name("win.scan")
         .build();
     let capture_button = gtk::Button::builder()
         .icon_name("media-record")
-        .tooltip_text("Capture")
+        .tooltip_text(tr!("Capture"))
         .action_name("win.capture")
         .build();
     let stop_button = gtk::Button::builder()
         .icon_name("media-playback-stop")
-        .tooltip_text("Stop")
+        .tooltip_text(tr!("Stop"))
         .action_name("win.stop")
         .build();
+    let pause_button = gtk::Button::builder()
+        .icon_name("media-playback-pause")
+        .tooltip_text(tr!("Pause"))
+        .action_name("win.pause")
+        .build();
+    let marker_button = gtk::Button::builder()
+        .icon_name("bookmark-new")
+        .tooltip_text(tr!("Add marker"))
+        .action_name("win.mark")
+        .build();
 
     open_button.set_sensitive(true);
     save_button.set_sensitive(false);
     scan_button.set_sensitive(true);
+    pause_button.set_sensitive(false);
+    marker_button.set_sensitive(false);
+
+    let preferences = load_preferences().unwrap_or_else(|e| {
+        display_error(Err(e));
+        Preferences::default()
+    });
 
-    let selector = DeviceSelector::new()?;
+    let mut selector = DeviceSelector::new()?;
+    selector.default_speed = preferences.speed;
     capture_button.set_sensitive(selector.device_available());
 
     let menu = Menu::new();
-    let about_item = MenuItem::new(Some("About..."), Some("actions.about"));
+    let export_data_item = MenuItem::new(
+        Some(tr!("Export endpoint data...")), Some("actions.export-data"));
+    let export_audio_item = MenuItem::new(
+        Some(tr!("Export audio as WAV...")), Some("actions.export-audio"));
+    let export_video_item = MenuItem::new(
+        Some(tr!("Export video frames...")), Some("actions.export-video"));
+    let export_hci_item = MenuItem::new(
+        Some(tr!("Export Bluetooth HCI as btsnoop...")),
+        Some("actions.export-hci"));
+    let export_facedancer_item = MenuItem::new(
+        Some(tr!("Export device as Facedancer script...")),
+        Some("actions.export-facedancer"));
+    let export_item_item = MenuItem::new(
+        Some(tr!("Export selected item...")), Some("actions.export-item"));
+    let export_time_item = MenuItem::new(
+        Some(tr!("Export traffic during selected item...")),
+        Some("actions.export-time"));
+    let export_device_item = MenuItem::new(
+        Some(tr!("Export selected device traffic...")),
+        Some("actions.export-device"));
+    let export_text_item = MenuItem::new(
+        Some(tr!("Export traffic as text...")), Some("actions.export-text"));
+    let export_hex_item = MenuItem::new(
+        Some(tr!("Export traffic as text with hex dumps...")),
+        Some("actions.export-hex"));
+    let export_html_item = MenuItem::new(
+        Some(tr!("Export traffic as HTML...")), Some("actions.export-html"));
+    let export_item_html_item = MenuItem::new(
+        Some(tr!("Export selected item as HTML...")),
+        Some("actions.export-item-html"));
+    let export_image_item = MenuItem::new(
+        Some(tr!("Export view as image...")), Some("actions.export-image"));
+    let print_view_item = MenuItem::new(
+        Some(tr!("Print view...")), Some("actions.print-view"));
+    let export_lsusb_item = MenuItem::new(
+        Some(tr!("Export descriptors as lsusb text...")),
+        Some("actions.export-lsusb"));
+    let export_c_item = MenuItem::new(
+        Some(tr!("Export descriptors as C...")), Some("actions.export-c"));
+    let save_session_item = MenuItem::new(
+        Some(tr!("Save session")), Some("actions.save-session"));
+    let go_to_item = MenuItem::new(
+        Some(tr!("Go to...")), Some("actions.go-to"));
+    let next_error_item = MenuItem::new(
+        Some(tr!("Go to next error")), Some("actions.next-error"));
+    let previous_error_item = MenuItem::new(
+        Some(tr!("Go to previous error")), Some("actions.previous-error"));
+    let expand_subtree_item = MenuItem::new(
+        Some(tr!("Expand selected item")), Some("actions.expand-subtree"));
+    let collapse_subtree_item = MenuItem::new(
+        Some(tr!("Collapse selected item")), Some("actions.collapse-subtree"));
+    let expand_all_item = MenuItem::new(
+        Some(tr!("Expand all")), Some("actions.expand-all"));
+    let collapse_all_item = MenuItem::new(
+        Some(tr!("Collapse all")), Some("actions.collapse-all"));
+    let color_rules_item = MenuItem::new(
+        Some(tr!("Color rules...")), Some("actions.color-rules"));
+    let preferences_item = MenuItem::new(
+        Some(tr!("Preferences...")), Some("actions.preferences"));
+    let throughput_item = MenuItem::new(
+        Some(tr!("Throughput graph...")), Some("actions.throughput"));
+    let swimlanes_item = MenuItem::new(
+        Some(tr!("Endpoint swimlanes...")), Some("actions.swimlanes"));
+    let latency_item = MenuItem::new(
+        Some(tr!("Latency...")), Some("actions.latency"));
+    let metrics_item = MenuItem::new(
+        Some(tr!("Script metrics...")), Some("actions.metrics"));
+    let compare_item = MenuItem::new(
+        Some(tr!("Compare with capture...")), Some("actions.compare"));
+    let about_item = MenuItem::new(
+        Some(tr!("About...")), Some("actions.about"));
+    let recent_menu = Menu::new();
+    let window_menu = Menu::new();
+    window_menu.append_submenu(Some(tr!("Open recent")), &recent_menu);
+    window_menu.append(Some(tr!("New window")), Some("actions.new-window"));
+    window_menu.append(
+        Some(tr!("Open in new window...")), Some("actions.open-new-window"));
+    menu.append_section(None, &window_menu);
+    menu.append_item(&go_to_item);
+    menu.append_item(&next_error_item);
+    menu.append_item(&previous_error_item);
+    let copy_menu = Menu::new();
+    for format in CopyFormat::ALL {
+        copy_menu.append(
+            Some(format.label()),
+            Some(&format!("actions.{}", format.action())));
+    }
+    menu.append_submenu(Some(tr!("Copy selected item")), &copy_menu);
+    menu.append_item(&expand_subtree_item);
+    menu.append_item(&collapse_subtree_item);
+    menu.append_item(&expand_all_item);
+    menu.append_item(&collapse_all_item);
+    menu.append_item(&throughput_item);
+    menu.append_item(&swimlanes_item);
+    menu.append_item(&latency_item);
+    menu.append_item(&metrics_item);
+    menu.append_item(&compare_item);
+    menu.append_item(&export_item_item);
+    menu.append_item(&export_time_item);
+    menu.append_item(&export_device_item);
+    menu.append_item(&export_text_item);
+    menu.append_item(&export_hex_item);
+    menu.append_item(&export_html_item);
+    menu.append_item(&export_item_html_item);
+    menu.append_item(&export_image_item);
+    menu.append_item(&print_view_item);
+    menu.append_item(&export_lsusb_item);
+    menu.append_item(&export_c_item);
+    menu.append_item(&export_data_item);
+    menu.append_item(&export_audio_item);
+    menu.append_item(&export_video_item);
+    menu.append_item(&export_hci_item);
+    menu.append_item(&export_facedancer_item);
+    let columns_menu = Menu::new();
+    for column in TrafficColumn::ALL {
+        columns_menu.append(
+            Some(column.label()),
+            Some(&format!("actions.{}", column.action())));
+    }
+    menu.append_submenu(Some(tr!("Columns")), &columns_menu);
+    let time_menu = Menu::new();
+    for mode in TimeMode::ALL {
+        time_menu.append(
+            Some(mode.label()),
+            Some(&format!("actions.time-mode::{}", mode.name())));
+    }
+    menu.append_submenu(Some(tr!("Time format")), &time_menu);
+    menu.append_item(&color_rules_item);
+    menu.append_item(&preferences_item);
+    menu.append_item(&save_session_item);
     menu.append_item(&about_item);
     let menu_button = MenuButton::builder()
         .menu_model(&menu)
         .build();
     let action_group = SimpleActionGroup::new();
+    let action_export_data = ActionEntry::builder("export-data")
+        .activate(|_, _, _| display_error(choose_data_export()))
+        .build();
+    let action_export_audio = ActionEntry::builder("export-audio")
+        .activate(|_, _, _| display_error(choose_audio_export()))
+        .build();
+    let action_export_video = ActionEntry::builder("export-video")
+        .activate(|_, _, _| display_error(choose_video_export()))
+        .build();
+    let action_export_hci = ActionEntry::builder("export-hci")
+        .activate(|_, _, _| display_error(choose_hci_export()))
+        .build();
+    let action_export_facedancer = ActionEntry::builder("export-facedancer")
+        .activate(|_, _, _| display_error(choose_facedancer_export()))
+        .build();
+    let action_export_item = ActionEntry::builder("export-item")
+        .activate(|_, _, _| display_error(
+            choose_subset_export(ExportSelection::Item)))
+        .build();
+    let action_export_time = ActionEntry::builder("export-time")
+        .activate(|_, _, _| display_error(
+            choose_subset_export(ExportSelection::ItemTime)))
+        .build();
+    let action_export_device = ActionEntry::builder("export-device")
+        .activate(|_, _, _| display_error(
+            choose_subset_export(ExportSelection::Device)))
+        .build();
+    let action_export_text = ActionEntry::builder("export-text")
+        .activate(|_, _, _| display_error(choose_report_export(false)))
+        .build();
+    let action_export_hex = ActionEntry::builder("export-hex")
+        .activate(|_, _, _| display_error(choose_report_export(true)))
+        .build();
+    let action_export_html = ActionEntry::builder("export-html")
+        .activate(|_, _, _| display_error(choose_html_export(false)))
+        .build();
+    let action_export_item_html = ActionEntry::builder("export-item-html")
+        .activate(|_, _, _| display_error(choose_html_export(true)))
+        .build();
+    let action_export_image = ActionEntry::builder("export-image")
+        .activate(|_, _, _| display_error(choose_image_export()))
+        .build();
+    let action_print_view = ActionEntry::builder("print-view")
+        .activate(|_, _, _| display_error(print_view()))
+        .build();
+    let action_export_lsusb = ActionEntry::builder("export-lsusb")
+        .activate(|_, _, _| display_error(choose_descriptor_export(false)))
+        .build();
+    let action_export_c = ActionEntry::builder("export-c")
+        .activate(|_, _, _| display_error(choose_descriptor_export(true)))
+        .build();
+    let action_save_session = ActionEntry::builder("save-session")
+        .activate(|_, _, _| display_error(save_current_session()))
+        .build();
+    let action_go_to = ActionEntry::builder("go-to")
+        .activate(|_, _, _| display_error(choose_target()))
+        .build();
+    let action_expand_subtree = ActionEntry::builder("expand-subtree")
+        .activate(|_, _, _| display_error(set_selected_expanded(true)))
+        .build();
+    let action_collapse_subtree = ActionEntry::builder("collapse-subtree")
+        .activate(|_, _, _| display_error(set_selected_expanded(false)))
+        .build();
+    let action_expand_all = ActionEntry::builder("expand-all")
+        .activate(|_, _, _| display_error(set_all_expanded(true)))
+        .build();
+    let action_collapse_all = ActionEntry::builder("collapse-all")
+        .activate(|_, _, _| display_error(set_all_expanded(false)))
+        .build();
+    let action_throughput = ActionEntry::builder("throughput")
+        .activate(|_, _, _| display_error(show_throughput()))
+        .build();
+    let action_next_error = ActionEntry::builder("next-error")
+        .activate(|_, _, _| display_error(go_to_error(true)))
+        .build();
+    let action_previous_error = ActionEntry::builder("previous-error")
+        .activate(|_, _, _| display_error(go_to_error(false)))
+        .build();
+    let action_swimlanes = ActionEntry::builder("swimlanes")
+        .activate(|_, _, _| display_error(show_swimlanes()))
+        .build();
+    let action_latency = ActionEntry::builder("latency")
+        .activate(|_, _, _| display_error(show_latency()))
+        .build();
+    let action_metrics = ActionEntry::builder("metrics")
+        .activate(|_, _, _| display_error(show_metrics()))
+        .build();
+    action_group.add_action_entries(EndpointFilter::ALL.map(|filter|
+        ActionEntry::builder(filter.action())
+            .activate(move |_, _, _| display_error(filter_selected(filter)))
+            .build()));
+    let action_follow_stream = ActionEntry::builder("follow-stream")
+        .activate(|_, _, _| display_error(follow_stream()))
+        .build();
+    let action_export_payload = ActionEntry::builder("export-payload")
+        .activate(|_, _, _| display_error(choose_payload_export()))
+        .build();
+    let action_bookmark = ActionEntry::builder("bookmark")
+        .activate(|_, _, _| display_error(with_ui(|ui| ui.annotate(true))))
+        .build();
+    let action_open_recent = ActionEntry::builder("open-recent")
+        .parameter_type(Some(glib::VariantTy::STRING))
+        .activate(|_, _, parameter| {
+            if let Some(uri) = parameter.and_then(|value| value.str()) {
+                display_error(
+                    open_files(vec![gio::File::for_uri(uri)]));
+            }
+        })
+        .build();
+    let action_new_window = ActionEntry::builder("new-window")
+        .activate(|_, _, _| display_error(open_new_window(&[], None)))
+        .build();
+    let action_open_new_window = ActionEntry::builder("open-new-window")
+        .activate(|_, _, _| display_error(choose_new_window_file()))
+        .build();
+    let action_compare = ActionEntry::builder("compare")
+        .activate(|_, _, _| display_error(choose_comparison()))
+        .build();
+    let action_color_rules = ActionEntry::builder("color-rules")
+        .activate(|_, _, _| display_error(choose_color_rules()))
+        .build();
+    let action_preferences = ActionEntry::builder("preferences")
+        .activate(|_, _, _| display_error(choose_preferences()))
+        .build();
     let action_about = ActionEntry::builder("about")
         .activate(|_, _, _| display_error(show_about()))
         .build();
-    action_group.add_action_entries([action_about]);
+    action_group.add_action_entries([
+        action_export_item,
+        action_export_time,
+        action_export_device,
+        action_export_text,
+        action_export_hex,
+        action_export_html,
+        action_export_item_html,
+        action_export_image,
+        action_print_view,
+        action_export_lsusb,
+        action_export_c,
+        action_export_data,
+        action_export_audio,
+        action_export_video,
+        action_export_hci,
+        action_export_facedancer,
+        action_save_session,
+        action_go_to,
+        action_next_error,
+        action_previous_error,
+        action_expand_subtree,
+        action_collapse_subtree,
+        action_expand_all,
+        action_collapse_all,
+        action_throughput,
+        action_swimlanes,
+        action_latency,
+        action_metrics,
+        action_compare,
+        action_open_recent,
+        action_new_window,
+        action_open_new_window,
+        action_follow_stream,
+        action_export_payload,
+        action_bookmark,
+        action_color_rules,
+        action_preferences,
+        action_about
+    ]);
+    action_group.add_action_entries(CopyFormat::ALL.map(|format|
+        ActionEntry::builder(format.action())
+            .activate(move |_, _, _| display_error(copy_selected(format)))
+            .build()));
+    action_group.add_action_entries(TrafficColumn::ALL.map(|column|
+        ActionEntry::builder(column.action())
+            .state(false.to_variant())
+            .change_state(move |_, action, state| {
+                let Some(state) = state else { return };
+                action.set_state(state);
+                let visible = state.get::<bool>().unwrap_or(false);
+                display_error(with_ui(|ui| {
+                    ui.set_column_visible(column.title(), visible);
+                    Ok(())
+                }))
+            })
+            .build()));
+    action_group.add_action_entries([
+        ActionEntry::builder("time-mode")
+            .parameter_type(Some(glib::VariantTy::STRING))
+            .state(TimeMode::default().name().to_variant())
+            .activate(|_, action, parameter| {
+                let Some(mode) = parameter
+                    .and_then(|parameter| parameter.str())
+                    .and_then(TimeMode::from_name)
+                else {
+                    return
+                };
+                action.set_state(&mode.name().to_variant());
+                display_error(set_time_mode(mode));
+            })
+            .build()
+    ]);
     window.insert_action_group("actions", Some(&action_group));
 
     action_bar.pack_start(&open_button);
@@ -455,12 +1522,35 @@ pub fn activate(application: &Application) -> Result<(), Error> {
     action_bar.pack_start(&scan_button);
     action_bar.pack_start(&capture_button);
     action_bar.pack_start(&stop_button);
+    action_bar.pack_start(&pause_button);
+    action_bar.pack_start(&marker_button);
     action_bar.pack_start(&selector.container);
     action_bar.pack_end(&menu_button);
 
     let warning = DeviceWarning::new();
     warning.update(selector.device_unusable());
 
+    // A capture file dropped on the window is opened. Dropping several
+    // files at once needs a newer GTK than we build against, so only one
+    // file is accepted.
+    let drop_target = gtk::DropTarget::new(
+        gio::File::static_type(), gtk::gdk::DragAction::COPY);
+    drop_target.connect_drop(|_, value, _, _| {
+        match value.get::<gio::File>() {
+            Ok(file) => {
+                display_error(open_files(vec![file]));
+                true
+            },
+            Err(_) => false,
+        }
+    });
+    window.add_controller(drop_target);
+
+    window.connect_close_request(|_| {
+        display_error(with_ui(|ui| ui.update_session()));
+        glib::Propagation::Proceed
+    });
+
     #[cfg(not(test))]
     window.show();
     WINDOW.with(|win_opt| win_opt.replace(Some(window.clone())));
@@ -471,66 +1561,359 @@ pub fn activate(application: &Application) -> Result<(), Error> {
         .hscrollbar_policy(gtk::PolicyType::Automatic)
         .min_content_height(480)
         .min_content_width(640)
+        .vexpand(true)
         .build();
 
-    let device_window = gtk::ScrolledWindow::builder()
-        .hscrollbar_policy(gtk::PolicyType::Automatic)
-        .min_content_height(480)
-        .min_content_width(240)
-        .build();
+    let traffic_menu = traffic_menu(&traffic_window);
 
-    let detail_text = gtk::TextBuffer::new(None);
-    let detail_view = gtk::TextView::builder()
-        .buffer(&detail_text)
-        .editable(false)
-        .wrap_mode(WrapMode::Word)
-        .vexpand(true)
-        .left_margin(5)
+    let view_filter_entry = Entry::builder()
+        .placeholder_text("address=5 endpoint=1 ignore=5.2 hide=SOF,NAK")
+        .tooltip_text(
+            "Show only the traffic of these device addresses and \
+             endpoints, hide that of these address.endpoint pairs, \
+             and hide SOF packets or NAKed transactions")
+        .hexpand(true)
         .build();
+    view_filter_entry.connect_activate(|entry| {
+        let text = entry.text();
+        display_error(with_ui(|ui| {
+            ui.view_filter = TrafficFilter::parse(text.as_str())?;
+            ui.update_traffic_filter()
+        }))
+    });
 
-    let detail_window = gtk::ScrolledWindow::builder()
-        .hscrollbar_policy(gtk::PolicyType::Automatic)
-        .min_content_width(640)
-        .min_content_height(120)
-        .child(&detail_view)
+    let view_filter_label = Label::builder()
+        .label(tr!("Show: "))
+        .margin_start(5)
+        .margin_end(2)
         .build();
-
-    let horizontal_panes = gtk::Paned::builder()
+    let view_filter_box = gtk::Box::builder()
         .orientation(Orientation::Horizontal)
-        .wide_handle(true)
-        .start_child(&traffic_window)
-        .end_child(&device_window)
-        .vexpand(true)
         .build();
+    view_filter_box.append(&view_filter_label);
+    view_filter_box.append(&view_filter_entry);
 
-    let vertical_panes = gtk::Paned::builder()
-        .orientation(Orientation::Vertical)
-        .wide_handle(true)
-        .start_child(&horizontal_panes)
-        .end_child(&detail_window)
-        .hexpand(true)
+    let hide_sof = CheckButton::builder()
+        .label(tr!("Hide SOF"))
+        .tooltip_text("Hide the groups of SOF packets")
+        .active(preferences.summary.hide_sof())
+        .margin_start(5)
         .build();
-
-    let separator = gtk::Separator::new(Orientation::Horizontal);
-
-    let progress_bar = gtk::ProgressBar::builder()
-        .show_text(true)
-        .text("")
-        .hexpand(true)
+    let collapse_idle = CheckButton::builder()
+        .label(tr!("Collapse idle"))
+        .tooltip_text(
+            "Show each group of SOF packets as the time the bus was idle")
+        .active(preferences.summary.collapse_idle())
+        .margin_start(5)
+        .build();
+    let collapse_polling = CheckButton::builder()
+        .label(tr!("Collapse polling"))
+        .tooltip_text(
+            "Show each group of NAKed transactions as the number of times \
+             the endpoint was polled, and for how long")
+        .active(preferences.summary.collapse_polling())
+        .margin_start(5)
+        .margin_end(5)
         .build();
+    for toggle in [&hide_sof, &collapse_idle, &collapse_polling] {
+        toggle.connect_toggled(|_|
+            display_error(with_ui(|ui| ui.update_traffic_filter())));
+        view_filter_box.append(toggle);
+    }
 
-    let status_label = gtk::Label::builder()
-        .label("Ready")
-        .single_line_mode(true)
-        .halign(Align::Start)
+    let find_entry = Entry::builder()
+        .placeholder_text("Setting configuration")
+        .tooltip_text(
+            "Find transfers and transactions whose summary contains \
+             this text, packets whose payload contains these bytes, or \
+             transactions matching a query")
         .hexpand(true)
-        .margin_top(2)
-        .margin_bottom(2)
-        .margin_start(3)
-        .margin_end(3)
         .build();
-
-    let vbox = gtk::Box::builder()
+    let find_mode = DropDown::from_strings(&FIND_MODES);
+    find_mode.set_tooltip_text(Some("What to search for"));
+    let entry = find_entry.clone();
+    find_mode.connect_selected_notify(move |dropdown| {
+        entry.set_placeholder_text(Some(match dropdown.selected() {
+            FIND_REGEX => "Class request #[01],",
+            FIND_PAYLOAD => "80 06 ?? 01",
+            FIND_QUERY => "pid=SETUP result=STALL",
+            _ => "Setting configuration",
+        }));
+    });
+    let mode = find_mode.clone();
+    find_entry.connect_activate(move |entry| {
+        display_error(find(mode.selected(), entry.text().as_str(), true))
+    });
+    let find_label = Label::builder()
+        .label(tr!("Find: "))
+        .margin_start(5)
+        .margin_end(2)
+        .build();
+    let find_previous_button = gtk::Button::builder()
+        .icon_name("go-up")
+        .tooltip_text("Previous match")
+        .build();
+    let find_next_button = gtk::Button::builder()
+        .icon_name("go-down")
+        .tooltip_text("Next match")
+        .build();
+    for (button, forward) in [(&find_previous_button, false),
+                              (&find_next_button, true)]
+    {
+        let entry = find_entry.clone();
+        let mode = find_mode.clone();
+        button.connect_clicked(move |_| {
+            display_error(
+                find(mode.selected(), entry.text().as_str(), forward))
+        });
+    }
+    view_filter_box.append(&find_label);
+    view_filter_box.append(&find_mode);
+    view_filter_box.append(&find_entry);
+    view_filter_box.append(&find_previous_button);
+    view_filter_box.append(&find_next_button);
+
+    let errors_label = Label::builder()
+        .label(tr!("Errors: "))
+        .margin_start(5)
+        .margin_end(2)
+        .build();
+    let previous_error_button = gtk::Button::builder()
+        .icon_name("go-up")
+        .tooltip_text("Previous error (Shift+F8)")
+        .action_name("actions.previous-error")
+        .build();
+    let next_error_button = gtk::Button::builder()
+        .icon_name("go-down")
+        .tooltip_text("Next error (F8)")
+        .action_name("actions.next-error")
+        .build();
+    view_filter_box.append(&errors_label);
+    view_filter_box.append(&previous_error_button);
+    view_filter_box.append(&next_error_button);
+
+    let timeline = Rc::new(RefCell::new(TimelineState::default()));
+    let timeline_area = timeline_area(&timeline, |time|
+        display_error(go_to_target(Target::Time(time))));
+    let follow = Rc::new(FollowState::default());
+    let adjustment = traffic_window.vadjustment();
+    let state = follow.clone();
+    adjustment.connect_value_changed(move |adjustment| {
+        // Scrolling away from the bottom stops following new traffic, and
+        // scrolling back to it resumes.
+        state.at_bottom.set(
+            adjustment.value() + adjustment.page_size() >=
+                adjustment.upper() - FOLLOW_MARGIN);
+        schedule_timeline_window();
+    });
+    let state = follow.clone();
+    adjustment.connect_changed(move |adjustment| {
+        // Rows were added, so scroll to the newest if following them.
+        if state.live.get() && state.at_bottom.get() {
+            adjustment.set_value(adjustment.upper() - adjustment.page_size());
+        }
+        schedule_timeline_window();
+    });
+
+    let traffic_box = gtk::Box::builder()
+        .orientation(Orientation::Vertical)
+        .build();
+    traffic_box.append(&view_filter_box);
+    traffic_box.append(&timeline_area);
+    traffic_box.append(&traffic_window);
+
+    let device_window = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Automatic)
+        .min_content_height(480)
+        .min_content_width(240)
+        .build();
+
+    let device_filter = CheckButton::builder()
+        .label(tr!("Show only traffic of selected device"))
+        .margin_start(5)
+        .build();
+    device_filter.connect_toggled(|_|
+        display_error(with_ui(|ui| ui.update_traffic_filter())));
+
+    let device_box = gtk::Box::builder()
+        .orientation(Orientation::Vertical)
+        .build();
+    device_box.append(&device_filter);
+    device_box.append(&device_window);
+
+    let note_entry = Entry::builder()
+        .placeholder_text("Note")
+        .tooltip_text("Text of the bookmark or comment to add")
+        .hexpand(true)
+        .build();
+    let bookmark_button = gtk::Button::builder()
+        .icon_name("bookmark-new")
+        .tooltip_text("Bookmark the selected item")
+        .build();
+    bookmark_button.connect_clicked(|_|
+        display_error(with_ui(|ui| ui.annotate(true))));
+    let comment_button = gtk::Button::builder()
+        .icon_name("document-edit")
+        .tooltip_text("Comment on the selected item")
+        .build();
+    comment_button.connect_clicked(|_|
+        display_error(with_ui(|ui| ui.annotate(false))));
+    let remove_button = gtk::Button::builder()
+        .icon_name("list-remove")
+        .tooltip_text("Remove the selected bookmark or comment")
+        .build();
+    remove_button.connect_clicked(|_|
+        display_error(with_ui(|ui| ui.remove_annotation())));
+    let note_box = gtk::Box::builder()
+        .orientation(Orientation::Horizontal)
+        .build();
+    note_box.append(&note_entry);
+    note_box.append(&bookmark_button);
+    note_box.append(&comment_button);
+    note_box.append(&remove_button);
+
+    let bookmark_list = ListBox::new();
+    bookmark_list.connect_row_activated(|_, row| {
+        let index = row.index() as usize;
+        display_error(go_to_annotation(index))
+    });
+    let bookmark_window = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Never)
+        .vexpand(true)
+        .child(&bookmark_list)
+        .build();
+
+    let bookmark_box = gtk::Box::builder()
+        .orientation(Orientation::Vertical)
+        .build();
+    bookmark_box.append(&note_box);
+    bookmark_box.append(&bookmark_window);
+
+    let side_notebook = Notebook::new();
+    side_notebook.append_page(&device_box,
+                              Some(&Label::new(Some(tr!("Devices")))));
+    side_notebook.append_page(&bookmark_box,
+                              Some(&Label::new(Some(tr!("Bookmarks")))));
+
+    let detail_text = gtk::TextBuffer::new(None);
+    let detail_view = gtk::TextView::builder()
+        .buffer(&detail_text)
+        .editable(false)
+        .wrap_mode(WrapMode::Word)
+        .vexpand(true)
+        .left_margin(5)
+        .build();
+
+    let detail_window = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Automatic)
+        .min_content_width(640)
+        .min_content_height(120)
+        .child(&detail_view)
+        .build();
+
+    let detail_tree = create_field_view();
+
+    let hex_view = gtk::TextView::builder()
+        .editable(false)
+        .cursor_visible(false)
+        .monospace(true)
+        .vexpand(true)
+        .left_margin(5)
+        .build();
+
+    let highlight = TextTag::builder()
+        .name("highlight")
+        .background("#3584e4")
+        .foreground("white")
+        .build();
+    hex_view.buffer().tag_table().add(&highlight);
+
+    let hex_click = GestureClick::new();
+    hex_click.connect_released(|_, _, x, y|
+        display_error(with_ui(|ui| {
+            ui.select_field_at(x, y);
+            Ok(())
+        })));
+    hex_view.add_controller(hex_click);
+
+    let hex_window = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Automatic)
+        .min_content_width(320)
+        .min_content_height(120)
+        .child(&hex_view)
+        .build();
+
+    let hex_panes = gtk::Paned::builder()
+        .orientation(Orientation::Horizontal)
+        .wide_handle(true)
+        .start_child(&hex_window)
+        .end_child(&detail_window)
+        .build();
+
+    let field_window = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Automatic)
+        .min_content_width(320)
+        .min_content_height(120)
+        .child(&detail_tree)
+        .build();
+
+    let detail_panes = gtk::Paned::builder()
+        .orientation(Orientation::Horizontal)
+        .wide_handle(true)
+        .start_child(&field_window)
+        .end_child(&hex_panes)
+        .build();
+
+    let horizontal_panes = gtk::Paned::builder()
+        .orientation(Orientation::Horizontal)
+        .wide_handle(true)
+        .start_child(&traffic_box)
+        .end_child(&side_notebook)
+        .vexpand(true)
+        .build();
+
+    let vertical_panes = gtk::Paned::builder()
+        .orientation(Orientation::Vertical)
+        .wide_handle(true)
+        .start_child(&horizontal_panes)
+        .end_child(&detail_panes)
+        .hexpand(true)
+        .build();
+
+    let separator = gtk::Separator::new(Orientation::Horizontal);
+
+    let progress_bar = gtk::ProgressBar::builder()
+        .show_text(true)
+        .text("")
+        .hexpand(true)
+        .valign(Align::Center)
+        .build();
+    let cancel_button = gtk::Button::builder()
+        .label(tr!("Cancel"))
+        .tooltip_text("Stop, keeping the packets read so far")
+        .action_name("win.stop")
+        .build();
+    let progress_box = gtk::Box::builder()
+        .orientation(Orientation::Horizontal)
+        .spacing(5)
+        .margin_start(5)
+        .margin_end(5)
+        .build();
+    progress_box.append(&progress_bar);
+    progress_box.append(&cancel_button);
+
+    let status_label = gtk::Label::builder()
+        .label(tr!("Ready"))
+        .single_line_mode(true)
+        .halign(Align::Start)
+        .hexpand(true)
+        .margin_top(2)
+        .margin_bottom(2)
+        .margin_start(3)
+        .margin_end(3)
+        .build();
+
+    let vbox = gtk::Box::builder()
         .orientation(Orientation::Vertical)
         .build();
 
@@ -545,6 +1928,13 @@ pub fn activate(application: &Application) -> Result<(), Error> {
 
     window.set_child(Some(&vbox));
 
+    let color_css = gtk::CssProvider::new();
+    color_css.load_from_data(&color_styles(&preferences.color_rules));
+    gtk::style_context_add_provider_for_display(
+        &WidgetExt::display(&window),
+        &color_css,
+        gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+
     UI.with(|cell| {
         cell.borrow_mut().replace(
             UserInterface {
@@ -554,50 +1944,276 @@ pub fn activate(application: &Application) -> Result<(), Error> {
                 capture,
                 selector,
                 file_name: None,
+                capture_file: None,
+                session: Session::default(),
+                preferences,
+                recent_menu,
+                color_css,
                 stop_state: StopState::Disabled,
+                bus_speed: None,
+                waiting_for_trigger: false,
+                capture_control: None,
+                paused: false,
+                marker_count: 0,
                 traffic_window,
+                timeline_area,
+                timeline,
+                timeline_packets: 0,
+                timeline_updated: Instant::now(),
+                follow,
+                time_format: TimeFormat {
+                    mode: TimeMode::default(),
+                    utc_offset: glib::DateTime::now_local()
+                        .map_or(0, |now| now.utc_offset().as_seconds()),
+                },
                 device_window,
+                device_filter,
+                hide_sof,
+                collapse_idle,
+                collapse_polling,
+                view_filter: TrafficFilter::default(),
+                traffic_filter: TrafficFilter::default(),
+                bookmark_list,
+                note_entry,
+                view_filter_entry,
+                traffic_menu,
                 traffic_model: None,
                 device_model: None,
+                selected_device_item: None,
+                selected_traffic_item: None,
+                selection_stats: None,
                 detail_text,
+                detail_tree,
+                detail_fields: Vec::new(),
+                hex_view,
+                hex_length: 0,
                 endpoint_count: 2,
                 show_progress: None,
+                progress_start: Instant::now(),
                 progress_bar,
+                progress_box,
                 separator,
                 vbox,
+                horizontal_panes,
                 vertical_panes,
+                detail_panes,
+                hex_panes,
                 scan_button,
                 open_button,
                 save_button,
                 capture_button,
                 stop_button,
+                pause_button,
+                marker_button,
                 status_label,
                 warning,
+                actions: action_group,
             }
         )
     });
 
     reset_capture()?;
 
+    with_ui(|ui| {
+        ui.update_recent_menu();
+        ui.update_column_actions();
+        Ok(())
+    })?;
+
     gtk::glib::idle_add_once(|| display_error(detect_hardware()));
 
+    #[cfg(not(test))]
+    std::thread::spawn(watch_devices);
+
+    #[cfg(not(test))]
+    gtk::glib::idle_add_once(|| display_error(offer_recovery()));
+
     Ok(())
 }
 
-pub fn open(file: &gio::File) -> Result<(), Error> {
-    start_pcap(FileAction::Load, file.clone())
+/// Open a capture file, or merge several into one capture.
+///
+/// The format of the files is detected unless given by name.
+pub fn open(files: &[gio::File], format: Option<&str>) -> Result<(), Error> {
+    let format = format.map(InputFormat::from_name).transpose()?;
+    start_pcap(FileAction::Load, files.to_vec(), format)
+}
+
+/// Open capture files chosen other than with the open button, unless an
+/// operation that prevents opening one is in progress.
+fn open_files(files: Vec<gio::File>) -> Result<(), Error> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    let mut available = false;
+    with_ui(|ui| {
+        available = ui.open_button.is_sensitive();
+        Ok(())
+    })?;
+    if !available {
+        bail!("Stop the current operation before opening a capture file");
+    }
+    start_pcap(FileAction::Load, files, None)
+}
+
+/// Create the view showing the fields of the selected item as a tree.
+fn create_field_view() -> ListView {
+    let factory = SignalListItemFactory::new();
+    factory.connect_setup(move |_, list_item| {
+        let label = Label::builder()
+            .halign(Align::Start)
+            .build();
+        let expander = TreeExpander::builder()
+            .child(&label)
+            .build();
+        list_item.set_child(Some(&expander));
+    });
+    let bind = move |list_item: &ListItem| -> Result<(), Error> {
+        let row = list_item
+            .item()
+            .context("ListItem has no item")?
+            .downcast::<TreeListRow>()
+            .or_else(|_| bail!("Item is not a TreeListRow"))?;
+        let field = row
+            .item()
+            .context("TreeListRow has no item")?
+            .downcast::<BoxedAnyObject>()
+            .or_else(|_| bail!("Row item is not a BoxedAnyObject"))?;
+        let expander = list_item
+            .child()
+            .context("ListItem has no child widget")?
+            .downcast::<TreeExpander>()
+            .or_else(|_| bail!("Child widget is not a TreeExpander"))?;
+        let label = expander
+            .child()
+            .context("TreeExpander has no child widget")?
+            .downcast::<Label>()
+            .or_else(|_| bail!("Expander child is not a Label"))?;
+        expander.set_list_row(Some(&row));
+        label.set_text(&field.borrow::<Field>().to_string());
+        Ok(())
+    };
+    factory.connect_bind(move |_, item| display_error(bind(item)));
+    ListView::builder()
+        .factory(&factory)
+        .build()
+}
+
+/// List of fields, for use as a level of the field tree.
+fn field_list(fields: &[Field]) -> gio::ListStore {
+    let list = gio::ListStore::new::<BoxedAnyObject>();
+    for field in fields {
+        list.append(&BoxedAnyObject::new(field.clone()));
+    }
+    list
+}
+
+/// Show the given fields in the field tree of the detail pane, with the
+/// bytes of the selected field highlighted in the hex view.
+fn show_fields(view: &ListView,
+               hex_view: &TextView,
+               fields: &[Field],
+               length: usize)
+{
+    let tree = TreeListModel::new(field_list(fields), false, false,
+        |item| {
+            let object = item.downcast_ref::<BoxedAnyObject>()?;
+            let field = object.borrow::<Field>();
+            if field.children.is_empty() {
+                None
+            } else {
+                Some(field_list(&field.children).upcast())
+            }
+        });
+    let selection = SingleSelection::builder()
+        .model(&tree)
+        .autoselect(false)
+        .can_unselect(true)
+        .build();
+    let hex_view = hex_view.clone();
+    selection.connect_selection_changed(move |selection, _, _| {
+        let range = selection
+            .selected_item()
+            .and_downcast::<TreeListRow>()
+            .and_then(|row| row.item())
+            .and_downcast::<BoxedAnyObject>()
+            .and_then(|object| object.borrow::<Field>().bytes.clone());
+        highlight_bytes(&hex_view, range, length);
+    });
+    view.set_model(Some(&selection));
+}
+
+/// Highlight a range of the bytes shown in the hex view.
+fn highlight_bytes(hex_view: &TextView,
+                   range: Option<Range<usize>>,
+                   length: usize)
+{
+    let buffer = hex_view.buffer();
+    let (start, end) = buffer.bounds();
+    buffer.remove_tag_by_name("highlight", &start, &end);
+    let Some(range) = range else {
+        return;
+    };
+    let mut first = None;
+    for (line, columns) in text_spans(&range, length) {
+        let line = line as i32;
+        if let (Some(start), Some(end)) = (
+            buffer.iter_at_line_offset(line, columns.start as i32),
+            buffer.iter_at_line_offset(line, columns.end as i32))
+        {
+            buffer.apply_tag_by_name("highlight", &start, &end);
+            first.get_or_insert(start);
+        }
+    }
+    if let Some(mut start) = first {
+        hex_view.scroll_to_iter(&mut start, 0.0, false, 0.0, 0.0);
+    }
+}
+
+/// Expand the rows of the field tree leading to the field at a path of
+/// indices, and return the position of its row.
+fn expand_path(tree: &TreeListModel, path: &[usize]) -> Option<u32> {
+    let mut position = 0;
+    let mut found: Option<TreeListRow> = None;
+    for (depth, &index) in path.iter().enumerate() {
+        if let Some(parent) = found.take() {
+            parent.set_expanded(true);
+            position += 1;
+        }
+        // Children follow their parent, among the rows of any expanded
+        // siblings before them.
+        let mut count = 0;
+        loop {
+            let row = tree.row(position)?;
+            let row_depth = row.depth() as usize;
+            if row_depth < depth {
+                return None;
+            }
+            if row_depth == depth {
+                if count == index {
+                    found = Some(row);
+                    break;
+                }
+                count += 1;
+            }
+            position += 1;
+        }
+    }
+    found.map(|_| position)
 }
 
-fn create_view<Item, Model, RowData>(
+fn create_view<Item, Model, RowData, Selection>(
         title: &str,
         capture: &CaptureReader,
+        new_selection: impl FnOnce(&Model) -> Selection,
         #[cfg(any(test, feature="record-ui-test"))]
         recording_args: (&Rc<RefCell<Recording>>, &'static str))
-    -> (Model, SingleSelection, ColumnView)
+    -> (Model, Selection, ColumnView)
     where
         Item: Copy + 'static,
         Model: GenericModel<Item> + IsA<ListModel> + IsA<Object>,
         RowData: GenericRowData<Item> + IsA<Object>,
+        Selection: IsA<gtk::SelectionModel>,
         CaptureReader: ItemSource<Item>,
         Object: ToGenericRowData<Item>
 {
@@ -618,7 +2234,7 @@ fn create_view<Item, Model, RowData>(
             )
         )).expect("Failed to create model");
     let bind_model = model.clone();
-    let selection_model = SingleSelection::new(Some(model.clone()));
+    let selection_model = new_selection(&model);
     let factory = SignalListItemFactory::new();
     factory.connect_setup(move |_, list_item| {
         let widget = ItemWidget::new();
@@ -641,8 +2257,9 @@ fn create_view<Item, Model, RowData>(
         match row.node() {
             Ok(node_ref) => {
                 let node = node_ref.borrow();
-                let summary = bind_model.description(&node.item, false);
-                let connectors = bind_model.connectors(&node.item);
+                let position = list_item.position();
+                let summary = bind_model.summary(position, &node.item);
+                let connectors = bind_model.connectors(position, &node.item);
                 item_widget.set_text(summary);
                 item_widget.set_connectors(connectors);
                 expander.set_visible(node.expandable());
@@ -706,46 +2323,6 @@ fn create_view<Item, Model, RowData>(
     view.append_column(&column);
     view.add_css_class("data-table");
 
-    if Model::HAS_TIMES {
-        let model = model.clone();
-        let factory = SignalListItemFactory::new();
-        factory.connect_setup(move |_, list_item| {
-            let label = Label::new(None);
-            list_item.set_child(Some(&label));
-        });
-        let bind = move |list_item: &ListItem| {
-            let row = list_item
-                .item()
-                .context("ListItem has no item")?
-                .downcast::<RowData>()
-                .or_else(|_| bail!("Item is not RowData"))?;
-            let label = list_item
-                .child()
-                .context("ListItem has no child widget")?
-                .downcast::<Label>()
-                .or_else(|_| bail!("Child widget is not a Label"))?;
-            match row.node() {
-                Ok(node_ref) => {
-                    let node = node_ref.borrow();
-                    let timestamp = model.timestamp(&node.item);
-                    label.set_markup(&format!("<tt><small>{}.{:09}</small></tt>",
-                                           timestamp / 1_000_000_000,
-                                           timestamp % 1_000_000_000));
-                },
-                Err(msg) => {
-                    label.set_text(&format!("Error: {msg}"));
-                }
-            }
-            Ok(())
-        };
-
-        factory.connect_bind(move |_, item| display_error(bind(item)));
-
-        let timestamp_column =
-            ColumnViewColumn::new(Some("Time"), Some(factory));
-        view.insert_column(0, &timestamp_column);
-    }
-
     #[cfg(any(test, feature="record-ui-test"))]
     model.connect_items_changed(move |model, position, removed, added|
         changed_rec.borrow_mut().log_items_changed(
@@ -754,164 +2331,2695 @@ fn create_view<Item, Model, RowData>(
     (model, selection_model, view)
 }
 
-pub fn reset_capture() -> Result<CaptureWriter, Error> {
-    let (writer, reader) = create_capture()?;
+/// Minimum time between updates of the timeline while capturing.
+const TIMELINE_UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Distance in pixels from the bottom of the traffic view within which it
+/// is taken to be scrolled to the bottom.
+const FOLLOW_MARGIN: f64 = 4.0;
+
+/// Whether the traffic view follows new traffic as it is captured.
+#[derive(Default)]
+struct FollowState {
+    /// Whether a live capture is in progress.
+    live: Cell<bool>,
+    /// Whether the view was last scrolled to the bottom.
+    at_bottom: Cell<bool>,
+}
+
+/// Use light or dark colors as the preferences say.
+pub fn apply_theme(theme: Theme) {
+    let dark = match theme {
+        Theme::System => matches!(dark_light::detect(), dark_light::Mode::Dark),
+        Theme::Light => false,
+        Theme::Dark => true,
+    };
+    if let Some(settings) = gtk::Settings::default() {
+        settings.set_gtk_application_prefer_dark_theme(dark);
+    }
+}
+
+/// Show a graph of the data transferred on each endpoint over time.
+fn show_throughput() -> Result<(), Error> {
+    let mut state = None;
     with_ui(|ui| {
-        let (traffic_model, traffic_selection, traffic_view) =
-            create_view::<TrafficItem, TrafficModel, TrafficRowData>(
-                "Traffic",
-                &reader,
-                #[cfg(any(test, feature="record-ui-test"))]
-                (&ui.recording, "traffic")
-            );
-        let (device_model, _device_selection, device_view) =
-            create_view::<DeviceItem, DeviceModel, DeviceRowData>(
-                "Devices",
-                &reader,
-                #[cfg(any(test, feature="record-ui-test"))]
-                (&ui.recording, "devices")
-            );
-        ui.capture = reader;
-        ui.traffic_model = Some(traffic_model.clone());
-        ui.device_model = Some(device_model);
-        ui.endpoint_count = 2;
-        ui.traffic_window.set_child(Some(&traffic_view));
-        ui.device_window.set_child(Some(&device_view));
-        ui.stop_button.set_sensitive(false);
-        traffic_selection.connect_selection_changed(
-            move |selection_model, _position, _n_items| {
-                display_error(with_ui(|ui| {
-                    let text = match selection_model.selected_item() {
-                        Some(item) => {
-                            let row = item
-                                .downcast::<TrafficRowData>()
-                                .or_else(|_|
-                                    bail!("Item is not TrafficRowData"))?;
-                            match row.node() {
-                                Ok(node_ref) => {
-                                    let node = node_ref.borrow();
-                                    traffic_model.description(&node.item, true)
-                                },
-                                Err(msg) => msg
-                            }
-                        },
-                        None => String::from("No item selected"),
-                    };
-                    ui.detail_text.set_text(&text);
-                    Ok(())
-                }))
-            }
-        );
+        // Show only the endpoint of the selected item, if there is one.
+        let selected = match ui.selected_traffic_item {
+            Some(item) => ui.capture.item_endpoint(&item)?,
+            None => None,
+        };
+        state = Some(ThroughputState::new(&mut ui.capture, selected)?);
         Ok(())
     })?;
-    Ok(writer)
+    let Some(state) = state else {
+        return Ok(());
+    };
+    let content = throughput_view(state, |time|
+        display_error(go_to_target(Target::Time(time))));
+    let dialog = WINDOW.with(|cell| {
+        gtk::Dialog::with_buttons(
+            Some("Throughput"),
+            cell.borrow().as_ref(),
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[("Close", ResponseType::Close)])
+    });
+    dialog.content_area().append(&content);
+    dialog.connect_response(|dialog, _| dialog.destroy());
+    dialog.show();
+    Ok(())
 }
 
-pub fn update_view() -> Result<(), Error> {
+/// Show the transfers on each endpoint over time, as swimlanes.
+fn show_swimlanes() -> Result<(), Error> {
+    let mut state = None;
     with_ui(|ui| {
-        use FileAction::*;
-        #[cfg(feature="record-ui-test")]
-        let guard = {
-            let guard = UPDATE_LOCK.lock();
-            let packet_count = ui.capture.packet_index.len();
-            ui.recording
-                .borrow_mut()
-                .log_update(packet_count);
-            guard
-        };
-        let mut more_updates = false;
-        if ui.show_progress == Some(Save) {
-            more_updates = true;
-        } else {
-            let (devices, endpoints, transactions, packets) = {
-                let cap = &ui.capture;
-                let devices = cap.devices.len().saturating_sub(1);
-                let endpoints = cap.endpoints.len().saturating_sub(2);
-                let transactions = cap.transaction_index.len();
-                let packets = cap.packet_index.len();
-                (devices, endpoints, transactions, packets)
-            };
-            ui.status_label.set_text(&format!(
-                "{}: {} devices, {} endpoints, {} transactions, {} packets",
-                ui.file_name.as_deref().unwrap_or("Unsaved capture"),
-                fmt_count(devices),
-                fmt_count(endpoints),
-                fmt_count(transactions),
-                fmt_count(packets)
-            ));
-            if let Some(model) = &ui.traffic_model {
-                let old_count = model.n_items();
-                more_updates |= model.update()?;
-                let new_count = model.n_items();
-                // If any endpoints were added, we need to redraw the rows above
-                // to add the additional columns of the connecting lines.
-                if new_count > old_count {
-                    let new_ep_count = ui.capture.endpoints.len() as u16;
-                    if new_ep_count > ui.endpoint_count {
-                        model.items_changed(0, old_count, old_count);
-                        ui.endpoint_count = new_ep_count;
-                    }
+        state = Some(SwimlaneState::new(&mut ui.capture)?);
+        Ok(())
+    })?;
+    let Some(state) = state else {
+        return Ok(());
+    };
+    let window = swimlane_view(state, |time|
+        display_error(go_to_target(Target::Time(time))));
+    let dialog = WINDOW.with(|cell| {
+        gtk::Dialog::with_buttons(
+            Some("Endpoint swimlanes"),
+            cell.borrow().as_ref(),
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[("Close", ResponseType::Close)])
+    });
+    dialog.content_area().append(&window);
+    dialog.connect_response(|dialog, _| dialog.destroy());
+    dialog.show();
+    Ok(())
+}
+
+/// Show the latency of the transfers on the endpoint of the selected item,
+/// or on the endpoint selected in the Devices pane.
+fn show_latency() -> Result<(), Error> {
+    let mut result = None;
+    with_ui(|ui| {
+        let endpoint_id = match ui.selected_traffic_item {
+            Some(item) => ui.capture
+                .item_endpoint_id(&item)?
+                .context("Select a transfer in the Traffic pane first")?,
+            None => ui.selected_endpoint()?.0,
+        };
+        let endpoint = ui.capture.endpoints.get(endpoint_id)?;
+        let latency = endpoint_latency(&mut ui.capture, endpoint_id)?;
+        result = Some((endpoint, latency));
+        Ok(())
+    })?;
+    let Some((endpoint, latency)) = result else {
+        return Ok(());
+    };
+    let content = latency_view(latency);
+    let dialog = WINDOW.with(|cell| {
+        gtk::Dialog::with_buttons(
+            Some(&format!("Latency of endpoint {endpoint}")),
+            cell.borrow().as_ref(),
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[("Close", ResponseType::Close)])
+    });
+    dialog.content_area().append(&content);
+    dialog.connect_response(|dialog, _| dialog.destroy());
+    dialog.show();
+    Ok(())
+}
+
+/// Show the values computed so far by user-supplied scripts.
+fn show_metrics() -> Result<(), Error> {
+    let mut metrics = Vec::new();
+    with_ui(|ui| {
+        metrics = ui.capture.shared.metrics.load().as_ref().clone();
+        Ok(())
+    })?;
+    if metrics.is_empty() {
+        bail!("No metrics have been computed. Scripts to compute them \
+               are loaded from the {} environment variable", hooks::ENV_VAR)
+    }
+    let text = metrics
+        .iter()
+        .map(|(name, value)| format!("{name}: {value}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let label = Label::builder()
+        .label(text)
+        .halign(Align::Start)
+        .selectable(true)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_top(5)
+        .margin_bottom(5)
+        .build();
+    let dialog = WINDOW.with(|cell| {
+        gtk::Dialog::with_buttons(
+            Some(tr!("Script metrics")),
+            cell.borrow().as_ref(),
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[("Close", ResponseType::Close)])
+    });
+    dialog.content_area().append(&label);
+    dialog.connect_response(|dialog, _| dialog.destroy());
+    dialog.show();
+    Ok(())
+}
+
+/// Update the span of the traffic view shown in the timeline, once the
+/// traffic view has settled.
+fn schedule_timeline_window() {
+    gtk::glib::idle_add_once(|| display_error(with_ui(|ui| {
+        let visible = ui.visible_time_range();
+        let mut state = ui.timeline.borrow_mut();
+        if state.visible != visible {
+            state.visible = visible;
+            ui.timeline_area.queue_draw();
+        }
+        Ok(())
+    })));
+}
+
+/// Optional columns of the Traffic pane.
+#[derive(Clone, Copy)]
+enum TrafficColumn {
+    Number,
+    Delta,
+    Endpoint,
+    Result,
+    Length,
+    Data,
+}
+
+/// Number of payload bytes shown in the Data column.
+const PREVIEW_LENGTH: usize = 16;
+
+impl TrafficColumn {
+    const ALL: [TrafficColumn; 6] = [
+        TrafficColumn::Number,
+        TrafficColumn::Delta,
+        TrafficColumn::Endpoint,
+        TrafficColumn::Result,
+        TrafficColumn::Length,
+        TrafficColumn::Data,
+    ];
+
+    /// Title of the column.
+    fn title(&self) -> &'static str {
+        use TrafficColumn::*;
+        match self {
+            Number => "No.",
+            Delta => "Delta",
+            Endpoint => "Endpoint",
+            Result => "Result",
+            Length => "Length",
+            Data => "Data",
+        }
+    }
+
+    /// Name of the column in the preferences file.
+    fn name(&self) -> &'static str {
+        use TrafficColumn::*;
+        match self {
+            Number => "number",
+            Delta => "delta",
+            Endpoint => "endpoint",
+            Result => "result",
+            Length => "length",
+            Data => "data",
+        }
+    }
+
+    /// Label of the column in the Columns menu.
+    fn label(&self) -> &'static str {
+        use TrafficColumn::*;
+        match self {
+            Number => "Number",
+            Delta => "Delta time",
+            Endpoint => "Device.endpoint",
+            Result => "PID / result",
+            Length => "Length",
+            Data => "Data preview",
+        }
+    }
+
+    /// Name of the action which shows or hides the column.
+    fn action(&self) -> &'static str {
+        use TrafficColumn::*;
+        match self {
+            Number => "column-number",
+            Delta => "column-delta",
+            Endpoint => "column-endpoint",
+            Result => "column-result",
+            Length => "column-length",
+            Data => "column-data",
+        }
+    }
+
+    /// Text shown in the column for an item.
+    fn text(&self, capture: &mut CaptureReader, item: &TrafficItem)
+        -> Result<String, Error>
+    {
+        use TrafficColumn::*;
+        Ok(match self {
+            Number => capture
+                .item_number(item)
+                .map(|number| number.to_string())
+                .unwrap_or_default(),
+            Delta => capture
+                .item_delta(item)?
+                .map(fmt_timestamp)
+                .unwrap_or_default(),
+            Endpoint => capture
+                .item_endpoint(item)?
+                .filter(|ep|
+                    ![INVALID_EP_NUM, FRAMING_EP_NUM].contains(&ep.number()))
+                .map(|ep| ep.to_string())
+                .unwrap_or_default(),
+            Result => capture
+                .item_result(item)?
+                .map(|pid| pid.to_string())
+                .unwrap_or_default(),
+            Length => capture
+                .item_length(item)?
+                .map(|length| length.to_string())
+                .unwrap_or_default(),
+            Data => {
+                let bytes = capture.item_preview(item, PREVIEW_LENGTH + 1)?;
+                let mut text = bytes
+                    .iter()
+                    .take(PREVIEW_LENGTH)
+                    .map(|byte| format!("{byte:02X}"))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                if bytes.len() > PREVIEW_LENGTH {
+                    text.push_str(" …");
                 }
+                text
             }
-            if let Some(model) = &ui.device_model {
-                more_updates |= model.update()?;
+        })
+    }
+}
+
+/// Add the column showing the time of each item to a traffic view.
+fn add_time_column(view: &ColumnView,
+                   capture: &CaptureReader,
+                   time_format: TimeFormat)
+{
+    let capture = RefCell::new(capture.clone());
+    let factory = SignalListItemFactory::new();
+    factory.connect_setup(move |_, list_item| {
+        let label = Label::new(None);
+        list_item.set_child(Some(&label));
+    });
+    let bind = move |list_item: &ListItem| {
+        let row = list_item
+            .item()
+            .context("ListItem has no item")?
+            .downcast::<TrafficRowData>()
+            .or_else(|_| bail!("Item is not TrafficRowData"))?;
+        let label = list_item
+            .child()
+            .context("ListItem has no child widget")?
+            .downcast::<Label>()
+            .or_else(|_| bail!("Child widget is not a Label"))?;
+        match row.node() {
+            Ok(node_ref) => {
+                let text = time_format
+                    .text(&mut capture.borrow_mut(), &node_ref.borrow().item)
+                    .unwrap_or_else(|e| format!("Error: {e}"));
+                label.set_markup(&format!("<tt><small>{}</small></tt>",
+                                          glib::markup_escape_text(&text)));
+            },
+            Err(msg) => {
+                label.set_text(&format!("Error: {msg}"));
             }
         }
-        if let Some(action) = ui.show_progress {
-            let total = TOTAL.load(Ordering::Relaxed);
-            let current = CURRENT.load(Ordering::Relaxed);
-            let (fraction, text_count) = if total == 0 {
-                (None, fmt_size(current))
-            } else {
-                (Some((current as f64) / (total as f64)),
-                    format!("{} / {}", fmt_size(current), fmt_size(total)))
-            };
-            let text = match action {
-                Load => format!("Loaded {text_count} bytes"),
-                Save => format!("Saved {text_count} packets"),
+        Ok(())
+    };
+    factory.connect_bind(move |_, item| display_error(bind(item)));
+    let timestamp_column = ColumnViewColumn::new(Some("Time"), Some(factory));
+    view.insert_column(0, &timestamp_column);
+}
+
+/// Add the optional columns, shown at first if set in the preferences, to
+/// a traffic view, and color its rows by the color rules.
+fn add_traffic_columns(view: &ColumnView,
+                       capture: &CaptureReader,
+                       preferences: &Preferences)
+{
+    let capture = Rc::new(RefCell::new(capture.clone()));
+    for column in TrafficColumn::ALL {
+        let capture = capture.clone();
+        let factory = SignalListItemFactory::new();
+        factory.connect_setup(move |_, list_item| {
+            let label = Label::builder().halign(Align::Start).build();
+            list_item.set_child(Some(&label));
+        });
+        let bind = move |list_item: &ListItem| {
+            let row = list_item
+                .item()
+                .context("ListItem has no item")?
+                .downcast::<TrafficRowData>()
+                .or_else(|_| bail!("Item is not TrafficRowData"))?;
+            let label = list_item
+                .child()
+                .context("ListItem has no child widget")?
+                .downcast::<Label>()
+                .or_else(|_| bail!("Child widget is not a Label"))?;
+            let text = match row.node() {
+                Ok(node_ref) => column
+                    .text(&mut capture.borrow_mut(), &node_ref.borrow().item)
+                    .unwrap_or_else(|e| format!("Error: {e}")),
+                Err(msg) => format!("Error: {msg}"),
             };
-            ui.progress_bar.set_text(Some(&text));
-            match fraction {
-                Some(fraction) => ui.progress_bar.set_fraction(fraction),
-                None => ui.progress_bar.pulse()
+            label.set_markup(&format!("<tt><small>{}</small></tt>",
+                                      glib::markup_escape_text(&text)));
+            Ok(())
+        };
+        factory.connect_bind(move |_, item| display_error(bind(item)));
+        let view_column =
+            ColumnViewColumn::new(Some(column.title()), Some(factory));
+        view_column.set_visible(
+            preferences.columns.iter().any(|name| name == column.name()));
+        // The number goes first, the rest before the summary column.
+        let position = match column {
+            TrafficColumn::Number => 0,
+            _ => view.columns().n_items().saturating_sub(1),
+        };
+        view.insert_column(position, &view_column);
+    }
+    if preferences.color_rules.is_empty() {
+        return;
+    }
+    let preferences = Rc::new(preferences.clone());
+    for factory in column_factories(view) {
+        let capture = capture.clone();
+        let preferences = preferences.clone();
+        let bind = move |list_item: &ListItem| {
+            let row = list_item
+                .item()
+                .context("ListItem has no item")?
+                .downcast::<TrafficRowData>()
+                .or_else(|_| bail!("Item is not TrafficRowData"))?;
+            let widget = list_item
+                .child()
+                .context("ListItem has no child widget")?;
+            for class in widget.css_classes() {
+                if class.starts_with(COLOR_CLASS) {
+                    widget.remove_css_class(&class);
+                }
+            }
+            if let Ok(node_ref) = row.node() {
+                let item = node_ref.borrow().item;
+                if let Some(index) = preferences
+                    .color_rule(&mut capture.borrow_mut(), &item)?
+                {
+                    widget.add_css_class(&format!("{COLOR_CLASS}{index}"));
+                }
+            }
+            Ok(())
+        };
+        factory.connect_bind(move |_, item| display_error(bind(item)));
+    }
+}
+
+/// The factories creating the cells of each column of a view.
+fn column_factories(view: &ColumnView) -> Vec<SignalListItemFactory> {
+    let columns = view.columns();
+    (0..columns.n_items())
+        .filter_map(|i| columns.item(i))
+        .filter_map(|column| column.downcast::<ColumnViewColumn>().ok())
+        .filter_map(|column| column
+            .factory()
+            .and_downcast::<SignalListItemFactory>())
+        .collect()
+}
+
+/// Number of lines of fields shown in the tooltip of a traffic item.
+const TOOLTIP_FIELD_LINES: usize = 16;
+
+/// Describe a traffic item in full, for its tooltip.
+fn item_tooltip(capture: &mut CaptureReader, item: &TrafficItem)
+    -> Result<String, Error>
+{
+    let mut text = capture.description(item, true)?;
+    if let Some(duration) = capture.item_duration(item)? {
+        text.push_str(&format!("\nDuration: {}", fmt_duration(duration)));
+    }
+    let fields = capture.detail_fields(item)?;
+    if !fields.is_empty() {
+        text.push_str("\n\n");
+        text.push_str(&fields_text(&fields, TOOLTIP_FIELD_LINES));
+    }
+    Ok(text)
+}
+
+/// Show the full details of each item in a traffic view as its tooltip,
+/// so that it can be inspected without selecting it.
+fn add_tooltips(view: &ColumnView, capture: &CaptureReader) {
+    let capture = Rc::new(RefCell::new(capture.clone()));
+    for factory in column_factories(view) {
+        let capture = capture.clone();
+        factory.connect_setup(move |_, list_item| {
+            let Some(widget) = list_item.child() else { return };
+            let list_item = list_item.downgrade();
+            let capture = capture.clone();
+            widget.set_has_tooltip(true);
+            widget.connect_query_tooltip(move |_, _, _, _, tooltip| {
+                let node = list_item
+                    .upgrade()
+                    .and_then(|list_item| list_item.item())
+                    .and_downcast::<TrafficRowData>()
+                    .and_then(|row| row.node().ok());
+                let Some(node_ref) = node else {
+                    return false
+                };
+                let item = node_ref.borrow().item;
+                let text = item_tooltip(&mut capture.borrow_mut(), &item)
+                    .unwrap_or_else(|e| format!("Error: {e}"));
+                tooltip.set_text(Some(&text));
+                true
+            });
+        });
+    }
+}
+
+/// Prefix of the style classes showing the items matching a color rule.
+const COLOR_CLASS: &str = "color-rule-";
+
+/// Styles showing the items matching each color rule in its color,
+/// lightened to stay readable on a dark theme.
+fn color_styles(rules: &[ColorRule]) -> String {
+    let dark = dark_theme();
+    rules
+        .iter()
+        .enumerate()
+        .map(|(index, rule)| {
+            let color = if dark {
+                format!("mix({}, white, 0.4)", rule.color)
+            } else {
+                rule.color.clone()
             };
+            format!(".{COLOR_CLASS}{index} {{ color: {color}; }}\n")
+        })
+        .collect()
+}
+
+/// The preferences file in the user's configuration directory.
+fn preferences_file() -> gio::File {
+    gio::File::for_path(
+        glib::user_config_dir()
+            .join("packetry")
+            .join(preferences::FILE_NAME))
+}
+
+/// Load the preferences, if they have been saved.
+pub fn load_preferences() -> Result<Preferences, Error> {
+    let file = preferences_file();
+    if !file.query_exists(Cancellable::NONE) {
+        return Ok(Preferences::default());
+    }
+    let source = file.read(Cancellable::NONE)?.into_read();
+    Preferences::load(source).context("Failed to load preferences")
+}
+
+/// Save the preferences, creating the configuration directory if needed.
+fn save_preferences(preferences: &Preferences) -> Result<(), Error> {
+    let file = preferences_file();
+    if let Some(parent) = file.parent() {
+        if !parent.query_exists(Cancellable::NONE) {
+            parent.make_directory_with_parents(Cancellable::NONE)?;
         }
-        if more_updates {
-            gtk::glib::timeout_add_once(
-                UPDATE_INTERVAL,
-                || display_error(update_view())
-            );
+    }
+    let dest = file
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write();
+    preferences.save(dest).context("Failed to save preferences")
+}
+
+/// Edit the color rules, one per line, in a dialog.
+fn choose_color_rules() -> Result<(), Error> {
+    let dialog = WINDOW.with(|cell| {
+        gtk::Dialog::with_buttons(
+            Some("Color rules"),
+            cell.borrow().as_ref(),
+            gtk::DialogFlags::MODAL,
+            &[("Cancel", ResponseType::Cancel),
+              ("Apply", ResponseType::Accept)])
+    });
+    dialog.set_default_response(ResponseType::Accept);
+    let mut text = String::new();
+    with_ui(|ui| {
+        for rule in &ui.preferences.color_rules {
+            text += &format!("{rule}\n");
         }
-        #[cfg(feature="record-ui-test")]
-        drop(guard);
         Ok(())
-    })
+    })?;
+    let label = Label::builder()
+        .label("One rule per line: a color, then conditions such as \
+                'pid=STALL', 'address=5' or 'endpoint=1,2'.\n\
+                Items are shown in the color of the first rule they match.")
+        .halign(Align::Start)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_top(5)
+        .build();
+    let text_view = TextView::builder()
+        .monospace(true)
+        .top_margin(5)
+        .bottom_margin(5)
+        .left_margin(5)
+        .right_margin(5)
+        .build();
+    text_view.buffer().set_text(&text);
+    let window = ScrolledWindow::builder()
+        .child(&text_view)
+        .min_content_height(150)
+        .min_content_width(400)
+        .vexpand(true)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_top(5)
+        .margin_bottom(5)
+        .build();
+    dialog.content_area().append(&label);
+    dialog.content_area().append(&window);
+    dialog.connect_response(move |dialog, response| {
+        if response == ResponseType::Accept {
+            let buffer = text_view.buffer();
+            let text = buffer.text(&buffer.start_iter(), &buffer.end_iter(),
+                                   false);
+            let result = parse_color_rules(text.as_str())
+                .and_then(set_color_rules);
+            if result.is_err() {
+                // Leave the dialog open to correct the rules.
+                display_error(result);
+                return;
+            }
+        }
+        dialog.destroy();
+    });
+    dialog.show();
+    Ok(())
 }
 
-fn choose_file(action: FileAction) -> Result<(), Error> {
-    use FileAction::*;
+/// Parse color rules entered one per line.
+fn parse_color_rules(text: &str) -> Result<Vec<ColorRule>, Error> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| ColorRule::parse(line).with_context(||
+            format!("Invalid color rule on line {}", index + 1)))
+        .collect()
+}
+
+/// Edit the settings kept in the preferences in a dialog.
+fn choose_preferences() -> Result<(), Error> {
+    let mut preferences = Preferences::default();
+    with_ui(|ui| {
+        preferences = ui.preferences.clone();
+        Ok(())
+    })?;
+    let columns = TrafficColumn::ALL.map(|column|
+        (column.name(), column.label()));
+    let dialog = WINDOW.with(|cell| preferences_dialog(
+        cell.borrow().as_ref(), &preferences, &columns, set_preferences));
+    dialog.show();
+    Ok(())
+}
+
+/// Save the settings chosen in the preferences dialog, and apply those
+/// that have changed.
+fn set_preferences(chosen: Preferences) -> Result<(), Error> {
+    let Preferences {
+        temp_dir, speed, columns, summary, theme, no_recovery, ..
+    } = chosen;
+    if let Some(dir) = &temp_dir {
+        if !dir.is_dir() {
+            bail!("The directory '{}' does not exist", dir.display());
+        }
+    }
+    stream::set_temp_dir(temp_dir.clone());
+    apply_theme(theme);
+    let mut toggles = None;
+    let mut column_actions = None;
+    with_ui(|ui| {
+        ui.selector.default_speed = speed;
+        if speed != ui.preferences.speed &&
+            !ui.selector.dev_speeds.is_empty()
+        {
+            ui.selector.update_speeds();
+        }
+        if summary != ui.preferences.summary {
+            toggles = Some([
+                (ui.hide_sof.clone(), summary.hide_sof()),
+                (ui.collapse_idle.clone(), summary.collapse_idle()),
+                (ui.collapse_polling.clone(), summary.collapse_polling()),
+            ]);
+        }
+        if columns != ui.preferences.columns {
+            column_actions = Some(ui.actions.clone());
+        }
+        if theme != ui.preferences.theme {
+            ui.color_css.load_from_data(
+                &color_styles(&ui.preferences.color_rules));
+        }
+        let preferences = &mut ui.preferences;
+        preferences.temp_dir = temp_dir;
+        preferences.speed = speed;
+        preferences.columns.clone_from(&columns);
+        preferences.summary = summary;
+        preferences.theme = theme;
+        preferences.no_recovery = no_recovery;
+        save_preferences(preferences)
+    })?;
+    // Changing the toggles and column states updates the UI in turn, so
+    // must be done outside of with_ui.
+    for (toggle, active) in toggles.into_iter().flatten() {
+        toggle.set_active(active);
+    }
+    if let Some(actions) = column_actions {
+        for column in TrafficColumn::ALL {
+            let visible = columns.iter().any(|name| name == column.name());
+            actions.change_action_state(column.action(),
+                                        &visible.to_variant());
+        }
+    }
+    Ok(())
+}
+
+/// Change the traffic filter according to the selected item's endpoint.
+fn filter_selected(filter: EndpointFilter) -> Result<(), Error> {
+    let mut entry = None;
+    with_ui(|ui| {
+        let item = ui.selected_traffic_item()?;
+        let endpoint = ui.capture
+            .item_endpoint(&item)?
+            .filter(|ep|
+                ![INVALID_EP_NUM, FRAMING_EP_NUM].contains(&ep.number()))
+            .context("The selected item is not on a device endpoint")?;
+        let text = filter.apply(&ui.view_filter_entry.text(),
+                                endpoint.device_address().0,
+                                endpoint.number().0);
+        ui.view_filter_entry.set_text(&text);
+        entry = Some(ui.view_filter_entry.clone());
+        Ok(())
+    })?;
+    // Applying the filter calls back into the UI.
+    if let Some(entry) = entry {
+        entry.emit_activate();
+    }
+    Ok(())
+}
+
+/// Show the data sent on the selected item's endpoint as a hex dump.
+fn follow_stream() -> Result<(), Error> {
+    let mut result = None;
+    with_ui(|ui| {
+        let item = ui.selected_traffic_item()?;
+        let endpoint_id = ui.capture
+            .item_endpoint_id(&item)?
+            .context("The selected item is not on an endpoint")?;
+        let endpoint = ui.capture.endpoints.get(endpoint_id)?;
+        let mut data = Vec::new();
+        let mut length = 0;
+        ui.capture.endpoint_payloads(endpoint_id, |payload| {
+            length += payload.len();
+            if data.len() < HEX_LIMIT {
+                data.extend(payload);
+            }
+            Ok(())
+        })?;
+        data.truncate(HEX_LIMIT);
+        result = Some((endpoint, data, length));
+        Ok(())
+    })?;
+    let Some((endpoint, data, length)) = result else {
+        return Ok(());
+    };
+    let content = stream_view(&endpoint, &data, length);
+    let dialog = WINDOW.with(|cell| {
+        gtk::Dialog::with_buttons(
+            Some(&format!("Stream of endpoint {endpoint}")),
+            cell.borrow().as_ref(),
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[("Close", ResponseType::Close)])
+    });
+    dialog.content_area().append(&content);
+    dialog.connect_response(|dialog, _| dialog.destroy());
+    dialog.show();
+    Ok(())
+}
+
+/// Ask for a file to save the data carried by the selected item to.
+fn choose_payload_export() -> Result<(), Error> {
+    let mut data = None;
+    with_ui(|ui| {
+        let item = ui.selected_traffic_item()?;
+        data = Some(item_data(&mut ui.capture, &item)?);
+        Ok(())
+    })?;
+    let data = data.context("No item selected")?;
     let chooser = WINDOW.with(|cell| {
-        let borrow = cell.borrow();
-        let window = borrow.as_ref();
-        match action {
-            Load => gtk::FileChooserDialog::new(
-                Some("Open pcap file"),
-                window,
-                gtk::FileChooserAction::Open,
-                &[("Open", gtk::ResponseType::Accept)]
-            ),
-            Save => gtk::FileChooserDialog::new(
-                Some("Save pcap file"),
-                window,
-                gtk::FileChooserAction::Save,
-                &[("Save", gtk::ResponseType::Accept)]
-            ),
+        gtk::FileChooserDialog::new(
+            Some("Export payload as binary file"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(file) = dialog.file() {
+                display_error(save_payload(&file, &data));
+            }
         }
+        dialog.destroy();
+    });
+    chooser.show();
+    Ok(())
+}
+
+/// Show timestamps in the traffic view in a new way.
+fn set_time_mode(mode: TimeMode) -> Result<(), Error> {
+    with_ui(|ui| {
+        if mode != ui.time_format.mode {
+            ui.time_format.mode = mode;
+            show_traffic(ui);
+        }
+        Ok(())
+    })
+}
+
+/// Save new color rules and show the traffic in their colors.
+fn set_color_rules(rules: Vec<ColorRule>) -> Result<(), Error> {
+    with_ui(|ui| {
+        ui.color_css.load_from_data(&color_styles(&rules));
+        ui.preferences.color_rules = rules;
+        show_traffic(ui);
+        save_preferences(&ui.preferences)
+    })
+}
+
+/// Show the traffic in the current capture which matches the filter.
+fn show_traffic(ui: &mut UserInterface) {
+    let mut capture = ui.capture.clone();
+    capture.set_filter(ui.traffic_filter.clone());
+    let (traffic_model, traffic_selection, traffic_view) =
+        create_view::<TrafficItem, TrafficModel, TrafficRowData, _>(
+            "Traffic",
+            &capture,
+            |model| MultiSelection::new(Some(model.clone())),
+            #[cfg(any(test, feature="record-ui-test"))]
+            (&ui.recording, "traffic")
+        );
+    // Keep the layout of the columns already shown.
+    let old_columns = ui.traffic_columns();
+    ui.traffic_model = Some(traffic_model.clone());
+    ui.selected_traffic_item = None;
+    ui.selection_stats = None;
+    // The time column needs the filter to find the item shown before.
+    add_time_column(&traffic_view, &capture, ui.time_format);
+    add_traffic_columns(&traffic_view, &ui.capture, &ui.preferences);
+    add_context_menu(&traffic_view,
+                     &column_factories(&traffic_view),
+                     &ui.traffic_menu);
+    add_tooltips(&traffic_view, &ui.capture);
+    ui.traffic_window.set_child(Some(&traffic_view));
+    for (old, new) in old_columns.iter().zip(ui.traffic_columns()) {
+        new.set_fixed_width(old.fixed_width());
+        new.set_visible(old.is_visible());
+    }
+    traffic_selection.connect_selection_changed(
+        move |selection_model, _position, _n_items| {
+            display_error(with_ui(|ui| {
+                ui.selected_traffic_item = None;
+                // The first of the items selected is shown in detail.
+                let selected = selection_model.selection();
+                let first = (!selected.is_empty())
+                    .then(|| selection_model.item(selected.minimum()))
+                    .flatten();
+                let mut fields = Vec::new();
+                let mut bytes = Vec::new();
+                let text = match first {
+                    Some(item) => {
+                        let row = item
+                            .downcast::<TrafficRowData>()
+                            .or_else(|_|
+                                bail!("Item is not TrafficRowData"))?;
+                        match row.node() {
+                            Ok(node_ref) => {
+                                let node = node_ref.borrow();
+                                ui.selected_traffic_item = Some(node.item);
+                                fields = ui.capture
+                                    .detail_fields(&node.item)
+                                    .unwrap_or_else(|e| vec![
+                                        Field::new("Error", e)]);
+                                bytes = ui.capture
+                                    .item_bytes(&node.item, HEX_LIMIT)
+                                    .unwrap_or_default();
+                                traffic_model.description(&node.item, true)
+                            },
+                            Err(msg) => msg
+                        }
+                    },
+                    None => String::from("No item selected"),
+                };
+                let text = match ui.selected_traffic_item
+                    .and_then(|item| ui.session.comment(&item))
+                {
+                    Some(comment) => format!("{text}\n\nComment: {comment}"),
+                    None => text,
+                };
+                ui.detail_text.set_text(&text);
+                ui.show_detail(fields, &bytes);
+                // Totals are shown for a selection of several items.
+                ui.selection_stats = None;
+                if selected.size() > 1 {
+                    let items = row_items(
+                        selection_model.upcast_ref(),
+                        (0..selected.size() as u32)
+                            .map(|index| selected.nth(index)));
+                    ui.selection_stats = Some(ui.capture.item_stats(&items)?);
+                }
+                ui.update_status();
+                Ok(())
+            }))
+        }
+    );
+}
+
+/// Modes of the Find entry, indexed by the FIND_* constants.
+const FIND_MODES: [&str; 4] = ["Text", "Regex", "Payload", "Query"];
+const FIND_REGEX: u32 = 1;
+const FIND_PAYLOAD: u32 = 2;
+const FIND_QUERY: u32 = 3;
+
+/// Select the next item, after or before the selected item, which
+/// matches what was entered in the Find entry.
+fn find(mode: u32, text: &str, forward: bool) -> Result<(), Error> {
+    let mut found = None;
+    with_ui(|ui| {
+        let mut capture = ui.capture.clone();
+        capture.set_filter(ui.traffic_filter.clone());
+        let path = if mode == FIND_PAYLOAD {
+            find_payload(&mut capture, ui.selected_traffic_item, text,
+                         forward)?
+        } else if mode == FIND_QUERY {
+            find_query(&mut capture, ui.selected_traffic_item, text,
+                       forward)?
+        } else {
+            let pattern = TextPattern::new(text, mode == FIND_REGEX)?;
+            let from = match ui.selected_traffic_item {
+                Some(item) => capture.item_path(&item)?,
+                None => None,
+            };
+            find_item(&mut capture, &pattern, from.as_deref(), forward)?
+                .with_context(|| format!(
+                    "No more items found matching '{text}'"))?
+        };
+        found = Some(ui.traffic_row(path)?);
+        Ok(())
+    })?;
+    match found {
+        Some(row) => select_traffic_row(row),
+        None => Ok(())
+    }
+}
+
+/// Select the next or previous item flagged as an error, such as a
+/// malformed packet, a STALL or a timeout.
+fn go_to_error(forward: bool) -> Result<(), Error> {
+    let mut found = None;
+    with_ui(|ui| {
+        let mut capture = ui.capture.clone();
+        capture.set_filter(ui.traffic_filter.clone());
+        let from = match ui.selected_traffic_item {
+            Some(item) => capture.item_path(&item)?,
+            None => None,
+        };
+        let path = find_error(&mut capture, from.as_deref(), forward)?
+            .context(if forward {
+                "No more errors found"
+            } else {
+                "No earlier errors found"
+            })?;
+        found = Some(ui.traffic_row(path)?);
+        Ok(())
+    })?;
+    match found {
+        Some(row) => select_traffic_row(row),
+        None => Ok(())
+    }
+}
+
+/// Select the item bookmarked or commented on at a position in the list.
+fn go_to_annotation(index: usize) -> Result<(), Error> {
+    let mut item = None;
+    with_ui(|ui| {
+        let (_, annotation) = ui.annotations()
+            .nth(index)
+            .context("No such bookmark")?;
+        item = Some(annotation.item);
+        Ok(())
+    })?;
+    match item {
+        Some(item) => go_to_traffic_item(item),
+        None => Ok(())
+    }
+}
+
+/// Select an item in the traffic view.
+fn go_to_traffic_item(item: TrafficItem) -> Result<(), Error> {
+    let mut found = None;
+    with_ui(|ui| {
+        let mut capture = ui.capture.clone();
+        capture.set_filter(ui.traffic_filter.clone());
+        let path = capture
+            .item_path(&item)?
+            .context("The item is hidden by the filter")?;
+        found = Some(ui.traffic_row(path)?);
+        Ok(())
+    })?;
+    match found {
+        Some(row) => select_traffic_row(row),
+        None => Ok(())
+    }
+}
+
+/// Ask for a capture to compare the current one with.
+fn choose_comparison() -> Result<(), Error> {
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Compare with capture file"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Open,
+            &[("Compare", gtk::ResponseType::Accept)])
+    });
+    let options: Vec<(&str, &str)> = Alignment::ALL
+        .iter()
+        .map(|alignment| (alignment.name(), alignment.description()))
+        .collect();
+    chooser.add_choice("align", "Align by:", &options);
+    chooser.set_choice("align", Alignment::Enumeration.name());
+    chooser.connect_response(|dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            let alignment = dialog
+                .choice("align")
+                .and_then(|name| Alignment::from_name(&name))
+                .unwrap_or(Alignment::Enumeration);
+            if let Some(file) = dialog.file() {
+                display_error(start_comparison(file, alignment));
+            }
+        }
+        dialog.destroy();
+    });
+    chooser.show();
+    Ok(())
+}
+
+/// Load a capture and compare the current one with it, in the background.
+fn start_comparison(file: gio::File, alignment: Alignment)
+    -> Result<(), Error>
+{
+    let mut capture = None;
+    with_ui(|ui| {
+        capture = Some(ui.capture.clone());
+        Ok(())
+    })?;
+    let Some(mut first) = capture else {
+        return Ok(());
+    };
+    std::thread::spawn(move || {
+        let result = load_comparison(&file).and_then(|(name, mut second)| {
+            let comparison = compare(&mut first, &mut second, alignment)?;
+            Ok((name, comparison))
+        });
+        match result {
+            Ok((name, comparison)) => {
+                gtk::glib::idle_add_once(move ||
+                    display_error(show_comparison(&name, comparison)));
+            },
+            Err(e) => display_error(Err(e)),
+        }
+    });
+    Ok(())
+}
+
+/// Load a capture to compare with, without showing it.
+fn load_comparison(file: &gio::File)
+    -> Result<(String, CaptureReader), Error>
+{
+    let (name, _, source) = open_source(file, &Cancellable::new())?;
+    let (writer, reader) = create_capture()?;
+    let mut source = BufReader::new(source);
+    if native::is_native(source.fill_buf()?) {
+        native::load(source, writer)?;
+        return Ok((name, reader));
+    }
+    let mut loader = open_loader(&name, source, None)?;
+    let mut decoder = Decoder::new(writer)?;
+    while let Some(result) = loader.next() {
+        let (packet, timestamp_ns) = result?;
+        decoder.handle_packet_from(
+            packet.source, &packet.data, timestamp_ns)?;
+    }
+    decoder.finish()?;
+    Ok((name, reader))
+}
+
+/// Show the requests of two captures side by side, highlighting where
+/// they diverge. Activating a row selects the request in this capture.
+fn show_comparison(name: &str, comparison: Comparison) -> Result<(), Error> {
+    let summary = match comparison.divergence() {
+        Some(index) => format!(
+            "The captures diverge at request {} of {}.",
+            index + 1, comparison.rows.len()),
+        None => format!(
+            "The {} requests compared are the same in both captures.",
+            comparison.rows.len()),
+    };
+    let summary_label = Label::builder()
+        .label(summary)
+        .halign(Align::Start)
+        .build();
+    let heading = gtk::Box::builder()
+        .orientation(Orientation::Horizontal)
+        .homogeneous(true)
+        .build();
+    for title in ["This capture", name] {
+        let label = Label::builder().halign(Align::Start).build();
+        label.set_markup(&format!("<b>{}</b>",
+                                  glib::markup_escape_text(title)));
+        heading.append(&label);
+    }
+    let list = ListBox::new();
+    let mut items = Vec::with_capacity(comparison.rows.len());
+    for row in &comparison.rows {
+        use Row::*;
+        let (first, second) = match *row {
+            Same(i, j) | Differs(i, j) =>
+                (Some(&comparison.first[i]), Some(&comparison.second[j])),
+            First(i) => (Some(&comparison.first[i]), None),
+            Second(j) => (None, Some(&comparison.second[j])),
+        };
+        items.push(first.map(|request| request.item));
+        let row_box = gtk::Box::builder()
+            .orientation(Orientation::Horizontal)
+            .homogeneous(true)
+            .spacing(10)
+            .build();
+        for request in [first, second] {
+            let label = Label::builder()
+                .label(request.map_or("", |request| request.summary.as_str()))
+                .halign(Align::Start)
+                .xalign(0.0)
+                .wrap(true)
+                .build();
+            match row {
+                Differs(..) => label.add_css_class("error"),
+                First(_) | Second(_) => label.add_css_class("warning"),
+                Same(..) => {},
+            }
+            row_box.append(&label);
+        }
+        list.append(&row_box);
+    }
+    list.connect_row_activated(move |_, row| {
+        let index = row.index() as usize;
+        if let Some(&Some(item)) = items.get(index) {
+            display_error(go_to_traffic_item(item));
+        }
+    });
+    let scrolled = ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Never)
+        .min_content_height(400)
+        .vexpand(true)
+        .child(&list)
+        .build();
+    let content = gtk::Box::builder()
+        .orientation(Orientation::Vertical)
+        .spacing(5)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_top(5)
+        .margin_bottom(5)
+        .build();
+    content.append(&summary_label);
+    content.append(&heading);
+    content.append(&scrolled);
+    let dialog = WINDOW.with(|cell| {
+        gtk::Dialog::with_buttons(
+            Some(&format!("Comparison with {name}")),
+            cell.borrow().as_ref(),
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[("Copy as text", ResponseType::Apply),
+              ("Close", ResponseType::Close)])
+    });
+    dialog.set_default_size(900, 500);
+    dialog.content_area().append(&content);
+    let text = comparison.to_text();
+    dialog.connect_response(move |dialog, response| {
+        if response == ResponseType::Apply {
+            WidgetExt::display(dialog).clipboard().set_text(&text);
+        } else {
+            dialog.destroy();
+        }
+    });
+    dialog.show();
+    // Start at the first divergence.
+    if let Some(row) = comparison
+        .divergence()
+        .and_then(|index| list.row_at_index(index as i32))
+    {
+        list.select_row(Some(&row));
+        row.grab_focus();
+    }
+    Ok(())
+}
+
+/// Ask for a packet, transaction, frame or time to go to.
+fn choose_target() -> Result<(), Error> {
+    let dialog = WINDOW.with(|cell| {
+        gtk::Dialog::with_buttons(
+            Some("Go to"),
+            cell.borrow().as_ref(),
+            gtk::DialogFlags::MODAL,
+            &[("Cancel", ResponseType::Cancel),
+              ("Go", ResponseType::Accept)])
+    });
+    dialog.set_default_response(ResponseType::Accept);
+    let entry = Entry::builder()
+        .placeholder_text("packet 1200")
+        .tooltip_text(
+            "A packet or transaction number, an SOF frame number or a \
+             time in seconds, e.g. 'transaction 56', 'frame 1787' or \
+             'time 1.000250'")
+        .activates_default(true)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_top(5)
+        .margin_bottom(5)
+        .build();
+    dialog.content_area().append(&entry);
+    dialog.connect_response(move |dialog, response| {
+        if response == ResponseType::Accept {
+            let result = Target::parse(entry.text().as_str())
+                .and_then(go_to_target);
+            if result.is_err() {
+                // Leave the dialog open to correct the entry.
+                display_error(result);
+                return;
+            }
+        }
+        dialog.destroy();
+    });
+    dialog.show();
+    Ok(())
+}
+
+/// Select the item at a target in the traffic view.
+fn go_to_target(target: Target) -> Result<(), Error> {
+    let mut found = None;
+    with_ui(|ui| {
+        // Frame numbers repeat, so look for the next after the selection.
+        let mut from = None;
+        if let Some(item) = ui.selected_traffic_item {
+            let subset = PacketSubset::Item(item);
+            if let Some(range) =
+                ui.capture.subset_packet_ranges(&subset)?.first()
+            {
+                from = Some(range.start);
+            }
+        }
+        let mut capture = ui.capture.clone();
+        capture.set_filter(ui.traffic_filter.clone());
+        let item = target_item(&mut capture, target, from)?;
+        let path = capture
+            .item_path(&item)?
+            .context("The item is hidden by the filter")?;
+        found = Some(ui.traffic_row(path)?);
+        Ok(())
+    })?;
+    match found {
+        Some(row) => select_traffic_row(row),
+        None => Ok(())
+    }
+}
+
+/// An item to select in the traffic view, and the path leading to it.
+struct TrafficRow {
+    model: TrafficModel,
+    window: ScrolledWindow,
+    path: Vec<u64>,
+}
+
+/// Expand the traffic view to show an item, then select it and scroll to
+/// it. Expanding and selecting rows calls back into the UI, so this must
+/// be done outside of with_ui.
+fn select_traffic_row(row: TrafficRow) -> Result<(), Error> {
+    let TrafficRow { model, window, path } = row;
+    let position = model.reveal(&path)?;
+    let Some(selection) = window
+        .child()
+        .and_downcast::<ColumnView>()
+        .and_then(|view| view.model())
+    else {
+        bail!("Traffic view has no selection model")
+    };
+    selection.select_item(position, true);
+    // Scroll the selected row into view.
+    let rows = selection.n_items().max(1) as f64;
+    let adjustment = window.vadjustment();
+    let row_height = adjustment.upper() / rows;
+    adjustment.set_value(
+        row_height * position as f64 - adjustment.page_size() / 2.0);
+    Ok(())
+}
+
+/// Copy the selected item in the traffic view to the clipboard.
+fn copy_selected(format: CopyFormat) -> Result<(), Error> {
+    with_ui(|ui| {
+        let item = ui.selected_traffic_item()?;
+        let text = copy_text(&mut ui.capture, &item, format)?;
+        WidgetExt::display(&ui.traffic_window)
+            .clipboard()
+            .set_text(&text);
+        Ok(())
+    })
+}
+
+/// Expand or collapse every item in the traffic view.
+fn set_all_expanded(expanded: bool) -> Result<(), Error> {
+    let mut model = None;
+    with_ui(|ui| {
+        model = ui.traffic_model.clone();
+        Ok(())
+    })?;
+    match model {
+        Some(model) => model.set_all_expanded(expanded),
+        None => Ok(())
+    }
+}
+
+/// Expand or collapse the selected item in the traffic view, and all the
+/// items below it. Expanding rows calls back into the UI, so this must be
+/// done outside of with_ui.
+fn set_selected_expanded(expanded: bool) -> Result<(), Error> {
+    let mut found = None;
+    with_ui(|ui| {
+        let selection = ui.traffic_window
+            .child()
+            .and_downcast::<ColumnView>()
+            .and_then(|view| view.model());
+        found = ui.traffic_model.clone().zip(selection);
+        Ok(())
+    })?;
+    let Some((model, selection)) = found else {
+        return Ok(());
+    };
+    let selected = selection.selection();
+    if selected.is_empty() {
+        bail!("Select an item in the Traffic pane first");
+    }
+    let position = selected.minimum();
+    let node_ref = selection
+        .item(position)
+        .and_downcast::<TrafficRowData>()
+        .context("Selected item is not TrafficRowData")?
+        .node()
+        .map_err(Error::msg)?;
+    model.set_subtree_expanded(&node_ref, position, expanded)
+}
+
+/// Find the path to the next packet, after or before the selected item,
+/// whose payload matches a pattern.
+fn find_payload(capture: &mut CaptureReader,
+                selected: Option<TrafficItem>,
+                text: &str,
+                forward: bool)
+    -> Result<Vec<u64>, Error>
+{
+    let pattern = BytePattern::parse(text)?;
+    let mut from = None;
+    if let Some(item) = selected {
+        let subset = PacketSubset::Item(item);
+        if let Some(range) = capture.subset_packet_ranges(&subset)?.first() {
+            // Include the first packet of a selected transfer or
+            // transaction when searching forwards.
+            from = match item {
+                TrafficItem::Packet(..) => Some(range.start),
+                _ if !forward => Some(range.start),
+                _ => range.start.value.checked_sub(1).map(PacketId::from),
+            };
+        }
+    }
+    // Skip any matching packets that the filter hides.
+    loop {
+        let Some(packet_id) = find_packet(capture, &pattern, from, forward)?
+        else {
+            bail!("No more packets found matching '{}'", text.trim())
+        };
+        let item = capture.packet_item(packet_id)?;
+        if let Some(path) = capture.item_path(&item)? {
+            return Ok(path);
+        }
+        from = Some(packet_id);
+    }
+}
+
+/// Find the path to the next transaction, after or before the selected
+/// item, which matches a query.
+fn find_query(capture: &mut CaptureReader,
+              selected: Option<TrafficItem>,
+              text: &str,
+              forward: bool)
+    -> Result<Vec<u64>, Error>
+{
+    let criteria = Criteria::parse(text)?;
+    // Transactions are compared by their first packets. Searching forwards
+    // from a selected transfer includes its own transactions.
+    let mut from = None;
+    if let Some(item) = selected {
+        let subset = PacketSubset::Item(item);
+        if let Some(range) = capture.subset_packet_ranges(&subset)?.first() {
+            let inclusive = forward && matches!(item, TrafficItem::Transfer(_));
+            from = Some((range.start, inclusive));
+        }
+    }
+    let mut matches = query(capture, &criteria)?;
+    if !forward {
+        matches.reverse();
+    }
+    for item in matches {
+        let TrafficItem::Transaction(_, transaction_id) = item else {
+            continue;
+        };
+        if let Some((from, inclusive)) = from {
+            let start = capture.transaction_index.get(transaction_id)?;
+            let beyond = if forward { start > from } else { start < from };
+            if !(beyond || (inclusive && start == from)) {
+                continue;
+            }
+        }
+        // Skip any matching transactions that the filter hides.
+        if let Some(path) = capture.item_path(&item)? {
+            return Ok(path);
+        }
+    }
+    bail!("No more transactions found matching '{}'", text.trim())
+}
+
+pub fn reset_capture() -> Result<CaptureWriter, Error> {
+    let (writer, reader) = create_capture()?;
+    with_ui(|ui| {
+        ui.capture = reader;
+        ui.update_timeline()?;
+        let mut filter = ui.view_filter.clone();
+        ui.apply_toggles(&mut filter);
+        ui.traffic_filter = filter;
+        // Bookmarks and comments belong to the capture they were made on.
+        ui.session.bookmarks.clear();
+        ui.session.comments.clear();
+        ui.show_bookmarks();
+        show_traffic(ui);
+        let (device_model, device_selection, device_view) =
+            create_view::<DeviceItem, DeviceModel, DeviceRowData, _>(
+                "Devices",
+                &ui.capture,
+                |model| SingleSelection::new(Some(model.clone())),
+                #[cfg(any(test, feature="record-ui-test"))]
+                (&ui.recording, "devices")
+            );
+        ui.device_model = Some(device_model);
+        ui.selected_device_item = None;
+        ui.endpoint_count = 2;
+        ui.device_window.set_child(Some(&device_view));
+        ui.stop_button.set_sensitive(false);
+        device_selection.connect_selection_changed(
+            move |selection_model, _position, _n_items| {
+                display_error(with_ui(|ui| {
+                    ui.selected_device_item = None;
+                    if let Some(item) = selection_model.selected_item() {
+                        let row = item
+                            .downcast::<DeviceRowData>()
+                            .or_else(|_| bail!("Item is not DeviceRowData"))?;
+                        if let Ok(node_ref) = row.node() {
+                            let node = node_ref.borrow();
+                            ui.selected_device_item = Some(node.item);
+                            // Show the serial data streams of a CDC data
+                            // interface, the messages exchanged with a
+                            // FIDO interface, or the input from a keyboard
+                            // or mouse, in the detail pane.
+                            if let DeviceItem::Interface(dev, conf, iface) =
+                                node.item
+                            {
+                                let capture = &mut ui.capture;
+                                let mut text =
+                                    capture.serial_data(dev, conf, iface)?;
+                                if text.is_none() {
+                                    text = capture
+                                        .ctap_messages(dev, conf, iface)?;
+                                }
+                                if text.is_none() {
+                                    text = capture.input_reconstruction(
+                                        dev, conf, iface)?;
+                                }
+                                if let Some(text) = text {
+                                    ui.detail_text.set_text(&text);
+                                    ui.show_detail(Vec::new(), &[]);
+                                }
+                            }
+                        }
+                    }
+                    ui.update_traffic_filter()
+                }))
+            }
+        );
+        Ok(())
+    })?;
+    Ok(writer)
+}
+
+pub fn update_view() -> Result<(), Error> {
+    with_ui(|ui| {
+        use FileAction::*;
+        #[cfg(feature="record-ui-test")]
+        let guard = {
+            let guard = UPDATE_LOCK.lock();
+            let packet_count = ui.capture.packet_index.len();
+            ui.recording
+                .borrow_mut()
+                .log_update(packet_count);
+            guard
+        };
+        let mut more_updates = false;
+        if ui.show_progress == Some(Save) {
+            more_updates = true;
+        } else {
+            ui.update_status();
+            if let Some(model) = &ui.traffic_model {
+                let old_count = model.n_items();
+                more_updates |= model.update()?;
+                let new_count = model.n_items();
+                // If any endpoints were added, we need to redraw the rows above
+                // to add the additional columns of the connecting lines.
+                if new_count > old_count {
+                    let new_ep_count = ui.capture.endpoints.len() as u16;
+                    if new_ep_count > ui.endpoint_count {
+                        model.items_changed(0, old_count, old_count);
+                        ui.endpoint_count = new_ep_count;
+                    }
+                }
+            }
+            if let Some(model) = &ui.device_model {
+                more_updates |= model.update()?;
+            }
+            let packets = ui.capture.packet_index.len();
+            if packets != ui.timeline_packets &&
+                (!more_updates ||
+                 ui.timeline_updated.elapsed() >= TIMELINE_UPDATE_INTERVAL)
+            {
+                ui.update_timeline()?;
+            }
+        }
+        if let Some(action) = ui.show_progress {
+            let total = TOTAL.load(Ordering::Relaxed);
+            let current = CURRENT.load(Ordering::Relaxed);
+            let (fraction, text_count) = if total == 0 {
+                (None, fmt_size(current))
+            } else {
+                (Some((current as f64) / (total as f64)),
+                    format!("{} / {}", fmt_size(current), fmt_size(total)))
+            };
+            let mut text = match action {
+                Load => format!("Loaded {text_count} bytes"),
+                Save => format!("Saved {text_count} packets"),
+            };
+            let elapsed = ui.progress_start.elapsed();
+            if let Some(remaining) = remaining_time(current, total, elapsed) {
+                text.push_str(&format!(", about {} remaining",
+                                       fmt_remaining(remaining)));
+            }
+            ui.progress_bar.set_text(Some(&text));
+            match fraction {
+                Some(fraction) => ui.progress_bar.set_fraction(fraction),
+                None => ui.progress_bar.pulse()
+            };
+        }
+        if more_updates {
+            gtk::glib::timeout_add_once(
+                UPDATE_INTERVAL,
+                || display_error(update_view())
+            );
+        }
+        #[cfg(feature="record-ui-test")]
+        drop(guard);
+        Ok(())
+    })
+}
+
+fn choose_file(action: FileAction) -> Result<(), Error> {
+    use FileAction::*;
+    let chooser = WINDOW.with(|cell| {
+        let borrow = cell.borrow();
+        let window = borrow.as_ref();
+        match action {
+            Load => gtk::FileChooserDialog::new(
+                Some("Open capture file"),
+                window,
+                gtk::FileChooserAction::Open,
+                &[("Open", gtk::ResponseType::Accept)]
+            ),
+            Save => gtk::FileChooserDialog::new(
+                Some("Save capture file"),
+                window,
+                gtk::FileChooserAction::Save,
+                &[("Save", gtk::ResponseType::Accept)]
+            ),
+        }
+    });
+    // Choosing several files to open merges them.
+    chooser.set_select_multiple(action == Load);
+    if action == Load {
+        // The format is detected by default, but may be chosen.
+        let mut options = vec![("auto", "Detect automatically")];
+        for format in InputFormat::ALL {
+            options.push((format.name(), format.description()));
+        }
+        chooser.add_choice("format", "Format:", &options);
+        chooser.set_choice("format", "auto");
+    }
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            let files: Vec<gio::File> = dialog
+                .files()
+                .iter::<gio::File>()
+                .filter_map(Result::ok)
+                .collect();
+            let format = dialog
+                .choice("format")
+                .filter(|name| name.as_str() != "auto")
+                .and_then(|name| InputFormat::from_name(&name).ok());
+            if !files.is_empty() {
+                display_error(start_pcap(action, files, format));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+/// Choose capture files to open in a new window.
+fn choose_new_window_file() -> Result<(), Error> {
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Open capture file in new window"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Open,
+            &[("Open", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.set_select_multiple(true);
+    let mut options = vec![("auto", "Detect automatically")];
+    for format in InputFormat::ALL {
+        options.push((format.name(), format.description()));
+    }
+    chooser.add_choice("format", "Format:", &options);
+    chooser.set_choice("format", "auto");
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            let files: Vec<gio::File> = dialog
+                .files()
+                .iter::<gio::File>()
+                .filter_map(Result::ok)
+                .collect();
+            let format = dialog
+                .choice("format")
+                .filter(|name| name.as_str() != "auto")
+                .and_then(|name| InputFormat::from_name(&name).ok());
+            if !files.is_empty() {
+                display_error(open_new_window(&files, format));
+            }
+        }
+        dialog.destroy();
+    });
+    chooser.show();
+    Ok(())
+}
+
+/// Open a new window, optionally loading capture files into it.
+///
+/// Each window is a separate instance of the application, so that the
+/// captures open in each have their own models, filters and settings.
+fn open_new_window(files: &[gio::File], format: Option<InputFormat>)
+    -> Result<(), Error>
+{
+    if files.iter().any(is_stdin) {
+        bail!("Standard input cannot be opened in a new window");
+    }
+    let executable = std::env::current_exe()
+        .context("Failed to find the Packetry executable")?;
+    let mut command = std::process::Command::new(executable);
+    if let Some(format) = format {
+        command.arg(format!("--format={}", format.name()));
+    }
+    // Command line arguments may be paths or URIs.
+    command.args(files.iter().map(|file| match file.path() {
+        Some(path) => path.into_os_string(),
+        None => file.uri().to_string().into(),
+    }));
+    command.spawn().context("Failed to start a new window")?;
+    Ok(())
+}
+
+fn start_pcap(action: FileAction,
+              files: Vec<gio::File>,
+              format: Option<InputFormat>)
+    -> Result<(), Error>
+{
+    use FileAction::*;
+    let file = files.first().context("No file chosen")?.clone();
+    let writer = if action == Load {
+        // Keep the layout of the capture being replaced.
+        display_error(with_ui(|ui| ui.update_session()));
+        Some(reset_capture()?)
+    } else {
+        None
+    };
+    with_ui(|ui| {
+        let cancel_handle = Cancellable::new();
+        #[cfg(feature="record-ui-test")]
+        ui.recording.borrow_mut().log_open_file(
+            &file.path().context("Cannot record UI test for non-local path")?,
+            &ui.capture);
+        ui.open_button.set_sensitive(false);
+        ui.save_button.set_sensitive(false);
+        ui.scan_button.set_sensitive(false);
+        ui.selector.set_sensitive(false);
+        ui.capture_button.set_sensitive(false);
+        ui.stop_button.set_sensitive(true);
+        ui.stop_state = StopState::Pcap(cancel_handle.clone());
+        ui.vbox.insert_child_after(&ui.separator, Some(&ui.vertical_panes));
+        ui.vbox.insert_child_after(&ui.progress_box, Some(&ui.separator));
+        ui.show_progress = Some(action);
+        ui.progress_start = Instant::now();
+        // The session is kept alongside a single capture file.
+        let single_file = files.len() == 1 && !is_stdin(&file);
+        match action {
+            Load => {
+                ui.capture_file = single_file.then(|| file.clone());
+                ui.session = match &ui.capture_file {
+                    Some(file) => load_session(file)?,
+                    None => Session::default(),
+                };
+                ui.apply_layout();
+                ui.show_bookmarks();
+            },
+            Save => {
+                ui.record_layout();
+                save_session(&file, &ui.session)?;
+                ui.capture_file = Some(file.clone());
+            },
+        }
+        if single_file {
+            display_error(ui.add_recent_file(&file));
+        }
+        ui.file_name = match files.len() {
+            1 if is_stdin(&file) => Some(STDIN_NAME.to_string()),
+            1 => file
+                .basename()
+                .map(|path| path.to_string_lossy().to_string()),
+            count => Some(format!("{count} merged captures")),
+        };
+        let capture = ui.capture.clone();
+        let packet_count = capture.packet_index.len();
+        CURRENT.store(0, Ordering::Relaxed);
+        TOTAL.store(match action {
+            Load => 0,
+            Save => packet_count,
+        }, Ordering::Relaxed);
+        std::thread::spawn(move || {
+            let start_time = Instant::now();
+            let result = match action {
+                Load if files.len() > 1 =>
+                    merge_pcaps(files, format, writer.unwrap(), cancel_handle),
+                Load =>
+                    load_pcap(file, format, writer.unwrap(), cancel_handle),
+                Save => save_pcap(file, capture, cancel_handle),
+            };
+            let duration = Instant::now().duration_since(start_time);
+            if result.is_ok() {
+                eprintln!("{} in {} ms",
+                    match action {
+                        Load => "Loaded",
+                        Save => "Saved",
+                    },
+                    duration.as_millis()
+                );
+            }
+            display_error(result);
+            gtk::glib::idle_add_once(|| {
+                STOP.store(false, Ordering::Relaxed);
+                display_error(
+                    with_ui(|ui| {
+                        ui.show_progress = None;
+                        ui.vbox.remove(&ui.separator);
+                        ui.vbox.remove(&ui.progress_box);
+                        ui.stop_state = StopState::Disabled;
+                        ui.stop_button.set_sensitive(false);
+                        ui.open_button.set_sensitive(true);
+                        ui.save_button.set_sensitive(true);
+                        ui.scan_button.set_sensitive(true);
+                        ui.selector.set_sensitive(true);
+                        ui.capture_button.set_sensitive(ui.selector.device_available());
+                        // Describe the bookmarked items, now loaded.
+                        ui.show_bookmarks();
+                        Ok(())
+                    })
+                );
+            });
+        });
+        gtk::glib::timeout_add_once(
+            UPDATE_INTERVAL,
+            || display_error(update_view()));
+        Ok(())
+    })
+}
+
+/// Estimate the time an operation has left, from the time taken so far,
+/// once it has run long enough for the estimate to be useful.
+fn remaining_time(current: u64, total: u64, elapsed: Duration)
+    -> Option<Duration>
+{
+    if total == 0 || current == 0 || current >= total ||
+        elapsed < Duration::from_secs(1)
+    {
+        return None;
+    }
+    let fraction = current as f64 / total as f64;
+    Some(elapsed.mul_f64((1.0 - fraction) / fraction))
+}
+
+/// Describe a remaining time to the nearest second, or minute if long.
+fn fmt_remaining(remaining: Duration) -> String {
+    let seconds = remaining.as_secs_f64().round() as u64;
+    match seconds {
+        0..=1 => String::from("1 second"),
+        2..=99 => format!("{seconds} seconds"),
+        _ => format!("{} minutes", (seconds + 30) / 60),
+    }
+}
+
+/// The session file kept alongside a capture file.
+fn session_file(file: &gio::File) -> Result<gio::File, Error> {
+    let name = file.basename().context("Capture file has no name")?;
+    let parent = file.parent().context("Capture file has no parent")?;
+    Ok(parent.child(session::file_name(&name.to_string_lossy())))
+}
+
+/// Load the session saved alongside a capture file, if there is one.
+fn load_session(file: &gio::File) -> Result<Session, Error> {
+    let session_file = session_file(file)?;
+    if !session_file.query_exists(Cancellable::NONE) {
+        return Ok(Session::default());
+    }
+    let source = session_file.read(Cancellable::NONE)?.into_read();
+    Session::load(source).context("Failed to load session")
+}
+
+/// Save a session alongside a capture file.
+fn save_session(file: &gio::File, session: &Session) -> Result<(), Error> {
+    let dest = session_file(file)?
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write();
+    session.save(dest)
+}
+
+fn save_current_session() -> Result<(), Error> {
+    with_ui(|ui| {
+        let file = ui.capture_file
+            .clone()
+            .context("Save the capture to a file before saving its session")?;
+        ui.record_layout();
+        save_session(&file, &ui.session)?;
+        ui.status_label.set_text("Session saved");
+        Ok(())
+    })
+}
+
+fn load_pcap(file: gio::File,
+             format: Option<InputFormat>,
+             writer: CaptureWriter,
+             cancel_handle: Cancellable)
+    -> Result<(), Error>
+{
+    let (name, size, source) = open_source(&file, &cancel_handle)?;
+    if let Some(size) = size {
+        TOTAL.store(size, Ordering::Relaxed);
+    }
+    let mut source = BufReader::new(source);
+    if format.is_none() && native::is_native(source.fill_buf()?) {
+        // The capture was saved with its indices, so needs no decoding.
+        let writer = native::load(source, writer)?;
+        CURRENT.store(TOTAL.load(Ordering::Relaxed), Ordering::Relaxed);
+        writer.print_storage_summary();
+        return Ok(());
+    }
+    // Packets are decoded as they are read, so a capture arriving through
+    // a pipe can be viewed while it is still being written.
+    let mut loader = open_loader(&name, source, format)?;
+    let shared = writer.shared.clone();
+    let mut decoder = Decoder::new(writer)?;
+    #[cfg(feature="step-decoder")]
+    let (mut client, _addr) =
+        TcpListener::bind("127.0.0.1:46563")?.accept()?;
+    let overflow_count = loader.overflow_count();
+    let mut overflows = 0;
+    let mut source_count = 0;
+    while let Some(result) = loader.next() {
+        let (packet, timestamp_ns) = match result {
+            // Keep what was read before the operation was cancelled.
+            Err(_) if cancel_handle.is_cancelled() => break,
+            result => result?,
+        };
+        #[cfg(feature="step-decoder")] {
+            let mut buf = [0; 1];
+            client.read(&mut buf).unwrap();
+        };
+        #[cfg(feature="record-ui-test")]
+        let guard = UPDATE_LOCK.lock();
+        // Show where the capture device's buffer overflowed.
+        let total_overflows = overflow_count
+            .as_ref()
+            .map_or(0, |count| count.load(Ordering::Relaxed));
+        while overflows < total_overflows {
+            decoder.handle_loss(None, timestamp_ns);
+            overflows += 1;
+        }
+        let source = packet.source;
+        decoder.handle_packet_from(source, &packet.data, timestamp_ns)?;
+        // Name each source along with its first packet.
+        if source as usize >= source_count {
+            source_count = source as usize + 1;
+            shared.sources.swap(Arc::new(loader.source_names()));
+        }
+        #[cfg(feature="record-ui-test")]
+        drop(guard);
+        if let Some(start_time) = loader.start_time() {
+            shared.start_time.get_or_init(|| start_time);
+        }
+        CURRENT.store(loader.file_bytes_read(), Ordering::Relaxed);
+        if STOP.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+    report_losses(&loader, None);
+    let writer = decoder.finish()?;
+    writer.print_storage_summary();
+    Ok(())
+}
+
+/// Name shown for a capture read from standard input.
+const STDIN_NAME: &str = "standard input";
+
+/// Whether a file is "-", meaning that the capture should be read from
+/// standard input.
+fn is_stdin(file: &gio::File) -> bool {
+    file.basename().is_some_and(|name| name.as_os_str() == "-") &&
+        !file.query_exists(Cancellable::NONE)
+}
+
+/// The name of a capture source, its size if known, and a reader for its
+/// contents.
+type CaptureSource = (String, Option<u64>, Box<dyn Read>);
+
+/// Open a capture file, or standard input.
+fn open_source(file: &gio::File, cancel_handle: &Cancellable)
+    -> Result<CaptureSource, Error>
+{
+    if is_stdin(file) {
+        let source = Box::new(StdinReader::new(cancel_handle.clone()));
+        return Ok((STDIN_NAME.to_string(), None, source));
+    }
+    let name = file
+        .basename()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let info = file.query_info("standard::*",
+                               FileQueryInfoFlags::NONE,
+                               Some(cancel_handle))?;
+    // Named pipes are reported with a size of zero.
+    let size = info
+        .has_attribute(gio::FILE_ATTRIBUTE_STANDARD_SIZE)
+        .then(|| info.size() as u64)
+        .filter(|&size| size > 0);
+    let source = Box::new(file.read(Some(cancel_handle))?.into_read());
+    Ok((name, size, source))
+}
+
+/// Reads standard input on a helper thread, so that reading can be
+/// cancelled while waiting for data.
+///
+/// If cancelled, the helper thread is left waiting until more data
+/// arrives or the input is closed.
+struct StdinReader {
+    chunks: std::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    offset: usize,
+    cancel_handle: Cancellable,
+}
+
+impl StdinReader {
+    fn new(cancel_handle: Cancellable) -> StdinReader {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut stdin = std::io::stdin();
+            loop {
+                let mut chunk = vec![0; 0x10000];
+                let result = stdin.read(&mut chunk).map(|length| {
+                    chunk.truncate(length);
+                    chunk
+                });
+                let end = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+                if tx.send(result).is_err() || end {
+                    break;
+                }
+            }
+        });
+        StdinReader {
+            chunks: rx,
+            chunk: Vec::new(),
+            offset: 0,
+            cancel_handle,
+        }
+    }
+}
+
+impl Read for StdinReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::sync::mpsc::RecvTimeoutError::*;
+        while self.offset == self.chunk.len() {
+            if self.cancel_handle.is_cancelled() {
+                return Err(std::io::Error::other("Reading was cancelled"));
+            }
+            match self.chunks.recv_timeout(Duration::from_millis(100)) {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.offset = 0;
+                    if self.chunk.is_empty() {
+                        return Ok(0);
+                    }
+                },
+                Err(Timeout) => {},
+                Err(Disconnected) => return Ok(0),
+            }
+        }
+        let length = buf.len().min(self.chunk.len() - self.offset);
+        buf[..length].copy_from_slice(&self.chunk[self.offset..][..length]);
+        self.offset += length;
+        Ok(length)
+    }
+}
+
+/// Open a loader for a capture file, in the format given, or else one
+/// chosen according to its name and contents.
+fn open_loader<Source: Read>(name: &str,
+                             source: Source,
+                             format: Option<InputFormat>)
+    -> Result<Loader<Source>, Error>
+{
+    match format {
+        Some(format) => Loader::open_as(source, format),
+        None if iti1480a::is_capture_name(name) =>
+            Loader::open_as(source, InputFormat::Iti1480a),
+        None => Loader::open(source),
+    }
+}
+
+/// Report any packets that were skipped or lost while loading a file.
+fn report_losses<Source: Read>(loader: &Loader<Source>,
+                               name: Option<&str>)
+{
+    let prefix = name.map(|name| format!("{name}: ")).unwrap_or_default();
+    for (interface, count) in loader.skipped() {
+        eprintln!("{prefix}Skipped {count} packets from {interface}, \
+                   which is not the USB capture source");
+    }
+    let overflows = loader.overflows();
+    if overflows > 0 {
+        eprintln!("{prefix}Capture device reported {overflows} buffer \
+                   overflows, so some packets are missing");
+    }
+}
+
+/// Load several capture files, merging them into one capture.
+fn merge_pcaps(files: Vec<gio::File>,
+               format: Option<InputFormat>,
+               writer: CaptureWriter,
+               cancel_handle: Cancellable)
+    -> Result<(), Error>
+{
+    let mut names = Vec::new();
+    let mut loaders = Vec::new();
+    let mut total_size = 0;
+    for file in files {
+        let (name, size, source) = open_source(&file, &cancel_handle)?;
+        total_size += size.unwrap_or(0);
+        let mut source = BufReader::new(source);
+        if format.is_none() && native::is_native(source.fill_buf()?) {
+            bail!("{name} is saved in Packetry's own format, \
+                   so cannot be merged with other captures");
+        }
+        loaders.push(open_loader(&name, source, format)
+            .with_context(|| format!("Failed to open {name}"))?);
+        names.push(name);
+    }
+    TOTAL.store(total_size, Ordering::Relaxed);
+    writer.shared.sources.swap(Arc::new(names.clone()));
+    let mut merger = Merger::new(loaders)?;
+    if let Some(start_time) = merger.start_time() {
+        writer.shared.start_time.get_or_init(|| start_time);
+    }
+    let shared = writer.shared.clone();
+    let mut decoder = Decoder::new(writer)?;
+    let mut source_count = names.len();
+    loop {
+        let (source, packet, timestamp_ns) = match merger.next() {
+            Ok(Some(next)) => next,
+            Ok(None) => break,
+            // Keep what was read before the operation was cancelled.
+            Err(_) if cancel_handle.is_cancelled() => break,
+            Err(e) => return Err(e),
+        };
+        // Name any new source found within one of the captures.
+        if source as usize >= source_count {
+            source_count = source as usize + 1;
+            shared.sources.swap(Arc::new(merger.source_names(&names)));
+        }
+        #[cfg(feature="record-ui-test")]
+        let guard = UPDATE_LOCK.lock();
+        decoder.handle_merged_packet(source, &packet, timestamp_ns)?;
+        #[cfg(feature="record-ui-test")]
+        drop(guard);
+        CURRENT.store(merger.bytes_read(), Ordering::Relaxed);
+        if STOP.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+    for (loader, name) in merger.loaders().zip(&names) {
+        report_losses(loader, Some(name));
+    }
+    let writer = decoder.finish()?;
+    writer.print_storage_summary();
+    Ok(())
+}
+
+fn save_pcap(file: gio::File,
+             mut capture: CaptureReader,
+             cancel_handle: Cancellable)
+    -> Result<(), Error>
+{
+    let packet_count = capture.packet_index.len();
+    let name = file
+        .basename()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let dest = file
+        .replace(None, false, FileCreateFlags::NONE, Some(&cancel_handle))?
+        .into_write();
+    if native::is_native_name(&name) {
+        native::save(&mut capture, dest)?;
+        CURRENT.store(packet_count, Ordering::Relaxed);
+        return Ok(());
+    }
+    let format = FileFormat::from_file_name(&name);
+    let mut writer = Writer::open(dest, format)?;
+    for (result, i) in capture.timestamped_packets()?.zip(0..packet_count) {
+        let (timestamp_ns, packet) = result?;
+        writer.add_packet(&packet, timestamp_ns)?;
+        CURRENT.store(i + 1, Ordering::Relaxed);
+        if STOP.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+    writer.close()?;
+    Ok(())
+}
+
+/// Part of the capture to be exported as a new capture file.
+#[derive(Copy, Clone)]
+enum ExportSelection {
+    /// The packets of the selected traffic item.
+    Item,
+    /// All packets captured during the selected traffic item.
+    ItemTime,
+    /// The packets exchanged with the selected device.
+    Device,
+}
+
+fn choose_subset_export(selection: ExportSelection) -> Result<(), Error> {
+    let mut chosen = None;
+    with_ui(|ui| {
+        let subset = match selection {
+            ExportSelection::Item =>
+                PacketSubset::Item(ui.selected_traffic_item()?),
+            ExportSelection::ItemTime => {
+                let item = ui.selected_traffic_item()?;
+                PacketSubset::TimeRange(ui.capture.item_time_range(&item)?)
+            },
+            ExportSelection::Device =>
+                PacketSubset::Device(ui.selected_device()?),
+        };
+        chosen = Some((subset, ui.capture.clone()));
+        Ok(())
+    })?;
+    let (subset, capture) = chosen.context("Nothing selected to export")?;
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Export selected packets as capture file"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(file) = dialog.file() {
+                let capture = capture.clone();
+                let subset = subset.clone();
+                std::thread::spawn(move || display_error(
+                    save_subset(file, capture, subset)));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+fn save_subset(file: gio::File,
+               mut capture: CaptureReader,
+               subset: PacketSubset)
+    -> Result<(), Error>
+{
+    let name = file
+        .basename()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let packets = capture.subset_packets(&subset)?;
+    let dest = file
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write();
+    let count = if native::is_native_name(&name) {
+        native::save_packets(packets, dest)?
+    } else {
+        let mut writer = Writer::open(dest, FileFormat::from_file_name(&name))?;
+        let mut count = 0;
+        for result in packets {
+            let (timestamp_ns, packet) = result?;
+            writer.add_packet(&packet, timestamp_ns)?;
+            count += 1;
+        }
+        writer.close()?;
+        count
+    };
+    eprintln!("Exported {count} packets");
+    Ok(())
+}
+
+fn choose_report_export(hex_dump: bool) -> Result<(), Error> {
+    let mut chosen = None;
+    with_ui(|ui| {
+        chosen = Some(ui.capture.clone());
+        Ok(())
+    })?;
+    let capture = chosen.context("No capture to export")?;
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Export traffic as text file"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(file) = dialog.file() {
+                let capture = capture.clone();
+                std::thread::spawn(move || display_error(
+                    save_report(file, capture, hex_dump)));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+fn save_report(file: gio::File,
+               mut capture: CaptureReader,
+               hex_dump: bool)
+    -> Result<(), Error>
+{
+    let dest = file
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write();
+    let count = report::write_traffic_report(&mut capture, hex_dump, dest)?;
+    eprintln!("Exported {count} items as text");
+    Ok(())
+}
+
+fn choose_html_export(selected_item: bool) -> Result<(), Error> {
+    let mut chosen = None;
+    with_ui(|ui| {
+        let item = if selected_item {
+            Some(ui.selected_traffic_item()?)
+        } else {
+            None
+        };
+        let title = ui.file_name
+            .clone()
+            .unwrap_or_else(|| "Unsaved capture".to_string());
+        chosen = Some((item, title, ui.capture.clone()));
+        Ok(())
+    })?;
+    let (item, title, capture) = chosen.context("No capture to export")?;
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Export traffic as HTML file"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(file) = dialog.file() {
+                let capture = capture.clone();
+                let title = title.clone();
+                std::thread::spawn(move || display_error(
+                    save_html_report(file, capture, item, title)));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+fn save_html_report(file: gio::File,
+                    mut capture: CaptureReader,
+                    item: Option<TrafficItem>,
+                    title: String)
+    -> Result<(), Error>
+{
+    let dest = file
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write();
+    let count = report::write_html_report(
+        &mut capture, item.as_ref(), &title, dest)?;
+    eprintln!("Exported {count} items as HTML");
+    Ok(())
+}
+
+/// The items shown at some positions of the traffic view.
+fn row_items(selection: &gtk::SelectionModel,
+             positions: impl Iterator<Item=u32>)
+    -> Vec<TrafficItem>
+{
+    positions
+        .filter_map(|position| selection
+            .item(position)
+            .and_downcast::<TrafficRowData>())
+        .filter_map(|row| row.node().ok())
+        .map(|node_ref| node_ref.borrow().item)
+        .collect()
+}
+
+/// The rows of the traffic view to export as an image or print.
+fn export_rows(content: ViewContent) -> Result<Vec<ViewRow>, Error> {
+    let mut rows = Vec::new();
+    with_ui(|ui| {
+        let selection = ui.traffic_window
+            .child()
+            .and_downcast::<ColumnView>()
+            .and_then(|view| view.model())
+            .context("Traffic view has no selection model")?;
+        let items = match content {
+            ViewContent::Visible => {
+                let count = selection.n_items();
+                let adjustment = ui.traffic_window.vadjustment();
+                let row_height = adjustment.upper() / count.max(1) as f64;
+                let first = (adjustment.value() / row_height) as u32;
+                let shown = (adjustment.page_size() / row_height).ceil();
+                let end = (first + shown as u32 + 1).min(count);
+                row_items(&selection, first..end)
+            },
+            ViewContent::Expanded => {
+                let selected = selection.selection();
+                row_items(&selection, (0..selected.size() as u32)
+                    .map(|index| selected.nth(index)))
+            },
+        };
+        if items.is_empty() {
+            match content {
+                ViewContent::Visible => bail!("No traffic to export"),
+                ViewContent::Expanded =>
+                    bail!("Select items in the Traffic pane first"),
+            }
+        }
+        let mut capture = ui.capture.clone();
+        capture.set_filter(ui.traffic_filter.clone());
+        rows = report::view_rows(&mut capture,
+                                 &items,
+                                 content == ViewContent::Expanded,
+                                 &ui.preferences)?;
+        Ok(())
+    })?;
+    Ok(rows)
+}
+
+fn choose_image_export() -> Result<(), Error> {
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Export view as PNG or SVG image"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    let options: Vec<(&str, &str)> = ViewContent::ALL
+        .iter()
+        .map(|content| (content.name(), content.description()))
+        .collect();
+    chooser.add_choice("rows", "Rows:", &options);
+    chooser.set_choice("rows", ViewContent::Visible.name());
+    chooser.set_current_name("traffic.png");
+    chooser.connect_response(|dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            let content = dialog
+                .choice("rows")
+                .and_then(|name| ViewContent::from_name(&name))
+                .unwrap_or(ViewContent::Visible);
+            if let Some(file) = dialog.file() {
+                display_error(export_rows(content)
+                    .and_then(|rows| save_view_image(&file, &rows)));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+/// Print rows of the traffic view, chosen in a tab of the print dialog.
+fn print_view() -> Result<(), Error> {
+    let window = WINDOW.with(|cell| cell.borrow().clone());
+    view_export::print_view(window.as_ref(), export_rows)
+}
+
+fn choose_descriptor_export(c_structs: bool) -> Result<(), Error> {
+    let mut chosen = None;
+    with_ui(|ui| {
+        chosen = Some(ui.capture.clone());
+        Ok(())
+    })?;
+    let capture = chosen.context("No capture to export")?;
+    let title = if c_structs {
+        "Export descriptors as C source file"
+    } else {
+        "Export descriptors as text file"
+    };
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some(title),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(file) = dialog.file() {
+                let capture = capture.clone();
+                std::thread::spawn(move || display_error(
+                    save_descriptors(file, capture, c_structs)));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+fn save_descriptors(file: gio::File,
+                    mut capture: CaptureReader,
+                    c_structs: bool)
+    -> Result<(), Error>
+{
+    let dest = file
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write();
+    let count = if c_structs {
+        descriptors::write_c_initializers(&mut capture, dest)?
+    } else {
+        descriptors::write_lsusb(&mut capture, dest)?
+    };
+    eprintln!("Exported descriptors of {count} devices");
+    Ok(())
+}
+
+fn choose_data_export() -> Result<(), Error> {
+    let mut selection = None;
+    with_ui(|ui| {
+        let (endpoint_id, _iface) = ui.selected_endpoint()?;
+        selection = Some((endpoint_id, ui.capture.clone()));
+        Ok(())
+    })?;
+    let (endpoint_id, capture) =
+        selection.context("No endpoint selected")?;
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Export endpoint data as binary file"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(file) = dialog.file() {
+                let capture = capture.clone();
+                std::thread::spawn(move || display_error(
+                    save_endpoint_data(file, capture, endpoint_id)));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+fn save_endpoint_data(file: gio::File,
+                      mut capture: CaptureReader,
+                      endpoint_id: EndpointId)
+    -> Result<(), Error>
+{
+    use std::io::{BufWriter, Write};
+    let mut dest = BufWriter::new(file
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write());
+    let mut size = 0;
+    capture.endpoint_payloads(endpoint_id, |payload| {
+        dest.write_all(&payload)?;
+        size += payload.len() as u64;
+        Ok(())
+    })?;
+    dest.flush()?;
+    eprintln!("Exported {} of endpoint data", fmt_size(size));
+    Ok(())
+}
+
+fn choose_audio_export() -> Result<(), Error> {
+    let mut selection = None;
+    with_ui(|ui| {
+        let (endpoint_id, iface) = ui.selected_endpoint()?;
+        if !uac::is_streaming(&iface) {
+            bail!("The selected endpoint is not on an audio streaming interface")
+        }
+        selection = Some((endpoint_id, ui.capture.clone()));
+        Ok(())
+    })?;
+    let (endpoint_id, capture) =
+        selection.context("No audio endpoint selected")?;
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Export audio as WAV file"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(file) = dialog.file() {
+                let capture = capture.clone();
+                std::thread::spawn(move || display_error(
+                    save_audio(file, capture, endpoint_id)));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+fn save_audio(file: gio::File,
+              mut capture: CaptureReader,
+              endpoint_id: EndpointId)
+    -> Result<(), Error>
+{
+    use std::io::Write;
+    let (format, data) = capture
+        .audio_data(endpoint_id)?
+        .context("No supported audio format found for this endpoint")?;
+    let mut dest = file
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write();
+    dest.write_all(&format.wav_file(&data))?;
+    eprintln!("Exported {} of audio, {}",
+              fmt_size(data.len() as u64), format.description());
+    Ok(())
+}
+
+fn choose_video_export() -> Result<(), Error> {
+    let mut selection = None;
+    with_ui(|ui| {
+        let (endpoint_id, iface) = ui.selected_endpoint()?;
+        if !uvc::is_streaming(&iface) {
+            bail!("The selected endpoint is not on a video streaming interface")
+        }
+        selection = Some((endpoint_id, ui.capture.clone()));
+        Ok(())
+    })?;
+    let (endpoint_id, capture) =
+        selection.context("No video endpoint selected")?;
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Export video frames to folder"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::SelectFolder,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(folder) = dialog.file() {
+                let capture = capture.clone();
+                std::thread::spawn(move || display_error(
+                    save_video_frames(folder, capture, endpoint_id)));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+fn save_video_frames(folder: gio::File,
+                     mut capture: CaptureReader,
+                     endpoint_id: EndpointId)
+    -> Result<(), Error>
+{
+    use std::io::Write;
+    let mut number = 0;
+    let count = capture.video_frames(endpoint_id, |frame| {
+        number += 1;
+        let name = format!(
+            "frame-{number:05}.{}", uvc::frame_extension(&frame));
+        let mut dest = folder
+            .child(name)
+            .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+            .into_write();
+        dest.write_all(&frame)?;
+        Ok(())
+    })?;
+    eprintln!("Exported {count} video frames");
+    Ok(())
+}
+
+fn choose_hci_export() -> Result<(), Error> {
+    let mut selection = None;
+    with_ui(|ui| {
+        selection = Some((ui.selected_device()?, ui.capture.clone()));
+        Ok(())
+    })?;
+    let (device_id, capture) =
+        selection.context("No Bluetooth device selected")?;
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Export Bluetooth HCI as btsnoop file"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
+    });
+    chooser.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(file) = dialog.file() {
+                let capture = capture.clone();
+                std::thread::spawn(move || display_error(
+                    save_hci(file, capture, device_id)));
+            }
+            dialog.destroy();
+        }
+    });
+    chooser.show();
+    Ok(())
+}
+
+fn save_hci(file: gio::File,
+            mut capture: CaptureReader,
+            device_id: DeviceId)
+    -> Result<(), Error>
+{
+    let packets = capture.hci_packets(device_id)?;
+    let dest = file
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+        .into_write();
+    let mut writer = btsnoop::Writer::open(dest)?;
+    for packet in &packets {
+        writer.add_packet(packet)?;
+    }
+    writer.close()?;
+    eprintln!("Exported {} HCI packets", packets.len());
+    Ok(())
+}
+
+fn choose_facedancer_export() -> Result<(), Error> {
+    let mut selection = None;
+    with_ui(|ui| {
+        selection = Some((ui.selected_device()?, ui.capture.clone()));
+        Ok(())
+    })?;
+    let (device_id, capture) = selection.context("No device selected")?;
+    let chooser = WINDOW.with(|cell| {
+        gtk::FileChooserDialog::new(
+            Some("Export device as Facedancer script"),
+            cell.borrow().as_ref(),
+            gtk::FileChooserAction::Save,
+            &[("Export", gtk::ResponseType::Accept)]
+        )
     });
+    chooser.set_current_name("device.py");
     chooser.connect_response(move |dialog, response| {
         if response == gtk::ResponseType::Accept {
             if let Some(file) = dialog.file() {
-                display_error(start_pcap(action, file));
+                let capture = capture.clone();
+                std::thread::spawn(move || display_error(
+                    save_facedancer(file, capture, device_id)));
             }
             dialog.destroy();
         }
@@ -920,140 +5028,16 @@ fn choose_file(action: FileAction) -> Result<(), Error> {
     Ok(())
 }
 
-fn start_pcap(action: FileAction, file: gio::File) -> Result<(), Error> {
-    use FileAction::*;
-    let writer = if action == Load {
-        Some(reset_capture()?)
-    } else {
-        None
-    };
-    with_ui(|ui| {
-        let cancel_handle = Cancellable::new();
-        #[cfg(feature="record-ui-test")]
-        ui.recording.borrow_mut().log_open_file(
-            &file.path().context("Cannot record UI test for non-local path")?,
-            &ui.capture);
-        ui.open_button.set_sensitive(false);
-        ui.save_button.set_sensitive(false);
-        ui.scan_button.set_sensitive(false);
-        ui.selector.set_sensitive(false);
-        ui.capture_button.set_sensitive(false);
-        ui.stop_button.set_sensitive(true);
-        ui.stop_state = StopState::Pcap(cancel_handle.clone());
-        ui.vbox.insert_child_after(&ui.separator, Some(&ui.vertical_panes));
-        ui.vbox.insert_child_after(&ui.progress_bar, Some(&ui.separator));
-        ui.show_progress = Some(action);
-        ui.file_name = file
-            .basename()
-            .map(|path| path.to_string_lossy().to_string());
-        let capture = ui.capture.clone();
-        let packet_count = capture.packet_index.len();
-        CURRENT.store(0, Ordering::Relaxed);
-        TOTAL.store(match action {
-            Load => 0,
-            Save => packet_count,
-        }, Ordering::Relaxed);
-        std::thread::spawn(move || {
-            let start_time = Instant::now();
-            let result = match action {
-                Load => load_pcap(file, writer.unwrap(), cancel_handle),
-                Save => save_pcap(file, capture, cancel_handle),
-            };
-            let duration = Instant::now().duration_since(start_time);
-            if result.is_ok() {
-                eprintln!("{} in {} ms",
-                    match action {
-                        Load => "Loaded",
-                        Save => "Saved",
-                    },
-                    duration.as_millis()
-                );
-            }
-            display_error(result);
-            gtk::glib::idle_add_once(|| {
-                STOP.store(false, Ordering::Relaxed);
-                display_error(
-                    with_ui(|ui| {
-                        ui.show_progress = None;
-                        ui.vbox.remove(&ui.separator);
-                        ui.vbox.remove(&ui.progress_bar);
-                        ui.stop_state = StopState::Disabled;
-                        ui.stop_button.set_sensitive(false);
-                        ui.open_button.set_sensitive(true);
-                        ui.save_button.set_sensitive(true);
-                        ui.scan_button.set_sensitive(true);
-                        ui.selector.set_sensitive(true);
-                        ui.capture_button.set_sensitive(ui.selector.device_available());
-                        Ok(())
-                    })
-                );
-            });
-        });
-        gtk::glib::timeout_add_once(
-            UPDATE_INTERVAL,
-            || display_error(update_view()));
-        Ok(())
-    })
-}
-
-fn load_pcap(file: gio::File,
-             writer: CaptureWriter,
-             cancel_handle: Cancellable)
-    -> Result<(), Error>
-{
-    let info = file.query_info("standard::*",
-                               FileQueryInfoFlags::NONE,
-                               Some(&cancel_handle))?;
-    if info.has_attribute(gio::FILE_ATTRIBUTE_STANDARD_SIZE) {
-        let file_size = info.size() as u64;
-        TOTAL.store(file_size, Ordering::Relaxed);
-    }
-    let source = file.read(Some(&cancel_handle))?.into_read();
-    let mut loader = Loader::open(source)?;
-    let mut decoder = Decoder::new(writer)?;
-    #[cfg(feature="step-decoder")]
-    let (mut client, _addr) =
-        TcpListener::bind("127.0.0.1:46563")?.accept()?;
-    while let Some(result) = loader.next() {
-        let (packet, timestamp_ns) = result?;
-        #[cfg(feature="step-decoder")] {
-            let mut buf = [0; 1];
-            client.read(&mut buf).unwrap();
-        };
-        #[cfg(feature="record-ui-test")]
-        let guard = UPDATE_LOCK.lock();
-        decoder.handle_raw_packet(&packet.data, timestamp_ns)?;
-        #[cfg(feature="record-ui-test")]
-        drop(guard);
-        CURRENT.store(loader.bytes_read, Ordering::Relaxed);
-        if STOP.load(Ordering::Relaxed) {
-            break;
-        }
-    }
-    let writer = decoder.finish()?;
-    writer.print_storage_summary();
-    Ok(())
-}
-
-fn save_pcap(file: gio::File,
-             mut capture: CaptureReader,
-             cancel_handle: Cancellable)
+fn save_facedancer(file: gio::File,
+                   mut capture: CaptureReader,
+                   device_id: DeviceId)
     -> Result<(), Error>
 {
-    let packet_count = capture.packet_index.len();
     let dest = file
-        .replace(None, false, FileCreateFlags::NONE, Some(&cancel_handle))?
+        .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
         .into_write();
-    let mut writer = Writer::open(dest)?;
-    for (result, i) in capture.timestamped_packets()?.zip(0..packet_count) {
-        let (timestamp_ns, packet) = result?;
-        writer.add_packet(&packet, timestamp_ns)?;
-        CURRENT.store(i + 1, Ordering::Relaxed);
-        if STOP.load(Ordering::Relaxed) {
-            break;
-        }
-    }
-    writer.close()?;
+    let count = facedancer::write_script(&mut capture, device_id, dest)?;
+    eprintln!("Exported Facedancer script with {count} captured responses");
     Ok(())
 }
 
@@ -1065,11 +5049,13 @@ pub fn stop_operation() -> Result<(), Error> {
                 STOP.store(true, Ordering::Relaxed);
                 cancel_handle.cancel();
             },
-            StopState::Cynthion(stop_handle) => {
+            StopState::Backend(stop_handle) => {
                 stop_handle.stop()?;
             }
         };
         ui.stop_button.set_sensitive(false);
+        ui.pause_button.set_sensitive(false);
+        ui.marker_button.set_sensitive(false);
         ui.scan_button.set_sensitive(true);
         ui.save_button.set_sensitive(true);
         Ok(())
@@ -1085,6 +5071,36 @@ fn detect_hardware() -> Result<(), Error> {
     })
 }
 
+/// Scan for analyzers again whenever one is connected or disconnected.
+#[cfg(not(test))]
+fn watch_devices() {
+    let mut watcher = match DeviceWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            eprintln!("Not watching for analyzers: {err}");
+            return;
+        }
+    };
+    while watcher.wait().is_ok() {
+        gtk::glib::idle_add_once(|| display_error(devices_changed()));
+    }
+}
+
+#[cfg(not(test))]
+fn devices_changed() -> Result<(), Error> {
+    // Leave the selected device alone while it is in use.
+    let mut capturing = false;
+    with_ui(|ui| {
+        capturing = matches!(ui.stop_state, StopState::Backend(_));
+        Ok(())
+    })?;
+    if capturing {
+        Ok(())
+    } else {
+        detect_hardware()
+    }
+}
+
 fn device_selection_changed() -> Result<(), Error> {
     with_ui(|ui| {
         ui.capture_button.set_sensitive(ui.selector.device_available());
@@ -1094,33 +5110,299 @@ fn device_selection_changed() -> Result<(), Error> {
     })
 }
 
-pub fn start_cynthion() -> Result<(), Error> {
+/// Decode a captured packet, noting its analyzer if there are several.
+fn decode_packet(decoder: &mut Decoder,
+                 packet: &TimestampedPacket,
+                 merged: bool)
+    -> Result<(), Error>
+{
+    if merged {
+        decoder.handle_merged_packet(
+            packet.source, &packet.bytes, packet.timestamp_ns)
+    } else {
+        decoder.handle_raw_packet(&packet.bytes, packet.timestamp_ns)
+    }
+}
+
+/// Directory in which live captures are recorded for recovery.
+fn recovery_dir() -> PathBuf {
+    glib::user_data_dir().join("packetry").join("recovery")
+}
+
+/// Start recording a live capture for recovery, if enabled and possible.
+fn start_recording(enabled: bool) -> Option<Recorder> {
+    if !enabled {
+        return None;
+    }
+    Recorder::create(&recovery_dir())
+        .map_err(|e| eprintln!("Not recording capture for recovery: {e:#}"))
+        .ok()
+}
+
+/// Record a captured packet for recovery, or stop recording if that fails.
+fn record_packet(recorder: &mut Option<Recorder>,
+                 packet: &TimestampedPacket)
+{
+    if let Some(Err(e)) = recorder.as_mut().map(|recorder|
+        recorder.add_packet(
+            packet.source, &packet.bytes, packet.timestamp_ns))
+    {
+        eprintln!("Stopped recording capture for recovery: {e:#}");
+        *recorder = None;
+    }
+}
+
+/// Offer to open the packets of the last capture that was interrupted.
+#[cfg(not(test))]
+fn offer_recovery() -> Result<(), Error> {
+    let Some(path) = recovery::interrupted(&recovery_dir()).pop() else {
+        return Ok(());
+    };
+    let window = WINDOW.with(|cell| cell.borrow().clone());
+    let dialog = MessageDialog::new(
+        window.as_ref(),
+        DialogFlags::MODAL,
+        MessageType::Question,
+        ButtonsType::None,
+        "A capture ended unexpectedly. Open the packets recovered from it?");
+    dialog.add_buttons(&[
+        ("Discard", ResponseType::Reject),
+        ("Open", ResponseType::Accept),
+    ]);
+    dialog.connect_response(move |dialog, response| {
+        dialog.destroy();
+        display_error(respond_recovery(path.clone(), response));
+    });
+    dialog.show();
+    Ok(())
+}
+
+/// Open or discard an interrupted capture, as chosen. If no choice was
+/// made, it will be offered again next time.
+#[cfg(not(test))]
+fn respond_recovery(path: PathBuf, response: ResponseType)
+    -> Result<(), Error>
+{
+    match response {
+        ResponseType::Accept => {
+            let path = recovery::keep(&path)?;
+            open_files(vec![gio::File::for_path(path)])
+        },
+        ResponseType::Reject => recovery::discard(&path),
+        _ => Ok(()),
+    }
+}
+
+/// Replace the capture being displayed with a new one, from the thread
+/// reading packets in a rolling capture.
+fn roll_capture() -> Result<CaptureWriter, Error> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    gtk::glib::idle_add_once(move || {
+        let result = reset_capture().and_then(|writer| {
+            with_ui(|ui| {
+                ui.stop_button.set_sensitive(
+                    matches!(ui.stop_state, StopState::Backend(_)));
+                Ok(())
+            })?;
+            Ok(writer)
+        });
+        // The capture thread may have ended meanwhile.
+        let _ = tx.send(result);
+    });
+    rx.recv().context("Failed to start new capture")?
+}
+
+/// Shown if most packets captured are invalid.
+const SPEED_WARNING: &str =
+    "Most of the packets captured are invalid. Check that the capture \
+     speed matches the speed of the device.";
+
+pub fn start_capture() -> Result<(), Error> {
+    // Check the capture options before discarding the current capture.
+    let mut filter = CaptureFilter::default();
+    let mut trigger = CaptureTrigger::default();
+    let mut rolling_limit = None;
+    let mut recovery = true;
+    with_ui(|ui| {
+        filter = ui.selector.filter()?;
+        trigger = ui.selector.trigger()?;
+        rolling_limit = ui.selector.rolling_limit()?;
+        recovery = !ui.preferences.no_recovery;
+        Ok(())
+    })?;
+    let mut events = events::configured()?;
+    if let Some(sink) = rpc::event_sink() {
+        match &mut events {
+            Some(events) => events.add(sink),
+            None => events = Some(EventStream::new(sink)),
+        }
+    }
     let writer = reset_capture()?;
     with_ui(|ui| {
-        let (cynthion, speed) = ui.selector.open()?;
+        let (handles, speed) = ui.selector.open()?;
+        let sources = ui.selector.source_names();
         let (stream_handle, stop_handle) =
-            cynthion.start(speed, display_error)?;
+            start_all(&handles, speed, &filter, display_error)?;
+        ui.stop_state = StopState::Backend(stop_handle);
+        ui.bus_speed = None;
+        ui.waiting_for_trigger = trigger.has_start();
+        let mut stream_handle = TriggeredStream::new(stream_handle, trigger);
+        let (control, mut control_receiver) = capture_control();
+        ui.capture_control = Some(control);
+        ui.marker_count = 0;
+        ui.follow.live.set(true);
+        ui.follow.at_bottom.set(true);
+        set_paused(ui, false);
         ui.open_button.set_sensitive(false);
         ui.scan_button.set_sensitive(false);
         ui.selector.set_sensitive(false);
         ui.capture_button.set_sensitive(false);
         ui.stop_button.set_sensitive(true);
-        ui.stop_state = StopState::Cynthion(stop_handle);
-        let read_cynthion = move || {
+        ui.pause_button.set_sensitive(true);
+        ui.marker_button.set_sensitive(true);
+        let read_packets = move || {
+            let merged = !sources.is_empty();
+            let sources = Arc::new(sources);
+            writer.shared.sources.swap(sources.clone());
+            let mut shared = writer.shared.clone();
             let mut decoder = Decoder::new(writer)?;
-            for packet in stream_handle {
-                decoder.handle_raw_packet(&packet.bytes, packet.timestamp_ns)?;
+            if let Some(events) = events.take() {
+                decoder.set_events(events);
+            }
+            let mut speed_check = SpeedCheck::default();
+            let mut space_check = SpaceCheck::default();
+            let mut out_of_space = false;
+            let mut recorder = start_recording(recovery);
+            let mut bus_speed = None;
+            let mut triggered = false;
+            let mut window = rolling_limit.map(RollingWindow::new);
+            let mut last_ns = 0;
+            while let Some(packet) = stream_handle.next() {
+                last_ns = packet.timestamp_ns;
+                // Timestamps count from the start of the capture, so
+                // work out when that was from the first packet.
+                shared.start_time.get_or_init(|| {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |time| time.as_nanos() as u64);
+                    now.saturating_sub(packet.timestamp_ns)
+                });
+                let losses = stream_handle.take_losses();
+                while let Some(text) = control_receiver.next_marker() {
+                    decoder.handle_marker(text, packet.timestamp_ns);
+                }
+                if control_receiver.paused() {
+                    continue;
+                }
+                for count in losses {
+                    decoder.handle_loss(count, packet.timestamp_ns);
+                }
+                if stream_handle.triggered() && !triggered {
+                    triggered = true;
+                    gtk::glib::idle_add_once(|| display_error(with_ui(|ui| {
+                        ui.waiting_for_trigger = false;
+                        Ok(())
+                    })));
+                }
+                match space_check.check() {
+                    Some(FreeSpace::Low(available)) => {
+                        let message = format!(
+                            "Only {} of space remains for captured data.",
+                            fmt_size(available));
+                        gtk::glib::idle_add_once(move || display_error(
+                            with_ui(|ui| {
+                                ui.warning.show(&message);
+                                Ok(())
+                            })));
+                    },
+                    Some(FreeSpace::Exhausted(available)) => {
+                        let message = format!(
+                            "The capture was stopped because only {} of \
+                             space remains for captured data.",
+                            fmt_size(available));
+                        gtk::glib::idle_add_once(move || display_error(
+                            with_ui(|ui| {
+                                ui.warning.show(&message);
+                                Ok(())
+                            })));
+                        out_of_space = true;
+                        break;
+                    },
+                    None => {},
+                }
+                decode_packet(&mut decoder, &packet, merged)?;
+                record_packet(&mut recorder, &packet);
+                if speed_check.check(&packet.bytes) {
+                    gtk::glib::idle_add_once(|| display_error(with_ui(|ui| {
+                        ui.warning.show(SPEED_WARNING);
+                        Ok(())
+                    })));
+                }
+                if stream_handle.speed() != bus_speed {
+                    bus_speed = stream_handle.speed();
+                    gtk::glib::idle_add_once(move || display_error(
+                        with_ui(|ui| {
+                            ui.bus_speed = bus_speed;
+                            Ok(())
+                        })));
+                }
+                if let Some(window) = &mut window {
+                    window.push(packet);
+                    if window.should_roll() {
+                        // Start a new capture from the most recent packets,
+                        // discarding the older ones.
+                        let writer = roll_capture()?;
+                        writer.shared.sources.swap(sources.clone());
+                        if let Some(&start_time) = shared.start_time.get() {
+                            writer.shared.start_time.set(start_time).ok();
+                        }
+                        shared = writer.shared.clone();
+                        // Events for the packets kept were written already.
+                        let events = decoder.take_events();
+                        decoder = Decoder::new(writer)?;
+                        recorder = start_recording(recovery);
+                        for packet in window.roll() {
+                            decode_packet(&mut decoder, packet, merged)?;
+                            record_packet(&mut recorder, packet);
+                        }
+                        if let Some(events) = events {
+                            decoder.set_events(events);
+                        }
+                    }
+                }
+            }
+            for count in stream_handle.take_losses() {
+                decoder.handle_loss(count, last_ns);
             }
             decoder.finish()?;
+            let finished = stream_handle.finished() || out_of_space;
+            let mut stream_handle = stream_handle.into_inner();
+            if finished {
+                // The trigger's stop condition was reached, or space ran
+                // out. Stop the analyzer, discarding anything it sends
+                // meanwhile.
+                gtk::glib::idle_add_once(|| display_error(stop_operation()));
+                for _ in stream_handle.by_ref() {}
+            }
+            let drops = stream_handle.drops();
+            if drops > 0 {
+                eprintln!("Packets were lost {drops} times during capture");
+            }
             Ok(())
         };
         std::thread::spawn(move || {
-            display_error(read_cynthion());
+            display_error(read_packets());
             gtk::glib::idle_add_once(|| {
                 display_error(
                     with_ui(|ui| {
                         ui.stop_state = StopState::Disabled;
+                        ui.waiting_for_trigger = false;
+                        ui.capture_control = None;
+                        ui.follow.live.set(false);
+                        set_paused(ui, false);
                         ui.stop_button.set_sensitive(false);
+                        ui.pause_button.set_sensitive(false);
+                        ui.marker_button.set_sensitive(false);
                         ui.open_button.set_sensitive(true);
                         ui.selector.set_sensitive(true);
                         ui.capture_button.set_sensitive(ui.selector.device_available());
@@ -1136,6 +5418,155 @@ pub fn start_cynthion() -> Result<(), Error> {
     })
 }
 
+/// Handle a request from a program controlling the application, on the
+/// thread serving that program, by making it on the main thread.
+pub fn rpc_request(method: &str, params: Value)
+    -> Option<Result<Value, Error>>
+{
+    let (tx, rx) = std::sync::mpsc::channel();
+    let method = method.to_string();
+    gtk::glib::idle_add_once(move || {
+        let _ = tx.send(rpc_call(&method, &params));
+    });
+    rx.recv().unwrap_or_else(|_| Some(Err(Error::msg("Packetry has exited"))))
+}
+
+/// Carry out a request from a program controlling the application, or
+/// return None if there is no such method.
+fn rpc_call(method: &str, params: &Value) -> Option<Result<Value, Error>> {
+    let result = match method {
+        "start_capture" => rpc_start_capture(params),
+        "stop_capture" => stop_operation(),
+        "set_trigger" => rpc_param(params, "trigger")
+            .and_then(|text| set_trigger(text.unwrap_or_default())),
+        "set_capture_filter" => rpc_param(params, "filter")
+            .and_then(|text| set_capture_filter(text.unwrap_or_default())),
+        "set_view_filter" => rpc_param(params, "filter")
+            .and_then(|text| set_view_filter(text.unwrap_or_default())),
+        "status" => return Some(rpc_status()),
+        _ => return None,
+    };
+    Some(result.map(|()| Value::Null))
+}
+
+/// A parameter of a request given as text, if given.
+fn rpc_param<'p>(params: &'p Value, name: &str)
+    -> Result<Option<&'p str>, Error>
+{
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(text)) => Ok(Some(text)),
+        Some(_) => bail!("The {name} parameter must be a string"),
+    }
+}
+
+/// Start capturing, with the trigger and capture filter given, if any.
+fn rpc_start_capture(params: &Value) -> Result<(), Error> {
+    if let Some(text) = rpc_param(params, "trigger")? {
+        set_trigger(text)?;
+    }
+    if let Some(text) = rpc_param(params, "filter")? {
+        set_capture_filter(text)?;
+    }
+    let mut available = false;
+    with_ui(|ui| {
+        available = ui.capture_button.is_sensitive();
+        Ok(())
+    })?;
+    if !available {
+        bail!("Cannot start capturing, check that an analyzer is \
+               connected and that no capture is in progress");
+    }
+    start_capture()
+}
+
+fn rpc_status() -> Result<Value, Error> {
+    let mut status = Value::Null;
+    with_ui(|ui| {
+        status = serde_json::json!({
+            "capturing": matches!(ui.stop_state, StopState::Backend(_)),
+            "waiting_for_trigger": ui.waiting_for_trigger,
+            "paused": ui.paused,
+            "packets": ui.capture.packet_index.len(),
+            "transactions": ui.capture.transaction_index.len(),
+        });
+        Ok(())
+    })?;
+    Ok(status)
+}
+
+/// Set the trigger for the next capture.
+fn set_trigger(text: &str) -> Result<(), Error> {
+    CaptureTrigger::parse(text)?;
+    with_ui(|ui| {
+        ui.selector.trigger_entry.set_text(text);
+        Ok(())
+    })
+}
+
+/// Set the filter for the next capture.
+fn set_capture_filter(text: &str) -> Result<(), Error> {
+    CaptureFilter::parse(text)?;
+    with_ui(|ui| {
+        ui.selector.filter_entry.set_text(text);
+        Ok(())
+    })
+}
+
+/// Set the filter of the traffic view, as if entered.
+fn set_view_filter(text: &str) -> Result<(), Error> {
+    TrafficFilter::parse(text)?;
+    let mut entry = None;
+    with_ui(|ui| {
+        ui.view_filter_entry.set_text(text);
+        entry = Some(ui.view_filter_entry.clone());
+        Ok(())
+    })?;
+    // Applying the filter calls back into the UI.
+    if let Some(entry) = entry {
+        entry.emit_activate();
+    }
+    Ok(())
+}
+
+/// Pause the capture in progress, or resume it if paused.
+fn pause_capture() -> Result<(), Error> {
+    with_ui(|ui| {
+        let paused = !ui.paused;
+        if let Some(control) = &ui.capture_control {
+            if paused {
+                control.pause();
+            } else {
+                control.resume();
+            }
+            set_paused(ui, paused);
+        }
+        Ok(())
+    })
+}
+
+fn set_paused(ui: &mut UserInterface, paused: bool) {
+    ui.paused = paused;
+    let (icon, tooltip) = if paused {
+        ("media-playback-start", "Resume")
+    } else {
+        ("media-playback-pause", "Pause")
+    };
+    ui.pause_button.set_icon_name(icon);
+    ui.pause_button.set_tooltip_text(Some(tooltip));
+}
+
+/// Add a numbered marker to the capture in progress.
+fn add_marker() -> Result<(), Error> {
+    with_ui(|ui| {
+        if let Some(control) = &ui.capture_control {
+            ui.marker_count += 1;
+            control.mark(format!("Marker {}", ui.marker_count));
+        }
+        Ok(())
+    })
+}
+
 fn show_about() -> Result<(), Error> {
     const LICENSE: &str = include_str!("../LICENSE");
     let about = AboutDialog::builder()
@@ -1,12 +1,13 @@
 use std::mem::size_of;
 
 use bytemuck_derive::{Pod, Zeroable};
-use bytemuck::pod_read_unaligned;
+use bytemuck::{bytes_of, pod_read_unaligned};
 use crc::{Crc, CRC_16_USB};
 use num_enum::{IntoPrimitive, FromPrimitive};
 use derive_more::{From, Into, Display};
 use usb_ids::FromId;
 
+use crate::i18n::{tr, translate};
 use crate::vec_map::VecMap;
 
 fn crc16(bytes: &[u8]) -> u16 {
@@ -61,6 +62,15 @@ impl std::fmt::Display for PID {
     }
 }
 
+impl PID {
+    /// The PID with a name, as displayed.
+    pub fn from_name(name: &str) -> Option<PID> {
+        (0..=u8::MAX)
+            .map(PID::from)
+            .find(|pid| *pid != PID::Malformed && pid.to_string() == name)
+    }
+}
+
 impl From<&u8> for PID {
     fn from(byte: &u8) -> PID {
         PID::from(*byte)
@@ -120,6 +130,25 @@ pub fn validate_packet(packet: &[u8]) -> Result<PID, Option<PID>> {
     }
 }
 
+/// Construct a token packet, for traffic not captured at the packet level.
+pub fn token_packet(pid: PID, addr: DeviceAddr, ep_num: EndpointNum)
+    -> Vec<u8>
+{
+    let data = addr.0 as u32 & 0x7F | (ep_num.0 as u32 & 0x0F) << 7;
+    let fields = data as u16 | (crc5(data, 11) as u16) << 11;
+    let [low, high] = fields.to_le_bytes();
+    vec![pid.into(), low, high]
+}
+
+/// Construct a data packet, for traffic not captured at the packet level.
+pub fn data_packet(pid: PID, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(payload.len() + 3);
+    packet.push(pid.into());
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(&crc16(payload).to_le_bytes());
+    packet
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Default,
          Pod, Zeroable, From, Into, Display)]
 #[repr(transparent)]
@@ -355,7 +384,7 @@ pub enum Recipient {
     Reserved = 4,
 }
 
-#[derive(Copy, Clone, Debug, FromPrimitive, IntoPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
 #[repr(u8)]
 pub enum Direction {
     #[default]
@@ -424,49 +453,44 @@ impl StandardRequest {
     pub fn description(&self, fields: &SetupFields) -> String {
         use StandardRequest::*;
         match self {
-            GetStatus => format!("Getting status"),
+            GetStatus => tr!("Getting status").to_string(),
             ClearFeature | SetFeature => {
                 let feature = StandardFeature::from(fields.value);
-                format!("{} {}",
-                    match self {
-                        ClearFeature => "Clearing",
-                        SetFeature => "Setting",
-                        _ => ""
-                    },
-                    feature.description()
-                )
+                match self {
+                    ClearFeature => tr!("Clearing {}", feature.description()),
+                    _ => tr!("Setting {}", feature.description()),
+                }
             },
-            SetAddress => format!("Setting address to {}", fields.value),
+            SetAddress => tr!("Setting address to {}", fields.value),
             GetDescriptor | SetDescriptor => {
                 let descriptor_type =
                     DescriptorType::from((fields.value >> 8) as u8);
-                format!(
-                    "{} {} descriptor #{}{}",
-                    match self {
-                        GetDescriptor => "Getting",
-                        SetDescriptor => "Setting",
-                        _ => ""
-                    },
-                    descriptor_type.description(),
-                    fields.value & 0xFF,
-                    match (descriptor_type, fields.index) {
-                        (DescriptorType::String, language) if language > 0 =>
-                            format!(", language 0x{language:04x}{}",
-                                language_name(language)
-                                    .map_or_else(
-                                        String::new,
-                                        |l| format!(" ({l})"))),
-                        (..) => format!(""),
-                    }
-                )
+                let description = descriptor_type.description();
+                let number = fields.value & 0xFF;
+                let language = match (descriptor_type, fields.index) {
+                    (DescriptorType::String, language) if language > 0 =>
+                        tr!(", language {}{}", format!("0x{language:04x}"),
+                            language_name(language)
+                                .map_or_else(
+                                    String::new,
+                                    |l| format!(" ({l})"))),
+                    (..) => format!(""),
+                };
+                match self {
+                    GetDescriptor => tr!("Getting {} descriptor #{}{}",
+                        description, number, language),
+                    _ => tr!("Setting {} descriptor #{}{}",
+                        description, number, language),
+                }
             },
-            GetConfiguration => format!("Getting configuration"),
-            SetConfiguration => format!("Setting configuration {}", fields.value),
-            GetInterface => format!("Getting interface {}", fields.index),
-            SetInterface => format!("Setting interface {} to {}",
-                                    fields.index, fields.value),
-            SynchFrame => format!("Synchronising frame"),
-            Unknown => format!("Unknown standard request"),
+            GetConfiguration => tr!("Getting configuration").to_string(),
+            SetConfiguration =>
+                tr!("Setting configuration {}", fields.value),
+            GetInterface => tr!("Getting interface {}", fields.index),
+            SetInterface => tr!("Setting interface {} to {}",
+                                fields.index, fields.value),
+            SynchFrame => tr!("Synchronising frame").to_string(),
+            Unknown => tr!("Unknown standard request").to_string(),
         }
     }
 }
@@ -532,7 +556,7 @@ impl DescriptorType {
             "interface power",
             "unknown",
         ];
-        STRINGS[self as usize]
+        translate(STRINGS[self as usize])
     }
 }
 
@@ -554,7 +578,7 @@ impl StandardFeature {
             "test mode",
             "unknown standard feature",
         ];
-        STRINGS[self as usize]
+        translate(STRINGS[self as usize])
     }
 }
 
@@ -743,9 +767,13 @@ pub enum Descriptor {
     Configuration(ConfigDescriptor),
     Interface(InterfaceDescriptor),
     Endpoint(EndpointDescriptor),
+    ClassSpecific(Vec<u8>),
     Other(DescriptorType)
 }
 
+/// Descriptor types from 0x20 to 0x2F are defined by device classes.
+const CLASS_SPECIFIC_TYPES: std::ops::RangeInclusive<u8> = 0x20..=0x2F;
+
 pub struct DescriptorIterator<'bytes> {
     bytes: &'bytes [u8],
     offset: usize,
@@ -767,13 +795,26 @@ impl Iterator for DescriptorIterator<'_> {
         while self.offset < self.bytes.len() - 2 {
             let remaining_bytes = &self.bytes[self.offset .. self.bytes.len()];
             let desc_length = remaining_bytes[0] as usize;
-            let desc_type = DescriptorType::from(remaining_bytes[1]);
+            let type_byte = remaining_bytes[1];
+            let desc_type = DescriptorType::from(type_byte);
             self.offset += desc_length;
+            if CLASS_SPECIFIC_TYPES.contains(&type_byte) {
+                if desc_length < 3 || desc_length > remaining_bytes.len() {
+                    continue
+                }
+                return Some(Descriptor::ClassSpecific(
+                    remaining_bytes[0 .. desc_length].to_vec()));
+            }
             if let Some(expected) = desc_type.expected_length() {
-                if desc_length != expected {
+                // Some classes extend the standard endpoint descriptor,
+                // e.g. audio class endpoints have two additional fields.
+                let extended = desc_type == DescriptorType::Endpoint &&
+                    desc_length > expected &&
+                    desc_length <= remaining_bytes.len();
+                if desc_length != expected && !extended {
                     continue
                 }
-                let bytes = &remaining_bytes[0 .. desc_length];
+                let bytes = &remaining_bytes[0 .. expected];
                 return Some(match desc_type {
                     DescriptorType::Device =>
                         Descriptor::Device(
@@ -797,7 +838,8 @@ impl Iterator for DescriptorIterator<'_> {
 
 pub struct Interface {
     pub descriptor: InterfaceDescriptor,
-    pub endpoint_descriptors: VecMap<InterfaceEpNum, EndpointDescriptor>
+    pub endpoint_descriptors: VecMap<InterfaceEpNum, EndpointDescriptor>,
+    pub class_descriptors: Vec<Vec<u8>>,
 }
 
 pub struct Configuration {
@@ -829,6 +871,7 @@ impl Configuration {
                                 endpoint_descriptors:
                                     VecMap::with_capacity(
                                         iface_desc.num_endpoints),
+                                class_descriptors: Vec::new(),
                             }
                         );
                     }
@@ -844,13 +887,51 @@ impl Configuration {
                         }
                     }
                 },
+                Descriptor::ClassSpecific(bytes) => {
+                    if let Some(config) = result.as_mut() {
+                        if let Some(num) = iface_num {
+                            if let Some(iface) =
+                                config.interfaces.get_mut(num)
+                            {
+                                iface.class_descriptors.push(bytes);
+                            }
+                        }
+                    }
+                },
                 _ => {},
             };
         }
         result
     }
+
+    /// Descriptor bytes from which this configuration can be rebuilt.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Descriptors that were longer than their standard fields are
+        // padded back out to their original length.
+        fn extend(bytes: &mut Vec<u8>, descriptor: &[u8]) {
+            let start = bytes.len();
+            bytes.extend_from_slice(descriptor);
+            let length = descriptor[0] as usize;
+            if length > descriptor.len() {
+                bytes.resize(start + length, 0);
+            }
+        }
+        let mut bytes = Vec::new();
+        extend(&mut bytes, bytes_of(&self.descriptor));
+        for iface in &self.interfaces {
+            extend(&mut bytes, bytes_of(&iface.descriptor));
+            for ep_desc in &iface.endpoint_descriptors {
+                extend(&mut bytes, bytes_of(ep_desc));
+            }
+            for class_desc in &iface.class_descriptors {
+                bytes.extend_from_slice(class_desc);
+            }
+        }
+        bytes
+    }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ControlResult {
     Completed,
     Incomplete,
@@ -862,6 +943,7 @@ pub struct ControlTransfer {
     pub fields: SetupFields,
     pub data: Vec<u8>,
     pub result: ControlResult,
+    pub class_description: Option<String>,
 }
 
 impl ControlTransfer {
@@ -872,18 +954,15 @@ impl ControlTransfer {
         let std_req = StandardRequest::from(request);
         let descriptor_type =
             DescriptorType::from((self.fields.value >> 8) as u8);
-        let action = match direction {
-            Direction::In => "reading",
-            Direction::Out => "writing"
-        };
         let size = self.data.len();
         let mut parts = vec![format!(
             "{} {}",
-            match request_type {
-                RequestType::Standard => std_req.description(&self.fields),
-                _ => format!(
-                    "{:?} request #{}, index {}, value {}",
-                    request_type, request,
+            match (request_type, &self.class_description) {
+                (RequestType::Standard, _) => std_req.description(&self.fields),
+                (RequestType::Class, Some(description)) => description.clone(),
+                _ => tr!(
+                    "{} request #{}, index {}, value {}",
+                    format!("{request_type:?}"), request,
                     match self.fields.type_fields.recipient() {
                         Recipient::Interface | Recipient::Endpoint =>
                             self.fields.index >> 8,
@@ -892,27 +971,34 @@ impl ControlTransfer {
                     self.fields.value)
             },
             match self.fields.type_fields.recipient() {
-                Recipient::Device => format!(
+                Recipient::Device => tr!(
                     "for device {}", self.address),
-                Recipient::Interface => format!(
+                Recipient::Interface => tr!(
                     "for interface {}.{}",
                     self.address, self.fields.index as u8),
                 Recipient::Endpoint => {
                     let ep_addr = EndpointAddr(self.fields.index as u8);
-                    format!("for endpoint {}.{} {}",
-                            self.address, ep_addr.number(), ep_addr.direction())
+                    tr!("for endpoint {}.{} {}",
+                        self.address, ep_addr.number(), ep_addr.direction())
                 }
-                _ => format!("on device {}", self.address)
+                _ => tr!("on device {}", self.address)
             }
         )];
-        match (self.fields.length, size) {
-            (0, 0) => {}
-            (len, _) if size == len as usize => {
-                parts.push(format!(", {action} {len} bytes"));
+        match (self.fields.length, direction, size) {
+            (0, _, 0) => {}
+            (len, Direction::In, _) if size == len as usize => {
+                parts.push(tr!(", reading {} bytes", len));
+            },
+            (len, Direction::Out, _) if size == len as usize => {
+                parts.push(tr!(", writing {} bytes", len));
             },
-            (len, _) => {
+            (len, Direction::In, _) => {
                 parts.push(
-                    format!(", {action} {size} of {len} requested bytes"));
+                    tr!(", reading {} of {} requested bytes", size, len));
+            },
+            (len, Direction::Out, _) => {
+                parts.push(
+                    tr!(", writing {} of {} requested bytes", size, len));
             }
         };
         match (request_type, std_req, descriptor_type) {
@@ -930,8 +1016,8 @@ impl ControlTransfer {
         let summary = parts.concat();
         match self.result {
             ControlResult::Completed => summary,
-            ControlResult::Incomplete => format!("{}, incomplete", summary),
-            ControlResult::Stalled => format!("{}, stalled", summary),
+            ControlResult::Incomplete => tr!("{}, incomplete", summary),
+            ControlResult::Stalled => tr!("{}, stalled", summary),
         }
     }
 }
@@ -1017,6 +1103,18 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_construct_packets() {
+        let setup = token_packet(PID::SETUP, DeviceAddr(2), EndpointNum(0));
+        assert_eq!(setup, vec![0x2d, 0x02, 0xa8]);
+        let token = token_packet(PID::IN, DeviceAddr(2), EndpointNum(1));
+        assert_eq!(token, vec![0x69, 0x82, 0x18]);
+        let data = data_packet(PID::DATA1, &[0x12, 0x01]);
+        assert_eq!(validate_packet(&data), Ok(PID::DATA1));
+        let empty = data_packet(PID::DATA0, &[]);
+        assert_eq!(empty, vec![0xc3, 0x00, 0x00]);
+    }
+
     #[test]
     fn test_parse_in() {
         let packet = vec![0x69, 0x82, 0x18];
@@ -0,0 +1,585 @@
+//! Conversion of usbip traffic to USB packets.
+//!
+//! usbip forwards the URBs submitted to a remote device over a TCP
+//! connection, usually on port 3240. Each URB submitted by the client is
+//! sent as a `CMD_SUBMIT` message, and completed by a `RET_SUBMIT` reply
+//! from the server. These are converted to usbmon URB events, and from
+//! those to packets, in the same way as Linux usbmon captures.
+//!
+//! Messages may be read from a capture of the TCP connection, or from a
+//! raw dump of the messages exchanged.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use anyhow::{Error, bail};
+use pcap_file::DataLink;
+
+use crate::usbmon::{
+    Urb,
+    EVENT_COMPLETE,
+    EVENT_SUBMIT,
+    TRANSFER_BULK,
+    TRANSFER_CONTROL,
+    TRANSFER_INTERRUPT,
+    TRANSFER_ISOCHRONOUS,
+};
+
+/// TCP port on which usbip servers listen.
+pub const PORT: u16 = 3240;
+
+/// Version found at the start of the messages used to set up a connection.
+const OP_VERSION: u16 = 0x0111;
+
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+
+const CMD_SUBMIT: u32 = 1;
+const CMD_UNLINK: u32 = 2;
+const RET_SUBMIT: u32 = 3;
+const RET_UNLINK: u32 = 4;
+
+/// Size of the header of a connection setup message.
+const OP_HEADER_SIZE: usize = 8;
+
+/// Size of the bus ID in an import request.
+const BUSID_SIZE: usize = 32;
+
+/// Size of the description of a device in a connection setup reply.
+const DEVICE_SIZE: usize = 312;
+
+/// Size of the description of an interface in a device list reply.
+const INTERFACE_SIZE: usize = 4;
+
+/// Size of the header of a URB message.
+const HEADER_SIZE: usize = 48;
+
+/// Size of each isochronous packet descriptor following a URB message.
+const ISO_DESCRIPTOR_SIZE: usize = 16;
+
+/// Status of a URB that has been submitted but not completed.
+const EINPROGRESS: i32 = -115;
+
+/// Status of an unlink request that cancelled its URB.
+const ECONNRESET: i32 = -104;
+
+/// Whether the start of a file appears to be a raw dump of usbip messages.
+pub fn is_stream(start: &[u8]) -> bool {
+    match start {
+        // A connection starts with a request to import a device.
+        [0x01, 0x11, 0x80, 0x03, ..] => true,
+        // A dump may also start with the first URB submitted.
+        [0, 0, 0, 1, ..] => start.len() >= HEADER_SIZE,
+        _ => false
+    }
+}
+
+fn be16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..][..4].try_into().unwrap())
+}
+
+/// Number of isochronous packets given in a URB message.
+fn iso_packets(header: &[u8]) -> usize {
+    // Non-isochronous URBs may give zero or -1 here.
+    match be32(header, 32) as i32 {
+        count if count > 0 => count as usize,
+        _ => 0,
+    }
+}
+
+/// What is remembered about a URB between its submission and completion.
+#[derive(Copy, Clone)]
+struct Submitted {
+    transfer_type: u8,
+    endpoint: u8,
+    device: u8,
+    bus: u16,
+}
+
+/// State of a usbip session, which may be carried over one connection or
+/// read from a raw dump.
+#[derive(Default)]
+pub struct Session {
+    /// Distinguishes the URBs of this session from those of others.
+    id: u32,
+    /// URBs submitted and not yet completed, by sequence number.
+    submitted: HashMap<u32, Submitted>,
+    /// URBs to be unlinked, by sequence number of the unlink request.
+    unlinks: HashMap<u32, u32>,
+}
+
+impl Session {
+    pub fn new(id: u32) -> Session {
+        Session { id, .. Session::default() }
+    }
+
+    /// Take messages from the start of a buffer until one is found that
+    /// corresponds to a URB event, or no complete message remains.
+    pub fn next_urb(&mut self, buffer: &mut Vec<u8>)
+        -> Result<Option<Urb<'static>>, Error>
+    {
+        while let Some(length) = self.message_length(buffer)? {
+            let message: Vec<u8> = buffer.drain(..length).collect();
+            if let Some(urb) = self.handle(message) {
+                return Ok(Some(urb));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the length of the message at the start of a buffer, if it
+    /// has been received completely.
+    fn message_length(&self, bytes: &[u8]) -> Result<Option<usize>, Error> {
+        if bytes.len() < 4 {
+            return Ok(None);
+        }
+        if be16(bytes, 0) == OP_VERSION {
+            if bytes.len() < OP_HEADER_SIZE {
+                return Ok(None);
+            }
+            let status = be32(bytes, 4);
+            let length = match be16(bytes, 2) {
+                OP_REQ_DEVLIST => OP_HEADER_SIZE,
+                OP_REQ_IMPORT => OP_HEADER_SIZE + BUSID_SIZE,
+                OP_REP_IMPORT if status == 0 => OP_HEADER_SIZE + DEVICE_SIZE,
+                OP_REP_IMPORT => OP_HEADER_SIZE,
+                OP_REP_DEVLIST => {
+                    let Some(count) = bytes.get(8..12) else {
+                        return Ok(None);
+                    };
+                    let count = be32(count, 0);
+                    let mut length = OP_HEADER_SIZE + 4;
+                    for _ in 0..count {
+                        let Some(device) = bytes.get(length..)
+                            .and_then(|rest| rest.get(..DEVICE_SIZE))
+                        else {
+                            return Ok(None);
+                        };
+                        let interfaces = device[DEVICE_SIZE - 1] as usize;
+                        length += DEVICE_SIZE + interfaces * INTERFACE_SIZE;
+                    }
+                    length
+                },
+                code => bail!("Unknown usbip operation {code:04X}")
+            };
+            return Ok((bytes.len() >= length).then_some(length));
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let data_length = match be32(bytes, 0) {
+            CMD_SUBMIT => {
+                let direction_in = be32(bytes, 12) != 0;
+                let length = be32(bytes, 24) as usize;
+                let data = if direction_in { 0 } else { length };
+                data + iso_packets(bytes) * ISO_DESCRIPTOR_SIZE
+            },
+            RET_SUBMIT => {
+                // The reply does not give the direction, so find it from
+                // the submission.
+                let direction_in = self.submitted
+                    .get(&be32(bytes, 4))
+                    .is_some_and(|urb| urb.endpoint & 0x80 != 0);
+                let length = be32(bytes, 24) as usize;
+                let data = if direction_in { length } else { 0 };
+                data + iso_packets(bytes) * ISO_DESCRIPTOR_SIZE
+            },
+            CMD_UNLINK | RET_UNLINK => 0,
+            command => bail!("Unknown usbip command {command:08X}")
+        };
+        let length = HEADER_SIZE + data_length;
+        Ok((bytes.len() >= length).then_some(length))
+    }
+
+    /// Handle a complete message, returning the URB event it represents.
+    fn handle(&mut self, message: Vec<u8>) -> Option<Urb<'static>> {
+        if be16(&message, 0) == OP_VERSION {
+            return None;
+        }
+        let seqnum = be32(&message, 4);
+        let id = (self.id as u64) << 32 | seqnum as u64;
+        match be32(&message, 0) {
+            CMD_SUBMIT => {
+                let devid = be32(&message, 8);
+                let direction_in = be32(&message, 12) != 0;
+                let ep = be32(&message, 16) as u8 & 0x0F;
+                let length = be32(&message, 24) as usize;
+                let interval = be32(&message, 36) as i32;
+                let transfer_type = if ep == 0 {
+                    TRANSFER_CONTROL
+                } else if iso_packets(&message) > 0 {
+                    TRANSFER_ISOCHRONOUS
+                } else if interval > 0 {
+                    TRANSFER_INTERRUPT
+                } else {
+                    TRANSFER_BULK
+                };
+                let submitted = Submitted {
+                    transfer_type,
+                    endpoint: ep | if direction_in { 0x80 } else { 0 },
+                    device: devid as u8,
+                    bus: (devid >> 16) as u16,
+                };
+                self.submitted.insert(seqnum, submitted);
+                let data = if direction_in {
+                    Vec::new()
+                } else {
+                    message[HEADER_SIZE..][..length].to_vec()
+                };
+                let setup = (ep == 0).then(|| {
+                    message[40..HEADER_SIZE].try_into().unwrap()
+                });
+                Some(urb(id, EVENT_SUBMIT, submitted, setup,
+                         EINPROGRESS, length, data))
+            },
+            RET_SUBMIT => {
+                let submitted = self.submitted.remove(&seqnum)?;
+                let status = be32(&message, 20) as i32;
+                let length = be32(&message, 24) as usize;
+                let data = if submitted.endpoint & 0x80 != 0 {
+                    message[HEADER_SIZE..][..length].to_vec()
+                } else {
+                    Vec::new()
+                };
+                Some(urb(id, EVENT_COMPLETE, submitted, None,
+                         status, length, data))
+            },
+            CMD_UNLINK => {
+                self.unlinks.insert(seqnum, be32(&message, 20));
+                None
+            },
+            RET_UNLINK => {
+                let target = self.unlinks.remove(&seqnum)?;
+                let status = be32(&message, 20) as i32;
+                if status != ECONNRESET {
+                    // The URB completed before it could be unlinked.
+                    return None;
+                }
+                let submitted = self.submitted.remove(&target)?;
+                let id = (self.id as u64) << 32 | target as u64;
+                Some(urb(id, EVENT_COMPLETE, submitted, None,
+                         status, 0, Vec::new()))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Construct a URB event.
+fn urb(id: u64,
+       event: u8,
+       submitted: Submitted,
+       setup: Option<[u8; 8]>,
+       status: i32,
+       length: usize,
+       data: Vec<u8>)
+    -> Urb<'static>
+{
+    Urb {
+        id,
+        event,
+        transfer_type: submitted.transfer_type,
+        endpoint: submitted.endpoint,
+        device: submitted.device,
+        bus: submitted.bus,
+        setup,
+        status,
+        length,
+        data: Cow::from(data),
+    }
+}
+
+/// One direction of a TCP connection.
+#[derive(Default)]
+struct TcpStream {
+    /// Sequence number of the next byte expected.
+    next_seq: Option<u32>,
+    /// Bytes received but not yet parsed.
+    buffer: Vec<u8>,
+}
+
+/// A usbip connection, and the TCP streams in each direction.
+struct Connection {
+    session: Session,
+    to_server: TcpStream,
+    to_client: TcpStream,
+}
+
+/// The fields of a TCP segment needed to reassemble a connection.
+struct Segment<'f> {
+    /// Client address and port, identifying the connection.
+    client: (Vec<u8>, u16),
+    to_server: bool,
+    seq: u32,
+    syn: bool,
+    payload: &'f [u8],
+}
+
+/// Extracts usbip URB events from captured network frames.
+#[derive(Default)]
+pub struct Dissector {
+    connections: HashMap<(Vec<u8>, u16), Connection>,
+}
+
+impl Dissector {
+    /// Handle a captured frame, returning any URB events it completed.
+    pub fn handle_frame(&mut self, frame: &[u8], datalink: DataLink)
+        -> Result<Vec<Urb<'static>>, Error>
+    {
+        let mut urbs = Vec::new();
+        let Some(segment) = tcp_segment(frame, datalink) else {
+            return Ok(urbs);
+        };
+        let count = self.connections.len() as u32;
+        let connection = self.connections
+            .entry(segment.client)
+            .or_insert_with(|| Connection {
+                session: Session::new(count),
+                to_server: TcpStream::default(),
+                to_client: TcpStream::default(),
+            });
+        let stream = if segment.to_server {
+            &mut connection.to_server
+        } else {
+            &mut connection.to_client
+        };
+        // The sequence number is advanced by one for a SYN.
+        let seq = segment.seq.wrapping_add(segment.syn as u32);
+        let next_seq = *stream.next_seq.get_or_insert(seq);
+        let offset = seq.wrapping_sub(next_seq) as i32;
+        if offset > 0 {
+            bail!("Segments of usbip connection missing from capture");
+        }
+        // Skip any data that was retransmitted.
+        let skip = (-offset) as usize;
+        if skip < segment.payload.len() {
+            let data = &segment.payload[skip..];
+            stream.buffer.extend_from_slice(data);
+            stream.next_seq = Some(next_seq.wrapping_add(data.len() as u32));
+            while let Some(urb) =
+                connection.session.next_urb(&mut stream.buffer)?
+            {
+                urbs.push(urb);
+            }
+        }
+        Ok(urbs)
+    }
+}
+
+/// Find the usbip TCP segment in a frame, if it carries one.
+fn tcp_segment(frame: &[u8], datalink: DataLink) -> Option<Segment<'_>> {
+    use DataLink::*;
+    let packet = match datalink {
+        ETHERNET => {
+            let mut ethertype = be16(frame.get(..14)?, 12);
+            let mut start = 14;
+            // Skip any VLAN tags.
+            while ethertype == 0x8100 || ethertype == 0x88A8 {
+                ethertype = be16(frame.get(..start + 4)?, start + 2);
+                start += 4;
+            }
+            match ethertype {
+                0x0800 | 0x86DD => &frame[start..],
+                _ => return None
+            }
+        },
+        LINUX_SLL => frame.get(16..)?,
+        LINUX_SLL2 => frame.get(20..)?,
+        // The address family is in the capturing machine's byte order,
+        // but IP packets can be recognised by their version anyway.
+        NULL | LOOP => frame.get(4..)?,
+        RAW | IPV4 | IPV6 => frame,
+        _ => return None
+    };
+    let (source, dest, payload) = match packet.first()? >> 4 {
+        4 => {
+            let header_length = (packet[0] & 0x0F) as usize * 4;
+            let total_length = be16(packet.get(..20)?, 2) as usize;
+            let fragmented = be16(packet, 6) & 0x3FFF != 0;
+            if packet[9] != 6 || fragmented {
+                return None;
+            }
+            (&packet[12..16], &packet[16..20],
+             packet.get(header_length..total_length)?)
+        },
+        6 => {
+            let payload_length = be16(packet.get(..40)?, 4) as usize;
+            if packet[6] != 6 {
+                return None;
+            }
+            (&packet[8..24], &packet[24..40],
+             packet.get(40..)?.get(..payload_length)?)
+        },
+        _ => return None
+    };
+    let source_port = be16(payload.get(..20)?, 0);
+    let dest_port = be16(payload, 2);
+    let to_server = match (source_port, dest_port) {
+        (_, PORT) => true,
+        (PORT, _) => false,
+        _ => return None
+    };
+    let data_offset = (payload[12] >> 4) as usize * 4;
+    let client = if to_server {
+        (source.to_vec(), source_port)
+    } else {
+        (dest.to_vec(), dest_port)
+    };
+    Some(Segment {
+        client,
+        to_server,
+        seq: be32(payload, 4),
+        syn: payload[13] & 0x02 != 0,
+        payload: payload.get(data_offset..)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pcap_file::pcap::{PcapHeader, PcapWriter, RawPcapPacket};
+    use crate::pcap::Loader;
+
+    const DEVID: u32 = 0x0001_0002;
+
+    /// Build the header of a URB message.
+    fn header(command: u32, seqnum: u32, fields: [u32; 8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        for value in [command, seqnum].into_iter().chain(fields) {
+            message.extend_from_slice(&value.to_be_bytes());
+        }
+        message.resize(HEADER_SIZE - 8, 0);
+        message
+    }
+
+    /// Messages sent by the client and server, in order.
+    fn session() -> Vec<(bool, Vec<u8>)> {
+        let mut import = vec![0x01, 0x11, 0x80, 0x03, 0, 0, 0, 0];
+        import.extend_from_slice(&[b'1'; BUSID_SIZE]);
+        let mut imported = vec![0x01, 0x11, 0x00, 0x03, 0, 0, 0, 0];
+        imported.resize(OP_HEADER_SIZE + DEVICE_SIZE, 0);
+        let request = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let mut get_descriptor =
+            header(CMD_SUBMIT, 1, [DEVID, 1, 0, 0, 18, 0, 0, 0]);
+        get_descriptor.extend_from_slice(&request);
+        let mut descriptor = header(RET_SUBMIT, 1, [0, 0, 0, 0, 18, 0, 0, 0]);
+        descriptor.extend_from_slice(&[0; 8]);
+        descriptor.extend_from_slice(&[
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x09, 0x12,
+            0x34, 0x56, 0x00, 0x01, 0x01, 0x02, 0x03, 0x01]);
+        let mut bulk_out = header(CMD_SUBMIT, 2, [DEVID, 0, 1, 0, 4, 0, 0, 0]);
+        bulk_out.extend_from_slice(&[0; 8]);
+        bulk_out.extend_from_slice(&[0x55, 0x53, 0x42, 0x43]);
+        let mut bulk_done = header(RET_SUBMIT, 2, [0, 0, 0, 0, 4, 0, 0, 0]);
+        bulk_done.extend_from_slice(&[0; 8]);
+        let mut bulk_in = header(CMD_SUBMIT, 3, [DEVID, 1, 2, 0, 64, 0, 0, 0]);
+        bulk_in.extend_from_slice(&[0; 8]);
+        let mut unlink = header(CMD_UNLINK, 4, [DEVID, 0, 0, 3, 0, 0, 0, 0]);
+        unlink.extend_from_slice(&[0; 8]);
+        let mut unlinked = header(RET_UNLINK, 4, [0, 0, 0, ECONNRESET as u32,
+                                                   0, 0, 0, 0]);
+        unlinked.extend_from_slice(&[0; 8]);
+        vec![
+            (true, import),
+            (false, imported),
+            (true, get_descriptor),
+            (false, descriptor),
+            (true, bulk_out),
+            (false, bulk_done),
+            (true, bulk_in),
+            (true, unlink),
+            (false, unlinked),
+        ]
+    }
+
+    /// Build an Ethernet frame carrying a TCP segment.
+    fn frame(to_server: bool, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let (client, server) = ([10, 0, 0, 1], [10, 0, 0, 2]);
+        let (source, dest, source_port, dest_port) = if to_server {
+            (client, server, 40000, PORT)
+        } else {
+            (server, client, PORT, 40000)
+        };
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        let total_length = (20 + 20 + payload.len()) as u16;
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&total_length.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        frame.extend_from_slice(&source);
+        frame.extend_from_slice(&dest);
+        frame.extend_from_slice(&u16::to_be_bytes(source_port));
+        frame.extend_from_slice(&u16::to_be_bytes(dest_port));
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 0, 0x50, 0x18, 0, 0, 0, 0, 0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn load(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut loader = Loader::open(data).unwrap();
+        let mut packets = Vec::new();
+        while let Some(result) = loader.next() {
+            packets.push(result.unwrap().0.data.into_owned());
+        }
+        assert_eq!(loader.bytes_read, data.len() as u64);
+        packets
+    }
+
+    #[test]
+    fn test_usbip_import() {
+        let session = session();
+        let dump: Vec<u8> = session
+            .iter()
+            .flat_map(|(_, message)| message.clone())
+            .collect();
+        assert!(is_stream(&dump));
+        let packets = load(&dump);
+        let pids: Vec<u8> = packets.iter().map(|packet| packet[0]).collect();
+        assert_eq!(pids, [
+            // SETUP, DATA0, ACK; IN, DATA1, ACK; OUT, DATA1, ACK
+            0x2D, 0xC3, 0xD2, 0x69, 0x4B, 0xD2, 0xE1, 0x4B, 0xD2,
+            // OUT, DATA0, ACK
+            0xE1, 0xC3, 0xD2,
+        ]);
+        assert_eq!(&packets[4][1..19], &session[3].1[HEADER_SIZE..]);
+        assert_eq!(&packets[10][1..5], &[0x55, 0x53, 0x42, 0x43]);
+
+        // Capture the same session over TCP, with the messages split
+        // into segments at arbitrary points, and a retransmission.
+        let header = PcapHeader {
+            datalink: DataLink::ETHERNET,
+            .. PcapHeader::default()
+        };
+        let mut writer = PcapWriter::with_header(Vec::new(), header).unwrap();
+        let mut seqs = [1000u32, 5000u32];
+        let mut write = |to_server: bool, payload: &[u8], advance: bool| {
+            let seq = &mut seqs[to_server as usize];
+            let frame = frame(to_server, *seq, payload);
+            writer.write_raw_packet(&RawPcapPacket {
+                ts_sec: 0,
+                ts_frac: 0,
+                incl_len: frame.len() as u32,
+                orig_len: frame.len() as u32,
+                data: Cow::from(frame),
+            }).unwrap();
+            if advance {
+                *seq = seq.wrapping_add(payload.len() as u32);
+            }
+        };
+        for (to_server, message) in &session {
+            let (first, second) = message.split_at(message.len() / 3);
+            write(*to_server, first, false);
+            write(*to_server, first, true);
+            write(*to_server, second, true);
+        }
+        let capture = writer.into_writer();
+        assert_eq!(load(&capture), packets);
+    }
+}
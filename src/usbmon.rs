@@ -0,0 +1,384 @@
+//! Conversion of Linux usbmon captures to USB packets.
+//!
+//! usbmon records the URBs submitted to and completed by host controller
+//! drivers, rather than the packets on the bus. Each completed URB is
+//! converted to the token, data and handshake packets that would have
+//! carried it, so that it can be decoded like a packet-level capture.
+//! Retries, SOF packets and the timing of individual packets are not
+//! recorded by usbmon, so are absent from the result.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Error, bail};
+
+use crate::usb::{data_packet, token_packet, DeviceAddr, EndpointNum, PID};
+
+/// Size of the header of each record with the `USB_LINUX` link type.
+pub const HEADER_SIZE: usize = 48;
+
+/// Size of the header of each record with the `USB_LINUX_MMAPPED` link type.
+pub const MMAPPED_HEADER_SIZE: usize = 64;
+
+pub const EVENT_SUBMIT: u8 = b'S';
+pub const EVENT_COMPLETE: u8 = b'C';
+
+pub const TRANSFER_ISOCHRONOUS: u8 = 0;
+pub const TRANSFER_INTERRUPT: u8 = 1;
+pub const TRANSFER_CONTROL: u8 = 2;
+pub const TRANSFER_BULK: u8 = 3;
+
+/// Status of a URB that ended with a STALL handshake.
+const EPIPE: i32 = -32;
+
+/// Maximum packet sizes to assume until descriptors have been seen.
+const DEFAULT_MAX_PACKET_SIZE_0: usize = 64;
+const DEFAULT_MAX_PACKET_SIZE: usize = 512;
+
+const REQUEST_GET_DESCRIPTOR: u8 = 6;
+const REQUEST_SET_ADDRESS: u8 = 5;
+const DESCRIPTOR_DEVICE: u8 = 1;
+const DESCRIPTOR_CONFIGURATION: u8 = 2;
+const DESCRIPTOR_ENDPOINT: u8 = 5;
+
+/// A usbmon record of an event on a URB.
+///
+/// Records are in the byte order of the capturing machine, which is
+/// assumed to be little-endian.
+pub struct Urb<'r> {
+    pub id: u64,
+    pub event: u8,
+    pub transfer_type: u8,
+    pub endpoint: u8,
+    pub device: u8,
+    pub bus: u16,
+    pub setup: Option<[u8; 8]>,
+    pub status: i32,
+    pub length: usize,
+    pub data: Cow<'r, [u8]>,
+}
+
+impl<'r> Urb<'r> {
+    pub fn parse(record: &'r [u8], header_size: usize)
+        -> Result<Urb<'r>, Error>
+    {
+        if record.len() < header_size {
+            bail!("usbmon record of {} bytes is too short", record.len());
+        }
+        let field = |offset: usize| -> [u8; 4] {
+            record[offset..][..4].try_into().unwrap()
+        };
+        let captured = u32::from_le_bytes(field(36)) as usize;
+        let data = if record[15] == 0 {
+            let data = &record[header_size..];
+            &data[..captured.min(data.len())]
+        } else {
+            &[]
+        };
+        Ok(Urb {
+            id: u64::from_le_bytes(record[0..8].try_into().unwrap()),
+            event: record[8],
+            transfer_type: record[9],
+            endpoint: record[10],
+            device: record[11],
+            bus: u16::from_le_bytes([record[12], record[13]]),
+            setup: (record[14] == 0).then(|| {
+                record[40..48].try_into().unwrap()
+            }),
+            status: i32::from_le_bytes(field(28)),
+            length: u32::from_le_bytes(field(32)) as usize,
+            data: Cow::from(data),
+        })
+    }
+
+    /// Parse a line of the usbmon text format, as read from the `1u` or
+    /// `0u` files, returning the record and its timestamp in nanoseconds.
+    pub fn parse_text(line: &str) -> Result<(Urb<'static>, u64), Error> {
+        let mut words = line.split_whitespace();
+        let mut next = || words.next().context("usbmon text is incomplete");
+        let id = u64::from_str_radix(next()?, 16)?;
+        let timestamp_us: u64 = next()?.parse()?;
+        let event = match next()?.as_bytes() {
+            &[event @ (b'S' | b'C' | b'E')] => event,
+            _ => bail!("Invalid usbmon event type")
+        };
+        // The 1u format includes the bus number, and the 0u format not.
+        let address: Vec<&str> = next()?.split(':').collect();
+        let (kind, bus, device, endpoint): (&str, u16, u8, u8) =
+            match address[..] {
+                [kind, bus, device, endpoint] =>
+                    (kind, bus.parse()?, device.parse()?, endpoint.parse()?),
+                [kind, device, endpoint] =>
+                    (kind, 0, device.parse()?, endpoint.parse()?),
+                _ => bail!("Invalid usbmon address")
+            };
+        let (transfer_type, direction_in) = match kind.as_bytes() {
+            &[kind, direction @ (b'i' | b'o')] => (
+                match kind {
+                    b'Z' => TRANSFER_ISOCHRONOUS,
+                    b'I' => TRANSFER_INTERRUPT,
+                    b'C' => TRANSFER_CONTROL,
+                    b'B' => TRANSFER_BULK,
+                    _ => bail!("Invalid usbmon transfer type")
+                },
+                direction == b'i'),
+            _ => bail!("Invalid usbmon transfer type")
+        };
+        let endpoint = endpoint | if direction_in { 0x80 } else { 0 };
+        let mut urb = Urb {
+            id,
+            event,
+            transfer_type,
+            endpoint,
+            device,
+            bus,
+            setup: None,
+            status: 0,
+            length: 0,
+            data: Cow::from(Vec::new()),
+        };
+        let timestamp = timestamp_us * 1000;
+        match next()? {
+            "s" => {
+                let mut setup = [0; 8];
+                setup[0] = u8::from_str_radix(next()?, 16)?;
+                setup[1] = u8::from_str_radix(next()?, 16)?;
+                for i in [2, 4, 6] {
+                    let value = u16::from_str_radix(next()?, 16)?;
+                    setup[i..][..2].copy_from_slice(&value.to_le_bytes());
+                }
+                urb.setup = Some(setup);
+            },
+            // Interrupt and isochronous URBs have further fields here.
+            status => urb.status = status
+                .split(':')
+                .next()
+                .unwrap_or_default()
+                .parse()?,
+        }
+        if transfer_type == TRANSFER_ISOCHRONOUS {
+            // Isochronous URBs are not converted.
+            return Ok((urb, timestamp));
+        }
+        urb.length = next()?.parse()?;
+        if words.next() == Some("=") {
+            let mut data = Vec::new();
+            for word in words {
+                for i in (0..word.len()).step_by(2) {
+                    let byte = word.get(i..i + 2).context("Invalid data")?;
+                    data.push(u8::from_str_radix(byte, 16)?);
+                }
+            }
+            urb.data = Cow::from(data);
+        }
+        Ok((urb, timestamp))
+    }
+}
+
+/// Whether the start of a file appears to be usbmon text.
+pub fn is_text(start: &[u8]) -> bool {
+    let line = start.split(|&b| b == b'\n').next().unwrap_or_default();
+    std::str::from_utf8(line).is_ok_and(|line| Urb::parse_text(line).is_ok())
+}
+
+/// What was sent when a URB was submitted.
+struct Submission {
+    setup: Option<[u8; 8]>,
+    data: Vec<u8>,
+}
+
+/// Converts usbmon records to packets.
+///
+/// Only the first bus seen is converted, since device addresses are not
+/// unique between buses. Isochronous URBs are not converted.
+#[derive(Default)]
+pub struct Converter {
+    bus: Option<u16>,
+    pending: HashMap<u64, Submission>,
+    max_packet_sizes: HashMap<(u8, u8), usize>,
+    toggles: HashMap<(u8, u8), bool>,
+    skipped: BTreeMap<u16, u64>,
+}
+
+impl Converter {
+    /// Convert a usbmon record to the packets it represents, if any.
+    pub fn convert(&mut self, record: &[u8], header_size: usize)
+        -> Result<Vec<Vec<u8>>, Error>
+    {
+        let urb = Urb::parse(record, header_size)?;
+        Ok(self.convert_urb(&urb))
+    }
+
+    /// Convert a URB event to the packets it represents, if any.
+    pub fn convert_urb(&mut self, urb: &Urb) -> Vec<Vec<u8>> {
+        if *self.bus.get_or_insert(urb.bus) != urb.bus {
+            *self.skipped.entry(urb.bus).or_default() += 1;
+            return Vec::new();
+        }
+        match urb.event {
+            EVENT_SUBMIT => {
+                let submission = Submission {
+                    setup: urb.setup,
+                    data: urb.data.to_vec(),
+                };
+                self.pending.insert(urb.id, submission);
+                Vec::new()
+            },
+            EVENT_COMPLETE => {
+                let submission = self.pending.remove(&urb.id);
+                self.complete(urb, submission)
+            },
+            _ => {
+                self.pending.remove(&urb.id);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Records skipped from buses other than the first, by bus number.
+    pub fn skipped(&self) -> impl Iterator<Item=(u16, u64)> + '_ {
+        self.skipped.iter().map(|(&bus, &count)| (bus, count))
+    }
+
+    fn complete(&mut self, urb: &Urb, submission: Option<Submission>)
+        -> Vec<Vec<u8>>
+    {
+        let mut packets = Vec::new();
+        let addr = DeviceAddr(urb.device);
+        let ep_num = EndpointNum(urb.endpoint & 0x0F);
+        let stalled = urb.status == EPIPE;
+        match urb.transfer_type {
+            TRANSFER_CONTROL => {
+                let (setup, out_data) = match submission {
+                    Some(Submission { setup: Some(setup), data }) =>
+                        (setup, data),
+                    _ => return packets
+                };
+                let request_in = setup[0] & 0x80 != 0;
+                let data = if request_in {
+                    &urb.data
+                } else {
+                    &out_data[..urb.length.min(out_data.len())]
+                };
+                let (data_pid, status_pid) = if request_in {
+                    (PID::IN, PID::OUT)
+                } else {
+                    (PID::OUT, PID::IN)
+                };
+                packets.push(token_packet(PID::SETUP, addr, ep_num));
+                packets.push(data_packet(PID::DATA0, &setup));
+                packets.push(vec![PID::ACK.into()]);
+                let max_size = self.max_packet_size(urb.device, 0);
+                let mut data1 = true;
+                for chunk in data.chunks(max_size) {
+                    let pid = if data1 { PID::DATA1 } else { PID::DATA0 };
+                    packets.push(token_packet(data_pid, addr, ep_num));
+                    packets.push(data_packet(pid, chunk));
+                    packets.push(vec![PID::ACK.into()]);
+                    data1 = !data1;
+                }
+                let requested = u16::from_le_bytes([setup[6], setup[7]]);
+                if stalled {
+                    // Report the stall in the stage that was not completed.
+                    let pid = if data.is_empty() && requested > 0 {
+                        data_pid
+                    } else if requested == 0 {
+                        PID::IN
+                    } else {
+                        status_pid
+                    };
+                    packets.push(token_packet(pid, addr, ep_num));
+                    packets.push(vec![PID::STALL.into()]);
+                } else if urb.status == 0 {
+                    let pid = if requested == 0 { PID::IN } else { status_pid };
+                    packets.push(token_packet(pid, addr, ep_num));
+                    packets.push(data_packet(PID::DATA1, &[]));
+                    packets.push(vec![PID::ACK.into()]);
+                    self.learn(urb.device, &setup, data);
+                }
+            },
+            TRANSFER_BULK | TRANSFER_INTERRUPT => {
+                let direction_in = urb.endpoint & 0x80 != 0;
+                let data = match (direction_in, &submission) {
+                    (true, _) => &urb.data,
+                    (false, Some(submission)) => {
+                        let data = &submission.data;
+                        &data[..urb.length.min(data.len())]
+                    },
+                    (false, None) => return packets
+                };
+                let token_pid = if direction_in { PID::IN } else { PID::OUT };
+                let max_size = self.max_packet_size(urb.device, urb.endpoint);
+                let key = (urb.device, urb.endpoint);
+                let mut chunks: Vec<&[u8]> = data.chunks(max_size).collect();
+                if chunks.is_empty() && urb.status == 0 {
+                    chunks.push(&[]);
+                }
+                for chunk in chunks {
+                    let data1 = self.toggles.entry(key).or_default();
+                    let pid = if *data1 { PID::DATA1 } else { PID::DATA0 };
+                    *data1 = !*data1;
+                    packets.push(token_packet(token_pid, addr, ep_num));
+                    packets.push(data_packet(pid, chunk));
+                    packets.push(vec![PID::ACK.into()]);
+                }
+                if stalled {
+                    packets.push(token_packet(token_pid, addr, ep_num));
+                    packets.push(vec![PID::STALL.into()]);
+                }
+            },
+            _ => {}
+        }
+        packets
+    }
+
+    fn max_packet_size(&self, device: u8, endpoint: u8) -> usize {
+        let default = if endpoint & 0x0F == 0 {
+            DEFAULT_MAX_PACKET_SIZE_0
+        } else {
+            DEFAULT_MAX_PACKET_SIZE
+        };
+        self.max_packet_sizes
+            .get(&(device, endpoint))
+            .copied()
+            .filter(|&size| size > 0)
+            .unwrap_or(default)
+    }
+
+    /// Learn maximum packet sizes from a completed control request.
+    fn learn(&mut self, device: u8, setup: &[u8; 8], data: &[u8]) {
+        let request = setup[1];
+        let descriptor_type = setup[3];
+        match (setup[0], request, descriptor_type) {
+            (0x80, REQUEST_GET_DESCRIPTOR, DESCRIPTOR_DEVICE)
+                if data.len() >= 8 =>
+            {
+                self.max_packet_sizes.insert((device, 0), data[7] as usize);
+            },
+            (0x80, REQUEST_GET_DESCRIPTOR, DESCRIPTOR_CONFIGURATION) => {
+                let mut rest = data;
+                while let [length, kind, ..] = *rest {
+                    let length = length as usize;
+                    if length < 2 || length > rest.len() {
+                        break;
+                    }
+                    if kind == DESCRIPTOR_ENDPOINT && length >= 7 {
+                        let address = rest[2];
+                        let size = u16::from_le_bytes([rest[4], rest[5]]);
+                        self.max_packet_sizes
+                            .insert((device, address), (size & 0x7FF).into());
+                    }
+                    rest = &rest[length..];
+                }
+            },
+            (0x00, REQUEST_SET_ADDRESS, _) => {
+                // The default control endpoint keeps its size.
+                if let Some(&size) = self.max_packet_sizes.get(&(device, 0)) {
+                    self.max_packet_sizes.insert((setup[2], 0), size);
+                }
+                self.toggles.retain(|&(dev, _), _| dev != setup[2]);
+            },
+            _ => {}
+        }
+    }
+}
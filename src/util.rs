@@ -14,3 +14,12 @@ pub fn fmt_size(size: u64) -> String {
         format!("{}", SizeFormatter::new(size, BINARY))
     }
 }
+
+pub fn fmt_duration(ns: u64) -> String {
+    match ns {
+        0..=999 => format!("{ns} ns"),
+        1_000..=999_999 => format!("{:.1} µs", ns as f64 / 1e3),
+        1_000_000..=999_999_999 => format!("{:.3} ms", ns as f64 / 1e6),
+        _ => format!("{:.3} s", ns as f64 / 1e9),
+    }
+}
@@ -69,6 +69,14 @@ impl<K, V> VecMap<K, V> where K: Key {
         }
         self.vec[id] = Some(value);
     }
+
+    /// Iterate over the keys and values present in the map.
+    pub fn entries(&self) -> impl Iterator<Item=(K, &V)> {
+        self.vec
+            .iter()
+            .enumerate()
+            .filter_map(|(id, opt)| Some((K::key(id), opt.as_ref()?)))
+    }
 }
 
 impl<K, V> Default for VecMap<K, V> where K: Key {
@@ -0,0 +1,195 @@
+//! Images and printouts of the rows of the traffic view.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use anyhow::{Context, Error, bail};
+use gtk::prelude::*;
+use gtk::gio::{self, Cancellable, FileCreateFlags};
+use gtk::glib;
+use gtk::{ApplicationWindow, DropDown, Label, Orientation};
+
+use crate::report::{self, ViewRow};
+use crate::ui::display_error;
+
+/// Rows of the traffic view to export as an image or print.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ViewContent {
+    /// The rows scrolled into view.
+    Visible,
+    /// The selected items, each followed by all its descendants.
+    Expanded,
+}
+
+impl ViewContent {
+    pub const ALL: [ViewContent; 2] =
+        [ViewContent::Visible, ViewContent::Expanded];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ViewContent::Visible => "visible",
+            ViewContent::Expanded => "expanded",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            ViewContent::Visible => "Rows in view",
+            ViewContent::Expanded => "Selected items, fully expanded",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ViewContent> {
+        ViewContent::ALL.into_iter().find(|content| content.name() == name)
+    }
+}
+
+/// Largest width or height of a PNG image of the traffic view, in pixels.
+const MAX_IMAGE_SIZE: f64 = 32767.0;
+
+/// Draw rows of the traffic view in their colors, from the top left.
+fn draw_view_rows(context: &gtk::cairo::Context, rows: &[ViewRow])
+    -> Result<(), gtk::cairo::Error>
+{
+    context.select_font_face("monospace",
+                             gtk::cairo::FontSlant::Normal,
+                             gtk::cairo::FontWeight::Normal);
+    context.set_font_size(report::VIEW_FONT_SIZE);
+    for (index, row) in rows.iter().enumerate() {
+        let (red, green, blue) = row.color
+            .as_deref()
+            .and_then(|color| gtk::gdk::RGBA::parse(color).ok())
+            .map_or((0.0, 0.0, 0.0), |rgba|
+                (rgba.red() as f64, rgba.green() as f64, rgba.blue() as f64));
+        context.set_source_rgb(red, green, blue);
+        let baseline = (index as f64 + 0.75) * report::VIEW_ROW_HEIGHT;
+        context.move_to(report::VIEW_MARGIN, report::VIEW_MARGIN + baseline);
+        context.show_text(&row.text)?;
+    }
+    Ok(())
+}
+
+/// Save rows of the traffic view as an SVG image if the file name ends
+/// in `.svg`, or otherwise as a PNG image.
+pub fn save_view_image(file: &gio::File, rows: &[ViewRow])
+    -> Result<(), Error>
+{
+    let name = file
+        .basename()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    if name.ends_with(".svg") {
+        let dest = file
+            .replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)?
+            .into_write();
+        report::write_svg_image(rows, dest)?;
+        return Ok(());
+    }
+    let (width, height) = report::view_size(rows);
+    if width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+        bail!("Too many rows for a PNG image, save as SVG instead");
+    }
+    let path = file.path()
+        .context("PNG images can only be saved to local files")?;
+    let (width, height) = (width.ceil() as i32, height.ceil() as i32);
+    let mut surface = gtk::cairo::ImageSurface::create(
+        gtk::cairo::Format::ARgb32, width, height)?;
+    {
+        let context = gtk::cairo::Context::new(&surface)?;
+        context.set_source_rgb(1.0, 1.0, 1.0);
+        context.paint()?;
+        draw_view_rows(&context, rows)?;
+    }
+    surface.flush();
+    let stride = surface.stride() as usize;
+    let bytes = glib::Bytes::from(&surface.data()?[..]);
+    // Cairo stores each pixel as a 32-bit ARGB value in native byte order.
+    #[cfg(target_endian = "little")]
+    let format = gtk::gdk::MemoryFormat::B8g8r8a8Premultiplied;
+    #[cfg(target_endian = "big")]
+    let format = gtk::gdk::MemoryFormat::A8r8g8b8Premultiplied;
+    let texture = gtk::gdk::MemoryTexture::new(
+        width, height, format, &bytes, stride);
+    texture.save_to_png(path)?;
+    Ok(())
+}
+
+/// Scale at which rows of the traffic view fit the width of a printed
+/// page, and the number of rows that fit on each page at that scale.
+fn page_layout(context: &gtk::PrintContext, rows: &[ViewRow])
+    -> (f64, usize)
+{
+    let (width, _) = report::view_size(rows);
+    let scale = (context.width() / width).min(1.0);
+    let height = context.height() / scale - 2.0 * report::VIEW_MARGIN;
+    let rows_per_page = (height / report::VIEW_ROW_HEIGHT) as usize;
+    (scale, rows_per_page.max(1))
+}
+
+/// Print rows of the traffic view, chosen in a tab of the print dialog,
+/// using `export_rows` to find the rows to print.
+pub fn print_view<F>(parent: Option<&ApplicationWindow>, export_rows: F)
+    -> Result<(), Error>
+    where F: Fn(ViewContent) -> Result<Vec<ViewRow>, Error> + 'static
+{
+    let operation = gtk::PrintOperation::new();
+    operation.set_job_name("Packetry traffic");
+    operation.set_unit(gtk::Unit::Points);
+    operation.set_custom_tab_label(Some("Traffic"));
+    operation.connect_create_custom_widget(|_| {
+        let descriptions = ViewContent::ALL.map(|content|
+            content.description());
+        let rows_box = gtk::Box::builder()
+            .orientation(Orientation::Horizontal)
+            .spacing(5)
+            .margin_start(10)
+            .margin_end(10)
+            .margin_top(10)
+            .margin_bottom(10)
+            .build();
+        rows_box.append(&Label::new(Some("Rows:")));
+        rows_box.append(&DropDown::from_strings(&descriptions));
+        Some(rows_box.upcast())
+    });
+    let content = Rc::new(Cell::new(ViewContent::Visible));
+    let chosen = content.clone();
+    operation.connect_custom_widget_apply(move |_, widget| {
+        let selected = widget
+            .last_child()
+            .and_downcast::<DropDown>()
+            .map(|dropdown| dropdown.selected() as usize);
+        if let Some(content) = selected
+            .and_then(|index| ViewContent::ALL.get(index).copied())
+        {
+            chosen.set(content);
+        }
+    });
+    let rows = Rc::new(RefCell::new(Vec::new()));
+    let begin_rows = rows.clone();
+    operation.connect_begin_print(move |operation, context| {
+        match export_rows(content.get()) {
+            Ok(rows) => {
+                let (_, rows_per_page) = page_layout(context, &rows);
+                let pages = rows.len().div_ceil(rows_per_page);
+                operation.set_n_pages(pages as i32);
+                begin_rows.replace(rows);
+            },
+            Err(e) => {
+                operation.cancel();
+                display_error(Err(e));
+            }
+        }
+    });
+    operation.connect_draw_page(move |_, context, page| {
+        let rows = rows.borrow();
+        let (scale, rows_per_page) = page_layout(context, &rows);
+        let start = page as usize * rows_per_page;
+        let end = (start + rows_per_page).min(rows.len());
+        let cairo = context.cairo_context();
+        cairo.scale(scale, scale);
+        display_error(draw_view_rows(&cairo, &rows[start.min(end)..end])
+            .map_err(Error::from));
+    });
+    operation.run(gtk::PrintOperationAction::PrintDialog, parent)?;
+    Ok(())
+}